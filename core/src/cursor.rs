@@ -0,0 +1,385 @@
+use crate::header::{read_header, Incompatible, SEGMENT_HEADER_LEN};
+use crate::record::{decode_record, is_footer, parse_header, record_crc, End, HEADER_LEN};
+use alloc::{vec, vec::Vec};
+#[cfg(test)]
+use {
+    crate::{encode_footer, encode_record, write_header},
+    alloc::string::String,
+};
+
+// Where a segment's bytes live: a file for `redo-log`, or a region of flash
+// for firmware. A segment takes up the whole of it, header first.
+pub trait Storage {
+    type Error;
+
+    // How many bytes there are to read, written or not.
+    fn size(&self) -> u64;
+
+    // Fills `buf` with the bytes starting at `offset`, which always fall
+    // within `size`.
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<(), Self::Error>;
+}
+
+// Storage that segments are written to as well as read from. Records go in at
+// the offset just past the last one, and whatever that write leaves behind,
+// torn or corrupted, is what `SegmentCursor` has to make sense of later.
+pub trait WriteStorage: Storage {
+    fn write_at(&mut self, offset: u64, buf: &[u8]) -> Result<(), Self::Error>;
+
+    // Makes everything written so far durable.
+    fn flush(&mut self) -> Result<(), Self::Error>;
+}
+
+// Reads the records of a single segment in order, stopping at the first one
+// that is zeroed (preallocated space), stale (left over from a recycled file),
+// or torn.
+#[derive(Debug)]
+pub struct SegmentCursor<S> {
+    storage: S,
+    len: u64,
+    number: u64,
+    // None if the segment header itself is missing or stale, in which case the
+    // segment is treated as empty.
+    first_seq: Option<u64>,
+    codec: u32,
+    offset: u64,
+    next_seq: u64,
+    end: Option<End>,
+    // Whether records are checked against their checksums.
+    verify: bool,
+    // Whether the sequence can skip ahead from one record to the next.
+    sparse: bool,
+}
+
+impl<S: Storage> SegmentCursor<S>
+where
+    S::Error: From<Incompatible>,
+{
+    pub fn open(mut storage: S, number: u64) -> Result<Self, S::Error> {
+        let len = storage.size();
+        let mut header = [0; SEGMENT_HEADER_LEN];
+        let header = &mut header[..len.min(SEGMENT_HEADER_LEN as u64) as usize];
+        storage.read_at(0, header)?;
+        let (first_seq, codec) = match read_header(number, header)? {
+            Some((first_seq, codec)) => (Some(first_seq), codec),
+            None => (None, 0),
+        };
+        Ok(SegmentCursor {
+            storage,
+            len,
+            number,
+            first_seq,
+            codec,
+            offset: SEGMENT_HEADER_LEN as u64,
+            next_seq: first_seq.unwrap_or(0),
+            end: first_seq.map_or(Some(End::BadHeader), |_| None),
+            verify: true,
+            sparse: false,
+        })
+    }
+
+    // Opens segment `number` to carry on from `offset`, where an earlier
+    // cursor left off after reading up to `next_seq`. Records appended since
+    // then show up as if the earlier cursor had kept going.
+    pub fn resume(storage: S, number: u64, offset: u64, next_seq: u64) -> Result<Self, S::Error> {
+        let mut cursor = Self::open(storage, number)?;
+        if cursor.first_seq.is_some() {
+            cursor.offset = offset;
+            cursor.next_seq = next_seq;
+        }
+        Ok(cursor)
+    }
+}
+
+impl<S: Storage> SegmentCursor<S> {
+    // Stops checking records against their checksums, for a segment that's
+    // known to be intact some other way. A damaged record is then only
+    // noticed if it stops making sense as a record.
+    pub fn set_verify(&mut self, verify: bool) {
+        self.verify = verify;
+    }
+
+    // Takes records to be in sequence as long as each one comes after the
+    // last, as they do in a shard of a sharded log, where the records in
+    // between went to the other shards. `next_seq` is then one past the last
+    // record read.
+    pub fn set_sparse(&mut self, sparse: bool) {
+        self.sparse = sparse;
+    }
+
+    pub fn number(&self) -> u64 {
+        self.number
+    }
+
+    pub fn first_seq(&self) -> Option<u64> {
+        self.first_seq
+    }
+
+    // The id of the codec the segment's records are in, or 0 if its header
+    // is missing or stale.
+    pub fn codec(&self) -> u32 {
+        self.codec
+    }
+
+    // The offset just past the last valid record read so far.
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    // The sequence number the next record in this segment would have.
+    pub fn next_seq(&self) -> u64 {
+        self.next_seq
+    }
+
+    // Why the cursor stopped, once it has.
+    pub fn end(&self) -> Option<End> {
+        self.end
+    }
+
+    pub fn into_storage(self) -> S {
+        self.storage
+    }
+
+    // Reads the next record into `payload`, which can be reused from one
+    // record to the next. Returns the record's offset and sequence number, or
+    // None once the end of the valid records has been reached.
+    pub fn next_record(&mut self, payload: &mut Vec<u8>) -> Result<Option<(u64, u64)>, S::Error> {
+        if self.end.is_some() {
+            return Ok(None);
+        }
+        let mut header = [0; HEADER_LEN];
+        if self.offset == self.len {
+            return Ok(self.stop(End::Eof));
+        }
+        if self.offset + HEADER_LEN as u64 > self.len {
+            return Ok(self.stop(End::Torn));
+        }
+        self.storage.read_at(self.offset, &mut header)?;
+        if header.iter().all(|&b| b == 0) {
+            return Ok(self.stop(End::Zeroed));
+        }
+        // A footer that's damaged stops the cursor one of the ways a damaged
+        // record would.
+        if is_footer(&header, self.number) {
+            return Ok(self.stop(End::Sealed));
+        }
+        let (crc, len, number, seq) = parse_header(&header);
+        let in_sequence = match self.sparse {
+            true => seq >= self.next_seq,
+            false => seq == self.next_seq,
+        };
+        if number != self.number && !in_sequence {
+            return Ok(self.stop(End::Stale));
+        }
+        if !in_sequence {
+            return Ok(self.stop(End::Sequence));
+        }
+        // Check the length against the storage before trusting it with an
+        // allocation.
+        if self.offset + (HEADER_LEN + len) as u64 > self.len {
+            return Ok(self.stop(if number == self.number {
+                End::Torn
+            } else {
+                End::Stale
+            }));
+        }
+        payload.resize(len, 0);
+        self.storage
+            .read_at(self.offset + HEADER_LEN as u64, payload)?;
+        if number != self.number {
+            // Either left over from the storage's previous life, or one of
+            // ours with a damaged segment number, which would still check out
+            // under the right one.
+            header[8..16].copy_from_slice(&self.number.to_le_bytes());
+            if record_crc(&header, payload) == crc {
+                return Ok(self.stop(End::Checksum));
+            }
+            return Ok(self.stop(End::Stale));
+        }
+        if self.verify && record_crc(&header, payload) != crc {
+            return Ok(self.stop(End::Checksum));
+        }
+        let offset = self.offset;
+        self.offset += (HEADER_LEN + len) as u64;
+        self.next_seq = seq + 1;
+        Ok(Some((offset, seq)))
+    }
+
+    fn stop(&mut self, end: End) -> Option<(u64, u64)> {
+        self.end = Some(end);
+        None
+    }
+
+    // Once the cursor has stopped at a record that is torn, fails its
+    // checksum, or is out of sequence, looks further into the segment for a
+    // valid record that carries on the sequence. If there is one, the cursor
+    // picks up from there and this returns true; if not, whatever went wrong
+    // was the last thing written to the segment.
+    pub fn resync(&mut self) -> Result<bool, S::Error> {
+        let mut rest = vec![0; self.len.saturating_sub(self.offset) as usize];
+        self.storage.read_at(self.offset, &mut rest)?;
+        for start in 0..rest.len() {
+            if rest.len() - start < HEADER_LEN {
+                break;
+            }
+            let record = match decode_record(&rest[start..]) {
+                Ok((record, _)) => record,
+                Err(_) => continue,
+            };
+            if record.segment != self.number || record.seq < self.next_seq {
+                continue;
+            }
+            self.offset += start as u64;
+            self.next_seq = record.seq;
+            self.end = None;
+            return Ok(true);
+        }
+        Ok(false)
+    }
+}
+
+// Replays every record `cursor` has left into `apply`, along with its sequence
+// number, the way a state machine catches up with its journal. Returns why the
+// segment ended; whether that's a problem is up to the caller (see
+// `End::is_clean`).
+pub fn replay<S, E, F>(cursor: &mut SegmentCursor<S>, mut apply: F) -> Result<End, E>
+where
+    S: Storage,
+    E: From<S::Error>,
+    F: FnMut(u64, &[u8]) -> Result<(), E>,
+{
+    let mut payload = Vec::new();
+    while let Some((_, seq)) = cursor.next_record(&mut payload)? {
+        apply(seq, &payload)?;
+    }
+    Ok(cursor.end.unwrap_or(End::Eof))
+}
+
+// A region of flash, with a segment written to the start of it.
+#[cfg(test)]
+#[derive(Debug)]
+pub(crate) struct Flash(pub(crate) Vec<u8>);
+
+#[cfg(test)]
+#[derive(Debug, PartialEq)]
+pub(crate) enum FlashError {
+    Incompatible(String),
+    OutOfRange,
+}
+
+#[cfg(test)]
+impl From<Incompatible> for FlashError {
+    fn from(e: Incompatible) -> Self {
+        FlashError::Incompatible(e.reason)
+    }
+}
+
+#[cfg(test)]
+impl Storage for Flash {
+    type Error = FlashError;
+
+    fn size(&self) -> u64 {
+        self.0.len() as u64
+    }
+
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<(), FlashError> {
+        let offset = offset as usize;
+        let bytes = self
+            .0
+            .get(offset..offset + buf.len())
+            .ok_or(FlashError::OutOfRange)?;
+        buf.copy_from_slice(bytes);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+impl WriteStorage for Flash {
+    fn write_at(&mut self, offset: u64, buf: &[u8]) -> Result<(), FlashError> {
+        let offset = offset as usize;
+        let end = offset + buf.len();
+        if self.0.len() < end {
+            self.0.resize(end, 0);
+        }
+        self.0[offset..end].copy_from_slice(buf);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), FlashError> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+fn flash(number: u64, records: &[(u64, &[u8])], size: usize) -> Flash {
+    let mut bytes = write_header(number, records[0].0, 2).to_vec();
+    for &(seq, payload) in records {
+        encode_record(number, seq, payload, &mut bytes);
+    }
+    bytes.resize(bytes.len().max(size), 0);
+    Flash(bytes)
+}
+
+#[test]
+fn test_replay() {
+    let records: [(u64, &[u8]); 3] = [(7, b"one"), (8, b""), (9, b"three")];
+    let mut cursor = SegmentCursor::open(flash(3, &records, 256), 3).unwrap();
+    assert_eq!((cursor.first_seq(), cursor.codec()), (Some(7), 2));
+    let mut applied = Vec::new();
+    let end = replay(&mut cursor, |seq, payload| {
+        applied.push((seq, payload.to_vec()));
+        Ok::<_, FlashError>(())
+    })
+    .unwrap();
+    assert_eq!(end, End::Zeroed);
+    let expected: Vec<_> = records.iter().map(|&(s, p)| (s, p.to_vec())).collect();
+    assert_eq!(applied, expected);
+    assert_eq!(cursor.next_seq(), 10);
+
+    // Picking up where it left off sees a record appended since.
+    let offset = cursor.offset();
+    let mut storage = flash(3, &records, 0);
+    encode_record(3, 10, b"four", &mut storage.0);
+    let mut cursor = SegmentCursor::resume(storage, 3, offset, 10).unwrap();
+    let mut payload = Vec::new();
+    assert_eq!(
+        cursor.next_record(&mut payload).unwrap(),
+        Some((offset, 10))
+    );
+    assert_eq!(payload, b"four");
+    assert_eq!(cursor.next_record(&mut payload).unwrap(), None);
+    assert_eq!(cursor.end(), Some(End::Eof));
+
+    // A damaged record stops the replay, and resyncing finds the next one.
+    let mut storage = flash(3, &records, 256);
+    storage.0[SEGMENT_HEADER_LEN + HEADER_LEN] ^= 1;
+    let mut cursor = SegmentCursor::open(storage, 3).unwrap();
+    let end = replay(&mut cursor, |_, _| Ok::<_, FlashError>(())).unwrap();
+    assert_eq!(end, End::Checksum);
+    assert!(cursor.resync().unwrap());
+    assert_eq!(cursor.next_record(&mut payload).unwrap().unwrap().1, 8);
+
+    // A footer ends the segment cleanly, however much space is left after
+    // it, and a damaged one doesn't.
+    let mut storage = flash(3, &records, 0);
+    encode_footer(3, 10, &mut storage.0);
+    storage.0.resize(256, 0xff);
+    let mut cursor = SegmentCursor::open(storage, 3).unwrap();
+    let end = replay(&mut cursor, |_, _| Ok::<_, FlashError>(())).unwrap();
+    assert_eq!((end, cursor.next_seq()), (End::Sealed, 10));
+    assert!(end.is_clean());
+    let mut storage = flash(3, &records, 0);
+    encode_footer(3, 10, &mut storage.0);
+    let len = storage.0.len();
+    storage.0[len - 1] ^= 1;
+    let mut cursor = SegmentCursor::open(storage, 3).unwrap();
+    let end = replay(&mut cursor, |_, _| Ok::<_, FlashError>(())).unwrap();
+    assert!(!end.is_clean());
+
+    // Storage that never held a segment reads as empty; storage holding
+    // something else isn't read at all.
+    let cursor = SegmentCursor::open(Flash(vec![0; 64]), 3).unwrap();
+    assert_eq!(cursor.end(), Some(End::BadHeader));
+    let err = SegmentCursor::open(Flash(vec![0xff; 64]), 3).unwrap_err();
+    assert!(matches!(err, FlashError::Incompatible(_)));
+}
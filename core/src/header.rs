@@ -0,0 +1,101 @@
+use alloc::{format, string::String};
+
+// Each segment starts with a header saying how to read it:
+//
+//   magic: [u8; 8], version: u16, flags: u16, compression: u8, checksum: u8,
+//   2 bytes of zeros, codec: u32, 4 bytes of zeros, number: u64, first_seq: u64
+//
+// The magic bytes tell a segment from any other file that happens to be
+// named like one. A reader that finds a version it doesn't know, or any of
+// the flags set, which are for features it would have to understand to read
+// the records correctly, gives up with `Incompatible` rather than guess.
+// Records aren't compressed yet, and their checksums are CRC-32; the codec is
+// the id of the one the records are in. After those come the segment's own
+// number and the sequence number of its first record, so that the sequence
+// survives even when a segment has no records in it yet.
+pub const SEGMENT_HEADER_LEN: usize = 40;
+
+pub const MAGIC: [u8; 8] = *b"redo-log";
+// The newest version of the header, and of what follows it, that this build
+// can read and the only one it writes.
+pub const FORMAT_VERSION: u16 = 1;
+const COMPRESSION_NONE: u8 = 0;
+const CHECKSUM_CRC32: u8 = 1;
+
+// A segment that isn't one this build knows how to read, either because it
+// was written by a newer one or because it isn't a segment at all.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Incompatible {
+    pub segment: u64,
+    pub reason: String,
+}
+
+// Checks that `header` is one this build can read, and returns the first
+// sequence number and codec it gives segment `number`, or None if it's missing
+// or stale: all zeros, as a crash between creating a segment and writing its
+// header leaves it, cut short, or left over from the file's previous life as
+// another segment.
+pub fn read_header(number: u64, header: &[u8]) -> Result<Option<(u64, u32)>, Incompatible> {
+    let incompatible = |reason: String| Incompatible {
+        segment: number,
+        reason,
+    };
+    let magic = &header[..header.len().min(MAGIC.len())];
+    if magic.iter().all(|&b| b == 0) {
+        return Ok(None);
+    }
+    if !MAGIC.starts_with(magic) {
+        return Err(incompatible(
+            "it doesn't start with a segment's magic bytes".into(),
+        ));
+    }
+    if header.len() < SEGMENT_HEADER_LEN {
+        return Ok(None);
+    }
+    let u16_at = |at: usize| u16::from_le_bytes(header[at..at + 2].try_into().unwrap());
+    let u32_at = |at: usize| u32::from_le_bytes(header[at..at + 4].try_into().unwrap());
+    let u64_at = |at: usize| u64::from_le_bytes(header[at..at + 8].try_into().unwrap());
+    match u16_at(8) {
+        FORMAT_VERSION => {}
+        version => {
+            return Err(incompatible(format!(
+                "it's format version {}, and this build only reads version {}",
+                version, FORMAT_VERSION
+            )))
+        }
+    }
+    if u16_at(10) != 0 {
+        return Err(incompatible(format!(
+            "it needs features this build doesn't have (flags {:#06x})",
+            u16_at(10)
+        )));
+    }
+    if header[12] != COMPRESSION_NONE {
+        return Err(incompatible(format!(
+            "it's compressed with unknown method {}",
+            header[12]
+        )));
+    }
+    if header[13] != CHECKSUM_CRC32 {
+        return Err(incompatible(format!(
+            "its records have checksums of unknown type {}",
+            header[13]
+        )));
+    }
+    if u64_at(24) != number {
+        return Ok(None);
+    }
+    Ok(Some((u64_at(32), u32_at(16))))
+}
+
+pub fn write_header(number: u64, first_seq: u64, codec: u32) -> [u8; SEGMENT_HEADER_LEN] {
+    let mut header = [0; SEGMENT_HEADER_LEN];
+    header[0..8].copy_from_slice(&MAGIC);
+    header[8..10].copy_from_slice(&FORMAT_VERSION.to_le_bytes());
+    header[12] = COMPRESSION_NONE;
+    header[13] = CHECKSUM_CRC32;
+    header[16..20].copy_from_slice(&codec.to_le_bytes());
+    header[24..32].copy_from_slice(&number.to_le_bytes());
+    header[32..40].copy_from_slice(&first_seq.to_le_bytes());
+    header
+}
@@ -0,0 +1,31 @@
+// The parts of the log format that don't need an operating system: encoding
+// records and checking them, segment headers, reading a segment back a record
+// at a time to replay it, and appending to one. It needs nothing but `alloc`,
+// so firmware can journal onto raw flash, or a browser into the origin private
+// file system, by implementing `WriteStorage` over it, while `redo-log` builds
+// files, threads and everything else on top.
+//
+// That's as far as storage is pluggable: `redo-log` itself doesn't go through
+// `Storage`. Its log, tables, value log and directory lock use `std::fs`, and
+// its commits and maintenance use OS threads, so `Db` doesn't build for
+// `wasm32-unknown-unknown`, and in a browser it's this crate, through
+// `redo-log-web`, that runs instead.
+#![no_std]
+extern crate alloc;
+#[cfg(test)]
+extern crate std;
+
+mod cursor;
+mod header;
+mod record;
+mod writer;
+
+pub use crate::cursor::{replay, SegmentCursor, Storage, WriteStorage};
+pub use crate::header::{
+    read_header, write_header, Incompatible, FORMAT_VERSION, MAGIC, SEGMENT_HEADER_LEN,
+};
+pub use crate::record::{
+    decode_record, encode_footer, encode_record, is_footer, parse_header, record_crc, End, Record,
+    FOOTER_LEN, HEADER_LEN,
+};
+pub use crate::writer::SegmentWriter;
@@ -0,0 +1,148 @@
+use alloc::vec::Vec;
+use core::fmt;
+
+// Every record is prefixed with a header containing a checksum, the length of
+// the payload, the number of the segment the record was written into, and the
+// record's sequence number. The segment number is what lets us reuse old files:
+// anything left over from the file's previous life carries a different number
+// and is treated as the end of the segment.
+pub const HEADER_LEN: usize = 24;
+
+pub fn encode_record(number: u64, seq: u64, payload: &[u8], buf: &mut Vec<u8>) {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(&number.to_le_bytes());
+    hasher.update(&seq.to_le_bytes());
+    hasher.update(payload);
+    buf.extend_from_slice(&hasher.finalize().to_le_bytes());
+    buf.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    buf.extend_from_slice(&number.to_le_bytes());
+    buf.extend_from_slice(&seq.to_le_bytes());
+    buf.extend_from_slice(payload);
+}
+
+// A segment that was finished with, rather than cut short by a crash, ends in
+// a footer: a record header with no payload and `SEALED` for its length,
+// carrying the sequence number the next segment starts at. An older build
+// takes it for a torn record.
+pub const FOOTER_LEN: usize = HEADER_LEN;
+const SEALED: u32 = u32::MAX;
+
+pub fn encode_footer(number: u64, next_seq: u64, buf: &mut Vec<u8>) {
+    let mut header = [0; HEADER_LEN];
+    header[4..8].copy_from_slice(&SEALED.to_le_bytes());
+    header[8..16].copy_from_slice(&number.to_le_bytes());
+    header[16..24].copy_from_slice(&next_seq.to_le_bytes());
+    let crc = record_crc(&header, &[]);
+    header[0..4].copy_from_slice(&crc.to_le_bytes());
+    buf.extend_from_slice(&header);
+}
+
+// Whether a record header of segment `number` is that segment's footer.
+pub fn is_footer(header: &[u8], number: u64) -> bool {
+    let (crc, len, segment, _) = parse_header(header);
+    len == SEALED as usize && segment == number && record_crc(header, &[]) == crc
+}
+
+// Why a segment reader stopped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum End {
+    // The segment header is missing or belongs to some other segment.
+    BadHeader,
+    // We reached the actual end of the file.
+    Eof,
+    // We reached space that was preallocated but never written.
+    Zeroed,
+    // The next record was left over from the file's previous life as some
+    // other segment.
+    Stale,
+    // The next record runs past the end of the file.
+    Torn,
+    // The next record's checksum doesn't match its contents.
+    Checksum,
+    // The next record belongs to this segment but has the wrong sequence
+    // number.
+    Sequence,
+    // We reached the segment's footer, so nothing more was ever written to
+    // it.
+    Sealed,
+}
+
+impl End {
+    // Whether this is how a segment normally ends, as opposed to a sign that
+    // something went wrong. Torn records are expected at the end of the last
+    // segment after a crash, so they're left to the caller to judge.
+    pub fn is_clean(self) -> bool {
+        matches!(self, End::Eof | End::Zeroed | End::Stale | End::Sealed)
+    }
+}
+
+impl fmt::Display for End {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            End::BadHeader => "bad segment header",
+            End::Eof => "end of file",
+            End::Zeroed => "unwritten space",
+            End::Stale => "stale record",
+            End::Torn => "record runs past the end of the segment",
+            End::Checksum => "checksum mismatch",
+            End::Sequence => "record out of sequence",
+            End::Sealed => "end of a sealed segment",
+        })
+    }
+}
+
+// A record found by `decode_record`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Record<'a> {
+    pub segment: u64,
+    pub seq: u64,
+    pub payload: &'a [u8],
+}
+
+// Decodes the record at the start of `bytes`, returning it along with how many
+// bytes it takes up, or why there's no record there. It does no I/O and makes
+// no assumptions about `bytes`, which makes it the place to point a fuzzer at
+// the record format.
+pub fn decode_record(bytes: &[u8]) -> Result<(Record<'_>, usize), End> {
+    let Some(header) = bytes.get(..HEADER_LEN) else {
+        return Err(End::Torn);
+    };
+    if header.iter().all(|&b| b == 0) {
+        return Err(End::Zeroed);
+    }
+    let (crc, len, segment, seq) = parse_header(header);
+    if is_footer(header, segment) {
+        return Err(End::Sealed);
+    }
+    let Some(payload) = bytes[HEADER_LEN..].get(..len) else {
+        return Err(End::Torn);
+    };
+    if record_crc(header, payload) != crc {
+        return Err(End::Checksum);
+    }
+    let record = Record {
+        segment,
+        seq,
+        payload,
+    };
+    Ok((record, HEADER_LEN + len))
+}
+
+// Splits a record header into its checksum, payload length, segment number and
+// sequence number.
+pub fn parse_header(header: &[u8]) -> (u32, usize, u64, u64) {
+    (
+        u32::from_le_bytes(header[0..4].try_into().unwrap()),
+        u32::from_le_bytes(header[4..8].try_into().unwrap()) as usize,
+        u64::from_le_bytes(header[8..16].try_into().unwrap()),
+        u64::from_le_bytes(header[16..24].try_into().unwrap()),
+    )
+}
+
+// The checksum covers the segment and sequence numbers as well as the payload.
+pub fn record_crc(header: &[u8], payload: &[u8]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(&header[8..24]);
+    hasher.update(payload);
+    hasher.finalize()
+}
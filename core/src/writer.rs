@@ -0,0 +1,139 @@
+use crate::cursor::{SegmentCursor, WriteStorage};
+use crate::header::{write_header, SEGMENT_HEADER_LEN};
+use crate::record::{encode_footer, encode_record};
+#[cfg(test)]
+use crate::{
+    cursor::{replay, Flash, FlashError},
+    record::End,
+};
+use alloc::vec::Vec;
+
+// Appends records to a single segment, the other half of `SegmentCursor`, for
+// whatever doesn't have `redo-log`'s files to write to. A journal is replayed
+// with a cursor first, and the writer carries on from where it stopped.
+#[derive(Debug)]
+pub struct SegmentWriter<S> {
+    storage: S,
+    number: u64,
+    offset: u64,
+    next_seq: u64,
+    buf: Vec<u8>,
+}
+
+impl<S: WriteStorage> SegmentWriter<S> {
+    // Starts segment `number` at the beginning of `storage`, with its first
+    // record numbered `first_seq`, in the codec with id `codec`.
+    pub fn create(
+        mut storage: S,
+        number: u64,
+        first_seq: u64,
+        codec: u32,
+    ) -> Result<Self, S::Error> {
+        storage.write_at(0, &write_header(number, first_seq, codec))?;
+        Ok(SegmentWriter {
+            storage,
+            number,
+            offset: SEGMENT_HEADER_LEN as u64,
+            next_seq: first_seq,
+            buf: Vec::new(),
+        })
+    }
+
+    // Carries on with the segment `cursor` has read to the end of, just past
+    // the last valid record, so that whatever a crash left torn after it is
+    // written over. If the storage doesn't hold the segment yet, it's handed
+    // back to be `create`d instead.
+    pub fn resume(cursor: SegmentCursor<S>) -> Result<Self, S> {
+        if cursor.first_seq().is_none() {
+            return Err(cursor.into_storage());
+        }
+        let (number, offset, next_seq) = (cursor.number(), cursor.offset(), cursor.next_seq());
+        Ok(SegmentWriter {
+            storage: cursor.into_storage(),
+            number,
+            offset,
+            next_seq,
+            buf: Vec::new(),
+        })
+    }
+
+    // Writes `payload` as the next record, and returns its sequence number.
+    // It isn't durable until `flush`.
+    pub fn append(&mut self, payload: &[u8]) -> Result<u64, S::Error> {
+        let seq = self.next_seq;
+        self.buf.clear();
+        encode_record(self.number, seq, payload, &mut self.buf);
+        self.storage.write_at(self.offset, &self.buf)?;
+        self.offset += self.buf.len() as u64;
+        self.next_seq += 1;
+        Ok(seq)
+    }
+
+    pub fn flush(&mut self) -> Result<(), S::Error> {
+        self.storage.flush()
+    }
+
+    // Ends the segment with a footer, so that replaying it knows nothing was
+    // lost off the end, and flushes it.
+    pub fn seal(&mut self) -> Result<(), S::Error> {
+        self.buf.clear();
+        encode_footer(self.number, self.next_seq, &mut self.buf);
+        self.storage.write_at(self.offset, &self.buf)?;
+        self.offset += self.buf.len() as u64;
+        self.flush()
+    }
+
+    // The offset just past the last record written.
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    // The sequence number the next record will have.
+    pub fn next_seq(&self) -> u64 {
+        self.next_seq
+    }
+
+    pub fn into_storage(self) -> S {
+        self.storage
+    }
+}
+
+#[test]
+fn test_writer() {
+    let mut writer = SegmentWriter::create(Flash(Vec::new()), 4, 10, 2).unwrap();
+    assert_eq!(writer.append(b"one").unwrap(), 10);
+    assert_eq!(writer.append(b"two").unwrap(), 11);
+    writer.flush().unwrap();
+
+    // A crash tears the next record partway through. Replaying stops before
+    // it, and the writer writes over it.
+    let end = writer.offset() as usize;
+    writer.append(b"three").unwrap();
+    let mut storage = writer.into_storage();
+    storage.0.truncate(end + 5);
+    let mut cursor = SegmentCursor::open(storage, 4).unwrap();
+    let mut applied = Vec::new();
+    let end = replay(&mut cursor, |seq, payload| {
+        applied.push((seq, payload.to_vec()));
+        Ok::<_, FlashError>(())
+    })
+    .unwrap();
+    assert_eq!(end, End::Torn);
+    assert_eq!(applied, [(10, b"one".to_vec()), (11, b"two".to_vec())]);
+    let mut writer = SegmentWriter::resume(cursor).unwrap();
+    assert_eq!(writer.append(b"four").unwrap(), 12);
+    writer.seal().unwrap();
+
+    let mut cursor = SegmentCursor::open(writer.into_storage(), 4).unwrap();
+    let mut seqs = Vec::new();
+    let end = replay(&mut cursor, |seq, _| {
+        seqs.push(seq);
+        Ok::<_, FlashError>(())
+    })
+    .unwrap();
+    assert_eq!((end, seqs), (End::Sealed, [10, 11, 12].to_vec()));
+
+    // Storage that's never been written to has no segment to carry on with.
+    let cursor = SegmentCursor::open(Flash(Vec::new()), 4).unwrap();
+    assert!(SegmentWriter::resume(cursor).is_err());
+}
@@ -0,0 +1,108 @@
+// Generated from ffi/src/lib.rs by cbindgen, in `test_header`.
+// Run REDO_LOG_BLESS=1 cargo test -p redo-log-ffi to update it.
+
+#ifndef REDO_LOG_H
+#define REDO_LOG_H
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+#define REDO_LOG_OK 0
+
+#define REDO_LOG_NOT_FOUND 1
+
+#define REDO_LOG_ERROR -1
+
+// An open database, from `redo_log_open`. Any number of threads can use a
+// handle at once, but `redo_log_close` has to wait until every other call
+// with it has returned.
+typedef struct RedoLogDb RedoLogDb;
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+// Opens, or creates, the database in the directory `dir`, a NUL-terminated
+// path. On success, `*db` is set to a handle that has to be passed to
+// `redo_log_close` once it's no longer needed.
+//
+// # Safety
+//
+// `dir` has to be a NUL-terminated string, and `db` a pointer that can be
+// written to. `err` can be null.
+int redo_log_open(const char *dir, struct RedoLogDb **db, char **err);
+
+// Sets `key` to `value`, returning once the write is durable.
+//
+// # Safety
+//
+// `db` has to come from `redo_log_open` and not have been closed, and `key`
+// and `value` have to point to `key_len` and `value_len` readable bytes.
+// `err` can be null.
+int redo_log_set(struct RedoLogDb *db,
+                 const char *key,
+                 uintptr_t key_len,
+                 const char *value,
+                 uintptr_t value_len,
+                 char **err);
+
+// Looks up `key`. If it's there, `*value` is set to a copy of its value,
+// which the caller frees with `redo_log_free`, and `*value_len` to its
+// length; the copy is NUL-terminated too, for convenience. If it isn't,
+// this returns `REDO_LOG_NOT_FOUND` and leaves them alone.
+//
+// # Safety
+//
+// `db` has to come from `redo_log_open` and not have been closed, `key` has
+// to point to `key_len` readable bytes, and `value` and `value_len` have to
+// be pointers that can be written to. `err` can be null.
+int redo_log_get(struct RedoLogDb *db,
+                 const char *key,
+                 uintptr_t key_len,
+                 char **value,
+                 uintptr_t *value_len,
+                 char **err);
+
+// Deletes `key`, returning once the delete is durable. Deleting a key that
+// isn't there succeeds.
+//
+// # Safety
+//
+// `db` has to come from `redo_log_open` and not have been closed, and `key`
+// has to point to `key_len` readable bytes. `err` can be null.
+int redo_log_delete(struct RedoLogDb *db, const char *key, uintptr_t key_len, char **err);
+
+// Waits until every write so far is durable.
+//
+// # Safety
+//
+// `db` has to come from `redo_log_open` and not have been closed. `err` can
+// be null.
+int redo_log_sync(struct RedoLogDb *db, char **err);
+
+// Syncs and closes the database, and frees `db`, which can't be used again
+// whether this succeeds or not. A null `db` is ignored.
+//
+// # Safety
+//
+// `db` has to be null or come from `redo_log_open` and not have been closed
+// already, and no other call with it can still be running. `err` can be
+// null.
+int redo_log_close(struct RedoLogDb *db, char **err);
+
+// Frees a value or error message returned by one of the other functions. A
+// null `p` is ignored.
+//
+// # Safety
+//
+// `p` has to be null or have come from this library, and not have been freed
+// already.
+void redo_log_free(char *p);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* REDO_LOG_H */
@@ -0,0 +1,415 @@
+// A C interface to `redo_log::Db`, for embedding it in programs that aren't
+// written in Rust. `redo_log.h` next to this crate declares everything here,
+// and `test_header` checks it's up to date.
+//
+// Every function returns one of the `REDO_LOG_*` status codes. On
+// `REDO_LOG_ERROR`, if `err` isn't null, `*err` is set to a message that the
+// caller frees with `redo_log_free`. Keys and values are passed as pointers
+// and lengths, and have to be UTF-8. A panic inside the library comes back as
+// an error rather than unwinding into C.
+use redo_log::Db;
+use std::{
+    ffi::{c_char, c_int, CStr},
+    panic::{self, AssertUnwindSafe},
+    ptr, slice,
+};
+
+pub const REDO_LOG_OK: c_int = 0;
+pub const REDO_LOG_NOT_FOUND: c_int = 1;
+pub const REDO_LOG_ERROR: c_int = -1;
+
+/// An open database, from `redo_log_open`. Any number of threads can use a
+/// handle at once, but `redo_log_close` has to wait until every other call
+/// with it has returned.
+pub struct RedoLogDb {
+    db: Db,
+}
+
+/// Opens, or creates, the database in the directory `dir`, a NUL-terminated
+/// path. On success, `*db` is set to a handle that has to be passed to
+/// `redo_log_close` once it's no longer needed.
+///
+/// # Safety
+///
+/// `dir` has to be a NUL-terminated string, and `db` a pointer that can be
+/// written to. `err` can be null.
+#[no_mangle]
+pub unsafe extern "C" fn redo_log_open(
+    dir: *const c_char,
+    db: *mut *mut RedoLogDb,
+    err: *mut *mut c_char,
+) -> c_int {
+    guard(err, || {
+        if dir.is_null() || db.is_null() {
+            return Err("dir and db can't be null".into());
+        }
+        let dir = CStr::from_ptr(dir).to_str().map_err(|e| e.to_string())?;
+        let opened = Db::new(dir).map_err(|e| e.to_string())?;
+        *db = Box::into_raw(Box::new(RedoLogDb { db: opened }));
+        Ok(REDO_LOG_OK)
+    })
+}
+
+/// Sets `key` to `value`, returning once the write is durable.
+///
+/// # Safety
+///
+/// `db` has to come from `redo_log_open` and not have been closed, and `key`
+/// and `value` have to point to `key_len` and `value_len` readable bytes.
+/// `err` can be null.
+#[no_mangle]
+pub unsafe extern "C" fn redo_log_set(
+    db: *mut RedoLogDb,
+    key: *const c_char,
+    key_len: usize,
+    value: *const c_char,
+    value_len: usize,
+    err: *mut *mut c_char,
+) -> c_int {
+    guard(err, || {
+        let db = handle(db)?;
+        let key = text(key, key_len)?;
+        let value = text(value, value_len)?;
+        db.db.clone().set(key, value).map_err(|e| e.to_string())?;
+        Ok(REDO_LOG_OK)
+    })
+}
+
+/// Looks up `key`. If it's there, `*value` is set to a copy of its value,
+/// which the caller frees with `redo_log_free`, and `*value_len` to its
+/// length; the copy is NUL-terminated too, for convenience. If it isn't,
+/// this returns `REDO_LOG_NOT_FOUND` and leaves them alone.
+///
+/// # Safety
+///
+/// `db` has to come from `redo_log_open` and not have been closed, `key` has
+/// to point to `key_len` readable bytes, and `value` and `value_len` have to
+/// be pointers that can be written to. `err` can be null.
+#[no_mangle]
+pub unsafe extern "C" fn redo_log_get(
+    db: *mut RedoLogDb,
+    key: *const c_char,
+    key_len: usize,
+    value: *mut *mut c_char,
+    value_len: *mut usize,
+    err: *mut *mut c_char,
+) -> c_int {
+    guard(err, || {
+        let db = handle(db)?;
+        let key = text(key, key_len)?;
+        if value.is_null() || value_len.is_null() {
+            return Err("value and value_len can't be null".into());
+        }
+        match db.db.try_get(key).map_err(|e| e.to_string())? {
+            Some(found) => {
+                *value = copy(found.as_bytes());
+                *value_len = found.len();
+                Ok(REDO_LOG_OK)
+            }
+            None => Ok(REDO_LOG_NOT_FOUND),
+        }
+    })
+}
+
+/// Deletes `key`, returning once the delete is durable. Deleting a key that
+/// isn't there succeeds.
+///
+/// # Safety
+///
+/// `db` has to come from `redo_log_open` and not have been closed, and `key`
+/// has to point to `key_len` readable bytes. `err` can be null.
+#[no_mangle]
+pub unsafe extern "C" fn redo_log_delete(
+    db: *mut RedoLogDb,
+    key: *const c_char,
+    key_len: usize,
+    err: *mut *mut c_char,
+) -> c_int {
+    guard(err, || {
+        let db = handle(db)?;
+        let key = text(key, key_len)?;
+        db.db.clone().delete(key).map_err(|e| e.to_string())?;
+        Ok(REDO_LOG_OK)
+    })
+}
+
+/// Waits until every write so far is durable.
+///
+/// # Safety
+///
+/// `db` has to come from `redo_log_open` and not have been closed. `err` can
+/// be null.
+#[no_mangle]
+pub unsafe extern "C" fn redo_log_sync(db: *mut RedoLogDb, err: *mut *mut c_char) -> c_int {
+    guard(err, || {
+        handle(db)?.db.sync().map_err(|e| e.to_string())?;
+        Ok(REDO_LOG_OK)
+    })
+}
+
+/// Syncs and closes the database, and frees `db`, which can't be used again
+/// whether this succeeds or not. A null `db` is ignored.
+///
+/// # Safety
+///
+/// `db` has to be null or come from `redo_log_open` and not have been closed
+/// already, and no other call with it can still be running. `err` can be
+/// null.
+#[no_mangle]
+pub unsafe extern "C" fn redo_log_close(db: *mut RedoLogDb, err: *mut *mut c_char) -> c_int {
+    guard(err, || {
+        if db.is_null() {
+            return Ok(REDO_LOG_OK);
+        }
+        let db = Box::from_raw(db);
+        db.db.close().map_err(|e| e.to_string())?;
+        Ok(REDO_LOG_OK)
+    })
+}
+
+/// Frees a value or error message returned by one of the other functions. A
+/// null `p` is ignored.
+///
+/// # Safety
+///
+/// `p` has to be null or have come from this library, and not have been freed
+/// already.
+#[no_mangle]
+pub unsafe extern "C" fn redo_log_free(p: *mut c_char) {
+    libc::free(p.cast());
+}
+
+// Runs `f`, turning an error or a panic into `REDO_LOG_ERROR` and a message
+// in `err`.
+unsafe fn guard<F>(err: *mut *mut c_char, f: F) -> c_int
+where
+    F: FnOnce() -> Result<c_int, String>,
+{
+    let message = match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(status)) => return status,
+        Ok(Err(message)) => message,
+        Err(panic) => match panic.downcast_ref::<&str>() {
+            Some(s) => format!("panicked: {}", s),
+            None => match panic.downcast_ref::<String>() {
+                Some(s) => format!("panicked: {}", s),
+                None => "panicked".into(),
+            },
+        },
+    };
+    if !err.is_null() {
+        *err = copy(message.as_bytes());
+    }
+    REDO_LOG_ERROR
+}
+
+// Handles are shared between threads, so they're only ever borrowed, and
+// writes go through a clone of the `Db`, which shares everything with it.
+unsafe fn handle<'a>(db: *mut RedoLogDb) -> Result<&'a RedoLogDb, String> {
+    db.as_ref().ok_or_else(|| "db can't be null".into())
+}
+
+unsafe fn text<'a>(p: *const c_char, len: usize) -> Result<&'a str, String> {
+    if len == 0 {
+        return Ok("");
+    }
+    if p.is_null() {
+        return Err("a key or value can't be null unless it's empty".into());
+    }
+    std::str::from_utf8(slice::from_raw_parts(p.cast(), len))
+        .map_err(|_| "keys and values have to be UTF-8".into())
+}
+
+// A NUL-terminated copy of `bytes` from `malloc`, for `redo_log_free` to free.
+unsafe fn copy(bytes: &[u8]) -> *mut c_char {
+    let p = libc::malloc(bytes.len() + 1).cast::<u8>();
+    if p.is_null() {
+        std::alloc::handle_alloc_error(std::alloc::Layout::array::<u8>(bytes.len() + 1).unwrap());
+    }
+    ptr::copy_nonoverlapping(bytes.as_ptr(), p, bytes.len());
+    *p.add(bytes.len()) = 0;
+    p.cast()
+}
+
+#[cfg(test)]
+fn header() -> String {
+    let dir = env!("CARGO_MANIFEST_DIR");
+    let config = cbindgen::Config {
+        language: cbindgen::Language::C,
+        include_guard: Some("REDO_LOG_H".into()),
+        header: Some(
+            "// Generated from ffi/src/lib.rs by cbindgen, in `test_header`.\n\
+             // Run REDO_LOG_BLESS=1 cargo test -p redo-log-ffi to update it."
+                .into(),
+        ),
+        cpp_compat: true,
+        documentation_style: cbindgen::DocumentationStyle::C99,
+        ..cbindgen::Config::default()
+    };
+    let mut out = vec![];
+    cbindgen::Builder::new()
+        .with_crate(dir)
+        .with_config(config)
+        .generate()
+        .expect("generating the header")
+        .write(&mut out);
+    String::from_utf8(out).unwrap()
+}
+
+#[test]
+fn test_header() {
+    let path = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("redo_log.h");
+    let generated = header();
+    if std::env::var_os("REDO_LOG_BLESS").is_some() {
+        std::fs::write(&path, &generated).unwrap();
+    }
+    let checked_in = std::fs::read_to_string(&path).unwrap_or_default();
+    assert!(
+        checked_in == generated,
+        "redo_log.h is out of date; run REDO_LOG_BLESS=1 cargo test -p redo-log-ffi"
+    );
+}
+
+#[test]
+fn test_ffi() -> Result<(), Box<dyn std::error::Error>> {
+    use std::ffi::CString;
+
+    let dir = tempfile::tempdir()?;
+    let path = CString::new(dir.path().join("db").to_str().unwrap())?;
+    unsafe {
+        let mut db = ptr::null_mut();
+        let mut err = ptr::null_mut();
+        assert_eq!(redo_log_open(path.as_ptr(), &mut db, &mut err), REDO_LOG_OK);
+        let key = "key";
+        let value = "value\0with a NUL";
+        let set = redo_log_set(
+            db,
+            key.as_ptr().cast(),
+            key.len(),
+            value.as_ptr().cast(),
+            value.len(),
+            &mut err,
+        );
+        assert_eq!(set, REDO_LOG_OK);
+        assert_eq!(redo_log_sync(db, &mut err), REDO_LOG_OK);
+
+        let (mut found, mut found_len) = (ptr::null_mut(), 0);
+        let get = |db, found: &mut *mut c_char, found_len: &mut usize| {
+            redo_log_get(
+                db,
+                key.as_ptr().cast(),
+                key.len(),
+                found,
+                found_len,
+                ptr::null_mut(),
+            )
+        };
+        assert_eq!(get(db, &mut found, &mut found_len), REDO_LOG_OK);
+        assert_eq!(
+            slice::from_raw_parts(found.cast::<u8>(), found_len),
+            value.as_bytes()
+        );
+        assert_eq!(*found.add(found_len), 0);
+        redo_log_free(found);
+
+        // It's still there once reopened, and gone once deleted.
+        assert_eq!(redo_log_close(db, &mut err), REDO_LOG_OK);
+        assert_eq!(redo_log_open(path.as_ptr(), &mut db, &mut err), REDO_LOG_OK);
+        assert_eq!(get(db, &mut found, &mut found_len), REDO_LOG_OK);
+        redo_log_free(found);
+        let delete = redo_log_delete(db, key.as_ptr().cast(), key.len(), &mut err);
+        assert_eq!(delete, REDO_LOG_OK);
+        assert_eq!(get(db, &mut found, &mut found_len), REDO_LOG_NOT_FOUND);
+
+        // Errors come back as messages.
+        let bad = [0xff_u8];
+        let set = redo_log_set(db, bad.as_ptr().cast(), 1, bad.as_ptr().cast(), 1, &mut err);
+        assert_eq!(set, REDO_LOG_ERROR);
+        assert_eq!(
+            CStr::from_ptr(err).to_str()?,
+            "keys and values have to be UTF-8"
+        );
+        redo_log_free(err);
+        err = ptr::null_mut();
+        let mut other = ptr::null_mut();
+        assert_eq!(
+            redo_log_open(path.as_ptr(), &mut other, &mut err),
+            REDO_LOG_ERROR
+        );
+        assert!(!err.is_null() && other.is_null());
+        redo_log_free(err);
+
+        assert_eq!(redo_log_close(db, ptr::null_mut()), REDO_LOG_OK);
+        assert_eq!(
+            redo_log_close(ptr::null_mut(), ptr::null_mut()),
+            REDO_LOG_OK
+        );
+    }
+    Ok(())
+}
+
+#[test]
+fn test_ffi_threads() -> Result<(), Box<dyn std::error::Error>> {
+    use std::ffi::CString;
+
+    let dir = tempfile::tempdir()?;
+    let path = CString::new(dir.path().join("db").to_str().unwrap())?;
+    let mut db = ptr::null_mut();
+    assert_eq!(
+        unsafe { redo_log_open(path.as_ptr(), &mut db, ptr::null_mut()) },
+        REDO_LOG_OK
+    );
+    // Pointers aren't `Send`, so the handle crosses over as an address.
+    let addr = db as usize;
+    let threads = (0..4)
+        .map(|t| {
+            std::thread::spawn(move || unsafe {
+                let db = addr as *mut RedoLogDb;
+                for i in 0..50 {
+                    let key = format!("{}/{}", t, i);
+                    let set = redo_log_set(
+                        db,
+                        key.as_ptr().cast(),
+                        key.len(),
+                        key.as_ptr().cast(),
+                        key.len(),
+                        ptr::null_mut(),
+                    );
+                    assert_eq!(set, REDO_LOG_OK);
+                    let (mut found, mut found_len) = (ptr::null_mut(), 0);
+                    let get = redo_log_get(
+                        db,
+                        key.as_ptr().cast(),
+                        key.len(),
+                        &mut found,
+                        &mut found_len,
+                        ptr::null_mut(),
+                    );
+                    assert_eq!(get, REDO_LOG_OK);
+                    assert_eq!(
+                        slice::from_raw_parts(found.cast::<u8>(), found_len),
+                        key.as_bytes()
+                    );
+                    redo_log_free(found);
+                    if i % 2 == 0 {
+                        let delete =
+                            redo_log_delete(db, key.as_ptr().cast(), key.len(), ptr::null_mut());
+                        assert_eq!(delete, REDO_LOG_OK);
+                    }
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+    unsafe {
+        assert_eq!(redo_log_close(db, ptr::null_mut()), REDO_LOG_OK);
+        assert_eq!(
+            redo_log_open(path.as_ptr(), &mut db, ptr::null_mut()),
+            REDO_LOG_OK
+        );
+        assert_eq!((*db).db.scan("").len(), 100);
+        assert_eq!(redo_log_close(db, ptr::null_mut()), REDO_LOG_OK);
+    }
+    Ok(())
+}
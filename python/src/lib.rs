@@ -0,0 +1,10 @@
+// The `redo_log` Python extension module: `pip install ./python`, or
+// `maturin develop` in this directory. Everything in it comes from
+// `redo_log::python`.
+use pyo3::prelude::*;
+
+#[pymodule]
+#[pyo3(name = "redo_log")]
+fn init(module: &Bound<'_, PyModule>) -> PyResult<()> {
+    redo_log::python::register(module)
+}
@@ -1,56 +1,25 @@
+// Runs the store in `stores/bad_1.rs` on its own. `compare` pulls in the same
+// module to run it alongside the others.
 use anyhow::Result;
-use std::{
-    collections::HashMap,
-    path::{Path, PathBuf},
-};
+#[cfg(test)]
+use std::fs;
+#[cfg(test)]
 use tempfile::tempdir;
 
-struct Db {
-    data: HashMap<String, String>,
-    fname: PathBuf,
-}
-
-impl Db {
-    fn new<P>(f: P) -> Result<Self>
-    where
-        P: AsRef<Path>,
-    {
-        let data = std::fs::read_to_string(&f)
-            .and_then(|v| Ok(serde_json::from_str(v.as_str())?))
-            .unwrap_or_else(|_| HashMap::new());
-        Ok(Db {
-            data,
-            fname: f.as_ref().to_path_buf(),
-        })
-    }
+#[path = "stores/bad_1.rs"]
+mod store;
 
-    fn set(&mut self, k: &str, v: &str) {
-        self.data.insert(k.to_owned(), v.to_owned());
-    }
-
-    fn delete(&mut self, k: &str) {
-        self.data.remove(k);
-    }
-
-    fn get(&self, k: &str) -> Option<&String> {
-        self.data.get(k)
-    }
-
-    fn flush(&self) -> Result<()> {
-        std::fs::write(&self.fname, serde_json::to_vec(&self.data)?)?;
-        Ok(())
-    }
-}
+use store::Db;
+#[cfg(test)]
+use store::Options;
 
 fn main() -> Result<()> {
     let mut db = Db::new("db_data")?;
     println!("value of abc is {:?}", db.get("abc"));
-    db.set("abc", "def");
+    db.set("abc", "def")?;
     println!("value of abc is {:?}", db.get("abc"));
+    // Unwinding drops `db`, which flushes it.
     panic!("");
-
-    db.flush()?;
-    Ok(())
 }
 
 #[test]
@@ -59,10 +28,10 @@ fn test_basic() -> Result<()> {
     let file = dir.path().to_path_buf().join("data");
 
     let mut db = Db::new(&file)?;
-    db.set("foo", "bar");
-    db.set("baz", "goo");
+    db.set("foo", "bar")?;
+    db.set("baz", "goo")?;
     assert_eq!(db.get("foo"), Some(&"bar".into()));
-    db.delete("foo");
+    db.delete("foo")?;
     assert_eq!(db.get("foo"), None);
 
     Ok(())
@@ -74,16 +43,50 @@ fn test_recover() -> Result<()> {
     let file = dir.path().to_path_buf().join("data");
 
     let mut db = Db::new(&file)?;
-    db.set("foo", "bar");
-    db.set("baz", "goo");
+    db.set("foo", "bar")?;
+    db.set("baz", "goo")?;
     assert_eq!(db.get("foo"), Some(&"bar".into()));
-    db.delete("foo");
+    db.delete("foo")?;
     assert_eq!(db.get("foo"), None);
 
-    db.flush();
+    db.flush()?;
 
     let db = Db::new(&file)?;
     assert_eq!(db.get("baz"), Some(&"goo".into()));
 
     Ok(())
 }
+
+#[test]
+fn test_flush_options() -> Result<()> {
+    let dir = tempdir()?;
+    let file = dir.path().to_path_buf().join("data");
+    let options = Options {
+        flush_every_n_ops: Some(3),
+        flush_on_drop: false,
+    };
+
+    // Only every third write is snapshotted, and dropping doesn't add any.
+    let mut db = Db::with_options(&file, options)?;
+    for i in 0..5 {
+        db.set(&format!("k{}", i), "v")?;
+    }
+    drop(db);
+    let db = Db::new(&file)?;
+    assert_eq!(db.data.len(), 3);
+    drop(db);
+
+    // A crash partway through a snapshot leaves the old one in place.
+    fs::write(dir.path().join("data.tmp"), "{\"torn")?;
+    let mut db = Db::new(&file)?;
+    assert_eq!(db.data.len(), 3);
+    db.set("k3", "v")?;
+    drop(db);
+    assert_eq!(Db::new(&file)?.data.len(), 4);
+
+    // A snapshot that's damaged some other way isn't taken for an empty one.
+    fs::write(&file, "{\"torn")?;
+    assert!(Db::new(&file).is_err());
+
+    Ok(())
+}
@@ -1,75 +1,13 @@
+// Runs the store in `stores/basic.rs` on its own. `compare` pulls in the same
+// module to run it alongside the others.
 use anyhow::Result;
-use serde::{Deserialize, Serialize};
-use std::{
-    fs::{File, OpenOptions},
-    io::{BufRead, BufReader, Write},
-    path::{Path, PathBuf},
-    time::{Duration, Instant},
-};
+use std::time::{Duration, Instant};
 use tempfile::tempdir;
 
-#[derive(Debug)]
-struct Db {
-    log: File,
-    fname: PathBuf,
-}
-
-#[derive(Serialize, Deserialize)]
-enum Command<'a> {
-    Set(&'a str, &'a str),
-    Delete(&'a str),
-}
-
-impl Db {
-    fn new<P>(f: P) -> Result<Self>
-    where
-        P: AsRef<Path>,
-    {
-        let log = OpenOptions::new().create(true).append(true).open(&f)?;
-        log.sync_all()?;
-        Ok(Db {
-            log,
-            fname: f.as_ref().to_path_buf(),
-        })
-    }
-
-    fn apply_command(&mut self, command: &Command) -> Result<()> {
-        self.log.write_all(&serde_json::to_vec(command)?)?;
-        self.log.write_all(b"\n")?;
-        self.log.sync_all()?;
-        Ok(())
-    }
+#[path = "stores/basic.rs"]
+mod store;
 
-    fn set(&mut self, k: &str, v: &str) -> Result<()> {
-        self.apply_command(&Command::Set(k, v))?;
-        Ok(())
-    }
-
-    fn delete(&mut self, k: &str) -> Result<()> {
-        self.apply_command(&Command::Delete(k))?;
-        Ok(())
-    }
-
-    fn get(&self, k: &str) -> Result<Option<String>> {
-        let file = BufReader::new(File::open(&self.fname)?);
-        let mut result = None;
-        for line in file.lines() {
-            match serde_json::from_str(&line?)? {
-                Command::Set(new_k, v) => {
-                    if k == new_k {
-                        result = Some(v.to_owned());
-                    }
-                }
-                Command::Delete(new_k) => {
-                    if k == new_k {
-                        result = None;
-                    }
-                }
-            }
-        }
-        Ok(result)
-    }
-}
+use store::Db;
 
 fn main() -> Result<()> {
     let dir = tempdir()?;
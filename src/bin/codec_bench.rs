@@ -0,0 +1,90 @@
+// Measures how quickly commands are encoded and decoded in the binary format
+// the log writes by default, against JSON, and how much room each takes.
+use anyhow::{bail, Result};
+use clap::Parser;
+use redo_log::{binary, Command, IdempotencyKey};
+use std::{hint::black_box, time::Instant};
+
+#[derive(Parser)]
+#[command(name = "codec_bench")]
+struct Args {
+    /// How many commands to encode and decode, of each kind.
+    #[arg(long, default_value_t = 100_000)]
+    commands: usize,
+    /// How long each value is, in bytes.
+    #[arg(long, default_value_t = 16)]
+    value_len: usize,
+}
+
+fn commands(n: usize, value_len: usize) -> Vec<Command> {
+    let value = "v".repeat(value_len);
+    (0..n)
+        .flat_map(|i| {
+            let k = format!("key{}", i);
+            [
+                Command::Set(k.clone(), value.clone()),
+                Command::Delete(k.clone()),
+                Command::SetExpiring(k.clone(), value.clone(), 1_700_000_000_000 + i as u64),
+                Command::Incr(k.clone(), 1, i as i64),
+                Command::Request(
+                    IdempotencyKey {
+                        client_id: 7,
+                        request_id: i as u64,
+                    },
+                    Box::new(Command::Set(k, value.clone())),
+                ),
+            ]
+        })
+        .collect()
+}
+
+fn measure(
+    name: &str,
+    commands: &[Command],
+    encode: impl Fn(&Command) -> Result<Vec<u8>>,
+    decode: impl Fn(&[u8]) -> Result<Command>,
+) -> Result<()> {
+    let start = Instant::now();
+    let encoded = commands.iter().map(encode).collect::<Result<Vec<_>>>()?;
+    let encode_secs = start.elapsed().as_secs_f64();
+
+    let start = Instant::now();
+    for payload in &encoded {
+        black_box(decode(payload)?);
+    }
+    let decode_secs = start.elapsed().as_secs_f64();
+
+    let bytes = encoded.iter().map(Vec::len).sum::<usize>();
+    let n = commands.len() as f64;
+    println!(
+        "{:>6}: {:>5.1} bytes/command, encode {:>6.0} ns/command ({:>6.1} MB/s), decode {:>6.0} ns/command ({:>6.1} MB/s)",
+        name,
+        bytes as f64 / n,
+        encode_secs * 1e9 / n,
+        bytes as f64 / encode_secs / 1e6,
+        decode_secs * 1e9 / n,
+        bytes as f64 / decode_secs / 1e6,
+    );
+    Ok(())
+}
+
+fn main() -> Result<()> {
+    let args = Args::parse();
+    if args.commands == 0 {
+        bail!("--commands must be at least 1");
+    }
+    let commands = commands(args.commands, args.value_len);
+    measure(
+        "json",
+        &commands,
+        |c| Ok(serde_json::to_vec(c)?),
+        |p| Ok(serde_json::from_slice(p)?),
+    )?;
+    measure(
+        "binary",
+        &commands,
+        |c| Ok(binary::to_vec(c)?),
+        |p| Ok(binary::from_slice(p)?),
+    )?;
+    Ok(())
+}
@@ -0,0 +1,128 @@
+// Runs the same workload against `Db` and against each of the experiments in
+// this directory, through `KvStore`, and prints a table of how they compare.
+//
+// The experiments' stores are pulled in from `stores`, which their own
+// binaries run on their own and test.
+use anyhow::{bail, Result};
+use clap::Parser;
+use redo_log::{Buckets, Db, Histogram, KvStore, StoreResult};
+use std::{
+    path::{Path, PathBuf},
+    time::Instant,
+};
+use tempfile::tempdir;
+
+#[path = "stores/add_memtable.rs"]
+mod add_memtable;
+#[path = "stores/bad_1.rs"]
+mod bad_1;
+#[path = "stores/basic.rs"]
+mod basic;
+#[path = "stores/concurrent_2.rs"]
+mod concurrent_2;
+#[path = "stores/with_panic.rs"]
+mod with_panic;
+
+#[derive(Parser)]
+#[command(name = "compare")]
+struct Args {
+    /// How many writes, and then reads, to make against each store.
+    #[arg(long, default_value_t = 1000)]
+    ops: u64,
+    /// Where to put the stores, instead of a temporary directory.
+    #[arg(long)]
+    dir: Option<PathBuf>,
+}
+
+// How one store did.
+struct Row {
+    name: &'static str,
+    // In nanoseconds.
+    writes: Buckets,
+    reads: Buckets,
+    write_secs: f64,
+    read_secs: f64,
+    // Whether the last write was still there after reopening the store, and
+    // after syncing and then reopening it.
+    reopened: bool,
+    synced: bool,
+}
+
+fn main() -> Result<()> {
+    let args = Args::parse();
+    if args.ops == 0 {
+        bail!("--ops must be at least 1");
+    }
+    let tmp = tempdir()?;
+    let dir = args.dir.as_deref().unwrap_or(tmp.path());
+    std::fs::create_dir_all(dir)?;
+    let ops = args.ops;
+    let rows = [
+        run::<Db>("Db", &dir.join("db"), ops),
+        run::<add_memtable::Db>("add_memtable", &dir.join("add_memtable"), ops),
+        run::<bad_1::Db>("bad_1", &dir.join("bad_1"), ops),
+        run::<basic::Db>("basic", &dir.join("basic"), ops),
+        run::<concurrent_2::Db>("concurrent_2", &dir.join("concurrent_2"), ops),
+        run::<with_panic::Db>("with_panic", &dir.join("with_panic"), ops),
+    ];
+
+    println!(
+        "{:<14} {:>10} {:>14} {:>10} {:>14} {:>9} {:>11}",
+        "store", "writes/s", "write p99 <=", "reads/s", "read p99 <=", "reopened", "after sync"
+    );
+    for row in rows {
+        let row = row.map_err(|e| anyhow::anyhow!(e))?;
+        let p99 = |b: &Buckets| format!("{:?}", std::time::Duration::from_nanos(b.quantile(0.99)));
+        let yes = |b| if b { "kept" } else { "lost" };
+        println!(
+            "{:<14} {:>10.0} {:>14} {:>10.0} {:>14} {:>9} {:>11}",
+            row.name,
+            row.writes.count() as f64 / row.write_secs,
+            p99(&row.writes),
+            row.reads.count() as f64 / row.read_secs,
+            p99(&row.reads),
+            yes(row.reopened),
+            yes(row.synced),
+        );
+    }
+    Ok(())
+}
+
+// Writes `ops` keys to a fresh store at `path`, reads them all back, and then
+// checks what survives reopening it.
+fn run<S: KvStore>(name: &'static str, path: &Path, ops: u64) -> StoreResult<Row> {
+    let key = |i: u64| format!("key{}", i);
+    let (writes, reads) = (Histogram::default(), Histogram::default());
+    let mut store = S::open(path)?;
+    let start = Instant::now();
+    for i in 0..ops {
+        let op = Instant::now();
+        store.set(&key(i), "value")?;
+        writes.record(op.elapsed().as_nanos() as u64);
+    }
+    let write_secs = start.elapsed().as_secs_f64();
+    let start = Instant::now();
+    for i in 0..ops {
+        let op = Instant::now();
+        store.get(&key(i))?;
+        reads.record(op.elapsed().as_nanos() as u64);
+    }
+    let read_secs = start.elapsed().as_secs_f64();
+
+    drop(store);
+    let mut store = S::open(path)?;
+    let reopened = store.get(&key(ops - 1))?.is_some();
+    store.set("synced", "value")?;
+    store.sync()?;
+    drop(store);
+    let synced = S::open(path)?.get("synced")?.is_some();
+    Ok(Row {
+        name,
+        writes: writes.snapshot(),
+        reads: reads.snapshot(),
+        write_secs,
+        read_secs,
+        reopened,
+        synced,
+    })
+}
@@ -0,0 +1,142 @@
+// Measures how many reads `Db` serves while another thread writes as fast as
+// it can, for each kind of memtable in turn unless `--kind` picks one.
+// Readers share the memtable with each other, so they only wait on a batch
+// being applied to it, never on one another or on the log, and with
+// `--shards`, only on batches that write to the shard they're reading. With
+// the concurrent kind, they don't wait on a batch at all unless it's writing
+// the key they're reading.
+use anyhow::{bail, Result};
+use clap::{Parser, ValueEnum};
+use redo_log::{Db, Durability, MemtableKind, Options};
+use std::{
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+use tempfile::tempdir;
+
+#[derive(Parser)]
+#[command(name = "read_bench")]
+struct Args {
+    /// How many threads to read with.
+    #[arg(long, default_value_t = 4)]
+    readers: usize,
+    /// How many keys to write before starting, and then to read and rewrite.
+    #[arg(long, default_value_t = 10_000)]
+    keys: u64,
+    /// How long to run for, in seconds.
+    #[arg(long, default_value_t = 5)]
+    secs: u64,
+    /// How the memtable keeps its keys, instead of trying every kind.
+    #[arg(long, value_enum)]
+    kind: Option<Kind>,
+    /// How many shards to split the memtable into.
+    #[arg(long, default_value_t = 1)]
+    shards: usize,
+    /// Where to put the database, instead of a temporary directory.
+    #[arg(long)]
+    dir: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum Kind {
+    Hash,
+    FxHash,
+    Ordered,
+    Concurrent,
+}
+
+fn main() -> Result<()> {
+    let args = Args::parse();
+    if args.readers == 0 || args.keys == 0 {
+        bail!("--readers and --keys must be at least 1");
+    }
+    let kinds = match args.kind {
+        Some(kind) => vec![kind],
+        None => Kind::value_variants().to_vec(),
+    };
+    for kind in kinds {
+        let (reads, writes) = run(&args, kind)?;
+        println!(
+            "{:?}, {} readers: {:.0} reads/s ({:.0} per reader), alongside {:.0} writes/s",
+            kind,
+            args.readers,
+            reads,
+            reads / args.readers as f64,
+            writes,
+        );
+    }
+    Ok(())
+}
+
+// Reads and writes a second with the memtable kept in `kind`.
+fn run(args: &Args, kind: Kind) -> Result<(f64, f64)> {
+    let tmp = tempdir()?;
+    let dir = match &args.dir {
+        Some(dir) => dir.join(format!("{:?}", kind).to_lowercase()),
+        None => tmp.path().join("db"),
+    };
+    let options = Options {
+        // The writer is there to keep the memtable busy, not the disk.
+        durability: Durability::None,
+        memtable: match kind {
+            Kind::Hash => MemtableKind::Hash,
+            Kind::FxHash => MemtableKind::FxHash,
+            Kind::Ordered => MemtableKind::Ordered,
+            Kind::Concurrent => MemtableKind::Concurrent,
+        },
+        memtable_shards: args.shards,
+        ..Options::default()
+    };
+    let mut db = Db::with_options(&dir, options)?;
+    for i in 0..args.keys {
+        db.set(&format!("key{}", i), "v")?;
+    }
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let (reads, writes) = (Arc::new(AtomicU64::new(0)), Arc::new(AtomicU64::new(0)));
+    let keys = args.keys;
+    let mut threads = vec![];
+    {
+        let (mut db, stop, writes) = (db.clone(), stop.clone(), writes.clone());
+        threads.push(thread::spawn(move || -> Result<()> {
+            let mut i = 0;
+            while !stop.load(Ordering::Relaxed) {
+                db.set(&format!("key{}", i % keys), &format!("v{}", i))?;
+                writes.fetch_add(1, Ordering::Relaxed);
+                i += 1;
+            }
+            Ok(())
+        }));
+    }
+    for r in 0..args.readers as u64 {
+        let (db, stop, reads) = (db.clone(), stop.clone(), reads.clone());
+        threads.push(thread::spawn(move || -> Result<()> {
+            let mut i = r;
+            while !stop.load(Ordering::Relaxed) {
+                if db.get(&format!("key{}", i % keys)).is_none() {
+                    bail!("key{} went missing", i % keys);
+                }
+                reads.fetch_add(1, Ordering::Relaxed);
+                i += 7919;
+            }
+            Ok(())
+        }));
+    }
+
+    let start = Instant::now();
+    thread::sleep(Duration::from_secs(args.secs));
+    stop.store(true, Ordering::Relaxed);
+    for thread in threads {
+        thread.join().expect("benchmark thread panicked")?;
+    }
+    let secs = start.elapsed().as_secs_f64();
+    Ok((
+        reads.load(Ordering::Relaxed) as f64 / secs,
+        writes.load(Ordering::Relaxed) as f64 / secs,
+    ))
+}
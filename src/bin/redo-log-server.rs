@@ -0,0 +1,218 @@
+use anyhow::Result;
+use axum::{
+    extract::{Path, Query, State},
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    routing::get,
+    Json, Router,
+};
+use clap::{Parser, ValueEnum};
+use redo_log::Db;
+use serde::Deserialize;
+use std::{net::SocketAddr, path::PathBuf};
+
+// Serves a database over HTTP, or over the Redis protocol with
+// `--protocol resp` (see `redo_log::resp`). The HTTP routes are:
+//
+//   GET    /keys/{key}       the value, or 404
+//   PUT    /keys/{key}       sets the key to the request body
+//   DELETE /keys/{key}
+//   GET    /scan?prefix=...  every matching key and value, in key order
+//   GET    /stats
+//   GET    /metrics          the same and more, for Prometheus to scrape
+//
+// `--no-metrics` leaves out `/metrics`. Failed requests get a 500, unless
+// it's a write over its namespace's quota (507), to a read-only database
+// (405), or that conflicted with another (409), or the server is overloaded
+// or shutting down (503, with Retry-After when it's worth trying again).
+#[derive(Parser)]
+#[command(name = "redo-log-server")]
+struct Args {
+    dir: PathBuf,
+    #[arg(long, default_value = "127.0.0.1:8080")]
+    addr: SocketAddr,
+    #[arg(long, value_enum, default_value_t = Protocol::Http)]
+    protocol: Protocol,
+    #[arg(long)]
+    no_metrics: bool,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Protocol {
+    Http,
+    Resp,
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args = Args::parse();
+    let db = Db::new(&args.dir)?;
+    let listener = tokio::net::TcpListener::bind(args.addr).await?;
+    println!("listening on {}", listener.local_addr()?);
+    if let Protocol::Resp = args.protocol {
+        return Ok(redo_log::resp::serve(db, listener).await?);
+    }
+    let mut app = Router::new()
+        .route("/keys/{key}", get(get_key).put(put_key).delete(delete_key))
+        .route("/scan", get(scan))
+        .route("/stats", get(stats));
+    if !args.no_metrics {
+        app = app.route("/metrics", get(metrics));
+    }
+    let app = app.with_state(db);
+    axum::serve(listener, app).await?;
+    Ok(())
+}
+
+// Writes block until the batch they end up in has been synced, so they run on
+// the blocking pool rather than tying up the runtime.
+async fn blocking<F, T>(f: F) -> Result<T, Error>
+where
+    F: FnOnce() -> redo_log::Result<T> + Send + 'static,
+    T: Send + 'static,
+{
+    Ok(tokio::task::spawn_blocking(f)
+        .await
+        .map_err(anyhow::Error::from)??)
+}
+
+struct Error(anyhow::Error);
+
+impl From<anyhow::Error> for Error {
+    fn from(e: anyhow::Error) -> Self {
+        Error(e)
+    }
+}
+
+impl From<redo_log::Error> for Error {
+    fn from(e: redo_log::Error) -> Self {
+        Error(e.into())
+    }
+}
+
+impl IntoResponse for Error {
+    fn into_response(self) -> Response {
+        let message = format!("{:#}", self.0);
+        match self.0.downcast_ref::<redo_log::Error>() {
+            // Nothing was written, and trying again soon might get in.
+            Some(redo_log::Error::Overloaded { .. }) => (
+                StatusCode::SERVICE_UNAVAILABLE,
+                [(header::RETRY_AFTER, "1")],
+                message,
+            )
+                .into_response(),
+            Some(e) => (status(e), message).into_response(),
+            None => (StatusCode::INTERNAL_SERVER_ERROR, message).into_response(),
+        }
+    }
+}
+
+// What to tell the client went wrong, for the errors that are down to the
+// request, or to the server being busy or on its way down, rather than broken.
+fn status(e: &redo_log::Error) -> StatusCode {
+    match e {
+        redo_log::Error::QuotaExceeded { .. } => StatusCode::INSUFFICIENT_STORAGE,
+        redo_log::Error::ReadOnly => StatusCode::METHOD_NOT_ALLOWED,
+        redo_log::Error::Conflict { .. } => StatusCode::CONFLICT,
+        redo_log::Error::Overloaded { .. } | redo_log::Error::Closed => {
+            StatusCode::SERVICE_UNAVAILABLE
+        }
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+async fn get_key(State(db): State<Db>, Path(key): Path<String>) -> Result<Response, Error> {
+    Ok(match db.try_get(&key)? {
+        Some(v) => v.into_response(),
+        None => StatusCode::NOT_FOUND.into_response(),
+    })
+}
+
+async fn put_key(
+    State(mut db): State<Db>,
+    Path(key): Path<String>,
+    value: String,
+) -> Result<StatusCode, Error> {
+    blocking(move || db.set(&key, &value)).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn delete_key(
+    State(mut db): State<Db>,
+    Path(key): Path<String>,
+) -> Result<StatusCode, Error> {
+    blocking(move || db.delete(&key)).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Deserialize)]
+struct ScanParams {
+    #[serde(default)]
+    prefix: String,
+}
+
+async fn scan(
+    State(db): State<Db>,
+    Query(params): Query<ScanParams>,
+) -> Result<Json<serde_json::Value>, Error> {
+    let entries = db
+        .try_scan(&params.prefix)?
+        .into_iter()
+        .map(|(k, v)| serde_json::json!({ "key": k, "value": v }))
+        .collect();
+    Ok(Json(serde_json::Value::Array(entries)))
+}
+
+// The key count is the estimate `Db::stats` keeps, since counting them
+// exactly would mean reading every table.
+async fn stats(State(db): State<Db>) -> Result<Json<serde_json::Value>, Error> {
+    let stats = db.stats()?;
+    let metrics = db.metrics();
+    Ok(Json(serde_json::json!({
+        "keys": stats.estimated_live_keys,
+        "batches": metrics.batches,
+        "commands": metrics.commands,
+        "bytes": metrics.bytes,
+        "largest_batch": metrics.largest_batch,
+        "mean_batch_size": metrics.mean_batch_size(),
+        "commit_time_secs": metrics.commit_time.as_secs_f64(),
+        "replay_time_secs": metrics.replay_time.as_secs_f64(),
+        "block_cache_hits": metrics.block_cache_hits,
+        "block_cache_misses": metrics.block_cache_misses,
+    })))
+}
+
+async fn metrics(State(db): State<Db>) -> impl IntoResponse {
+    (
+        [(
+            axum::http::header::CONTENT_TYPE,
+            "text/plain; version=0.0.4",
+        )],
+        db.metrics().to_prometheus(),
+    )
+}
+
+#[test]
+fn test_status() {
+    let quota = redo_log::Error::QuotaExceeded {
+        namespace: "ns".into(),
+        bytes: 1,
+        keys: 1,
+    };
+    assert_eq!(status(&quota), StatusCode::INSUFFICIENT_STORAGE);
+    assert_eq!(
+        status(&redo_log::Error::ReadOnly),
+        StatusCode::METHOD_NOT_ALLOWED
+    );
+    assert_eq!(
+        status(&redo_log::Error::Closed),
+        StatusCode::SERVICE_UNAVAILABLE
+    );
+
+    let response = Error::from(redo_log::Error::Overloaded { pending: 8 }).into_response();
+    assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(response.headers()[header::RETRY_AFTER], "1");
+    let io = std::io::Error::other("disk on fire");
+    let response = Error::from(redo_log::Error::from(io)).into_response();
+    assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+}
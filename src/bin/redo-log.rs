@@ -0,0 +1,787 @@
+use anyhow::{anyhow, bail, Result};
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use redo_log::{
+    codec,
+    segment::{self, SegmentReader},
+    Command, Db, DbListener, Durability, Format, Histogram, LogReader, Options, RecordCodec, Rng,
+    SlowSync, WriteStall,
+};
+use std::{
+    collections::HashMap,
+    fs,
+    io::{self, Write},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+// Tools for poking at a log directory without writing a program against the
+// library.
+#[derive(Parser)]
+#[command(name = "redo-log")]
+struct Cli {
+    #[command(subcommand)]
+    command: Cmd,
+}
+
+#[derive(Subcommand)]
+enum Cmd {
+    /// Print every record as a line of JSON.
+    Dump { dir: PathBuf },
+    /// Check every segment's structure and checksums, reporting where any
+    /// corruption starts.
+    Verify { dir: PathBuf },
+    /// Print record counts, live and dead keys, and sizes.
+    Stats { dir: PathBuf },
+    /// Rewrite the log so it only holds live data.
+    Compact { dir: PathBuf },
+    /// Copy every record that can still be read into a fresh log, moving the
+    /// damaged one aside to `<dir>.corrupt`.
+    Repair { dir: PathBuf },
+    /// Turn a log file of JSON lines, as the first versions wrote, into a log
+    /// directory in its place, keeping the original as `<path>.jsonl`.
+    Migrate(Migrate),
+    /// Print every live key and its value, keyspaces included.
+    Export {
+        dir: PathBuf,
+        #[arg(long, value_enum, default_value_t = DataFormat::Json)]
+        format: DataFormat,
+    },
+    /// Set every key read from stdin, in the format `export` prints.
+    Import {
+        dir: PathBuf,
+        #[arg(long, value_enum, default_value_t = DataFormat::Json)]
+        format: DataFormat,
+    },
+    /// Open the database and run get/set/del/scan/stats commands read from
+    /// stdin.
+    Shell { dir: PathBuf },
+    /// Run a workload against the database and report throughput and
+    /// latency. `--keys` keys are loaded first unless the workload only
+    /// writes.
+    Bench(Bench),
+}
+
+#[derive(Args)]
+struct Migrate {
+    path: PathBuf,
+    /// The format the log is in now.
+    #[arg(long, value_enum, default_value_t = MigrateFrom::Json)]
+    from: MigrateFrom,
+    /// The format to write the new log's records in.
+    #[arg(long, value_enum, default_value_t = MigrateTo::BinaryV2)]
+    to: MigrateTo,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum MigrateFrom {
+    /// One JSON command per line, in a single file.
+    Json,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum MigrateTo {
+    /// Segments of records in the compact binary codec.
+    BinaryV2,
+    /// Segments of records as JSON.
+    Json,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum DataFormat {
+    /// One JSON object per line.
+    Json,
+    /// A header row and then a row per key.
+    Csv,
+}
+
+impl DataFormat {
+    fn format(self) -> Format {
+        match self {
+            DataFormat::Json => Format::Json,
+            DataFormat::Csv => Format::Csv,
+        }
+    }
+}
+
+#[derive(Args)]
+struct Bench {
+    dir: PathBuf,
+    /// Threads reading and writing at once.
+    #[arg(long, default_value_t = 8)]
+    threads: usize,
+    /// How long to run for, in seconds.
+    #[arg(long, default_value_t = 10)]
+    duration: u64,
+    /// How many distinct keys to read and write.
+    #[arg(long, default_value_t = 10_000)]
+    keys: u64,
+    /// The size of each value written, in bytes.
+    #[arg(long, default_value_t = 100)]
+    value_size: usize,
+    /// The fraction of operations that are reads rather than writes, when
+    /// there's no --workload.
+    #[arg(long, default_value_t = 0.0)]
+    read_ratio: f64,
+    /// A standard YCSB mix of operations.
+    #[arg(long, value_enum)]
+    workload: Option<Workload>,
+    /// How keys are picked. Defaults to zipfian for the YCSB workloads (or
+    /// latest for D), and uniform otherwise.
+    #[arg(long, value_enum)]
+    distribution: Option<Distribution>,
+    #[arg(long, value_enum, default_value_t = SyncPolicy::Media)]
+    sync: SyncPolicy,
+    /// Write each batch while the one before it is still syncing.
+    #[arg(long)]
+    pipeline: bool,
+    /// Sort batches of at least this many writes out for the memtable while
+    /// they sync.
+    #[arg(long)]
+    apply_during_sync: Option<usize>,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum SyncPolicy {
+    Media,
+    Device,
+    None,
+}
+
+fn main() -> Result<()> {
+    match Cli::parse().command {
+        Cmd::Dump { dir } => dump(dir),
+        Cmd::Verify { dir } => verify(dir),
+        Cmd::Stats { dir } => stats(dir),
+        Cmd::Compact { dir } => compact(dir),
+        Cmd::Repair { dir } => repair(dir),
+        Cmd::Migrate(args) => migrate(args),
+        Cmd::Export { dir, format } => export(dir, format),
+        Cmd::Import { dir, format } => import(dir, format),
+        Cmd::Shell { dir } => shell(dir),
+        Cmd::Bench(args) => bench(args),
+    }
+}
+
+fn dump(dir: PathBuf) -> Result<()> {
+    let mut out = io::stdout().lock();
+    for record in LogReader::open(dir)? {
+        let (offset, seq, command) = record?;
+        let line = serde_json::json!({
+            "segment": offset.segment,
+            "offset": offset.offset,
+            "seq": seq,
+            "command": command,
+        });
+        match writeln!(out, "{}", line) {
+            Ok(()) => {}
+            // Being piped into `head` is fine.
+            Err(e) if e.kind() == io::ErrorKind::BrokenPipe => return Ok(()),
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(())
+}
+
+fn verify(dir: PathBuf) -> Result<()> {
+    let (segments, _) = segment::list(&dir)?;
+    let mut problems = 0;
+    for (i, &number) in segments.iter().enumerate() {
+        // A torn record is what a crash in the middle of a write leaves
+        // behind, and isn't a problem as long as the log carries on from it,
+        // which is what replay goes by too.
+        let report = segment::check(&dir, number, segments.get(i + 1).copied())?;
+        let codec = SegmentReader::open(&dir, number)?.codec();
+        let codec = match codec::builtin(codec) {
+            Some(codec) => codec.name().to_owned(),
+            None => format!("codec {}", codec),
+        };
+        let end = match report.torn.first() {
+            Some(at) => format!(", torn at offset {}", at.offset),
+            None => String::new(),
+        };
+        println!(
+            "segment {} ({}): {} records{}{}",
+            number,
+            codec,
+            report.records,
+            end,
+            if report.skipped.is_empty() {
+                ""
+            } else {
+                " (CORRUPT)"
+            },
+        );
+        for skipped in &report.skipped {
+            println!(
+                "  {} at offset {}, {} bytes, {} records lost",
+                skipped.reason, skipped.at.offset, skipped.len, skipped.records
+            );
+        }
+        if !report.skipped.is_empty() {
+            problems += 1;
+        }
+    }
+    if problems > 0 {
+        bail!("{} corrupt segment(s)", problems);
+    }
+    println!("ok");
+    Ok(())
+}
+
+fn stats(dir: PathBuf) -> Result<()> {
+    let (segments, recycled) = segment::list(&dir)?;
+    let mut buf = vec![];
+    let (mut sets, mut deletes, mut custom) = (0, 0, 0);
+    let (mut disk_bytes, mut used_bytes) = (0, 0);
+    // The size of the record holding each live key's current value, by
+    // keyspace and key.
+    let mut live = HashMap::new();
+    for &number in &segments {
+        disk_bytes += fs::metadata(segment::segment_path(&dir, number))?.len();
+        let mut reader = SegmentReader::open(&dir, number)?;
+        let codec = codec::builtin(reader.codec());
+        while reader.next_record(&mut buf)?.is_some() {
+            let size = (segment::HEADER_LEN + buf.len()) as u64;
+            let codec = codec
+                .as_ref()
+                .ok_or_else(|| anyhow!("segment {} is in codec {}", number, reader.codec()))?;
+            let command = match codec.decode(&buf).map_err(|e| anyhow!(e))? {
+                Command::Request(_, command) => *command,
+                command => command,
+            };
+            match command {
+                Command::Set(k, _) | Command::SetExpiring(k, _, _) | Command::Incr(k, _, _) => {
+                    sets += 1;
+                    live.insert((None, k), size);
+                }
+                // Every append to a list is needed for its current value.
+                Command::Append(k, _) => {
+                    sets += 1;
+                    *live.entry((None, k)).or_insert(0) += size;
+                }
+                Command::Delete(k) => {
+                    deletes += 1;
+                    live.remove(&(None, k));
+                }
+                // The record is shared between every key it writes.
+                Command::Transaction(writes) => {
+                    let share = size / writes.len().max(1) as u64;
+                    for (k, v) in writes {
+                        match v {
+                            Some(_) => {
+                                sets += 1;
+                                live.insert((None, k), share);
+                            }
+                            None => {
+                                deletes += 1;
+                                live.remove(&(None, k));
+                            }
+                        }
+                    }
+                }
+                Command::Custom(_)
+                | Command::Expire(..)
+                | Command::LastRequest(_)
+                | Command::Request(..) => custom += 1,
+                Command::KeyspaceSet(name, k, _) => {
+                    sets += 1;
+                    live.insert((Some(name), k), size);
+                }
+                Command::KeyspaceDelete(name, k) => {
+                    deletes += 1;
+                    live.remove(&(Some(name), k));
+                }
+                Command::DropKeyspace(name) => {
+                    deletes += 1;
+                    live.retain(|(keyspace, _), _| keyspace.as_ref() != Some(&name));
+                }
+                Command::DeleteRange(start, end) => {
+                    deletes += 1;
+                    live.retain(|(keyspace, k), _| keyspace.is_some() || *k < start || *k >= end);
+                }
+                Command::DeletePrefix(prefix) => {
+                    deletes += 1;
+                    live.retain(|(keyspace, k), _| keyspace.is_some() || !k.starts_with(&prefix));
+                }
+            }
+        }
+        used_bytes += reader.offset();
+    }
+    let records = sets + deletes + custom;
+    let live_bytes: u64 = live.values().sum();
+    println!(
+        "segments:      {} ({} recycled)",
+        segments.len(),
+        recycled.len()
+    );
+    println!(
+        "records:       {} ({} sets, {} deletes, {} custom)",
+        records, sets, deletes, custom
+    );
+    println!("live keys:     {}", live.len());
+    println!("dead records:  {}", sets + deletes - live.len());
+    println!("disk bytes:    {}", disk_bytes);
+    println!("used bytes:    {}", used_bytes);
+    println!("live bytes:    {}", live_bytes);
+    Ok(())
+}
+
+fn compact(dir: PathBuf) -> Result<()> {
+    if !dir.is_dir() {
+        bail!("{} is not a log directory", dir.display());
+    }
+    Db::new(&dir)?.compact()?;
+    Ok(())
+}
+
+fn repair(dir: PathBuf) -> Result<()> {
+    let report = Db::repair(&dir)?;
+    print!("{}", report);
+    println!(
+        "the original log is in {}.corrupt, and this report in {}",
+        dir.display(),
+        dir.join("REPAIR").display()
+    );
+    Ok(())
+}
+
+fn migrate(args: Migrate) -> Result<()> {
+    let MigrateFrom::Json = args.from;
+    let codec: Arc<dyn RecordCodec> = match args.to {
+        MigrateTo::BinaryV2 => Arc::new(codec::Binary),
+        MigrateTo::Json => Arc::new(codec::Json),
+    };
+    let commands = Db::migrate(&args.path, codec)?;
+    println!(
+        "migrated {} commands into {}, and the original is in {}.jsonl",
+        commands,
+        args.path.display(),
+        args.path.display()
+    );
+    Ok(())
+}
+
+fn export(dir: PathBuf, format: DataFormat) -> Result<()> {
+    let db = Db::open_read_only(&dir)?;
+    match db.export(io::stdout().lock(), format.format()) {
+        Ok(_) => Ok(()),
+        Err(redo_log::Error::Io(e)) if e.kind() == io::ErrorKind::BrokenPipe => Ok(()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn import(dir: PathBuf, format: DataFormat) -> Result<()> {
+    let mut db = Db::new(&dir)?;
+    let imported = db.import(io::stdin().lock(), format.format())?;
+    eprintln!("imported {} keys", imported);
+    Ok(())
+}
+
+const SHELL_HELP: &str = "\
+get <key>           print the value of <key>
+set <key> <value>   set <key> to <value> (the rest of the line)
+del <key>           delete <key>
+scan [prefix]       print every key starting with [prefix], in order
+stats               print key count, commit metrics and disk usage
+flush               write the memtable out to a table and sync the log
+help                print this message
+quit                exit";
+
+fn shell(dir: PathBuf) -> Result<()> {
+    let mut db = Db::new(&dir)?;
+    let stdin = io::stdin();
+    let mut out = io::stdout();
+    let mut line = String::new();
+    loop {
+        write!(out, "> ")?;
+        out.flush()?;
+        line.clear();
+        if stdin.read_line(&mut line)? == 0 {
+            break;
+        }
+        let line = line.trim();
+        let (cmd, rest) = line.split_once(' ').unwrap_or((line, ""));
+        let rest = rest.trim_start();
+        match cmd {
+            "" => {}
+            "get" if !rest.is_empty() => match db.get(rest) {
+                Some(v) => println!("{}", v),
+                None => println!("(not found)"),
+            },
+            "set" => match rest.split_once(' ') {
+                Some((k, v)) => {
+                    db.set(k, v.trim_start())?;
+                    println!("ok");
+                }
+                None => println!("usage: set <key> <value>"),
+            },
+            "del" if !rest.is_empty() => {
+                db.delete(rest)?;
+                println!("ok");
+            }
+            "scan" => {
+                for (k, v) in db.scan(rest) {
+                    println!("{} = {}", k, v);
+                }
+            }
+            "stats" => {
+                let metrics = db.metrics();
+                println!("keys:             {}", db.len());
+                println!("batches:          {}", metrics.batches);
+                println!("commands:         {}", metrics.commands);
+                println!("mean batch size:  {:.1}", metrics.mean_batch_size());
+                println!("bytes written:    {}", metrics.bytes);
+                println!("cache hit rate:   {:.2}", metrics.block_cache_hit_rate());
+                let stats = db.stats()?;
+                println!("disk bytes:       {}", stats.disk_bytes);
+                println!("log records:      {}", stats.log_records);
+                println!("tables:           {}", stats.tables);
+                println!("write amp:        {:.2}", stats.write_amplification);
+            }
+            "flush" => println!("durable through seq {}", db.flush()?),
+            "get" | "del" => println!("usage: {} <key>", cmd),
+            "help" => println!("{}", SHELL_HELP),
+            "quit" | "exit" => break,
+            _ => println!("unrecognized command, try `help`"),
+        }
+    }
+    Ok(())
+}
+
+// Counts the warnings the database gives while the benchmark runs.
+#[derive(Debug, Default)]
+struct Warnings {
+    slow_syncs: AtomicU64,
+    stalls: AtomicU64,
+}
+
+impl DbListener for Warnings {
+    fn on_slow_sync(&self, _: &SlowSync) {
+        self.slow_syncs.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn on_write_stall(&self, _: &WriteStall) {
+        self.stalls.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+fn bench(args: Bench) -> Result<()> {
+    if !(0.0..=1.0).contains(&args.read_ratio) {
+        bail!("--read-ratio must be between 0 and 1");
+    }
+    if args.keys == 0 {
+        bail!("--keys must be at least 1");
+    }
+    let mix = match args.workload {
+        Some(workload) => workload.mix(),
+        None => Mix {
+            read: args.read_ratio,
+            update: 1.0 - args.read_ratio,
+            ..Mix::default()
+        },
+    };
+    let distribution = args
+        .distribution
+        .or(args.workload.map(Workload::distribution))
+        .unwrap_or(Distribution::Uniform);
+    let value = "x".repeat(args.value_size);
+    if mix.update < 1.0 {
+        // Something to read, written without syncing since it isn't being
+        // measured.
+        let options = Options {
+            durability: Durability::None,
+            ..Options::default()
+        };
+        let mut db = Db::with_options(&args.dir, options)?;
+        for i in 0..args.keys {
+            db.set(&key(i), &value)?;
+        }
+    }
+
+    let warnings = Arc::new(Warnings::default());
+    let options = Options {
+        durability: match args.sync {
+            SyncPolicy::Media => Durability::Media,
+            SyncPolicy::Device => Durability::Device,
+            SyncPolicy::None => Durability::None,
+        },
+        listener: Some(warnings.clone()),
+        pipeline_commits: args.pipeline,
+        apply_during_sync: args.apply_during_sync,
+        ..Options::default()
+    };
+    let db = Db::with_options(&args.dir, options)?;
+    let chooser = Arc::new(Chooser::new(distribution, args.keys));
+    // Keys from here on haven't been inserted yet.
+    let inserted = Arc::new(AtomicU64::new(args.keys));
+    let stop = Arc::new(AtomicBool::new(false));
+    // For each kind of operation, in nanoseconds.
+    let latencies = Arc::new(Op::ALL.map(|_| Histogram::default()));
+    let threads = (0..args.threads)
+        .map(|i| {
+            let (mut db, stop) = (db.clone(), stop.clone());
+            let (chooser, inserted) = (chooser.clone(), inserted.clone());
+            let (latencies, value) = (latencies.clone(), value.clone());
+            thread::spawn(move || -> Result<()> {
+                let mut rng = Rng::new(i as u64 + 1);
+                while !stop.load(Ordering::Relaxed) {
+                    let op = mix.pick(rng.fraction());
+                    let k = key(chooser.next(&mut rng, inserted.load(Ordering::Relaxed)));
+                    let start = Instant::now();
+                    match op {
+                        Op::Read => {
+                            db.get(&k);
+                        }
+                        Op::Update => db.set(&k, &value)?,
+                        Op::Insert => {
+                            let k = key(inserted.fetch_add(1, Ordering::Relaxed));
+                            db.set(&k, &value)?;
+                        }
+                        // The keys that only differ in their last digit.
+                        Op::Scan => {
+                            db.scan(&k[..k.len() - 1]);
+                        }
+                        Op::ReadModifyWrite => {
+                            db.get(&k);
+                            db.set(&k, &value)?;
+                        }
+                    }
+                    latencies[op as usize].record(start.elapsed().as_nanos() as u64);
+                }
+                Ok(())
+            })
+        })
+        .collect::<Vec<_>>();
+    let start = Instant::now();
+    thread::sleep(Duration::from_secs(args.duration));
+    stop.store(true, Ordering::Relaxed);
+    for thread in threads {
+        thread.join().unwrap()?;
+    }
+    let elapsed = start.elapsed().as_secs_f64();
+
+    let total: u64 = latencies.iter().map(|h| h.snapshot().count()).sum();
+    println!(
+        "{} operations ({:.0}/s), {:?} keys",
+        total,
+        total as f64 / elapsed,
+        distribution
+    );
+    for op in Op::ALL {
+        let latency = latencies[op as usize].snapshot();
+        if latency.count() == 0 {
+            continue;
+        }
+        let bound = |q| Duration::from_nanos(latency.quantile(q));
+        println!(
+            "{:?}: {} ({:.0}/s), latency p50 <= {:?}, p99 <= {:?}, p99.9 <= {:?}, max <= {:?}",
+            op,
+            latency.count(),
+            latency.count() as f64 / elapsed,
+            bound(0.5),
+            bound(0.99),
+            bound(0.999),
+            bound(1.0),
+        );
+    }
+    let metrics = db.metrics();
+    println!(
+        "batches: {}, mean batch size {:.1}, largest {}",
+        metrics.batches,
+        metrics.mean_batch_size(),
+        metrics.largest_batch,
+    );
+    println!(
+        "slow syncs: {}, stalled writes: {}",
+        warnings.slow_syncs.load(Ordering::Relaxed),
+        warnings.stalls.load(Ordering::Relaxed),
+    );
+    Ok(())
+}
+
+fn key(i: u64) -> String {
+    format!("key{:010}", i)
+}
+
+// The standard workloads from YCSB, the Yahoo! Cloud Serving Benchmark.
+#[derive(Clone, Copy, ValueEnum)]
+enum Workload {
+    /// Half reads, half updates.
+    A,
+    /// 95% reads, 5% updates.
+    B,
+    /// Only reads.
+    C,
+    /// 95% reads, 5% inserts, reading mostly what was inserted last.
+    D,
+    /// 95% short scans, 5% inserts.
+    E,
+    /// Half reads, half read-modify-writes.
+    F,
+}
+
+impl Workload {
+    fn mix(self) -> Mix {
+        let (read, update, insert, scan, read_modify_write) = match self {
+            Workload::A => (0.5, 0.5, 0.0, 0.0, 0.0),
+            Workload::B => (0.95, 0.05, 0.0, 0.0, 0.0),
+            Workload::C => (1.0, 0.0, 0.0, 0.0, 0.0),
+            Workload::D => (0.95, 0.0, 0.05, 0.0, 0.0),
+            Workload::E => (0.0, 0.0, 0.05, 0.95, 0.0),
+            Workload::F => (0.5, 0.0, 0.0, 0.0, 0.5),
+        };
+        Mix {
+            read,
+            update,
+            insert,
+            scan,
+            read_modify_write,
+        }
+    }
+
+    fn distribution(self) -> Distribution {
+        match self {
+            Workload::D => Distribution::Latest,
+            _ => Distribution::Zipfian,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum Distribution {
+    Uniform,
+    /// A few keys get most of the traffic.
+    Zipfian,
+    /// Zipfian, but with the most recently inserted keys the hottest.
+    Latest,
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Op {
+    Read,
+    Update,
+    Insert,
+    Scan,
+    ReadModifyWrite,
+}
+
+impl Op {
+    const ALL: [Op; 5] = [
+        Op::Read,
+        Op::Update,
+        Op::Insert,
+        Op::Scan,
+        Op::ReadModifyWrite,
+    ];
+}
+
+// The fraction of operations of each kind, adding up to one.
+#[derive(Clone, Copy, Default)]
+struct Mix {
+    read: f64,
+    update: f64,
+    insert: f64,
+    scan: f64,
+    read_modify_write: f64,
+}
+
+impl Mix {
+    // The operation that `r`, between 0 and 1, falls on.
+    fn pick(&self, mut r: f64) -> Op {
+        let weights = [
+            self.read,
+            self.update,
+            self.insert,
+            self.scan,
+            self.read_modify_write,
+        ];
+        for (op, weight) in Op::ALL.into_iter().zip(weights) {
+            if r < weight {
+                return op;
+            }
+            r -= weight;
+        }
+        Op::ALL[weights.iter().rposition(|&w| w > 0.0).unwrap_or(0)]
+    }
+}
+
+// Picks which existing key each operation goes to.
+struct Chooser {
+    distribution: Distribution,
+    zipf: Zipf,
+}
+
+impl Chooser {
+    fn new(distribution: Distribution, keys: u64) -> Self {
+        Chooser {
+            distribution,
+            zipf: Zipf::new(keys),
+        }
+    }
+
+    // One of the `inserted` keys.
+    fn next(&self, rng: &mut Rng, inserted: u64) -> u64 {
+        match self.distribution {
+            Distribution::Uniform => rng.next_u64() % inserted,
+            // Hashed so that the hot keys are spread around rather than all
+            // next to each other.
+            Distribution::Zipfian => fnv(self.zipf.next(rng.fraction())) % inserted,
+            Distribution::Latest => inserted - 1 - self.zipf.next(rng.fraction()).min(inserted - 1),
+        }
+    }
+}
+
+// Ranks from 0 to `n`, with rank `i` drawn in proportion to `1 / (i + 1)^0.99`,
+// the way YCSB does it (from Gray et al, "Quickly Generating Billion-Record
+// Synthetic Databases").
+struct Zipf {
+    n: f64,
+    zeta: f64,
+    alpha: f64,
+    eta: f64,
+}
+
+impl Zipf {
+    const THETA: f64 = 0.99;
+
+    fn new(n: u64) -> Self {
+        let zeta = |n: u64| {
+            (1..=n)
+                .map(|i| 1.0 / (i as f64).powf(Self::THETA))
+                .sum::<f64>()
+        };
+        let (zeta_n, zeta_2) = (zeta(n), zeta(2));
+        Zipf {
+            n: n as f64,
+            zeta: zeta_n,
+            alpha: 1.0 / (1.0 - Self::THETA),
+            eta: (1.0 - (2.0 / n as f64).powf(1.0 - Self::THETA)) / (1.0 - zeta_2 / zeta_n),
+        }
+    }
+
+    // The rank that `u`, between 0 and 1, falls on.
+    fn next(&self, u: f64) -> u64 {
+        let uz = u * self.zeta;
+        if uz < 1.0 {
+            return 0;
+        }
+        if uz < 1.0 + 0.5f64.powf(Self::THETA) {
+            return 1;
+        }
+        let rank = self.n * (self.eta * u - self.eta + 1.0).powf(self.alpha);
+        (rank as u64).min(self.n as u64 - 1)
+    }
+}
+
+fn fnv(i: u64) -> u64 {
+    let mut hash = 0xcbf29ce484222325u64;
+    for byte in i.to_le_bytes() {
+        hash = (hash ^ byte as u64).wrapping_mul(0x100000001b3);
+    }
+    hash
+}
@@ -0,0 +1,101 @@
+use anyhow::Result;
+use redo_log::{KvStore, StoreResult};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    path::Path,
+};
+
+#[derive(Debug)]
+pub(crate) struct Db {
+    log: File,
+    memtable: HashMap<String, String>,
+}
+
+#[derive(Serialize, Deserialize)]
+enum Command<'a> {
+    Set(&'a str, &'a str),
+    Delete(&'a str),
+}
+
+impl Db {
+    pub(crate) fn new<P>(f: P) -> Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        let log = OpenOptions::new().create(true).append(true).open(&f)?;
+        log.sync_all()?;
+        let memtable = Self::replay_log(&f)?;
+        Ok(Db { log, memtable })
+    }
+
+    fn apply_command_to_memtable(memtable: &mut HashMap<String, String>, cmd: &Command) {
+        match cmd {
+            Command::Set(k, v) => {
+                memtable.insert((*k).to_owned(), (*v).to_owned());
+            }
+            Command::Delete(k) => {
+                memtable.remove(*k);
+            }
+        }
+    }
+
+    fn replay_log<P>(f: P) -> Result<HashMap<String, String>>
+    where
+        P: AsRef<Path>,
+    {
+        let file = BufReader::new(File::open(f)?);
+        let mut result = HashMap::new();
+        for line in file.lines() {
+            Self::apply_command_to_memtable(&mut result, &serde_json::from_str(line?.as_str())?);
+        }
+        Ok(result)
+    }
+
+    fn apply_command(&mut self, command: &Command) -> Result<()> {
+        self.log.write_all(&serde_json::to_vec(command)?)?;
+        self.log.write_all(b"\n")?;
+        self.log.sync_all()?;
+        Self::apply_command_to_memtable(&mut self.memtable, command);
+        Ok(())
+    }
+
+    pub(crate) fn set(&mut self, k: &str, v: &str) -> Result<()> {
+        self.apply_command(&Command::Set(k, v))?;
+        Ok(())
+    }
+
+    pub(crate) fn delete(&mut self, k: &str) -> Result<()> {
+        self.apply_command(&Command::Delete(k))?;
+        Ok(())
+    }
+
+    pub(crate) fn get(&self, k: &str) -> Option<String> {
+        self.memtable.get(k).cloned()
+    }
+}
+
+impl KvStore for Db {
+    fn open(path: &Path) -> StoreResult<Self> {
+        Ok(Db::new(path)?)
+    }
+
+    fn set(&mut self, k: &str, v: &str) -> StoreResult<()> {
+        Ok(Db::set(self, k, v)?)
+    }
+
+    fn delete(&mut self, k: &str) -> StoreResult<()> {
+        Ok(Db::delete(self, k)?)
+    }
+
+    fn get(&self, k: &str) -> StoreResult<Option<String>> {
+        Ok(Db::get(self, k))
+    }
+
+    // Every write is synced before it returns.
+    fn sync(&mut self) -> StoreResult<()> {
+        Ok(())
+    }
+}
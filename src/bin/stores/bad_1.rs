@@ -0,0 +1,157 @@
+// Keeps everything in memory, and writes all of it out to a single file as a
+// snapshot: on `sync`, every `flush_every_n_ops` writes, and when it's
+// dropped. Whatever was written since the last snapshot is lost in a crash,
+// which makes it the snapshot-only baseline to compare the log against.
+//
+// It started out deliberately broken, crashing before it ever got to flush,
+// and overwriting the file in place when it did, so that a crash partway
+// through lost everything. The snapshot now goes to a temporary file that's
+// synced and renamed over the old one, so a crash leaves one or the other.
+use anyhow::Result;
+use redo_log::{KvStore, StoreResult};
+use std::{
+    collections::HashMap,
+    ffi::OsString,
+    fs::{self, File},
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Options {
+    // Writes a snapshot after this many sets and deletes since the last one.
+    pub flush_every_n_ops: Option<u64>,
+    // Writes a snapshot on drop, including while unwinding from a panic, if
+    // anything has changed since the last one. Failing to is ignored.
+    pub flush_on_drop: bool,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            flush_every_n_ops: None,
+            flush_on_drop: true,
+        }
+    }
+}
+
+pub(crate) struct Db {
+    pub(crate) data: HashMap<String, String>,
+    fname: PathBuf,
+    options: Options,
+    // Sets and deletes since the last snapshot.
+    unflushed: u64,
+}
+
+impl Db {
+    pub(crate) fn new<P>(f: P) -> Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        Self::with_options(f, Options::default())
+    }
+
+    pub(crate) fn with_options<P>(f: P, options: Options) -> Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        // Only a missing file means an empty store: one we can't read or
+        // parse is an error, not something to quietly start over from.
+        let data = match fs::read(&f) {
+            Ok(bytes) => serde_json::from_slice(&bytes)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Db {
+            data,
+            fname: f.as_ref().to_path_buf(),
+            options,
+            unflushed: 0,
+        })
+    }
+
+    pub(crate) fn set(&mut self, k: &str, v: &str) -> Result<()> {
+        self.data.insert(k.to_owned(), v.to_owned());
+        self.written()
+    }
+
+    pub(crate) fn delete(&mut self, k: &str) -> Result<()> {
+        self.data.remove(k);
+        self.written()
+    }
+
+    pub(crate) fn get(&self, k: &str) -> Option<&String> {
+        self.data.get(k)
+    }
+
+    fn written(&mut self) -> Result<()> {
+        self.unflushed += 1;
+        match self.options.flush_every_n_ops {
+            Some(n) if self.unflushed >= n => self.flush(),
+            _ => Ok(()),
+        }
+    }
+
+    // Writes the snapshot to `<fname>.tmp`, syncs it, renames it over the
+    // old one, and syncs the directory so that the rename sticks.
+    pub(crate) fn flush(&mut self) -> Result<()> {
+        let mut tmp = OsString::from(self.fname.as_os_str());
+        tmp.push(".tmp");
+        let tmp = PathBuf::from(tmp);
+        let mut file = File::create(&tmp)?;
+        file.write_all(&serde_json::to_vec(&self.data)?)?;
+        file.sync_all()?;
+        drop(file);
+        fs::rename(&tmp, &self.fname)?;
+        sync_parent(&self.fname)?;
+        self.unflushed = 0;
+        Ok(())
+    }
+}
+
+#[cfg(unix)]
+fn sync_parent(path: &Path) -> Result<()> {
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    File::open(parent)?.sync_all()?;
+    Ok(())
+}
+
+// Directories can't be opened as files on Windows, and NTFS journals the
+// rename for us anyway.
+#[cfg(not(unix))]
+fn sync_parent(_path: &Path) -> Result<()> {
+    Ok(())
+}
+
+impl Drop for Db {
+    fn drop(&mut self) {
+        if self.options.flush_on_drop && self.unflushed > 0 {
+            let _ = self.flush();
+        }
+    }
+}
+
+impl KvStore for Db {
+    fn open(path: &Path) -> StoreResult<Self> {
+        Ok(Db::new(path)?)
+    }
+
+    fn set(&mut self, k: &str, v: &str) -> StoreResult<()> {
+        Ok(Db::set(self, k, v)?)
+    }
+
+    fn delete(&mut self, k: &str) -> StoreResult<()> {
+        Ok(Db::delete(self, k)?)
+    }
+
+    fn get(&self, k: &str) -> StoreResult<Option<String>> {
+        Ok(Db::get(self, k).cloned())
+    }
+
+    fn sync(&mut self) -> StoreResult<()> {
+        Ok(self.flush()?)
+    }
+}
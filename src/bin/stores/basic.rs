@@ -0,0 +1,94 @@
+use anyhow::Result;
+use redo_log::{KvStore, StoreResult};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+};
+
+#[derive(Debug)]
+pub(crate) struct Db {
+    log: File,
+    fname: PathBuf,
+}
+
+#[derive(Serialize, Deserialize)]
+enum Command<'a> {
+    Set(&'a str, &'a str),
+    Delete(&'a str),
+}
+
+impl Db {
+    pub(crate) fn new<P>(f: P) -> Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        let log = OpenOptions::new().create(true).append(true).open(&f)?;
+        log.sync_all()?;
+        Ok(Db {
+            log,
+            fname: f.as_ref().to_path_buf(),
+        })
+    }
+
+    fn apply_command(&mut self, command: &Command) -> Result<()> {
+        self.log.write_all(&serde_json::to_vec(command)?)?;
+        self.log.write_all(b"\n")?;
+        self.log.sync_all()?;
+        Ok(())
+    }
+
+    pub(crate) fn set(&mut self, k: &str, v: &str) -> Result<()> {
+        self.apply_command(&Command::Set(k, v))?;
+        Ok(())
+    }
+
+    pub(crate) fn delete(&mut self, k: &str) -> Result<()> {
+        self.apply_command(&Command::Delete(k))?;
+        Ok(())
+    }
+
+    pub(crate) fn get(&self, k: &str) -> Result<Option<String>> {
+        let file = BufReader::new(File::open(&self.fname)?);
+        let mut result = None;
+        for line in file.lines() {
+            match serde_json::from_str(&line?)? {
+                Command::Set(new_k, v) => {
+                    if k == new_k {
+                        result = Some(v.to_owned());
+                    }
+                }
+                Command::Delete(new_k) => {
+                    if k == new_k {
+                        result = None;
+                    }
+                }
+            }
+        }
+        Ok(result)
+    }
+}
+
+impl KvStore for Db {
+    fn open(path: &Path) -> StoreResult<Self> {
+        Ok(Db::new(path)?)
+    }
+
+    fn set(&mut self, k: &str, v: &str) -> StoreResult<()> {
+        Ok(Db::set(self, k, v)?)
+    }
+
+    fn delete(&mut self, k: &str) -> StoreResult<()> {
+        Ok(Db::delete(self, k)?)
+    }
+
+    fn get(&self, k: &str) -> StoreResult<Option<String>> {
+        Ok(Db::get(self, k)?)
+    }
+
+    // Every write is synced before it returns.
+    fn sync(&mut self) -> StoreResult<()> {
+        Ok(())
+    }
+}
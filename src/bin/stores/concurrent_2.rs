@@ -0,0 +1,127 @@
+use anyhow::Result;
+use redo_log::{Histogram, KvStore, StoreResult};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    path::Path,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+#[derive(Debug)]
+pub(crate) struct Db {
+    log: File,
+    memtable: Arc<Mutex<HashMap<String, String>>>,
+    // In microseconds.
+    pub(crate) write_latency: Arc<Histogram>,
+    pub(crate) sync_latency: Arc<Histogram>,
+}
+
+impl Clone for Db {
+    fn clone(&self) -> Self {
+        Db {
+            log: self.log.try_clone().unwrap(),
+            memtable: self.memtable.clone(),
+            write_latency: self.write_latency.clone(),
+            sync_latency: self.sync_latency.clone(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+enum Command {
+    Set(String, String),
+    Delete(String),
+}
+
+impl Db {
+    pub(crate) fn new<P>(f: P) -> Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        let log = OpenOptions::new().create(true).append(true).open(&f)?;
+        log.sync_all()?;
+        let memtable = Self::replay_log(&f)?;
+        Ok(Db {
+            log,
+            memtable: Arc::new(Mutex::new(memtable)),
+            write_latency: Arc::default(),
+            sync_latency: Arc::default(),
+        })
+    }
+
+    fn apply_command_to_memtable(memtable: &mut HashMap<String, String>, cmd: &Command) {
+        match cmd {
+            Command::Set(k, v) => {
+                memtable.insert(k.clone(), v.clone());
+            }
+            Command::Delete(k) => {
+                memtable.remove(k);
+            }
+        }
+    }
+
+    fn replay_log<P>(f: P) -> Result<HashMap<String, String>>
+    where
+        P: AsRef<Path>,
+    {
+        let file = BufReader::new(File::open(f)?);
+        let mut result = HashMap::new();
+        for line in file.lines() {
+            Self::apply_command_to_memtable(&mut result, &serde_json::from_str(line?.as_str())?);
+        }
+        Ok(result)
+    }
+
+    fn apply_command(&mut self, command: &Command) -> Result<()> {
+        let a = Instant::now();
+        let mut data = serde_json::to_vec(command)?;
+        data.extend(b"\n");
+        self.log.write_all(&data)?;
+        let b = Instant::now();
+        self.write_latency.record((b - a).as_micros() as u64);
+        self.log.sync_all()?;
+        self.sync_latency.record(b.elapsed().as_micros() as u64);
+        Self::apply_command_to_memtable(&mut self.memtable.lock().unwrap(), command);
+        Ok(())
+    }
+
+    pub(crate) fn set(&mut self, k: &str, v: &str) -> Result<()> {
+        self.apply_command(&Command::Set(k.to_owned(), v.to_owned()))?;
+        Ok(())
+    }
+
+    pub(crate) fn delete(&mut self, k: &str) -> Result<()> {
+        self.apply_command(&Command::Delete(k.to_owned()))?;
+        Ok(())
+    }
+
+    pub(crate) fn get(&self, k: &str) -> Option<String> {
+        self.memtable.lock().unwrap().get(k).cloned()
+    }
+}
+
+impl KvStore for Db {
+    fn open(path: &Path) -> StoreResult<Self> {
+        Ok(Db::new(path)?)
+    }
+
+    fn set(&mut self, k: &str, v: &str) -> StoreResult<()> {
+        Ok(Db::set(self, k, v)?)
+    }
+
+    fn delete(&mut self, k: &str) -> StoreResult<()> {
+        Ok(Db::delete(self, k)?)
+    }
+
+    fn get(&self, k: &str) -> StoreResult<Option<String>> {
+        Ok(Db::get(self, k))
+    }
+
+    // Every write is synced before it returns.
+    fn sync(&mut self) -> StoreResult<()> {
+        Ok(())
+    }
+}
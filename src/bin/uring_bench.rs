@@ -0,0 +1,141 @@
+// Measures how quickly commits go through the log from async code, appending
+// and syncing through io_uring (see `redo_log::uring`) against the usual way
+// of wrapping the blocking calls in `spawn_blocking`. Each of `--logs` tasks
+// writes to a log of its own, a batch at a time, syncing after every batch.
+use anyhow::{bail, Result};
+use clap::Parser;
+use redo_log::segment::SegmentWriter;
+use redo_log::uring::UringLog;
+use redo_log::{binary, Command, Durability, Options};
+use std::{
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+use tempfile::tempdir;
+
+#[derive(Parser)]
+#[command(name = "uring_bench")]
+struct Args {
+    /// How many logs to write to at once, each from a task of its own.
+    #[arg(long, default_value_t = 8)]
+    logs: usize,
+    /// How many batches each task commits.
+    #[arg(long, default_value_t = 500)]
+    batches: usize,
+    /// How many commands go in each batch.
+    #[arg(long, default_value_t = 4)]
+    batch: usize,
+    /// How long each value is, in bytes.
+    #[arg(long, default_value_t = 100)]
+    value_len: usize,
+    /// Where to put the logs, instead of a temporary directory.
+    #[arg(long)]
+    dir: Option<PathBuf>,
+}
+
+fn batch(args: &Args, task: usize, i: usize) -> Vec<Command> {
+    (0..args.batch)
+        .map(|j| {
+            let key = format!("task{}-key{}", task, i * args.batch + j);
+            Command::Set(key, "v".repeat(args.value_len))
+        })
+        .collect()
+}
+
+// Every task's log, under `dir`, made fresh for each run.
+fn log_dirs(dir: &Path, run: &str, logs: usize) -> Result<Vec<PathBuf>> {
+    let dirs = (0..logs)
+        .map(|i| dir.join(run).join(i.to_string()))
+        .collect::<Vec<_>>();
+    for dir in &dirs {
+        std::fs::create_dir_all(dir)?;
+    }
+    Ok(dirs)
+}
+
+fn spawn_blocking(args: &Args, dir: &Path) -> Result<Duration> {
+    let dirs = log_dirs(dir, "spawn_blocking", args.logs)?;
+    let runtime = tokio::runtime::Runtime::new()?;
+    let size = Options::default().segment_size;
+    runtime.block_on(async {
+        let start = Instant::now();
+        let tasks = dirs.into_iter().enumerate().map(|(task, dir)| {
+            let batches = (0..args.batches)
+                .map(|i| batch(args, task, i))
+                .collect::<Vec<_>>();
+            tokio::spawn(async move {
+                let mut log = tokio::task::spawn_blocking(move || {
+                    SegmentWriter::create(&dir, 1, 1, 2, size, None, Durability::Media)
+                })
+                .await??;
+                for commands in batches {
+                    log = tokio::task::spawn_blocking(move || -> Result<_> {
+                        let payloads = commands
+                            .iter()
+                            .map(binary::to_vec)
+                            .collect::<std::result::Result<Vec<_>, _>>()?;
+                        log.append_batch(&payloads)?;
+                        log.sync()?;
+                        Ok(log)
+                    })
+                    .await??;
+                }
+                anyhow::Ok(())
+            })
+        });
+        for task in tasks.collect::<Vec<_>>() {
+            task.await??;
+        }
+        Ok(start.elapsed())
+    })
+}
+
+fn uring(args: &Args, dir: &Path) -> Result<Duration> {
+    let dirs = log_dirs(dir, "uring", args.logs)?;
+    tokio_uring::start(async {
+        let start = Instant::now();
+        let tasks = dirs.into_iter().enumerate().map(|(task, dir)| {
+            let batches = (0..args.batches)
+                .map(|i| batch(args, task, i))
+                .collect::<Vec<_>>();
+            tokio_uring::spawn(async move {
+                let mut log = UringLog::open(&dir, Options::default()).await?;
+                for commands in batches {
+                    log.append(&commands).await?;
+                    log.sync().await?;
+                }
+                log.close().await
+            })
+        });
+        for task in tasks.collect::<Vec<_>>() {
+            task.await??;
+        }
+        Ok(start.elapsed())
+    })
+}
+
+fn main() -> Result<()> {
+    let args = Args::parse();
+    if args.logs == 0 || args.batches == 0 || args.batch == 0 {
+        bail!("--logs, --batches and --batch must be at least 1");
+    }
+    let tmp = tempdir()?;
+    let dir = args.dir.clone().unwrap_or_else(|| tmp.path().to_path_buf());
+    let commits = (args.logs * args.batches) as f64;
+    for (name, run) in [
+        (
+            "spawn_blocking",
+            spawn_blocking as fn(&Args, &Path) -> Result<Duration>,
+        ),
+        ("uring", uring),
+    ] {
+        let elapsed = run(&args, &dir)?.as_secs_f64();
+        println!(
+            "{:>14}: {:>8.0} commits/s, {:>7.1} us/commit per log",
+            name,
+            commits / elapsed,
+            elapsed * 1e6 / args.batches as f64,
+        );
+    }
+    Ok(())
+}
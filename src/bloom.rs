@@ -0,0 +1,108 @@
+// A bloom filter over the keys of a table, so that looking up a key the table
+// doesn't have usually doesn't touch the file at all.
+//
+// It's stored as the bit array followed by a byte holding the number of probes,
+// which means a table written with one `bits_per_key` can be read with
+// another. An empty filter matches everything.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Bloom {
+    bits: Vec<u8>,
+    probes: u32,
+}
+
+impl Bloom {
+    // Builds a filter over the keys with the given hashes (see `hash`). With
+    // zero `bits_per_key`, there's no filter.
+    pub fn new(hashes: &[u32], bits_per_key: usize) -> Self {
+        if bits_per_key == 0 {
+            return Bloom::default();
+        }
+        // ln(2) times the bits per key is what minimizes false positives.
+        let probes = ((bits_per_key as f64 * 0.69) as u32).clamp(1, 30);
+        // Tiny filters have a terrible false positive rate, so don't go
+        // below 64 bits.
+        let len = (hashes.len() * bits_per_key).max(64).div_ceil(8);
+        let mut bloom = Bloom {
+            bits: vec![0; len],
+            probes,
+        };
+        for &h in hashes {
+            for bit in bloom.probe(h) {
+                bloom.bits[bit / 8] |= 1 << (bit % 8);
+            }
+        }
+        bloom
+    }
+
+    // Whether the key with this hash might be in the set. False positives are
+    // possible, false negatives aren't.
+    pub fn may_contain(&self, h: u32) -> bool {
+        self.bits.is_empty()
+            || self
+                .probe(h)
+                .all(|bit| self.bits[bit / 8] & (1 << (bit % 8)) != 0)
+    }
+
+    // The bits to check for a hash, using the double hashing scheme from
+    // "Less Hashing, Same Performance" (Kirsch and Mitzenmacher).
+    fn probe(&self, mut h: u32) -> impl Iterator<Item = usize> {
+        let n = self.bits.len() * 8;
+        let delta = h.rotate_right(17);
+        (0..self.probes).map(move |_| {
+            let bit = h as usize % n;
+            h = h.wrapping_add(delta);
+            bit
+        })
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        if self.bits.is_empty() {
+            return vec![];
+        }
+        let mut buf = self.bits.clone();
+        buf.push(self.probes as u8);
+        buf
+    }
+
+    // None if it isn't a filter `encode` could have written.
+    pub fn decode(raw: &[u8]) -> Option<Self> {
+        match raw.split_last() {
+            None => Some(Bloom::default()),
+            Some((&probes, bits)) if !bits.is_empty() && (1..=30).contains(&probes) => {
+                Some(Bloom {
+                    bits: bits.to_vec(),
+                    probes: probes as u32,
+                })
+            }
+            Some(_) => None,
+        }
+    }
+}
+
+// 32-bit FNV-1a. Filters are stored on disk, so this has to stay the same from
+// one build to the next, which rules out std's `DefaultHasher`.
+pub fn hash(key: &str) -> u32 {
+    key.bytes().fold(0x811c9dc5, |h: u32, b| {
+        (h ^ b as u32).wrapping_mul(0x01000193)
+    })
+}
+
+#[test]
+fn test_bloom() {
+    let keys = (0..10_000).map(|i| format!("key{}", i)).collect::<Vec<_>>();
+    let hashes = keys.iter().map(|k| hash(k)).collect::<Vec<_>>();
+    let bloom = Bloom::new(&hashes, 10);
+    assert!(hashes.iter().all(|&h| bloom.may_contain(h)));
+
+    // With 10 bits per key, about 1% of absent keys get through.
+    let false_positives = (0..10_000)
+        .filter(|i| bloom.may_contain(hash(&format!("other{}", i))))
+        .count();
+    assert!(false_positives < 300, "{} false positives", false_positives);
+
+    assert_eq!(Bloom::decode(&bloom.encode()), Some(bloom));
+    let none = Bloom::new(&hashes, 0);
+    assert!(none.may_contain(hash("anything")));
+    assert_eq!(Bloom::decode(&none.encode()), Some(none));
+    assert_eq!(Bloom::decode(&[0xff, 0]), None);
+}
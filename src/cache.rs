@@ -0,0 +1,137 @@
+use crate::Result;
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+// A table's id (see `BlockCache::new_id`) and a block number within it.
+type BlockId = (u64, u64);
+
+// Blocks of table files, shared by every table in a database and bounded by
+// the total size of the blocks in it. The least recently used blocks go first.
+//
+// Blocks of tables that have been retired aren't removed; they just stop being
+// used, and get evicted in their turn.
+#[derive(Debug, Default)]
+pub struct BlockCache {
+    capacity: usize,
+    lru: Mutex<Lru>,
+    next_id: AtomicU64,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+#[derive(Debug, Default)]
+struct Lru {
+    // Each block along with when it was last used.
+    blocks: HashMap<BlockId, (Arc<[u8]>, u64)>,
+    by_use: BTreeMap<u64, BlockId>,
+    clock: u64,
+    bytes: usize,
+}
+
+impl BlockCache {
+    // With zero `capacity`, nothing is cached.
+    pub fn new(capacity: usize) -> Self {
+        BlockCache {
+            capacity,
+            ..BlockCache::default()
+        }
+    }
+
+    // An id for a newly opened table to key its blocks by. Table numbers
+    // aren't enough, since a read-only database can reopen the same table.
+    pub fn new_id(&self) -> u64 {
+        self.next_id.fetch_add(1, Ordering::Relaxed)
+    }
+
+    // Returns the block, calling `load` to read it if it isn't cached. The
+    // cache isn't locked while `load` runs, so two threads can end up reading
+    // the same block, but neither holds up readers of other blocks.
+    pub fn get<F>(&self, table: u64, block: u64, load: F) -> Result<Arc<[u8]>>
+    where
+        F: FnOnce() -> Result<Vec<u8>>,
+    {
+        let id = (table, block);
+        if let Some(data) = self.lru.lock()?.touch(id) {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            return Ok(data);
+        }
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        let data: Arc<[u8]> = load()?.into();
+        if data.len() <= self.capacity {
+            let mut lru = self.lru.lock()?;
+            lru.insert(id, data.clone());
+            while lru.bytes > self.capacity {
+                lru.evict();
+            }
+        }
+        Ok(data)
+    }
+
+    // The number of hits and misses so far.
+    pub fn stats(&self) -> (u64, u64) {
+        (
+            self.hits.load(Ordering::Relaxed),
+            self.misses.load(Ordering::Relaxed),
+        )
+    }
+}
+
+impl Lru {
+    fn touch(&mut self, id: BlockId) -> Option<Arc<[u8]>> {
+        let (data, used) = self.blocks.get_mut(&id)?;
+        self.by_use.remove(used);
+        self.clock += 1;
+        *used = self.clock;
+        self.by_use.insert(self.clock, id);
+        Some(data.clone())
+    }
+
+    fn insert(&mut self, id: BlockId, data: Arc<[u8]>) {
+        self.clock += 1;
+        self.bytes += data.len();
+        if let Some((old, used)) = self.blocks.insert(id, (data, self.clock)) {
+            self.bytes -= old.len();
+            self.by_use.remove(&used);
+        }
+        self.by_use.insert(self.clock, id);
+    }
+
+    fn evict(&mut self) {
+        if let Some((_, id)) = self.by_use.pop_first() {
+            let (data, _) = self.blocks.remove(&id).unwrap();
+            self.bytes -= data.len();
+        }
+    }
+}
+
+#[test]
+fn test_block_cache() -> Result<()> {
+    let cache = BlockCache::new(300);
+    let load = |n: u8| move || Ok(vec![n; 100]);
+    for block in 0..3 {
+        cache.get(0, block, load(block as u8))?;
+    }
+    assert_eq!(cache.stats(), (0, 3));
+    // Using block 0 makes block 1 the least recently used.
+    assert_eq!(&*cache.get(0, 0, load(9))?, &[0; 100][..]);
+    cache.get(1, 0, load(3))?;
+    assert_eq!(cache.stats(), (1, 4));
+    assert_eq!(&*cache.get(0, 2, load(9))?, &[2; 100][..]);
+    assert_eq!(&*cache.get(0, 1, load(9))?, &[9; 100][..]);
+    assert_eq!(cache.stats(), (2, 5));
+    assert!(cache.lru.lock()?.bytes <= 300);
+
+    // Blocks too big for the cache are passed through.
+    let none = BlockCache::new(0);
+    none.get(0, 0, load(1))?;
+    none.get(0, 0, load(1))?;
+    assert_eq!(none.stats(), (0, 2));
+    assert!(none.lru.lock()?.blocks.is_empty());
+
+    Ok(())
+}
@@ -0,0 +1,124 @@
+#[cfg(test)]
+use crate::{segment, Db, Options, Result, Retention};
+use std::{
+    fmt::Debug,
+    sync::Mutex,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+#[cfg(test)]
+use tempfile::tempdir;
+
+// Where the database gets the time from, set with `Options::clock`. It's what
+// decides when keys expire and how old segments are for `Retention::age`, and
+// what batches, syncs and stalls are timed with. Background threads, like an
+// `ExpirySweeper` or `Maintenance`, still wait in real time between runs.
+pub trait Clock: Debug + Send + Sync {
+    fn now(&self) -> Instant;
+    fn system_time(&self) -> SystemTime;
+}
+
+// The time as the operating system tells it.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn system_time(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+// A clock that starts at the time it's made and only moves on when it's told
+// to, so that tests of TTLs and the like don't have to sleep.
+#[derive(Debug)]
+pub struct MockClock {
+    start: Instant,
+    system_start: SystemTime,
+    elapsed: Mutex<Duration>,
+}
+
+impl MockClock {
+    pub fn new() -> Self {
+        MockClock {
+            start: Instant::now(),
+            system_start: SystemTime::now(),
+            elapsed: Mutex::default(),
+        }
+    }
+
+    pub fn advance(&self, by: Duration) {
+        *crate::error::lock(&self.elapsed) += by;
+    }
+}
+
+impl Default for MockClock {
+    fn default() -> Self {
+        MockClock::new()
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        self.start + *crate::error::lock(&self.elapsed)
+    }
+
+    fn system_time(&self) -> SystemTime {
+        self.system_start + *crate::error::lock(&self.elapsed)
+    }
+}
+
+// Milliseconds since the Unix epoch by `clock`, which is what expiry times
+// are kept in.
+pub(crate) fn millis(clock: &dyn Clock) -> u64 {
+    clock
+        .system_time()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64)
+}
+
+#[test]
+fn test_mock_clock() -> Result<()> {
+    let dir = tempdir()?;
+    let file = dir.path().join("logfile");
+    let clock = std::sync::Arc::new(MockClock::new());
+    let options = Options {
+        segment_size: 4096,
+        retention: Retention {
+            age: Some(Duration::from_secs(3600)),
+            ..Retention::default()
+        },
+        clock: clock.clone(),
+        ..Options::default()
+    };
+    let mut db = Db::with_options(&file, options)?;
+    db.set_with_ttl("day", "v", Duration::from_secs(24 * 3600))?;
+    db.set_with_ttl("week", "v", Duration::from_secs(7 * 24 * 3600))?;
+    for i in 0..200 {
+        db.set(&format!("k{}", i % 10), "v")?;
+    }
+
+    // Segments are kept for an hour after they're last written to.
+    db.compact()?;
+    let kept = segment::list(&file)?.0.len();
+    assert!(kept > 1);
+    clock.advance(Duration::from_secs(3599));
+    db.compact()?;
+    assert_eq!(segment::list(&file)?.0.len(), kept + 1);
+    clock.advance(Duration::from_secs(2));
+    db.compact()?;
+    assert_eq!(segment::list(&file)?.0.len(), 1);
+
+    // Keys expire as the clock says.
+    assert_eq!(db.get("day"), Some("v".into()));
+    clock.advance(Duration::from_secs(24 * 3600));
+    assert_eq!(db.get("day"), None);
+    assert_eq!(db.get("week"), Some("v".into()));
+    assert_eq!(db.expire()?, 1);
+    clock.advance(Duration::from_secs(6 * 24 * 3600));
+    assert_eq!(db.len(), 10);
+    assert_eq!(db.expire()?, 1);
+    Ok(())
+}
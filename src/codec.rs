@@ -0,0 +1,243 @@
+use crate::{binary, Command, Error, Result};
+use serde::Deserialize;
+use std::{fmt, sync::Arc};
+
+// How commands are turned into records in the log, and back again (see
+// `Options::codec`). Every segment's header says which codec its records were
+// written with, so a log written with one codec can be read with any other,
+// and tools can tell how to decode a log they know nothing else about.
+//
+// Besides the two here, a codec can be anything that round-trips a `Command`,
+// such as MessagePack or CBOR through their serde crates. Reading a log that
+// has segments in a codec of your own needs that same codec passed in again.
+pub trait RecordCodec: fmt::Debug + Send + Sync {
+    // What segment headers call this codec. `Json` and `Binary` are 1 and 2;
+    // anything else should pick a number from 128 up, so as not to collide
+    // with codecs added here later.
+    fn id(&self) -> u32;
+    fn name(&self) -> &str;
+    fn encode(
+        &self,
+        command: &Command,
+    ) -> std::result::Result<Vec<u8>, Box<dyn std::error::Error + Send + Sync>>;
+    fn decode(
+        &self,
+        payload: &[u8],
+    ) -> std::result::Result<Command, Box<dyn std::error::Error + Send + Sync>>;
+}
+
+// Records as JSON, which is the easiest to pick apart with other tools.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Json;
+
+// Records in the format in `binary`, which is around half the size of JSON and
+// somewhat quicker to write and read back (see the `codec_bench` bin).
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Binary;
+
+impl RecordCodec for Json {
+    fn id(&self) -> u32 {
+        1
+    }
+
+    fn name(&self) -> &str {
+        "json"
+    }
+
+    fn encode(
+        &self,
+        command: &Command,
+    ) -> std::result::Result<Vec<u8>, Box<dyn std::error::Error + Send + Sync>> {
+        Ok(serde_json::to_vec(command)?)
+    }
+
+    fn decode(
+        &self,
+        payload: &[u8],
+    ) -> std::result::Result<Command, Box<dyn std::error::Error + Send + Sync>> {
+        Ok(serde_json::from_slice(payload)?)
+    }
+}
+
+impl RecordCodec for Binary {
+    fn id(&self) -> u32 {
+        2
+    }
+
+    fn name(&self) -> &str {
+        "binary"
+    }
+
+    fn encode(
+        &self,
+        command: &Command,
+    ) -> std::result::Result<Vec<u8>, Box<dyn std::error::Error + Send + Sync>> {
+        Ok(binary::to_vec(command)?)
+    }
+
+    fn decode(
+        &self,
+        payload: &[u8],
+    ) -> std::result::Result<Command, Box<dyn std::error::Error + Send + Sync>> {
+        Ok(binary::from_slice(payload)?)
+    }
+}
+
+// The codec here with the given id, if there is one.
+pub fn builtin(id: u32) -> Option<Arc<dyn RecordCodec>> {
+    match id {
+        1 => Some(Arc::new(Json)),
+        2 => Some(Arc::new(Binary)),
+        _ => None,
+    }
+}
+
+pub(crate) fn encode(codec: &dyn RecordCodec, command: &Command) -> Result<Vec<u8>> {
+    codec.encode(command).map_err(Error::Encode)
+}
+
+// Decodes the records of a segment, given the id its header has and the codec
+// the log was opened with.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Decoder<'a> {
+    Json,
+    Binary,
+    Other(&'a dyn RecordCodec),
+}
+
+impl<'a> Decoder<'a> {
+    pub fn new(id: u32, codec: &'a dyn RecordCodec) -> Result<Self> {
+        match id {
+            1 => Ok(Decoder::Json),
+            2 => Ok(Decoder::Binary),
+            id if id == codec.id() => Ok(Decoder::Other(codec)),
+            id => Err(Error::InvalidConfig(format!(
+                "the log has records in codec {}, which it wasn't opened with",
+                id
+            ))),
+        }
+    }
+
+    // Decodes the record at `offset` in `segment`. The built-in codecs can
+    // decode it as anything, and borrow from `payload` where `T` can;
+    // others only know how to decode a `Command`.
+    pub fn decode<'p, T>(self, segment: u64, offset: u64, payload: &'p [u8]) -> Result<T>
+    where
+        T: Deserialize<'p> + From<Command>,
+    {
+        let decoded = match self {
+            Decoder::Json => serde_json::from_slice(payload).map_err(|e| e.to_string()),
+            Decoder::Binary => binary::from_slice(payload).map_err(|e| e.to_string()),
+            Decoder::Other(codec) => codec
+                .decode(payload)
+                .map(T::from)
+                .map_err(|e| e.to_string()),
+        };
+        decoded.map_err(|e| Error::corruption(segment, offset, e))
+    }
+}
+
+// JSON written back to front, so that nothing else can read it.
+#[cfg(test)]
+#[derive(Debug)]
+struct Reversed;
+
+#[cfg(test)]
+impl RecordCodec for Reversed {
+    fn id(&self) -> u32 {
+        200
+    }
+
+    fn name(&self) -> &str {
+        "reversed"
+    }
+
+    fn encode(
+        &self,
+        command: &Command,
+    ) -> std::result::Result<Vec<u8>, Box<dyn std::error::Error + Send + Sync>> {
+        let mut payload = serde_json::to_vec(command)?;
+        payload.reverse();
+        Ok(payload)
+    }
+
+    fn decode(
+        &self,
+        payload: &[u8],
+    ) -> std::result::Result<Command, Box<dyn std::error::Error + Send + Sync>> {
+        let payload = payload.iter().rev().copied().collect::<Vec<_>>();
+        Ok(serde_json::from_slice(&payload)?)
+    }
+}
+
+#[test]
+fn test_codecs() -> Result<()> {
+    use crate::{segment, Db, LogReader, Options};
+
+    let dir = tempfile::tempdir()?;
+    let file = dir.path().join("logfile");
+    let options = |codec: Arc<dyn RecordCodec>| Options {
+        codec,
+        ..Options::default()
+    };
+
+    // Each time the codec changes, the log reads back just the same.
+    let mut db = Db::with_options(&file, options(Arc::new(Json)))?;
+    db.set("a", "1")?;
+    db.incr("n", 5)?;
+    db.log_custom(&("json", 1))?;
+    drop(db);
+    let mut db = Db::with_options(&file, options(Arc::new(Binary)))?;
+    assert_eq!(db.get("a").as_deref(), Some("1"));
+    db.set("b", "\"quoted\"")?;
+    db.incr("n", -2)?;
+    db.log_custom(&("binary", 2))?;
+    db.cf("cf").set("k", "v")?;
+    drop(db);
+    let mut db = Db::with_options(&file, options(Arc::new(Reversed)))?;
+    db.incr("n", 1)?;
+    db.log_custom(&("reversed", 3))?;
+    drop(db);
+
+    let mut customs = vec![];
+    let db = Db::with_custom_handler(&file, options(Arc::new(Reversed)), |op: (String, u32)| {
+        customs.push(op);
+        Ok::<_, Error>(())
+    })?;
+    assert_eq!(db.get("b").as_deref(), Some("\"quoted\""));
+    assert_eq!(db.get("n").as_deref(), Some("4"));
+    assert_eq!(db.cf("cf").get("k").as_deref(), Some("v"));
+    assert_eq!(
+        customs,
+        [
+            ("json".into(), 1),
+            ("binary".into(), 2),
+            ("reversed".into(), 3)
+        ]
+    );
+    drop(db);
+
+    // Which codec each segment is in is there for tools to find.
+    let (numbers, _) = segment::list(&file)?;
+    let mut codecs = vec![];
+    for number in numbers {
+        let reader = segment::SegmentReader::open(&file, number)?;
+        codecs.push(reader.codec());
+    }
+    codecs.dedup();
+    assert_eq!(codecs, [1, 2, 200]);
+
+    // A codec that isn't built in has to be passed in to read it again.
+    let err = Db::new(&file).unwrap_err();
+    assert!(matches!(err, Error::InvalidConfig(_)), "{}", err);
+    assert!(LogReader::open(&file)?.any(|r| r.is_err()));
+    let reader = LogReader::open(&file)?.with_codec(Arc::new(Reversed));
+    assert_eq!(reader.collect::<Result<Vec<_>>>()?.len(), 9);
+
+    // Payloads all come out in the reader's codec.
+    let reader = LogReader::open(&file)?.with_codec(Arc::new(Reversed));
+    for payload in reader.payloads() {
+        Reversed.decode(&payload?.1).map_err(Error::Encode)?;
+    }
+    Ok(())
+}
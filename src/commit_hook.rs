@@ -0,0 +1,102 @@
+use crate::{error, Command, CommitCallbacks};
+use std::fmt;
+use std::sync::{Arc, Mutex, RwLock};
+
+type Callback = Arc<dyn Fn(u64, &[Command]) + Send + Sync>;
+
+// The callbacks registered with `Db::on_commit`, and the batches committed
+// without a sync that they haven't been given yet.
+pub(crate) struct CommitHooks {
+    when: CommitCallbacks,
+    callbacks: RwLock<Vec<Callback>>,
+    // Oldest first. Held while callbacks run, so that they get one batch at
+    // a time.
+    unsynced: Mutex<Vec<Committed>>,
+}
+
+// A batch as it was logged, from its first sequence number on.
+pub(crate) struct Committed {
+    first_seq: u64,
+    commands: Vec<Command>,
+    synced: bool,
+}
+
+impl fmt::Debug for CommitHooks {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CommitHooks")
+            .field("when", &self.when)
+            .field("callbacks", &error::read(&self.callbacks).len())
+            .finish()
+    }
+}
+
+impl CommitHooks {
+    pub fn new(when: CommitCallbacks) -> Self {
+        CommitHooks {
+            when,
+            callbacks: RwLock::new(vec![]),
+            unsynced: Mutex::new(vec![]),
+        }
+    }
+
+    pub fn add(&self, callback: Callback) {
+        error::write(&self.callbacks).push(callback);
+    }
+
+    // A copy of a batch about to be logged, if anyone wants to hear about it,
+    // since applying it uses it up.
+    pub fn capture(&self, first_seq: u64, commands: &[Command], synced: bool) -> Option<Committed> {
+        if commands.is_empty() || error::read(&self.callbacks).is_empty() {
+            return None;
+        }
+        Some(Committed {
+            first_seq,
+            commands: commands.to_vec(),
+            synced,
+        })
+    }
+
+    // Hands a committed batch to the callbacks if they run before its
+    // writers are told, or back to be given to `deliver` once they have
+    // been.
+    pub fn before_release(&self, committed: Option<Committed>) -> Option<Committed> {
+        match self.when {
+            CommitCallbacks::BeforeRelease => {
+                self.deliver(committed);
+                None
+            }
+            CommitCallbacks::AfterRelease => committed,
+        }
+    }
+
+    // Gives the callbacks a batch once it's durable, along with every
+    // unsynced batch before it, which it made durable too. An unsynced batch
+    // waits for that.
+    pub fn deliver(&self, committed: Option<Committed>) {
+        let Some(committed) = committed else {
+            return;
+        };
+        let mut unsynced = error::lock(&self.unsynced);
+        if !committed.synced {
+            unsynced.push(committed);
+            return;
+        }
+        self.run(unsynced.drain(..).chain([committed]));
+    }
+
+    // Gives the callbacks every unsynced batch, now that a sync of the whole
+    // log has made them durable.
+    pub fn synced(&self) {
+        let mut unsynced = error::lock(&self.unsynced);
+        self.run(unsynced.drain(..));
+    }
+
+    fn run(&self, batches: impl Iterator<Item = Committed>) {
+        let callbacks = error::read(&self.callbacks).clone();
+        for batch in batches {
+            for callback in &callbacks {
+                callback(batch.first_seq, &batch.commands);
+            }
+        }
+    }
+}
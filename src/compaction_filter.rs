@@ -0,0 +1,108 @@
+#[cfg(test)]
+use crate::{Db, Options, Result};
+use std::fmt::Debug;
+#[cfg(test)]
+use std::sync::{Arc, Mutex};
+#[cfg(test)]
+use tempfile::tempdir;
+
+// Decides what happens to each live key as `Db::compact` rewrites it, set
+// with `Options::compaction_filter`. That makes compaction somewhere to drop
+// keys past some retention policy, erase someone's data for good, or move
+// values to a new format, without having to write each change through the
+// log first.
+//
+// Whatever it decides is only written out by the compaction, not logged as
+// a write of its own, so it isn't seen by `on_commit` callbacks, followers or
+// open transactions. Values in the value log are read back for it, blobs
+// included, and a value it changes stays in the log and tables however long
+// it is. Keyspaces aren't filtered. It's called with the log locked, so
+// it mustn't write to the database.
+pub trait CompactionFilter: Debug + Send + Sync {
+    fn filter(&self, key: &str, value: &str) -> FilterDecision;
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FilterDecision {
+    Keep,
+    // The key is deleted.
+    Remove,
+    // The key is set to this instead, keeping any expiry it has.
+    Change(String),
+}
+
+#[cfg(test)]
+#[derive(Debug, Default)]
+struct Redact {
+    seen: Mutex<Vec<String>>,
+}
+
+#[cfg(test)]
+impl CompactionFilter for Redact {
+    fn filter(&self, key: &str, value: &str) -> FilterDecision {
+        self.seen.lock().unwrap().push(key.to_owned());
+        if key.starts_with("user/") {
+            FilterDecision::Remove
+        } else if value.contains("secret") {
+            FilterDecision::Change(value.replace("secret", "******"))
+        } else {
+            FilterDecision::Keep
+        }
+    }
+}
+
+#[test]
+fn test_compaction_filter() -> Result<()> {
+    let dir = tempdir()?;
+    let file = dir.path().join("logfile");
+    let redact = Arc::new(Redact::default());
+    let options = Options {
+        compaction_filter: Some(redact.clone()),
+        value_log: true,
+        value_separation_threshold: 100,
+        ..Options::default()
+    };
+    let long = format!("a secret {}", "x".repeat(200));
+    let check = |db: &Db| {
+        assert_eq!(db.get("user/1"), None);
+        assert_eq!(db.get("note"), Some("my ****** plan".to_owned()));
+        assert_eq!(db.get("other"), Some("hello".to_owned()));
+        assert_eq!(db.get("long"), Some(long.replace("secret", "******")));
+        assert!(db.memtable.read().key("note").expiry("note").is_some());
+        assert_eq!(db.cf("ks").get("user/2"), Some("kept".to_owned()));
+    };
+
+    // Compacting just the log changes the memtable along with it.
+    let mut db = Db::with_options(&file, options.clone())?;
+    db.set("user/1", "alice")?;
+    db.set_with_ttl(
+        "note",
+        "my secret plan",
+        std::time::Duration::from_secs(3600),
+    )?;
+    db.set("other", "hello")?;
+    db.set("long", &long)?;
+    db.cf("ks").set("user/2", "kept")?;
+    assert_eq!(db.get("user/1"), Some("alice".to_owned()));
+    db.compact()?;
+    assert_eq!(redact.seen.lock()?.len(), 4);
+    check(&db);
+    drop(db);
+    let mut db = Db::with_options(&file, options.clone())?;
+    check(&db);
+
+    // As does merging the tables.
+    db.flush()?;
+    db.set("user/3", "bob")?;
+    db.set("secret", "not secret")?;
+    db.flush()?;
+    db.compact()?;
+    check(&db);
+    assert_eq!(db.get("user/3"), None);
+    assert_eq!(db.get("secret"), Some("not ******".to_owned()));
+    drop(db);
+    let db = Db::with_options(&file, options)?;
+    check(&db);
+    assert_eq!(db.len(), 4);
+    Ok(())
+}
@@ -0,0 +1,113 @@
+// Crash testing: `test_crash_recovery` runs this same test binary again as a
+// child process that does nothing but `crash_child`, which writes as fast as
+// it can and prints every write once it's been acknowledged. The parent kills
+// it at some random point with SIGKILL, so that it gets no chance to clean up,
+// and then checks that reopening the database finds every acknowledged write.
+// Each round carries on in the same directory, so later rounds recover from
+// whatever the earlier ones left behind.
+use crate::{Db, Options, Result, Rng};
+use std::{
+    collections::HashMap,
+    env,
+    io::{BufRead, BufReader},
+    process::{Command, Stdio},
+    sync::mpsc,
+    thread,
+    time::Duration,
+};
+use tempfile::tempdir;
+
+const DIR_VAR: &str = "REDO_LOG_CRASH_DIR";
+const ROUND_VAR: &str = "REDO_LOG_CRASH_ROUND";
+
+// Small enough that the child rotates segments and flushes tables all the
+// time, so that it gets killed partway through those too.
+fn options() -> Options {
+    Options {
+        segment_size: 4096,
+        memtable_bytes: Some(4096),
+        ..Options::default()
+    }
+}
+
+#[test]
+fn crash_child() -> Result<()> {
+    let (Ok(dir), Ok(round)) = (env::var(DIR_VAR), env::var(ROUND_VAR)) else {
+        return Ok(());
+    };
+    let db = Db::with_options(&dir, options())?;
+    let threads = (0..4)
+        .map(|t| {
+            let (mut db, round) = (db.clone(), round.clone());
+            thread::spawn(move || -> Result<()> {
+                for i in 0.. {
+                    let k = format!("{}_{}_{}", round, t, i);
+                    db.set(&k, &format!("value{}", i))?;
+                    println!("ack {} value{}", k, i);
+                    if t == 0 && i % 100 == 99 {
+                        db.compact()?;
+                    }
+                }
+                Ok(())
+            })
+        })
+        .collect::<Vec<_>>();
+    for thread in threads {
+        thread.join().unwrap()?;
+    }
+    Ok(())
+}
+
+#[test]
+fn test_crash_recovery() -> Result<()> {
+    let dir = tempdir()?;
+    let (mut rng, seed) = Rng::for_test();
+    let mut acked = HashMap::new();
+    for round in 0..20 {
+        let mut child = Command::new(env::current_exe()?)
+            .args(["--exact", "crash::crash_child", "--nocapture"])
+            .env(DIR_VAR, dir.path())
+            .env(ROUND_VAR, round.to_string())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()?;
+        let stdout = BufReader::new(child.stdout.take().unwrap());
+        let (tx, rx) = mpsc::channel();
+        let reader = thread::spawn(move || {
+            for line in stdout.split(b'\n') {
+                // A line cut off by the kill has no newline, and so doesn't
+                // show up here at all.
+                let Ok(line) = line else { break };
+                let line = String::from_utf8_lossy(&line);
+                if let Some(["ack", k, v]) = line.split(' ').collect::<Vec<_>>().get(..) {
+                    if tx.send((k.to_string(), v.to_string())).is_err() {
+                        break;
+                    }
+                }
+            }
+        });
+        // Wait for the child to get going, and then let it run for a random
+        // little while.
+        let first = rx.recv_timeout(Duration::from_secs(60));
+        thread::sleep(Duration::from_millis(rng.below(30)));
+        child.kill()?;
+        child.wait()?;
+        reader.join().unwrap();
+        let first = first.unwrap_or_else(|_| panic!("round {} never acked a write", round));
+        acked.extend(std::iter::once(first).chain(rx.try_iter()));
+
+        let db = Db::with_options(dir.path(), options())?;
+        for (k, v) in &acked {
+            assert_eq!(
+                db.get(k).as_ref(),
+                Some(v),
+                "lost {} after round {} with seed {}",
+                k,
+                round,
+                seed
+            );
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,177 @@
+use crate::Durability;
+use crate::Result;
+use std::{
+    fs::{self, File, OpenOptions},
+    io,
+    path::Path,
+};
+#[cfg(test)]
+use tempfile::tempdir;
+
+pub fn sync_file(file: &File, durability: Durability) -> Result<()> {
+    match durability {
+        Durability::Media => sync_to_media(file)?,
+        Durability::Device => sync_to_device(file)?,
+        Durability::None => {}
+    }
+    Ok(())
+}
+
+// A plain fsync on macOS only hands the data to the drive, which is free to
+// keep it in a volatile cache. F_FULLFSYNC asks the drive to flush that cache
+// too.
+#[cfg(target_os = "macos")]
+fn sync_to_media(file: &File) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+    if unsafe { libc::fcntl(file.as_raw_fd(), libc::F_FULLFSYNC) } == -1 {
+        // Some filesystems (network mounts, mostly) don't support it, in which
+        // case an fsync is the best we can do.
+        return file.sync_all();
+    }
+    Ok(())
+}
+
+// On Linux fsync already flushes the drive's cache, and since segments are
+// preallocated we only ever need the data, so fdatasync suffices. On Windows
+// this is FlushFileBuffers.
+#[cfg(not(target_os = "macos"))]
+fn sync_to_media(file: &File) -> io::Result<()> {
+    file.sync_data()
+}
+
+#[cfg(target_os = "macos")]
+fn sync_to_device(file: &File) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+    if unsafe { libc::fsync(file.as_raw_fd()) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "macos"))]
+fn sync_to_device(file: &File) -> io::Result<()> {
+    file.sync_data()
+}
+
+#[cfg(unix)]
+pub fn sync_dir(dir: &Path) -> Result<()> {
+    File::open(dir)?.sync_all()?;
+    Ok(())
+}
+
+// Directories can't be opened as files on Windows, and NTFS journals the
+// metadata change for us anyway.
+#[cfg(not(unix))]
+pub fn sync_dir(_dir: &Path) -> Result<()> {
+    Ok(())
+}
+
+fn sync_parent(path: &Path) -> Result<()> {
+    match path.parent() {
+        Some(parent) if parent.as_os_str().is_empty() => sync_dir(Path::new(".")),
+        Some(parent) => sync_dir(parent),
+        None => Ok(()),
+    }
+}
+
+// Creating, renaming, or removing a file only changes the directory that
+// contains it, and that change is not durable until the directory itself has
+// been synced. These wrappers do the filesystem operation and then sync
+// whichever directories it touched.
+
+pub fn create_dir_all(dir: &Path) -> Result<()> {
+    // Find the deepest ancestor that already exists, since everything below it
+    // is new and every new directory needs its parent synced.
+    let mut missing = vec![];
+    let mut cur = Some(dir);
+    while let Some(d) = cur {
+        if d.as_os_str().is_empty() || d.exists() {
+            break;
+        }
+        missing.push(d);
+        cur = d.parent();
+    }
+    fs::create_dir_all(dir)?;
+    for d in missing.into_iter().rev() {
+        sync_parent(d)?;
+    }
+    Ok(())
+}
+
+pub fn create_new(path: &Path) -> Result<File> {
+    let file = OpenOptions::new().write(true).create_new(true).open(path)?;
+    sync_parent(path)?;
+    Ok(file)
+}
+
+pub fn rename(from: &Path, to: &Path) -> Result<()> {
+    fs::rename(from, to)?;
+    sync_parent(to)?;
+    if from.parent() != to.parent() {
+        sync_parent(from)?;
+    }
+    Ok(())
+}
+
+pub fn remove_file(path: &Path) -> Result<()> {
+    fs::remove_file(path)?;
+    sync_parent(path)
+}
+
+pub fn remove_dir(path: &Path) -> Result<()> {
+    fs::remove_dir(path)?;
+    sync_parent(path)
+}
+
+#[test]
+fn test_create_dir_all() -> Result<()> {
+    let dir = tempdir()?;
+    let nested = dir.path().join("a").join("b").join("c");
+
+    create_dir_all(&nested)?;
+    assert!(nested.is_dir());
+    // Creating it again is fine.
+    create_dir_all(&nested)?;
+
+    Ok(())
+}
+
+#[test]
+fn test_create_rename_remove() -> Result<()> {
+    let dir = tempdir()?;
+    let a = dir.path().join("a");
+    let b = dir.path().join("b");
+
+    create_new(&a)?;
+    assert!(create_new(&a).is_err());
+    rename(&a, &b)?;
+    assert!(!a.exists());
+    assert!(b.exists());
+    remove_file(&b)?;
+    assert!(!b.exists());
+    assert!(remove_file(&b).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_sync_file() -> Result<()> {
+    let dir = tempdir()?;
+    let file = create_new(&dir.path().join("f"))?;
+
+    for durability in [Durability::Media, Durability::Device, Durability::None] {
+        sync_file(&file, durability)?;
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_sync_dir() -> Result<()> {
+    let dir = tempdir()?;
+
+    sync_dir(dir.path())?;
+    assert!(sync_dir(&dir.path().join("missing")).is_err() || cfg!(not(unix)));
+
+    Ok(())
+}
@@ -0,0 +1,259 @@
+use std::{
+    fmt, io,
+    path::PathBuf,
+    sync::{Mutex, MutexGuard, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard},
+};
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+// Everything that can go wrong in this crate, split up by what a caller might
+// want to do about it.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Error {
+    Io(io::Error),
+    // A record that made it into the log intact but can't be decoded.
+    Corruption {
+        segment: u64,
+        offset: u64,
+        reason: String,
+    },
+    // A segment that isn't one this build knows how to read, either because
+    // it was written by a newer one or because it isn't a segment at all.
+    // Nothing is read from a log with one of these in it.
+    IncompatibleFormat {
+        segment: u64,
+        reason: String,
+    },
+    // A table file that has been damaged.
+    CorruptTable {
+        table: u64,
+        reason: String,
+    },
+    // A LevelDB or RocksDB database that `interop::import_leveldb` can't
+    // carry over: a file it can't make sense of, or a key or value that isn't
+    // UTF-8. Nothing was imported.
+    Import(String),
+    // A value in the value log (see `Options::value_log`) that has been
+    // damaged.
+    CorruptValue {
+        file: u64,
+        offset: u64,
+        reason: String,
+    },
+    // A command that couldn't be serialized to be written to the log.
+    Encode(Box<dyn std::error::Error + Send + Sync>),
+    // A thread panicked while holding one of the database's locks, which may
+    // have left what it guards half-updated. Writes fail with this until the
+    // database is reopened, which rebuilds everything from the log; reads
+    // carry on with whatever the panic left behind.
+    Poisoned,
+    InvalidConfig(String),
+    // Another process has the log open for writing.
+    Busy(PathBuf),
+    // A write to a log that was opened read-only.
+    ReadOnly,
+    // Records that were needed had already been compacted away, starting
+    // from this one.
+    Compacted {
+        seq: u64,
+    },
+    // A write was committed locally, but not enough followers acknowledged it
+    // in time.
+    QuorumTimeout {
+        seq: u64,
+        acked: usize,
+        needed: usize,
+    },
+    // Anything else that went wrong between a primary and a follower.
+    Replication(String),
+    // A request that doesn't make sense given what's in the Raft log.
+    Raft(String),
+    // A client of the RESP front-end sent something we can't parse.
+    Protocol(String),
+    // A handler passed to `Db::with_custom_handler` failed.
+    Handler(Box<dyn std::error::Error + Send + Sync>),
+    // A transaction touched a key that something else wrote after it started.
+    // Nothing it wrote was committed, and running it again from the start
+    // may well succeed.
+    Conflict {
+        key: String,
+    },
+    // `Db::lock_timeout` gave up waiting for someone else to release the lock
+    // on a key.
+    LockTimeout {
+        key: String,
+    },
+    // A write or sync of the log failed, either for a batch this write was
+    // part of or for an earlier one. How much of it reached the disk is
+    // unknown, so nothing more can be written until the log is reopened.
+    WriteFailed(String),
+    // A write to a database after `Db::close`.
+    Closed,
+    // A write turned away because `Options::max_pending_writes` were already
+    // waiting to be committed, with `Backpressure::Fail`. Nothing was
+    // written, so it's safe to try again later.
+    Overloaded {
+        pending: usize,
+    },
+    // Replay was stopped with `RecoveryOptions::cancel`.
+    Cancelled,
+    // A write that would have taken a namespace (see `Options::namespaces`)
+    // past its quota, which holds this much already. Nothing was written.
+    QuotaExceeded {
+        namespace: String,
+        bytes: u64,
+        keys: u64,
+    },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "{}", e),
+            Error::Corruption {
+                segment,
+                offset,
+                reason,
+            } => write!(
+                f,
+                "corrupt record in segment {} at offset {}: {}",
+                segment, offset, reason
+            ),
+            Error::IncompatibleFormat { segment, reason } => {
+                write!(f, "can't read segment {}: {}", segment, reason)
+            }
+            Error::CorruptTable { table, reason } => {
+                write!(f, "corrupt table {}: {}", table, reason)
+            }
+            Error::CorruptValue {
+                file,
+                offset,
+                reason,
+            } => write!(
+                f,
+                "corrupt value in value log file {} at offset {}: {}",
+                file, offset, reason
+            ),
+            Error::Import(msg) => write!(f, "can't import: {}", msg),
+            Error::Encode(e) => write!(f, "couldn't encode command: {}", e),
+            Error::Poisoned => write!(f, "a thread panicked while holding a lock"),
+            Error::InvalidConfig(msg) => write!(f, "{}", msg),
+            Error::Busy(path) => write!(
+                f,
+                "{} is locked by another process that has the log open",
+                path.display()
+            ),
+            Error::ReadOnly => write!(f, "the log is open read-only"),
+            Error::Compacted { seq } => write!(f, "seq {} is no longer in the log", seq),
+            Error::QuorumTimeout { seq, acked, needed } => write!(
+                f,
+                "committed locally, but only {} of {} followers acknowledged seq {} in time",
+                acked, needed, seq
+            ),
+            Error::Replication(msg) => write!(f, "replication: {}", msg),
+            Error::Raft(msg) => write!(f, "{}", msg),
+            Error::Protocol(msg) => write!(f, "{}", msg),
+            Error::Handler(e) => write!(f, "custom command handler: {}", e),
+            Error::Conflict { key } => {
+                write!(
+                    f,
+                    "transaction conflict: {:?} was written concurrently",
+                    key
+                )
+            }
+            Error::LockTimeout { key } => write!(f, "timed out waiting for the lock on {:?}", key),
+            Error::WriteFailed(msg) => write!(f, "write to the log failed: {}", msg),
+            Error::Closed => write!(f, "the database has been closed"),
+            Error::Overloaded { pending } => {
+                write!(f, "too many writes pending: {} already waiting", pending)
+            }
+            Error::Cancelled => write!(f, "recovery was cancelled"),
+            Error::QuotaExceeded {
+                namespace,
+                bytes,
+                keys,
+            } => write!(
+                f,
+                "namespace {} is over its quota, with {} keys taking up {} bytes",
+                namespace, keys, bytes
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            Error::Encode(e) | Error::Handler(e) => Some(&**e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<redo_log_core::Incompatible> for Error {
+    fn from(e: redo_log_core::Incompatible) -> Self {
+        Error::IncompatibleFormat {
+            segment: e.segment,
+            reason: e.reason,
+        }
+    }
+}
+
+impl<T> From<PoisonError<T>> for Error {
+    fn from(_: PoisonError<T>) -> Self {
+        Error::Poisoned
+    }
+}
+
+impl Error {
+    pub(crate) fn corruption(segment: u64, offset: u64, reason: impl fmt::Display) -> Self {
+        Error::Corruption {
+            segment,
+            offset,
+            reason: reason.to_string(),
+        }
+    }
+
+    // Whether this is because a file was missing, such as a segment that was
+    // retired while we were reading the log.
+    pub(crate) fn is_not_found(&self) -> bool {
+        matches!(self, Error::Io(e) if e.kind() == io::ErrorKind::NotFound)
+    }
+}
+
+// Locks `mutex` for something that can't fail, like a read, even if a thread
+// panicked while holding it. Anything that writes takes the lock with `?`
+// instead, and fails with `Error::Poisoned`.
+pub(crate) fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+// The same for an `RwLock`.
+pub(crate) fn read<T>(lock: &RwLock<T>) -> RwLockReadGuard<'_, T> {
+    lock.read().unwrap_or_else(PoisonError::into_inner)
+}
+
+pub(crate) fn write<T>(lock: &RwLock<T>) -> RwLockWriteGuard<'_, T> {
+    lock.write().unwrap_or_else(PoisonError::into_inner)
+}
+
+// Decodes the record at `offset` in `segment`.
+pub(crate) fn decode<'a, T>(segment: u64, offset: u64, payload: &'a [u8]) -> Result<T>
+where
+    T: serde::Deserialize<'a>,
+{
+    serde_json::from_slice(payload).map_err(|e| Error::corruption(segment, offset, e))
+}
+
+// Encodes a record to be written to the log.
+pub(crate) fn encode<T: serde::Serialize>(value: &T) -> Result<Vec<u8>> {
+    serde_json::to_vec(value).map_err(|e| Error::Encode(e.into()))
+}
@@ -0,0 +1,128 @@
+use crate::{error, Db, Result};
+#[cfg(test)]
+use crate::{table, MockClock, Options};
+use std::{
+    sync::{Arc, Condvar, Mutex},
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+#[cfg(test)]
+use tempfile::tempdir;
+
+// Calls `Db::expire` every so often on a thread of its own, from
+// `Db::start_expiry_sweeper`, until it's stopped or dropped. The thread holds
+// a clone of the database, so the database isn't closed until the sweeper is
+// stopped too.
+// It skips sweeps while `Db::pause_background_work` has it paused.
+#[derive(Debug)]
+pub struct ExpirySweeper {
+    stopped: Arc<(Mutex<bool>, Condvar)>,
+    thread: Option<JoinHandle<Result<()>>>,
+}
+
+impl ExpirySweeper {
+    pub(crate) fn start(mut db: Db, interval: Duration) -> Self {
+        let stopped = Arc::new((Mutex::new(false), Condvar::new()));
+        let thread = {
+            let stopped = stopped.clone();
+            thread::spawn(move || loop {
+                let guard = stopped.0.lock()?;
+                let (guard, _) = stopped.1.wait_timeout(guard, interval)?;
+                if *guard {
+                    return Ok(());
+                }
+                drop(guard);
+                if let Some(_running) = db.background.begin() {
+                    db.expire()?;
+                }
+            })
+        };
+        ExpirySweeper {
+            stopped,
+            thread: Some(thread),
+        }
+    }
+
+    // Stops the sweeper and waits for it to finish, returning whatever error
+    // stopped it early if something did.
+    pub fn stop(mut self) -> Result<()> {
+        self.signal();
+        let thread = self.thread.take().unwrap();
+        thread
+            .join()
+            .unwrap_or_else(|e| std::panic::resume_unwind(e))
+    }
+
+    fn signal(&self) {
+        *error::lock(&self.stopped.0) = true;
+        self.stopped.1.notify_all();
+    }
+}
+
+impl Drop for ExpirySweeper {
+    fn drop(&mut self) {
+        if let Some(thread) = self.thread.take() {
+            self.signal();
+            let _ = thread.join();
+        }
+    }
+}
+
+#[test]
+fn test_ttl() -> Result<()> {
+    let dir = tempdir()?;
+    let file = dir.path().join("logfile");
+    let clock = Arc::new(MockClock::new());
+    let options = Options {
+        segment_size: 4096,
+        memtable_bytes: Some(2048),
+        clock: clock.clone(),
+        ..Options::default()
+    };
+    let mut db = Db::with_options(&file, options.clone())?;
+    db.set_with_ttl("short", "v", Duration::from_millis(50))?;
+    db.set_with_ttl("long", "v", Duration::from_secs(3600))?;
+    db.set_with_ttl("reset", "v", Duration::from_millis(50))?;
+    db.set("reset", "forever")?;
+    db.set("plain", "v")?;
+    // Enough to flush the expiring keys out to a table.
+    for i in 0..50 {
+        db.set(&format!("filler{:02}", i), &"x".repeat(50))?;
+    }
+    assert!(!table::list(&file)?.is_empty());
+    assert_eq!(db.get("short"), Some("v".into()));
+    clock.advance(Duration::from_millis(60));
+
+    // Expired keys disappear from reads straight away, but stay in the log
+    // until they're swept.
+    assert_eq!(db.get("short"), None);
+    assert_eq!(db.get("long"), Some("v".into()));
+    assert_eq!(db.get("reset"), Some("forever".into()));
+    assert_eq!(db.scan("").len(), 53);
+    assert_eq!(db.len(), 53);
+    drop(db);
+    let mut db = Db::with_options(&file, options.clone())?;
+    assert_eq!(db.get("short"), None);
+    assert_eq!(db.expire()?, 1);
+    assert_eq!(db.expire()?, 0);
+
+    // The sweeper does the same on its own.
+    db.set_with_ttl("swept", "v", Duration::from_millis(10))?;
+    clock.advance(Duration::from_millis(10));
+    let seq = db.next_seq();
+    let sweeper = db.start_expiry_sweeper(Duration::from_millis(5));
+    let start = std::time::Instant::now();
+    while db.next_seq() == seq {
+        assert!(start.elapsed() < Duration::from_secs(10));
+        thread::sleep(Duration::from_millis(5));
+    }
+    sweeper.stop()?;
+    db.compact()?;
+    drop(db);
+    let db = Db::with_options(&file, options)?;
+    assert_eq!(db.get("swept"), None);
+    assert_eq!(db.get("long"), Some("v".into()));
+    assert_eq!(db.memtable.read().expiries().count(), 1);
+
+    Ok(())
+}
@@ -0,0 +1,264 @@
+use crate::{Command, Db, Error, Result, WriteOptions};
+use serde::{Deserialize, Serialize};
+use std::io::{BufRead, BufReader, Read, Write};
+#[cfg(test)]
+use tempfile::tempdir;
+
+// How `Db::export` writes a database's contents out, and `Db::import` reads
+// them back in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    // One object per line, with `key` and `value`, and `keyspace` for keys in
+    // a named keyspace (see `Db::cf`).
+    Json,
+    // A `keyspace,key,value` header and then a row per key, quoted where
+    // needed as in RFC 4180. The keyspace is empty for the default one. An
+    // import only needs `key` and `value` columns, in any order.
+    Csv,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    keyspace: Option<String>,
+    key: String,
+    value: String,
+}
+
+// Writes every live key out to `writer`, the default keyspace in key order
+// and then each named keyspace in turn. The default keyspace is read from a
+// snapshot, so writes carrying on alongside don't show up halfway through it.
+// Returns the number of keys written.
+pub(crate) fn export(db: &Db, writer: &mut dyn Write, format: Format) -> Result<u64> {
+    let mut out = Writer {
+        writer,
+        format,
+        written: 0,
+    };
+    if format == Format::Csv {
+        out.writer.write_all(b"keyspace,key,value\n")?;
+    }
+    let snapshot = db.snapshot();
+    let mut failed = None;
+    db.merged("", Some(snapshot.seq()), |k, v| {
+        if failed.is_none() {
+            failed = out.write(None, k, v).err();
+        }
+    })?;
+    if let Some(e) = failed {
+        return Err(e);
+    }
+    for name in db.cf_names() {
+        for (k, v) in db.cf(&name).scan("") {
+            out.write(Some(&name), k, v)?;
+        }
+    }
+    out.writer.flush()?;
+    Ok(out.written)
+}
+
+struct Writer<'a> {
+    writer: &'a mut dyn Write,
+    format: Format,
+    written: u64,
+}
+
+impl Writer<'_> {
+    fn write(&mut self, keyspace: Option<&str>, key: String, value: String) -> Result<()> {
+        match self.format {
+            Format::Json => {
+                let entry = Entry {
+                    keyspace: keyspace.map(str::to_owned),
+                    key,
+                    value,
+                };
+                serde_json::to_writer(&mut *self.writer, &entry)
+                    .map_err(|e| Error::Encode(e.into()))?;
+                self.writer.write_all(b"\n")?;
+            }
+            Format::Csv => {
+                let row = [keyspace.unwrap_or(""), &key, &value]
+                    .map(csv_field)
+                    .join(",");
+                writeln!(self.writer, "{}", row)?;
+            }
+        }
+        self.written += 1;
+        Ok(())
+    }
+}
+
+// Reads keys in from `reader`, as `export` writes them, setting each one. The
+// writes aren't synced one by one, only all together once they're in.
+// Returns the number of keys set.
+pub(crate) fn import(db: &mut Db, reader: &mut dyn Read, format: Format) -> Result<u64> {
+    let options = WriteOptions {
+        sync: false,
+        ..db.write_options
+    };
+    let mut reader = BufReader::new(reader);
+    let mut imported = 0;
+    let mut set = |entry: Entry| {
+        let command = match entry.keyspace {
+            Some(name) => Command::KeyspaceSet(name, entry.key, entry.value),
+            None => Command::Set(entry.key, entry.value),
+        };
+        imported += 1;
+        db.apply_command_with_options(command, &options)
+    };
+    match format {
+        Format::Json => {
+            let mut line = String::new();
+            let mut number = 0;
+            while reader.read_line(&mut line)? > 0 {
+                number += 1;
+                if !line.trim().is_empty() {
+                    let entry = serde_json::from_str(&line)
+                        .map_err(|e| bad_input(number, &e.to_string()))?;
+                    set(entry)?;
+                }
+                line.clear();
+            }
+        }
+        Format::Csv => {
+            let mut number = 0;
+            let header = match read_csv_row(&mut reader, &mut number)? {
+                Some(header) => header,
+                None => return Ok(0),
+            };
+            let column = |name: &str| header.iter().position(|h| h == name);
+            let (key, value) = match (column("key"), column("value")) {
+                (Some(key), Some(value)) => (key, value),
+                _ => return Err(bad_input(1, "the header has no key or no value column")),
+            };
+            let keyspace = column("keyspace");
+            while let Some(mut row) = read_csv_row(&mut reader, &mut number)? {
+                if row.len() != header.len() {
+                    return Err(bad_input(
+                        number,
+                        &format!("{} fields, not {}", row.len(), header.len()),
+                    ));
+                }
+                let keyspace = keyspace
+                    .map(|i| std::mem::take(&mut row[i]))
+                    .filter(|name| !name.is_empty());
+                set(Entry {
+                    keyspace,
+                    key: std::mem::take(&mut row[key]),
+                    value: std::mem::take(&mut row[value]),
+                })?;
+            }
+        }
+    }
+    db.sync()?;
+    Ok(imported)
+}
+
+fn bad_input(line: usize, reason: &str) -> Error {
+    Error::InvalidConfig(format!("line {} of the import: {}", line, reason))
+}
+
+// `field` as a CSV field, quoted if it has to be.
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_owned()
+    }
+}
+
+// Reads the next CSV row, which runs over more than one line where a quoted
+// field has line breaks in it. `line` counts the lines read so far. Returns
+// None at the end of the input.
+fn read_csv_row(reader: &mut dyn BufRead, line: &mut usize) -> Result<Option<Vec<String>>> {
+    let mut text = String::new();
+    let mut fields = vec![];
+    let mut field = String::new();
+    let mut quoted = false;
+    loop {
+        text.clear();
+        if reader.read_line(&mut text)? == 0 {
+            if quoted {
+                return Err(bad_input(*line, "a quoted field runs to the end"));
+            }
+            return Ok(None);
+        }
+        *line += 1;
+        let mut chars = text.chars().peekable();
+        while let Some(c) = chars.next() {
+            match (quoted, c) {
+                (true, '"') if chars.peek() == Some(&'"') => {
+                    chars.next();
+                    field.push('"');
+                }
+                (true, '"') => quoted = false,
+                (true, c) => field.push(c),
+                (false, '"') if field.is_empty() => quoted = true,
+                (false, ',') => fields.push(std::mem::take(&mut field)),
+                (false, '\r') if chars.peek() == Some(&'\n') => {}
+                (false, '\n') => {}
+                (false, c) => field.push(c),
+            }
+        }
+        if !quoted {
+            break;
+        }
+    }
+    fields.push(field);
+    Ok(Some(fields))
+}
+
+#[test]
+fn test_export_import() -> Result<()> {
+    use crate::Options;
+
+    let dir = tempdir()?;
+    let options = Options {
+        memtable_bytes: Some(256),
+        ..Options::default()
+    };
+    let mut db = Db::with_options(dir.path().join("from"), options)?;
+    for i in 0..50 {
+        db.set(&format!("key{:02}", i), &format!("value{}", i))?;
+    }
+    db.delete("key07")?;
+    db.set("comma", "a,b")?;
+    db.set("quote", "say \"hi\"")?;
+    db.set("lines", "one\r\ntwo\n")?;
+    db.set("", "empty key")?;
+    db.set("unicode", "héllo ✓")?;
+    db.cf("users").set("alice", "1")?;
+    db.cf("users").set("bob", "x,\"y\"")?;
+    let expected = (db.scan(""), db.cf("users").scan(""));
+
+    for format in [Format::Json, Format::Csv] {
+        let mut exported = vec![];
+        assert_eq!(db.export(&mut exported, format)?, 56);
+
+        let mut to = Db::new(dir.path().join(format!("{:?}", format)))?;
+        assert_eq!(to.import(&mut &exported[..], format)?, 56);
+        assert_eq!((to.scan(""), to.cf("users").scan("")), expected);
+
+        // Exporting what was imported gives back the same thing.
+        let mut again = vec![];
+        to.export(&mut again, format)?;
+        assert_eq!(again, exported);
+    }
+
+    // A CSV made by hand only needs keys and values.
+    let mut to = Db::new(dir.path().join("by-hand"))?;
+    let csv = "value,key\r\n1,a\r\n\"2\n3\",b\r\n";
+    assert_eq!(to.import(&mut csv.as_bytes(), Format::Csv)?, 2);
+    assert_eq!(to.get("b").as_deref(), Some("2\n3"));
+
+    for (bad, format) in [
+        ("key,value\n1,2,3\n", Format::Csv),
+        ("key,value\n\"1,2\n", Format::Csv),
+        ("keyspace,value\n1,2\n", Format::Csv),
+        ("{\"key\":\"a\"}\n", Format::Json),
+    ] {
+        let err = to.import(&mut bad.as_bytes(), format).unwrap_err();
+        assert!(matches!(err, Error::InvalidConfig(_)), "{}", err);
+    }
+    Ok(())
+}
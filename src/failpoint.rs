@@ -0,0 +1,349 @@
+use std::{collections::HashMap, io, sync::Mutex};
+#[cfg(test)]
+use {
+    crate::segment::{self, End, SegmentReader},
+    crate::{Db, Error, MirrorPolicy, Options, RecoveryMode, RecoveryOptions, Result},
+    std::{fs, sync::Arc, thread},
+    tempfile::tempdir,
+};
+
+// Places in the write path where a test can make the log fail on purpose, to
+// see what a crash or a bad disk at just that moment would leave behind. Only
+// there with the `failpoints` feature, and set with `Options::failpoints`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Failpoint {
+    // Before a batch is written to the active segment, and after.
+    BeforeWrite,
+    AfterWrite,
+    // Before the active segment is synced, and after.
+    BeforeSync,
+    AfterSync,
+    // The steps of rotating to a new segment: before the active segment's
+    // footer is written, and after, before it's synced; then before the next
+    // segment is created, and after it's created and its directory synced,
+    // before it's recorded in the log's manifest.
+    BeforeSeal,
+    AfterSeal,
+    BeforeCreate,
+    AfterCreate,
+    // Before a batch is written to the mirror's active segment, or it's
+    // synced (see `Options::log_mirror`). The mirror's segments only reach
+    // these two.
+    MirrorWrite,
+    MirrorSync,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FailAction {
+    // Fail with an I/O error.
+    Error,
+    // Write only the first this many bytes of the batch and then fail, as a
+    // crash partway through the write would. Anywhere but `BeforeWrite`,
+    // `BeforeSeal` and `MirrorWrite`, it's the same as `Error`.
+    PartialWrite(usize),
+    // Write the batch with this bit of it flipped, counting from its first
+    // byte and wrapping around, and carry on as if nothing had gone wrong, as
+    // a bad disk or cable would. Where `PartialWrite` only writes, that is;
+    // anywhere else, it's the same as `Error`.
+    FlipBit(usize),
+}
+
+// The failpoints that are armed. Each one fires every time it's reached until
+// it's cleared.
+#[derive(Debug, Default)]
+pub struct Failpoints {
+    armed: Mutex<HashMap<Failpoint, FailAction>>,
+}
+
+impl Failpoints {
+    pub fn set(&self, point: Failpoint, action: FailAction) {
+        self.armed.lock().unwrap().insert(point, action);
+    }
+
+    pub fn clear(&self, point: Failpoint) {
+        self.armed.lock().unwrap().remove(&point);
+    }
+
+    pub(crate) fn hit(&self, point: Failpoint) -> Option<FailAction> {
+        self.armed.lock().unwrap().get(&point).copied()
+    }
+}
+
+pub(crate) fn error(point: Failpoint) -> io::Error {
+    io::Error::other(format!("failpoint {:?}", point))
+}
+
+#[cfg(test)]
+fn open(dir: &std::path::Path, failpoints: &Arc<Failpoints>) -> Result<Db> {
+    Db::with_options(
+        dir,
+        Options {
+            segment_size: 4096,
+            failpoints: Some(failpoints.clone()),
+            ..Options::default()
+        },
+    )
+}
+
+#[test]
+fn test_crash_before_sync() -> Result<()> {
+    let dir = tempdir()?;
+    let failpoints = Arc::new(Failpoints::default());
+    let mut db = open(dir.path(), &failpoints)?;
+    db.set("a", "1")?;
+    failpoints.set(Failpoint::AfterWrite, FailAction::Error);
+    assert!(matches!(db.set("b", "2"), Err(Error::Io(_))));
+    // Nothing more goes into a log that might be missing a write.
+    failpoints.clear(Failpoint::AfterWrite);
+    assert!(matches!(db.set("c", "3"), Err(Error::WriteFailed(_))));
+    assert_eq!(db.get("b"), None);
+    drop(db);
+
+    // The write that failed made it into the file, just not necessarily to
+    // the disk, so with the machine still up it's there after all.
+    let db = open(dir.path(), &failpoints)?;
+    assert_eq!(db.get("a"), Some("1".into()));
+    assert_eq!(db.get("b"), Some("2".into()));
+    assert_eq!(db.get("c"), None);
+    Ok(())
+}
+
+#[test]
+fn test_partial_write() -> Result<()> {
+    let dir = tempdir()?;
+    let failpoints = Arc::new(Failpoints::default());
+    let mut db = open(dir.path(), &failpoints)?;
+    db.set("a", "1")?;
+    failpoints.set(Failpoint::BeforeWrite, FailAction::PartialWrite(10));
+    assert!(db.set("b", "2").is_err());
+    failpoints.clear(Failpoint::BeforeWrite);
+    drop(db);
+
+    let mut db = open(dir.path(), &failpoints)?;
+    assert_eq!(db.recovery_report().torn.len(), 1);
+    assert_eq!(db.get("a"), Some("1".into()));
+    assert_eq!(db.get("b"), None);
+    db.set("b", "2")?;
+    drop(db);
+    let db = open(dir.path(), &failpoints)?;
+    assert_eq!(db.get("b"), Some("2".into()));
+    Ok(())
+}
+
+#[test]
+fn test_flipped_write() -> Result<()> {
+    let dir = tempdir()?;
+    let failpoints = Arc::new(Failpoints::default());
+    let mut db = open(dir.path(), &failpoints)?;
+    db.set("a", "1")?;
+    // The write goes through as far as the log can tell, and so do the ones
+    // after it.
+    failpoints.set(Failpoint::BeforeWrite, FailAction::FlipBit(100));
+    db.set("b", "2")?;
+    failpoints.clear(Failpoint::BeforeWrite);
+    db.set("c", "3")?;
+    drop(db);
+
+    assert!(open(dir.path(), &failpoints).is_err());
+    let db = Db::with_options(
+        dir.path(),
+        Options {
+            segment_size: 4096,
+            recovery: RecoveryOptions {
+                mode: RecoveryMode::SkipCorrupt,
+                ..RecoveryOptions::default()
+            },
+            ..Options::default()
+        },
+    )?;
+    assert_eq!(db.recovery_report().skipped.len(), 1);
+    assert_eq!(db.get("a"), Some("1".into()));
+    assert_eq!(db.get("b"), None);
+    assert_eq!(db.get("c"), Some("3".into()));
+    Ok(())
+}
+
+// A batch to a sharded log isn't applied until it's synced, so one whose
+// sync fails can't have been read in the meantime, by the batches after it
+// included.
+#[test]
+fn test_sharded_sync_error() -> Result<()> {
+    let dir = tempdir()?;
+    let failpoints = Arc::new(Failpoints::default());
+    let mut db = Db::with_options(
+        dir.path(),
+        Options {
+            log_shards: 2,
+            failpoints: Some(failpoints.clone()),
+            ..Options::default()
+        },
+    )?;
+    db.incr("n", 1)?;
+    failpoints.set(Failpoint::BeforeSync, FailAction::Error);
+    assert!(db.incr("n", 1).is_err());
+    assert_eq!(db.get("n"), Some("1".into()));
+    assert!(db.incr("n", 1).is_err());
+    assert_eq!(db.get("n"), Some("1".into()));
+    Ok(())
+}
+
+#[test]
+fn test_sync_error() -> Result<()> {
+    let dir = tempdir()?;
+    let failpoints = Arc::new(Failpoints::default());
+    let db = open(dir.path(), &failpoints)?;
+    failpoints.set(Failpoint::BeforeSync, FailAction::Error);
+    // Everyone waiting on a batch that failed hears about it, rather than
+    // waiting forever.
+    let writers = (0..8)
+        .map(|i| {
+            let mut db = db.clone();
+            thread::spawn(move || db.set(&format!("k{}", i), "v"))
+        })
+        .collect::<Vec<_>>();
+    for writer in writers {
+        assert!(writer.join().unwrap().is_err());
+    }
+    Ok(())
+}
+
+// A batch staged while its sync fails is never applied, so none of it can be
+// read.
+#[test]
+fn test_sync_error_while_staged() -> Result<()> {
+    let dir = tempdir()?;
+    let failpoints = Arc::new(Failpoints::default());
+    let mut db = Db::with_options(
+        dir.path(),
+        Options {
+            apply_during_sync: Some(1),
+            memtable_shards: 4,
+            failpoints: Some(failpoints.clone()),
+            ..Options::default()
+        },
+    )?;
+    db.set("a", "1")?;
+    failpoints.set(Failpoint::BeforeSync, FailAction::Error);
+    let mut txn = db.transaction();
+    txn.set("a", "2");
+    txn.set("b", "2");
+    assert!(matches!(txn.commit(), Err(Error::Io(_))));
+    failpoints.clear(Failpoint::BeforeSync);
+    assert_eq!(db.get("a"), Some("1".into()));
+    assert_eq!(db.get("b"), None);
+    assert!(matches!(db.set("c", "3"), Err(Error::WriteFailed(_))));
+    assert_eq!(db.get("c"), None);
+    Ok(())
+}
+
+#[test]
+fn test_mirror_failure() -> Result<()> {
+    let dir = tempdir()?;
+    let (file, mirror) = (dir.path().join("db"), dir.path().join("mirror"));
+    let failpoints = Arc::new(Failpoints::default());
+    let options = |policy| Options {
+        segment_size: 4096,
+        log_mirror: Some(mirror.clone()),
+        mirror_policy: policy,
+        failpoints: Some(failpoints.clone()),
+        ..Options::default()
+    };
+
+    // A batch that only reaches one copy fails when it has to reach both.
+    let mut db = Db::with_options(&file, options(MirrorPolicy::Both))?;
+    db.set("a", "1")?;
+    failpoints.set(Failpoint::MirrorSync, FailAction::Error);
+    assert!(matches!(db.set("b", "2"), Err(Error::Io(_))));
+    failpoints.clear(Failpoint::MirrorSync);
+    assert!(matches!(db.set("c", "3"), Err(Error::WriteFailed(_))));
+    drop(db);
+
+    // Otherwise the log carries on without the mirror until it's reopened.
+    let mut db = Db::with_options(&file, options(MirrorPolicy::Primary))?;
+    assert_eq!(db.get("b"), Some("2".into()));
+    failpoints.set(Failpoint::MirrorWrite, FailAction::PartialWrite(10));
+    for i in 0..100 {
+        db.set(&format!("k{}", i), "v")?;
+    }
+    failpoints.clear(Failpoint::MirrorWrite);
+    drop(db);
+    let db = Db::with_options(&file, options(MirrorPolicy::Primary))?;
+    assert_eq!(db.len(), 102);
+    drop(db);
+
+    // By which time the mirror has caught up.
+    for path in fs::read_dir(&file)? {
+        let path = path?.path();
+        if path.extension().is_some_and(|e| e == "log") {
+            assert_eq!(
+                fs::read(&path)?,
+                fs::read(mirror.join(path.file_name().unwrap()))?
+            );
+        }
+    }
+    Ok(())
+}
+
+// A crash at each step of rotating to a new segment loses nothing that was
+// acknowledged, and the log carries on from there once it's reopened. Only a
+// footer cut short leaves anything for replay to call torn.
+#[test]
+fn test_crash_during_rotation() -> Result<()> {
+    let steps = [
+        (Failpoint::BeforeSeal, FailAction::PartialWrite(10), true),
+        (Failpoint::BeforeSeal, FailAction::Error, false),
+        (Failpoint::AfterSeal, FailAction::Error, false),
+        (Failpoint::BeforeCreate, FailAction::Error, false),
+        (Failpoint::AfterCreate, FailAction::Error, false),
+    ];
+    for (point, action, torn) in steps {
+        let dir = tempdir()?;
+        let failpoints = Arc::new(Failpoints::default());
+        let mut db = open(dir.path(), &failpoints)?;
+        failpoints.set(point, action);
+        let mut acked = 0;
+        while db.set(&format!("k{}", acked), "v").is_ok() {
+            acked += 1;
+        }
+        assert!(acked > 1, "{:?}", point);
+        assert!(matches!(db.set("k", "v"), Err(Error::WriteFailed(_))));
+        failpoints.clear(point);
+        drop(db);
+
+        let mut db = open(dir.path(), &failpoints)?;
+        assert_eq!(db.len(), acked, "{:?}", point);
+        assert_eq!(
+            db.recovery_report().torn.len(),
+            torn as usize,
+            "{:?}",
+            point
+        );
+        for i in acked..acked * 3 {
+            db.set(&format!("k{}", i), "v")?;
+        }
+        drop(db);
+
+        // Nothing on disk looks torn but what the crash left, and the
+        // segments filled since end in footers.
+        let (segments, _) = segment::list(dir.path())?;
+        let mut reader = SegmentReader::open(dir.path(), segments[segments.len() - 2])?;
+        while reader.next_record(&mut vec![])?.is_some() {}
+        assert_eq!(reader.end(), Some(End::Sealed), "{:?}", point);
+        let db = Db::with_options(
+            dir.path(),
+            Options {
+                segment_size: 4096,
+                recovery: RecoveryOptions {
+                    mode: match torn {
+                        true => RecoveryMode::TolerateTornTail,
+                        false => RecoveryMode::Strict,
+                    },
+                    ..RecoveryOptions::default()
+                },
+                ..Options::default()
+            },
+        )?;
+        assert_eq!(db.len(), acked * 3, "{:?}", point);
+    }
+    Ok(())
+}
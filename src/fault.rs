@@ -0,0 +1,237 @@
+// Fault injection for recovery: `test_faults` makes the log's writes fail
+// partway through, or go through with a bit flipped, while it's being written,
+// and otherwise flips a bit of a closed segment the way a bad disk would. Then
+// it checks that reopening either gets back the state as of some prefix of
+// the writes, or says that something is wrong. It must never hand back a
+// value that was never written.
+//
+// The faults mid-write come from failpoints, which sit where the segment
+// writer writes through `WriteStorage`, so that they can land in any batch or
+// in the footer that rotating to the next segment writes.
+use crate::segment::{self, SEGMENT_HEADER_LEN};
+use crate::{
+    Db, FailAction, Failpoint, Failpoints, Options, RecoveryMode, RecoveryOptions, Result, Rng,
+};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fs::OpenOptions,
+    os::unix::fs::FileExt,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use tempfile::tempdir;
+
+struct Faults {
+    dir: PathBuf,
+    rng: Rng,
+}
+
+impl Faults {
+    fn next(&mut self, n: u64) -> u64 {
+        self.rng.below(n)
+    }
+
+    // Something to go wrong the next time the log writes a batch, or seals a
+    // segment, and whether it's there to be cleared once that write is done.
+    fn in_flight(&mut self) -> (Failpoint, FailAction, bool) {
+        let point = match self.next(2) {
+            0 => Failpoint::BeforeWrite,
+            _ => Failpoint::BeforeSeal,
+        };
+        match self.next(2) {
+            0 => (
+                point,
+                FailAction::PartialWrite(self.next(64) as usize),
+                false,
+            ),
+            _ => (
+                point,
+                FailAction::FlipBit(self.next(1 << 16) as usize),
+                point == Failpoint::BeforeWrite,
+            ),
+        }
+    }
+
+    // The segments with records in them, and where the last record ends.
+    fn segments(&self) -> Result<Vec<(u64, u64)>> {
+        let mut segments = vec![];
+        for number in segment::list(&self.dir)?.0 {
+            let mut records = 0;
+            let end = segment::read_records(&self.dir, number, &mut vec![], |_, _| {
+                records += 1;
+                Ok(())
+            })?;
+            if records > 0 {
+                segments.push((number, end));
+            }
+        }
+        Ok(segments)
+    }
+
+    // Flips one bit anywhere in a segment that has records, header included.
+    fn flip_bit(&mut self) -> Result<String> {
+        let segments = self.segments()?;
+        if segments.is_empty() {
+            return Ok("nothing to flip".into());
+        }
+        let (number, end) = segments[self.next(segments.len() as u64) as usize];
+        let at = self.next(end);
+        let bit = 1 << self.next(8);
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(segment::segment_path(&self.dir, number))?;
+        let mut byte = [0];
+        file.read_exact_at(&mut byte, at)?;
+        byte[0] ^= bit;
+        file.write_all_at(&byte, at)?;
+        let part = if at < SEGMENT_HEADER_LEN as u64 {
+            "header"
+        } else {
+            "records"
+        };
+        Ok(format!(
+            "flipped bit {:#x} at {} of segment {} ({})",
+            bit, at, number, part
+        ))
+    }
+}
+
+fn options(mode: RecoveryMode) -> Options {
+    Options {
+        segment_size: 4096,
+        recovery: RecoveryOptions {
+            mode,
+            ..RecoveryOptions::default()
+        },
+        ..Options::default()
+    }
+}
+
+// What the database should hold after each prefix of `writes`.
+fn prefixes(writes: &[(String, Option<String>)]) -> Vec<BTreeMap<String, String>> {
+    let mut state = BTreeMap::new();
+    let mut states = vec![state.clone()];
+    for (k, v) in writes {
+        match v {
+            Some(v) => state.insert(k.clone(), v.clone()),
+            None => state.remove(k),
+        };
+        states.push(state.clone());
+    }
+    states
+}
+
+fn check(dir: &Path, mode: RecoveryMode, writes: &[(String, Option<String>)]) -> Result<String> {
+    let db = match Db::with_options(dir, options(mode)) {
+        Ok(db) => db,
+        Err(e) => return Ok(format!("refused to open: {}", e)),
+    };
+    let keys = writes.iter().map(|(k, _)| k).collect::<HashSet<_>>();
+    let state = keys
+        .iter()
+        .filter_map(|&k| Some((k.clone(), db.get(k)?)))
+        .collect::<BTreeMap<_, _>>();
+    if let Some(p) = prefixes(writes).iter().rposition(|s| *s == state) {
+        return Ok(format!("kept {} of {} writes", p, writes.len()));
+    }
+    // Only skipping corrupt records can lose writes from the middle, and it
+    // has to say so.
+    let skipped = &db.recovery_report().skipped;
+    assert!(
+        !skipped.is_empty(),
+        "{:?} recovered something that's no prefix of the writes",
+        mode
+    );
+    let written = writes
+        .iter()
+        .filter_map(|(k, v)| Some((k, v.as_ref()?)))
+        .fold(HashMap::<_, HashSet<_>>::new(), |mut m, (k, v)| {
+            m.entry(k).or_default().insert(v);
+            m
+        });
+    for (k, v) in &state {
+        assert!(written[k].contains(v), "{} = {} was never written", k, v);
+    }
+    Ok(format!("skipped {:?}", skipped))
+}
+
+#[test]
+fn test_faults() -> Result<()> {
+    let (mut rng, seed) = Rng::for_test();
+    for round in 0..200 {
+        let dir = tempdir()?;
+        let mut faults = Faults {
+            dir: dir.path().to_path_buf(),
+            rng: Rng::new(rng.next_u64()),
+        };
+        let failpoints = Arc::new(Failpoints::default());
+        let options = Options {
+            failpoints: Some(failpoints.clone()),
+            ..options(RecoveryMode::Strict)
+        };
+        let mut db = Db::with_options(dir.path(), options)?;
+        let count = 1 + faults.next(200);
+        let at = faults.next(count + count / 4);
+        let mut fault = None;
+        let mut writes = vec![];
+        for i in 0..count {
+            if i == at {
+                let (point, action, clear) = faults.in_flight();
+                failpoints.set(point, action);
+                fault = Some((point, action, clear));
+            }
+            let k = format!("k{}", faults.next(10));
+            let (v, written) = if faults.next(5) == 0 {
+                (None, db.delete(&k))
+            } else {
+                let v = format!("v{}", i);
+                let written = db.set(&k, &v);
+                (Some(v), written)
+            };
+            // A write that failed might have made it into the log whole, or
+            // not at all, and either is a prefix of the writes. Nothing after
+            // it gets written.
+            writes.push((k, v));
+            if written.is_err() {
+                break;
+            }
+            if let Some((point, _, true)) = fault {
+                failpoints.clear(point);
+            }
+        }
+        drop(db);
+
+        let fault = match fault {
+            Some((point, action, _)) => format!("{:?} at {:?} in write {}", action, point, at),
+            None => faults.flip_bit()?,
+        };
+        for mode in [
+            RecoveryMode::Strict,
+            RecoveryMode::TolerateTornTail,
+            RecoveryMode::SkipCorrupt,
+        ] {
+            let copy = tempdir()?;
+            for entry in std::fs::read_dir(dir.path())? {
+                let path = entry?.path();
+                std::fs::copy(&path, copy.path().join(path.file_name().unwrap()))?;
+            }
+            let outcome = std::panic::catch_unwind(|| check(copy.path(), mode, &writes));
+            match outcome {
+                Ok(outcome) => {
+                    outcome?;
+                }
+                Err(e) => {
+                    panic!(
+                        "round {} with seed {}, after {}: {:?}",
+                        round,
+                        seed,
+                        fault,
+                        e.downcast_ref::<String>()
+                    );
+                }
+            }
+        }
+    }
+    Ok(())
+}
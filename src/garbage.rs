@@ -0,0 +1,254 @@
+// Keeps count of how much of each segment is dead: taken up by records that
+// compacting the log would leave out, because what they wrote has since been
+// overwritten or deleted, or because they're deletions themselves. That's
+// what `Options::compact_dead_ratio` goes by, and what `Stats` reports.
+//
+// Doing that means remembering which record last wrote each key, so it's
+// only done when `compact_dead_ratio` asks for it. Writes to keyspaces,
+// expiries and custom commands are never counted as dead, though they're
+// rewritten by compaction like everything else.
+use crate::memtable::KeyRange;
+use crate::segment;
+use crate::Command;
+#[cfg(test)]
+use crate::{Db, Options, Result};
+use std::collections::{BTreeMap, HashMap};
+#[cfg(test)]
+use tempfile::tempdir;
+
+// How much of a segment is dead, in `Stats::segment_garbage`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SegmentGarbage {
+    pub segment: u64,
+    pub records: u64,
+    // Of the records, including their headers.
+    pub bytes: u64,
+    pub dead_bytes: u64,
+    // Deletions, range deletions included.
+    pub tombstones: u64,
+}
+
+// What a record did, as far as which records it makes dead.
+pub(crate) enum Effect<'a> {
+    // Set each of these keys, or deleted it if it's false.
+    Keys(Vec<(&'a str, bool)>),
+    DeleteRange(KeyRange),
+    // A deletion of something that isn't counted, like a key in a keyspace.
+    Tombstone,
+    // Anything else, which stays live until the log is compacted.
+    Other,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct Ledger {
+    enabled: bool,
+    segments: BTreeMap<u64, SegmentGarbage>,
+    // The segment of the record that last set each key, and how many of the
+    // record's bytes that accounts for: all of them, unless it's a
+    // transaction that set several keys.
+    live: HashMap<Box<str>, (u64, u64)>,
+}
+
+impl Ledger {
+    pub fn new(enabled: bool) -> Self {
+        Ledger {
+            enabled,
+            ..Ledger::default()
+        }
+    }
+
+    // Counts a record of `len` bytes, header included, in `segment`.
+    pub fn record(&mut self, segment: u64, len: usize, effect: Effect) {
+        if !self.enabled {
+            return;
+        }
+        let len = len as u64;
+        let counts = self.segment(segment);
+        counts.records += 1;
+        counts.bytes += len;
+        match effect {
+            Effect::Keys(keys) => {
+                let share = len / keys.len().max(1) as u64;
+                for (k, set) in keys {
+                    self.kill(k);
+                    if set {
+                        self.live.insert(k.into(), (segment, share));
+                    } else {
+                        let counts = self.segment(segment);
+                        counts.dead_bytes += share;
+                        counts.tombstones += 1;
+                    }
+                }
+            }
+            Effect::DeleteRange(range) => {
+                let segments = &mut self.segments;
+                self.live.retain(|k, &mut (segment, share)| {
+                    if !range.contains(k) {
+                        return true;
+                    }
+                    if let Some(counts) = segments.get_mut(&segment) {
+                        counts.dead_bytes += share;
+                    }
+                    false
+                });
+                let counts = self.segment(segment);
+                counts.dead_bytes += len;
+                counts.tombstones += 1;
+            }
+            Effect::Tombstone => {
+                let counts = self.segment(segment);
+                counts.dead_bytes += len;
+                counts.tombstones += 1;
+            }
+            Effect::Other => {}
+        }
+    }
+
+    // Counts a batch that's just been appended to `log`, as `commands`
+    // encoded as `payloads`.
+    pub fn record_batch(
+        &mut self,
+        placed: &[(u64, usize)],
+        payloads: &[Vec<u8>],
+        commands: &[Command],
+    ) {
+        if !self.enabled {
+            return;
+        }
+        let mut start = 0;
+        for &(segment, end) in placed {
+            for (payload, command) in payloads[start..end].iter().zip(&commands[start..end]) {
+                self.record(
+                    segment,
+                    segment::HEADER_LEN + payload.len(),
+                    command.effect(),
+                );
+            }
+            start = end;
+        }
+    }
+
+    fn segment(&mut self, segment: u64) -> &mut SegmentGarbage {
+        self.segments
+            .entry(segment)
+            .or_insert_with(|| SegmentGarbage {
+                segment,
+                ..SegmentGarbage::default()
+            })
+    }
+
+    // The record that last set `k`, if any, is dead now.
+    fn kill(&mut self, k: &str) {
+        if let Some((segment, share)) = self.live.remove(k) {
+            self.segment(segment).dead_bytes += share;
+        }
+    }
+
+    // Forgets every segment, once they've been compacted away.
+    pub fn reset(&mut self) {
+        self.segments.clear();
+        self.live.clear();
+    }
+
+    pub fn segments(&self) -> Vec<SegmentGarbage> {
+        self.segments.values().cloned().collect()
+    }
+
+    // How many bytes of the log are dead, and how many there are in all.
+    pub fn dead(&self) -> (u64, u64) {
+        self.segments.values().fold((0, 0), |(dead, bytes), s| {
+            (dead + s.dead_bytes, bytes + s.bytes)
+        })
+    }
+}
+
+#[test]
+fn test_garbage() -> Result<()> {
+    let dir = tempdir()?;
+    let file = dir.path().join("logfile");
+    let options = Options {
+        segment_size: 4096,
+        // Too high to ever compact, so this only counts.
+        compact_dead_ratio: Some(1.0),
+        ..Options::default()
+    };
+    let mut db = Db::with_options(&file, options.clone())?;
+    assert!(db.stats()?.segment_garbage.is_empty());
+    for i in 0..100 {
+        db.set(&format!("k{:02}", i), &"first".repeat(10))?;
+    }
+    let stats = db.stats()?;
+    assert!(stats.segment_garbage.len() > 1, "{:?}", stats);
+    assert_eq!(stats.dead_log_bytes, 0);
+
+    // Overwriting and deleting keys makes the records that set them dead,
+    // and deletions are dead from the start.
+    for i in 0..50 {
+        db.set(&format!("k{:02}", i), "second")?;
+    }
+    db.delete("k50")?;
+    db.delete_range("k60", "k70")?;
+    let mut txn = db.transaction();
+    txn.set("k90", "third");
+    txn.delete("k91");
+    txn.commit()?;
+    db.cf("ks").set("k00", "v")?;
+    db.cf("ks").delete("k00")?;
+    let stats = db.stats()?;
+    let first = &stats.segment_garbage[0];
+    assert_eq!(first.segment, 1);
+    assert!(first.dead_bytes > first.bytes / 2, "{:?}", first);
+    let tombstones = stats
+        .segment_garbage
+        .iter()
+        .map(|s| s.tombstones)
+        .sum::<u64>();
+    assert_eq!(tombstones, 4);
+    let records = stats.segment_garbage.iter().map(|s| s.records).sum::<u64>();
+    assert_eq!(records, 155);
+    let dead = |stats: &crate::Stats| {
+        let bytes = stats.segment_garbage.iter().map(|s| s.bytes).sum::<u64>();
+        stats.dead_log_bytes as f64 / bytes as f64
+    };
+    assert!((0.4..0.6).contains(&dead(&stats)), "{:?}", stats);
+
+    // Replay comes up with the same counts.
+    drop(db);
+    let db = Db::with_options(&file, options.clone())?;
+    assert_eq!(db.stats()?.segment_garbage, stats.segment_garbage);
+
+    // Compacting leaves nothing dead.
+    db.compact()?;
+    let compacted = db.stats()?;
+    assert_eq!(compacted.dead_log_bytes, 0);
+    assert_eq!(
+        compacted
+            .segment_garbage
+            .iter()
+            .map(|s| s.records)
+            .sum::<u64>(),
+        88
+    );
+    drop(db);
+
+    // Past the ratio, and a segment's worth of dead bytes, the log is
+    // compacted without being asked.
+    let options = Options {
+        compact_dead_ratio: Some(0.5),
+        ..options
+    };
+    let mut db = Db::with_options(&file, options)?;
+    for i in 0..1000 {
+        db.set("hot", &format!("{}", i))?;
+        let stats = db.stats()?;
+        assert!(stats.log_records < 250, "{:?}", stats);
+        assert!(
+            dead(&stats) <= 0.5 || stats.dead_log_bytes < 4096,
+            "{:?}",
+            stats
+        );
+    }
+    assert_eq!(db.get("hot"), Some("999".to_owned()));
+    assert_eq!(db.get("k00"), Some("second".to_owned()));
+    Ok(())
+}
@@ -0,0 +1,153 @@
+// Tracks which keys are written most, for `Db::hot_keys`, when
+// `Options::hot_keys` asks for it.
+//
+// Every key written is counted in a count-min sketch: `DEPTH` rows of
+// `WIDTH` counters, each row indexed by a different hash of the key. A key's
+// count is the smallest of its counters, which can only be too high, and
+// then only by however many writes to other keys landed in all the same
+// counters. Alongside it we keep the keys with the highest counts seen so
+// far, so the sketch never has to be searched for them.
+use crate::error;
+use crate::Command;
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    hash::{Hash, Hasher},
+    sync::Mutex,
+};
+#[cfg(test)]
+use tempfile::tempdir;
+
+const WIDTH: usize = 4096;
+const DEPTH: usize = 4;
+
+#[derive(Debug)]
+pub(crate) struct HotKeys {
+    // How many keys to keep in `top`, or zero not to count anything.
+    capacity: usize,
+    counts: Mutex<Counts>,
+}
+
+#[derive(Debug, Default)]
+struct Counts {
+    sketch: Vec<u64>,
+    // The keys with the highest counts, and their counts.
+    top: HashMap<String, u64>,
+}
+
+impl HotKeys {
+    pub fn new(capacity: usize) -> Self {
+        HotKeys {
+            capacity,
+            counts: Mutex::default(),
+        }
+    }
+
+    // Counts a write to each key `command` writes to. A range deletion
+    // isn't a write to any key in particular, so it isn't counted.
+    pub fn record(&self, command: &Command) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut counts = error::lock(&self.counts);
+        match command.unwrap_request() {
+            Command::Transaction(writes) => {
+                for (k, _) in writes {
+                    counts.add(k, self.capacity);
+                }
+            }
+            command => {
+                if let Some(k) = command.key() {
+                    counts.add(k, self.capacity);
+                }
+            }
+        }
+    }
+
+    // Up to `n` of the most-written keys, most written first, with roughly
+    // how many times each was written.
+    pub fn top(&self, n: usize) -> Vec<(String, u64)> {
+        let counts = error::lock(&self.counts);
+        let mut top = counts
+            .top
+            .iter()
+            .map(|(k, &count)| (k.clone(), count))
+            .collect::<Vec<_>>();
+        top.sort_unstable_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        top.truncate(n);
+        top
+    }
+}
+
+impl Counts {
+    fn add(&mut self, k: &str, capacity: usize) {
+        if self.sketch.is_empty() {
+            self.sketch = vec![0; WIDTH * DEPTH];
+        }
+        let mut count = u64::MAX;
+        for row in 0..DEPTH {
+            let mut hasher = DefaultHasher::new();
+            (row, k).hash(&mut hasher);
+            let counter = &mut self.sketch[row * WIDTH + hasher.finish() as usize % WIDTH];
+            *counter += 1;
+            count = count.min(*counter);
+        }
+        if let Some(top) = self.top.get_mut(k) {
+            *top = count;
+            return;
+        }
+        if self.top.len() < capacity {
+            self.top.insert(k.to_owned(), count);
+            return;
+        }
+        // It takes the place of the coldest key, if it's hotter.
+        let (coldest, &least) = self.top.iter().min_by_key(|(_, &c)| c).unwrap();
+        if count > least {
+            let coldest = coldest.clone();
+            self.top.remove(&coldest);
+            self.top.insert(k.to_owned(), count);
+        }
+    }
+}
+
+#[test]
+fn test_hot_keys() -> crate::Result<()> {
+    use crate::{Db, Options};
+
+    let dir = tempdir()?;
+    let file = dir.path().join("db");
+    let mut db = Db::new(&file)?;
+    db.set("k", "v")?;
+    assert!(db.hot_keys(10).is_empty());
+    drop(db);
+
+    let options = Options {
+        hot_keys: 4,
+        ..Options::default()
+    };
+    let mut db = Db::with_options(&file, options)?;
+    // A few keys get most of the writes, among plenty that get one each.
+    for i in 0..3000 {
+        db.set(&format!("cold{}", i), "v")?;
+        if i % 10 == 0 {
+            db.set("hot", "v")?;
+        }
+        if i % 20 == 0 {
+            db.incr("warm", 1)?;
+        }
+        if i % 30 == 0 {
+            let mut txn = db.transaction();
+            txn.set("tepid", "v");
+            txn.commit()?;
+        }
+    }
+    db.delete_prefix("cold")?;
+    let top = db.hot_keys(3);
+    let keys = top.iter().map(|(k, _)| k.as_str()).collect::<Vec<_>>();
+    assert_eq!(keys, ["hot", "warm", "tepid"], "{:?}", top);
+    // Counts can only be too high, and only by a little with this few keys.
+    assert!((300..310).contains(&top[0].1), "{:?}", top);
+    assert!((150..160).contains(&top[1].1), "{:?}", top);
+    assert!((100..110).contains(&top[2].1), "{:?}", top);
+    assert_eq!(db.hot_keys(10).len(), 4);
+    Ok(())
+}
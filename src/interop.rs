@@ -0,0 +1,991 @@
+// Moving data between a `Db` and RocksDB or LevelDB.
+//
+// `export_sst` writes the default keyspace out as a table file in the format
+// the two share, which RocksDB's `IngestExternalFile` takes in as it would
+// one from its own `SstFileWriter`, and which LevelDB can read too.
+// `import_leveldb` goes the other way, reading the tables and write-ahead
+// logs of a LevelDB or RocksDB directory and setting every live key.
+//
+// Only as much of the formats is covered as it takes to get plain keys and
+// values across: tables with RocksDB's `format_version` up to 5, compressed
+// with Snappy or not at all, and only the default column family. Anything
+// else (merge operands, range deletions, blob files, other compression) is
+// refused rather than half-read.
+use crate::{Command, Db, Durability, Error, Result, WriteOptions};
+use std::{
+    collections::HashMap,
+    fs,
+    io::{BufWriter, Write},
+    path::Path,
+};
+#[cfg(test)]
+use tempfile::tempdir;
+
+// LevelDB's footer magic, which RocksDB still reads as `format_version` 0.
+const LEGACY_MAGIC: u64 = 0xdb47_7524_8b80_fb57;
+// RocksDB's, for `format_version` 1 and up.
+const BLOCK_BASED_MAGIC: u64 = 0x88e2_41b7_85f4_cff7;
+const LEGACY_FOOTER_LEN: usize = 48;
+const FOOTER_LEN: usize = 53;
+
+// A block's compression type and checksum come after it.
+const BLOCK_TRAILER_LEN: usize = 5;
+const NO_COMPRESSION: u8 = 0;
+const SNAPPY: u8 = 1;
+const CHECKSUM_NONE: u8 = 0;
+const CHECKSUM_CRC32C: u8 = 1;
+
+// Data blocks are cut once they reach this many bytes, with a restart point,
+// where a key is written out whole, every so many keys.
+const BLOCK_SIZE: usize = 4096;
+const RESTART_INTERVAL: usize = 16;
+
+// What the low byte of an internal key's trailer says it is.
+const TYPE_DELETION: u8 = 0;
+const TYPE_VALUE: u8 = 1;
+const TYPE_SINGLE_DELETION: u8 = 7;
+
+// Write-ahead logs are written in blocks of this size, each record with a
+// header of checksum, length and type.
+const LOG_BLOCK_SIZE: usize = 32 << 10;
+const LOG_HEADER_LEN: usize = 7;
+const RECYCLABLE_LOG_HEADER_LEN: usize = 11;
+
+// Writes every live key in the default keyspace to a new table file at
+// `path`, and returns how many there were. Every key gets sequence number 0
+// and the properties `SstFileWriter` would give it, as ingesting a file
+// requires. The keys are read from a snapshot, so writes carrying on
+// alongside don't show up partway through. Named keyspaces and expiry times
+// aren't carried over.
+pub fn export_sst(db: &Db, path: &Path) -> Result<u64> {
+    let file = crate::durable_fs::create_new(path)?;
+    let written = write_sst(db, file);
+    if written.is_err() {
+        // Don't leave half a table behind to be ingested by mistake.
+        let _ = fs::remove_file(path);
+    }
+    written
+}
+
+fn write_sst(db: &Db, file: fs::File) -> Result<u64> {
+    let mut table = TableWriter::new(BufWriter::new(file));
+    let snapshot = db.snapshot();
+    let mut failed = None;
+    db.merged("", Some(snapshot.seq()), |k, v| {
+        if failed.is_none() {
+            failed = table.add(k.as_bytes(), v.as_bytes()).err();
+        }
+    })?;
+    if let Some(e) = failed {
+        return Err(e);
+    }
+    if table.entries == 0 {
+        return Err(Error::InvalidConfig(
+            "there are no keys to export, and RocksDB won't ingest an empty table".into(),
+        ));
+    }
+    let entries = table.entries;
+    let file = table.finish()?;
+    let file = file.into_inner().map_err(|e| e.into_error())?;
+    crate::durable_fs::sync_file(&file, Durability::Media)?;
+    Ok(entries)
+}
+
+// Sets every key that's live in the LevelDB or RocksDB database in `dir`,
+// going by its tables and write-ahead logs, and returns how many there were.
+// Its `MANIFEST` isn't read, so it should be closed cleanly first, leaving no
+// obsolete files behind. Keys and values have to be UTF-8, or nothing is
+// imported. Keys the database already has are overwritten, and the rest left
+// alone.
+pub fn import_leveldb(db: &mut Db, dir: &Path) -> Result<u64> {
+    let mut latest: HashMap<Vec<u8>, (u64, Option<Vec<u8>>)> = HashMap::new();
+    let mut add = |key: &[u8], seq: u64, value: Option<&[u8]>| match latest.get(key) {
+        Some(&(newest, _)) if newest >= seq => {}
+        _ => {
+            latest.insert(key.to_vec(), (seq, value.map(<[u8]>::to_vec)));
+        }
+    };
+    let mut paths = fs::read_dir(dir)?
+        .map(|entry| Ok(entry?.path()))
+        .collect::<Result<Vec<_>>>()?;
+    paths.sort();
+    for path in paths {
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("sst") | Some("ldb") => read_table(&path, &mut add)?,
+            Some("log") => read_log(&path, &mut add)?,
+            _ => {}
+        }
+    }
+
+    // Every key and value is checked before anything is written, so that a
+    // database that can't be carried over is left as it was.
+    let mut live = latest
+        .into_iter()
+        .filter_map(|(k, (_, v))| Some((k, v?)))
+        .map(
+            |(k, v)| match (String::from_utf8(k), String::from_utf8(v)) {
+                (Ok(k), Ok(v)) => Ok((k, v)),
+                (Err(e), _) | (_, Err(e)) => Err(Error::Import(format!(
+                    "{:?} isn't UTF-8, which keys and values have to be",
+                    String::from_utf8_lossy(e.as_bytes())
+                ))),
+            },
+        )
+        .collect::<Result<Vec<_>>>()?;
+    live.sort_unstable();
+
+    let options = WriteOptions {
+        sync: false,
+        ..db.write_options
+    };
+    let mut imported = 0;
+    for (k, v) in live {
+        db.apply_command_with_options(Command::Set(k, v), &options)?;
+        imported += 1;
+    }
+    db.sync()?;
+    Ok(imported)
+}
+
+// Takes each write found in a file: the key, its sequence number, and its
+// value, or None if it was a deletion.
+type AddWrite<'a> = dyn FnMut(&[u8], u64, Option<&[u8]>) + 'a;
+
+struct TableWriter<W> {
+    out: W,
+    offset: u64,
+    block: BlockBuilder,
+    index: BlockBuilder,
+    last_key: Vec<u8>,
+    entries: u64,
+    blocks: u64,
+    raw_key_size: u64,
+    raw_value_size: u64,
+}
+
+impl<W: Write> TableWriter<W> {
+    fn new(out: W) -> Self {
+        TableWriter {
+            out,
+            offset: 0,
+            block: BlockBuilder::new(RESTART_INTERVAL),
+            index: BlockBuilder::new(1),
+            last_key: vec![],
+            entries: 0,
+            blocks: 0,
+            raw_key_size: 0,
+            raw_value_size: 0,
+        }
+    }
+
+    // Keys have to come in order.
+    fn add(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+        let mut internal = key.to_vec();
+        internal.extend_from_slice(&(TYPE_VALUE as u64).to_le_bytes());
+        self.block.add(&internal, value);
+        self.last_key = internal;
+        self.entries += 1;
+        self.raw_key_size += self.last_key.len() as u64;
+        self.raw_value_size += value.len() as u64;
+        if self.block.len() >= BLOCK_SIZE {
+            self.finish_data_block()?;
+        }
+        Ok(())
+    }
+
+    fn finish_data_block(&mut self) -> Result<()> {
+        if self.block.is_empty() {
+            return Ok(());
+        }
+        let block = std::mem::replace(&mut self.block, BlockBuilder::new(RESTART_INTERVAL));
+        let handle = self.write_block(&block.finish())?;
+        // The last key in the block is as good a separator as any.
+        self.index.add(&self.last_key, &handle);
+        self.blocks += 1;
+        Ok(())
+    }
+
+    // Writes out a block and its trailer, and returns its handle.
+    fn write_block(&mut self, contents: &[u8]) -> Result<Vec<u8>> {
+        let mut trailer = [NO_COMPRESSION, 0, 0, 0, 0];
+        let crc = crc32c_extend(crc32c(contents), &[NO_COMPRESSION]);
+        trailer[1..].copy_from_slice(&mask_crc(crc).to_le_bytes());
+        self.out.write_all(contents)?;
+        self.out.write_all(&trailer)?;
+        let mut handle = vec![];
+        put_varint(&mut handle, self.offset);
+        put_varint(&mut handle, contents.len() as u64);
+        self.offset += (contents.len() + BLOCK_TRAILER_LEN) as u64;
+        Ok(handle)
+    }
+
+    fn finish(mut self) -> Result<W> {
+        self.finish_data_block()?;
+        let data_size = self.offset;
+        let index = std::mem::replace(&mut self.index, BlockBuilder::new(1)).finish();
+
+        // Properties go in key order, numbers as varints except the two that
+        // `SstFileWriter` writes as fixed-width.
+        let mut properties = BlockBuilder::new(1);
+        let number = |n: u64| {
+            let mut v = vec![];
+            put_varint(&mut v, n);
+            v
+        };
+        let props: [(&str, Vec<u8>); 12] = [
+            ("rocksdb.comparator", b"leveldb.BytewiseComparator".to_vec()),
+            ("rocksdb.data.size", number(data_size)),
+            (
+                "rocksdb.external_sst_file.global_seqno",
+                0u64.to_le_bytes().to_vec(),
+            ),
+            (
+                "rocksdb.external_sst_file.version",
+                2u32.to_le_bytes().to_vec(),
+            ),
+            ("rocksdb.filter.size", number(0)),
+            ("rocksdb.fixed.key.length", number(0)),
+            ("rocksdb.format.version", number(0)),
+            ("rocksdb.index.size", number(index.len() as u64)),
+            ("rocksdb.num.data.blocks", number(self.blocks)),
+            ("rocksdb.num.entries", number(self.entries)),
+            ("rocksdb.raw.key.size", number(self.raw_key_size)),
+            ("rocksdb.raw.value.size", number(self.raw_value_size)),
+        ];
+        for (name, value) in &props {
+            properties.add(name.as_bytes(), value);
+        }
+        let properties = self.write_block(&properties.finish())?;
+        let mut metaindex = BlockBuilder::new(1);
+        metaindex.add(b"rocksdb.properties", &properties);
+        let metaindex = self.write_block(&metaindex.finish())?;
+        let index = self.write_block(&index)?;
+
+        let mut footer = vec![];
+        footer.extend_from_slice(&metaindex);
+        footer.extend_from_slice(&index);
+        footer.resize(LEGACY_FOOTER_LEN - 8, 0);
+        footer.extend_from_slice(&LEGACY_MAGIC.to_le_bytes());
+        self.out.write_all(&footer)?;
+        self.out.flush()?;
+        Ok(self.out)
+    }
+}
+
+// A block of key-value entries, each key sharing what it can of the one
+// before, and starting over whole at each restart point.
+struct BlockBuilder {
+    buf: Vec<u8>,
+    restarts: Vec<u32>,
+    interval: usize,
+    count: usize,
+    last_key: Vec<u8>,
+}
+
+impl BlockBuilder {
+    fn new(interval: usize) -> Self {
+        BlockBuilder {
+            buf: vec![],
+            restarts: vec![0],
+            interval,
+            count: 0,
+            last_key: vec![],
+        }
+    }
+
+    fn add(&mut self, key: &[u8], value: &[u8]) {
+        let mut shared = 0;
+        if self.count == self.interval {
+            self.restarts.push(self.buf.len() as u32);
+            self.count = 0;
+        } else {
+            shared = key
+                .iter()
+                .zip(&self.last_key)
+                .take_while(|(a, b)| a == b)
+                .count();
+        }
+        put_varint(&mut self.buf, shared as u64);
+        put_varint(&mut self.buf, (key.len() - shared) as u64);
+        put_varint(&mut self.buf, value.len() as u64);
+        self.buf.extend_from_slice(&key[shared..]);
+        self.buf.extend_from_slice(value);
+        self.last_key = key.to_vec();
+        self.count += 1;
+    }
+
+    fn is_empty(&self) -> bool {
+        self.buf.is_empty()
+    }
+
+    fn len(&self) -> usize {
+        self.buf.len() + 4 * (self.restarts.len() + 1)
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        for restart in &self.restarts {
+            self.buf.extend_from_slice(&restart.to_le_bytes());
+        }
+        self.buf
+            .extend_from_slice(&(self.restarts.len() as u32).to_le_bytes());
+        self.buf
+    }
+}
+
+// A table file being read, with what its footer says about how.
+struct Table<'a> {
+    path: &'a Path,
+    data: Vec<u8>,
+    checksum: u8,
+}
+
+fn read_table(path: &Path, add: &mut AddWrite) -> Result<()> {
+    let data = fs::read(path)?;
+    let bad = |reason: &str| bad_file(path, reason);
+    if data.len() < LEGACY_FOOTER_LEN {
+        return Err(bad("it's too short to be a table"));
+    }
+    let magic = u64::from_le_bytes(data[data.len() - 8..].try_into().unwrap());
+    let (footer, checksum, format_version) = match magic {
+        LEGACY_MAGIC => (&data[data.len() - LEGACY_FOOTER_LEN..], CHECKSUM_CRC32C, 0),
+        BLOCK_BASED_MAGIC if data.len() >= FOOTER_LEN => {
+            let footer = &data[data.len() - FOOTER_LEN..];
+            let version = u32::from_le_bytes(footer[FOOTER_LEN - 12..][..4].try_into().unwrap());
+            (&footer[1..], footer[0], version)
+        }
+        _ => {
+            return Err(bad(
+                "it doesn't end with a block-based table's magic number",
+            ))
+        }
+    };
+    if format_version > 5 {
+        return Err(bad(&format!(
+            "it's format_version {}, and only up to 5 can be read",
+            format_version
+        )));
+    }
+    if checksum != CHECKSUM_NONE && checksum != CHECKSUM_CRC32C {
+        return Err(bad(&format!(
+            "its blocks have checksums of type {}, and only CRC-32C can be read",
+            checksum
+        )));
+    }
+    let mut footer = footer;
+    let metaindex = get_handle(&mut footer).ok_or_else(|| bad("its footer is damaged"))?;
+    let index = get_handle(&mut footer).ok_or_else(|| bad("its footer is damaged"))?;
+    let table = Table {
+        path,
+        data,
+        checksum,
+    };
+
+    let mut global_seqno = 0;
+    let mut delta_encoded = false;
+    for (name, handle) in table.entries(&table.block(metaindex)?, false)? {
+        match &name[..] {
+            b"rocksdb.properties" => {
+                let handle = get_handle(&mut &handle[..]).ok_or_else(|| bad("bad properties"))?;
+                for (name, value) in table.entries(&table.block(handle)?, false)? {
+                    match &name[..] {
+                        b"rocksdb.external_sst_file.global_seqno" if value.len() == 8 => {
+                            global_seqno = u64::from_le_bytes(value[..].try_into().unwrap());
+                        }
+                        b"rocksdb.index.value.is.delta.encoded" => {
+                            delta_encoded = get_varint(&mut &value[..]) == Some(1);
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            b"rocksdb.range_del" => {
+                return Err(bad("it has range deletions, which can't be imported"));
+            }
+            _ => {}
+        }
+    }
+
+    for (_, handle) in table.entries(&table.block(index)?, delta_encoded)? {
+        let handle = get_handle(&mut &handle[..]).ok_or_else(|| bad("bad index entry"))?;
+        for (key, value) in table.entries(&table.block(handle)?, false)? {
+            if key.len() < 8 {
+                return Err(bad("a key is too short to be an internal key"));
+            }
+            let (user_key, trailer) = key.split_at(key.len() - 8);
+            let trailer = u64::from_le_bytes(trailer.try_into().unwrap());
+            let seq = match trailer >> 8 {
+                0 => global_seqno,
+                seq => seq,
+            };
+            match trailer as u8 {
+                TYPE_VALUE => add(user_key, seq, Some(&value)),
+                TYPE_DELETION | TYPE_SINGLE_DELETION => add(user_key, seq, None),
+                kind => {
+                    return Err(bad(&format!(
+                        "it has a key of type {}, which can't be imported",
+                        kind
+                    )))
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+impl Table<'_> {
+    // The contents of the block at `handle`, checked and decompressed.
+    fn block(&self, (offset, size): (u64, u64)) -> Result<Vec<u8>> {
+        let bad = |reason: &str| bad_file(self.path, reason);
+        let end = offset
+            .checked_add(size + BLOCK_TRAILER_LEN as u64)
+            .filter(|&end| end <= self.data.len() as u64)
+            .ok_or_else(|| bad("a block runs past the end of the file"))?;
+        let block = &self.data[offset as usize..end as usize];
+        let (contents, trailer) = block.split_at(size as usize);
+        if self.checksum == CHECKSUM_CRC32C {
+            let crc = crc32c_extend(crc32c(contents), &trailer[..1]);
+            let expected = u32::from_le_bytes(trailer[1..].try_into().unwrap());
+            if mask_crc(crc) != expected {
+                return Err(bad("a block's checksum doesn't match"));
+            }
+        }
+        match trailer[0] {
+            NO_COMPRESSION => Ok(contents.to_vec()),
+            SNAPPY => snappy_decompress(contents).ok_or_else(|| bad("a block isn't valid Snappy")),
+            kind => Err(bad(&format!(
+                "a block is compressed with type {}, and only Snappy can be read",
+                kind
+            ))),
+        }
+    }
+
+    // Every key and value in `block`. In an index block with `delta_encoded`
+    // values, as `format_version` 4 writes, values have no length, and only
+    // the first entry at each restart point has a whole block handle; the
+    // rest have the difference in size from the block before, and start
+    // right after it.
+    fn entries(&self, block: &[u8], delta_encoded: bool) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let bad = || bad_file(self.path, "a block is damaged");
+        if block.len() < 4 {
+            return Err(bad());
+        }
+        let packed = u32::from_le_bytes(block[block.len() - 4..].try_into().unwrap());
+        if packed >> 31 != 0 {
+            return Err(bad_file(
+                self.path,
+                "it has a data block hash index, which can't be read",
+            ));
+        }
+        let restarts_at = block
+            .len()
+            .checked_sub(4 * (packed as usize + 1))
+            .ok_or_else(bad)?;
+        let restarts = block[restarts_at..block.len() - 4]
+            .chunks(4)
+            .map(|c| u32::from_le_bytes(c.try_into().unwrap()) as usize)
+            .collect::<Vec<_>>();
+        let mut entries = vec![];
+        let mut rest = &block[..restarts_at];
+        let mut key = vec![];
+        let mut last_handle = (0, 0);
+        while !rest.is_empty() {
+            let at = restarts_at - rest.len();
+            let shared = get_varint(&mut rest).ok_or_else(bad)? as usize;
+            let non_shared = get_varint(&mut rest).ok_or_else(bad)? as usize;
+            let value_len = match delta_encoded {
+                true => 0,
+                false => get_varint(&mut rest).ok_or_else(bad)? as usize,
+            };
+            if shared > key.len() || rest.len() < non_shared + value_len {
+                return Err(bad());
+            }
+            key.truncate(shared);
+            key.extend_from_slice(&rest[..non_shared]);
+            rest = &rest[non_shared..];
+            let value = if delta_encoded {
+                let handle = if restarts.contains(&at) {
+                    get_handle(&mut rest).ok_or_else(bad)?
+                } else {
+                    let size =
+                        last_handle.1 as i64 + get_signed_varint(&mut rest).ok_or_else(bad)?;
+                    let offset = last_handle.0 + last_handle.1 + BLOCK_TRAILER_LEN as u64;
+                    (offset, size as u64)
+                };
+                last_handle = handle;
+                let mut value = vec![];
+                put_varint(&mut value, handle.0);
+                put_varint(&mut value, handle.1);
+                value
+            } else {
+                let value = rest[..value_len].to_vec();
+                rest = &rest[value_len..];
+                value
+            };
+            entries.push((key.clone(), value));
+        }
+        Ok(entries)
+    }
+}
+
+// Reads every write in a write-ahead log, stopping quietly where it was cut
+// off by a crash.
+fn read_log(path: &Path, add: &mut AddWrite) -> Result<()> {
+    let data = fs::read(path)?;
+    let mut record = vec![];
+    for block in data.chunks(LOG_BLOCK_SIZE) {
+        let mut rest = block;
+        while rest.len() >= LOG_HEADER_LEN {
+            let crc = u32::from_le_bytes(rest[..4].try_into().unwrap());
+            let len = u16::from_le_bytes(rest[4..6].try_into().unwrap()) as usize;
+            let kind = rest[6];
+            let header_len = match kind {
+                // Padding, or space that was never written.
+                0 => break,
+                1..=4 => LOG_HEADER_LEN,
+                5..=8 => RECYCLABLE_LOG_HEADER_LEN,
+                _ => return Ok(()),
+            };
+            if rest.len() < header_len + len {
+                return Ok(());
+            }
+            let payload = &rest[header_len..header_len + len];
+            let actual = crc32c_extend(crc32c(&rest[6..7]), &rest[7..header_len + len]);
+            if mask_crc(actual) != crc {
+                return Ok(());
+            }
+            rest = &rest[header_len + len..];
+            match kind {
+                // Whole, or first.
+                1 | 5 | 2 | 6 => record = payload.to_vec(),
+                // Middle, or last.
+                _ => record.extend_from_slice(payload),
+            }
+            if matches!(kind, 1 | 5 | 4 | 8) {
+                read_batch(path, &record, add)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+// Reads the writes in a write batch, which are numbered in sequence from the
+// batch's own sequence number.
+fn read_batch(path: &Path, batch: &[u8], add: &mut AddWrite) -> Result<()> {
+    let bad = || bad_file(path, "a write batch is damaged");
+    if batch.len() < 12 {
+        return Err(bad());
+    }
+    let mut seq = u64::from_le_bytes(batch[..8].try_into().unwrap());
+    let mut rest = &batch[12..];
+    while let Some((&tag, after)) = rest.split_first() {
+        rest = after;
+        // Writes to column families other than the default one are skipped,
+        // but they still take up a sequence number.
+        let default_cf = match tag {
+            4 | 5 | 8 => get_varint(&mut rest).ok_or_else(bad)? == 0,
+            _ => true,
+        };
+        match tag {
+            // Deletions.
+            0 | 4 | 7 | 8 => {
+                let key = get_slice(&mut rest).ok_or_else(bad)?;
+                if default_cf {
+                    add(key, seq, None);
+                }
+            }
+            // Values.
+            1 | 5 => {
+                let key = get_slice(&mut rest).ok_or_else(bad)?;
+                let value = get_slice(&mut rest).ok_or_else(bad)?;
+                if default_cf {
+                    add(key, seq, Some(value));
+                }
+            }
+            // A blob of the writer's own, which isn't a write.
+            3 => {
+                get_slice(&mut rest).ok_or_else(bad)?;
+                continue;
+            }
+            tag => {
+                return Err(bad_file(
+                    path,
+                    &format!(
+                        "a write batch has a write of type {}, which can't be imported",
+                        tag
+                    ),
+                ))
+            }
+        }
+        seq += 1;
+    }
+    Ok(())
+}
+
+fn bad_file(path: &Path, reason: &str) -> Error {
+    Error::Import(format!("{}: {}", path.display(), reason))
+}
+
+fn put_varint(buf: &mut Vec<u8>, mut n: u64) {
+    while n >= 0x80 {
+        buf.push(n as u8 | 0x80);
+        n >>= 7;
+    }
+    buf.push(n as u8);
+}
+
+fn get_varint(buf: &mut &[u8]) -> Option<u64> {
+    let mut n = 0;
+    for shift in (0..64).step_by(7) {
+        let (&b, rest) = buf.split_first()?;
+        *buf = rest;
+        n |= ((b & 0x7f) as u64) << shift;
+        if b < 0x80 {
+            return Some(n);
+        }
+    }
+    None
+}
+
+fn get_signed_varint(buf: &mut &[u8]) -> Option<i64> {
+    let n = get_varint(buf)?;
+    Some((n >> 1) as i64 ^ -((n & 1) as i64))
+}
+
+fn get_handle(buf: &mut &[u8]) -> Option<(u64, u64)> {
+    Some((get_varint(buf)?, get_varint(buf)?))
+}
+
+fn get_slice<'a>(buf: &mut &'a [u8]) -> Option<&'a [u8]> {
+    let len = get_varint(buf)? as usize;
+    if buf.len() < len {
+        return None;
+    }
+    let (slice, rest) = buf.split_at(len);
+    *buf = rest;
+    Some(slice)
+}
+
+// CRC-32C, which both formats use for their checksums, rather than the CRC-32
+// the rest of this crate uses.
+const CRC32C_TABLE: [u32; 256] = {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0x82f6_3b78
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+fn crc32c(data: &[u8]) -> u32 {
+    crc32c_extend(0, data)
+}
+
+fn crc32c_extend(crc: u32, data: &[u8]) -> u32 {
+    let mut crc = !crc;
+    for &b in data {
+        crc = CRC32C_TABLE[((crc ^ b as u32) & 0xff) as usize] ^ (crc >> 8);
+    }
+    !crc
+}
+
+// Stored checksums are masked, so that a checksum of data that itself holds
+// checksums doesn't come out predictably.
+fn mask_crc(crc: u32) -> u32 {
+    crc.rotate_right(15).wrapping_add(0xa282_ead8)
+}
+
+// Decompresses a block in Snappy's raw format: the length, and then a run of
+// literals and copies of what came before.
+fn snappy_decompress(mut input: &[u8]) -> Option<Vec<u8>> {
+    let len = get_varint(&mut input)? as usize;
+    let mut out = Vec::with_capacity(len);
+    while let Some((&tag, rest)) = input.split_first() {
+        input = rest;
+        let (len, offset) = match tag & 3 {
+            0 => {
+                let mut len = (tag >> 2) as usize;
+                if len >= 60 {
+                    let bytes = len - 59;
+                    if input.len() < bytes {
+                        return None;
+                    }
+                    let mut n = [0; 8];
+                    n[..bytes].copy_from_slice(&input[..bytes]);
+                    len = u64::from_le_bytes(n) as usize;
+                    input = &input[bytes..];
+                }
+                let len = len + 1;
+                if input.len() < len {
+                    return None;
+                }
+                out.extend_from_slice(&input[..len]);
+                input = &input[len..];
+                continue;
+            }
+            1 => {
+                let (&low, rest) = input.split_first()?;
+                input = rest;
+                (
+                    ((tag >> 2) & 7) as usize + 4,
+                    ((tag as usize >> 5) << 8) | low as usize,
+                )
+            }
+            2 => {
+                if input.len() < 2 {
+                    return None;
+                }
+                let offset = u16::from_le_bytes([input[0], input[1]]) as usize;
+                input = &input[2..];
+                ((tag >> 2) as usize + 1, offset)
+            }
+            _ => {
+                if input.len() < 4 {
+                    return None;
+                }
+                let offset = u32::from_le_bytes(input[..4].try_into().unwrap()) as usize;
+                input = &input[4..];
+                ((tag >> 2) as usize + 1, offset)
+            }
+        };
+        if offset == 0 || offset > out.len() {
+            return None;
+        }
+        // Copies can overlap what they're copying, so a byte at a time.
+        for _ in 0..len {
+            out.push(out[out.len() - offset]);
+        }
+    }
+    (out.len() == len).then_some(out)
+}
+
+#[test]
+fn test_sst_round_trip() -> Result<()> {
+    use crate::Options;
+
+    let dir = tempdir()?;
+    let options = Options {
+        memtable_bytes: Some(4096),
+        ..Options::default()
+    };
+    let mut db = Db::with_options(dir.path().join("from"), options)?;
+    for i in 0..2000 {
+        db.set(&format!("key{:05}", i), &format!("value{}", i))?;
+    }
+    db.delete("key00007")?;
+    db.set("", "empty key")?;
+    db.set("unicode", "héllo ✓")?;
+    let expected = db.scan("");
+
+    // Enough keys to need more than one data block.
+    let sst = dir.path().join("export").join("000001.sst");
+    fs::create_dir(sst.parent().unwrap())?;
+    assert_eq!(export_sst(&db, &sst)?, 2001);
+    let mut to = Db::new(dir.path().join("to"))?;
+    assert_eq!(import_leveldb(&mut to, sst.parent().unwrap())?, 2001);
+    assert_eq!(to.scan(""), expected);
+
+    // An empty database can't be exported, and nor can a file be overwritten.
+    let empty = Db::new(dir.path().join("empty"))?;
+    assert!(export_sst(&empty, &dir.path().join("empty.sst")).is_err());
+    assert!(!dir.path().join("empty.sst").exists());
+    assert!(export_sst(&db, &sst).is_err());
+    Ok(())
+}
+
+#[test]
+fn test_leveldb_import() -> Result<()> {
+    let dir = tempdir()?;
+    let source = dir.path().join("leveldb");
+    fs::create_dir(&source)?;
+
+    // An older table, with a value later overwritten and one later deleted,
+    // its data block compressed with Snappy.
+    let mut data = BlockBuilder::new(RESTART_INTERVAL);
+    for (key, value) in [
+        (&b"a"[..], &b"old"[..]),
+        (b"b", b"abcabcabcabc"),
+        (b"c", b"gone"),
+    ] {
+        let mut internal = key.to_vec();
+        internal.extend_from_slice(&((1 << 8) | TYPE_VALUE as u64).to_le_bytes());
+        data.add(&internal, value);
+    }
+    let mut sst = table_with_block(&snappy_literal(&data.finish()), SNAPPY);
+    fs::write(source.join("000005.ldb"), &sst)?;
+
+    // A log of later writes, one batch split across log blocks.
+    let mut log = vec![];
+    let mut batch = vec![];
+    batch.extend_from_slice(&10u64.to_le_bytes());
+    batch.extend_from_slice(&3u32.to_le_bytes());
+    for (tag, key, value) in [
+        (1, &b"a"[..], Some(&b"new"[..])),
+        (0, b"c", None),
+        (1, b"d", Some(&[b'x'; 40_000][..])),
+    ] {
+        batch.push(tag);
+        put_varint(&mut batch, key.len() as u64);
+        batch.extend_from_slice(key);
+        if let Some(value) = value {
+            put_varint(&mut batch, value.len() as u64);
+            batch.extend_from_slice(value);
+        }
+    }
+    write_log_record(&mut log, &batch);
+    // And a torn one after it.
+    log.extend_from_slice(&[1, 2, 3, 4, 100, 0, 1]);
+    fs::write(source.join("000006.log"), &log)?;
+
+    let mut db = Db::new(dir.path().join("to"))?;
+    assert_eq!(import_leveldb(&mut db, &source)?, 3);
+    assert_eq!(db.get("a").as_deref(), Some("new"));
+    assert_eq!(db.get("b").as_deref(), Some("abcabcabcabc"));
+    assert_eq!(db.get("c"), None);
+    assert_eq!(db.get("d").map(|v| v.len()), Some(40_000));
+
+    assert_eq!(crc32c(b"123456789"), 0xe306_9283);
+    // Snappy copies, including ones that overlap what they copy.
+    assert_eq!(
+        snappy_decompress(&[12, 0x08, b'a', b'b', b'c', 0x15, 3]).as_deref(),
+        Some(&b"abcabcabcabc"[..])
+    );
+    assert_eq!(
+        snappy_decompress(&[12, 0x08, b'a', b'b', b'c', 0x15, 4]),
+        None
+    );
+
+    // A damaged table is refused.
+    let last = sst.len() - LEGACY_FOOTER_LEN - 1;
+    sst[last] ^= 1;
+    fs::write(source.join("000005.ldb"), &sst)?;
+    let err = import_leveldb(&mut db, &source).unwrap_err();
+    assert!(matches!(err, Error::Import(_)), "{}", err);
+
+    // So is a value that isn't UTF-8, before anything else is written.
+    let source = dir.path().join("not_utf8");
+    fs::create_dir(&source)?;
+    let mut batch = vec![];
+    batch.extend_from_slice(&1u64.to_le_bytes());
+    batch.extend_from_slice(&2u32.to_le_bytes());
+    for (key, value) in [(&b"e"[..], &b"fine"[..]), (b"f", &[0xff][..])] {
+        batch.push(TYPE_VALUE);
+        put_varint(&mut batch, key.len() as u64);
+        batch.extend_from_slice(key);
+        put_varint(&mut batch, value.len() as u64);
+        batch.extend_from_slice(value);
+    }
+    let mut log = vec![];
+    write_log_record(&mut log, &batch);
+    fs::write(source.join("000003.log"), &log)?;
+    let err = import_leveldb(&mut db, &source).unwrap_err();
+    assert!(matches!(err, Error::Import(_)), "{}", err);
+    assert_eq!(db.get("e"), None);
+    Ok(())
+}
+
+// testdata/leveldb was written by LevelDB 1.22 itself (see
+// testdata/make_leveldb.cc): a table compressed with Snappy, holding every
+// version of the keys written before it, and a log with the last few writes.
+#[test]
+fn test_leveldb_fixture() -> Result<()> {
+    let dir = tempdir()?;
+    let source = Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata/leveldb");
+    let mut db = Db::new(dir.path())?;
+    assert_eq!(import_leveldb(&mut db, &source)?, 429);
+    for i in 0..500 {
+        let expected = match i {
+            1 => Some("from the log".to_owned()),
+            2 => None,
+            7 => Some("back again".to_owned()),
+            _ if i % 7 == 0 => None,
+            _ if i % 5 == 0 => Some(format!("rewritten {}", i)),
+            _ => Some(format!("value {}{}", i, "x".repeat(40))),
+        };
+        assert_eq!(db.get(&format!("key{:03}", i)), expected, "key{:03}", i);
+    }
+    assert_eq!(db.get("log only").as_deref(), Some("yes"));
+    Ok(())
+}
+
+// `data` as Snappy that's all one literal.
+#[cfg(test)]
+fn snappy_literal(data: &[u8]) -> Vec<u8> {
+    let mut out = vec![];
+    put_varint(&mut out, data.len() as u64);
+    out.push(61 << 2);
+    out.extend_from_slice(&((data.len() - 1) as u16).to_le_bytes());
+    out.extend_from_slice(data);
+    out
+}
+
+// A table holding just the one data block, written with `compression`.
+#[cfg(test)]
+fn table_with_block(contents: &[u8], compression: u8) -> Vec<u8> {
+    let mut out = vec![];
+    let block = |out: &mut Vec<u8>, contents: &[u8], compression: u8| {
+        let offset = out.len() as u64;
+        out.extend_from_slice(contents);
+        let crc = crc32c_extend(crc32c(contents), &[compression]);
+        out.push(compression);
+        out.extend_from_slice(&mask_crc(crc).to_le_bytes());
+        let mut handle = vec![];
+        put_varint(&mut handle, offset);
+        put_varint(&mut handle, contents.len() as u64);
+        handle
+    };
+    let data = block(&mut out, contents, compression);
+    let mut index = BlockBuilder::new(1);
+    index.add(b"z", &data);
+    let metaindex = block(&mut out, &BlockBuilder::new(1).finish(), NO_COMPRESSION);
+    let index = block(&mut out, &index.finish(), NO_COMPRESSION);
+    let mut footer = vec![];
+    footer.extend_from_slice(&metaindex);
+    footer.extend_from_slice(&index);
+    footer.resize(LEGACY_FOOTER_LEN - 8, 0);
+    footer.extend_from_slice(&LEGACY_MAGIC.to_le_bytes());
+    out.extend_from_slice(&footer);
+    out
+}
+
+// Appends `record` to a write-ahead log, split into fragments wherever it
+// crosses from one block into the next.
+#[cfg(test)]
+fn write_log_record(log: &mut Vec<u8>, mut record: &[u8]) {
+    let mut first = true;
+    loop {
+        let left = LOG_BLOCK_SIZE - log.len() % LOG_BLOCK_SIZE;
+        if left < LOG_HEADER_LEN {
+            log.resize(log.len() + left, 0);
+            continue;
+        }
+        let len = record.len().min(left - LOG_HEADER_LEN);
+        let last = len == record.len();
+        let kind = match (first, last) {
+            (true, true) => 1,
+            (true, false) => 2,
+            (false, false) => 3,
+            (false, true) => 4,
+        };
+        let crc = crc32c_extend(crc32c(&[kind]), &record[..len]);
+        log.extend_from_slice(&mask_crc(crc).to_le_bytes());
+        log.extend_from_slice(&(len as u16).to_le_bytes());
+        log.push(kind);
+        log.extend_from_slice(&record[..len]);
+        record = &record[len..];
+        first = false;
+        if last {
+            return;
+        }
+    }
+}
@@ -0,0 +1,131 @@
+#[cfg(test)]
+use crate::Db;
+use crate::{Error, Result};
+use std::{
+    collections::HashSet,
+    sync::{Arc, Condvar, Mutex},
+    time::{Duration, Instant},
+};
+#[cfg(test)]
+use tempfile::tempdir;
+
+// The keys that someone holds a lock on, from `Db::lock`. These only keep out
+// other callers of `Db::lock`; writes go ahead whether a key is locked or
+// not.
+#[derive(Debug, Default)]
+pub(crate) struct KeyLocks {
+    held: Mutex<HashSet<String>>,
+    released: Condvar,
+}
+
+impl KeyLocks {
+    // Waits until none of `keys` is held, and then takes all of them at once.
+    // Nobody ever holds some keys while waiting for others, so callers can't
+    // deadlock each other however they order their keys. With a `timeout`,
+    // gives up once it has passed.
+    pub fn lock(self: &Arc<Self>, keys: &[&str], timeout: Option<Duration>) -> Result<KeyGuard> {
+        let deadline = timeout.map(|t| Instant::now() + t);
+        let mut held = self.held.lock()?;
+        while let Some(k) = keys.iter().find(|k| held.contains(**k)) {
+            held = match deadline {
+                None => self.released.wait(held)?,
+                Some(deadline) => {
+                    let left = deadline.saturating_duration_since(Instant::now());
+                    if left.is_zero() {
+                        return Err(Error::LockTimeout { key: k.to_string() });
+                    }
+                    self.released.wait_timeout(held, left)?.0
+                }
+            };
+        }
+        let mut owned = Vec::with_capacity(keys.len());
+        for &k in keys {
+            if held.insert(k.to_owned()) {
+                owned.push(k.to_owned());
+            }
+        }
+        Ok(KeyGuard {
+            locks: self.clone(),
+            keys: owned,
+        })
+    }
+}
+
+// Locks on one or more keys, released when it's dropped.
+#[derive(Debug)]
+pub struct KeyGuard {
+    locks: Arc<KeyLocks>,
+    keys: Vec<String>,
+}
+
+impl KeyGuard {
+    pub fn keys(&self) -> &[String] {
+        &self.keys
+    }
+}
+
+impl Drop for KeyGuard {
+    fn drop(&mut self) {
+        if let Ok(mut held) = self.locks.held.lock() {
+            for k in &self.keys {
+                held.remove(k);
+            }
+        }
+        self.locks.released.notify_all();
+    }
+}
+
+#[test]
+fn test_key_locks() -> Result<()> {
+    let dir = tempdir()?;
+    let db = Db::new(dir.path().join("logfile"))?;
+
+    // Read-modify-write cycles under the lock don't lose updates.
+    let threads = (0..8)
+        .map(|_| {
+            let mut db = db.clone();
+            std::thread::spawn(move || -> Result<()> {
+                for _ in 0..25 {
+                    let _guard = db.lock("counter");
+                    let n = db.get("counter").map_or(0, |v| v.parse::<u64>().unwrap());
+                    db.set("counter", &(n + 1).to_string())?;
+                }
+                Ok(())
+            })
+        })
+        .collect::<Vec<_>>();
+    for thread in threads {
+        thread.join().unwrap()?;
+    }
+    assert_eq!(db.get("counter"), Some("200".into()));
+
+    // Taking the same keys in opposite orders can't deadlock.
+    let threads = [["a", "b"], ["b", "a"]].map(|keys| {
+        let db = db.clone();
+        std::thread::spawn(move || {
+            for _ in 0..1000 {
+                let guard = db.lock_all(&keys);
+                assert_eq!(guard.keys().len(), 2);
+            }
+        })
+    });
+    for thread in threads {
+        thread.join().unwrap();
+    }
+
+    let guard = db.lock_all(&["x", "y", "x"]);
+    assert_eq!(guard.keys(), ["x", "y"]);
+    let err = db
+        .lock_timeout(&["z", "y"], Duration::from_millis(10))
+        .unwrap_err();
+    assert!(
+        matches!(&err, Error::LockTimeout { key } if key == "y"),
+        "{}",
+        err
+    );
+    assert!(db.lock_timeout(&["z"], Duration::ZERO).is_ok());
+    drop(guard);
+    db.lock_timeout(&["x", "y"], Duration::ZERO)?;
+
+    Ok(())
+}
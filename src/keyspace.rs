@@ -0,0 +1,138 @@
+#[cfg(test)]
+use crate::{table, Options};
+use crate::{Command, Db, Result, WriteOptions};
+#[cfg(test)]
+use tempfile::tempdir;
+
+// A named map within a `Db`, from `Db::cf`. Writes to it go through the same
+// log and group commit as the rest of the database, but it has a memtable of
+// its own, so its keys never clash with those of the default keyspace or any
+// other.
+//
+// Keyspaces always stay in memory: a flush (see `Options::memtable_bytes`)
+// writes them back into the log rather than out to tables, so they're best
+// kept small.
+#[derive(Debug, Clone)]
+pub struct Keyspace {
+    db: Db,
+    name: String,
+}
+
+impl Keyspace {
+    pub(crate) fn new(db: Db, name: &str) -> Self {
+        Keyspace {
+            db,
+            name: name.to_owned(),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn get(&self, k: &str) -> Option<String> {
+        let memtable = self.db.memtable.home();
+        memtable.keyspace(&self.name)?.get(k).cloned()
+    }
+
+    pub fn set(&mut self, k: &str, v: &str) -> Result<()> {
+        let options = self.db.write_options;
+        self.set_with_options(k, v, &options)
+    }
+
+    pub fn set_with_options(&mut self, k: &str, v: &str, options: &WriteOptions) -> Result<()> {
+        let cmd = Command::KeyspaceSet(self.name.clone(), k.to_owned(), v.to_owned());
+        self.db.apply_command_with_options(cmd, options)
+    }
+
+    pub fn delete(&mut self, k: &str) -> Result<()> {
+        let options = self.db.write_options;
+        self.delete_with_options(k, &options)
+    }
+
+    pub fn delete_with_options(&mut self, k: &str, options: &WriteOptions) -> Result<()> {
+        let cmd = Command::KeyspaceDelete(self.name.clone(), k.to_owned());
+        self.db.apply_command_with_options(cmd, options)
+    }
+
+    // Returns every key in the keyspace starting with `prefix` along with its
+    // value, in key order.
+    pub fn scan(&self, prefix: &str) -> Vec<(String, String)> {
+        let memtable = self.db.memtable.home();
+        let mut result = memtable
+            .keyspace(&self.name)
+            .into_iter()
+            .flatten()
+            .filter(|(k, _)| k.starts_with(prefix))
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect::<Vec<_>>();
+        result.sort_unstable();
+        result
+    }
+
+    pub fn len(&self) -> usize {
+        let memtable = self.db.memtable.home();
+        memtable
+            .keyspace(&self.name)
+            .map_or(0, |keyspace| keyspace.len())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[test]
+fn test_keyspaces() -> Result<()> {
+    let dir = tempdir()?;
+    let file = dir.path().join("logfile");
+    let options = Options {
+        segment_size: 4096,
+        memtable_bytes: Some(1024),
+        ..Options::default()
+    };
+    let mut db = Db::with_options(&file, options.clone())?;
+    let mut users = db.cf("users");
+    let mut orders = db.cf("orders");
+    db.set("k", "default")?;
+    users.set("k", "user")?;
+    orders.set("k", "order")?;
+    orders.set("k2", "order2")?;
+    assert_eq!(db.get("k"), Some("default".into()));
+    assert_eq!(users.get("k"), Some("user".into()));
+    assert_eq!(
+        orders.scan(""),
+        vec![("k".into(), "order".into()), ("k2".into(), "order2".into())]
+    );
+    assert_eq!(db.len(), 1);
+    assert_eq!(db.cf_names(), vec!["orders", "users"]);
+
+    users.delete("k")?;
+    assert_eq!(users.get("k"), None);
+    assert_eq!(db.cf_names(), vec!["orders"]);
+    db.drop_cf("orders")?;
+    assert!(orders.is_empty());
+    assert!(db.cf_names().is_empty());
+
+    // Keyspaces survive flushes, compactions and reopening.
+    for i in 0..50 {
+        users.set(&format!("user{}", i), "x")?;
+        db.set(&format!("key{}", i), &"v".repeat(50))?;
+    }
+    assert!(!table::list(&file)?.is_empty());
+    let reader = Db::open_read_only(&file)?;
+    assert_eq!(reader.cf("users").len(), 50);
+    drop(users);
+    drop(orders);
+    drop(db);
+    let db = Db::with_options(&file, options.clone())?;
+    assert_eq!(db.cf("users").len(), 50);
+    assert_eq!(db.len(), 51);
+    db.compact()?;
+    drop(db);
+    let db = Db::with_options(&file, options)?;
+    assert_eq!(db.cf("users").get("user7"), Some("x".into()));
+    assert_eq!(db.cf("orders").get("k"), None);
+
+    Ok(())
+}
@@ -0,0 +1,65 @@
+use crate::Db;
+use std::{error::Error, path::Path};
+#[cfg(test)]
+use tempfile::tempdir;
+
+pub type StoreResult<T> = std::result::Result<T, Box<dyn Error + Send + Sync>>;
+
+// What every key-value store in this repository can do, from `Db` down to the
+// experiments in `src/bin`, so that the `compare` binary can run the same
+// workload against all of them. What `path` is depends on the store: a
+// directory for `Db`, a single file for most of the experiments.
+pub trait KvStore: Sized {
+    fn open(path: &Path) -> StoreResult<Self>;
+
+    fn set(&mut self, k: &str, v: &str) -> StoreResult<()>;
+
+    fn delete(&mut self, k: &str) -> StoreResult<()>;
+
+    fn get(&self, k: &str) -> StoreResult<Option<String>>;
+
+    // Makes every write so far survive a reopen, for stores that don't
+    // manage that on their own.
+    fn sync(&mut self) -> StoreResult<()>;
+}
+
+impl KvStore for Db {
+    fn open(path: &Path) -> StoreResult<Self> {
+        Ok(Db::new(path)?)
+    }
+
+    fn set(&mut self, k: &str, v: &str) -> StoreResult<()> {
+        Ok(Db::set(self, k, v)?)
+    }
+
+    fn delete(&mut self, k: &str) -> StoreResult<()> {
+        Ok(Db::delete(self, k)?)
+    }
+
+    fn get(&self, k: &str) -> StoreResult<Option<String>> {
+        Ok(Db::get(self, k))
+    }
+
+    fn sync(&mut self) -> StoreResult<()> {
+        Db::sync(self)?;
+        Ok(())
+    }
+}
+
+#[test]
+fn test_kv_store() -> StoreResult<()> {
+    fn exercise<S: KvStore>(path: &Path) -> StoreResult<()> {
+        let mut store = S::open(path)?;
+        store.set("a", "1")?;
+        store.set("b", "2")?;
+        store.delete("a")?;
+        store.sync()?;
+        drop(store);
+        let store = S::open(path)?;
+        assert_eq!(store.get("a")?, None);
+        assert_eq!(store.get("b")?, Some("2".into()));
+        Ok(())
+    }
+    let dir = tempdir()?;
+    exercise::<Db>(&dir.path().join("logfile"))
+}
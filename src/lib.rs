@@ -0,0 +1,5491 @@
+use serde::{de::DeserializeOwned, Deserialize, Deserializer, Serialize, Serializer};
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, HashMap, HashSet},
+    io,
+    ops::Deref,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex, MutexGuard, PoisonError,
+    },
+    time::{Duration, Instant},
+};
+#[cfg(test)]
+use tempfile::tempdir;
+
+#[macro_use]
+mod trace;
+
+pub mod binary;
+mod bloom;
+mod cache;
+mod clock;
+pub mod codec;
+mod commit_hook;
+mod compaction_filter;
+#[cfg(test)]
+mod crash;
+mod durable_fs;
+mod error;
+mod expiry;
+mod export;
+#[cfg(feature = "failpoints")]
+mod failpoint;
+#[cfg(all(test, feature = "failpoints"))]
+mod fault;
+mod garbage;
+mod hot_keys;
+pub mod interop;
+mod key_lock;
+mod keyspace;
+mod kv_store;
+mod listener;
+mod log;
+mod maintenance;
+mod manager;
+mod manifest;
+mod memtable;
+mod metrics;
+mod migrate;
+mod mirror;
+#[cfg(test)]
+mod model;
+#[cfg(feature = "python")]
+pub mod python;
+mod quota;
+#[cfg(feature = "raft")]
+pub mod raft;
+mod rate_limit;
+mod reader;
+mod redo_log;
+mod repair;
+mod replay;
+pub mod replication;
+pub mod resp;
+mod rng;
+pub mod segment;
+#[cfg(test)]
+mod sim;
+mod snapshot;
+mod table;
+mod tail;
+mod tailer;
+mod transaction;
+mod unapplied;
+#[cfg(all(feature = "uring", target_os = "linux"))]
+pub mod uring;
+mod value_log;
+mod watermark;
+
+use crate::cache::BlockCache;
+pub use crate::clock::{Clock, MockClock, SystemClock};
+pub use crate::codec::RecordCodec;
+use crate::commit_hook::{CommitHooks, Committed};
+pub use crate::compaction_filter::{CompactionFilter, FilterDecision};
+pub use crate::error::{Error, Result};
+pub use crate::expiry::ExpirySweeper;
+pub use crate::export::Format;
+#[cfg(feature = "failpoints")]
+pub use crate::failpoint::{FailAction, Failpoint, Failpoints};
+pub use crate::garbage::SegmentGarbage;
+use crate::garbage::{Effect, Ledger};
+use crate::hot_keys::HotKeys;
+pub use crate::key_lock::KeyGuard;
+use crate::key_lock::KeyLocks;
+pub use crate::keyspace::Keyspace;
+pub use crate::kv_store::{KvStore, StoreResult};
+pub use crate::listener::{DbListener, SlowSync, WriteStall};
+use crate::log::Log;
+use crate::maintenance::Background;
+pub use crate::maintenance::{Maintenance, MaintenanceOptions, MaintenanceTask};
+pub use crate::manager::DbManager;
+use crate::memtable::{KeyRange, Memtable, ShardedMemtable, Shards, Writing};
+use crate::metrics::Counters;
+pub use crate::metrics::{Buckets, Histogram, Metrics, Stats};
+pub use crate::quota::{Namespace, NamespaceUsage};
+use crate::quota::{Quotas, Reservation};
+pub use crate::rate_limit::RateLimiter;
+pub use crate::reader::{LogChunk, LogOffset, LogReader};
+pub use crate::redo_log::{RedoLog, StateMachine};
+use crate::replay::CustomHandler;
+pub use crate::replay::{CancelToken, RecoveryObserver, RecoveryProgress, RecoveryReport, Skipped};
+use crate::replication::{Acks, Batch, Feed};
+pub use crate::rng::Rng;
+pub use crate::snapshot::Snapshot;
+use crate::table::{Merge, Table};
+use crate::tail::Tail;
+pub use crate::tailer::LogTailer;
+pub use crate::transaction::Transaction;
+use crate::transaction::Versions;
+use crate::unapplied::{BatchWrites, Unapplied};
+pub use crate::value_log::Blob;
+use crate::value_log::{BlobRef, ValueLog};
+use crate::watermark::Watermarks;
+
+// The longest `Options::memtable_slowdown` holds a write back.
+const MAX_SLOWDOWN: Duration = Duration::from_millis(1);
+
+// The keyspace consumers' cursors are kept in (see `Db::save_cursor`), which
+// `cf_names` leaves out.
+const CURSORS: &str = "\0cursors";
+
+#[derive(Debug, Clone)]
+pub struct Options {
+    // Segments are preallocated to this size, and the log moves on to a new
+    // segment once the current one is full.
+    pub segment_size: u64,
+    // How many retired segments to keep around for reuse rather than deleting.
+    pub max_recycled_segments: usize,
+    pub durability: Durability,
+    pub recovery: RecoveryOptions,
+    // Used by `set` and `delete`.
+    pub write: WriteOptions,
+    pub lock: LockPolicy,
+    // Once the memtable holds roughly this many bytes, it's written out to a
+    // sorted table file and the log is truncated, so that the database doesn't
+    // have to fit in memory. Reads then go to the tables for anything the
+    // memtable doesn't have. With None, everything stays in memory.
+    //
+    // Tables aren't part of the log, so they're invisible to `LogReader`,
+    // `LogTailer` and replication: followers have to start from a copy of the
+    // primary's directory rather than from nothing.
+    pub memtable_bytes: Option<usize>,
+    // Once the memtable takes up this many bytes, counting what a flush
+    // leaves in it, writes are held back before they're let in: by nothing
+    // at first, up to a millisecond each as the memtable reaches
+    // `memtable_bytes`, or twice this without it. That gives flushes a
+    // chance to keep up, and slows ingest that nothing can flush before it
+    // runs the process out of memory. With None, writes never wait for it.
+    pub memtable_slowdown: Option<usize>,
+    // Once the memtable takes up this many bytes, writes aren't let in at all
+    // until it's back under: the first writer to find it full flushes it, and
+    // the rest wait for them. Keyspaces and what snapshots keep count too,
+    // though a flush doesn't free them, so if they're all that's left, writes
+    // go ahead rather than wait on something that may never come. With None,
+    // only `memtable_slowdown` holds writes back.
+    pub memtable_limit: Option<usize>,
+    // How many bytes a second flushes, compactions and value log rewrites can
+    // write between them, so that they leave the disk to commits (see
+    // `RateLimiter`). A flush lets go of the log while it writes its table,
+    // but a compaction rewrites the log itself, so writers can end up waiting
+    // on the limit for that. With None, they go as fast as the disk allows.
+    pub background_bytes_per_sec: Option<u64>,
+    pub memtable: MemtableKind,
+    // How many shards to split the memtable into by key, each behind a lock
+    // of its own, so that reading a key only waits on a batch being applied
+    // if the batch writes to that key's shard. With one, which is what a
+    // read-only `Db` always has, every read waits on every batch.
+    pub memtable_shards: usize,
+    // How commands are written to the log. Each segment records the codec its
+    // records are in, so this can be changed between opens, though replaying
+    // a segment in a codec other than the built-in ones takes that codec. A
+    // follower has to use the same codec as its primary.
+    pub codec: Arc<dyn RecordCodec>,
+    // How many bits of bloom filter each table gets per key. More bits mean
+    // fewer wasted reads when looking up keys a table doesn't have: 10 bits
+    // lets about 1% through. Zero leaves the filters out.
+    pub bloom_bits_per_key: usize,
+    // The most table data to keep in memory, shared across every table. Zero
+    // turns the cache off, so that every read goes to the file.
+    pub block_cache_bytes: usize,
+    pub listener: Option<Arc<dyn DbListener>>,
+    // Syncs that take longer than this, and writers that wait longer than
+    // this for their batch to commit, are warned about through the listener
+    // and `tracing`. None turns the warning off.
+    pub slow_sync: Option<Duration>,
+    pub write_stall: Option<Duration>,
+    // The most writes that can be waiting on batches that haven't been
+    // committed yet, so that a stalled sync can't have them pile up in
+    // memory without end. Past it, `backpressure` says what happens to new
+    // ones. One is always let in. With None, there's no limit.
+    pub max_pending_writes: Option<usize>,
+    pub backpressure: Backpressure,
+    // How many log files to stripe batches across, for disks that keep up
+    // better with several syncs at once. Each batch goes to the next shard in
+    // turn and is synced there while the next batch is written to another,
+    // and replay merges the shards back together by sequence number. As with
+    // `pipeline_commits`, none of a batch can be read until it's synced.
+    // `RedoLog`, `LogTailer`, `open_read_only` and `repair` can't open
+    // sharded logs.
+    pub log_shards: usize,
+    // Whether the next batch can be written to the log while the one before
+    // it is still being synced, rather than waiting for it to be committed.
+    // Batches are still synced one at a time, and each is only applied, and
+    // its writers told, once it's synced and the one before it is committed,
+    // so nothing is any less durable or visible any sooner. A batch with
+    // increments or idempotent requests in it waits for the one before it to
+    // be applied first, since what it logs depends on it. Sharded logs work
+    // this way regardless, except that they keep track of what the batches
+    // not yet applied do, so that the next one needn't wait. Mirrored logs
+    // don't.
+    pub pipeline_commits: bool,
+    // Batches that write at least this many keys are sorted out for the
+    // memtable while they sync, on the leader, with the sync handed to a
+    // thread of its own, so that applying them afterwards takes less time.
+    // None of a batch can be read until its sync has finished, and if the
+    // sync fails, none of it ever is. Starting the thread costs more than it
+    // saves on batches of a few dozen small writes. Batches with range
+    // deletions in them, and pipelined commits, are applied as usual.
+    pub apply_during_sync: Option<usize>,
+    // A second directory to keep a copy of the log in, ideally on another
+    // disk, so that losing one of them loses nothing. Every batch is written
+    // and synced to both, the syncs running side by side, and
+    // `mirror_policy` says whether a batch has to reach both to commit.
+    // Opening the log for writing reads both copies through, segment by
+    // segment, and repairs whichever is behind or damaged from the other,
+    // even after a clean shutdown. Only the log is
+    // mirrored, not tables, and it can't be sharded. After `repair`, or to
+    // stop mirroring, the mirror's directory has to be removed by hand.
+    pub log_mirror: Option<PathBuf>,
+    pub mirror_policy: MirrorPolicy,
+    pub retention: Retention,
+    pub commit_callbacks: CommitCallbacks,
+    // Whether values of `value_separation_threshold` bytes or more are kept
+    // in a value log of their own rather than in the log, so that compactions
+    // and flushes only have to copy a pointer to them. Each one is synced on
+    // its own before the write is logged, and `Db::gc_value_log` gets back
+    // the space taken by values since overwritten or deleted. Named keyspaces
+    // keep their values in the log, `append` starts a new list at a key
+    // whose list went to the value log, and followers only get the pointers,
+    // so they can't read them.
+    pub value_log: bool,
+    // Shorter values stay in the log, the memtable and tables, where reading
+    // one costs no more than finding its key, rather than a second read from
+    // the value log. Values only go there once copying them around costs
+    // more than that, which with the default of 4 KiB is rare.
+    pub value_separation_threshold: usize,
+    // How many of the most-written keys `Db::hot_keys` keeps track of, or
+    // zero, the default, to not count writes at all. Counting them costs a
+    // few hashes and a lock for every key written, and 128 KiB for the counts.
+    pub hot_keys: usize,
+    // Called for every live key as `Db::compact` rewrites it, to drop or
+    // change it on the way. See `CompactionFilter`.
+    pub compaction_filter: Option<Arc<dyn CompactionFilter>>,
+    // Compacts the log once more than this much of it, and at least a
+    // segment's worth, is dead: taken up by records for keys that have since
+    // been overwritten or deleted, and by the deletions. With tables, that
+    // means flushing the memtable, which truncates the log too. Keeping
+    // count means remembering which record last wrote each key until the
+    // next compaction, so it's only done with this set, and `Stats` only
+    // has the counts then. A ratio of 1 or more counts without compacting.
+    pub compact_dead_ratio: Option<f64>,
+    // What the time is, for expiries, retention and timings. Tests can
+    // use a `MockClock` rather than sleep.
+    pub clock: Arc<dyn Clock>,
+    // Parts of the default keyspace, by prefix, that writes fail with
+    // `Error::QuotaExceeded` rather than take past a number of keys or
+    // bytes. How much each holds is in `Stats`. See `Namespace`.
+    pub namespaces: Vec<Namespace>,
+    #[cfg(feature = "failpoints")]
+    pub failpoints: Option<Arc<Failpoints>>,
+}
+
+// What to do when opening a log that another process already has open.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LockPolicy {
+    // Fail to open it.
+    Fail,
+    // Open it without writing to it: replay whatever is there, and fail any
+    // write that's attempted later.
+    ReadOnly,
+    // Open it for writing anyway. Two processes appending to the same log will
+    // corrupt it, so this is only for when the lock is known to be stale, say
+    // because it's held by a process that's hung on a network filesystem.
+    Force,
+}
+
+// What a write does when `Options::max_pending_writes` are already waiting.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backpressure {
+    // Wait for one of them to be committed, checking less and less often.
+    Block,
+    // Fail with `Error::Overloaded`, having written nothing.
+    Fail,
+}
+
+// How the memtable keeps its keys.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemtableKind {
+    // In a hash map, which is quickest for getting and setting single keys.
+    Hash,
+    // In a hash map with a much quicker hash function for short keys, but
+    // one that keys can be chosen to collide under, which slows the map to a
+    // crawl. For keys that don't come from whoever might want that.
+    FxHash,
+    // In order, in a B-tree, so that scans and flushes don't have to sort
+    // what they read out of it, at some cost to everything else.
+    Ordered,
+    // In order, in a lock-free skiplist, which `Db::get` reads without
+    // locking the memtable, so that it doesn't wait on batches being applied
+    // unless they write the key it's after (see
+    // `ShardedMemtable::get_unlocked`). Every read copies the value out,
+    // though, so it's slower than the others unless writes would otherwise
+    // hold reads up.
+    Concurrent,
+}
+
+// Which copies of a mirrored log a batch has to reach to be committed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MirrorPolicy {
+    // Both of them: failing to write or sync either one fails the batch, and
+    // everything written after it, as it would without a mirror.
+    Both,
+    // The log's own directory. The first time writing or syncing the mirror
+    // fails, the log drops it and carries on alone until it's reopened, which
+    // brings the mirror up to date again. `DbListener::on_mirror_failed` hears
+    // about it.
+    Primary,
+}
+
+// Which segments to keep for whoever is reading the log (see
+// `Db::read_log_from` and `LogTailer`) once a compaction or flush no longer
+// needs them. A segment is kept if any of these says to, along with every
+// segment after it. Replay skips kept segments, and they're retired by a
+// later compaction or flush, or `Db::expire_cursors`, once nothing keeps them
+// any more. Sharded logs keep nothing.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Retention {
+    // Keeps the newest segments until they add up to this many bytes on disk.
+    pub bytes: Option<u64>,
+    // Keeps segments last written to less than this long ago.
+    pub age: Option<Duration>,
+    // Keeps whatever a consumer that's saved its cursor (see
+    // `Db::save_cursor`) hasn't got past yet.
+    pub cursors: bool,
+}
+
+// When the callbacks passed to `Db::on_commit` hear about a batch, once it's
+// durable.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CommitCallbacks {
+    // Before its writers are told it's committed, so that by the time a
+    // write returns, the callbacks have seen it. Batches reach them in order.
+    BeforeRelease,
+    // After, so that its writers don't wait on them. Callbacks still get one
+    // batch at a time, but a pipelined batch can reach them before the one
+    // committed ahead of it.
+    AfterRelease,
+}
+
+#[derive(Debug, Clone)]
+pub struct RecoveryOptions {
+    // How many threads read and deserialize segments while the log is
+    // replayed. Work is split up by segment, so this only helps logs that span
+    // several of them.
+    pub parallelism: usize,
+    pub mode: RecoveryMode,
+    // Whether to check every record against its checksum as it's replayed.
+    // A `Db` that was closed cleanly skips it by itself, since it has the
+    // memtable it was closed with to check against (see `Db::close`).
+    // Turning it off otherwise leaves damage to the log to show up however it
+    // happens to.
+    pub verify: bool,
+    // Told how replay is getting on about every `progress_interval`, and
+    // once more when it's done.
+    pub observer: Option<Arc<dyn RecoveryObserver>>,
+    pub progress_interval: Duration,
+    // Checked as records are replayed. Once it's cancelled, opening the
+    // database stops and fails with `Error::Cancelled`, having changed
+    // nothing on disk but what opening it always does before replay starts.
+    pub cancel: Option<CancelToken>,
+}
+
+impl Default for RecoveryOptions {
+    fn default() -> Self {
+        RecoveryOptions {
+            parallelism: 1,
+            mode: RecoveryMode::TolerateTornTail,
+            verify: true,
+            observer: None,
+            progress_interval: Duration::from_secs(1),
+            cancel: None,
+        }
+    }
+}
+
+// What replay does about records that fail their checksum, run past the end of
+// their segment, or can't be decoded. Writing always resumes in a fresh
+// segment, so every segment that was being written to when the process died
+// can end in a torn record.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecoveryMode {
+    // Fail on any bad record, even a torn one at the end of a segment.
+    Strict,
+    // Ignore a bad record if nothing valid follows it in its segment, since
+    // that's what a crash partway through a write leaves behind, and fail on
+    // any other.
+    TolerateTornTail,
+    // Skip over bad records wherever they are, carrying on from the next valid
+    // one. Everything skipped is listed in the `RecoveryReport`.
+    SkipCorrupt,
+}
+
+// How hard to try to make a write survive a crash before acknowledging it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Durability {
+    // The write is on stable media: F_FULLFSYNC on macOS, fdatasync on Linux,
+    // FlushFileBuffers on Windows.
+    Media,
+    // The write has been handed to the storage device, but may still be
+    // sitting in its volatile cache. This is what a plain fsync gives you on
+    // macOS; elsewhere it is the same as `Media`.
+    Device,
+    // Never sync. Writes survive a process crash but not a power failure.
+    None,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            segment_size: 64 << 20,
+            max_recycled_segments: 4,
+            durability: Durability::Media,
+            recovery: RecoveryOptions::default(),
+            write: WriteOptions::default(),
+            lock: LockPolicy::Fail,
+            memtable_bytes: None,
+            memtable_slowdown: None,
+            memtable_limit: None,
+            background_bytes_per_sec: None,
+            memtable: MemtableKind::Hash,
+            memtable_shards: 1,
+            codec: Arc::new(codec::Binary),
+            bloom_bits_per_key: 10,
+            block_cache_bytes: 8 << 20,
+            listener: None,
+            slow_sync: Some(Duration::from_millis(500)),
+            write_stall: Some(Duration::from_secs(1)),
+            max_pending_writes: None,
+            backpressure: Backpressure::Block,
+            log_shards: 1,
+            pipeline_commits: false,
+            apply_during_sync: None,
+            log_mirror: None,
+            mirror_policy: MirrorPolicy::Both,
+            retention: Retention::default(),
+            commit_callbacks: CommitCallbacks::BeforeRelease,
+            value_log: false,
+            value_separation_threshold: 4 << 10,
+            hot_keys: 0,
+            compaction_filter: None,
+            clock: Arc::new(SystemClock),
+            namespaces: vec![],
+            compact_dead_ratio: None,
+            #[cfg(feature = "failpoints")]
+            failpoints: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WriteOptions {
+    pub replication: Replication,
+    // How long to wait for followers before giving up. The write has already
+    // been committed locally by then, so giving up doesn't undo it.
+    pub timeout: Duration,
+    // Identifies the write, so that retrying it after an error that leaves it
+    // unclear whether it went through can't apply it twice. It belongs on a
+    // single write's options: in `Options::write`, every write after the
+    // first would look like a retry.
+    pub idempotency_key: Option<IdempotencyKey>,
+    // With false, the write returns once it's in the log, without waiting for
+    // a sync. It survives the process crashing but not the machine, until
+    // the next write that is synced, or `Db::sync`, takes it along. For bulk
+    // loads that can be redone if the machine goes down.
+    pub sync: bool,
+}
+
+impl Default for WriteOptions {
+    fn default() -> Self {
+        WriteOptions {
+            replication: Replication::Local,
+            timeout: Duration::from_secs(10),
+            idempotency_key: None,
+            sync: true,
+        }
+    }
+}
+
+// A write's client and its request number within that client. Only the latest
+// request from each client is remembered, so a client's request ids have to
+// increase, and it has to wait for each write to return before sending the
+// next. A write whose request id is no greater than the last one applied for
+// its client is taken to be a retry and skipped, but still succeeds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct IdempotencyKey {
+    pub client_id: u64,
+    pub request_id: u64,
+}
+
+// Where a write has to be before it's acknowledged.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Replication {
+    // Synced to the local log.
+    Local,
+    // Synced to the local log, and to the logs of at least this many
+    // followers.
+    Quorum(usize),
+}
+
+// Set by a batch's leader once it's done with the batch, to what became of it.
+type BatchNotif = Arc<(Mutex<Option<Result<(), String>>>, std::sync::Condvar)>;
+
+// Held by a batch's leader, to tell everyone in the batch what became of it.
+// If the leader never gets that far, because it panicked or found a lock
+// poisoned, they hear that the batch failed rather than waiting forever.
+struct Finish(BatchNotif);
+
+impl Finish {
+    fn set(&self, result: Result<(), String>) {
+        *error::lock(&self.0 .0) = Some(result);
+        self.0 .1.notify_all();
+    }
+}
+
+impl Drop for Finish {
+    fn drop(&mut self) {
+        let mut done = error::lock(&self.0 .0);
+        if done.is_none() {
+            *done = Some(Err(Error::Poisoned.to_string()));
+            self.0 .1.notify_all();
+        }
+    }
+}
+
+// Turns at joining a batch, handed out in the order writers show up. The
+// state lock alone would let a writer that just finished a batch get straight
+// back in ahead of others that have been waiting for it, and keep doing so
+// under load, so they'd sit through batch after batch.
+#[derive(Debug, Default)]
+struct Tickets {
+    next: AtomicU64,
+    serving: Mutex<u64>,
+    turn: std::sync::Condvar,
+}
+
+impl Tickets {
+    // Waits for every writer that got here first to have joined a batch.
+    fn wait_turn(&self) -> Turn<'_> {
+        let ticket = self.next.fetch_add(1, Ordering::SeqCst);
+        let mut serving = error::lock(&self.serving);
+        while *serving != ticket {
+            serving = self
+                .turn
+                .wait(serving)
+                .unwrap_or_else(PoisonError::into_inner);
+        }
+        Turn(self)
+    }
+}
+
+// Lets the next writer join once dropped, however this one got on.
+struct Turn<'a>(&'a Tickets);
+
+impl Drop for Turn<'_> {
+    fn drop(&mut self) {
+        *error::lock(&self.0.serving) += 1;
+        self.0.turn.notify_all();
+    }
+}
+
+// How far a pipelined, unsharded log has got. A sync takes along every
+// batch written to the segment before it started, so the batches after it
+// that it took along can skip syncing themselves.
+#[derive(Debug, Default)]
+struct Progress {
+    // The segment the last batch went to, and the sequence number after it.
+    segment: u64,
+    written: u64,
+    // The sequence number after the last record known to be synced.
+    synced: u64,
+}
+
+// A batch sorted out by the shard of the memtable each write goes to, while
+// it's being synced (see `Options::apply_during_sync`). Nothing reads from it,
+// so none of it is visible until `Db::publish` applies it.
+#[derive(Debug)]
+struct Pending {
+    // The sequence number of the batch's last record.
+    last_seq: u64,
+    // For each shard, the writes to it and the seq each was logged as, in
+    // order. Each only writes to its own shard.
+    shards: Vec<Vec<(u64, Command)>>,
+}
+
+// A write counted in `Db::pending`, until it's dropped.
+struct Admitted<'a>(&'a AtomicUsize);
+
+impl Drop for Admitted<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+#[derive(Debug)]
+enum DbState {
+    // Outstanding fsync, currently no leader.
+    Pending {
+        // This condition variable will allow us to wait for the previous batch
+        // to finish committing before we go and commit our own. With the log
+        // sharded, it's set as soon as the batch is written, since it's synced
+        // outside the log lock, and `prev_done` once it's committed.
+        prev_batch_notif: BatchNotif,
+        prev_done: BatchNotif,
+    },
+    // Outstanding fsync, there is a leader.
+    PendingLeader {
+        // If a new thread comes along and tries to write, it will stuff its
+        // write into this buffer that the leader will use when it actually does
+        // its write.
+        writes: Vec<Command>,
+        // Whether anyone in the batch wants it synced.
+        sync: bool,
+        // This will tell us when the leader has finished writing and we can
+        // safely return (informing the caller that their write has been
+        // committed).
+        batch_notif: BatchNotif,
+    },
+}
+
+// A handle on an open database. Clones share it, and it's `Send + Sync`
+// because everything in it is, so it can go to other threads as it is or
+// behind an `Arc`. Nothing needs to be unsafe for that, and nothing should be.
+//
+// Reads never wait on I/O, or on each other: they share the memtable, and only
+// wait while a batch is being applied to it (or with `Options::memtable_shards`,
+// to their key's shard), or on other threads briefly holding the tables. What
+// can block for longer:
+//  - writes, until their batch is written, and synced if it's to be: a
+//    thread joining a batch waits on whoever is writing it, including while
+//    they start or finish flushing a memtable that's outgrown
+//    `Options::memtable_bytes`, though not while its table is written. Writers
+//    join batches in the order they arrive, so none waits more than the
+//    batch ahead of its own, though with `Options::max_pending_writes` and
+//    `Backpressure::Block`, they can first wait to be let in at all, as
+//    they can for a memtable past `Options::memtable_slowdown`, or at
+//    `Options::memtable_limit`, until it's flushed;
+//  - writes with `Replication::Quorum`, on replicas, up to the timeout;
+//  - `sync` and `compact`, on the disk, with the log locked so that writes
+//    wait too, and `flush`, which only has it locked to start and finish.
+//    With `Options::log_shards`, they and other writes
+//    that go straight to the log first wait for batches still being synced;
+//  - `close`, on the writes already under way;
+//  - `lock` and `lock_all`, on whoever holds the keys, for as long as they
+//    hold them.
+#[derive(Debug, Clone)]
+pub struct Db {
+    state: Arc<Mutex<DbState>>,
+    // Taken before `state` by writers joining a batch.
+    tickets: Arc<Tickets>,
+    // Writes between being let in and their batch being done.
+    pending: Arc<AtomicUsize>,
+    max_pending_writes: Option<usize>,
+    backpressure: Backpressure,
+    log: Arc<Mutex<Log>>,
+    log_shards: usize,
+    // Whether batches are written while the one before is still syncing:
+    // `Options::pipeline_commits`, or a sharded log.
+    pipelined: bool,
+    apply_during_sync: Option<usize>,
+    // See `last_durable_seq` and `last_applied_seq`.
+    watermarks: Arc<Watermarks>,
+    hooks: Arc<CommitHooks>,
+    // Set once the last batch written to a pipelined, unsharded log has been
+    // synced, which the next batch waits for before syncing, so that syncs
+    // go one at a time and in order. Locked after `log`.
+    synced: Arc<Mutex<BatchNotif>>,
+    // How far such a log has been written and synced. Locked after `log`.
+    progress: Arc<Mutex<Progress>>,
+    // Set once the last batch written to a pipelined log is committed, since
+    // it may still be syncing after letting go of the log. Locked after
+    // `log`.
+    in_flight: Arc<Mutex<BatchNotif>>,
+    // Held for the whole of a flush, which only has the log locked at its
+    // start and end, so that there's only one at a time. Locked before
+    // `log`, or else only tried.
+    flushing: Arc<Mutex<()>>,
+    // Batches written to a sharded log but not yet applied. Locked after
+    // `log`.
+    unapplied: Arc<Unapplied>,
+    memtable: Arc<ShardedMemtable>,
+    // Oldest first. Always locked after `memtable`, so that a flush can move
+    // entries from one to the other without readers seeing them in neither.
+    tables: Arc<Mutex<Vec<Arc<Table>>>>,
+    block_cache: Arc<BlockCache>,
+    value_log: Arc<ValueLog>,
+    hot_keys: Arc<HotKeys>,
+    // Whether `Maintenance` and `ExpirySweeper` tasks may start.
+    background: Arc<Background>,
+    // How much each of `Options::namespaces` holds. Updated with the
+    // memtable locked.
+    quotas: Arc<Quotas>,
+    compaction_filter: Option<Arc<dyn CompactionFilter>>,
+    // How much of each segment is dead, for `compact_dead_ratio`. Only
+    // touched with the log locked.
+    ledger: Arc<Mutex<Ledger>>,
+    compact_dead_ratio: Option<f64>,
+    segment_size: u64,
+    counters: Arc<Counters>,
+    dir: Arc<PathBuf>,
+    feed: Arc<Mutex<Feed>>,
+    acks: Arc<Acks>,
+    // What replay found when the database was opened.
+    recovery: Arc<RecoveryReport>,
+    write_options: WriteOptions,
+    memtable_bytes: Option<usize>,
+    memtable_slowdown: Option<usize>,
+    memtable_limit: Option<usize>,
+    // Shared with other databases opened by the same `DbManager`.
+    rate_limiter: Arc<RateLimiter>,
+    bloom_bits_per_key: usize,
+    codec: Arc<dyn RecordCodec>,
+    read_only: bool,
+    // Set by `open_follower`: only what `replication::follow` receives from
+    // the primary is written.
+    following: bool,
+    // Set by `close`, so that writes through other clones fail from then on.
+    closed: Arc<AtomicBool>,
+    // Dropped along with the last clone.
+    _close_on_drop: Arc<CloseOnDrop>,
+    // What's been written since the oldest open transaction started. Locked
+    // after `tables`.
+    versions: Arc<Mutex<Versions>>,
+    key_locks: Arc<KeyLocks>,
+    // Only set for databases opened with `open_read_only`.
+    tail: Arc<Mutex<Option<Tail>>>,
+    listener: Option<Arc<dyn DbListener>>,
+    slow_sync: Option<Duration>,
+    write_stall: Option<Duration>,
+    clock: Arc<dyn Clock>,
+}
+
+// Closes the log when the last clone of a `Db` is dropped, the same way
+// `Db::close` does, though there's nobody to tell if it fails. Nothing can be
+// writing by then, so there's nothing to wait for.
+#[derive(Debug)]
+struct CloseOnDrop {
+    log: Arc<Mutex<Log>>,
+    memtable: Arc<ShardedMemtable>,
+}
+
+impl Drop for CloseOnDrop {
+    fn drop(&mut self) {
+        let Ok(mut log) = self.log.lock() else {
+            return;
+        };
+        let checksum = Some(self.memtable.read().checksum());
+        let _ = log.close(checksum);
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum Command {
+    Set(String, String),
+    Delete(String),
+    // An application-defined operation, logged alongside the key-value ones
+    // and handed back to the handler passed to `with_custom_handler` on
+    // replay. It doesn't touch the memtable.
+    Custom(
+        #[serde(serialize_with = "ser_custom", deserialize_with = "de_custom")] serde_json::Value,
+    ),
+    // The same as `Set` and `Delete`, but in a named keyspace (see `Db::cf`).
+    KeyspaceSet(String, String, String),
+    KeyspaceDelete(String, String),
+    // Deletes every key in a keyspace.
+    DropKeyspace(String),
+    // Deletes every key from the first up to but not including the second.
+    DeleteRange(String, String),
+    DeletePrefix(String),
+    // A `Set` that expires at the given time, in milliseconds since the Unix
+    // epoch (see `Db::set_with_ttl`).
+    SetExpiring(String, String, u64),
+    // Sets when a key expires without touching its value. A flush writes
+    // these out for keys whose values went to a table.
+    Expire(String, u64),
+    // Adds the first number to the key's integer value (see `Db::incr`). The
+    // second is the value that leaves it with, worked out by the group-commit
+    // leader just before it's logged, so that replay only has to set it.
+    Incr(String, i64, i64),
+    // Adds an element to the end of the list that is the key's value (see
+    // `Db::append`). Only the element is logged, however long the list gets.
+    Append(String, String),
+    // The writes a transaction made, each a value or None for a deletion (see
+    // `Db::transaction`). They're a single record so that a crash can't
+    // leave some of them in the log without the rest.
+    Transaction(Vec<(String, Option<String>)>),
+    // A write made with `WriteOptions::idempotency_key`. Retries are dropped
+    // before they're logged, so the log has each request at most once.
+    Request(IdempotencyKey, Box<Command>),
+    // The last request applied for a client, written by compaction and
+    // flushes so that retries are still caught afterwards.
+    LastRequest(IdempotencyKey),
+}
+
+// The same records as `Command`, but borrowing from the buffer they were read
+// out of wherever possible, so that replay only allocates for what actually
+// ends up in the memtable. JSON strings containing escapes can't be borrowed
+// and come back owned.
+#[derive(Deserialize, Debug)]
+enum CommandRef<'a> {
+    Set(#[serde(borrow)] Cow<'a, str>, #[serde(borrow)] Cow<'a, str>),
+    Delete(#[serde(borrow)] Cow<'a, str>),
+    Custom(#[serde(deserialize_with = "de_custom")] serde_json::Value),
+    KeyspaceSet(
+        #[serde(borrow)] Cow<'a, str>,
+        #[serde(borrow)] Cow<'a, str>,
+        #[serde(borrow)] Cow<'a, str>,
+    ),
+    KeyspaceDelete(#[serde(borrow)] Cow<'a, str>, #[serde(borrow)] Cow<'a, str>),
+    DropKeyspace(#[serde(borrow)] Cow<'a, str>),
+    DeleteRange(#[serde(borrow)] Cow<'a, str>, #[serde(borrow)] Cow<'a, str>),
+    DeletePrefix(#[serde(borrow)] Cow<'a, str>),
+    SetExpiring(
+        #[serde(borrow)] Cow<'a, str>,
+        #[serde(borrow)] Cow<'a, str>,
+        u64,
+    ),
+    Expire(#[serde(borrow)] Cow<'a, str>, u64),
+    // Replay only needs the resulting value, but the binary encoding can't
+    // skip over the delta without being told what it is.
+    Incr(#[serde(borrow)] Cow<'a, str>, #[allow(dead_code)] i64, i64),
+    Append(#[serde(borrow)] Cow<'a, str>, #[serde(borrow)] Cow<'a, str>),
+    Transaction(Vec<(String, Option<String>)>),
+    Request(IdempotencyKey, #[serde(borrow)] Box<CommandRef<'a>>),
+    LastRequest(IdempotencyKey),
+}
+
+// For codecs that can only decode a `Command`, which then owns everything.
+impl From<Command> for CommandRef<'_> {
+    fn from(command: Command) -> Self {
+        match command {
+            Command::Set(k, v) => CommandRef::Set(k.into(), v.into()),
+            Command::Delete(k) => CommandRef::Delete(k.into()),
+            Command::Custom(op) => CommandRef::Custom(op),
+            Command::KeyspaceSet(name, k, v) => {
+                CommandRef::KeyspaceSet(name.into(), k.into(), v.into())
+            }
+            Command::KeyspaceDelete(name, k) => CommandRef::KeyspaceDelete(name.into(), k.into()),
+            Command::DropKeyspace(name) => CommandRef::DropKeyspace(name.into()),
+            Command::DeleteRange(start, end) => CommandRef::DeleteRange(start.into(), end.into()),
+            Command::DeletePrefix(prefix) => CommandRef::DeletePrefix(prefix.into()),
+            Command::SetExpiring(k, v, at) => CommandRef::SetExpiring(k.into(), v.into(), at),
+            Command::Expire(k, at) => CommandRef::Expire(k.into(), at),
+            Command::Incr(k, delta, value) => CommandRef::Incr(k.into(), delta, value),
+            Command::Append(k, v) => CommandRef::Append(k.into(), v.into()),
+            Command::Transaction(writes) => CommandRef::Transaction(writes),
+            Command::Request(key, cmd) => CommandRef::Request(key, Box::new((*cmd).into())),
+            Command::LastRequest(key) => CommandRef::LastRequest(key),
+        }
+    }
+}
+
+impl CommandRef<'_> {
+    // The same as `Command::effect`.
+    fn effect(&self) -> Effect<'_> {
+        match self {
+            CommandRef::Set(k, _)
+            | CommandRef::SetExpiring(k, _, _)
+            | CommandRef::Incr(k, _, _)
+            | CommandRef::Append(k, _) => Effect::Keys(vec![(k, true)]),
+            CommandRef::Delete(k) => Effect::Keys(vec![(k, false)]),
+            CommandRef::Transaction(writes) => {
+                Effect::Keys(writes.iter().map(|(k, v)| (&**k, v.is_some())).collect())
+            }
+            CommandRef::DeleteRange(start, end) => {
+                Effect::DeleteRange(KeyRange::Between(start.to_string(), end.to_string()))
+            }
+            CommandRef::DeletePrefix(prefix) => {
+                Effect::DeleteRange(KeyRange::Prefix(prefix.to_string()))
+            }
+            CommandRef::KeyspaceDelete(..) | CommandRef::DropKeyspace(_) => Effect::Tombstone,
+            CommandRef::Request(_, cmd) => cmd.effect(),
+            CommandRef::Custom(_)
+            | CommandRef::KeyspaceSet(..)
+            | CommandRef::Expire(..)
+            | CommandRef::LastRequest(_) => Effect::Other,
+        }
+    }
+}
+
+// A custom command's value can be anything, which only a self-describing
+// format like JSON can read back without knowing what to expect, so the binary
+// encoding holds it as a string of JSON.
+fn ser_custom<S: Serializer>(value: &serde_json::Value, serializer: S) -> Result<S::Ok, S::Error> {
+    match serializer.is_human_readable() {
+        true => value.serialize(serializer),
+        false => serializer.serialize_str(&value.to_string()),
+    }
+}
+
+fn de_custom<'de, D: Deserializer<'de>>(deserializer: D) -> Result<serde_json::Value, D::Error> {
+    match deserializer.is_human_readable() {
+        true => serde_json::Value::deserialize(deserializer),
+        false => {
+            let json = <Cow<str>>::deserialize(deserializer)?;
+            serde_json::from_str(&json).map_err(serde::de::Error::custom)
+        }
+    }
+}
+
+impl Command {
+    // The command itself, without the `Request` around it if it has one.
+    fn unwrap_request(&self) -> &Command {
+        match self {
+            Command::Request(_, cmd) => cmd,
+            cmd => cmd,
+        }
+    }
+
+    // Which earlier records the command makes dead (see `Ledger`).
+    fn effect(&self) -> Effect<'_> {
+        match self {
+            Command::Set(k, _)
+            | Command::SetExpiring(k, _, _)
+            | Command::Incr(k, _, _)
+            | Command::Append(k, _) => Effect::Keys(vec![(k, true)]),
+            Command::Delete(k) => Effect::Keys(vec![(k, false)]),
+            Command::Transaction(writes) => {
+                Effect::Keys(writes.iter().map(|(k, v)| (&**k, v.is_some())).collect())
+            }
+            Command::DeleteRange(start, end) => {
+                Effect::DeleteRange(KeyRange::Between(start.clone(), end.clone()))
+            }
+            Command::DeletePrefix(prefix) => Effect::DeleteRange(KeyRange::Prefix(prefix.clone())),
+            Command::KeyspaceDelete(..) | Command::DropKeyspace(_) => Effect::Tombstone,
+            Command::Request(_, cmd) => cmd.effect(),
+            Command::Custom(_)
+            | Command::KeyspaceSet(..)
+            | Command::Expire(..)
+            | Command::LastRequest(_) => Effect::Other,
+        }
+    }
+
+    // How many keys the command writes, counting a range deletion as one.
+    fn write_count(&self) -> usize {
+        match self.unwrap_request() {
+            Command::Transaction(writes) => writes.len(),
+            _ => 1,
+        }
+    }
+
+    // Whether what gets logged for the command depends on what the commands
+    // before it did, so it can't be encoded until they've been applied.
+    fn reads_state(&self) -> bool {
+        matches!(self, Command::Request(..) | Command::Incr(..))
+    }
+
+    // The key the command writes to in the database's own keyspace, if it
+    // writes to just one.
+    fn key(&self) -> Option<&str> {
+        match self {
+            Command::Set(k, _)
+            | Command::Delete(k)
+            | Command::SetExpiring(k, _, _)
+            | Command::Expire(k, _)
+            | Command::Incr(k, _, _)
+            | Command::Append(k, _) => Some(k),
+            _ => None,
+        }
+    }
+
+    fn unwrap_request_mut(&mut self) -> &mut Command {
+        match self {
+            Command::Request(_, cmd) => cmd,
+            cmd => cmd,
+        }
+    }
+}
+
+impl Db {
+    pub fn new<P>(dir: P) -> Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        Self::with_options(dir, Options::default())
+    }
+
+    pub fn with_options<P>(dir: P, options: Options) -> Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        let block_cache = Arc::new(BlockCache::new(options.block_cache_bytes));
+        let rate_limiter = Arc::new(RateLimiter::new(options.background_bytes_per_sec));
+        Self::open(
+            dir.as_ref(),
+            options,
+            block_cache,
+            rate_limiter,
+            &mut |_| Ok(()),
+        )
+    }
+
+    // Like `with_options`, but every custom command in the log is passed to
+    // `handler` as it is replayed, in the order they were written. Compaction
+    // only keeps what's in the memtable, so custom commands written before the
+    // last compaction are gone; applications whose state lives entirely in
+    // their own commands are better off with a `RedoLog`.
+    pub fn with_custom_handler<P, T, F, E>(dir: P, options: Options, mut handler: F) -> Result<Self>
+    where
+        P: AsRef<Path>,
+        T: DeserializeOwned,
+        F: FnMut(T) -> std::result::Result<(), E>,
+        E: Into<Box<dyn std::error::Error + Send + Sync>>,
+    {
+        let block_cache = Arc::new(BlockCache::new(options.block_cache_bytes));
+        let rate_limiter = Arc::new(RateLimiter::new(options.background_bytes_per_sec));
+        Self::open(
+            dir.as_ref(),
+            options,
+            block_cache,
+            rate_limiter,
+            &mut |op| {
+                let op = serde_json::from_value(op).map_err(|e| Error::Handler(e.into()))?;
+                handler(op).map_err(|e| Error::Handler(e.into()))
+            },
+        )
+    }
+
+    // Opens the database in `dir`, caching its tables' blocks in
+    // `block_cache` and limiting its background writes with `rate_limiter`,
+    // either of which other databases may be sharing.
+    fn open(
+        dir: &Path,
+        options: Options,
+        block_cache: Arc<BlockCache>,
+        rate_limiter: Arc<RateLimiter>,
+        custom: &mut CustomHandler,
+    ) -> Result<Self> {
+        let mut memtable = Memtable::default();
+        let mut ledger = Ledger::default();
+        let mut tables = vec![];
+        let mut report = RecoveryReport::default();
+        let start = options.clock.now();
+        let _span = span!("recover", dir = %dir.display());
+        let log = Log::open_shards(dir, options.clone(), |shards| {
+            // After a clean shutdown, every table was synced by the process
+            // that wrote it and nothing was cut short, so there's no need to
+            // read them all the way through. Nor is there to check each record
+            // in the log, as long as replaying them comes up with the memtable
+            // the database was closed with.
+            let clean = Log::closed_cleanly(dir);
+            tables = table::open_all(dir, &block_cache, clean.is_none())?;
+            if let Some(checksum) = clean.and_then(|c| c.memtable) {
+                let trusting = Options {
+                    recovery: RecoveryOptions {
+                        verify: false,
+                        ..options.recovery.clone()
+                    },
+                    ..options.clone()
+                };
+                // Custom commands are held back until it's known that they
+                // won't be replayed a second time.
+                let mut deferred = vec![];
+                memtable = Memtable::new(options.memtable, !tables.is_empty());
+                ledger = Ledger::new(options.compact_dead_ratio.is_some());
+                let trusted = replay::replay_shards(
+                    shards,
+                    &trusting,
+                    &tables,
+                    (&mut memtable, &mut ledger),
+                    &mut |op| {
+                        deferred.push(op);
+                        Ok(())
+                    },
+                );
+                if let Err(Error::Cancelled) = trusted {
+                    return Err(Error::Cancelled);
+                }
+                if let (Ok((trusted, cut)), true) = (trusted, memtable.checksum() == checksum) {
+                    report = RecoveryReport {
+                        clean_shutdown: true,
+                        trusted: true,
+                        ..trusted
+                    };
+                    deferred.into_iter().try_for_each(&mut *custom)?;
+                    return Ok(cut);
+                }
+            }
+            // Some of what the log has may already be in the tables, if we
+            // crashed right after a flush. Replay sorts that out.
+            memtable = Memtable::new(options.memtable, !tables.is_empty());
+            ledger = Ledger::new(options.compact_dead_ratio.is_some());
+            let cut;
+            (report, cut) = replay::replay_shards(
+                shards,
+                &options,
+                &tables,
+                (&mut memtable, &mut ledger),
+                custom,
+            )?;
+            report.clean_shutdown = clean.is_some();
+            Ok(cut)
+        })?;
+        if let (false, Some(first)) = (log.is_read_only(), tables.first()) {
+            table::remove_before(dir, first.number())?;
+        }
+        let replayed = options.clock.now() - start;
+        let mut db = Self::from_log(dir, log, memtable, tables, block_cache, report, &options);
+        db.ledger = Arc::new(Mutex::new(ledger));
+        db.rate_limiter = rate_limiter;
+        db.counters.record_replay(replayed);
+        db.recount_namespaces()?;
+        Ok(db)
+    }
+
+    // Opens a log that another process may be writing to, without taking the
+    // lock, for reporting or analytics. Writes fail, and the contents stay as
+    // they were when it was opened until `refresh` is called. Replay is always
+    // serial, custom commands are skipped, and segments have to be in one of
+    // the built-in codecs.
+    pub fn open_read_only<P>(dir: P) -> Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        let dir = dir.as_ref();
+        let options = Options::default();
+        let log = Log::open_read_only(dir, options.clone(), |_| Ok(()))?;
+        let memtable = ShardedMemtable::new(Memtable::default(), 1);
+        let tables = Mutex::new(vec![]);
+        let block_cache = Arc::new(BlockCache::new(options.block_cache_bytes));
+        let mut tail = Tail::default();
+        let report = RecoveryReport {
+            records: tail.refresh(dir, &memtable, &tables, &block_cache)? as u64,
+            ..RecoveryReport::default()
+        };
+        let db = Self::from_log(
+            dir,
+            log,
+            memtable.into_unsharded()?,
+            tables.into_inner()?,
+            block_cache,
+            report,
+            &options,
+        );
+        *db.tail.lock()? = Some(tail);
+        Ok(db)
+    }
+
+    // Opens a database to follow a primary with `replication::follow`. It
+    // takes writes from nothing else, which would give its records sequence
+    // numbers that the primary's have, so they fail with `Error::ReadOnly`.
+    pub fn open_follower<P>(dir: P, options: Options) -> Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        let mut db = Self::with_options(dir, options)?;
+        db.following = true;
+        Ok(db)
+    }
+
+    // Whether there is a database in `dir`.
+    pub fn exists<P>(dir: P) -> Result<bool>
+    where
+        P: AsRef<Path>,
+    {
+        Log::exists(dir.as_ref())
+    }
+
+    // Deletes the database in `dir` along with every file it keeps there. The
+    // directory goes too, unless it holds files that aren't the database's.
+    // Fails if the database is open.
+    pub fn destroy<P>(dir: P) -> Result<()>
+    where
+        P: AsRef<Path>,
+    {
+        Log::destroy(dir.as_ref())
+    }
+
+    // Salvages every record that can still be read from the damaged log in
+    // `dir` into a fresh log in its place, moving the original aside to
+    // `<dir>.corrupt`. What was skipped is returned, and also written to a
+    // `REPAIR` file in the new log. Sequence numbers start over, so followers
+    // and tailers have to as well. Nothing else can have the log open.
+    pub fn repair<P>(dir: P) -> Result<RecoveryReport>
+    where
+        P: AsRef<Path>,
+    {
+        repair::repair(dir.as_ref())
+    }
+
+    // Turns the log in the file `path`, one JSON command per line as the first
+    // versions of this crate wrote it, into a log directory of the same name
+    // with its records in `codec`, keeping the original as `<path>.jsonl`.
+    // Returns the number of commands carried over. The new log only takes the
+    // original's place once it's complete, so a crash loses nothing, and
+    // running this again after one finishes the job.
+    pub fn migrate<P>(path: P, codec: Arc<dyn RecordCodec>) -> Result<u64>
+    where
+        P: AsRef<Path>,
+    {
+        migrate::migrate(path.as_ref(), codec)
+    }
+
+    // Catches a database opened with `open_read_only` up with whatever has
+    // been written to its log since, and returns the number of records read.
+    // Call it periodically to tail a log.
+    pub fn refresh(&self) -> Result<usize> {
+        match &mut *self.tail.lock()? {
+            Some(tail) => {
+                let records =
+                    tail.refresh(&self.dir, &self.memtable, &self.tables, &self.block_cache)?;
+                self.quotas.invalidate();
+                Ok(records)
+            }
+            None => Err(Error::InvalidConfig(
+                "only databases opened with open_read_only can be refreshed".into(),
+            )),
+        }
+    }
+
+    fn from_log(
+        dir: &Path,
+        log: Log,
+        memtable: Memtable,
+        tables: Vec<Arc<Table>>,
+        block_cache: Arc<BlockCache>,
+        recovery: RecoveryReport,
+        options: &Options,
+    ) -> Self {
+        let read_only = log.is_read_only();
+        if let Some(listener) = &options.listener {
+            listener.on_recovery_complete(&recovery);
+        }
+        let log_shards = log.writing_shards();
+        // Whatever was replayed has been applied, but after a crash, only
+        // what's in the tables is known to be durable until the next sync.
+        let last_seq = log.next_seq() - 1;
+        let watermarks = match recovery.clean_shutdown {
+            true => Watermarks::new(last_seq, last_seq),
+            false => Watermarks::new(log.first_seq() - 1, last_seq),
+        };
+        let log = Arc::new(Mutex::new(log));
+        let shards = match read_only {
+            true => 1,
+            false => options.memtable_shards,
+        };
+        let memtable = Arc::new(ShardedMemtable::new(memtable, shards));
+        let done: BatchNotif = Arc::new((Mutex::new(Some(Ok(()))), std::sync::Condvar::new()));
+        Db {
+            state: Arc::new(Mutex::new(DbState::Pending {
+                prev_batch_notif: done.clone(),
+                prev_done: done.clone(),
+            })),
+            tickets: Arc::new(Tickets::default()),
+            pending: Arc::new(AtomicUsize::new(0)),
+            max_pending_writes: options.max_pending_writes,
+            backpressure: options.backpressure,
+            log: log.clone(),
+            log_shards,
+            pipelined: log_shards > 1 || (options.pipeline_commits && options.log_mirror.is_none()),
+            apply_during_sync: options.apply_during_sync,
+            watermarks: Arc::new(watermarks),
+            hooks: Arc::new(CommitHooks::new(options.commit_callbacks)),
+            synced: Arc::new(Mutex::new(done.clone())),
+            progress: Arc::new(Mutex::new(Progress::default())),
+            in_flight: Arc::new(Mutex::new(done)),
+            flushing: Arc::default(),
+            unapplied: Arc::default(),
+            memtable: memtable.clone(),
+            tables: Arc::new(Mutex::new(tables)),
+            block_cache,
+            value_log: Arc::new(ValueLog::new(dir, options)),
+            hot_keys: Arc::new(HotKeys::new(options.hot_keys)),
+            background: Arc::default(),
+            quotas: Arc::new(Quotas::new(options.namespaces.clone())),
+            clock: options.clock.clone(),
+            compaction_filter: options.compaction_filter.clone(),
+            ledger: Arc::new(Mutex::new(Ledger::new(
+                options.compact_dead_ratio.is_some(),
+            ))),
+            compact_dead_ratio: options.compact_dead_ratio,
+            segment_size: options.segment_size,
+            counters: Arc::new(Counters::default()),
+            dir: Arc::new(dir.to_path_buf()),
+            feed: Arc::new(Mutex::new(Feed::new(last_seq))),
+            acks: Arc::new(Acks::default()),
+            recovery: Arc::new(recovery),
+            write_options: options.write,
+            memtable_bytes: options.memtable_bytes,
+            memtable_slowdown: options.memtable_slowdown,
+            memtable_limit: options.memtable_limit,
+            rate_limiter: Arc::new(RateLimiter::new(options.background_bytes_per_sec)),
+            bloom_bits_per_key: options.bloom_bits_per_key,
+            codec: options.codec.clone(),
+            read_only,
+            following: false,
+            closed: Arc::new(AtomicBool::new(false)),
+            _close_on_drop: Arc::new(CloseOnDrop {
+                log: log.clone(),
+                memtable: memtable.clone(),
+            }),
+            versions: Arc::new(Mutex::new(Versions::default())),
+            key_locks: Arc::new(KeyLocks::default()),
+            tail: Arc::new(Mutex::new(None)),
+            listener: options.listener.clone(),
+            slow_sync: options.slow_sync,
+            write_stall: options.write_stall,
+        }
+    }
+
+    // Locks the shards of the memtable that `commands` write to, and applies
+    // them as a batch logged from `first_seq` on, with what `read_tables` read
+    // for them. The caller holds the log lock.
+    fn apply_batch(
+        &self,
+        first_seq: u64,
+        commands: Vec<Command>,
+        reads: &TableReads,
+    ) -> Result<()> {
+        let mut touched = vec![false; self.memtable.shard_count()];
+        for command in &commands {
+            self.touched_shards(command, &mut touched);
+        }
+        let last_seq = first_seq + commands.len() as u64 - 1;
+        let mut memtable = self.memtable.write_shards(&touched)?;
+        self.apply_locked(&mut memtable, first_seq, commands, reads)?;
+        self.watermarks.set_applied(last_seq);
+        Ok(())
+    }
+
+    // Marks the shards of the memtable that applying `command` writes to.
+    fn touched_shards(&self, command: &Command, touched: &mut [bool]) {
+        match command {
+            Command::Custom(_) => {}
+            Command::DeleteRange(..) | Command::DeletePrefix(..) => touched.fill(true),
+            Command::Transaction(writes) => {
+                for (k, _) in writes {
+                    touched[self.memtable.shard_of(k)] = true;
+                }
+            }
+            Command::Request(_, command) => {
+                touched[0] = true;
+                self.touched_shards(command, touched);
+            }
+            command => match command.key() {
+                Some(k) => touched[self.memtable.shard_of(k)] = true,
+                None => touched[0] = true,
+            },
+        }
+    }
+
+    // Applies a batch that has been logged from `first_seq` on to the shards
+    // it writes to, noting what it writes for the sake of any open
+    // transactions.
+    fn apply_locked(
+        &self,
+        memtable: &mut Shards<Writing<'_>>,
+        first_seq: u64,
+        commands: Vec<Command>,
+        reads: &TableReads,
+    ) -> Result<()> {
+        let _span = span!("apply", first_seq);
+        let mut versions = error::lock(&self.versions);
+        for (seq, command) in (first_seq..).zip(commands) {
+            versions.record(seq, &command);
+            self.hot_keys.record(&command);
+            if memtable.has_snapshots() {
+                Self::remember_versions(memtable, reads, seq, &command)?;
+            }
+            let keys = self.quotas.keys(&command);
+            let before = Self::stored_sizes(memtable, reads, &keys)?;
+            Self::apply_command_to_shards(memtable, &|k| reads.get(k), command)?;
+            let after = Self::stored_sizes(memtable, reads, &keys)?;
+            self.quotas.update(&keys, &before, &after);
+        }
+        Ok(())
+    }
+
+    // Saves whatever `command`, numbered `seq`, is about to overwrite, for
+    // open snapshots to read.
+    fn remember_versions(
+        memtable: &mut Shards<Writing<'_>>,
+        reads: &TableReads,
+        seq: u64,
+        command: &Command,
+    ) -> Result<()> {
+        let range = match command.unwrap_request() {
+            Command::Set(k, _)
+            | Command::Delete(k)
+            | Command::SetExpiring(k, _, _)
+            | Command::Incr(k, _, _)
+            | Command::Append(k, _) => {
+                let prior = Self::current(memtable.key(k), reads, k)?;
+                memtable.key_mut(k).remember(k.clone(), seq, prior);
+                return Ok(());
+            }
+            Command::Transaction(writes) => {
+                for (k, _) in writes {
+                    let prior = Self::current(memtable.key(k), reads, k)?;
+                    memtable.key_mut(k).remember(k.clone(), seq, prior);
+                }
+                return Ok(());
+            }
+            Command::DeleteRange(start, end) => KeyRange::Between(start.clone(), end.clone()),
+            Command::DeletePrefix(prefix) => KeyRange::Prefix(prefix.clone()),
+            _ => return Ok(()),
+        };
+        let mut keys = memtable
+            .sorted("")
+            .into_iter()
+            .map(|(k, _)| k)
+            .filter(|k| range.contains(k))
+            .collect::<Vec<_>>();
+        keys.extend(reads.keys(&range));
+        keys.sort_unstable();
+        keys.dedup();
+        for k in keys {
+            let prior = Self::current(memtable.key(&k), reads, &k)?;
+            memtable.key_mut(&k).remember(k, seq, prior);
+        }
+        Ok(())
+    }
+
+    // The value of `k`, ignoring TTLs, while the caller holds the memtable.
+    fn current(memtable: &Memtable, reads: &TableReads, k: &str) -> Result<Option<String>> {
+        match memtable.get(k) {
+            Some(v) => Ok(v.map(Cow::into_owned)),
+            None => reads.get(k),
+        }
+    }
+
+    // Takes the command by value so that its key and value move straight into
+    // the memtable. `base` is what the tables have for a key, which is only
+    // asked for by appends to keys the memtable doesn't have.
+    fn apply_command_to_memtable(
+        memtable: &mut Memtable,
+        base: Base<'_>,
+        cmd: Command,
+    ) -> Result<()> {
+        match cmd {
+            Command::Set(k, v) => memtable.set(k.into(), v.into()),
+            Command::Delete(k) => memtable.delete(k.into()),
+            Command::Custom(_) => {}
+            Command::KeyspaceSet(name, k, v) => memtable.set_in(name.into(), k.into(), v.into()),
+            Command::KeyspaceDelete(name, k) => memtable.delete_in(&name, &k),
+            Command::DropKeyspace(name) => memtable.drop_keyspace(&name),
+            Command::DeleteRange(start, end) => {
+                memtable.delete_range(KeyRange::Between(start, end))
+            }
+            Command::DeletePrefix(prefix) => memtable.delete_range(KeyRange::Prefix(prefix)),
+            Command::SetExpiring(k, v, at) => memtable.set_expiring(k.into(), v.into(), at),
+            Command::Expire(k, at) => memtable.expire(&k, at),
+            Command::Incr(k, _, value) => memtable.set(k.into(), value.to_string().into()),
+            Command::Append(k, element) => {
+                memtable.append(k.as_str().into(), &element, || base(&k))?
+            }
+            Command::Transaction(writes) => {
+                for (k, v) in writes {
+                    match v {
+                        Some(v) => memtable.set(k.into(), v.into()),
+                        None => memtable.delete(k.into()),
+                    }
+                }
+            }
+            Command::Request(key, cmd) => {
+                memtable.note_request(key);
+                return Self::apply_command_to_memtable(memtable, base, *cmd);
+            }
+            Command::LastRequest(key) => memtable.note_request(key),
+        }
+        Ok(())
+    }
+
+    // Applies `cmd` to whichever shards it writes to, which the caller has
+    // locked.
+    fn apply_command_to_shards(
+        memtable: &mut Shards<Writing<'_>>,
+        base: Base<'_>,
+        cmd: Command,
+    ) -> Result<()> {
+        match cmd {
+            Command::Custom(_) => {}
+            Command::DeleteRange(..) | Command::DeletePrefix(..) => {
+                for shard in memtable.each_mut() {
+                    Self::apply_command_to_memtable(shard, base, cmd.clone())?;
+                }
+            }
+            Command::Transaction(writes) => {
+                for (k, v) in writes {
+                    let shard = memtable.key_mut(&k);
+                    match v {
+                        Some(v) => shard.set(k.into(), v.into()),
+                        None => shard.delete(k.into()),
+                    }
+                }
+            }
+            Command::Request(key, cmd) => {
+                memtable.home_mut().note_request(key);
+                return Self::apply_command_to_shards(memtable, base, *cmd);
+            }
+            cmd => {
+                let shard = match cmd.key() {
+                    Some(k) => memtable.key_mut(k),
+                    None => memtable.home_mut(),
+                };
+                return Self::apply_command_to_memtable(shard, base, cmd);
+            }
+        }
+        Ok(())
+    }
+
+    fn replay_command(
+        memtable: &mut Memtable,
+        tables: &[Arc<Table>],
+        cmd: CommandRef,
+    ) -> Result<()> {
+        match cmd {
+            CommandRef::Set(k, v) => memtable.set(k, v),
+            CommandRef::Delete(k) => memtable.delete(k),
+            CommandRef::Custom(_) => {}
+            CommandRef::KeyspaceSet(name, k, v) => memtable.set_in(name, k, v),
+            CommandRef::KeyspaceDelete(name, k) => memtable.delete_in(&name, &k),
+            CommandRef::DropKeyspace(name) => memtable.drop_keyspace(&name),
+            CommandRef::DeleteRange(start, end) => {
+                memtable.delete_range(KeyRange::Between(start.into(), end.into()))
+            }
+            CommandRef::DeletePrefix(prefix) => {
+                memtable.delete_range(KeyRange::Prefix(prefix.into()))
+            }
+            CommandRef::SetExpiring(k, v, at) => memtable.set_expiring(k, v, at),
+            CommandRef::Expire(k, at) => memtable.expire(&k, at),
+            CommandRef::Incr(k, _, value) => memtable.set(k, value.to_string().into()),
+            CommandRef::Append(k, element) => {
+                memtable.append(k.clone(), &element, || try_table_get(tables, &k))?
+            }
+            CommandRef::Transaction(writes) => {
+                let base = |k: &str| try_table_get(tables, k);
+                return Self::apply_command_to_memtable(
+                    memtable,
+                    &base,
+                    Command::Transaction(writes),
+                );
+            }
+            CommandRef::Request(key, cmd) => {
+                memtable.note_request(key);
+                return Self::replay_command(memtable, tables, *cmd);
+            }
+            CommandRef::LastRequest(key) => memtable.note_request(key),
+        }
+        Ok(())
+    }
+
+    fn wait_for(cvar: BatchNotif) -> Result<(), String> {
+        let mut done = error::lock(&cvar.0);
+        loop {
+            match &*done {
+                Some(result) => return result.clone(),
+                // A simulated thread can't block, or whoever it's waiting
+                // on would never get to run.
+                #[cfg(test)]
+                None if sim::active() => {
+                    drop(done);
+                    sim::step("wait");
+                    done = error::lock(&cvar.0);
+                }
+                None => done = cvar.1.wait(done).unwrap_or_else(PoisonError::into_inner),
+            }
+        }
+    }
+
+    // Writes, syncs and applies a batch on behalf of everyone in it. Without
+    // `sync`, nobody in the batch asked for it to be synced, and it's left
+    // for the next batch that is. Hands back the batch if the commit
+    // callbacks are to hear about it once its writers have been told.
+    fn commit_batch(
+        &self,
+        log: &mut Log,
+        mut writes: Vec<Command>,
+        sync: bool,
+    ) -> Result<Option<Committed>> {
+        self.drop_retries(&mut writes)?;
+        self.resolve_incrs(&mut writes, false)?;
+        let reads = self.read_tables(&writes)?;
+        let start = self.now();
+        let payloads = writes
+            .iter()
+            .map(|cmd| codec::encode(&*self.codec, cmd))
+            .collect::<Result<Vec<_>>>()?;
+        let first_seq = log.next_seq();
+        let committed = self.hooks.capture(first_seq, &writes, sync);
+        let bytes = {
+            let _span = span!("write", first_seq);
+            log.append_batch(&payloads)?
+        };
+        error::lock(&self.ledger).record_batch(log.placed(), &payloads, &writes);
+        let mut pending = None;
+        let sync = if sync {
+            let synced = self.now();
+            let _span = span!("sync");
+            pending = self.sync_staging(log, first_seq, &mut writes)?;
+            Some(self.now() - synced)
+        } else {
+            None
+        };
+        self.counters
+            .record_batch(payloads.len(), bytes, self.now() - start, sync);
+        self.committed(first_seq, payloads.len(), bytes);
+        if let Some(sync) = sync {
+            self.check_sync(sync, payloads.len(), bytes);
+        }
+        self.feed.lock()?.publish(Batch {
+            first_seq,
+            payloads,
+        });
+        // Now we apply each command to the memtable:
+        match pending {
+            Some(pending) => self.publish(pending, &reads)?,
+            None => self.apply_batch(first_seq, writes, &reads)?,
+        }
+        Ok(self.hooks.before_release(committed))
+    }
+
+    // Syncs the log, and if `writes` are enough of them (see
+    // `Options::apply_during_sync`), stages them while it does. Whatever
+    // isn't staged is left in `writes`.
+    fn sync_staging(
+        &self,
+        log: &mut Log,
+        first_seq: u64,
+        writes: &mut Vec<Command>,
+    ) -> Result<Option<Pending>> {
+        let count = writes.iter().map(Command::write_count).sum::<usize>();
+        if self.apply_during_sync.is_none_or(|min| count < min) {
+            #[cfg(test)]
+            sim::sync();
+            log.sync()?;
+            self.set_durable(log.next_seq() - 1);
+            return Ok(None);
+        }
+        let (staged, synced) = std::thread::scope(|s| {
+            let syncing = s.spawn(|| log.sync());
+            let staged = self.stage(first_seq, std::mem::take(writes));
+            #[cfg(test)]
+            sim::sync();
+            (staged, syncing.join())
+        });
+        let pending = match staged {
+            Ok(pending) => Some(pending),
+            Err(unstaged) => {
+                *writes = unstaged;
+                None
+            }
+        };
+        synced.unwrap_or_else(|e| std::panic::resume_unwind(e))?;
+        self.set_durable(log.next_seq() - 1);
+        Ok(pending)
+    }
+
+    // Sorts a batch logged from `first_seq` on into a `Pending`, or hands it
+    // back if it deletes a range, which writes to every shard.
+    fn stage(&self, first_seq: u64, writes: Vec<Command>) -> Result<Pending, Vec<Command>> {
+        let ranges = writes.iter().any(|command| {
+            matches!(
+                command.unwrap_request(),
+                Command::DeleteRange(..) | Command::DeletePrefix(..)
+            )
+        });
+        if ranges {
+            return Err(writes);
+        }
+        let last_seq = first_seq + writes.len() as u64 - 1;
+        let mut shards = vec![vec![]; self.memtable.shard_count()];
+        for (seq, command) in (first_seq..).zip(writes) {
+            self.stage_command(&mut shards, seq, command);
+        }
+        Ok(Pending { last_seq, shards })
+    }
+
+    // Splits `command` into writes to one shard each, doing whatever work
+    // applying them would that doesn't need the memtable.
+    fn stage_command(&self, shards: &mut [Vec<(u64, Command)>], seq: u64, command: Command) {
+        let (k, command) = match command {
+            Command::Custom(_) => return,
+            Command::Transaction(writes) => {
+                for (k, v) in writes {
+                    let command = match v {
+                        Some(v) => Command::Set(k, v),
+                        None => Command::Delete(k),
+                    };
+                    self.stage_command(shards, seq, command);
+                }
+                return;
+            }
+            Command::Request(key, command) => {
+                shards[0].push((seq, Command::LastRequest(key)));
+                return self.stage_command(shards, seq, *command);
+            }
+            Command::Incr(k, _, value) => {
+                let shard = self.memtable.shard_of(&k);
+                shards[shard].push((seq, Command::Set(k, value.to_string())));
+                return;
+            }
+            command => (command.key().map(|k| self.memtable.shard_of(k)), command),
+        };
+        shards[k.unwrap_or(0)].push((seq, command));
+    }
+
+    // Applies a batch staged while it was synced, now that it has been.
+    fn publish(&self, pending: Pending, reads: &TableReads) -> Result<()> {
+        let touched = pending
+            .shards
+            .iter()
+            .map(|writes| !writes.is_empty())
+            .collect::<Vec<_>>();
+        let mut memtable = self.memtable.write_shards(&touched)?;
+        let _span = span!("apply");
+        let mut versions = error::lock(&self.versions);
+        for (i, writes) in pending.shards.into_iter().enumerate() {
+            for (seq, command) in writes {
+                versions.record(seq, &command);
+                self.hot_keys.record(&command);
+                if memtable.has_snapshots() {
+                    Self::remember_versions(&mut memtable, reads, seq, &command)?;
+                }
+                let keys = self.quotas.keys(&command);
+                let before = Self::stored_sizes(&memtable, reads, &keys)?;
+                let shard = memtable.shard_mut(i);
+                Self::apply_command_to_memtable(shard, &|k| reads.get(k), command)?;
+                let after = Self::stored_sizes(&memtable, reads, &keys)?;
+                self.quotas.update(&keys, &before, &after);
+            }
+        }
+        self.watermarks.set_applied(pending.last_seq);
+        Ok(())
+    }
+
+    // Writes a batch to the log, then lets go of the log to sync it, so that
+    // the next batch can be written alongside. `appended` tells the next
+    // leader it can go ahead. Batches are still committed in order: none is
+    // done until the one before it is.
+    //
+    // Either way, a batch is only applied once it's synced and the batch
+    // before it is committed. A sharded log syncs it alongside the next
+    // batch's sync of another shard, and leaves what it does in `unapplied`
+    // for the next batch to work out its increments and retries from.
+    // Otherwise the syncs go one at a time, and a batch that needs to know
+    // what the one before it did waits for it to be applied.
+    fn commit_pipelined(
+        &self,
+        mut log: MutexGuard<'_, Log>,
+        mut writes: Vec<Command>,
+        sync: bool,
+        appended: &Finish,
+        prev_done: BatchNotif,
+    ) -> Result<Option<Committed>> {
+        let start = self.now();
+        let early = self.log_shards > 1;
+        let synced = Finish(Arc::new((Mutex::new(None), std::sync::Condvar::new())));
+        let written = (|| -> Result<_> {
+            if !early && writes.iter().any(Command::reads_state) {
+                Self::wait_for(prev_done.clone()).map_err(Error::WriteFailed)?;
+            }
+            self.drop_retries(&mut writes)?;
+            let effects = self.resolve_incrs(&mut writes, early)?;
+            let reads = self.read_tables(&writes)?;
+            let payloads = writes
+                .iter()
+                .map(|cmd| codec::encode(&*self.codec, cmd))
+                .collect::<Result<Vec<_>>>()?;
+            let first_seq = log.next_seq();
+            let committed = self.hooks.capture(first_seq, &writes, sync);
+            let bytes = {
+                let _span = span!("write", first_seq);
+                log.append_batch(&payloads)?
+            };
+            error::lock(&self.ledger).record_batch(log.placed(), &payloads, &writes);
+            if early {
+                self.unapplied.push(log.next_seq() - 1, effects);
+            }
+            let syncers = match sync {
+                true => log.syncers(),
+                false => vec![],
+            };
+            let writes = std::mem::take(&mut writes);
+            let prev_synced = match early {
+                true => None,
+                false => {
+                    let mut progress = self.progress.lock()?;
+                    progress.segment = log.active_number().unwrap_or(0);
+                    progress.written = log.next_seq();
+                    Some(std::mem::replace(
+                        &mut *self.synced.lock()?,
+                        synced.0.clone(),
+                    ))
+                }
+            };
+            let batch = (first_seq, payloads, bytes, committed);
+            Ok((batch, syncers, (writes, reads), prev_synced))
+        })();
+        appended.set(written.as_ref().map(|_| ()).map_err(|e| e.to_string()));
+        drop(log);
+        let ((first_seq, payloads, bytes, committed), syncers, (writes, reads), prev_synced) =
+            written?;
+        // If the sync before failed, so will this one.
+        if let Some(prev_synced) = prev_synced {
+            let _ = Self::wait_for(prev_synced);
+        }
+        let end = first_seq + payloads.len() as u64;
+        // How far syncing takes the log, if it isn't there already.
+        let through = match (sync, early) {
+            (false, _) => None,
+            (true, true) => Some(end),
+            (true, false) => {
+                let progress = self.progress.lock()?;
+                match syncers.iter().any(|s| s.number() == progress.segment) {
+                    _ if progress.synced >= end => None,
+                    true => Some(progress.written),
+                    false => Some(end),
+                }
+            }
+        };
+        let sync = if let Some(through) = through {
+            let started = self.now();
+            let _span = span!("sync");
+            #[cfg(test)]
+            sim::sync();
+            for syncer in syncers {
+                syncer.sync()?;
+            }
+            let mut progress = self.progress.lock()?;
+            progress.synced = progress.synced.max(through);
+            // Syncs of an unsharded log go in order, so everything before
+            // `through` is durable by now. A sharded log's batches can sync
+            // out of order, so it has to wait for the batch before.
+            if !early {
+                self.set_durable(through - 1);
+            }
+            Some(self.now() - started)
+        } else {
+            None
+        };
+        synced.set(Ok(()));
+        Self::wait_for(prev_done).map_err(Error::WriteFailed)?;
+        if let (true, Some(_)) = (early, sync) {
+            self.set_durable(end - 1);
+        }
+        self.apply_batch(first_seq, writes, &reads)?;
+        self.unapplied.applied(end - 1);
+        self.counters
+            .record_batch(payloads.len(), bytes, self.now() - start, sync);
+        self.committed(first_seq, payloads.len(), bytes);
+        if let Some(sync) = sync {
+            self.check_sync(sync, payloads.len(), bytes);
+        }
+        self.feed.lock()?.publish(Batch {
+            first_seq,
+            payloads,
+        });
+        Ok(self.hooks.before_release(committed))
+    }
+
+    // Everything logged through `seq` has been synced, so it's durable, and
+    // followers can have it.
+    fn set_durable(&self, seq: u64) {
+        self.watermarks.set_durable(seq);
+        error::lock(&self.feed).synced(seq);
+    }
+
+    // Locks the log for writing to it directly, once whatever batch might
+    // still be syncing a shard of it is done. If that failed, so will the
+    // write.
+    fn lock_log(&self) -> Result<MutexGuard<'_, Log>> {
+        let log = self.log.lock()?;
+        let in_flight = self.in_flight.lock()?.clone();
+        let _ = Self::wait_for(in_flight);
+        Ok(log)
+    }
+
+    // Locks the log once every batch written to it has been applied, for
+    // transactions and snapshots to start from its next sequence number. A
+    // pipelined batch lets go of the log before it's applied, so starting
+    // from there without waiting for it would miss what it writes.
+    fn lock_applied(&self) -> MutexGuard<'_, Log> {
+        let log = error::lock(&self.log);
+        let _ = Self::wait_for(error::lock(&self.in_flight).clone());
+        log
+    }
+
+    // Waits, or fails, until there's room for another pending write under
+    // `max_pending_writes`, and counts it until the guard is dropped.
+    fn admit(&self) -> Result<Admitted<'_>> {
+        let max = self.max_pending_writes.map_or(usize::MAX, |max| max.max(1));
+        let mut backoff = Duration::from_micros(50);
+        loop {
+            let admitted = self
+                .pending
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| {
+                    (n < max).then_some(n + 1)
+                });
+            match (admitted, self.backpressure) {
+                (Ok(_), _) => return Ok(Admitted(&self.pending)),
+                (Err(pending), Backpressure::Fail) => return Err(Error::Overloaded { pending }),
+                // Sleeping would leave everyone else in the simulation parked
+                // too, so nothing would ever get committed.
+                #[cfg(test)]
+                (Err(_), Backpressure::Block) if sim::active() => sim::step("backoff"),
+                (Err(_), Backpressure::Block) => {
+                    std::thread::sleep(backoff);
+                    backoff = (backoff * 2).min(Duration::from_millis(10));
+                }
+            }
+        }
+    }
+
+    // Holds a write back while the memtable is past
+    // `Options::memtable_slowdown`, for longer the further past it is.
+    fn slow_down(&self) -> Result<()> {
+        self.wait_for_memory()?;
+        let Some(slowdown) = self.memtable_slowdown else {
+            return Ok(());
+        };
+        let memory = self.memtable.memory();
+        if memory <= slowdown {
+            return Ok(());
+        }
+        let limit = self
+            .memtable_bytes
+            .filter(|&max| max > slowdown)
+            .unwrap_or(slowdown.saturating_mul(2));
+        let past = (memory - slowdown) as f64 / (limit - slowdown).max(1) as f64;
+        let delay = MAX_SLOWDOWN.mul_f64(past.min(1.0));
+        self.counters.record_slowdown(delay);
+        // A simulated thread can't sleep without stopping everyone else.
+        #[cfg(test)]
+        if sim::active() {
+            sim::step("slowdown");
+            return Ok(());
+        }
+        std::thread::sleep(delay);
+        Ok(())
+    }
+
+    // Holds a write back while the memtable is at `Options::memtable_limit`,
+    // flushing it if nobody else is.
+    fn wait_for_memory(&self) -> Result<()> {
+        let Some(limit) = self.memtable_limit else {
+            return Ok(());
+        };
+        let started = self.now();
+        let mut backoff = Duration::from_micros(50);
+        while self.memtable.memory() >= limit {
+            if self.memtable.read().bytes() == 0 {
+                break;
+            }
+            match self.flushing.try_lock() {
+                Ok(_flushing) => {
+                    let log = self.lock_log()?;
+                    if log.is_closed() {
+                        return Err(Error::Closed);
+                    }
+                    drop(self.flush_locked(log, false)?);
+                    break;
+                }
+                Err(std::sync::TryLockError::WouldBlock) => {}
+                Err(std::sync::TryLockError::Poisoned(_)) => return Err(Error::Poisoned),
+            }
+            // Someone else is flushing, which the simulation has to be let
+            // get on with.
+            #[cfg(test)]
+            if sim::active() {
+                sim::step("memtable limit");
+                continue;
+            }
+            std::thread::sleep(backoff);
+            backoff = (backoff * 2).min(Duration::from_millis(10));
+        }
+        let waited = self.now().saturating_duration_since(started);
+        if waited > Duration::ZERO {
+            self.counters.record_slowdown(waited);
+        }
+        Ok(())
+    }
+
+    fn apply_command(&mut self, command: Command, sync: bool) -> Result<()> {
+        self.slow_down()?;
+        let _admitted = self.admit()?;
+        #[cfg(test)]
+        sim::step("join");
+        let joined = self.now();
+        let turn = self.tickets.wait_turn();
+        let mut state = self.state.lock()?;
+        match &mut *state {
+            DbState::Pending { .. } => {
+                // There's a pending batch, but no current leader. We shall
+                // become the leader.
+                let done: BatchNotif = Arc::new((Mutex::new(None), std::sync::Condvar::new()));
+                let finish = Finish(done.clone());
+                let appended = match self.pipelined {
+                    false => done.clone(),
+                    true => Arc::new((Mutex::new(None), std::sync::Condvar::new())),
+                };
+                let (notif, prev_done) = if let DbState::Pending {
+                    prev_batch_notif,
+                    prev_done,
+                } = std::mem::replace(
+                    &mut *state,
+                    DbState::PendingLeader {
+                        writes: vec![command],
+                        sync,
+                        batch_notif: done.clone(),
+                    },
+                ) {
+                    (prev_batch_notif, prev_done)
+                } else {
+                    panic!("invalid");
+                };
+                drop(state);
+                drop(turn);
+                #[cfg(test)]
+                sim::step("lead");
+                // Now wait for the previous batch to finish. If it failed, the
+                // log will refuse our batch too, so there's no need to look.
+                let _ = Self::wait_for(notif);
+                // Regrab the lock.
+                let mut state = self.state.lock()?;
+                let (writes, sync) = if let DbState::PendingLeader { writes, sync, .. } =
+                    std::mem::replace(
+                        &mut *state,
+                        DbState::Pending {
+                            prev_batch_notif: appended.clone(),
+                            prev_done: done.clone(),
+                        },
+                    ) {
+                    (writes, sync)
+                } else {
+                    panic!("expected to still be the leader");
+                };
+                let mut log = self.log.lock()?;
+                drop(state);
+                let _span = span!("commit", commands = writes.len());
+                if self.pipelined {
+                    *self.in_flight.lock()? = done.clone();
+                    let appended = Finish(appended);
+                    let result = self.commit_pipelined(log, writes, sync, &appended, prev_done);
+                    finish.set(result.as_ref().map(|_| ()).map_err(|e| e.to_string()));
+                    self.hooks.deliver(result?);
+                    self.check_stall(self.now() - joined, 1);
+                    if self.memtable_full()? {
+                        self.flush_if_full(self.lock_log()?)?;
+                    }
+                    return Ok(());
+                }
+                let result = self.commit_batch(&mut log, writes, sync);
+                // Finally, we are done. Let everyone know, including whether
+                // it worked: they share our fate.
+                finish.set(result.as_ref().map(|_| ()).map_err(|e| e.to_string()));
+                self.hooks.deliver(result?);
+                self.check_stall(self.now() - joined, 1);
+                // Everyone in the batch can go, and the next batch only
+                // waits for the log while we start a flush and finish it.
+                self.flush_if_full(log)?;
+            }
+            DbState::PendingLeader {
+                writes,
+                sync: batch_sync,
+                batch_notif,
+            } => {
+                // There is already a leader, so we will push our writes into
+                // the queue and then wait for the leader to tell us that the
+                // batch has been synced.
+                writes.push(command);
+                *batch_sync |= sync;
+                let queue_depth = writes.len();
+                let batch_notif = batch_notif.clone();
+                drop(state);
+                drop(turn);
+                #[cfg(test)]
+                sim::step("follow");
+                Self::wait_for(batch_notif).map_err(Error::WriteFailed)?;
+                self.check_stall(self.now() - joined, queue_depth);
+            }
+        }
+        Ok(())
+    }
+
+    // Once the command has been committed locally, waits for as many
+    // followers as `options` asks for to have it too.
+    fn apply_command_with_options(
+        &mut self,
+        command: Command,
+        options: &WriteOptions,
+    ) -> Result<()> {
+        self.check_writable()?;
+        let mut command = command;
+        let value_log = self.value_log.clone();
+        let _pinned = value_log.pin();
+        self.separate(&mut command)?;
+        self.apply_separated(command, options)
+    }
+
+    fn check_writable(&self) -> Result<()> {
+        if self.read_only || self.following {
+            return Err(Error::ReadOnly);
+        }
+        if self.closed.load(Ordering::SeqCst) {
+            return Err(Error::Closed);
+        }
+        Ok(())
+    }
+
+    // `apply_command_with_options` for a command whose values have already
+    // been moved out to the value log wherever they need to be.
+    fn apply_separated(&mut self, command: Command, options: &WriteOptions) -> Result<()> {
+        let _reserved = self.check_quota(&command)?;
+        // Custom commands are left as they are, so that replay can find them.
+        let command = match options.idempotency_key {
+            Some(key) if !matches!(command, Command::Custom(_)) => {
+                Command::Request(key, Box::new(command))
+            }
+            _ => command,
+        };
+        // Followers only get what's been synced, so a write that waits for
+        // them is synced whatever `sync` says.
+        let quorum = matches!(options.replication, Replication::Quorum(_));
+        self.apply_command(command, options.sync || quorum)?;
+        if let Replication::Quorum(n) = options.replication {
+            // We don't know exactly which seq our command got if it went out
+            // as part of someone else's batch, but it's no later than the
+            // last one published, so waiting for that is enough.
+            let seq = self.feed.lock()?.durable_seq();
+            self.acks.wait_for(seq, n, options.timeout)?;
+        }
+        Ok(())
+    }
+
+    pub fn set(&mut self, k: &str, v: &str) -> Result<()> {
+        let options = self.write_options;
+        self.set_with_options(k, v, &options)
+    }
+
+    // Sets `k` to everything `reader` has, which goes straight to the value
+    // log a chunk at a time, each with a checksum of its own, so that it
+    // never has to be in memory all at once. Returns how many bytes that
+    // was. Only a pointer to it is logged, as for any value in the value log,
+    // though this works whether `Options::value_log` is on or not. `get`
+    // reads it whole, with anything that isn't UTF-8 replaced, and
+    // `get_blob` reads it back the way it was written.
+    pub fn put_blob<R: io::Read>(&mut self, k: &str, reader: R) -> Result<u64> {
+        self.check_writable()?;
+        let value_log = self.value_log.clone();
+        let _pinned = value_log.pin();
+        let blob = value_log.put_blob(k, reader)?;
+        let options = self.write_options;
+        self.apply_separated(Command::Set(k.to_owned(), blob.to_string()), &options)?;
+        Ok(blob.len)
+    }
+
+    pub fn set_with_options(&mut self, k: &str, v: &str, options: &WriteOptions) -> Result<()> {
+        self.apply_command_with_options(Command::Set(k.to_owned(), v.to_owned()), options)
+    }
+
+    // Sets `k` to `v` until `ttl` has passed. After that, reads act as though
+    // it was deleted, and `Db::expire` or an `ExpirySweeper` deletes it for
+    // real. The time it expires is logged, so a restart doesn't extend it.
+    pub fn set_with_ttl(&mut self, k: &str, v: &str, ttl: Duration) -> Result<()> {
+        let options = self.write_options;
+        let expires_at = clock::millis(&*self.clock).saturating_add(ttl.as_millis() as u64);
+        let cmd = Command::SetExpiring(k.to_owned(), v.to_owned(), expires_at);
+        self.apply_command_with_options(cmd, &options)
+    }
+
+    // Deletes every key whose TTL has run out, and returns how many there
+    // were. The deletions are logged like any others, so they replicate and
+    // survive recovery.
+    pub fn expire(&mut self) -> Result<usize> {
+        if self.read_only || self.following {
+            return Err(Error::ReadOnly);
+        }
+        // Holding the log lock keeps writers out, so nothing can set a key
+        // again between finding it expired and deleting it.
+        let mut log = self.lock_log()?;
+        let expired = self.memtable.read().expired(clock::millis(&*self.clock));
+        if expired.is_empty() {
+            return Ok(0);
+        }
+        let n = expired.len();
+        let commands = expired.into_iter().map(Command::Delete).collect::<Vec<_>>();
+        let payloads = commands
+            .iter()
+            .map(|cmd| codec::encode(&*self.codec, cmd))
+            .collect::<Result<Vec<_>>>()?;
+        let first_seq = log.next_seq();
+        self.write_locked(&mut log, first_seq, payloads, commands)?;
+        self.flush_if_full(log)?;
+        Ok(n)
+    }
+
+    // Starts calling `expire` every `interval` in the background.
+    pub fn start_expiry_sweeper(&self, interval: Duration) -> ExpirySweeper {
+        ExpirySweeper::start(self.clone(), interval)
+    }
+
+    // Starts running checkpoints, compactions, expiry and archival in the
+    // background, as `options` says to.
+    pub fn start_maintenance(&self, options: MaintenanceOptions) -> Result<Maintenance> {
+        Maintenance::start(self.clone(), options)
+    }
+
+    // Keeps `Maintenance` and `ExpirySweeper` tasks from starting, through
+    // every clone, until `resume_background_work` is called, and waits for
+    // any that are running to finish. That leaves the database to whoever
+    // is maintaining it by hand; writes, and the flushes and compactions
+    // they set off themselves, carry on as usual.
+    pub fn pause_background_work(&self) {
+        self.background.pause();
+    }
+
+    pub fn resume_background_work(&self) {
+        self.background.resume();
+    }
+
+    // Adds `delta` to the integer value of `k`, treating a key that isn't
+    // there, or whose value isn't an integer, as zero, and saturating rather
+    // than overflowing. The addition happens as the write is committed, so
+    // concurrent increments never lose each other's updates. Like `set`, it
+    // clears any TTL.
+    pub fn incr(&mut self, k: &str, delta: i64) -> Result<()> {
+        let options = self.write_options;
+        self.apply_command_with_options(Command::Incr(k.to_owned(), delta, 0), &options)
+    }
+
+    // Adds `element` to the end of the list at `k`, which `get_list` reads
+    // back. The list is kept as a JSON array of strings, which `get` returns
+    // as is; a key that isn't there, or whose value isn't a list, starts a new
+    // one. Only `element` is logged, so a long list costs no more to append
+    // to than a short one. Appending leaves a TTL alone, so the list expires
+    // as a whole, taking anything appended after its TTL ran out with it.
+    pub fn append(&mut self, k: &str, element: &str) -> Result<()> {
+        let options = self.write_options;
+        let cmd = Command::Append(k.to_owned(), element.to_owned());
+        self.apply_command_with_options(cmd, &options)
+    }
+
+    // Starts a transaction, which reads the database as it is now and commits
+    // its writes all at once, unless something else has written to a key it
+    // wrote in the meantime. See `Transaction`.
+    pub fn transaction(&self) -> Transaction {
+        let log = self.lock_applied();
+        Transaction::new(self.clone(), log.next_seq())
+    }
+
+    // Waits for, and takes, a lock on `k`, which is held until the guard is
+    // dropped. It's for callers that would rather queue up on a hot key than
+    // retry a `Transaction` that keeps conflicting: it only keeps out others
+    // taking the same lock, not plain reads or writes. Locks aren't
+    // reentrant, so taking one twice on the same thread never returns.
+    pub fn lock(&self, k: &str) -> KeyGuard {
+        self.lock_all(&[k])
+    }
+
+    // Like `lock`, but for every key in `keys` at once. Keys are only taken
+    // when all of them are free, so callers can't deadlock each other however
+    // they order them.
+    pub fn lock_all(&self, keys: &[&str]) -> KeyGuard {
+        self.key_locks.lock(keys, None).unwrap()
+    }
+
+    // Like `lock_all`, but fails with `Error::LockTimeout` if the keys aren't
+    // all free within `timeout`.
+    pub fn lock_timeout(&self, keys: &[&str], timeout: Duration) -> Result<KeyGuard> {
+        self.key_locks.lock(keys, Some(timeout))
+    }
+
+    // Logs a transaction's writes as one record, unless a key it wrote has
+    // changed since `start`.
+    pub(crate) fn commit_transaction(
+        &self,
+        start: u64,
+        writes: Vec<(String, Option<String>)>,
+    ) -> Result<()> {
+        if self.read_only || self.following {
+            return Err(Error::ReadOnly);
+        }
+        if writes.is_empty() {
+            return Ok(());
+        }
+        let mut command = Command::Transaction(writes);
+        let _pinned = self.value_log.pin();
+        self.separate(&mut command)?;
+        let Command::Transaction(writes) = &command else {
+            unreachable!()
+        };
+        let mut log = self.lock_log()?;
+        let versions = self.versions.lock()?;
+        if let Some((k, _)) = writes
+            .iter()
+            .find(|(k, _)| versions.changed_since(k, start))
+        {
+            return Err(Error::Conflict { key: k.clone() });
+        }
+        drop(versions);
+        let _reserved = self.check_quota(&command)?;
+        let commands = vec![command];
+        let payloads = vec![codec::encode(&*self.codec, &commands[0])?];
+        let first_seq = log.next_seq();
+        self.write_locked(&mut log, first_seq, payloads, commands)?;
+        self.flush_if_full(log)
+    }
+
+    // Moves the values `command` sets out to the value log, if they're long
+    // enough (see `ValueLog::separate`).
+    fn separate(&self, command: &mut Command) -> Result<()> {
+        match command.unwrap_request_mut() {
+            Command::Set(k, v) | Command::SetExpiring(k, v, _) => self.value_log.separate(k, v),
+            Command::Transaction(writes) => writes.iter_mut().try_for_each(|(k, v)| match v {
+                Some(v) => self.value_log.separate(k, v),
+                None => Ok(()),
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    // Drops the requests in `commands` that have already been made, in an
+    // earlier batch, applied or not, or earlier in this one. The caller holds
+    // the log lock.
+    fn drop_retries(&self, commands: &mut Vec<Command>) -> Result<()> {
+        if !commands
+            .iter()
+            .any(|cmd| matches!(cmd, Command::Request(..)))
+        {
+            return Ok(());
+        }
+        let memtable = self.memtable.home();
+        let mut latest = HashMap::new();
+        commands.retain(|cmd| {
+            let Command::Request(key, _) = cmd else {
+                return true;
+            };
+            let last = match latest.get(&key.client_id) {
+                Some(&last) => Some(last),
+                None => (self.unapplied.last_request(key.client_id))
+                    .or_else(|| memtable.last_request(key.client_id)),
+            };
+            if last.is_some_and(|last| key.request_id <= last) {
+                return false;
+            }
+            latest.insert(key.client_id, key.request_id);
+            true
+        });
+        Ok(())
+    }
+
+    // Works out the value each `Incr` in `commands` leaves its key with,
+    // taking the commands before it in the batch into account, and the
+    // batches before it that haven't been applied yet. The caller holds the
+    // log lock, which keeps every other writer out until the batch has been
+    // applied, or with a sharded log, until what it does has been added to
+    // `unapplied`. Returns what it does, though only if it increments
+    // anything or `track` is set. Fails if a value it needs can't be read.
+    fn resolve_incrs(&self, commands: &mut [Command], track: bool) -> Result<BatchWrites> {
+        let is_incr = |cmd: &Command| matches!(cmd.unwrap_request(), Command::Incr(..));
+        let mut batch = BatchWrites::default();
+        if !track && !commands.iter().any(is_incr) {
+            return Ok(batch);
+        }
+        let current = |batch: &BatchWrites, k: &str| match batch.value(k) {
+            Some(v) => Ok(v.cloned()),
+            None => match self.unapplied.value(k) {
+                Some(v) => Ok(v),
+                None => self.try_get(k),
+            },
+        };
+        for cmd in commands.iter_mut() {
+            if let Command::Request(key, _) = cmd {
+                batch.request(key.client_id, key.request_id);
+            }
+            match cmd.unwrap_request_mut() {
+                Command::Incr(k, delta, value) => {
+                    let current = current(&batch, k)?.and_then(|v| v.parse::<i64>().ok());
+                    *value = current.unwrap_or(0).saturating_add(*delta);
+                    batch.write(k, Some(value.to_string()));
+                }
+                Command::Set(k, v) | Command::SetExpiring(k, v, _) => {
+                    batch.write(k, Some(v.clone()));
+                }
+                Command::Delete(k) => batch.write(k, None),
+                Command::Append(k, element) => {
+                    let mut list = current(&batch, k)?.unwrap_or_default();
+                    memtable::append_element(&mut list, element);
+                    batch.write(k, Some(list));
+                }
+                Command::Transaction(writes) => {
+                    for (k, v) in writes.iter() {
+                        batch.write(k, v.clone());
+                    }
+                }
+                Command::DeleteRange(start, end) => {
+                    batch.delete(KeyRange::Between(start.clone(), end.clone()));
+                }
+                Command::DeletePrefix(prefix) => batch.delete(KeyRange::Prefix(prefix.clone())),
+                _ => {}
+            }
+        }
+        Ok(batch)
+    }
+
+    // Reads what applying `commands` needs from the tables: the lists that
+    // appends add to, the sizes of keys in namespaces with quotas, and with
+    // snapshots open, whatever the commands overwrite. The caller holds the
+    // log lock, which keeps the tables as they are until the batch has been
+    // applied. Reading them before the batch is logged means a read that
+    // fails takes the batch with it, rather than leaving it logged but not
+    // applied.
+    fn read_tables(&self, commands: &[Command]) -> Result<TableReads> {
+        let mut reads = TableReads {
+            tables: error::lock(&self.tables).clone(),
+            values: BTreeMap::new(),
+        };
+        if reads.tables.is_empty() {
+            return Ok(reads);
+        }
+        let snapshots = self.memtable.home().has_snapshots();
+        let mut keys = vec![];
+        for command in commands {
+            let (range, written) = match command.unwrap_request() {
+                Command::DeleteRange(start, end) => {
+                    (Some(KeyRange::Between(start.clone(), end.clone())), vec![])
+                }
+                Command::DeletePrefix(prefix) => (Some(KeyRange::Prefix(prefix.clone())), vec![]),
+                Command::Transaction(writes) => {
+                    (None, writes.iter().map(|(k, _)| k.as_str()).collect())
+                }
+                command => (None, command.key().into_iter().collect()),
+            };
+            if let Command::Append(k, _) = command.unwrap_request() {
+                keys.push(k.clone());
+            }
+            if snapshots {
+                keys.extend(written.into_iter().map(str::to_owned));
+            }
+            match range {
+                Some(range) if snapshots => {
+                    for entry in tables_from(&reads.tables, range.start()) {
+                        match entry? {
+                            (k, v) if range.contains(&k) => reads.values.insert(k, v),
+                            _ => break,
+                        };
+                    }
+                }
+                Some(_) => {}
+                None => keys.extend(self.quotas.keys(command)),
+            }
+        }
+        for k in keys {
+            if reads.values.contains_key(&k) || self.memtable.key(&k).get(&k).is_some() {
+                continue;
+            }
+            let v = try_table_get(&reads.tables, &k)?;
+            reads.values.insert(k, v);
+        }
+        Ok(reads)
+    }
+
+    pub fn delete(&mut self, k: &str) -> Result<()> {
+        let options = self.write_options;
+        self.delete_with_options(k, &options)
+    }
+
+    pub fn delete_with_options(&mut self, k: &str, options: &WriteOptions) -> Result<()> {
+        self.apply_command_with_options(Command::Delete(k.to_owned()), options)
+    }
+
+    // Deletes every key from `start` up to but not including `end`. However
+    // many keys that is, it's logged as a single record, and keys that have
+    // been flushed to tables are only deleted from them by the next flush.
+    pub fn delete_range(&mut self, start: &str, end: &str) -> Result<()> {
+        let options = self.write_options;
+        let cmd = Command::DeleteRange(start.to_owned(), end.to_owned());
+        self.apply_command_with_options(cmd, &options)
+    }
+
+    // Deletes every key starting with `prefix`, like `delete_range`.
+    pub fn delete_prefix(&mut self, prefix: &str) -> Result<()> {
+        let options = self.write_options;
+        self.apply_command_with_options(Command::DeletePrefix(prefix.to_owned()), &options)
+    }
+
+    // Logs `op`, which can be anything serializable, as a `Command::Custom`.
+    pub fn log_custom<T: Serialize>(&mut self, op: &T) -> Result<()> {
+        let options = self.write_options;
+        let op = serde_json::to_value(op).map_err(|e| Error::Encode(e.into()))?;
+        self.apply_command_with_options(Command::Custom(op), &options)
+    }
+
+    // Reading a table or the value log can fail, and since there's no way to
+    // report that here, it panics. `try_get` reports it instead.
+    pub fn get(&self, k: &str) -> Option<String> {
+        self.try_get(k)
+            .unwrap_or_else(|e| panic!("reading the value of {:?}: {}", k, e))
+    }
+
+    pub fn try_get(&self, k: &str) -> Result<Option<String>> {
+        self.read(k, None)
+    }
+
+    // The value `k` had once every record before `seq` was applied, which
+    // takes a `Snapshot` from no later than `seq` being open, since only they
+    // keep old versions around. Otherwise, it fails with `Error::Compacted`.
+    // TTLs are still checked against the current time.
+    pub fn get_at(&self, k: &str, seq: u64) -> Result<Option<String>> {
+        let oldest = self.memtable.home().oldest_snapshot();
+        match oldest {
+            Some(oldest) if oldest <= seq => self.read(k, Some(seq)),
+            _ => Err(Error::Compacted { seq }),
+        }
+    }
+
+    // A view of the database as it is now, which stays the same while writes
+    // carry on. See `Snapshot`.
+    pub fn snapshot(&self) -> Snapshot {
+        let log = self.lock_applied();
+        let seq = log.next_seq();
+        self.memtable.pin(seq);
+        Snapshot::new(self.clone(), seq)
+    }
+
+    // `try_get`, or `get_at` if there's an `at`.
+    fn read(&self, k: &str, at: Option<u64>) -> Result<Option<String>> {
+        match self.read_raw(k, at)? {
+            Some(v) => self.value_log.resolve(k, v).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    // `read`, but leaving a value that went to the value log as the pointer
+    // to it.
+    fn read_raw(&self, k: &str, at: Option<u64>) -> Result<Option<String>> {
+        if at.is_none() {
+            if let Some(v) = self.memtable.get_unlocked(k) {
+                return Ok(v);
+            }
+        }
+        let memtable = self.memtable.key(k);
+        if memtable.is_expired(k, clock::millis(&*self.clock)) {
+            return Ok(None);
+        }
+        if let Some(v) = at.and_then(|seq| memtable.value_at(k, seq)) {
+            return Ok(v.cloned());
+        }
+        if let Some(v) = memtable.get(k) {
+            return Ok(v.map(Cow::into_owned));
+        }
+        let tables = error::lock(&self.tables).clone();
+        drop(memtable);
+        try_table_get(&tables, k)
+    }
+
+    // The value of `k`, to be read a chunk at a time rather than all at once,
+    // or None if `k` isn't there. It can be any value, but only one written
+    // by `put_blob` is read from disk as it goes.
+    pub fn get_blob(&self, k: &str) -> Result<Option<Blob>> {
+        match self.read_raw(k, None)? {
+            Some(v) => self.value_log.blob(k, v).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    // The list `append` has built up at `k`, or None if `k` isn't there or
+    // its value isn't a list.
+    pub fn get_list(&self, k: &str) -> Option<Vec<String>> {
+        serde_json::from_str(&self.get(k)?).ok()
+    }
+
+    // Returns every key starting with `prefix` along with its value, in key
+    // order. Like `get`, panics if a table or the value log can't be read,
+    // where `try_scan` reports it.
+    pub fn scan(&self, prefix: &str) -> Vec<(String, String)> {
+        self.try_scan(prefix)
+            .unwrap_or_else(|e| panic!("scanning {:?}: {}", prefix, e))
+    }
+
+    pub fn try_scan(&self, prefix: &str) -> Result<Vec<(String, String)>> {
+        let mut result = vec![];
+        self.merged(prefix, None, |k, v| result.push((k, v)))?;
+        Ok(result)
+    }
+
+    // Up to `n` live keys from `start` on, in key order, without their values,
+    // for paging through the keys: the next page starts just after the last
+    // one. Unlike `try_scan`, only as much of the memtable and the tables is
+    // read as it takes to find them.
+    pub fn keys_from(&self, start: &str, n: usize) -> Result<Vec<String>> {
+        let mut keys = Vec::with_capacity(n.min(1024));
+        let mut from = Some(start.to_owned());
+        while let Some(start) = from.take().filter(|_| keys.len() < n) {
+            from = self.keys_page(&start, n - keys.len(), &mut keys)?;
+        }
+        Ok(keys)
+    }
+
+    // Adds up to `n` live keys from `start` on to `keys`, reading no more than
+    // `n` of the memtable's entries. If that leaves some of them unread, it
+    // stops after the last one read, and returns where to carry on from.
+    fn keys_page(&self, start: &str, n: usize, keys: &mut Vec<String>) -> Result<Option<String>> {
+        let memtable = self.memtable.read();
+        let entries = memtable.sorted_from(start, n);
+        let deleted = memtable.deleted_ranges().to_vec();
+        let expired = memtable
+            .expired(clock::millis(&*self.clock))
+            .into_iter()
+            .collect::<HashSet<_>>();
+        let tables = error::lock(&self.tables).clone();
+        drop(memtable);
+        // The smallest key after the last entry read.
+        let rest = entries
+            .last()
+            .filter(|_| entries.len() == n)
+            .map(|(k, _)| format!("{}\0", k));
+        let from_tables = tables_from(&tables, start).filter(|entry| !in_ranges(&deleted, entry));
+        let sources: Vec<table::Source> =
+            vec![Box::new(entries.into_iter().map(Ok)), Box::new(from_tables)];
+        let mut added = 0;
+        for entry in Merge::new(sources) {
+            let (k, v) = entry?;
+            if rest.as_ref().is_some_and(|rest| k >= *rest) {
+                break;
+            }
+            if v.is_some() && !expired.contains(&k) {
+                keys.push(k);
+                added += 1;
+                if added == n {
+                    return Ok(None);
+                }
+            }
+        }
+        Ok(rest)
+    }
+
+    // Passes every live key starting with `prefix` to `f` along with its
+    // value, in key order, merging the memtable with the tables, and with the
+    // versions of the keys written since `at` if there is one.
+    pub(crate) fn merged<F>(&self, prefix: &str, at: Option<u64>, mut f: F) -> Result<()>
+    where
+        F: FnMut(String, String),
+    {
+        let mut result = Ok(());
+        self.merged_raw(prefix, at, |k, v| {
+            if result.is_ok() {
+                match self.value_log.resolve(&k, v) {
+                    Ok(v) => f(k, v),
+                    Err(e) => result = Err(e),
+                }
+            }
+        })?;
+        result
+    }
+
+    // The same, but with values that were moved to the value log left as the
+    // pointers to them.
+    fn merged_raw<F>(&self, prefix: &str, at: Option<u64>, mut f: F) -> Result<()>
+    where
+        F: FnMut(String, String),
+    {
+        let memtable = self.memtable.read();
+        let history = at.map_or(vec![], |seq| memtable.history_at(prefix, seq));
+        let entries = memtable.sorted(prefix);
+        let deleted = memtable.deleted_ranges().to_vec();
+        let expired = memtable
+            .expired(clock::millis(&*self.clock))
+            .into_iter()
+            .collect::<HashSet<_>>();
+        let tables = error::lock(&self.tables).clone();
+        drop(memtable);
+        let from_tables = tables_from(&tables, prefix)
+            .take_while(|entry| entry.as_ref().map_or(true, |(k, _)| k.starts_with(prefix)))
+            .filter(|entry| !in_ranges(&deleted, entry));
+        let sources: Vec<table::Source> = vec![
+            Box::new(history.into_iter().map(Ok)),
+            Box::new(entries.into_iter().map(Ok)),
+            Box::new(from_tables),
+        ];
+        for entry in Merge::new(sources) {
+            if let (k, Some(v)) = entry? {
+                if !expired.contains(&k) {
+                    f(k, v);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Writes every live key and its value out to `writer` in `format`, keys
+    // in named keyspaces included, and returns how many there were. Expiry
+    // times aren't carried over. See `Format`.
+    pub fn export<W: io::Write>(&self, mut writer: W, format: Format) -> Result<u64> {
+        export::export(self, &mut writer, format)
+    }
+
+    // Sets every key read from `reader`, which is in `format` as `export`
+    // writes it, and returns how many there were. Keys the database already
+    // has are overwritten, and the rest left alone. Stops at the first entry
+    // that doesn't parse, keeping the ones before it.
+    pub fn import<R: io::Read>(&mut self, mut reader: R, format: Format) -> Result<u64> {
+        export::import(self, &mut reader, format)
+    }
+
+    // Whether the database was opened read-only, either with `open_read_only`
+    // or because another process has it open (see `LockPolicy::ReadOnly`),
+    // or to follow a primary with `open_follower`.
+    pub fn is_read_only(&self) -> bool {
+        self.read_only || self.following
+    }
+
+    // The number of live keys. Once the memtable has been flushed, this has
+    // to read every table, and like `get`, panics if one can't be read, where
+    // `try_len` reports it. `Stats::estimated_live_keys` is much cheaper, if
+    // a rough count will do.
+    pub fn len(&self) -> usize {
+        self.try_len()
+            .unwrap_or_else(|e| panic!("counting keys: {}", e))
+    }
+
+    pub fn try_len(&self) -> Result<usize> {
+        let memtable = self.memtable.read();
+        if error::lock(&self.tables).is_empty() {
+            let expired = memtable.expired(clock::millis(&*self.clock)).len();
+            return Ok(memtable.len().saturating_sub(expired));
+        }
+        drop(memtable);
+        let mut len = 0;
+        self.merged_raw("", None, |_, _| len += 1)?;
+        Ok(len)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Up to `n` of the keys written most since the database was opened, most
+    // written first, with roughly how many times each was written: never
+    // fewer times than it really was, but sometimes a few more. Always empty
+    // unless `Options::hot_keys` is set, and never more than that many keys.
+    // Keys in keyspaces aren't counted, nor are range deletions.
+    pub fn hot_keys(&self, n: usize) -> Vec<(String, u64)> {
+        self.hot_keys.top(n)
+    }
+
+    // Up to `n` live keys picked at random, each as likely to be picked as any
+    // other, in key order. Picking them means going through every key, as
+    // `len` does, though no values are read out of the value log. Keys in
+    // keyspaces aren't included.
+    pub fn sample_keys(&self, n: usize) -> Result<Vec<String>> {
+        let mut rng = Rng::random();
+        // Reservoir sampling: the `seen`th key replaces one already picked
+        // with probability `n / seen`.
+        let mut sample = Vec::with_capacity(n);
+        let mut seen = 0;
+        self.merged_raw("", None, |k, _| {
+            seen += 1;
+            if sample.len() < n {
+                sample.push(k);
+            } else if let Some(slot) = sample.get_mut(rng.below(seen) as usize) {
+                *slot = k;
+            }
+        })?;
+        sample.sort_unstable();
+        Ok(sample)
+    }
+
+    // Roughly how many bytes the keys from `start` up to but not including
+    // `end` take up, counting each key and value as a table lays them out.
+    // No entries are read to find out: the tables are estimated from their
+    // indexes, which can be out by up to 16 entries at each end of the range,
+    // and the memtable is counted, since it's in memory anyway. A key is
+    // counted again for every table it was flushed to, deletions and deleted
+    // ranges aren't subtracted, values in the value log count as the pointers
+    // to them, and keyspaces aren't counted at all. It's meant for deciding
+    // where to split a database or what to compact, not for an exact answer.
+    pub fn approximate_size(&self, start: &str, end: &str) -> u64 {
+        let range = KeyRange::Between(start.to_owned(), end.to_owned());
+        self.approximate(&range).1
+    }
+
+    // Roughly how many keys start with `prefix`, estimated like
+    // `approximate_size`. Deleted keys still in tables are counted.
+    pub fn approximate_key_count(&self, prefix: &str) -> u64 {
+        self.approximate(&KeyRange::Prefix(prefix.to_owned())).0
+    }
+
+    fn approximate(&self, range: &KeyRange) -> (u64, u64) {
+        let (mut keys, mut bytes) = self.memtable.read().approximate(range);
+        for table in error::lock(&self.tables).iter() {
+            let (k, b) = table.approximate(range);
+            keys += k;
+            bytes += b;
+        }
+        (keys, bytes)
+    }
+
+    // A named keyspace, which is a map of its own that shares this database's
+    // log, so writes to it are ordered with every other write. See `Keyspace`.
+    pub fn cf(&self, name: &str) -> Keyspace {
+        Keyspace::new(self.clone(), name)
+    }
+
+    // The names of the keyspaces with at least one key, in order.
+    pub fn cf_names(&self) -> Vec<String> {
+        let memtable = self.memtable.home();
+        memtable
+            .keyspaces()
+            .map(|(name, _)| name.clone())
+            .filter(|name| name != CURSORS)
+            .collect()
+    }
+
+    // Deletes every key in the keyspace `name`.
+    pub fn drop_cf(&mut self, name: &str) -> Result<()> {
+        let options = self.write_options;
+        self.apply_command_with_options(Command::DropKeyspace(name.to_owned()), &options)
+    }
+
+    // Records that the consumer `name` has processed the log up to `seq`, as
+    // a write like any other, so that it survives restarts, flushes and
+    // compaction and goes to followers. A consumer that saves its cursor
+    // after handling records sees them at least once: a crash between the
+    // two has it handle them again.
+    pub fn save_cursor(&mut self, name: &str, seq: u64) -> Result<()> {
+        let options = self.write_options;
+        let cmd = Command::KeyspaceSet(CURSORS.to_owned(), name.to_owned(), seq.to_string());
+        self.apply_command_with_options(cmd, &options)
+    }
+
+    // Where the consumer `name` last saved its cursor, if it ever has.
+    pub fn load_cursor(&self, name: &str) -> Option<u64> {
+        let memtable = self.memtable.home();
+        memtable.keyspace(CURSORS)?.get(name)?.parse().ok()
+    }
+
+    // Forgets the consumer `name`'s cursor.
+    pub fn remove_cursor(&mut self, name: &str) -> Result<()> {
+        let options = self.write_options;
+        let cmd = Command::KeyspaceDelete(CURSORS.to_owned(), name.to_owned());
+        self.apply_command_with_options(cmd, &options)
+    }
+
+    // Forgets the cursor of every consumer more than `max_lag` records
+    // behind, so that `Retention::cursors` stops keeping the log for them,
+    // and retires whatever segments retention no longer keeps. Returns the
+    // names of the consumers, which have to start again from wherever the
+    // log begins by then.
+    pub fn expire_cursors(&mut self, max_lag: u64) -> Result<Vec<String>> {
+        let next_seq = self.next_seq();
+        let mut lagging = {
+            let memtable = self.memtable.home();
+            let cursors = memtable.keyspace(CURSORS).into_iter().flatten();
+            cursors
+                .filter(|(_, seq)| {
+                    let seq = seq.parse::<u64>().unwrap_or(0);
+                    next_seq.saturating_sub(seq + 1) > max_lag
+                })
+                .map(|(name, _)| name.clone())
+                .collect::<Vec<_>>()
+        };
+        lagging.sort_unstable();
+        for name in &lagging {
+            self.remove_cursor(name)?;
+        }
+        self.lock_log()?.trim(self.cursor_floor())?;
+        Ok(lagging)
+    }
+
+    // The oldest record a consumer that's saved its cursor still needs.
+    fn cursor_floor(&self) -> Option<u64> {
+        let memtable = self.memtable.home();
+        let cursors = memtable.keyspace(CURSORS)?.values();
+        cursors
+            .filter_map(|seq| seq.parse::<u64>().ok())
+            .min()
+            .map(|seq| seq + 1)
+    }
+
+    // Waits until every write that has returned so far is durable, syncing
+    // the log even if `Options::durability` says not to, and returns the
+    // sequence number of the last of them. With `Durability::None` this is
+    // the only thing that makes writes survive a power failure.
+    pub fn sync(&self) -> Result<u64> {
+        let mut log = self.log.lock()?;
+        log.barrier()?;
+        self.set_durable(log.next_seq() - 1);
+        self.hooks.synced();
+        Ok(log.next_seq() - 1)
+    }
+
+    // Like `sync`, but first writes the memtable out to a table, so that
+    // reopening doesn't have to replay any of the log before this point.
+    pub fn flush(&self) -> Result<u64> {
+        let _flushing = self.flushing.lock()?;
+        let log = self.lock_log()?;
+        if log.is_closed() {
+            return Err(Error::Closed);
+        }
+        let mut log = self.flush_locked(log, false)?;
+        log.barrier()?;
+        self.set_durable(log.next_seq() - 1);
+        self.hooks.synced();
+        Ok(log.next_seq() - 1)
+    }
+
+    // Stops taking writes, waits for the ones already under way to be
+    // committed, and closes the log: it's synced, the lock on the directory
+    // is released, and the next open knows it was shut down cleanly and can
+    // skip checking the tables over. Writes through other clones fail with
+    // `Error::Closed` from then on, though reads still work. There's no
+    // background work of the database's own to wait for; an `ExpirySweeper`
+    // or `Maintenance` holds a clone, so stop it first.
+    //
+    // Dropping the last clone does the same on a best-effort basis, without
+    // saying whether it worked.
+    pub fn close(self) -> Result<()> {
+        self.closed.store(true, Ordering::SeqCst);
+        // A flush that's under way lets go of the log while it writes its
+        // table, but gets to install it.
+        let _flushing = self.flushing.lock()?;
+        loop {
+            let state = self.state.lock()?;
+            let last = match &*state {
+                DbState::Pending { prev_done, .. } => prev_done.clone(),
+                DbState::PendingLeader { batch_notif, .. } => batch_notif.clone(),
+            };
+            // Batches are committed in order, so once the last one is done and
+            // nobody is queued up behind it, nothing is under way. Taking the
+            // log lock waits out any flush its leader is still doing.
+            let idle = matches!(*state, DbState::Pending { .. });
+            if idle && last.0.lock()?.is_some() {
+                let mut log = self.log.lock()?;
+                drop(state);
+                let checksum = self.memtable.read().checksum();
+                log.close(Some(checksum))?;
+                self.set_durable(log.next_seq() - 1);
+                self.hooks.synced();
+                return Ok(());
+            }
+            drop(state);
+            // If it failed, its writers have heard, and closing will too.
+            let _ = Self::wait_for(last);
+        }
+    }
+
+    // Rewrites the current contents of the database into a fresh segment so
+    // that every segment before it can be recycled. Once the memtable has been
+    // flushed, the memtable and every table are merged into a single table
+    // instead. Either way, `Options::compaction_filter` gets to drop or change
+    // each key on the way.
+    pub fn compact(&self) -> Result<()> {
+        let _flushing = self.flushing.lock()?;
+        let mut log = self.lock_log()?;
+        if !self.tables.lock()?.is_empty() {
+            return self.flush_locked(log, true).map(drop);
+        }
+        self.compact_locked(&mut log)
+    }
+
+    // Compacts a log that has no tables, which the caller has locked.
+    fn compact_locked(&self, log: &mut Log) -> Result<()> {
+        let memtable = self.memtable.read();
+        let mut snapshot = vec![];
+        // What the filter did, for the memtable to catch up with once the
+        // log has.
+        let mut filtered = vec![];
+        for (k, v) in memtable.iter() {
+            let Some(kept) = self.filter_entry(&k, v.to_string())? else {
+                filtered.push(Command::Delete(k.into_owned()));
+                continue;
+            };
+            let changed = kept != v;
+            let command = match memtable.key(&k).expiry(&k) {
+                Some(at) => Command::SetExpiring(k.into_owned(), kept, at),
+                None => Command::Set(k.into_owned(), kept),
+            };
+            if changed {
+                filtered.push(command.clone());
+            }
+            snapshot.push(command);
+        }
+        snapshot.extend(self.unflushed_snapshot(&memtable));
+        drop(memtable);
+        self.compact_log(log, snapshot)?;
+        if !filtered.is_empty() {
+            let mut memtable = self.memtable.write()?;
+            for command in filtered {
+                Self::apply_command_to_shards(&mut memtable, &|_| Ok(None), command)?;
+            }
+            self.quotas.invalidate();
+        }
+        Ok(())
+    }
+
+    // What compacting keeps of `k`'s value `v`, going by
+    // `Options::compaction_filter`: `v` itself, what the filter changed it
+    // to, or None if it's to be deleted.
+    fn filter_entry(&self, k: &str, v: String) -> Result<Option<String>> {
+        let Some(filter) = &self.compaction_filter else {
+            return Ok(Some(v));
+        };
+        let resolved = self.value_log.resolve(k, v.clone())?;
+        Ok(match filter.filter(k, &resolved) {
+            FilterDecision::Keep => Some(v),
+            FilterDecision::Remove => None,
+            FilterDecision::Change(changed) => Some(changed),
+        })
+    }
+
+    // Copies the values still in use out of every value log file in which
+    // values since overwritten or deleted take up at least `min_dead` of the
+    // space, pointing their keys at the copies, and then removes the file.
+    // Returns how many bytes that frees up. Writers are kept out while it
+    // runs, and it does nothing while snapshots are open, since they may
+    // still need the old values. Blobs written with `put_blob` that no key
+    // points to any more are removed too.
+    pub fn gc_value_log(&self, min_dead: f64) -> Result<u64> {
+        if self.read_only || self.following {
+            return Err(Error::ReadOnly);
+        }
+        let _collecting = self.value_log.collecting();
+        let mut log = self.lock_log()?;
+        if self.memtable.read().has_snapshots() {
+            return Ok(0);
+        }
+        let mut freed = 0;
+        for number in self.value_log.sealed()? {
+            let (stored, bytes) = self.value_log.read_file(number)?;
+            let mut live = vec![];
+            for s in stored {
+                if self.read_raw(&s.key, None)? == Some(s.pointer.to_string()) {
+                    live.push(s);
+                }
+            }
+            let live_bytes = live.iter().map(|s| s.pointer.len).sum::<u64>();
+            if ((bytes - live_bytes) as f64) < min_dead * bytes as f64 {
+                continue;
+            }
+            let mut commands = vec![];
+            for s in live {
+                self.throttle(s.value.len() as u64);
+                let v = self.value_log.put(&s.key, &s.value)?.to_string();
+                let expiry = self.memtable.key(&s.key).expiry(&s.key);
+                commands.push(match expiry {
+                    Some(at) => Command::SetExpiring(s.key, v, at),
+                    None => Command::Set(s.key, v),
+                });
+            }
+            if !commands.is_empty() {
+                let payloads = commands
+                    .iter()
+                    .map(|cmd| codec::encode(&*self.codec, cmd))
+                    .collect::<Result<Vec<_>>>()?;
+                let first_seq = log.next_seq();
+                self.write_locked(&mut log, first_seq, payloads, commands)?;
+                log.sync()?;
+            }
+            self.value_log.remove(number)?;
+            self.counters.record_rewrite(live_bytes);
+            freed += bytes - live_bytes;
+        }
+        // Blobs are never rewritten, just removed once nothing points to them.
+        for (number, bytes) in self.value_log.blobs()? {
+            let live = match self.value_log.blob_key(number)? {
+                Some(k) => {
+                    let v = self.read_raw(&k, None)?;
+                    v.and_then(|v| BlobRef::decode(&v)).map(|b| b.file) == Some(number)
+                }
+                None => false,
+            };
+            if !live {
+                self.value_log.remove_blob(number)?;
+                freed += bytes;
+            }
+        }
+        self.flush_if_full(log)?;
+        Ok(freed)
+    }
+
+    // Every key in every named keyspace, every expiry, and the last request
+    // from each client, as commands to recreate them. These are all that a
+    // flush doesn't write to a table.
+    fn unflushed_snapshot<G>(&self, memtable: &Shards<G>) -> Vec<Command>
+    where
+        G: Deref<Target = Memtable>,
+    {
+        let mut snapshot = vec![];
+        for key in memtable.home().requests() {
+            snapshot.push(Command::LastRequest(key));
+        }
+        for (k, at) in memtable.expiries() {
+            snapshot.push(Command::Expire(k.clone(), at));
+        }
+        for (name, keyspace) in memtable.home().keyspaces() {
+            for (k, v) in keyspace {
+                snapshot.push(Command::KeyspaceSet(name.clone(), k.clone(), v.clone()));
+            }
+        }
+        snapshot
+    }
+
+    // Retires every segment, starting the log afresh with `commands`. It has
+    // the log locked throughout, so it waits for the rate limiter a record at
+    // a time as it encodes them, the way a flush does for each entry, rather
+    // than running up the whole snapshot's worth of debt at once.
+    fn compact_log(&self, log: &mut Log, commands: Vec<Command>) -> Result<()> {
+        let mut bytes = 0;
+        let mut snapshot = Vec::with_capacity(commands.len());
+        for cmd in &commands {
+            let payload = codec::encode(&*self.codec, cmd)?;
+            let len = segment::HEADER_LEN + payload.len();
+            self.throttle(len as u64);
+            bytes += len;
+            snapshot.push(payload);
+        }
+        let first_seq = log.next_seq();
+        log.compact(&snapshot, self.cursor_floor())?;
+        let mut ledger = error::lock(&self.ledger);
+        ledger.reset();
+        ledger.record_batch(log.placed(), &snapshot, &commands);
+        drop(ledger);
+        // The memtable already has what the snapshot says, and compacting
+        // synced it.
+        let last_seq = log.next_seq() - 1;
+        self.watermarks.set_applied(last_seq);
+        self.set_durable(last_seq);
+        self.hooks.synced();
+        self.counters.record_rewrite(bytes as u64);
+        // Followers need to see these too, or their sequence numbers would
+        // fall out of step with ours.
+        self.feed.lock()?.publish(Batch {
+            first_seq,
+            payloads: snapshot,
+        });
+        Ok(())
+    }
+
+    // Waits for the `RateLimiter` to let a background write of `bytes` through,
+    // counting it against this database.
+    fn throttle(&self, bytes: u64) {
+        let waited = self.rate_limiter.request(bytes);
+        self.counters.record_background(bytes, waited);
+    }
+
+    fn memtable_full(&self) -> Result<bool> {
+        Ok(match self.memtable_bytes {
+            Some(max) => self.memtable.read().bytes() > max,
+            None => false,
+        })
+    }
+
+    // Flushes the memtable if it's full, or compacts the log if
+    // `Options::compact_dead_ratio` says enough of it is dead, once the
+    // caller is done with the log it hands over. If a flush is already under
+    // way, that's left to it.
+    fn flush_if_full(&self, mut log: MutexGuard<'_, Log>) -> Result<()> {
+        let full = self.memtable_full()?;
+        if !full && !self.log_dead() {
+            return Ok(());
+        }
+        let _flushing = match self.flushing.try_lock() {
+            Ok(flushing) => flushing,
+            Err(std::sync::TryLockError::WouldBlock) => return Ok(()),
+            Err(std::sync::TryLockError::Poisoned(_)) => return Err(Error::Poisoned),
+        };
+        match full || !self.tables.lock()?.is_empty() {
+            true => self.flush_locked(log, false).map(drop),
+            false => self.compact_locked(&mut log),
+        }
+    }
+
+    // Whether `Options::compact_dead_ratio` says enough of the log is dead
+    // to compact it.
+    fn log_dead(&self) -> bool {
+        let Some(ratio) = self.compact_dead_ratio else {
+            return false;
+        };
+        let (dead, bytes) = error::lock(&self.ledger).dead();
+        dead >= self.segment_size && dead as f64 > ratio * bytes as f64
+    }
+
+    // Writes the memtable out as a new table, and then truncates the log,
+    // since everything in it is in the tables now, apart from the named
+    // keyspaces and expiries, which are written back into it. With `full`, the
+    // existing tables are merged into the new one, which replaces them.
+    //
+    // The log is only held while the memtable is taken a copy of, and again
+    // while the table is installed, so that writers, whose batches go on
+    // being applied to the memtable, don't wait on the table being written
+    // and rate limited. What they write in the meantime stays in the memtable,
+    // and is written back into the log along with the keyspaces. The caller
+    // holds `flushing`, and hands over the log, which it gets back.
+    fn flush_locked<'a>(
+        &'a self,
+        log: MutexGuard<'a, Log>,
+        full: bool,
+    ) -> Result<MutexGuard<'a, Log>> {
+        let _span = span!("flush", full);
+        let seq = log.next_seq();
+        let mut memtable = self.memtable.write()?;
+        memtable.each_mut().for_each(Memtable::keep_tombstones);
+        let flushed = memtable.sorted("");
+        let deleted = memtable.deleted_ranges().to_vec();
+        drop(memtable);
+        let old = self.tables.lock()?.clone();
+        drop(log);
+        let number = old.last().map_or(1, |t| t.number() + 1);
+        let entries = flushed.iter().cloned().map(Ok);
+        let mut sources: Vec<table::Source> = vec![Box::new(entries)];
+        if full {
+            sources.push(Box::new(
+                tables_from(&old, "").filter(|entry| !in_ranges(&deleted, entry)),
+            ));
+        } else {
+            // The deleted ranges have to be spelled out key by key for
+            // whatever the older tables have in them.
+            for range in &deleted {
+                let keys = tables_from(&old, range.start())
+                    .take_while(|entry| entry.as_ref().map_or(true, |(k, _)| range.contains(k)))
+                    .map(|entry| entry.map(|(k, _)| (k, None)));
+                sources.push(Box::new(keys));
+            }
+        }
+        // A full table is a compaction, which the filter gets to see, and
+        // which leaves out what it deletes. Either way, each entry waits for
+        // the rate limiter before it's written.
+        let entries = Merge::new(sources).map(|entry| {
+            let entry = match entry? {
+                (k, Some(v)) if full => {
+                    let v = self.filter_entry(&k, v)?;
+                    (k, v)
+                }
+                entry => entry,
+            };
+            let (k, v) = &entry;
+            self.throttle((k.len() + v.as_ref().map_or(0, String::len)) as u64);
+            Ok(entry)
+        });
+        let table = Table::write(
+            &self.dir,
+            number,
+            entries,
+            seq,
+            full,
+            self.bloom_bits_per_key,
+            &self.block_cache,
+        )?;
+        self.counters.record_rewrite(table.bytes());
+        let mut log = self.lock_log()?;
+        let mut memtable = self.memtable.write()?;
+        let mut tables = self.tables.lock()?;
+        if full {
+            tables.clear();
+        }
+        tables.push(Arc::new(table));
+        for shard in memtable.each_mut() {
+            shard.forget_flushed(&flushed, deleted.len());
+        }
+        drop(tables);
+        if full && self.compaction_filter.is_some() {
+            self.quotas.invalidate();
+        }
+        let mut snapshot = Self::unflushed_writes(&memtable);
+        snapshot.extend(self.unflushed_snapshot(&memtable));
+        drop(memtable);
+        self.compact_log(&mut log, snapshot)?;
+        if full {
+            table::remove_before(&self.dir, number)?;
+        }
+        Ok(log)
+    }
+
+    // What's been written to the memtable's own keys since the flush that's
+    // installing its table started, as commands to write it again: ranges
+    // deleted since, then each key written since.
+    fn unflushed_writes(memtable: &Shards<Writing<'_>>) -> Vec<Command> {
+        let ranges = memtable.deleted_ranges().iter().map(|range| match range {
+            KeyRange::Between(start, end) => Command::DeleteRange(start.clone(), end.clone()),
+            KeyRange::Prefix(prefix) => Command::DeletePrefix(prefix.clone()),
+        });
+        let writes = memtable.sorted("").into_iter().map(|(k, v)| match v {
+            Some(v) => Command::Set(k, v),
+            None => Command::Delete(k),
+        });
+        ranges.chain(writes).collect()
+    }
+
+    // The sequence number the next record written will get.
+    pub fn next_seq(&self) -> u64 {
+        error::lock(&self.log).next_seq()
+    }
+
+    // The sequence number of the last record known to be durable, which a
+    // crash can't take away, along with everything before it. It's been
+    // synced as far as `Options::durability` goes, or by `sync`. After a
+    // crash, nothing replayed from the log counts until it's synced again.
+    pub fn last_durable_seq(&self) -> u64 {
+        self.watermarks.durable()
+    }
+
+    // The sequence number of the last record applied, whose writes reads
+    // see. Writes that don't ask to be synced, and batches of a sharded log,
+    // are applied before they're durable, so this can be ahead of
+    // `last_durable_seq`; with `Options::pipeline_commits` a batch is
+    // durable before it's applied, so it can also be behind.
+    pub fn last_applied_seq(&self) -> u64 {
+        self.watermarks.applied()
+    }
+
+    // Waits until record `seq` is durable. If it's been written but isn't
+    // being synced, because the write didn't ask to be, this syncs the log
+    // as `sync` does. A `seq` that hasn't been written yet is waited for.
+    pub fn wait_for_durable(&self, seq: u64) -> Result<()> {
+        if !self.watermarks.wait_for(seq)? {
+            self.sync()?;
+        }
+        Ok(())
+    }
+
+    // Calls `callback` with the first sequence number and commands of every
+    // batch committed from now on, as they were logged, once it's durable:
+    // a batch that nobody asked to sync waits for the next one that is, or
+    // for `sync`, `flush` or `close`. `Options::commit_callbacks` says
+    // whether that's before or after its writers are told. For keeping a
+    // cache or an outbox in step with the database without tailing the log.
+    //
+    // Callbacks run on the writer that led the batch, one batch at a time,
+    // often with the log locked, so they should be quick and mustn't write to
+    // the database. Every clone shares them, and there's no removing one.
+    pub fn on_commit<F>(&self, callback: F)
+    where
+        F: Fn(u64, &[Command]) + Send + Sync + 'static,
+    {
+        self.hooks.add(Arc::new(callback));
+    }
+
+    // Reads records from `from` on as they're framed in the log, up to about
+    // `max_bytes` of them, for a consumer to ship elsewhere and pick up from
+    // `next` on the next call without reading anything twice. `from` is
+    // `LogOffset::START` or an offset a chunk, `LogReader` or `LogTailer`
+    // handed out. Only committed records that have been synced are read,
+    // from one segment at a time, since each has its own codec. An offset in
+    // a segment that compaction has since retired fails, and a sharded log
+    // can't be read this way.
+    pub fn read_log_from(&self, from: LogOffset, max_bytes: usize) -> Result<LogChunk> {
+        if Log::is_sharded(&self.dir) {
+            return Err(Error::InvalidConfig(format!(
+                "{} is a sharded log, so its records have no one offset",
+                self.dir.display()
+            )));
+        }
+        let last_seq = self.feed.lock()?.durable_seq();
+        reader::read_chunk(&self.dir, from, max_bytes, last_seq)
+    }
+
+    // Writes records received from a primary, which must carry on exactly
+    // where this database's log leaves off, unless the log has never had a
+    // record, in which case they can start anywhere.
+    fn apply_replicated(&self, records: Vec<(u64, Vec<u8>)>) -> Result<()> {
+        let mut log = self.lock_log()?;
+        let first_seq = records[0].0;
+        if first_seq != log.next_seq() {
+            if first_seq < log.next_seq() || log.next_seq() > 1 {
+                return Err(Error::Replication(format!(
+                    "expected seq {} from the primary, got {}",
+                    log.next_seq(),
+                    first_seq
+                )));
+            }
+            log.skip_to(first_seq)?;
+        }
+        let mut commands = Vec::with_capacity(records.len());
+        for (i, (seq, payload)) in records.iter().enumerate() {
+            if *seq != first_seq + i as u64 {
+                return Err(Error::Replication(format!(
+                    "gap in replicated records at seq {}",
+                    seq
+                )));
+            }
+            let command = self.codec.decode(payload).map_err(|e| {
+                Error::Replication(format!("undecodable record at seq {}: {}", seq, e))
+            })?;
+            commands.push(command);
+        }
+        let payloads = records.into_iter().map(|(_, p)| p).collect::<Vec<_>>();
+        self.write_locked(&mut log, first_seq, payloads, commands)?;
+        self.flush_if_full(log)
+    }
+
+    // Commits `commands` as a single batch, bypassing group commit. Used where
+    // the caller is already applying writes one batch at a time, such as a
+    // Raft state machine.
+    #[cfg_attr(not(feature = "raft"), allow(dead_code))]
+    pub(crate) fn write_batch(&self, mut commands: Vec<Command>) -> Result<()> {
+        if commands.is_empty() {
+            return Ok(());
+        }
+        let _pinned = self.value_log.pin();
+        for command in &mut commands {
+            self.separate(command)?;
+        }
+        let mut log = self.lock_log()?;
+        self.drop_retries(&mut commands)?;
+        self.resolve_incrs(&mut commands, false)?;
+        let payloads = commands
+            .iter()
+            .map(|cmd| codec::encode(&*self.codec, cmd))
+            .collect::<Result<Vec<_>>>()?;
+        let first_seq = log.next_seq();
+        self.write_locked(&mut log, first_seq, payloads, commands)?;
+        self.flush_if_full(log)
+    }
+
+    fn write_locked(
+        &self,
+        log: &mut Log,
+        first_seq: u64,
+        payloads: Vec<Vec<u8>>,
+        mut commands: Vec<Command>,
+    ) -> Result<()> {
+        let _span = span!("commit", commands = commands.len());
+        let reads = self.read_tables(&commands)?;
+        let start = self.now();
+        let committed = self.hooks.capture(first_seq, &commands, true);
+        let bytes = {
+            let _span = span!("write", first_seq);
+            log.append_batch(&payloads)?
+        };
+        error::lock(&self.ledger).record_batch(log.placed(), &payloads, &commands);
+        let synced = self.now();
+        let pending = {
+            let _span = span!("sync");
+            self.sync_staging(log, first_seq, &mut commands)?
+        };
+        let sync = self.now() - synced;
+        self.counters
+            .record_batch(payloads.len(), bytes, self.now() - start, Some(sync));
+        self.committed(first_seq, payloads.len(), bytes);
+        self.check_sync(sync, payloads.len(), bytes);
+        match pending {
+            Some(pending) => self.publish(pending, &reads)?,
+            None => self.apply_batch(first_seq, commands, &reads)?,
+        }
+        self.feed.lock()?.publish(Batch {
+            first_seq,
+            payloads,
+        });
+        self.hooks.deliver(committed);
+        Ok(())
+    }
+
+    pub fn stats(&self) -> Result<Stats> {
+        self.recount_namespaces()?;
+        let log = self.log.lock()?;
+        let memtable = self.memtable.read();
+        let tables = self.tables.lock()?;
+        let metrics = self.counters.snapshot();
+        let (background_bytes, background_throttled) = self.counters.background();
+        let ledger = error::lock(&self.ledger);
+        Ok(Stats {
+            disk_bytes: log.disk_bytes()? + tables.iter().map(|t| t.bytes()).sum::<u64>(),
+            log_records: log.records(),
+            tables: tables.len(),
+            memtable_bytes: memtable.memory(),
+            estimated_live_keys: memtable.len() as u64
+                + tables.iter().map(|t| t.count()).sum::<u64>(),
+            write_amplification: metrics.write_amplification(),
+            replay_time: metrics.replay_time,
+            dead_log_bytes: ledger.dead().0,
+            segment_garbage: ledger.segments(),
+            namespaces: self.quotas.usage(),
+            background_bytes_per_sec: self.rate_limiter.bytes_per_sec(),
+            background_bytes,
+            background_throttled,
+        })
+    }
+
+    // Fails with `Error::QuotaExceeded` if `command` would take a namespace
+    // past its quota, counting what writes let through before it that
+    // haven't been applied yet might add. What it might add itself counts
+    // against the quota until the reservation is dropped, which the caller
+    // does once the write has been applied or has failed.
+    fn check_quota(&self, command: &Command) -> Result<Reservation> {
+        self.recount_namespaces()?;
+        self.quotas.reserve(command, |k| {
+            let memtable = self.memtable.key(k);
+            let tables = error::lock(&self.tables).clone();
+            stored_size(&memtable, k, |k| try_table_get(&tables, k))
+        })
+    }
+
+    // Counts from scratch whatever namespaces need it, with the memtable
+    // locked so that nothing is applied to it in the meantime.
+    fn recount_namespaces(&self) -> Result<()> {
+        let stale = self.quotas.stale();
+        if stale.is_empty() {
+            return Ok(());
+        }
+        let memtable = self.memtable.write()?;
+        let tables = error::lock(&self.tables).clone();
+        let deleted = memtable.deleted_ranges().to_vec();
+        for (i, prefix) in stale {
+            let from_tables = tables_from(&tables, &prefix)
+                .take_while(|entry| entry.as_ref().map_or(true, |(k, _)| k.starts_with(&prefix)))
+                .filter(|entry| !in_ranges(&deleted, entry));
+            let sources: Vec<table::Source> = vec![
+                Box::new(memtable.sorted(&prefix).into_iter().map(Ok)),
+                Box::new(from_tables),
+            ];
+            let mut usage = (0, 0);
+            for entry in Merge::new(sources) {
+                if let (k, Some(v)) = entry? {
+                    usage.0 += (k.len() + v.len()) as u64;
+                    usage.1 += 1;
+                }
+            }
+            self.quotas.recounted(i, usage);
+        }
+        Ok(())
+    }
+
+    // `stored_size` of each of `keys`, with the shards they're in locked.
+    fn stored_sizes<G>(
+        memtable: &Shards<G>,
+        reads: &TableReads,
+        keys: &[String],
+    ) -> Result<Vec<Option<usize>>>
+    where
+        G: std::ops::Deref<Target = Memtable>,
+    {
+        keys.iter()
+            .map(|k| stored_size(memtable.key(k), k, |k| reads.get(k)))
+            .collect()
+    }
+
+    // The time as far as group commit is concerned, which is virtual while
+    // it's being simulated.
+    fn now(&self) -> Instant {
+        #[cfg(test)]
+        if let Some(now) = sim::now() {
+            return now;
+        }
+        self.clock.now()
+    }
+
+    fn check_sync(&self, duration: Duration, batch_size: usize, bytes: usize) {
+        if self.slow_sync.is_none_or(|max| duration <= max) {
+            return;
+        }
+        warn!(?duration, batch_size, bytes, "slow sync");
+        if let Some(listener) = &self.listener {
+            listener.on_slow_sync(&SlowSync {
+                duration,
+                batch_size,
+                bytes,
+            });
+        }
+    }
+
+    fn check_stall(&self, waited: Duration, queue_depth: usize) {
+        self.counters.record_wait(waited);
+        if self.write_stall.is_none_or(|max| waited <= max) {
+            return;
+        }
+        warn!(?waited, queue_depth, "write stalled");
+        if let Some(listener) = &self.listener {
+            listener.on_write_stall(&WriteStall {
+                waited,
+                queue_depth,
+            });
+        }
+    }
+
+    fn committed(&self, first_seq: u64, records: usize, bytes: usize) {
+        if let (Some(listener), false) = (&self.listener, records == 0) {
+            listener.on_batch_committed(first_seq..first_seq + records as u64, bytes);
+        }
+    }
+
+    pub fn metrics(&self) -> Metrics {
+        let mut metrics = self.counters.snapshot();
+        (metrics.block_cache_hits, metrics.block_cache_misses) = self.block_cache.stats();
+        metrics
+    }
+
+    // What replaying the log turned up when the database was opened: torn
+    // records at the ends of segments, and anything skipped with
+    // `RecoveryMode::SkipCorrupt`.
+    pub fn recovery_report(&self) -> &RecoveryReport {
+        &self.recovery
+    }
+}
+
+// How much `k` and its value take up as stored, expired or not, going by the
+// shard of the memtable it's in and `base` for what the tables have, or None
+// if it isn't there.
+fn stored_size<F>(memtable: &Memtable, k: &str, base: F) -> Result<Option<usize>>
+where
+    F: FnOnce(&str) -> Result<Option<String>>,
+{
+    let v = match memtable.get(k) {
+        Some(v) => v.map(|v| v.len()),
+        None => base(k)?.map(|v| v.len()),
+    };
+    Ok(v.map(|v| k.len() + v))
+}
+
+// The value of `k` in the newest of `tables` that has it.
+fn try_table_get(tables: &[Arc<Table>], k: &str) -> Result<Option<String>> {
+    for table in tables.iter().rev() {
+        if let Some(v) = table.get(k)? {
+            return Ok(v);
+        }
+    }
+    Ok(None)
+}
+
+// What applying a command asks of the tables for a key the memtable doesn't
+// have (see `Db::apply_command_to_memtable`).
+type Base<'a> = &'a dyn Fn(&str) -> Result<Option<String>>;
+
+// What a batch needs from the tables to be applied, read before it's logged
+// (see `Db::read_tables`), along with the tables it was read from. Anything
+// else is read from them as it's asked for.
+#[derive(Debug, Default)]
+struct TableReads {
+    tables: Vec<Arc<Table>>,
+    values: BTreeMap<String, Option<String>>,
+}
+
+impl TableReads {
+    fn get(&self, k: &str) -> Result<Option<String>> {
+        match self.values.get(k) {
+            Some(v) => Ok(v.clone()),
+            None => try_table_get(&self.tables, k),
+        }
+    }
+
+    // The keys read that are in `range`.
+    fn keys<'a>(&'a self, range: &'a KeyRange) -> impl Iterator<Item = String> + 'a {
+        self.values
+            .range(range.start().to_owned()..)
+            .map(|(k, _)| k)
+            .take_while(|k| range.contains(k))
+            .cloned()
+    }
+}
+
+// Every entry in `tables` from `start` on, with newer tables winning.
+fn tables_from<'a>(tables: &'a [Arc<Table>], start: &str) -> Merge<'a> {
+    let sources = tables
+        .iter()
+        .rev()
+        .map(|table| Box::new(table.iter_from(start)) as table::Source)
+        .collect();
+    Merge::new(sources)
+}
+
+// Whether `entry` is in one of the `ranges`. Errors aren't, so that they get
+// passed along.
+fn in_ranges(ranges: &[KeyRange], entry: &Result<table::Entry>) -> bool {
+    matches!(entry, Ok((k, _)) if ranges.iter().any(|r| r.contains(k)))
+}
+
+#[test]
+fn test_basic() -> Result<()> {
+    let dir = tempdir()?;
+    let file = dir.path().to_path_buf().join("logfile");
+
+    let mut db = Db::new(&file)?;
+    db.set("foo", "bar")?;
+    db.set("baz", "goo")?;
+    assert_eq!(db.get("foo"), Some("bar".into()));
+    db.delete("foo")?;
+    assert_eq!(db.get("foo"), None);
+
+    Ok(())
+}
+
+#[test]
+fn test_recover() -> Result<()> {
+    let dir = tempdir()?;
+    let file = dir.path().to_path_buf().join("logfile");
+
+    let mut db = Db::new(&file)?;
+    db.set("foo", "bar")?;
+    db.set("baz", "goo")?;
+    assert_eq!(db.get("foo"), Some("bar".into()));
+    db.delete("foo")?;
+    assert_eq!(db.get("foo"), None);
+
+    drop(db);
+    let db = Db::new(&file)?;
+    assert_eq!(db.get("baz"), Some("goo".into()));
+
+    Ok(())
+}
+
+#[test]
+fn test_scan() -> Result<()> {
+    let dir = tempdir()?;
+    let file = dir.path().to_path_buf().join("logfile");
+
+    let mut db = Db::new(&file)?;
+    assert!(db.is_empty());
+    db.set("b/2", "two")?;
+    db.set("a/1", "one")?;
+    db.set("b/1", "one")?;
+    db.set("c", "three")?;
+    assert_eq!(db.len(), 4);
+    assert_eq!(
+        db.scan("b/"),
+        vec![("b/1".into(), "one".into()), ("b/2".into(), "two".into())]
+    );
+    assert_eq!(db.scan("").len(), 4);
+    assert_eq!(db.scan("d"), vec![]);
+
+    Ok(())
+}
+
+#[test]
+fn test_ordered_memtable() -> Result<()> {
+    for kind in [MemtableKind::Ordered, MemtableKind::Concurrent] {
+        let dir = tempdir()?;
+        let options = Options {
+            memtable: kind,
+            memtable_bytes: Some(512),
+            ..Options::default()
+        };
+        let mut db = Db::with_options(dir.path(), options.clone())?;
+        for i in (0..40).rev() {
+            db.set(&format!("k/{:02}", i), &i.to_string())?;
+        }
+        db.delete_prefix("k/1")?;
+        assert!(db.stats()?.tables > 0);
+        let scanned = db.scan("k/");
+        assert_eq!(scanned.len(), 30);
+        assert!(scanned.windows(2).all(|w| w[0].0 < w[1].0));
+        drop(db);
+        let db = Db::with_options(dir.path(), options)?;
+        assert_eq!(db.scan("k/"), scanned);
+    }
+    Ok(())
+}
+
+#[test]
+fn test_memtable_shards() -> Result<()> {
+    let dir = tempdir()?;
+    let options = |shards| Options {
+        memtable_shards: shards,
+        memtable_bytes: Some(2048),
+        ..Options::default()
+    };
+    // The same writes, with and without shards, should read the same.
+    let mut dbs = vec![];
+    for shards in [1, 8] {
+        let path = dir.path().join(shards.to_string());
+        let mut db = Db::with_options(&path, options(shards))?;
+        for i in 0..60 {
+            db.set(&format!("k/{:02}", i), &i.to_string())?;
+        }
+        let snapshot = db.snapshot();
+        db.delete_prefix("k/1")?;
+        db.delete("k/20")?;
+        db.incr("n", 3)?;
+        db.append("list", "a")?;
+        db.set_with_ttl("short", "v", Duration::from_secs(3600))?;
+        db.cf("ks").set("a", "1")?;
+        let mut tx = db.transaction();
+        tx.set("k/15", "back");
+        tx.set("k/50", "changed");
+        tx.commit()?;
+        assert_eq!(snapshot.get("k/15"), Some("15".into()));
+        assert_eq!(snapshot.scan("k/1").len(), 10);
+        drop(snapshot);
+        dbs.push((path, db.scan(""), db.len()));
+        db.close()?;
+    }
+    assert_eq!(dbs[0].1, dbs[1].1);
+    assert_eq!(dbs[0].2, dbs[1].2);
+
+    // Replay comes up with what the shards held between them.
+    let db = Db::with_options(&dbs[1].0, options(8))?;
+    assert!(db.recovery_report().trusted);
+    assert_eq!(db.scan(""), dbs[1].1);
+    assert_eq!(db.cf("ks").get("a"), Some("1".into()));
+    Ok(())
+}
+
+#[test]
+fn test_replay_borrowed() -> Result<()> {
+    let dir = tempdir()?;
+    let file = dir.path().to_path_buf().join("logfile");
+
+    let mut db = Db::new(&file)?;
+    db.set("plain", "value")?;
+    db.set("quo\"ted", "new\nline")?;
+    db.set("plain", "overwritten")?;
+    db.set("gone", "soon")?;
+    db.delete("gone")?;
+
+    drop(db);
+    let db = Db::new(&file)?;
+    assert_eq!(db.get("plain"), Some("overwritten".into()));
+    assert_eq!(db.get("quo\"ted"), Some("new\nline".into()));
+    assert_eq!(db.get("gone"), None);
+
+    Ok(())
+}
+
+#[test]
+fn test_metrics() -> Result<()> {
+    let dir = tempdir()?;
+    let file = dir.path().to_path_buf().join("logfile");
+
+    let mut db = Db::new(&file)?;
+    let metrics = db.metrics();
+    assert_eq!(metrics.batches, 0);
+    assert_eq!(metrics.sync_latency.count(), 0);
+    db.set("foo", "bar")?;
+    db.delete("foo")?;
+    let metrics = db.metrics();
+    assert_eq!(metrics.batches, 2);
+    assert_eq!(metrics.commands, 2);
+    assert_eq!(metrics.largest_batch, 1);
+    assert_eq!(metrics.mean_batch_size(), 1.0);
+    assert!(metrics.bytes > 2 * segment::HEADER_LEN as u64);
+    assert_eq!(metrics.batch_sizes.counts[0], 2);
+    assert_eq!(metrics.sync_latency.count(), 2);
+    assert!(metrics.sync_latency.sum as u128 <= metrics.commit_time.as_micros());
+    assert_eq!(metrics.write_wait.count(), 2);
+
+    drop(db);
+    let db = Db::new(&file)?;
+    assert!(db.metrics().replay_time > Duration::ZERO);
+    assert!(db
+        .metrics()
+        .to_prometheus()
+        .contains("redo_log_writes_total 0\n"));
+
+    Ok(())
+}
+
+#[test]
+fn test_stats() -> Result<()> {
+    let dir = tempdir()?;
+    let file = dir.path().to_path_buf().join("logfile");
+    let options = Options {
+        segment_size: 4096,
+        ..Options::default()
+    };
+
+    let mut db = Db::with_options(&file, options.clone())?;
+    for i in 0..300 {
+        db.set(&format!("key{}", i % 10), &i.to_string())?;
+    }
+    let stats = db.stats()?;
+    assert_eq!(stats.log_records, 300);
+    assert_eq!(stats.estimated_live_keys, 10);
+    assert_eq!(stats.tables, 0);
+    assert_eq!(stats.write_amplification, 1.0);
+    assert!(stats.disk_bytes >= 2 * 4096);
+
+    // Compacting leaves one record per key, at the cost of writing them again.
+    db.compact()?;
+    let stats = db.stats()?;
+    assert_eq!(stats.log_records, 10);
+    assert!(stats.write_amplification > 1.0);
+
+    // Flushed keys are counted in the tables, and overwrites counted twice.
+    db.memtable_bytes = Some(0);
+    db.set("other", "x")?;
+    db.set("key0", "again")?;
+    let stats = db.stats()?;
+    assert_eq!(stats.tables, 2);
+    assert_eq!(stats.log_records, 0);
+    assert_eq!(stats.estimated_live_keys, 12);
+    db.compact()?;
+    assert_eq!(db.stats()?.estimated_live_keys, 11);
+
+    drop(db);
+    let db = Db::with_options(&file, options)?;
+    let stats = db.stats()?;
+    assert_eq!(stats.tables, 1);
+    assert_eq!(stats.write_amplification, 0.0);
+    assert!(stats.replay_time > Duration::ZERO);
+
+    Ok(())
+}
+
+// Paging through the keys with `keys_from`, a few at a time, finds the same
+// ones `try_scan` does, with some of them only in the tables, some deleted
+// from the memtable, and some deleted by a range, whatever the memtable is.
+#[test]
+fn test_keys_from() -> Result<()> {
+    for kind in [MemtableKind::Ordered, MemtableKind::Hash] {
+        let dir = tempdir()?;
+        let options = Options {
+            memtable: kind,
+            ..Options::default()
+        };
+        let mut db = Db::with_options(dir.path(), options)?;
+        for i in 0..20 {
+            db.set(&format!("k{:02}", i), "v")?;
+        }
+        db.flush()?;
+        for i in 5..10 {
+            db.delete(&format!("k{:02}", i))?;
+        }
+        db.delete_range("k12", "k15")?;
+        for i in 18..25 {
+            db.set(&format!("k{:02}", i), "v")?;
+        }
+
+        let all = db.try_scan("")?.into_iter().map(|(k, _)| k);
+        let all = all.collect::<Vec<_>>();
+        assert_eq!(all.len(), 17);
+        for n in [1, 3, 100] {
+            let (mut paged, mut start) = (vec![], String::new());
+            loop {
+                let page = db.keys_from(&start, n)?;
+                assert!(page.len() <= n);
+                match page.last() {
+                    Some(last) => start = format!("{}\0", last),
+                    None => break,
+                }
+                paged.extend(page);
+            }
+            assert_eq!(paged, all);
+        }
+        assert_eq!(db.keys_from("k10", 2)?, ["k10", "k11"]);
+    }
+    Ok(())
+}
+
+// Writes stop at `memtable_limit` until the memtable is flushed, by whoever
+// finds it full, or by someone else already flushing it.
+#[test]
+fn test_memtable_limit() -> Result<()> {
+    let dir = tempdir()?;
+    let options = Options {
+        memtable_limit: Some(2048),
+        ..Options::default()
+    };
+    let mut db = Db::with_options(dir.path(), options)?;
+    for i in 0..100 {
+        db.set(&format!("k{:03}", i), "v")?;
+        // Give or take the write that found it full.
+        assert!(db.stats()?.memtable_bytes < 2048 + 128);
+    }
+    assert!(db.stats()?.tables > 0);
+
+    let flushing = db.flushing.clone();
+    let held = flushing.lock()?;
+    let mut writer = db.clone();
+    let writer = std::thread::spawn(move || -> Result<()> {
+        for i in 100..200 {
+            writer.set(&format!("k{:03}", i), "v")?;
+        }
+        Ok(())
+    });
+    while db.stats()?.memtable_bytes < 2048 {
+        std::thread::yield_now();
+    }
+    std::thread::sleep(Duration::from_millis(50));
+    assert!(!writer.is_finished());
+    assert!(db.stats()?.memtable_bytes < 2048 + 128);
+    drop(held);
+    writer.join().unwrap()?;
+    assert_eq!(db.try_len()?, 200);
+    Ok(())
+}
+
+#[test]
+fn test_memtable_slowdown() -> Result<()> {
+    let dir = tempdir()?;
+    let options = Options {
+        memtable_slowdown: Some(1024),
+        ..Options::default()
+    };
+    let mut db = Db::with_options(dir.path(), options)?;
+    let mut keyspace = db.cf("ks");
+    for i in 0..10 {
+        keyspace.set(&format!("k{}", i), "v")?;
+    }
+    assert_eq!(db.metrics().slowed_writes, 0);
+    // Keyspaces stay in the memtable, but count against it all the same.
+    for i in 10..20 {
+        keyspace.set(&format!("k{}", i), "v")?;
+    }
+    assert!(db.stats()?.memtable_bytes > 1024);
+    let metrics = db.metrics();
+    assert!(metrics.slowed_writes > 0);
+    let most = MAX_SLOWDOWN * metrics.slowed_writes as u32;
+    assert!(metrics.slowdown_time > Duration::ZERO && metrics.slowdown_time <= most);
+
+    // Until they're deleted.
+    db.drop_cf("ks")?;
+    assert!(db.stats()?.memtable_bytes < 1024);
+    let slowed = db.metrics().slowed_writes;
+    db.set("k", "v")?;
+    assert_eq!(db.metrics().slowed_writes, slowed);
+    Ok(())
+}
+
+#[test]
+fn test_rotate_and_recycle() -> Result<()> {
+    let dir = tempdir()?;
+    let file = dir.path().to_path_buf().join("logfile");
+    let options = Options {
+        segment_size: 256,
+        max_recycled_segments: 2,
+        ..Options::default()
+    };
+
+    let mut db = Db::with_options(&file, options.clone())?;
+    for i in 0..50 {
+        db.set(
+            format!("key{}", i % 5).as_str(),
+            format!("val{}", i).as_str(),
+        )?;
+    }
+    db.delete("key0")?;
+    assert!(segment::list(&file)?.0.len() > 2);
+
+    db.compact()?;
+    let (segments, recycled) = segment::list(&file)?;
+    assert_eq!(recycled.len(), 2);
+    db.set("key1", "after")?;
+
+    drop(db);
+    let db = Db::with_options(&file, options)?;
+    assert_eq!(db.get("key0"), None);
+    assert_eq!(db.get("key1"), Some("after".into()));
+    assert_eq!(db.get("key4"), Some("val49".into()));
+    // Reopening starts a new segment, which reuses one of the recycled files.
+    assert_eq!(segment::list(&file)?.1.len(), 1);
+    assert!(segment::list(&file)?.0.len() <= segments.len() + 1);
+
+    Ok(())
+}
+
+#[test]
+fn test_custom_commands() -> Result<()> {
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct Transfer {
+        from: String,
+        to: String,
+        amount: u64,
+    }
+
+    let dir = tempdir()?;
+    let file = dir.path().to_path_buf().join("logfile");
+    let options = Options {
+        segment_size: 256,
+        ..Options::default()
+    };
+
+    let mut db = Db::with_options(&file, options.clone())?;
+    for amount in 0..20 {
+        db.set("last", &amount.to_string())?;
+        db.log_custom(&Transfer {
+            from: "alice".into(),
+            to: "bob".into(),
+            amount,
+        })?;
+    }
+    assert_eq!(db.len(), 1);
+    drop(db);
+
+    // Custom commands come back in order, however the log is replayed.
+    for parallelism in [1, 4] {
+        let options = Options {
+            recovery: RecoveryOptions {
+                parallelism,
+                ..RecoveryOptions::default()
+            },
+            ..options.clone()
+        };
+        let mut transfers = vec![];
+        let db = Db::with_custom_handler(&file, options, |t: Transfer| {
+            transfers.push(t.amount);
+            Ok::<_, Error>(())
+        })?;
+        assert_eq!(transfers, (0..20).collect::<Vec<_>>());
+        assert_eq!(db.get("last"), Some("19".into()));
+    }
+
+    // A handler that can't make sense of what it's given stops recovery.
+    let err = Db::with_custom_handler(&file, options, |_: String| Ok::<_, Error>(())).unwrap_err();
+    assert!(matches!(err, Error::Handler(_)), "{}", err);
+    assert!(err.to_string().contains("expected a string"), "{}", err);
+
+    Ok(())
+}
+
+#[test]
+fn test_failed_reads() -> Result<()> {
+    let dir = tempdir()?;
+    let file = dir.path().join("logfile");
+    let options = Options {
+        memtable_bytes: Some(2048),
+        block_cache_bytes: 0,
+        ..Options::default()
+    };
+    let mut db = Db::with_options(&file, options)?;
+    for i in 0..100 {
+        db.set(&format!("key{:03}", i), "v")?;
+    }
+    db.flush()?;
+    assert_eq!(db.try_get("key000")?, Some("v".into()));
+
+    // Reads that need a table that's since been cut short fail, rather than
+    // panicking, unless they're the ones that can't say so.
+    for number in table::list(&file)? {
+        std::fs::OpenOptions::new()
+            .write(true)
+            .open(table::table_path(&file, number))?
+            .set_len(0)?;
+    }
+    assert!(db.try_get("key000").is_err());
+    assert!(db.try_scan("").is_err());
+    assert!(db.try_len().is_err());
+    assert!(db.sample_keys(10).is_err());
+    assert!(db.snapshot().try_get("key050").is_err());
+    assert!(db.transaction().get("key050").is_err());
+    let db = std::panic::AssertUnwindSafe(&db);
+    assert!(std::panic::catch_unwind(|| db.get("key000")).is_err());
+
+    // So do writes that need to know what a table has, before anything is
+    // logged, and without panicking in the middle of committing.
+    let mut db = db.clone();
+    let seq = db.next_seq();
+    assert!(db.incr("key001", 1).is_err());
+    assert!(db.append("key002", "x").is_err());
+    let snapshot = db.snapshot();
+    assert!(db.set("key003", "w").is_err());
+    assert!(db.delete_prefix("key").is_err());
+    drop(snapshot);
+    assert_eq!(db.next_seq(), seq);
+
+    // What the memtable has can still be read.
+    db.set("new", "v")?;
+    assert_eq!(db.try_get("new")?, Some("v".into()));
+    Ok(())
+}
+
+#[test]
+fn test_memtable_flush() -> Result<()> {
+    let dir = tempdir()?;
+    let file = dir.path().to_path_buf().join("logfile");
+    let options = Options {
+        segment_size: 4096,
+        memtable_bytes: Some(2048),
+        ..Options::default()
+    };
+    let tables = || -> Result<usize> { Ok(table::list(&file)?.len()) };
+
+    let mut db = Db::with_options(&file, options.clone())?;
+    let reader = Db::open_read_only(&file)?;
+    let mut expected = std::collections::BTreeMap::new();
+    for i in 0..300 {
+        let key = format!("key{:03}", i % 70);
+        if i % 7 == 3 {
+            db.delete(&key)?;
+            expected.remove(&key);
+        } else {
+            db.set(&key, &format!("val{}", i))?;
+            expected.insert(key, format!("val{}", i));
+        }
+    }
+    let expected = expected.into_iter().collect::<Vec<_>>();
+    assert!(tables()? > 1);
+    assert!(db.memtable.read().bytes() <= 2048);
+    // The log only holds what's been written since the last flush.
+    assert!(segment::list(&file)?.0.len() <= 2);
+    let check = |db: &Db| {
+        assert_eq!(db.scan(""), expected);
+        assert_eq!(
+            db.scan("key01"),
+            expected[..]
+                .iter()
+                .filter(|(k, _)| k.starts_with("key01"))
+                .cloned()
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(db.len(), expected.len());
+        for (k, v) in &expected {
+            assert_eq!(db.get(k).as_ref(), Some(v));
+        }
+        assert_eq!(db.get("key003"), None);
+    };
+    check(&db);
+    let metrics = db.metrics();
+    assert!(metrics.block_cache_hits > metrics.block_cache_misses);
+    reader.refresh()?;
+    check(&reader);
+
+    drop(db);
+    let db = Db::with_options(&file, options.clone())?;
+    check(&db);
+    db.compact()?;
+    assert_eq!(tables()?, 1);
+    check(&db);
+    drop(db);
+    let uncached = Db::with_options(
+        &file,
+        Options {
+            block_cache_bytes: 0,
+            ..options
+        },
+    )?;
+    check(&uncached);
+    assert_eq!(uncached.metrics().block_cache_hits, 0);
+
+    Ok(())
+}
+
+#[test]
+fn test_delete_range() -> Result<()> {
+    let dir = tempdir()?;
+    let file = dir.path().to_path_buf().join("logfile");
+    let keys = |db: &Db| db.scan("").into_iter().map(|(k, _)| k).collect::<Vec<_>>();
+
+    let mut db = Db::new(&file)?;
+    for tenant in ["a", "b", "c"] {
+        for i in 0..5 {
+            db.set(&format!("{}/{}", tenant, i), "v")?;
+        }
+    }
+    let seq = db.next_seq();
+    db.delete_prefix("a/")?;
+    db.delete_range("b/1", "b/4")?;
+    assert_eq!(db.next_seq(), seq + 2);
+    let expected = ["b/0", "b/4", "c/0", "c/1", "c/2", "c/3", "c/4"];
+    assert_eq!(keys(&db), expected);
+    drop(db);
+    assert_eq!(keys(&Db::new(&file)?), expected);
+
+    // Once there are tables, deleted ranges hide what's in them until the
+    // next flush deletes it for good.
+    let options = Options {
+        segment_size: 4096,
+        memtable_bytes: Some(2048),
+        ..Options::default()
+    };
+    let mut db = Db::with_options(&file, options.clone())?;
+    for i in 0..100 {
+        db.set(&format!("d/{:02}", i), "v")?;
+    }
+    assert!(!table::list(&file)?.is_empty());
+    db.delete_prefix("d/")?;
+    db.delete_range("c/1", "c/3")?;
+    db.set("d/50", "back")?;
+    let expected = ["b/0", "b/4", "c/0", "c/3", "c/4", "d/50"];
+    assert_eq!(keys(&db), expected);
+    assert_eq!(db.get("d/10"), None);
+    assert_eq!(db.get("d/50"), Some("back".into()));
+    assert_eq!(db.len(), expected.len());
+    for i in 0..100 {
+        db.set(&format!("e/{:02}", i), "v")?;
+    }
+    assert!(db.memtable.read().deleted_ranges().is_empty());
+    assert_eq!(db.get("d/10"), None);
+    assert_eq!(db.scan("d/").len(), 1);
+    drop(db);
+    let db = Db::with_options(&file, options)?;
+    assert_eq!(db.scan("d/").len(), 1);
+    db.compact()?;
+    assert_eq!(db.len(), expected.len() + 100);
+    assert_eq!(keys(&db)[..6], expected);
+
+    Ok(())
+}
+
+#[test]
+fn test_incr() -> Result<()> {
+    let dir = tempdir()?;
+    let file = dir.path().to_path_buf().join("logfile");
+    let options = Options {
+        segment_size: 4096,
+        memtable_bytes: Some(512),
+        ..Options::default()
+    };
+    let db = Db::with_options(&file, options.clone())?;
+    let threads = (0..8)
+        .map(|i| {
+            let mut db = db.clone();
+            std::thread::spawn(move || -> Result<()> {
+                for j in 0..100 {
+                    db.incr("counter", 1)?;
+                    db.incr(&format!("key{}", j % 10), i)?;
+                }
+                Ok(())
+            })
+        })
+        .collect::<Vec<_>>();
+    for thread in threads {
+        thread.join().unwrap()?;
+    }
+    assert_eq!(db.get("counter"), Some("800".into()));
+    assert_eq!(db.get("key3"), Some((10 * (0..8).sum::<i64>()).to_string()));
+    // Some of that has been flushed, which increments have to read back.
+    assert!(!table::list(&file)?.is_empty());
+
+    let mut db = db;
+    db.set("text", "abc")?;
+    db.incr("text", 5)?;
+    assert_eq!(db.get("text"), Some("5".into()));
+    db.set("max", &i64::MAX.to_string())?;
+    db.incr("max", 1)?;
+    assert_eq!(db.get("max"), Some(i64::MAX.to_string()));
+    db.delete_prefix("key")?;
+    db.incr("key3", -2)?;
+    assert_eq!(db.get("key3"), Some("-2".into()));
+    drop(db);
+
+    let db = Db::with_options(&file, options)?;
+    assert_eq!(db.get("counter"), Some("800".into()));
+    assert_eq!(db.get("key3"), Some("-2".into()));
+    assert_eq!(db.get("text"), Some("5".into()));
+
+    Ok(())
+}
+
+#[test]
+fn test_append() -> Result<()> {
+    let dir = tempdir()?;
+    let file = dir.path().to_path_buf().join("logfile");
+    let options = Options {
+        segment_size: 4096,
+        memtable_bytes: Some(1024),
+        ..Options::default()
+    };
+    let mut db = Db::with_options(&file, options.clone())?;
+    assert_eq!(db.get_list("events"), None);
+    db.append("events", "a")?;
+    db.append("events", "b \"quoted\"")?;
+    assert_eq!(db.get("events"), Some(r#"["a","b \"quoted\""]"#.into()));
+    db.set("plain", "v")?;
+    db.append("plain", "x")?;
+    assert_eq!(db.get_list("plain"), Some(vec!["x".into()]));
+    assert_eq!(db.get_list("missing"), None);
+
+    // Keep appending across flushes, so that the list's earlier elements are
+    // in a table when the later ones are appended.
+    let mut expected = vec!["a".to_string(), "b \"quoted\"".to_string()];
+    for i in 0..100 {
+        db.append("events", &i.to_string())?;
+        expected.push(i.to_string());
+        db.set(&format!("filler{:02}", i % 20), &"x".repeat(40))?;
+    }
+    assert!(table::list(&file)?.len() > 1);
+    assert_eq!(db.get_list("events").as_ref(), Some(&expected));
+    drop(db);
+    let mut db = Db::with_options(&file, options.clone())?;
+    assert_eq!(db.get_list("events").as_ref(), Some(&expected));
+
+    // A crash after a flush writes its table but before it truncates the log
+    // leaves records in the log that the table already has. Appending them a
+    // second time would duplicate them.
+    db.append("events", "last")?;
+    expected.push("last".into());
+    let (segments, _) = segment::list(&file)?;
+    let saved = segments
+        .iter()
+        .map(|&n| Ok((n, std::fs::read(segment::segment_path(&file, n))?)))
+        .collect::<Result<Vec<_>>>()?;
+    let manifest = std::fs::read(file.join("MANIFEST"))?;
+    db.compact()?;
+    drop(db);
+    for n in segment::list(&file)?.0 {
+        std::fs::remove_file(segment::segment_path(&file, n))?;
+    }
+    for (n, bytes) in saved {
+        std::fs::write(segment::segment_path(&file, n), bytes)?;
+    }
+    std::fs::write(file.join("MANIFEST"), manifest)?;
+    let db = Db::with_options(&file, options)?;
+    assert_eq!(db.get_list("events").as_ref(), Some(&expected));
+    assert_eq!(db.get_list("plain"), Some(vec!["x".into()]));
+    assert_eq!(
+        Db::open_read_only(&file)?.get_list("events"),
+        Some(expected)
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_idempotent_writes() -> Result<()> {
+    let dir = tempdir()?;
+    let file = dir.path().to_path_buf().join("logfile");
+    let mut db = Db::new(&file)?;
+    let request = |client_id, request_id| WriteOptions {
+        idempotency_key: Some(IdempotencyKey {
+            client_id,
+            request_id,
+        }),
+        ..WriteOptions::default()
+    };
+    db.set_with_options("a", "1", &request(1, 1))?;
+    let seq = db.next_seq();
+    db.set_with_options("a", "retried", &request(1, 1))?;
+    assert_eq!(db.get("a"), Some("1".into()));
+    assert_eq!(db.next_seq(), seq);
+    db.delete_with_options("a", &request(1, 2))?;
+    db.set("a", "2")?;
+    db.delete_with_options("a", &request(1, 2))?;
+    assert_eq!(db.get("a"), Some("2".into()));
+    // Other clients have request ids of their own.
+    db.set_with_options("b", "1", &request(2, 1))?;
+    db.cf("ks").set_with_options("c", "1", &request(2, 2))?;
+    db.cf("ks")
+        .set_with_options("c", "retried", &request(2, 1))?;
+    assert_eq!(db.get("b"), Some("1".into()));
+    assert_eq!(db.cf("ks").get("c"), Some("1".into()));
+
+    // Retries within a batch are caught too.
+    let key = request(3, 1).idempotency_key.unwrap();
+    db.write_batch(vec![
+        Command::Request(key, Box::new(Command::Set("d".into(), "1".into()))),
+        Command::Request(key, Box::new(Command::Set("d".into(), "2".into()))),
+    ])?;
+    assert_eq!(db.get("d"), Some("1".into()));
+
+    // What's been applied survives replay and compaction.
+    drop(db);
+    let mut db = Db::new(&file)?;
+    db.set_with_options("a", "retried", &request(1, 2))?;
+    db.compact()?;
+    drop(db);
+    let mut db = Db::new(&file)?;
+    db.set_with_options("a", "retried", &request(1, 1))?;
+    db.set_with_options("d", "retried", &request(3, 1))?;
+    assert_eq!(db.get("a"), Some("2".into()));
+    assert_eq!(db.get("d"), Some("1".into()));
+    db.set_with_options("a", "3", &request(1, 3))?;
+    assert_eq!(db.get("a"), Some("3".into()));
+
+    Ok(())
+}
+
+#[test]
+fn test_exists_and_destroy() -> Result<()> {
+    let dir = tempdir()?;
+    let file = dir.path().to_path_buf().join("logfile");
+    assert!(!Db::exists(&file)?);
+    Db::destroy(&file)?;
+
+    let mut db = Db::new(&file)?;
+    db.set("foo", "bar")?;
+    db.compact()?;
+    assert!(Db::exists(&file)?);
+    assert!(matches!(Db::destroy(&file), Err(Error::Busy(_))));
+    drop(db);
+    Db::destroy(&file)?;
+    assert!(!file.exists());
+    assert!(!Db::exists(&file)?);
+
+    // Files that aren't the database's are left alone.
+    Db::new(&file)?;
+    std::fs::write(file.join("notes.txt"), "hello")?;
+    Db::destroy(&file)?;
+    assert!(!Db::exists(&file)?);
+    assert_eq!(std::fs::read_dir(&file)?.count(), 1);
+
+    Ok(())
+}
+
+#[test]
+fn test_lock() -> Result<()> {
+    let dir = tempdir()?;
+    let file = dir.path().to_path_buf().join("logfile");
+    let with_lock = |lock| Options {
+        lock,
+        ..Options::default()
+    };
+
+    let mut db = Db::new(&file)?;
+    db.set("foo", "bar")?;
+    let err = Db::new(&file).unwrap_err();
+    assert!(matches!(err, Error::Busy(_)), "{}", err);
+
+    let mut reader = Db::with_options(&file, with_lock(LockPolicy::ReadOnly))?;
+    assert!(reader.is_read_only());
+    assert_eq!(reader.get("foo"), Some("bar".into()));
+    assert!(matches!(reader.set("foo", "baz"), Err(Error::ReadOnly)));
+    assert!(reader.compact().is_err());
+    let segments = segment::list(&file)?;
+    drop(reader);
+    // Opening read-only didn't start a segment of its own.
+    assert_eq!(segment::list(&file)?, segments);
+
+    let forced = Db::with_options(&file, with_lock(LockPolicy::Force))?;
+    assert!(!forced.is_read_only());
+    drop(forced);
+
+    drop(db);
+    let db = Db::with_options(&file, with_lock(LockPolicy::ReadOnly))?;
+    assert!(!db.is_read_only());
+    assert_eq!(db.get("foo"), Some("bar".into()));
+
+    Ok(())
+}
+
+#[test]
+fn test_open_read_only() -> Result<()> {
+    let dir = tempdir()?;
+    let file = dir.path().to_path_buf().join("logfile");
+    let options = Options {
+        segment_size: 256,
+        ..Options::default()
+    };
+
+    assert!(Db::open_read_only(&file).is_err());
+    let mut db = Db::with_options(&file, options)?;
+    for i in 0..10 {
+        db.set(&format!("key{}", i), "before")?;
+    }
+
+    let mut reader = Db::open_read_only(&file)?;
+    assert!(reader.is_read_only());
+    assert_eq!(reader.len(), 10);
+    assert!(reader.set("key0", "reader").is_err());
+    assert!(db.refresh().is_err());
+
+    // New writes only show up once the reader is refreshed, which picks up
+    // from where it left off, across segment boundaries.
+    for i in 0..20 {
+        db.set(&format!("key{}", i % 15), "after")?;
+    }
+    assert_eq!(reader.get("key12"), None);
+    assert_eq!(reader.refresh()?, 20);
+    assert_eq!(reader.len(), 15);
+    assert_eq!(reader.get("key3"), Some("after".into()));
+    assert_eq!(reader.refresh()?, 0);
+
+    // A compaction hides deletes from anyone reading only the new segments,
+    // so the reader starts over.
+    db.delete("key0")?;
+    assert_eq!(reader.refresh()?, 1);
+    db.delete("key1")?;
+    db.compact()?;
+    reader.refresh()?;
+    assert_eq!(reader.get("key0"), None);
+    assert_eq!(reader.get("key1"), None);
+    assert_eq!(reader.scan(""), db.scan(""));
+
+    // The reader never held the lock, so the writer can come and go.
+    drop(db);
+    let mut db = Db::new(&file)?;
+    db.set("key0", "reopened")?;
+    reader.refresh()?;
+    assert_eq!(reader.get("key0"), Some("reopened".into()));
+
+    Ok(())
+}
+
+#[test]
+fn test_approximate_size() -> Result<()> {
+    let dir = tempdir()?;
+    let file = dir.path().to_path_buf().join("logfile");
+    let mut db = Db::new(&file)?;
+    let value = "v".repeat(100);
+    for i in 0..1000 {
+        db.set(&format!("a{:04}", i), &value)?;
+    }
+    for i in 0..500 {
+        db.set(&format!("b{:04}", i), &value)?;
+    }
+    db.flush()?;
+    for i in 0..100 {
+        db.set(&format!("c{:03}", i), &value)?;
+    }
+    // A table is only out by up to 16 entries at either end, and the
+    // memtable not at all.
+    let near = |estimate: u64, actual: u64, slack: u64| {
+        assert!(
+            estimate.abs_diff(actual) <= slack,
+            "{} {}",
+            estimate,
+            actual
+        );
+    };
+    near(db.approximate_key_count("a"), 1000, 32);
+    near(db.approximate_key_count("b0"), 500, 32);
+    near(db.approximate_key_count(""), 1600, 32);
+    assert_eq!(db.approximate_key_count("c"), 100);
+    assert_eq!(db.approximate_key_count("d"), 0);
+    let entry = 8 + 5 + value.len() as u64;
+    near(db.approximate_size("a", "b"), 1000 * entry, 32 * entry);
+    near(
+        db.approximate_size("a0500", "a0600"),
+        100 * entry,
+        32 * entry,
+    );
+    near(
+        db.approximate_size("b0100", "c050"),
+        400 * entry + 50 * (entry - 1),
+        32 * entry,
+    );
+    assert_eq!(db.approximate_size("x", "y"), 0);
+    Ok(())
+}
+
+#[test]
+fn test_sample_keys() -> Result<()> {
+    let dir = tempdir()?;
+    let file = dir.path().to_path_buf().join("logfile");
+    let mut db = Db::new(&file)?;
+    for i in 0..200 {
+        db.set(&format!("k{:03}", i), "v")?;
+    }
+    db.flush()?;
+    // Every other key is deleted, half of them since the flush.
+    for i in (0..200).step_by(2) {
+        db.delete(&format!("k{:03}", i))?;
+        if i == 100 {
+            db.flush()?;
+        }
+    }
+    assert!(db.sample_keys(0)?.is_empty());
+    let keys = db.scan("").into_iter().map(|(k, _)| k).collect::<Vec<_>>();
+    assert_eq!(keys.len(), 100);
+    assert_eq!(db.sample_keys(1000)?, keys);
+
+    let mut picked = HashMap::new();
+    for _ in 0..200 {
+        let sample = db.sample_keys(10)?;
+        assert_eq!(sample.len(), 10);
+        assert!(sample.windows(2).all(|w| w[0] < w[1]), "{:?}", sample);
+        for k in sample {
+            *picked.entry(k).or_insert(0) += 1;
+        }
+    }
+    // Each of the 100 live keys is picked 20 times on average, and the odds
+    // of any one never being picked are about one in a billion.
+    assert_eq!(picked.len(), 100);
+    assert!(picked
+        .keys()
+        .all(|k| k[1..].parse::<u32>().unwrap() % 2 == 1));
+    Ok(())
+}
+
+#[test]
+fn test_sync_and_flush() -> Result<()> {
+    let dir = tempdir()?;
+    let file = dir.path().to_path_buf().join("logfile");
+    let options = Options {
+        segment_size: 256,
+        durability: Durability::None,
+        ..Options::default()
+    };
+
+    let mut db = Db::with_options(&file, options.clone())?;
+    assert_eq!(db.sync()?, 0);
+    for i in 0..20 {
+        db.set(&format!("key{}", i), "value")?;
+    }
+    // Plenty of segments were sealed without a sync along the way.
+    assert_eq!(db.sync()?, 20);
+    assert_eq!(db.sync()?, 20);
+
+    db.delete("key0")?;
+    assert_eq!(db.flush()?, 21);
+    let stats = db.stats()?;
+    assert_eq!(stats.tables, 1);
+    assert_eq!(stats.log_records, 0);
+    db.set("key0", "again")?;
+    db.compact()?;
+    assert_eq!(db.sync()?, 22);
+
+    drop(db);
+    let db = Db::with_options(&file, options)?;
+    assert_eq!(db.get("key0"), Some("again".into()));
+    assert_eq!(db.get("key19"), Some("value".into()));
+    assert_eq!(db.sync()?, 22);
+
+    Ok(())
+}
+
+#[test]
+fn test_tickets() {
+    let tickets = Arc::new(Tickets::default());
+    let order = Arc::new(Mutex::new(vec![]));
+    let first = tickets.wait_turn();
+    let waiters = (0..8)
+        .map(|i| {
+            let (shared, order) = (tickets.clone(), order.clone());
+            let waiter = std::thread::spawn(move || {
+                let _turn = shared.wait_turn();
+                order.lock().unwrap().push(i);
+            });
+            // Each one has its ticket before the next shows up.
+            while tickets.next.load(Ordering::SeqCst) < i + 2 {
+                std::thread::yield_now();
+            }
+            waiter
+        })
+        .collect::<Vec<_>>();
+    drop(first);
+    for waiter in waiters {
+        waiter.join().unwrap();
+    }
+    assert_eq!(*order.lock().unwrap(), (0..8).collect::<Vec<_>>());
+}
+
+// Writers through a `Db` take their turns in the same order: with 8 of them
+// writing flat out, none is left waiting while the others keep getting in
+// ahead of it. Each one waits for at most the batch ahead of its own and
+// then its own, which is two syncs, give or take the scheduler.
+#[test]
+fn test_writers_not_starved() -> Result<()> {
+    let dir = tempdir()?;
+    let db = Db::new(dir.path())?;
+    let writers = (0..8)
+        .map(|t| {
+            let mut db = db.clone();
+            std::thread::spawn(move || -> Result<()> {
+                for i in 0..200 {
+                    db.set(&format!("{}_{}", t, i), "v")?;
+                }
+                Ok(())
+            })
+        })
+        .collect::<Vec<_>>();
+    for writer in writers {
+        writer.join().unwrap()?;
+    }
+    let metrics = db.metrics();
+    assert_eq!(metrics.write_wait.count(), 8 * 200);
+    let (wait, sync) = (
+        metrics.write_wait.quantile(0.99),
+        metrics.sync_latency.quantile(0.99),
+    );
+    // Two syncs, twice over for the scheduler and whatever else runs between
+    // them, with a couple of milliseconds on top for a busy machine.
+    assert!(
+        wait <= 4 * sync + 2000,
+        "p99 wait {}us, p99 sync {}us: {:?}",
+        wait,
+        sync,
+        metrics.write_wait
+    );
+    Ok(())
+}
+
+#[test]
+fn test_send_sync() {
+    // Fails to compile, rather than to run, if any of these stop being safe
+    // to share between threads.
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<Db>();
+    assert_send_sync::<Snapshot>();
+    assert_send_sync::<Transaction>();
+    assert_send_sync::<Keyspace>();
+    assert_send_sync::<KeyGuard>();
+    assert_send_sync::<ExpirySweeper>();
+    assert_send_sync::<Maintenance>();
+    assert_send_sync::<DbManager>();
+    assert_send_sync::<LogReader>();
+    assert_send_sync::<Error>();
+}
+
+#[test]
+fn test_close() -> Result<()> {
+    let dir = tempdir()?;
+    let file = dir.path().to_path_buf().join("logfile");
+    let options = Options {
+        memtable_bytes: Some(256),
+        ..Options::default()
+    };
+
+    let db = Db::with_options(&file, options.clone())?;
+    let writers = (0..8)
+        .map(|t| {
+            let mut db = db.clone();
+            std::thread::spawn(move || {
+                let mut written = vec![];
+                for i in 0.. {
+                    let k = format!("{}_{}", t, i);
+                    match db.set(&k, "v") {
+                        Ok(()) => written.push(k),
+                        Err(Error::Closed) => return written,
+                        Err(e) => panic!("{}", e),
+                    }
+                }
+                unreachable!()
+            })
+        })
+        .collect::<Vec<_>>();
+    std::thread::sleep(Duration::from_millis(20));
+    let mut other = db.clone();
+    db.close()?;
+    assert!(matches!(other.set("a", "b"), Err(Error::Closed)));
+    assert!(matches!(other.sync(), Err(Error::Closed)));
+    let written = writers
+        .into_iter()
+        .flat_map(|w| w.join().unwrap())
+        .collect::<Vec<_>>();
+    assert!(Log::closed_cleanly(&file).is_some());
+    // The lock was released even though a clone is still around.
+    let db = Db::with_options(&file, options.clone())?;
+    assert!(db.recovery_report().trusted);
+    assert!(Log::closed_cleanly(&file).is_none());
+    assert!(!written.is_empty());
+    for k in &written {
+        assert_eq!(db.get(k), Some("v".into()), "{}", k);
+    }
+    drop(other);
+
+    // Dropping the last clone closes it too, but a crash doesn't.
+    drop(db);
+    let db = Db::with_options(&file, options.clone())?;
+    assert!(db.recovery_report().trusted);
+    std::mem::forget(db);
+    let db = Db::with_options(
+        &file,
+        Options {
+            lock: LockPolicy::Force,
+            ..options
+        },
+    )?;
+    assert!(!db.recovery_report().clean_shutdown);
+    Ok(())
+}
+
+#[test]
+fn test_reopen_after_clean_shutdown() -> Result<()> {
+    let dir = tempdir()?;
+    let file = dir.path().to_path_buf().join("logfile");
+    let strict = Options {
+        recovery: RecoveryOptions {
+            mode: RecoveryMode::Strict,
+            ..RecoveryOptions::default()
+        },
+        ..Options::default()
+    };
+    let mut db = Db::with_options(&file, strict.clone())?;
+    for i in 0..10 {
+        db.set(&format!("key{}", i), &format!("value{}", i))?;
+    }
+    db.close()?;
+
+    // The clean shutdown says where the records end, so this much can be
+    // found without reading them.
+    let clean = Log::closed_cleanly(&file).unwrap();
+    assert_eq!(clean.next_seq, 11);
+    assert_eq!(segment::list(&file)?.0, vec![clean.segment]);
+    let mut starts = vec![];
+    segment::read_records(&file, clean.segment, &mut vec![], |offset, _| {
+        starts.push(offset);
+        Ok(())
+    })?;
+    let copy = tempdir()?;
+    for entry in std::fs::read_dir(&file)? {
+        let path = entry?.path();
+        std::fs::copy(&path, copy.path().join(path.file_name().unwrap()))?;
+    }
+    let damage = |dir: &Path, at: usize| -> Result<()> {
+        let path = segment::segment_path(dir, clean.segment);
+        let mut bytes = std::fs::read(&path)?;
+        bytes[at] ^= 1;
+        Ok(std::fs::write(&path, bytes)?)
+    };
+
+    // A damaged checksum goes unnoticed, since nothing it covers changed.
+    damage(&file, starts[3] as usize)?;
+    let db = Db::with_options(&file, strict.clone())?;
+    assert!(db.recovery_report().trusted);
+    assert_eq!(db.get("key3"), Some("value3".into()));
+
+    // But a damaged value changes the memtable, so replay starts over and
+    // checks every record this time.
+    let value = r#"{"Set":["key3","value"#.len();
+    damage(
+        copy.path(),
+        starts[3] as usize + segment::HEADER_LEN + value,
+    )?;
+    assert!(matches!(
+        Db::with_options(copy.path(), strict),
+        Err(Error::Corruption { .. })
+    ));
+    Ok(())
+}
+
+#[test]
+fn test_panicking_writer() -> Result<()> {
+    #[derive(Debug)]
+    struct Panicky;
+
+    impl DbListener for Panicky {
+        fn on_batch_committed(&self, seqs: std::ops::Range<u64>, _: usize) {
+            assert!(!seqs.contains(&20), "listener panicked");
+        }
+    }
+
+    let dir = tempdir()?;
+    let file = dir.path().to_path_buf().join("logfile");
+    let options = Options {
+        listener: Some(Arc::new(Panicky)),
+        ..Options::default()
+    };
+    let db = Db::with_options(&file, options)?;
+    // Whoever leads the batch with seq 20 in it panics while holding the
+    // log, and everyone else has to hear about it rather than hang or panic
+    // too.
+    let writers = (0..8)
+        .map(|t| {
+            let mut db = db.clone();
+            std::thread::spawn(move || -> Result<usize> {
+                for i in 0..10 {
+                    match db.set(&format!("{}_{}", t, i), "v") {
+                        Ok(()) => {}
+                        Err(Error::Poisoned | Error::WriteFailed(_)) => return Ok(i),
+                        Err(e) => return Err(e),
+                    }
+                }
+                Ok(10)
+            })
+        })
+        .collect::<Vec<_>>();
+    let mut panicked = 0;
+    for writer in writers {
+        match writer.join() {
+            Ok(written) => {
+                written?;
+            }
+            Err(_) => panicked += 1,
+        }
+    }
+    assert_eq!(panicked, 1);
+    let mut other = db.clone();
+    assert!(matches!(other.set("a", "b"), Err(Error::Poisoned)));
+    // Reads still work, though they don't see the batch that panicked.
+    let applied = db.len();
+    assert!(applied < 20);
+    drop((db, other));
+
+    // It made it into the log, though, so reopening gets it back.
+    let db = Db::new(&file)?;
+    assert!(db.len() >= 20);
+    assert!(db.len() > applied);
+    Ok(())
+}
+
+#[test]
+fn test_unsynced_writes() -> Result<()> {
+    let dir = tempdir()?;
+    let file = dir.path().to_path_buf().join("logfile");
+    let unsynced = WriteOptions {
+        sync: false,
+        ..WriteOptions::default()
+    };
+
+    let mut db = Db::new(&file)?;
+    for i in 0..10 {
+        db.set_with_options(&format!("bulk{}", i), "v", &unsynced)?;
+    }
+    db.delete_with_options("bulk0", &unsynced)?;
+    let metrics = db.metrics();
+    assert_eq!(metrics.batches, 11);
+    assert_eq!(metrics.sync_latency.count(), 0);
+    assert_eq!(db.get("bulk1"), Some("v".into()));
+
+    // The next synced write takes everything before it along.
+    db.set("critical", "v")?;
+    assert_eq!(db.metrics().sync_latency.count(), 1);
+    db.set_with_options("late", "v", &unsynced)?;
+    assert_eq!(db.sync()?, 13);
+
+    drop(db);
+    let db = Db::new(&file)?;
+    assert_eq!(db.get("bulk0"), None);
+    assert_eq!(db.get("bulk9"), Some("v".into()));
+    assert_eq!(db.get("late"), Some("v".into()));
+
+    Ok(())
+}
+
+#[test]
+fn test_watermarks() -> Result<()> {
+    let dir = tempdir()?;
+    let file = dir.path().join("logfile");
+    let unsynced = WriteOptions {
+        sync: false,
+        ..WriteOptions::default()
+    };
+
+    let mut db = Db::new(&file)?;
+    assert_eq!((db.last_durable_seq(), db.last_applied_seq()), (0, 0));
+    for i in 0..3 {
+        db.set_with_options(&format!("bulk{}", i), "v", &unsynced)?;
+    }
+    assert_eq!((db.last_durable_seq(), db.last_applied_seq()), (0, 3));
+    // Waiting for a record that hasn't been written yet waits for it to be,
+    // and then syncs it if nothing else is going to.
+    let waiter = {
+        let db = db.clone();
+        std::thread::spawn(move || db.wait_for_durable(5))
+    };
+    db.set("synced", "v")?;
+    assert_eq!((db.last_durable_seq(), db.last_applied_seq()), (4, 4));
+    db.set_with_options("late", "v", &unsynced)?;
+    waiter.join().unwrap()?;
+    assert_eq!((db.last_durable_seq(), db.last_applied_seq()), (5, 5));
+    db.wait_for_durable(2)?;
+
+    // Everything is durable after a clean shutdown, but after a crash, only
+    // once it's been synced again.
+    drop(db);
+    let db = Db::new(&file)?;
+    assert_eq!((db.last_durable_seq(), db.last_applied_seq()), (5, 5));
+    std::mem::forget(db);
+    let db = Db::with_options(
+        &file,
+        Options {
+            lock: LockPolicy::Force,
+            ..Options::default()
+        },
+    )?;
+    assert_eq!((db.last_durable_seq(), db.last_applied_seq()), (0, 5));
+    db.wait_for_durable(5)?;
+    assert_eq!(db.last_durable_seq(), 5);
+    Ok(())
+}
+
+#[test]
+fn test_on_commit() -> Result<()> {
+    let dir = tempdir()?;
+    let unsynced = WriteOptions {
+        sync: false,
+        ..WriteOptions::default()
+    };
+    let cases = [
+        (false, CommitCallbacks::BeforeRelease),
+        (false, CommitCallbacks::AfterRelease),
+        (true, CommitCallbacks::BeforeRelease),
+        (true, CommitCallbacks::AfterRelease),
+    ];
+    for (i, (pipeline_commits, commit_callbacks)) in cases.into_iter().enumerate() {
+        let options = Options {
+            pipeline_commits,
+            commit_callbacks,
+            ..Options::default()
+        };
+        let mut db = Db::with_options(dir.path().join(format!("log{}", i)), options)?;
+        db.set("early", "v")?;
+        let seen = Arc::new(Mutex::new(vec![]));
+        {
+            let seen = seen.clone();
+            db.on_commit(move |seq, commands| {
+                let keys = commands.iter().filter_map(Command::key);
+                let keys = keys.map(str::to_owned).collect::<Vec<_>>();
+                seen.lock().unwrap().push((seq, keys));
+            });
+        }
+        let seen = || seen.lock().unwrap().clone();
+
+        // A batch that isn't synced waits for the next one that is.
+        db.set_with_options("a", "1", &unsynced)?;
+        assert_eq!(seen(), vec![]);
+        db.set("b", "2")?;
+        assert_eq!(
+            seen(),
+            vec![(2, vec!["a".to_owned()]), (3, vec!["b".to_owned()])]
+        );
+        // Or for `sync`.
+        db.set_with_options("c", "3", &unsynced)?;
+        db.sync()?;
+        assert_eq!(seen().len(), 3);
+        assert_eq!(seen()[2], (4, vec!["c".to_owned()]));
+
+        // Writes that skip group commit are heard about too.
+        let mut txn = db.transaction();
+        txn.set("d", "4");
+        txn.commit()?;
+        assert_eq!(seen().len(), 4);
+        assert_eq!(seen()[3].0, 5);
+        db.close()?;
+    }
+    Ok(())
+}
+
+#[test]
+fn test_cursors() -> Result<()> {
+    let dir = tempdir()?;
+    let file = dir.path().join("logfile");
+    let options = Options {
+        segment_size: 4096,
+        memtable_bytes: Some(1024),
+        ..Options::default()
+    };
+    let mut db = Db::with_options(&file, options.clone())?;
+    assert_eq!(db.load_cursor("cdc"), None);
+    db.set("k", "v")?;
+    db.save_cursor("cdc", 1)?;
+    db.save_cursor("audit", 0)?;
+    db.save_cursor("cdc", 2)?;
+    assert_eq!(db.load_cursor("cdc"), Some(2));
+    assert_eq!(db.load_cursor("audit"), Some(0));
+    // They're kept out of the way of everything else.
+    assert_eq!(db.len(), 1);
+    assert!(db.cf_names().is_empty());
+    db.remove_cursor("audit")?;
+    assert_eq!(db.load_cursor("audit"), None);
+
+    // And survive flushes, compactions and reopening.
+    for i in 0..50 {
+        db.set(&format!("key{}", i), &"v".repeat(50))?;
+    }
+    db.compact()?;
+    drop(db);
+    let db = Db::with_options(&file, options)?;
+    assert_eq!(db.load_cursor("cdc"), Some(2));
+    assert_eq!(db.load_cursor("audit"), None);
+    Ok(())
+}
+
+#[test]
+fn test_retention() -> Result<()> {
+    let dir = tempdir()?;
+    // The sequence number of the first record `read_log_from` can get to.
+    let oldest = |db: &Db| -> Result<u64> {
+        let chunk = db.read_log_from(LogOffset::START, 1)?;
+        Ok(segment::decode_record(&chunk.records).unwrap().0.seq)
+    };
+
+    // A consumer's cursor keeps the log from where it's got to.
+    let file = dir.path().join("cursors");
+    let options = Options {
+        segment_size: 512,
+        retention: Retention {
+            cursors: true,
+            ..Retention::default()
+        },
+        ..Options::default()
+    };
+    let mut db = Db::with_options(&file, options.clone())?;
+    for i in 0..30 {
+        db.set(&format!("key{}", i), "v")?;
+        db.log_custom(&i)?;
+    }
+    db.save_cursor("cdc", 40)?;
+    db.compact()?;
+    let kept = oldest(&db)?;
+    assert!(kept > 1 && kept <= 41, "{}", kept);
+    assert_eq!(db.len(), 30);
+    drop(db);
+
+    // Replay skips what's only kept for the consumer, and reading the log
+    // doesn't.
+    let mut customs = 0;
+    let mut db = Db::with_custom_handler(&file, options.clone(), |_: i32| {
+        customs += 1;
+        Ok::<_, Error>(())
+    })?;
+    assert_eq!(customs, 0);
+    assert_eq!(db.len(), 30);
+    assert_eq!(oldest(&db)?, kept);
+
+    // Until the consumer falls too far behind.
+    db.set("more", "v")?;
+    assert_eq!(db.expire_cursors(100)?, Vec::<String>::new());
+    assert_eq!(oldest(&db)?, kept);
+    assert_eq!(db.expire_cursors(10)?, vec!["cdc".to_owned()]);
+    assert!(oldest(&db)? > 60);
+    assert_eq!(db.get("key7"), Some("v".into()));
+
+    // Or for a number of bytes.
+    let file = dir.path().join("bytes");
+    let options = Options {
+        segment_size: 512,
+        retention: Retention {
+            bytes: Some(1024),
+            ..Retention::default()
+        },
+        ..Options::default()
+    };
+    let mut db = Db::with_options(&file, options)?;
+    for i in 0..30 {
+        db.set(&format!("key{}", i), "v")?;
+    }
+    db.compact()?;
+    let kept = oldest(&db)?;
+    assert!(kept > 1 && kept < 30, "{}", kept);
+    Ok(())
+}
+
+// Restarting replays the snapshot the last compaction wrote and whatever came
+// after it, however much history retention keeps on disk from before it.
+#[test]
+fn test_restart_from_checkpoint() -> Result<()> {
+    let dir = tempdir()?;
+    let file = dir.path().join("db");
+    let options = Options {
+        segment_size: 4096,
+        retention: Retention {
+            bytes: Some(u64::MAX),
+            ..Retention::default()
+        },
+        ..Options::default()
+    };
+    let mut segments = 0;
+    for round in 0..4 {
+        let mut db = Db::with_options(&file, options.clone())?;
+        for i in 0..1000 {
+            db.set(&format!("key{}", i % 20), &format!("{}/{}", round, i))?;
+        }
+        db.compact()?;
+        for i in 0..5 {
+            db.set(&format!("new{}", i), "v")?;
+        }
+        drop(db);
+        // The manifest is enough to go by without the START file.
+        std::fs::remove_file(file.join("START"))?;
+        let db = Db::with_options(&file, options.clone())?;
+        // The snapshot has the 20 keys, plus the 5 others from the round
+        // before, and then the 5 written after it.
+        let snapshot = if round == 0 { 20 } else { 25 };
+        assert_eq!(db.recovery_report().records, snapshot + 5);
+        assert_eq!(db.get("key19"), Some(format!("{}/999", round)));
+        assert_eq!(db.len(), 25);
+        let (kept, _) = segment::list(&file)?;
+        assert!(kept.len() > segments, "{:?}", kept);
+        segments = kept.len();
+    }
+    Ok(())
+}
+
+#[test]
+fn test_log_shards() -> Result<()> {
+    let dir = tempdir()?;
+    let file = dir.path().to_path_buf().join("logfile");
+    let options = Options {
+        segment_size: 4096,
+        memtable_bytes: Some(2048),
+        log_shards: 3,
+        ..Options::default()
+    };
+    let db = Db::with_options(&file, options.clone())?;
+    let writers = (0..4)
+        .map(|t| {
+            let mut db = db.clone();
+            std::thread::spawn(move || -> Result<()> {
+                for i in 0..50 {
+                    db.set(&format!("{}_{}", t, i), &format!("v{}", i))?;
+                    db.incr("counter", 1)?;
+                    db.append("list", &t.to_string())?;
+                }
+                Ok(())
+            })
+        })
+        .collect::<Vec<_>>();
+    for writer in writers {
+        writer.join().unwrap()?;
+    }
+    assert!(file.join("shard-1").is_dir() && file.join("shard-2").is_dir());
+    assert!(db.stats()?.tables > 0);
+    assert_eq!(db.get("counter"), Some("200".into()));
+    assert_eq!(db.get_list("list").map(|l| l.len()), Some(200));
+    // Whichever shard each record went to, they read back as one sequence.
+    let seqs = LogReader::open(&file)?
+        .map(|record| Ok(record?.1))
+        .collect::<Result<Vec<_>>>()?;
+    assert!(seqs.windows(2).all(|w| w[1] == w[0] + 1));
+    assert_eq!(seqs.last(), Some(&(db.next_seq() - 1)));
+    let last = db.next_seq() - 1;
+    assert_eq!((db.last_durable_seq(), db.last_applied_seq()), (last, last));
+    drop(db);
+
+    let check = |db: &Db| {
+        for t in 0..4 {
+            for i in 0..50 {
+                assert_eq!(db.get(&format!("{}_{}", t, i)), Some(format!("v{}", i)));
+            }
+        }
+        assert_eq!(db.get("counter"), Some("200".into()));
+        assert_eq!(db.get_list("list").map(|l| l.len()), Some(200));
+    };
+    let db = Db::with_options(&file, options.clone())?;
+    check(&db);
+    drop(db);
+
+    // Going back to one shard still reads the others.
+    let one = Options {
+        log_shards: 1,
+        ..options
+    };
+    let mut db = Db::with_options(&file, one.clone())?;
+    check(&db);
+    db.set("after", "v")?;
+    db.compact()?;
+    drop(db);
+    let db = Db::with_options(&file, one)?;
+    check(&db);
+    assert_eq!(db.get("after"), Some("v".into()));
+    drop(db);
+
+    assert!(matches!(
+        LogTailer::open(&file),
+        Err(Error::InvalidConfig(_))
+    ));
+    assert!(matches!(
+        Db::open_read_only(&file),
+        Err(Error::InvalidConfig(_))
+    ));
+    assert!(matches!(Db::repair(&file), Err(Error::InvalidConfig(_))));
+    Db::destroy(&file)?;
+    assert!(!file.exists());
+    Ok(())
+}
+
+#[test]
+fn test_pipeline_commits() -> Result<()> {
+    let dir = tempdir()?;
+    let file = dir.path().join("logfile");
+    let options = Options {
+        segment_size: 4096,
+        memtable_bytes: Some(2048),
+        pipeline_commits: true,
+        ..Options::default()
+    };
+    let db = Db::with_options(&file, options.clone())?;
+    let writers = (0..4)
+        .map(|t| {
+            let mut db = db.clone();
+            std::thread::spawn(move || -> Result<()> {
+                let key = |request_id| IdempotencyKey {
+                    client_id: t,
+                    request_id,
+                };
+                for i in 0..50 {
+                    db.set(&format!("{}_{}", t, i), &format!("v{}", i))?;
+                    db.incr("counter", 1)?;
+                    // A retry is still caught when it lands in the batch
+                    // right after the write it repeats.
+                    let options = WriteOptions {
+                        idempotency_key: Some(key(i)),
+                        ..WriteOptions::default()
+                    };
+                    for value in ["first", "retried"] {
+                        db.set_with_options(&format!("r{}_{}", t, i), value, &options)?;
+                    }
+                }
+                Ok(())
+            })
+        })
+        .collect::<Vec<_>>();
+    for writer in writers {
+        writer.join().unwrap()?;
+    }
+    let seqs = LogReader::open(&file)?
+        .map(|record| Ok(record?.1))
+        .collect::<Result<Vec<_>>>()?;
+    assert!(seqs.windows(2).all(|w| w[1] == w[0] + 1));
+    // Once every write has returned, each is both applied and durable.
+    let last = db.next_seq() - 1;
+    assert_eq!((db.last_durable_seq(), db.last_applied_seq()), (last, last));
+    let check = |db: &Db| {
+        for t in 0..4 {
+            for i in 0..50 {
+                assert_eq!(db.get(&format!("{}_{}", t, i)), Some(format!("v{}", i)));
+                assert_eq!(db.get(&format!("r{}_{}", t, i)), Some("first".into()));
+            }
+        }
+        assert_eq!(db.get("counter"), Some("200".into()));
+    };
+    check(&db);
+    assert!(db.stats()?.tables > 0);
+    drop(db);
+    check(&Db::with_options(&file, options)?);
+    Ok(())
+}
+
+// Staging batches while they sync leaves the database, and snapshots of it,
+// just as applying them afterwards would.
+#[test]
+fn test_apply_during_sync() -> Result<()> {
+    let dir = tempdir()?;
+    let run = |path: PathBuf, apply_during_sync| -> Result<_> {
+        let options = Options {
+            memtable_shards: 4,
+            apply_during_sync,
+            ..Options::default()
+        };
+        let mut db = Db::with_options(&path, options.clone())?;
+        for i in 0..20 {
+            db.set(&format!("k{}", i), &i.to_string())?;
+        }
+        let snapshot = db.snapshot();
+        let request = WriteOptions {
+            idempotency_key: Some(IdempotencyKey {
+                client_id: 1,
+                request_id: 1,
+            }),
+            ..WriteOptions::default()
+        };
+        for value in ["first", "retried"] {
+            db.set_with_options("k1", value, &request)?;
+        }
+        db.delete("k2")?;
+        db.incr("k3", 10)?;
+        db.append("list", "a")?;
+        db.append("list", "b")?;
+        let mut txn = db.transaction();
+        for i in 4..12 {
+            txn.set(&format!("k{}", i), "txn");
+        }
+        txn.delete("k12");
+        txn.commit()?;
+        db.delete_prefix("k19")?;
+        db.cf("ks").set("k", "v")?;
+        let state = (db.scan(""), snapshot.scan(""), db.cf("ks").get("k"));
+        drop(snapshot);
+        drop(db);
+        let db = Db::with_options(&path, options)?;
+        assert_eq!(db.scan(""), state.0);
+        Ok(state)
+    };
+    let staged = run(dir.path().join("staged"), Some(1))?;
+    assert_eq!(staged, run(dir.path().join("applied"), None)?);
+    assert_eq!(staged.0.len(), 18);
+    assert_eq!(staged.1.len(), 20);
+    Ok(())
+}
+
+#[test]
+fn test_log_shards_gap() -> Result<()> {
+    let dir = tempdir()?;
+    let file = dir.path().to_path_buf().join("logfile");
+    let options = Options {
+        log_shards: 2,
+        ..Options::default()
+    };
+    let mut db = Db::with_options(&file, options.clone())?;
+    for i in 0..10 {
+        db.set(&format!("k{}", i), "v")?;
+    }
+    drop(db);
+
+    // As if the process died while the write at seq 6 was being synced, and
+    // the one after it, in the other shard, already had been.
+    let (at, _, _) = LogReader::open(&file)?
+        .find(|record| matches!(record, Ok((_, 6, _))))
+        .unwrap()?;
+    let shard = (0..2)
+        .map(|i| log::shard_dir(&file, i))
+        .find(|dir| segment::segment_path(dir, at.segment).exists())
+        .unwrap();
+    segment::zero_from(&shard, at.segment, at.offset)?;
+
+    let mut db = Db::with_options(&file, options.clone())?;
+    assert_eq!(db.len(), 5);
+    assert_eq!(db.get("k6"), None);
+    assert_eq!(db.recovery_report().torn.len(), 1);
+    assert_eq!(db.next_seq(), 6);
+    db.set("k5", "again")?;
+    drop(db);
+
+    // What came after the gap is gone for good.
+    let db = Db::with_options(&file, options)?;
+    assert_eq!(db.len(), 6);
+    assert_eq!(db.get("k5"), Some("again".into()));
+    assert_eq!(db.get("k6"), None);
+    Ok(())
+}
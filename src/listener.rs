@@ -0,0 +1,181 @@
+#[cfg(test)]
+use crate::{Db, Options, Result};
+use crate::{Error, RecoveryReport};
+#[cfg(test)]
+use std::sync::{Arc, Mutex};
+use std::{fmt::Debug, ops::Range, time::Duration};
+#[cfg(test)]
+use tempfile::tempdir;
+
+// Hooks for logging and alerting, set with `Options::listener`. Every method
+// does nothing by default, so implementations only need the ones they want.
+//
+// The callbacks run on whichever thread did the work, some of them while the
+// log is locked, so they should be quick and must not write to the database
+// themselves.
+pub trait DbListener: Debug + Send + Sync {
+    // A batch covering `seqs` has been written, and synced unless every
+    // write in it asked not to be, taking `bytes` of the log. Records
+    // received from a primary count too.
+    fn on_batch_committed(&self, seqs: Range<u64>, bytes: usize) {
+        let _ = (seqs, bytes);
+    }
+
+    // Segment `sealed` filled up or was cut short, and the log moved on to
+    // segment `next`.
+    fn on_segment_rotated(&self, sealed: u64, next: u64) {
+        let _ = (sealed, next);
+    }
+
+    // The log has been compacted, retiring the segments in `retired`, either
+    // by `compact` or because the memtable was flushed.
+    fn on_compaction_finished(&self, retired: &[u64]) {
+        let _ = retired;
+    }
+
+    // Writing to or syncing the log's mirror failed with `error`, and the log
+    // has carried on without it, as `MirrorPolicy::Primary` has it do.
+    fn on_mirror_failed(&self, error: &Error) {
+        let _ = error;
+    }
+
+    // The database has been opened and the log replayed.
+    fn on_recovery_complete(&self, report: &RecoveryReport) {
+        let _ = report;
+    }
+
+    // A sync took longer than `Options::slow_sync`.
+    fn on_slow_sync(&self, sync: &SlowSync) {
+        let _ = sync;
+    }
+
+    // A writer waited longer than `Options::write_stall` for its write to
+    // commit.
+    fn on_write_stall(&self, stall: &WriteStall) {
+        let _ = stall;
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SlowSync {
+    pub duration: Duration,
+    // The batch that was being synced.
+    pub batch_size: usize,
+    pub bytes: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WriteStall {
+    // From the write being made until it was committed.
+    pub waited: Duration,
+    // How many writes were waiting for the next batch, this one included,
+    // when it joined them. A writer that led its batch counts as one.
+    pub queue_depth: usize,
+}
+
+#[cfg(test)]
+#[derive(Debug, Default)]
+struct Recorder {
+    events: Mutex<Vec<String>>,
+}
+
+#[cfg(test)]
+impl DbListener for Recorder {
+    fn on_batch_committed(&self, seqs: Range<u64>, _: usize) {
+        let event = format!("committed {:?}", seqs);
+        self.events.lock().unwrap().push(event);
+    }
+
+    fn on_segment_rotated(&self, sealed: u64, next: u64) {
+        let event = format!("rotated {} {}", sealed, next);
+        self.events.lock().unwrap().push(event);
+    }
+
+    fn on_compaction_finished(&self, retired: &[u64]) {
+        let event = format!("compacted {:?}", retired);
+        self.events.lock().unwrap().push(event);
+    }
+
+    fn on_recovery_complete(&self, report: &RecoveryReport) {
+        let event = format!("recovered {}", report.records);
+        self.events.lock().unwrap().push(event);
+    }
+
+    fn on_slow_sync(&self, sync: &SlowSync) {
+        let event = format!("slow sync {}", sync.batch_size);
+        self.events.lock().unwrap().push(event);
+    }
+
+    fn on_write_stall(&self, stall: &WriteStall) {
+        let event = format!("stalled {}", stall.queue_depth);
+        self.events.lock().unwrap().push(event);
+    }
+}
+
+#[test]
+fn test_listener() -> Result<()> {
+    let dir = tempdir()?;
+    let file = dir.path().join("logfile");
+    let recorder = Arc::new(Recorder::default());
+    let options = Options {
+        listener: Some(recorder.clone()),
+        slow_sync: None,
+        write_stall: None,
+        ..Options::default()
+    };
+    let mut db = Db::with_options(&file, options.clone())?;
+    db.set("a", "1")?;
+    db.set("b", "2")?;
+    db.compact()?;
+    drop(db);
+    Db::with_options(&file, options)?;
+    assert_eq!(
+        *recorder.events.lock()?,
+        [
+            "recovered 0",
+            "committed 1..2",
+            "committed 2..3",
+            "rotated 1 2",
+            "compacted [1]",
+            "recovered 2",
+        ]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_warnings() -> Result<()> {
+    let dir = tempdir()?;
+    let recorder = Arc::new(Recorder::default());
+    let options = Options {
+        listener: Some(recorder.clone()),
+        slow_sync: Some(Duration::ZERO),
+        write_stall: Some(Duration::ZERO),
+        ..Options::default()
+    };
+    let mut db = Db::with_options(dir.path().join("logfile"), options)?;
+    recorder.events.lock()?.clear();
+    db.set("a", "1")?;
+    assert_eq!(
+        *recorder.events.lock()?,
+        ["committed 1..2", "slow sync 1", "stalled 1"]
+    );
+
+    // Every writer is warned about, whether it led its batch or not.
+    recorder.events.lock()?.clear();
+    let threads = (0..8)
+        .map(|i| {
+            let mut db = db.clone();
+            std::thread::spawn(move || db.set(&i.to_string(), "v"))
+        })
+        .collect::<Vec<_>>();
+    for thread in threads {
+        thread.join().unwrap()?;
+    }
+    let events = recorder.events.lock()?;
+    let stalls = events.iter().filter(|e| e.starts_with("stalled")).count();
+    assert_eq!(stalls, 8);
+
+    Ok(())
+}
@@ -0,0 +1,1218 @@
+use crate::durable_fs;
+use crate::error;
+#[cfg(feature = "failpoints")]
+use crate::failpoint::{self, Failpoint};
+use crate::manifest::{self, Edit, Manifest, State};
+use crate::mirror;
+use crate::segment::{self, SegmentReader, SegmentSync, SegmentWriter, FORMAT_VERSION};
+use crate::table;
+use crate::value_log;
+use crate::{Durability, Error, LockPolicy, MirrorPolicy, Options, Result, Retention};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::{self, File, OpenOptions, TryLockError},
+    io::{self, Write},
+    panic,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+    time::SystemTime,
+};
+
+// The log is a directory of numbered segments. Only the highest-numbered
+// segment is ever written to; once it fills up we seal it and move on to a new
+// one. Segments that are no longer needed (because a compaction has written
+// their contents out again) are kept around as `.recycle` files so that the
+// next rotation can reuse their already-allocated space, unless
+// `Options::retention` keeps them for readers of the log a while longer.
+//
+// Only one process can have a log open for writing at a time, which is
+// enforced with an advisory lock on a `LOCK` file in the directory. A log that
+// is opened read-only has no active segment, and never touches the directory.
+//
+// Which segments make up the log is recorded in its `MANIFEST` as they're
+// created and retired (see `manifest`), and recovery goes by that.
+//
+// Closing the log syncs it and leaves a `CLEAN` file behind, which the next
+// open takes as a sign that nothing was cut short (see `CleanShutdown`). It's
+// removed as soon as the log is opened for writing again.
+//
+// A sharded log (see `Options::log_shards`) has one such chain of segments
+// per shard: the first in the directory itself, the others in `shard-<n>`
+// directories inside it. Segment numbers are shared, so each one is unique
+// across the whole log. Batches go to the shards in turn, each numbered from
+// where the one before it left off, so replay merges the shards back into a
+// single sequence. A crash can leave a later batch on disk without the one
+// before it, in which case the log is cut back to the gap when it's opened.
+// Once sharded, a log stays sharded: lowering the shard count only stops
+// batches going to the shards past it.
+//
+// A mirrored log (see `Options::log_mirror`) writes each segment a second
+// time in the mirror's directory, with the same number. See `mirror` for how
+// the copies are brought back into line after a crash.
+#[derive(Debug)]
+pub struct Log {
+    dir: PathBuf,
+    options: Options,
+    shards: Vec<Shard>,
+    // The shard the last batch went to.
+    current: usize,
+    // How many of the shards batches go to.
+    writing: usize,
+    next_number: u64,
+    // The sequence number the next record appended gets.
+    next_seq: u64,
+    // The sequence number of the oldest record still in the log.
+    first_seq: u64,
+    // Set along with any segment that fails, so that none are written to
+    // after that.
+    failed: Arc<AtomicBool>,
+    mirror: Option<Mirror>,
+    // None if the log is open read-only.
+    manifest: Option<Manifest>,
+    // Set by `close`, after which nothing more can be written.
+    closed: bool,
+    // Where the last batch went: each segment it was written to, and how far
+    // through the batch it got in that one.
+    placed: Vec<(u64, usize)>,
+    // Held for as long as the log is open; dropping it releases the lock.
+    lock: Option<File>,
+}
+
+#[derive(Debug)]
+struct Shard {
+    dir: PathBuf,
+    active: Option<SegmentWriter>,
+    sealed: Vec<u64>,
+    recycled: Vec<u64>,
+    // Sealed segments that were never synced, because the log's durability
+    // is `None`, and that `barrier` has yet to catch up on.
+    unsynced: Vec<u64>,
+    // Whether the active segment has been written to since it was last
+    // synced.
+    dirty: bool,
+    // Sealed segments before this one were compacted away, and are only
+    // still here for `Options::retention`. Replay skips them.
+    replayed_from: u64,
+}
+
+impl Shard {
+    // Finds the segments shard `i` keeps in `dir`, if it has been written to
+    // before, going by `state` if there's a manifest (see `manifest::list`).
+    fn open(
+        dir: PathBuf,
+        i: usize,
+        existing: bool,
+        state: Option<&State>,
+        tidy: bool,
+        options: &Options,
+    ) -> Result<Self> {
+        let (sealed, recycled) = match existing {
+            true => manifest::list(&dir, i, state, tidy)?,
+            false => (vec![], vec![]),
+        };
+        let unsynced = match options.durability {
+            Durability::None => sealed.clone(),
+            _ => vec![],
+        };
+        Ok(Shard {
+            dir,
+            active: None,
+            sealed,
+            recycled,
+            unsynced,
+            dirty: false,
+            replayed_from: 0,
+        })
+    }
+
+    // Skips over the segments that only hold records from before `start`,
+    // which a compaction has replaced, and returns the rest.
+    fn replayed(&mut self, start: u64) -> Result<Vec<u64>> {
+        let mut skip = 0;
+        if start > 0 {
+            for &number in self.sealed.iter().skip(1) {
+                match SegmentReader::open(&self.dir, number)?.first_seq() {
+                    Some(seq) if seq <= start => skip += 1,
+                    _ => break,
+                }
+            }
+        }
+        if skip > 0 {
+            self.replayed_from = self.sealed[skip];
+        }
+        Ok(self.sealed[skip..].to_vec())
+    }
+
+    // The oldest sealed segment that `retention` says to keep, or None if
+    // it doesn't say to keep any. A segment's records run up to where the
+    // next one's start, the last of them up to `end`, `cursor` is the oldest
+    // record a consumer still needs, and segments' ages are as of `now`.
+    fn kept(
+        &self,
+        retention: &Retention,
+        cursor: Option<u64>,
+        end: u64,
+        now: SystemTime,
+    ) -> Result<Option<u64>> {
+        let mut bytes = 0;
+        let mut kept = None;
+        let mut next_first = Some(end);
+        for &number in self.sealed.iter().rev() {
+            let path = segment::segment_path(&self.dir, number);
+            let metadata = fs::metadata(&path)?;
+            let for_bytes = retention.bytes.is_some_and(|max| bytes < max);
+            let for_age = retention.age.is_some_and(|age| {
+                let written = metadata.modified().unwrap_or(now);
+                now.duration_since(written).unwrap_or_default() < age
+            });
+            let for_cursors = match (retention.cursors, cursor) {
+                (true, Some(cursor)) => next_first.is_none_or(|first| first > cursor),
+                _ => false,
+            };
+            if for_bytes || for_age || for_cursors {
+                kept = Some(number);
+            }
+            bytes += metadata.len();
+            next_first = SegmentReader::open(&self.dir, number)?.first_seq();
+        }
+        Ok(kept)
+    }
+
+    // Syncs the whole shard, as `durability` says to.
+    fn barrier(&mut self, durability: Durability) -> Result<()> {
+        for &number in &self.unsynced {
+            let file = File::open(segment::segment_path(&self.dir, number))?;
+            durable_fs::sync_file(&file, durability)?;
+        }
+        self.unsynced.clear();
+        self.dirty = false;
+        if let Some(active) = &self.active {
+            active.sync_with(durability)?;
+        }
+        Ok(())
+    }
+
+    // Seals the active segment, which has to be synced first, and puts
+    // `active` in its place. Returns the number of the segment sealed.
+    fn rotate(&mut self, active: SegmentWriter, options: &Options) -> Option<u64> {
+        let sealed = self.active.replace(active)?.number();
+        self.sealed.push(sealed);
+        if options.durability == Durability::None {
+            self.unsynced.push(sealed);
+        }
+        self.dirty = false;
+        Some(sealed)
+    }
+
+    // Retires every sealed segment before `first`, oldest first, and returns
+    // their numbers.
+    fn retire_before(&mut self, first: u64, options: &Options) -> Result<Vec<u64>> {
+        let before = self.sealed.iter().take_while(|&&n| n < first).count();
+        let retired = self.sealed.drain(..before).collect::<Vec<_>>();
+        for &number in &retired {
+            self.unsynced.retain(|&n| n != number);
+            let path = segment::segment_path(&self.dir, number);
+            if self.recycled.len() < options.max_recycled_segments {
+                durable_fs::rename(&path, &segment::recycled_path(&self.dir, number))?;
+                self.recycled.push(number);
+            } else {
+                durable_fs::remove_file(&path)?;
+            }
+        }
+        Ok(retired)
+    }
+}
+
+// The copy of the log kept in `Options::log_mirror`. Its segments are numbered
+// and filled just like the first shard's, which is the only one there is.
+#[derive(Debug)]
+struct Mirror {
+    shard: Shard,
+    // The failure flag its segments share: the log's own under
+    // `MirrorPolicy::Both`, and one of their own otherwise.
+    failed: Arc<AtomicBool>,
+    // Held on the mirror's directory, like `Log::lock`.
+    _lock: Option<File>,
+}
+
+// The segments of each of a log's shards, oldest first, for replay to merge.
+#[derive(Debug, Clone, Default)]
+pub struct Shards {
+    pub dirs: Vec<PathBuf>,
+    pub segments: Vec<Vec<u64>>,
+    // Records before this one were compacted away, though a crash partway
+    // through retiring their segments can leave some of them behind.
+    pub start: u64,
+}
+
+// Where shard `i` of the log in `dir` keeps its segments.
+pub fn shard_dir(dir: &Path, i: usize) -> PathBuf {
+    match i {
+        0 => dir.to_path_buf(),
+        i => dir.join(format!("shard-{}", i)),
+    }
+}
+
+// What `Log::close` leaves in the `CLEAN` file: where the records ended, which
+// saves reading the last segment through to find out, and a checksum of the
+// memtable if a `Db` closed it (see `Memtable::checksum`).
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CleanShutdown {
+    pub segment: u64,
+    pub offset: u64,
+    pub next_seq: u64,
+    pub memtable: Option<u32>,
+}
+
+fn create_segment(
+    dir: &Path,
+    number: u64,
+    first_seq: u64,
+    reuse: Option<u64>,
+    options: &Options,
+    failed: &Arc<AtomicBool>,
+    mirror: bool,
+) -> Result<SegmentWriter> {
+    let mut segment = SegmentWriter::create(
+        dir,
+        number,
+        first_seq,
+        options.codec.id(),
+        options.segment_size,
+        reuse,
+        options.durability,
+    )?;
+    segment.share_failure(failed.clone());
+    #[cfg(feature = "failpoints")]
+    segment.set_failpoints(options.failpoints.clone(), mirror);
+    #[cfg(not(feature = "failpoints"))]
+    let _ = mirror;
+    Ok(segment)
+}
+
+impl Log {
+    // Opens the log in `dir`, handing the existing segments to `replay` in
+    // order. Writing always resumes in a fresh segment so that we never append
+    // after a torn tail. Sharded logs are left to `open_shards`.
+    pub fn open<F>(dir: &Path, options: Options, replay: F) -> Result<Self>
+    where
+        F: FnOnce(&[u64]) -> Result<()>,
+    {
+        Self::open_shards(dir, options, |shards| {
+            Self::unsharded(dir, shards)?;
+            replay(&shards.segments[0])?;
+            Ok(None)
+        })
+    }
+
+    // Like `open`, but for a log that may be sharded. `replay` returns where
+    // the sequence picks up again if it stopped at a gap between shards,
+    // and everything from there on is dropped, or `None` if it got to the
+    // end of a log that isn't sharded.
+    pub fn open_shards<F>(dir: &Path, options: Options, replay: F) -> Result<Self>
+    where
+        F: FnOnce(&Shards) -> Result<Option<u64>>,
+    {
+        if dir.is_file() {
+            return Err(Error::InvalidConfig(format!(
+                "{} is a file, not a log directory; if it's a log of JSON lines, \
+                 `Db::migrate` turns it into one",
+                dir.display()
+            )));
+        }
+        durable_fs::create_dir_all(dir)?;
+        let lock = Self::lock(dir, options.lock)?;
+        let read_only = lock.is_none() && options.lock == LockPolicy::ReadOnly;
+        Self::open_segments(dir, options, lock, read_only, replay)
+    }
+
+    // Whether the log in `dir` has more than one shard.
+    pub fn is_sharded(dir: &Path) -> bool {
+        shard_dir(dir, 1).is_dir()
+    }
+
+    fn unsharded(dir: &Path, shards: &Shards) -> Result<()> {
+        if shards.dirs.len() > 1 {
+            return Err(Error::InvalidConfig(format!(
+                "the log in {} is sharded, which only a Db opened for writing can read",
+                dir.display()
+            )));
+        }
+        Ok(())
+    }
+
+    // Opens the log in `dir` without taking the lock or writing anything, so
+    // that it can be read while another process has it open for writing.
+    pub fn open_read_only<F>(dir: &Path, options: Options, replay: F) -> Result<Self>
+    where
+        F: FnOnce(&[u64]) -> Result<()>,
+    {
+        if !dir.is_dir() {
+            return Err(Error::InvalidConfig(format!(
+                "{} is not a log directory",
+                dir.display()
+            )));
+        }
+        Self::open_segments(dir, options, None, true, |shards| {
+            Self::unsharded(dir, shards)?;
+            replay(&shards.segments[0])?;
+            Ok(None)
+        })
+    }
+
+    fn open_segments<F>(
+        dir: &Path,
+        options: Options,
+        lock: Option<File>,
+        read_only: bool,
+        replay: F,
+    ) -> Result<Self>
+    where
+        F: FnOnce(&Shards) -> Result<Option<u64>>,
+    {
+        let existing = (1..).take_while(|&i| shard_dir(dir, i).is_dir()).count() + 1;
+        let count = existing.max(options.log_shards);
+        // Healing may bring segments over from the mirror, so it comes first.
+        let mut mirror = match (&options.log_mirror, read_only) {
+            (Some(path), false) => Some(Self::open_mirror(dir, path, count, &options)?),
+            _ => None,
+        };
+        let state = Manifest::load(dir)?;
+        let start = Self::start(dir, state.as_ref())?;
+        let mut shards = (0..count)
+            .map(|i| {
+                let tidy = !read_only;
+                Shard::open(
+                    shard_dir(dir, i),
+                    i,
+                    i < existing,
+                    state.as_ref(),
+                    tidy,
+                    &options,
+                )
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let mut manifest = match read_only {
+            true => None,
+            false => Some(Manifest::create(
+                dir,
+                &State {
+                    format: Some(manifest::Format {
+                        version: FORMAT_VERSION,
+                        codec: options.codec.id(),
+                        segment_size: options.segment_size,
+                        shards: shards.len(),
+                    }),
+                    segments: shards.iter().map(|s| s.sealed.clone()).collect(),
+                    snapshot: state.and_then(|s| s.snapshot),
+                    last_number: 0,
+                },
+            )?),
+        };
+        let segments = shards
+            .iter_mut()
+            .map(|shard| shard.replayed(start))
+            .collect::<Result<Vec<_>>>()?;
+        let cut = replay(&Shards {
+            dirs: shards.iter().map(|s| s.dir.clone()).collect(),
+            segments,
+            start,
+        })?;
+        let clean = Self::closed_cleanly(dir);
+        if !read_only && clean.is_some() {
+            durable_fs::remove_file(&dir.join("CLEAN"))?;
+        }
+        let mut next_number = shards
+            .iter()
+            .chain(mirror.iter().map(|m| &m.shard))
+            .flat_map(|s| s.sealed.iter().chain(s.recycled.iter()))
+            .max()
+            .map_or(1, |n| n + 1);
+        let next_seq = match cut {
+            Some(seq) => {
+                if let Some(manifest) = &mut manifest {
+                    for (i, shard) in shards.iter_mut().enumerate() {
+                        Self::cut(shard, i, seq, manifest)?;
+                    }
+                }
+                seq
+            }
+            None => Self::recover_next_seq(dir, &shards[0].sealed, clean)?,
+        };
+        // After a crash, the segments being written at the time may never have
+        // been synced, so the next barrier syncs every one of them.
+        if !read_only && clean.is_none() {
+            for shard in &mut shards {
+                shard.unsynced = shard.sealed.clone();
+            }
+        }
+        let mut first_seq = next_seq;
+        for shard in &shards {
+            for &number in &shard.sealed {
+                if let Some(seq) = SegmentReader::open(&shard.dir, number)?.first_seq() {
+                    first_seq = first_seq.min(seq.max(start));
+                    break;
+                }
+            }
+        }
+        let failed = Arc::new(AtomicBool::new(false));
+        let writing = options.log_shards.max(1);
+        if let Some(manifest) = &mut manifest {
+            for (i, shard) in shards[..writing].iter_mut().enumerate() {
+                durable_fs::create_dir_all(&shard.dir)?;
+                shard.active = Some(create_segment(
+                    &shard.dir,
+                    next_number,
+                    next_seq,
+                    shard.recycled.pop(),
+                    &options,
+                    &failed,
+                    false,
+                )?);
+                manifest.record(&[Edit::Add {
+                    shard: i,
+                    number: next_number,
+                }])?;
+                next_number += 1;
+            }
+        }
+        if let Some(mirror) = &mut mirror {
+            if options.mirror_policy == MirrorPolicy::Both {
+                mirror.failed = failed.clone();
+            }
+            let shard = &mut mirror.shard;
+            shard.active = Some(create_segment(
+                &shard.dir,
+                next_number - 1,
+                next_seq,
+                shard.recycled.pop(),
+                &options,
+                &mirror.failed,
+                true,
+            )?);
+        }
+        Ok(Log {
+            dir: dir.to_path_buf(),
+            options,
+            shards,
+            current: 0,
+            writing,
+            next_number,
+            next_seq,
+            first_seq,
+            failed,
+            mirror,
+            manifest,
+            closed: false,
+            placed: vec![],
+            lock,
+        })
+    }
+
+    // Locks the mirror in `path` of the log in `dir`, and heals the two
+    // copies of the log.
+    fn open_mirror(dir: &Path, path: &Path, shards: usize, options: &Options) -> Result<Mirror> {
+        if shards > 1 {
+            return Err(Error::InvalidConfig(format!(
+                "the log in {} is sharded, so it can't be mirrored",
+                dir.display()
+            )));
+        }
+        durable_fs::create_dir_all(path)?;
+        let policy = match options.lock {
+            LockPolicy::Force => LockPolicy::Force,
+            _ => LockPolicy::Fail,
+        };
+        let lock = Self::lock(path, policy)?;
+        mirror::heal(dir, path)?;
+        Ok(Mirror {
+            shard: Shard::open(path.to_path_buf(), 0, true, None, false, options)?,
+            failed: Arc::new(AtomicBool::new(false)),
+            _lock: lock,
+        })
+    }
+
+    // Drops every record from `seq` on from `shard`, since replay stopped
+    // short of them. Records only ever go forward within a shard, so this is
+    // only ever the end of it.
+    fn cut(shard: &mut Shard, i: usize, seq: u64, manifest: &mut Manifest) -> Result<()> {
+        let mut buf = vec![];
+        let mut removed = vec![];
+        for &number in shard.sealed.iter().rev() {
+            let mut reader = SegmentReader::open(&shard.dir, number)?;
+            reader.set_sparse(true);
+            let (mut first, mut from) = (None, None);
+            loop {
+                while let Some((offset, record)) = reader.next_record(&mut buf)? {
+                    first = first.or(Some(offset));
+                    if record >= seq {
+                        from = Some(offset);
+                        break;
+                    }
+                }
+                let damaged = reader.end().is_some_and(|end| !end.is_clean());
+                if from.is_some() || !damaged || !reader.resync()? {
+                    break;
+                }
+            }
+            match from {
+                // Everything in it comes after the cut.
+                Some(offset) if Some(offset) == first => removed.push(number),
+                Some(offset) => {
+                    segment::zero_from(&shard.dir, number, offset)?;
+                    break;
+                }
+                // It all comes before the cut, and so does everything before
+                // it.
+                None if first.is_some() => break,
+                // There's nothing in it, so look further back.
+                None => {}
+            }
+        }
+        let edits = removed
+            .iter()
+            .map(|&number| Edit::Remove { shard: i, number })
+            .collect::<Vec<_>>();
+        manifest.record(&edits)?;
+        for number in removed {
+            durable_fs::remove_file(&segment::segment_path(&shard.dir, number))?;
+            shard.sealed.retain(|&n| n != number);
+            shard.unsynced.retain(|&n| n != number);
+        }
+        Ok(())
+    }
+
+    // Where the log starts: at the snapshot the last compaction wrote, if the
+    // manifest in `state` has it, so that replay can pass over everything
+    // before it however much of that there is. Failing that, it goes by the
+    // `START` file of a sharded log, or of one that kept segments from before
+    // it for retention.
+    pub(crate) fn start(dir: &Path, state: Option<&State>) -> Result<u64> {
+        let start = match fs::read(dir.join("START")) {
+            Ok(bytes) => serde_json::from_slice(&bytes).map_err(|e| {
+                Error::InvalidConfig(format!("bad START file in {}: {}", dir.display(), e))
+            })?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => 0,
+            Err(e) => return Err(e.into()),
+        };
+        let checkpoint = match state {
+            Some(state) => Self::checkpoint(dir, state)?,
+            None => None,
+        };
+        Ok(checkpoint.map_or(start, |seq| seq.max(start)))
+    }
+
+    // Where the snapshot the manifest in `state` records starts, as long as
+    // its segment is still there and starts there too. If not, the manifest
+    // and the segments disagree, and it's safer to replay all of them.
+    fn checkpoint(dir: &Path, state: &State) -> Result<Option<u64>> {
+        let Some((number, seq)) = state.snapshot else {
+            return Ok(None);
+        };
+        let Some(shard) = state.segments.iter().position(|s| s.contains(&number)) else {
+            return Ok(None);
+        };
+        let dir = shard_dir(dir, shard);
+        if !segment::segment_path(&dir, number).exists() {
+            return Ok(None);
+        }
+        let first = SegmentReader::open(&dir, number)?.first_seq();
+        Ok((first == Some(seq)).then_some(seq))
+    }
+
+    // Records that the log starts at `seq` from now on, replacing the `START`
+    // file whole so that a crash leaves either the old one or the new one.
+    fn set_start(&self, seq: u64) -> Result<()> {
+        let path = self.dir.join("START");
+        let tmp = self.dir.join("START.tmp");
+        match fs::remove_file(&tmp) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+        let mut file = durable_fs::create_new(&tmp)?;
+        file.write_all(&error::encode(&seq)?)?;
+        durable_fs::sync_file(&file, Durability::Media)?;
+        durable_fs::rename(&tmp, &path)
+    }
+
+    // How the log in `dir` was closed, if it was closed cleanly rather than
+    // whoever had it open crashing or being killed. Only meaningful until it's
+    // opened for writing again.
+    pub fn closed_cleanly(dir: &Path) -> Option<CleanShutdown> {
+        let marker = fs::read(dir.join("CLEAN")).ok()?;
+        serde_json::from_slice(&marker).ok()
+    }
+
+    // Syncs everything written so far, marks the log as closed cleanly along
+    // with the checksum of the memtable it leaves behind, if there's one, and
+    // releases the lock. Nothing more can be written afterwards.
+    pub fn close(&mut self, memtable: Option<u32>) -> Result<()> {
+        if self.closed {
+            return Ok(());
+        }
+        if let Some(active) = &self.shards[self.current].active {
+            let clean = CleanShutdown {
+                segment: active.number(),
+                offset: active.offset(),
+                next_seq: active.next_seq(),
+                memtable,
+            };
+            self.barrier()?;
+            let path = self.dir.join("CLEAN");
+            match fs::remove_file(&path) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
+            }
+            let mut file = durable_fs::create_new(&path)?;
+            file.write_all(&error::encode(&clean)?)?;
+            durable_fs::sync_file(&file, Durability::Media)?;
+        }
+        self.closed = true;
+        self.lock = None;
+        self.mirror = None;
+        Ok(())
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.closed
+    }
+
+    // Takes the lock on `dir`, returning `None` if someone else has it and
+    // `policy` says to carry on regardless.
+    pub fn lock(dir: &Path, policy: LockPolicy) -> Result<Option<File>> {
+        let path = dir.join("LOCK");
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&path)?;
+        match file.try_lock() {
+            Ok(()) => Ok(Some(file)),
+            Err(TryLockError::WouldBlock) => match policy {
+                LockPolicy::Fail => Err(Error::Busy(path)),
+                LockPolicy::ReadOnly | LockPolicy::Force => Ok(None),
+            },
+            Err(TryLockError::Error(e)) => Err(e.into()),
+        }
+    }
+
+    // Whether `dir` holds a log, recycled segments and all.
+    pub fn exists(dir: &Path) -> Result<bool> {
+        if !dir.is_dir() {
+            return Ok(false);
+        }
+        for i in 0.. {
+            let dir = shard_dir(dir, i);
+            if i > 0 && !dir.is_dir() {
+                break;
+            }
+            let (sealed, recycled) = segment::list(&dir)?;
+            if !sealed.is_empty() || !recycled.is_empty() {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    // Deletes everything a log keeps in `dir`, tables included, and then `dir` itself unless
+    // something else has been left in it. Fails if the log is open.
+    pub fn destroy(dir: &Path) -> Result<()> {
+        if !dir.is_dir() {
+            return Ok(());
+        }
+        let lock = Self::lock(dir, LockPolicy::Fail)?;
+        for shard in (1..).map(|i| shard_dir(dir, i)) {
+            if !shard.is_dir() {
+                break;
+            }
+            Self::remove_segments(&shard)?;
+            durable_fs::remove_dir(&shard)?;
+        }
+        Self::remove_segments(dir)?;
+        let paths = table::list(dir)?
+            .into_iter()
+            .map(|n| table::table_path(dir, n))
+            .chain(value_log::paths(dir)?)
+            .chain([
+                dir.join("REPAIR"),
+                dir.join("CLEAN"),
+                dir.join("START"),
+                dir.join("START.tmp"),
+                dir.join("LOCK"),
+            ])
+            .chain(manifest::paths(dir));
+        for path in paths {
+            match fs::remove_file(&path) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
+            }
+        }
+        drop(lock);
+        match durable_fs::remove_dir(dir) {
+            Err(Error::Io(e)) if e.kind() == io::ErrorKind::DirectoryNotEmpty => {
+                durable_fs::sync_dir(dir)
+            }
+            result => result,
+        }
+    }
+
+    fn remove_segments(dir: &Path) -> Result<()> {
+        let (sealed, recycled) = segment::list(dir)?;
+        let paths = sealed
+            .into_iter()
+            .map(|n| segment::segment_path(dir, n))
+            .chain(recycled.into_iter().map(|n| segment::recycled_path(dir, n)));
+        for path in paths {
+            fs::remove_file(&path)?;
+        }
+        Ok(())
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.shards[0].active.is_none()
+    }
+
+    // How many shards batches go to.
+    pub fn writing_shards(&self) -> usize {
+        self.writing
+    }
+
+    fn active(&mut self) -> Result<&mut SegmentWriter> {
+        self.active_in(self.current)
+    }
+
+    fn active_in(&mut self, shard: usize) -> Result<&mut SegmentWriter> {
+        if self.closed {
+            return Err(Error::Closed);
+        }
+        self.shards[shard].active.as_mut().ok_or(Error::ReadOnly)
+    }
+
+    // Finds where the sequence left off by reading the last segment that has
+    // a valid header. This reads that segment a second time, but it's only
+    // the one. After a clean shutdown, only the last record is checked, and
+    // only if the records end where the shutdown said they would: anything
+    // else means the segment has changed since, and it's read again in full.
+    pub(crate) fn recover_next_seq(
+        dir: &Path,
+        sealed: &[u64],
+        clean: Option<CleanShutdown>,
+    ) -> Result<u64> {
+        let mut buf = vec![];
+        for &number in sealed.iter().rev() {
+            let mut reader = SegmentReader::open(dir, number)?;
+            if reader.first_seq().is_none() {
+                continue;
+            }
+            let clean = clean.filter(|c| c.segment == number);
+            reader.set_verify(clean.is_none());
+            let mut last = None;
+            while let Some(record) = reader.next_record(&mut buf)? {
+                last = Some(record);
+            }
+            if let Some(clean) = clean {
+                let intact = match last {
+                    Some((offset, seq)) => SegmentReader::resume(dir, number, offset, seq)?
+                        .next_record(&mut buf)?
+                        .is_some(),
+                    None => true,
+                };
+                if !intact || (reader.offset(), reader.next_seq()) != (clean.offset, clean.next_seq)
+                {
+                    return Self::recover_next_seq(dir, sealed, None);
+                }
+            }
+            return Ok(reader.next_seq());
+        }
+        Ok(1)
+    }
+
+    // The sequence number the next record appended will get.
+    pub fn next_seq(&self) -> u64 {
+        self.next_seq
+    }
+
+    // Moves the sequence forward so that the next record appended gets `seq`,
+    // by starting a new segment there in the shard it will go to.
+    pub fn skip_to(&mut self, seq: u64) -> Result<()> {
+        assert!(seq >= self.next_seq());
+        if self.records() == 0 {
+            self.first_seq = seq;
+            if self.shards.len() > 1 {
+                self.set_start(seq)?;
+            }
+        }
+        self.rotate_to((self.current + 1) % self.writing, seq)?;
+        self.next_seq = seq;
+        Ok(())
+    }
+
+    // The sequence number of the oldest record still in the log. Everything
+    // before it is in the tables.
+    pub fn first_seq(&self) -> u64 {
+        self.first_seq
+    }
+
+    // How many records the log holds, going by their sequence numbers.
+    pub fn records(&self) -> u64 {
+        self.next_seq() - self.first_seq
+    }
+
+    // The space taken up by every segment, recycled ones included. Segments
+    // are allocated in full up front, so this is mostly a multiple of the
+    // segment size.
+    pub fn disk_bytes(&self) -> Result<u64> {
+        let shards = self
+            .shards
+            .iter()
+            .chain(self.mirror.iter().map(|m| &m.shard));
+        let paths = shards.flat_map(|shard| {
+            let active = shard.active.as_ref().map(|a| a.number());
+            shard
+                .sealed
+                .iter()
+                .chain(active.iter())
+                .map(|&n| segment::segment_path(&shard.dir, n))
+                .chain(
+                    shard
+                        .recycled
+                        .iter()
+                        .map(|&n| segment::recycled_path(&shard.dir, n)),
+                )
+                .collect::<Vec<_>>()
+        });
+        let mut bytes = 0;
+        for path in paths {
+            match fs::metadata(path) {
+                Ok(metadata) => bytes += metadata.len(),
+                // A read-only log's writer may have retired it since.
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(bytes)
+    }
+
+    // Appends `payloads` to the next shard using as few writes as possible:
+    // one per segment that the batch ends up spanning. Returns the number of
+    // bytes written.
+    pub fn append_batch(&mut self, payloads: &[Vec<u8>]) -> Result<usize> {
+        self.current = (self.current + 1) % self.writing;
+        self.placed.clear();
+        let mut written = 0;
+        let mut start = 0;
+        while start < payloads.len() {
+            let mut offset = self.active()?.offset();
+            let mut end = start;
+            while end < payloads.len() {
+                let len = (segment::HEADER_LEN + payloads[end].len()) as u64;
+                // Leaving room for the footer the segment is sealed with.
+                if offset > segment::SEGMENT_HEADER_LEN as u64
+                    && offset + len + segment::FOOTER_LEN as u64 > self.options.segment_size
+                {
+                    break;
+                }
+                offset += len;
+                end += 1;
+            }
+            if end == start {
+                self.rotate()?;
+                continue;
+            }
+            // The shard carries on from wherever the other shards left the
+            // sequence, which a segment started for this batch doesn't
+            // claim to start at. It can only be further along itself if a
+            // write to it failed, which it's about to say.
+            let next_seq = self.next_seq + start as u64;
+            let active = self.active()?;
+            if next_seq > active.next_seq() {
+                active.skip_to(next_seq);
+            }
+            written += active.append_batch(&payloads[start..end])?;
+            let number = active.number();
+            self.placed.push((number, end));
+            self.shards[self.current].dirty = true;
+            let result = self.append_mirror(next_seq, &payloads[start..end]);
+            self.mirrored(result)?;
+            start = end;
+        }
+        self.next_seq += payloads.len() as u64;
+        Ok(written)
+    }
+
+    fn append_mirror(&mut self, next_seq: u64, payloads: &[Vec<u8>]) -> Result<()> {
+        let Some(active) = self.mirror.as_mut().and_then(|m| m.shard.active.as_mut()) else {
+            return Ok(());
+        };
+        if next_seq > active.next_seq() {
+            active.skip_to(next_seq);
+        }
+        active.append_batch(payloads)?;
+        Ok(())
+    }
+
+    // Goes by `Options::mirror_policy` as to whether writing to the mirror
+    // failing fails the log too, or only drops the mirror.
+    fn mirrored(&mut self, result: Result<()>) -> Result<()> {
+        let Err(e) = result else {
+            return Ok(());
+        };
+        if self.options.mirror_policy == MirrorPolicy::Both {
+            return Err(e);
+        }
+        self.mirror = None;
+        warn!(error = %e, "stopped writing to the log's mirror");
+        if let Some(listener) = &self.options.listener {
+            listener.on_mirror_failed(&e);
+        }
+        Ok(())
+    }
+
+    // Syncs the segment the last batch went to, along with any other shard's
+    // that batches have gone to since it was last synced, and the mirror's.
+    pub fn sync(&mut self) -> Result<()> {
+        let syncers = self.syncers();
+        let mirror = self.mirror.as_ref().and_then(|m| m.shard.active.as_ref());
+        let Some(mirror) = mirror.map(|a| a.syncer()) else {
+            return syncers.iter().try_for_each(|s| s.sync());
+        };
+        // The copies are meant to be on different disks, so they're synced
+        // at the same time.
+        let (synced, mirrored) = thread::scope(|s| {
+            let mirrored = s.spawn(|| mirror.sync());
+            let synced = syncers.iter().try_for_each(|s| s.sync());
+            (synced, mirrored.join())
+        });
+        synced?;
+        self.mirrored(mirrored.unwrap_or_else(|e| panic::resume_unwind(e)))
+    }
+
+    // The segment each record of the last batch went to, as the segments it
+    // was written to and the index of the record after the last one in each.
+    pub fn placed(&self) -> &[(u64, usize)] {
+        &self.placed
+    }
+
+    // The number of the segment the last batch went to.
+    pub fn active_number(&self) -> Option<u64> {
+        self.shards[self.current]
+            .active
+            .as_ref()
+            .map(|a| a.number())
+    }
+
+    // Like `sync`, but for doing the syncing without the log, while the next
+    // batch goes to another shard. The segments count as synced from here on.
+    pub fn syncers(&mut self) -> Vec<SegmentSync> {
+        let current = self.current;
+        let mut syncers = vec![];
+        for (i, shard) in self.shards.iter_mut().enumerate() {
+            if i == current || shard.dirty {
+                shard.dirty = false;
+                syncers.extend(shard.active.as_ref().map(|a| a.syncer()));
+            }
+        }
+        syncers
+    }
+
+    // Makes everything written so far durable, even if the log's durability
+    // is `None`, in which case it's synced all the way to media.
+    pub fn barrier(&mut self) -> Result<()> {
+        if self.closed {
+            return Err(Error::Closed);
+        }
+        let durability = match self.options.durability {
+            Durability::None => Durability::Media,
+            durability => durability,
+        };
+        for shard in &mut self.shards {
+            shard.barrier(durability)?;
+        }
+        let result = match &mut self.mirror {
+            Some(mirror) => mirror.shard.barrier(durability),
+            None => Ok(()),
+        };
+        self.mirrored(result)
+    }
+
+    fn rotate(&mut self) -> Result<()> {
+        let next_seq = self.active()?.next_seq();
+        self.rotate_to(self.current, next_seq)
+    }
+
+    // Seals the active segment of `shard` and starts a new one, whose records
+    // start at `first_seq`. The footer is written and synced before the new
+    // segment is created, and that's synced along with its directory and
+    // recorded in the manifest before anything is written to it, so that a
+    // crash at any point leaves either a sealed segment and maybe an empty
+    // one after it, or a segment whose tail replay treats like any other torn
+    // one. If any step fails, nothing
+    // more is written until the log is reopened.
+    fn rotate_to(&mut self, shard: usize, first_seq: u64) -> Result<()> {
+        let result = self.seal_and_create(shard, first_seq);
+        if result.is_err() {
+            self.failed.store(true, Ordering::Release);
+        }
+        let (sealed, next) = result?;
+        if shard == 0 {
+            let result = self.rotate_mirror(next, first_seq);
+            self.mirrored(result)?;
+        }
+        if let Some(listener) = &self.options.listener {
+            listener.on_segment_rotated(sealed, next);
+        }
+        Ok(())
+    }
+
+    // Returns the numbers of the segment sealed and the one created.
+    fn seal_and_create(&mut self, shard: usize, first_seq: u64) -> Result<(u64, u64)> {
+        self.active_in(shard)?.seal()?;
+        #[cfg(feature = "failpoints")]
+        self.failpoint(Failpoint::BeforeCreate)?;
+        let next = self.next_number;
+        let current = &mut self.shards[shard];
+        let active = create_segment(
+            &current.dir,
+            next,
+            first_seq,
+            current.recycled.pop(),
+            &self.options,
+            &self.failed,
+            false,
+        )?;
+        self.next_number += 1;
+        #[cfg(feature = "failpoints")]
+        self.failpoint(Failpoint::AfterCreate)?;
+        self.record(&[Edit::Add {
+            shard,
+            number: next,
+        }])?;
+        let sealed = self.shards[shard].rotate(active, &self.options).unwrap();
+        Ok((sealed, next))
+    }
+
+    fn rotate_mirror(&mut self, next: u64, first_seq: u64) -> Result<()> {
+        let Some(mirror) = &mut self.mirror else {
+            return Ok(());
+        };
+        let shard = &mut mirror.shard;
+        if let Some(active) = &mut shard.active {
+            active.seal()?;
+        }
+        let active = create_segment(
+            &shard.dir,
+            next,
+            first_seq,
+            shard.recycled.pop(),
+            &self.options,
+            &mirror.failed,
+            true,
+        )?;
+        shard.rotate(active, &self.options);
+        Ok(())
+    }
+
+    fn record(&mut self, edits: &[Edit]) -> Result<()> {
+        match &mut self.manifest {
+            Some(manifest) => manifest.record(edits),
+            None => Ok(()),
+        }
+    }
+
+    // Fails if `point` is armed, the way a segment's failpoints do.
+    #[cfg(feature = "failpoints")]
+    fn failpoint(&self, point: Failpoint) -> Result<()> {
+        match self.options.failpoints.as_ref().and_then(|f| f.hit(point)) {
+            Some(_) => Err(failpoint::error(point).into()),
+            None => Ok(()),
+        }
+    }
+
+    // Writes `snapshot` into a new segment and retires every segment before
+    // it. Retired segments are dropped oldest first, so that if we crash
+    // partway through, whatever remains is still a contiguous suffix of the
+    // log followed by the snapshot. Shards can't all be cut back at once, so
+    // a sharded log first records where the snapshot starts, and replay
+    // ignores whatever's left from before it.
+    //
+    // Segments that `Options::retention` says to keep stay behind the
+    // snapshot, with replay told to skip them the same way. `cursor` is the
+    // oldest record a consumer that's saved its cursor still needs.
+    pub fn compact(&mut self, snapshot: &[Vec<u8>], cursor: Option<u64>) -> Result<()> {
+        let first = self.next_number;
+        for shard in 0..self.writing {
+            let next_seq = self.active_in(shard)?.next_seq();
+            self.rotate_to(shard, next_seq)?;
+        }
+        self.first_seq = self.next_seq();
+        let kept = self.kept(cursor, self.first_seq)?;
+        self.append_batch(snapshot)?;
+        self.sync()?;
+        if let Some(segment) = self.active_number() {
+            let seq = self.first_seq;
+            self.record(&[Edit::Snapshot { segment, seq }])?;
+        }
+        if self.shards.len() > 1 || kept.is_some() {
+            self.set_start(self.first_seq)?;
+        }
+        let retired = self.retire_before(first.min(kept.unwrap_or(u64::MAX)), first)?;
+        if let Some(listener) = &self.options.listener {
+            listener.on_compaction_finished(&retired);
+        }
+        Ok(())
+    }
+
+    // Retires the segments kept for `Options::retention` that it no longer
+    // says to keep, and returns their numbers.
+    pub fn trim(&mut self, cursor: Option<u64>) -> Result<Vec<u64>> {
+        let kept = self.kept(cursor, self.next_seq())?;
+        let replayed_from = self.shards[0].replayed_from;
+        self.retire_before(replayed_from.min(kept.unwrap_or(u64::MAX)), replayed_from)
+    }
+
+    // The oldest segment retention says to keep, if any.
+    fn kept(&self, cursor: Option<u64>, end: u64) -> Result<Option<u64>> {
+        match self.shards.len() {
+            1 => {
+                let now = self.options.clock.system_time();
+                self.shards[0].kept(&self.options.retention, cursor, end, now)
+            }
+            _ => Ok(None),
+        }
+    }
+
+    // Retires every segment before `before`, in every shard and the mirror,
+    // and has replay start from `replayed_from`.
+    fn retire_before(&mut self, before: u64, replayed_from: u64) -> Result<Vec<u64>> {
+        let mut edits = vec![];
+        for (i, shard) in self.shards.iter().enumerate() {
+            let numbers = shard.sealed.iter().take_while(|&&n| n < before);
+            edits.extend(numbers.map(|&number| Edit::Remove { shard: i, number }));
+        }
+        self.record(&edits)?;
+        let mut retired = vec![];
+        for shard in &mut self.shards {
+            retired.extend(shard.retire_before(before, &self.options)?);
+            shard.replayed_from = replayed_from;
+        }
+        let result = match &mut self.mirror {
+            Some(mirror) => mirror.shard.retire_before(before, &self.options).map(drop),
+            None => Ok(()),
+        };
+        self.mirrored(result)?;
+        retired.sort_unstable();
+        Ok(retired)
+    }
+}
+
+// Closing is best-effort here, since there's nobody to tell if it fails. The
+// next open just won't find the log closed cleanly.
+impl Drop for Log {
+    fn drop(&mut self) {
+        let _ = self.close(None);
+    }
+}
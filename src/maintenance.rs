@@ -0,0 +1,449 @@
+use crate::{error, Db, Error, Result};
+#[cfg(test)]
+use crate::{table, Options};
+use std::{
+    collections::{BTreeMap, HashSet, VecDeque},
+    path::PathBuf,
+    sync::{Arc, Condvar, Mutex},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+#[cfg(test)]
+use tempfile::tempdir;
+
+// What `Db::start_maintenance` does, and how often. Nothing is done unless
+// it's asked for here.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MaintenanceOptions {
+    // How many tasks can run at once. The ones that take the log's lock
+    // (everything but expiry) still go one at a time.
+    pub threads: usize,
+    // Flushes the memtable to a table (see `Db::flush`) this often, if
+    // anything has been written since the last time.
+    pub checkpoint: Option<Duration>,
+    // Compacts (see `Db::compact`) this often.
+    pub compaction: Option<Duration>,
+    // Compacts as soon as the log has this many records, as of the last
+    // check.
+    pub compaction_records: Option<u64>,
+    // Deletes expired keys (see `Db::expire`) this often.
+    pub expiry: Option<Duration>,
+    // Retires the segments `Options::retention` no longer keeps this often,
+    // first forgetting the cursors of consumers more than
+    // `archival_max_lag` records behind, if it's set (see
+    // `Db::expire_cursors`).
+    pub archival: Option<Duration>,
+    pub archival_max_lag: Option<u64>,
+    // How often `compaction_records` is checked.
+    pub poll: Duration,
+}
+
+impl Default for MaintenanceOptions {
+    fn default() -> Self {
+        MaintenanceOptions {
+            threads: 1,
+            checkpoint: None,
+            compaction: None,
+            compaction_records: None,
+            expiry: None,
+            archival: None,
+            archival_max_lag: None,
+            poll: Duration::from_secs(1),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum MaintenanceTask {
+    Checkpoint,
+    Compaction,
+    Expiry,
+    Archival,
+}
+
+// Runs the tasks `MaintenanceOptions` asks for on a pool of threads of its
+// own, from `Db::start_maintenance`, until it's stopped or dropped, or a task
+// fails. Like an `ExpirySweeper`, its threads hold clones of the database, so
+// the database isn't closed until it's stopped too. A `DbManager` runs one
+// for all of its databases.
+//
+// No task is started while `Db::pause_background_work` has it paused; one
+// that comes due then is skipped, and runs the next time it's due instead.
+#[derive(Debug)]
+pub struct Maintenance {
+    shared: Arc<Shared>,
+    threads: Vec<JoinHandle<()>>,
+}
+
+#[derive(Debug)]
+struct Shared {
+    state: Mutex<State>,
+    // Signalled when a task is queued or done, and when it's stopped.
+    changed: Condvar,
+}
+
+// A task for the database in a directory.
+type Job = (Arc<PathBuf>, MaintenanceTask);
+
+#[derive(Debug, Default)]
+struct State {
+    stopped: bool,
+    // Each database being maintained, by directory, and its `Db::next_seq`
+    // as of its last checkpoint.
+    dbs: BTreeMap<Arc<PathBuf>, (Db, u64)>,
+    queue: VecDeque<Job>,
+    // Whatever is queued or running, so that no task is run twice at once.
+    pending: HashSet<Job>,
+    // The first error a task returned, which stopped everything.
+    error: Option<Error>,
+}
+
+impl Maintenance {
+    pub(crate) fn start(db: Db, options: MaintenanceOptions) -> Result<Self> {
+        let maintenance = Maintenance::new(options)?;
+        maintenance.add(db);
+        Ok(maintenance)
+    }
+
+    // Starts the threads, with no databases to maintain yet.
+    pub(crate) fn new(options: MaintenanceOptions) -> Result<Self> {
+        if options.threads == 0 {
+            return Err(Error::InvalidConfig(
+                "maintenance needs at least one thread".to_owned(),
+            ));
+        }
+        let shared = Arc::new(Shared {
+            state: Mutex::default(),
+            changed: Condvar::new(),
+        });
+        let max_lag = options.archival_max_lag.unwrap_or(u64::MAX);
+        let mut threads = (0..options.threads)
+            .map(|_| {
+                let shared = shared.clone();
+                thread::spawn(move || work(&shared, max_lag))
+            })
+            .collect::<Vec<_>>();
+        let scheduler = shared.clone();
+        threads.push(thread::spawn(move || schedule(&scheduler, &options)));
+        Ok(Maintenance { shared, threads })
+    }
+
+    pub(crate) fn add(&self, db: Db) {
+        let seq = db.next_seq();
+        let mut state = error::lock(&self.shared.state);
+        state.dbs.insert(db.dir.clone(), (db, seq));
+    }
+
+    // Stops maintaining `db`, dropping whatever tasks for it are queued.
+    // Those already running carry on.
+    pub(crate) fn remove(&self, db: &Db) {
+        let mut state = error::lock(&self.shared.state);
+        state.dbs.remove(&db.dir);
+        let (queued, queue) = std::mem::take(&mut state.queue)
+            .into_iter()
+            .partition::<VecDeque<_>, _>(|(dir, _)| *dir == db.dir);
+        state.queue = queue;
+        for job in queued {
+            state.pending.remove(&job);
+        }
+    }
+
+    // Queues `task` to run as soon as a thread is free, unless it's already
+    // queued or running.
+    pub fn trigger(&self, task: MaintenanceTask) {
+        self.shared.queue_all(task);
+    }
+
+    // Stops every thread once the tasks they're running are done, and
+    // returns whatever error stopped them early if something did.
+    pub fn stop(mut self) -> Result<()> {
+        self.shared.stop();
+        for thread in self.threads.drain(..) {
+            thread
+                .join()
+                .unwrap_or_else(|e| std::panic::resume_unwind(e));
+        }
+        match error::lock(&self.shared.state).error.take() {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for Maintenance {
+    fn drop(&mut self) {
+        self.shared.stop();
+        for thread in self.threads.drain(..) {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Shared {
+    // Queues `task` for every database.
+    fn queue_all(&self, task: MaintenanceTask) {
+        let mut state = error::lock(&self.state);
+        let dirs = state.dbs.keys().cloned().collect::<Vec<_>>();
+        for dir in dirs {
+            Self::queue(&mut state, (dir, task));
+        }
+        self.changed.notify_all();
+    }
+
+    fn queue(state: &mut State, job: Job) {
+        if !state.stopped && state.pending.insert(job.clone()) {
+            state.queue.push_back(job);
+        }
+    }
+
+    fn stop(&self) {
+        error::lock(&self.state).stopped = true;
+        self.changed.notify_all();
+    }
+}
+
+// Queues each task as it comes due.
+fn schedule(shared: &Shared, options: &MaintenanceOptions) {
+    let intervals = [
+        (MaintenanceTask::Checkpoint, options.checkpoint),
+        (MaintenanceTask::Compaction, options.compaction),
+        (MaintenanceTask::Expiry, options.expiry),
+        (MaintenanceTask::Archival, options.archival),
+    ];
+    let start = Instant::now();
+    let mut due = intervals
+        .iter()
+        .filter_map(|&(task, interval)| Some((task, interval?, start + interval?)))
+        .collect::<Vec<_>>();
+    let mut poll = start + options.poll;
+    loop {
+        let now = Instant::now();
+        for (task, interval, at) in &mut due {
+            if *at <= now {
+                shared.queue_all(*task);
+                *at = now + *interval;
+            }
+        }
+        if let (Some(max), true) = (options.compaction_records, poll <= now) {
+            let dbs = error::lock(&shared.state)
+                .dbs
+                .values()
+                .map(|(db, _)| db.clone())
+                .collect::<Vec<_>>();
+            for db in dbs {
+                if db.stats().is_ok_and(|stats| stats.log_records >= max) {
+                    let mut state = error::lock(&shared.state);
+                    Shared::queue(&mut state, (db.dir.clone(), MaintenanceTask::Compaction));
+                    shared.changed.notify_all();
+                }
+            }
+            poll = now + options.poll;
+        }
+        let mut next = due.iter().map(|&(_, _, at)| at).min();
+        if options.compaction_records.is_some() {
+            next = Some(next.map_or(poll, |next| next.min(poll)));
+        }
+        let state = error::lock(&shared.state);
+        if state.stopped {
+            return;
+        }
+        match next {
+            Some(next) => {
+                let timeout = next.saturating_duration_since(Instant::now());
+                let _state = shared.changed.wait_timeout(state, timeout);
+            }
+            None => {
+                let _state = shared.changed.wait(state);
+            }
+        }
+    }
+}
+
+// Runs queued tasks until it's stopped.
+fn work(shared: &Shared, max_lag: u64) {
+    loop {
+        let mut state = error::lock(&shared.state);
+        let (job, member) = loop {
+            if state.stopped {
+                return;
+            }
+            if let Some(job) = state.queue.pop_front() {
+                let member = state.dbs.get(&job.0).cloned();
+                break (job, member);
+            }
+            state = match shared.changed.wait(state) {
+                Ok(state) => state,
+                Err(_) => return,
+            };
+        };
+        drop(state);
+        let result = match &member {
+            Some((db, checkpointed)) => match db.background.begin() {
+                Some(_running) => run(&mut db.clone(), job.1, *checkpointed, max_lag),
+                None => Ok(None),
+            },
+            None => Ok(None),
+        };
+        let mut state = error::lock(&shared.state);
+        state.pending.remove(&job);
+        match result {
+            Ok(Some(seq)) => {
+                if let Some((_, checkpointed)) = state.dbs.get_mut(&job.0) {
+                    *checkpointed = (*checkpointed).max(seq);
+                }
+            }
+            Ok(None) => {}
+            Err(e) => {
+                state.error.get_or_insert(e);
+                state.stopped = true;
+            }
+        }
+        shared.changed.notify_all();
+    }
+}
+
+// Runs `task`, returning `Db::next_seq` as of the checkpoint if it was one.
+fn run(db: &mut Db, task: MaintenanceTask, checkpointed: u64, max_lag: u64) -> Result<Option<u64>> {
+    match task {
+        MaintenanceTask::Checkpoint if db.next_seq() == checkpointed => {}
+        MaintenanceTask::Checkpoint => return Ok(Some(db.flush()? + 1)),
+        MaintenanceTask::Compaction => db.compact()?,
+        MaintenanceTask::Expiry => {
+            db.expire()?;
+        }
+        MaintenanceTask::Archival => {
+            db.expire_cursors(max_lag)?;
+        }
+    }
+    Ok(None)
+}
+
+// Whether background work is paused (see `Db::pause_background_work`), and
+// how many tasks are running, for a `Maintenance` or `ExpirySweeper` to
+// check before starting one. Shared by every clone of a `Db`.
+#[derive(Debug, Default)]
+pub(crate) struct Background {
+    state: Mutex<Paused>,
+    // Signalled when the last running task is done.
+    idle: Condvar,
+}
+
+#[derive(Debug, Default)]
+struct Paused {
+    paused: bool,
+    running: usize,
+}
+
+// A task that's running, until it's dropped.
+pub(crate) struct Running(Arc<Background>);
+
+impl Background {
+    // Notes that a task is starting, unless background work is paused.
+    pub fn begin(self: &Arc<Self>) -> Option<Running> {
+        let mut state = error::lock(&self.state);
+        if state.paused {
+            return None;
+        }
+        state.running += 1;
+        Some(Running(self.clone()))
+    }
+
+    // Stops tasks from starting and waits for those running to finish.
+    pub fn pause(&self) {
+        let mut state = error::lock(&self.state);
+        state.paused = true;
+        while state.running > 0 {
+            state = match self.idle.wait(state) {
+                Ok(state) => state,
+                Err(_) => return,
+            };
+        }
+    }
+
+    pub fn resume(&self) {
+        error::lock(&self.state).paused = false;
+    }
+}
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        let mut state = error::lock(&self.0.state);
+        state.running -= 1;
+        if state.running == 0 {
+            self.0.idle.notify_all();
+        }
+    }
+}
+
+#[test]
+fn test_maintenance() -> Result<()> {
+    let dir = tempdir()?;
+    let file = dir.path().join("logfile");
+    let options = Options {
+        segment_size: 4096,
+        ..Options::default()
+    };
+    let mut db = Db::with_options(&file, options)?;
+    let none = MaintenanceOptions {
+        threads: 0,
+        ..MaintenanceOptions::default()
+    };
+    assert!(db.start_maintenance(none).is_err());
+    let wait = |done: &dyn Fn() -> Result<bool>| -> Result<()> {
+        let start = Instant::now();
+        while !done()? {
+            assert!(start.elapsed() < Duration::from_secs(10));
+            thread::sleep(Duration::from_millis(5));
+        }
+        Ok(())
+    };
+
+    // Checkpoints and sweeps come round on their own.
+    db.set_with_ttl("short", "v", Duration::from_millis(10))?;
+    db.set("k", "v")?;
+    let seq = db.next_seq();
+    let maintenance = db.start_maintenance(MaintenanceOptions {
+        threads: 2,
+        checkpoint: Some(Duration::from_millis(5)),
+        expiry: Some(Duration::from_millis(5)),
+        ..MaintenanceOptions::default()
+    })?;
+    wait(&|| Ok(db.next_seq() > seq && !table::list(&file)?.is_empty()))?;
+    assert_eq!(db.get("short"), None);
+
+    // Until they're paused.
+    db.pause_background_work();
+    let tables = table::list(&file)?;
+    db.set_with_ttl("paused", "v", Duration::from_millis(1))?;
+    let seq = db.next_seq();
+    thread::sleep(Duration::from_millis(50));
+    assert_eq!(db.next_seq(), seq);
+    assert_eq!(table::list(&file)?, tables);
+    db.resume_background_work();
+    wait(&|| Ok(db.next_seq() > seq && table::list(&file)? != tables))?;
+    maintenance.stop()?;
+
+    // Compaction comes when the log gets long, or when it's asked for.
+    let maintenance = db.start_maintenance(MaintenanceOptions {
+        compaction_records: Some(50),
+        poll: Duration::from_millis(5),
+        ..MaintenanceOptions::default()
+    })?;
+    for i in 0..100 {
+        db.set(&format!("k{}", i % 10), "v")?;
+    }
+    wait(&|| Ok(db.stats()?.log_records < 50))?;
+    db.set("more", "v")?;
+    let tables = table::list(&file)?;
+    maintenance.trigger(MaintenanceTask::Compaction);
+    wait(&|| Ok(table::list(&file)? != tables))?;
+    assert_eq!(db.get("more"), Some("v".into()));
+
+    // A task that fails stops everything, and stopping says why.
+    db.clone().close()?;
+    maintenance.trigger(MaintenanceTask::Checkpoint);
+    wait(&|| Ok(error::lock(&maintenance.shared.state).stopped))?;
+    let err = maintenance.stop().unwrap_err();
+    assert!(matches!(err, Error::Closed), "{}", err);
+    Ok(())
+}
@@ -0,0 +1,236 @@
+use crate::cache::BlockCache;
+#[cfg(test)]
+use crate::table;
+use crate::{error, Db, Error, Maintenance, MaintenanceOptions, Options, RateLimiter, Result};
+#[cfg(test)]
+use std::time::{Duration, Instant};
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+#[cfg(test)]
+use tempfile::tempdir;
+
+// Opens and closes databases by name, each in a directory of its own under a
+// root directory, for embedders with many of them in one process, such as one
+// per tenant. They're all opened with the same options, and share one block
+// cache of `Options::block_cache_bytes` rather than each having their own, one
+// `RateLimiter` holding all their background writes together to
+// `Options::background_bytes_per_sec`, and one `Maintenance` pool if the
+// manager is given `MaintenanceOptions`.
+//
+// Dropping the manager stops the pool and drops its handles on the
+// databases, which close once nothing else holds one.
+#[derive(Debug)]
+pub struct DbManager {
+    root: PathBuf,
+    options: Options,
+    block_cache: Arc<BlockCache>,
+    rate_limiter: Arc<RateLimiter>,
+    // Dropped before the databases, so that nothing is running on them.
+    maintenance: Option<Maintenance>,
+    // The databases that are open, by name.
+    dbs: Mutex<BTreeMap<String, Db>>,
+}
+
+impl DbManager {
+    pub fn new<P>(
+        root: P,
+        options: Options,
+        maintenance: Option<MaintenanceOptions>,
+    ) -> Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        fs::create_dir_all(root.as_ref())?;
+        Ok(DbManager {
+            root: root.as_ref().to_owned(),
+            block_cache: Arc::new(BlockCache::new(options.block_cache_bytes)),
+            rate_limiter: Arc::new(RateLimiter::new(options.background_bytes_per_sec)),
+            options,
+            maintenance: maintenance.map(Maintenance::new).transpose()?,
+            dbs: Mutex::default(),
+        })
+    }
+
+    // Opens the database called `name`, creating it if there isn't one, or
+    // returns another handle on it if it's open already.
+    pub fn open(&self, name: &str) -> Result<Db> {
+        let dir = self.dir(name)?;
+        let mut dbs = error::lock(&self.dbs);
+        if let Some(db) = dbs.get(name) {
+            return Ok(db.clone());
+        }
+        let db = Db::open(
+            &dir,
+            self.options.clone(),
+            self.block_cache.clone(),
+            self.rate_limiter.clone(),
+            &mut |_| Ok(()),
+        )?;
+        if let Some(maintenance) = &self.maintenance {
+            maintenance.add(db.clone());
+        }
+        dbs.insert(name.to_owned(), db.clone());
+        Ok(db)
+    }
+
+    // A handle on the database called `name`, if it's open.
+    pub fn get(&self, name: &str) -> Option<Db> {
+        error::lock(&self.dbs).get(name).cloned()
+    }
+
+    // Closes the database called `name` (see `Db::close`), once whatever
+    // maintenance is running on it is done, and returns whether it was open.
+    // Writes through other handles on it fail with `Error::Closed` from then
+    // on.
+    pub fn close(&self, name: &str) -> Result<bool> {
+        let Some(db) = error::lock(&self.dbs).remove(name) else {
+            return Ok(false);
+        };
+        if let Some(maintenance) = &self.maintenance {
+            maintenance.remove(&db);
+        }
+        db.pause_background_work();
+        db.close()?;
+        Ok(true)
+    }
+
+    // The names of the databases under the root, open or not. Anything else
+    // there, including directories without a database in them, is left out.
+    pub fn list(&self) -> Result<Vec<String>> {
+        let mut names = vec![];
+        for entry in fs::read_dir(&self.root)? {
+            let entry = entry?;
+            if !entry.file_type()?.is_dir() || !Db::exists(entry.path())? {
+                continue;
+            }
+            if let Some(name) = entry.file_name().to_str() {
+                names.push(name.to_owned());
+            }
+        }
+        names.sort_unstable();
+        Ok(names)
+    }
+
+    // The names of the databases that are open.
+    pub fn open_names(&self) -> Vec<String> {
+        error::lock(&self.dbs).keys().cloned().collect()
+    }
+
+    // Stops maintenance and closes every open database, returning the first
+    // error either of those ran into.
+    pub fn close_all(mut self) -> Result<()> {
+        let mut result = match self.maintenance.take() {
+            Some(maintenance) => maintenance.stop(),
+            None => Ok(()),
+        };
+        let dbs = std::mem::take(&mut *error::lock(&self.dbs));
+        for db in dbs.into_values() {
+            let closed = db.close();
+            result = result.and(closed);
+        }
+        result
+    }
+
+    // Where the database called `name` lives. Names are single path
+    // components, so that every database stays under the root.
+    fn dir(&self, name: &str) -> Result<PathBuf> {
+        let valid = !matches!(name, "" | "." | "..") && !name.contains(['/', '\\']);
+        if !valid {
+            return Err(Error::InvalidConfig(format!(
+                "{:?} is not a database name",
+                name
+            )));
+        }
+        Ok(self.root.join(name))
+    }
+}
+
+#[test]
+fn test_db_manager() -> Result<()> {
+    let dir = tempdir()?;
+    let root = dir.path().join("root");
+    let maintenance = MaintenanceOptions {
+        checkpoint: Some(Duration::from_millis(5)),
+        ..MaintenanceOptions::default()
+    };
+    let manager = DbManager::new(&root, Options::default(), Some(maintenance))?;
+    for name in ["", ".", "..", "a/b", "a\\b"] {
+        let err = manager.open(name).unwrap_err();
+        assert!(matches!(err, Error::InvalidConfig(_)), "{}", err);
+    }
+
+    // Each database is a directory under the root, and opening one that's
+    // open already hands out another handle on it.
+    let mut a = manager.open("a")?;
+    let mut b = manager.open("b")?;
+    a.set("k", "a")?;
+    b.set("k", "b")?;
+    assert_eq!(manager.open("a")?.get("k"), Some("a".into()));
+    assert_eq!(manager.get("b").unwrap().get("k"), Some("b".into()));
+    assert!(manager.get("c").is_none());
+    fs::create_dir(root.join("not-a-db"))?;
+    fs::write(root.join("stray"), "")?;
+    assert_eq!(manager.list()?, ["a", "b"]);
+    assert_eq!(manager.open_names(), ["a", "b"]);
+    assert!(Arc::ptr_eq(&a.block_cache, &b.block_cache));
+    assert!(Arc::ptr_eq(&a.rate_limiter, &b.rate_limiter));
+
+    // The one pool looks after all of them.
+    let start = Instant::now();
+    while table::list(&root.join("a"))?.is_empty() || table::list(&root.join("b"))?.is_empty() {
+        assert!(start.elapsed() < Duration::from_secs(10));
+        std::thread::sleep(Duration::from_millis(5));
+    }
+
+    // Closing one leaves it on disk to be opened again.
+    assert!(manager.close("a")?);
+    assert!(!manager.close("a")?);
+    assert!(matches!(a.set("k", "v"), Err(Error::Closed)));
+    assert_eq!(manager.list()?, ["a", "b"]);
+    assert_eq!(manager.open_names(), ["b"]);
+    let a = manager.open("a")?;
+    assert_eq!(a.get("k"), Some("a".into()));
+    b.set("more", "v")?;
+    manager.close_all()?;
+    assert!(matches!(b.set("k", "v"), Err(Error::Closed)));
+
+    // Another manager can open them once this one's done with them.
+    let manager = DbManager::new(&root, Options::default(), None)?;
+    assert_eq!(manager.open("b")?.get("more"), Some("v".into()));
+    Ok(())
+}
+
+#[test]
+fn test_db_manager_rate_limit() -> Result<()> {
+    let dir = tempdir()?;
+    let options = Options {
+        background_bytes_per_sec: Some(20_000),
+        ..Options::default()
+    };
+    let manager = DbManager::new(dir.path(), options, None)?;
+    let mut a = manager.open("a")?;
+    let mut b = manager.open("b")?;
+    for i in 0..50 {
+        a.set(&format!("k{:03}", i), &"v".repeat(400))?;
+        b.set(&format!("k{:03}", i), &"v".repeat(400))?;
+    }
+    // Each flush writes some 20KB of table, so either one alone fits in the
+    // first second's worth, but the two of them together have to wait for
+    // the second.
+    let start = Instant::now();
+    a.flush()?;
+    b.flush()?;
+    assert!(start.elapsed() >= Duration::from_millis(500));
+    // They share the rate, but each counts its own writes, and only the
+    // second had to wait.
+    let (a, b) = (a.stats()?, b.stats()?);
+    assert!(a.background_bytes >= 20_000 && b.background_bytes >= 20_000);
+    assert!(a.background_bytes < 40_000 && b.background_bytes < 40_000);
+    assert!(b.background_throttled >= Duration::from_millis(500));
+    assert!(a.background_throttled < b.background_throttled);
+    Ok(())
+}
@@ -0,0 +1,358 @@
+use crate::segment::{self, SegmentReader, FORMAT_VERSION};
+use crate::{durable_fs, error, Durability, Error, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::{self, File},
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+#[cfg(test)]
+use {
+    crate::{Db, Options},
+    tempfile::tempdir,
+};
+
+// The log's own record of which segments make it up, kept in `MANIFEST` in
+// its directory, so that opening it goes by what it wrote down rather than by
+// whatever files happen to be lying around. Each change is appended as a
+// record, framed and checksummed like a segment's, numbered from 1 and synced
+// before the change is made, except that a new segment is only recorded once
+// its file exists. A crash can then leave two kinds of file the manifest
+// doesn't list: a segment it was retiring, and one that was created but never
+// recorded, which nothing can have been written to yet. Opening the log for
+// writing removes both, and rewrites the manifest with just what's left.
+//
+// A log from before there was a manifest is listed from its directories the
+// first time it's opened for writing, and gets one then. Shards are listed in
+// the one manifest, in the log's own directory; a mirror goes by its
+// directory, since it's healed from the log's (see `mirror`).
+const MANIFEST: &str = "MANIFEST";
+const MANIFEST_TMP: &str = "MANIFEST.tmp";
+// How many more records than it takes to describe the log the manifest can
+// build up before it's rewritten.
+const REWRITE_AFTER: u64 = 1024;
+
+// How the log is written, as of the last time it was opened.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Format {
+    pub version: u16,
+    pub codec: u32,
+    pub segment_size: u64,
+    pub shards: usize,
+}
+
+// A change to the log, as the manifest records it.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Edit {
+    Format(Format),
+    // Segment `number` was created in shard `shard`.
+    Add { shard: usize, number: u64 },
+    // Segment `number` of shard `shard` is about to be retired, or cut.
+    Remove { shard: usize, number: u64 },
+    // A compaction wrote a snapshot of everything before `seq` into segment
+    // `segment`.
+    Snapshot { segment: u64, seq: u64 },
+}
+
+// What the records in a manifest add up to.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct State {
+    pub format: Option<Format>,
+    // Each shard's segments, oldest first.
+    pub segments: Vec<Vec<u64>>,
+    // The segment the last compaction's snapshot went to, and the sequence
+    // number it starts at.
+    pub snapshot: Option<(u64, u64)>,
+    // The highest segment number ever recorded. A segment numbered past it
+    // was never recorded at all, rather than retired.
+    pub last_number: u64,
+}
+
+impl State {
+    fn apply(&mut self, edit: Edit) {
+        match edit {
+            Edit::Format(format) => self.format = Some(format),
+            Edit::Add { shard, number } => {
+                if self.segments.len() <= shard {
+                    self.segments.resize(shard + 1, vec![]);
+                }
+                let segments = &mut self.segments[shard];
+                let at = segments.partition_point(|&n| n < number);
+                if segments.get(at) != Some(&number) {
+                    segments.insert(at, number);
+                }
+                self.last_number = self.last_number.max(number);
+            }
+            Edit::Remove { shard, number } => {
+                if let Some(segments) = self.segments.get_mut(shard) {
+                    segments.retain(|&n| n != number);
+                }
+            }
+            Edit::Snapshot { segment, seq } => self.snapshot = Some((segment, seq)),
+        }
+    }
+
+    // The records that build this state up from nothing, but for
+    // `last_number`, which comes out as the newest segment there is.
+    fn edits(&self) -> Vec<Edit> {
+        let format = self.format.map(Edit::Format);
+        let segments = self
+            .segments
+            .iter()
+            .enumerate()
+            .flat_map(|(shard, segments)| {
+                segments
+                    .iter()
+                    .map(move |&number| Edit::Add { shard, number })
+            });
+        let snapshot = self
+            .snapshot
+            .map(|(segment, seq)| Edit::Snapshot { segment, seq });
+        format.into_iter().chain(segments).chain(snapshot).collect()
+    }
+
+    fn len(&self) -> u64 {
+        self.segments.iter().map(|s| s.len() as u64).sum::<u64>() + 2
+    }
+}
+
+// The manifest of a log that's open for writing.
+#[derive(Debug)]
+pub struct Manifest {
+    dir: PathBuf,
+    file: File,
+    state: State,
+    // How many records are in the file, which numbers the next one.
+    records: u64,
+    // Set once an append has failed, since whatever part of it made it to
+    // disk would hide anything appended after it.
+    failed: bool,
+    buf: Vec<u8>,
+}
+
+impl Manifest {
+    // Reads the manifest of the log in `dir`, if it has one. It ends at the
+    // first record that's torn or fails its checksum, which can only be one
+    // that a crash cut short, and so was never acted on.
+    pub fn load(dir: &Path) -> Result<Option<State>> {
+        let bytes = match fs::read(dir.join(MANIFEST)) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let bad = |reason: String| {
+            Error::InvalidConfig(format!("bad MANIFEST in {}: {}", dir.display(), reason))
+        };
+        let mut state = State::default();
+        let (mut at, mut seq) = (0, 1);
+        while let Ok((record, len)) = segment::decode_record(&bytes[at..]) {
+            if (record.segment, record.seq) != (0, seq) {
+                break;
+            }
+            let edit = serde_json::from_slice(record.payload).map_err(|e| bad(e.to_string()))?;
+            if let Edit::Format(format) = edit {
+                if format.version > FORMAT_VERSION {
+                    return Err(Error::IncompatibleFormat {
+                        segment: 0,
+                        reason: format!(
+                            "the log's MANIFEST says it's format version {}, and this build \
+                             only reads version {}",
+                            format.version, FORMAT_VERSION
+                        ),
+                    });
+                }
+            }
+            state.apply(edit);
+            at += len;
+            seq += 1;
+        }
+        Ok(Some(state))
+    }
+
+    // Writes a manifest for `state` into `dir`, replacing the one that's
+    // there whole, so that a crash leaves one or the other.
+    pub fn create(dir: &Path, state: &State) -> Result<Self> {
+        let tmp = dir.join(MANIFEST_TMP);
+        match fs::remove_file(&tmp) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+        let edits = state.edits();
+        let mut manifest = Manifest {
+            dir: dir.to_path_buf(),
+            file: durable_fs::create_new(&tmp)?,
+            state: State::default(),
+            records: 0,
+            failed: false,
+            buf: vec![],
+        };
+        manifest.append(&edits)?;
+        durable_fs::rename(&tmp, &dir.join(MANIFEST))?;
+        Ok(manifest)
+    }
+
+    // Appends `edits` and syncs them, to be made afterwards.
+    pub fn record(&mut self, edits: &[Edit]) -> Result<()> {
+        if edits.is_empty() {
+            return Ok(());
+        }
+        if self.failed {
+            return Err(Error::WriteFailed(format!(
+                "an earlier write to the MANIFEST in {} failed; reopen the log",
+                self.dir.display()
+            )));
+        }
+        let appended = self.append(edits);
+        self.failed = appended.is_err();
+        appended?;
+        if self.records > self.state.len() + REWRITE_AFTER {
+            *self = Self::create(&self.dir, &self.state)?;
+        }
+        Ok(())
+    }
+
+    fn append(&mut self, edits: &[Edit]) -> Result<()> {
+        self.buf.clear();
+        for (i, edit) in edits.iter().enumerate() {
+            let seq = self.records + i as u64 + 1;
+            segment::encode_record(0, seq, &error::encode(edit)?, &mut self.buf);
+        }
+        self.file.write_all(&self.buf)?;
+        durable_fs::sync_file(&self.file, Durability::Media)?;
+        self.records += edits.len() as u64;
+        for &edit in edits {
+            self.state.apply(edit);
+        }
+        Ok(())
+    }
+}
+
+// Returns the segments and recycled segments of shard `shard`, which keeps
+// them in `dir`, going by `state` if the log has a manifest and listing `dir`
+// otherwise. When `tidy`, segments the manifest doesn't list are removed, and
+// ones it does that are missing are an error. Otherwise they're passed over,
+// since a writer may be partway through adding or retiring them.
+pub fn list(
+    dir: &Path,
+    shard: usize,
+    state: Option<&State>,
+    tidy: bool,
+) -> Result<(Vec<u64>, Vec<u64>)> {
+    let (found, recycled) = segment::list(dir)?;
+    let Some(state) = state else {
+        return Ok((found, recycled));
+    };
+    let mut listed = state.segments.get(shard).cloned().unwrap_or_default();
+    if !tidy {
+        listed.retain(|n| found.contains(n));
+        return Ok((listed, recycled));
+    }
+    if let Some(number) = listed.iter().find(|&n| !found.contains(n)) {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!(
+                "segment {} of the log in {} is missing, though its MANIFEST lists it",
+                number,
+                dir.display()
+            ),
+        )
+        .into());
+    }
+    for &number in found.iter().filter(|&n| !listed.contains(n)) {
+        if number > state.last_number
+            && SegmentReader::open(dir, number)?
+                .next_record(&mut vec![])?
+                .is_some()
+        {
+            return Err(Error::InvalidConfig(format!(
+                "segment {} in {} has records in it, but the log's MANIFEST doesn't list it; \
+                 if an older build has written to the log since, removing the MANIFEST has \
+                 the log listed from its directory again",
+                number,
+                dir.display()
+            )));
+        }
+        durable_fs::remove_file(&segment::segment_path(dir, number))?;
+    }
+    Ok((listed, recycled))
+}
+
+// Whatever `Log::destroy` has to remove along with the segments.
+pub fn paths(dir: &Path) -> [PathBuf; 2] {
+    [dir.join(MANIFEST), dir.join(MANIFEST_TMP)]
+}
+
+#[test]
+fn test_manifest() -> Result<()> {
+    let dir = tempdir()?;
+    let file = dir.path().join("db");
+    let options = Options {
+        segment_size: 4096,
+        ..Options::default()
+    };
+    let mut db = Db::with_options(&file, options.clone())?;
+    for i in 0..200 {
+        db.set(&format!("k{}", i), "v")?;
+    }
+    db.compact()?;
+    db.set("after", "v")?;
+    let state = Manifest::load(&file)?.unwrap();
+    assert_eq!(state.segments, vec![segment::list(&file)?.0]);
+    assert_eq!(state.format.unwrap().segment_size, 4096);
+    let (snapshot, _) = state.snapshot.unwrap();
+    assert!(state.segments[0].contains(&snapshot));
+    drop(db);
+
+    // A segment created but never recorded is removed, as is one that was
+    // being retired, records and all.
+    let last = state.last_number;
+    let (first, retired) = (state.segments[0][0], state.segments[0][0] - 1);
+    fs::copy(
+        segment::segment_path(&file, first),
+        segment::segment_path(&file, retired),
+    )?;
+    segment::SegmentWriter::create(&file, last + 1, 0, 2, 4096, None, Durability::Media)?;
+    // Neither is a record cut short.
+    fs::OpenOptions::new()
+        .append(true)
+        .open(file.join(MANIFEST))?
+        .write_all(&[1; 10])?;
+    let db = Db::with_options(&file, options.clone())?;
+    assert_eq!(db.len(), 201);
+    assert_eq!(
+        segment::list(&file)?.0,
+        Manifest::load(&file)?.unwrap().segments[0]
+    );
+    drop(db);
+
+    // One that has records, though, wasn't written by this log.
+    let last = Manifest::load(&file)?.unwrap().last_number;
+    let mut w =
+        segment::SegmentWriter::create(&file, last + 1, 0, 2, 4096, None, Durability::Media)?;
+    w.append(b"x")?;
+    assert!(matches!(
+        Db::with_options(&file, options.clone()),
+        Err(Error::InvalidConfig(_))
+    ));
+    fs::remove_file(segment::segment_path(&file, last + 1))?;
+
+    // Nor does a missing segment go unnoticed.
+    let number = Manifest::load(&file)?.unwrap().segments[0][0];
+    let saved = fs::read(segment::segment_path(&file, number))?;
+    fs::remove_file(segment::segment_path(&file, number))?;
+    assert!(matches!(
+        Db::with_options(&file, options.clone()),
+        Err(Error::Io(e)) if e.kind() == io::ErrorKind::NotFound
+    ));
+    fs::write(segment::segment_path(&file, number), saved)?;
+
+    // A log without a manifest gets one.
+    fs::remove_file(file.join(MANIFEST))?;
+    let db = Db::with_options(&file, options)?;
+    assert_eq!(db.len(), 201);
+    assert_eq!(
+        Manifest::load(&file)?.unwrap().segments,
+        vec![segment::list(&file)?.0]
+    );
+    Ok(())
+}
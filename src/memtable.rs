@@ -0,0 +1,1471 @@
+use crate::error;
+use crate::{IdempotencyKey, MemtableKind, Result};
+use crossbeam_skiplist::SkipMap;
+use std::{
+    borrow::Cow,
+    collections::{hash_map::DefaultHasher, BTreeMap, HashMap},
+    fmt::Debug,
+    hash::{BuildHasher, BuildHasherDefault, Hash, Hasher},
+    ops::{Bound, Deref, DerefMut},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc, RwLock, RwLockReadGuard, RwLockWriteGuard,
+    },
+};
+
+// Roughly what a `HashMap` slot and two `String`s cost on top of the bytes in
+// them, so that lots of tiny entries still count for something.
+const ENTRY_OVERHEAD: usize = 64;
+
+// A range of keys deleted with `Db::delete_range` or `Db::delete_prefix`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KeyRange {
+    // From the first key up to but not including the second.
+    Between(String, String),
+    Prefix(String),
+}
+
+impl KeyRange {
+    pub fn contains(&self, k: &str) -> bool {
+        match self {
+            KeyRange::Between(start, end) => start.as_str() <= k && k < end.as_str(),
+            KeyRange::Prefix(prefix) => k.starts_with(prefix.as_str()),
+        }
+    }
+
+    // The first key that could be in the range. Every key in it comes before
+    // any key after this that isn't.
+    pub fn start(&self) -> &str {
+        match self {
+            KeyRange::Between(start, _) | KeyRange::Prefix(start) => start,
+        }
+    }
+
+    fn len(&self) -> usize {
+        match self {
+            KeyRange::Between(start, end) => start.len() + end.len(),
+            KeyRange::Prefix(prefix) => prefix.len(),
+        }
+    }
+}
+
+// A key and its value, or None for a deletion.
+type Entry<'a> = (Cow<'a, str>, Option<Cow<'a, str>>);
+
+// Where a memtable keeps its entries, each a value or None for a deletion,
+// which is up to `Options::memtable`. Keys are boxed rather than `String`s,
+// since they never change once they're in, and that saves their capacity.
+// Entries are handed out as `Cow`s, since a concurrent map can't lend out
+// what another thread might replace under it, and has to copy them instead.
+trait Entries: Debug + Send + Sync {
+    fn get(&self, k: &str) -> Option<Option<Cow<'_, str>>>;
+    // Changes the value of `k` in place, if it has one.
+    fn update(&mut self, k: &str, f: &mut dyn FnMut(&mut String)) -> bool;
+    // Returns what the entry replaced, if there was one. The key is only
+    // allocated if it's new.
+    fn insert(&mut self, k: Cow<str>, v: Option<String>) -> Option<Option<String>>;
+    fn remove(&mut self, k: &str) -> Option<(Box<str>, Option<String>)>;
+    fn retain(&mut self, f: &mut dyn FnMut(&str, &mut Option<String>) -> bool);
+    fn len(&self) -> usize;
+    fn iter(&self) -> Box<dyn Iterator<Item = Entry<'_>> + '_>;
+    fn clear(&mut self);
+    fn clone_box(&self) -> Box<dyn Entries>;
+    // An empty map of the same kind.
+    fn empty(&self) -> Box<dyn Entries>;
+
+    fn concurrent(&self) -> Option<&Arc<Concurrent>> {
+        None
+    }
+
+    // Every entry whose key starts with `prefix`, in key order.
+    fn sorted(&self, prefix: &str) -> Vec<(String, Option<String>)> {
+        let mut entries = self
+            .iter()
+            .filter(|(k, _)| k.starts_with(prefix))
+            .map(|(k, v)| (k.into_owned(), v.map(Cow::into_owned)))
+            .collect::<Vec<_>>();
+        entries.sort_unstable();
+        entries
+    }
+
+    // The first `n` entries from `start` on, in key order.
+    fn sorted_from(&self, start: &str, n: usize) -> Vec<(String, Option<String>)> {
+        let mut entries = self
+            .iter()
+            .filter(|(k, _)| k.as_ref() >= start)
+            .collect::<Vec<_>>();
+        if entries.len() > n {
+            entries.select_nth_unstable_by(n, |a, b| a.0.cmp(&b.0));
+            entries.truncate(n);
+        }
+        entries.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+        entries
+            .into_iter()
+            .map(|(k, v)| (k.into_owned(), v.map(Cow::into_owned)))
+            .collect()
+    }
+}
+
+impl<S> Entries for HashMap<Box<str>, Option<String>, S>
+where
+    S: BuildHasher + Default + Clone + Debug + Send + Sync + 'static,
+{
+    fn get(&self, k: &str) -> Option<Option<Cow<'_, str>>> {
+        HashMap::get(self, k).map(|v| v.as_deref().map(Cow::Borrowed))
+    }
+
+    fn update(&mut self, k: &str, f: &mut dyn FnMut(&mut String)) -> bool {
+        HashMap::get_mut(self, k)
+            .and_then(Option::as_mut)
+            .map(f)
+            .is_some()
+    }
+
+    fn insert(&mut self, k: Cow<str>, v: Option<String>) -> Option<Option<String>> {
+        match HashMap::get_mut(self, &*k) {
+            Some(slot) => Some(std::mem::replace(slot, v)),
+            None => HashMap::insert(self, k.into(), v),
+        }
+    }
+
+    fn remove(&mut self, k: &str) -> Option<(Box<str>, Option<String>)> {
+        self.remove_entry(k)
+    }
+
+    fn retain(&mut self, f: &mut dyn FnMut(&str, &mut Option<String>) -> bool) {
+        HashMap::retain(self, |k, v| f(k, v));
+    }
+
+    fn len(&self) -> usize {
+        HashMap::len(self)
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = Entry<'_>> + '_> {
+        Box::new(
+            HashMap::iter(self)
+                .map(|(k, v)| (Cow::Borrowed(&**k), v.as_deref().map(Cow::Borrowed))),
+        )
+    }
+
+    fn clear(&mut self) {
+        HashMap::clear(self);
+    }
+
+    fn clone_box(&self) -> Box<dyn Entries> {
+        Box::new(self.clone())
+    }
+
+    fn empty(&self) -> Box<dyn Entries> {
+        Box::<HashMap<_, _, S>>::default()
+    }
+}
+
+// Slower to get at single keys, but already in order for scans and flushes.
+impl Entries for BTreeMap<Box<str>, Option<String>> {
+    fn get(&self, k: &str) -> Option<Option<Cow<'_, str>>> {
+        BTreeMap::get(self, k).map(|v| v.as_deref().map(Cow::Borrowed))
+    }
+
+    fn update(&mut self, k: &str, f: &mut dyn FnMut(&mut String)) -> bool {
+        BTreeMap::get_mut(self, k)
+            .and_then(Option::as_mut)
+            .map(f)
+            .is_some()
+    }
+
+    fn insert(&mut self, k: Cow<str>, v: Option<String>) -> Option<Option<String>> {
+        match BTreeMap::get_mut(self, &*k) {
+            Some(slot) => Some(std::mem::replace(slot, v)),
+            None => BTreeMap::insert(self, k.into(), v),
+        }
+    }
+
+    fn remove(&mut self, k: &str) -> Option<(Box<str>, Option<String>)> {
+        self.remove_entry(k)
+    }
+
+    fn retain(&mut self, f: &mut dyn FnMut(&str, &mut Option<String>) -> bool) {
+        BTreeMap::retain(self, |k, v| f(k, v));
+    }
+
+    fn len(&self) -> usize {
+        BTreeMap::len(self)
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = Entry<'_>> + '_> {
+        Box::new(
+            BTreeMap::iter(self)
+                .map(|(k, v)| (Cow::Borrowed(&**k), v.as_deref().map(Cow::Borrowed))),
+        )
+    }
+
+    fn clear(&mut self) {
+        BTreeMap::clear(self);
+    }
+
+    fn clone_box(&self) -> Box<dyn Entries> {
+        Box::new(self.clone())
+    }
+
+    fn empty(&self) -> Box<dyn Entries> {
+        Box::<BTreeMap<_, _>>::default()
+    }
+
+    fn sorted(&self, prefix: &str) -> Vec<(String, Option<String>)> {
+        self.range::<str, _>((Bound::Included(prefix), Bound::Unbounded))
+            .take_while(|(k, _)| k.starts_with(prefix))
+            .map(|(k, v)| (k.to_string(), v.clone()))
+            .collect()
+    }
+
+    fn sorted_from(&self, start: &str, n: usize) -> Vec<(String, Option<String>)> {
+        self.range::<str, _>((Bound::Included(start), Bound::Unbounded))
+            .take(n)
+            .map(|(k, v)| (k.to_string(), v.clone()))
+            .collect()
+    }
+}
+
+// The entries of a `MemtableKind::Concurrent` memtable: in order like the
+// B-tree, but in a lock-free skiplist, which its `ShardedMemtable` shares so
+// that it can read them without taking the lock (see `get_unlocked`). Each
+// entry is tagged with the write that made it, counting each time the shard
+// is locked for writing, so that a read can tell what a write still holding
+// the lock has done. It can't lend out its entries, though, so every read
+// copies the value, and every change copies the entry it changes.
+#[derive(Debug, Default)]
+struct Concurrent {
+    entries: SkipMap<Box<str>, (u64, Option<String>)>,
+    // The write holding the lock, if there is one, and the last to let it go.
+    writing: AtomicU64,
+    written: AtomicU64,
+    // Whether any of the memtable's keys have TTLs, which only it knows.
+    expiring: AtomicBool,
+}
+
+impl Concurrent {
+    fn tag(&self) -> u64 {
+        self.writing.load(Ordering::Relaxed)
+    }
+
+    // The value of `k`, unless a write that hasn't let go of the lock yet
+    // changed it.
+    fn read(&self, k: &str) -> Option<Option<String>> {
+        let written = self.written.load(Ordering::Acquire);
+        let entry = self.entries.get(k)?;
+        let (tag, v) = entry.value();
+        (*tag <= written && !self.expiring.load(Ordering::Acquire)).then(|| v.clone())
+    }
+}
+
+impl Entries for Arc<Concurrent> {
+    fn get(&self, k: &str) -> Option<Option<Cow<'_, str>>> {
+        let entry = self.entries.get(k)?;
+        Some(entry.value().1.clone().map(Cow::Owned))
+    }
+
+    fn update(&mut self, k: &str, f: &mut dyn FnMut(&mut String)) -> bool {
+        let Some(entry) = self.entries.get(k) else {
+            return false;
+        };
+        let Some(mut v) = entry.value().1.clone() else {
+            return false;
+        };
+        f(&mut v);
+        self.entries
+            .insert(entry.key().clone(), (self.tag(), Some(v)));
+        true
+    }
+
+    // Replaces the entry rather than removing it first, so that there's no
+    // moment when it isn't there.
+    fn insert(&mut self, k: Cow<str>, v: Option<String>) -> Option<Option<String>> {
+        let prior = self.entries.get(&*k).map(|e| e.value().1.clone());
+        self.entries.insert(k.into(), (self.tag(), v));
+        prior
+    }
+
+    fn remove(&mut self, k: &str) -> Option<(Box<str>, Option<String>)> {
+        let entry = self.entries.remove(k)?;
+        Some((entry.key().clone(), entry.value().1.clone()))
+    }
+
+    fn retain(&mut self, f: &mut dyn FnMut(&str, &mut Option<String>) -> bool) {
+        for entry in self.entries.iter() {
+            let mut v = entry.value().1.clone();
+            if !f(entry.key(), &mut v) {
+                entry.remove();
+            } else if v != entry.value().1 {
+                self.entries.insert(entry.key().clone(), (self.tag(), v));
+            }
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = Entry<'_>> + '_> {
+        Box::new(self.entries.iter().map(|e| {
+            let v = e.value().1.clone().map(Cow::Owned);
+            (Cow::Owned(e.key().to_string()), v)
+        }))
+    }
+
+    fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    // The copy starts counting writes over, so its entries are tagged as
+    // though they'd all been written before the first.
+    fn clone_box(&self) -> Box<dyn Entries> {
+        let copy = Concurrent {
+            expiring: AtomicBool::new(self.expiring.load(Ordering::Relaxed)),
+            ..Concurrent::default()
+        };
+        for entry in self.entries.iter() {
+            let v = entry.value().1.clone();
+            copy.entries.insert(entry.key().clone(), (0, v));
+        }
+        Box::new(Arc::new(copy))
+    }
+
+    fn empty(&self) -> Box<dyn Entries> {
+        Box::<Arc<Concurrent>>::default()
+    }
+
+    fn sorted(&self, prefix: &str) -> Vec<(String, Option<String>)> {
+        let entries = self
+            .entries
+            .range::<str, _>((Bound::Included(prefix), Bound::Unbounded));
+        entries
+            .take_while(|e| e.key().starts_with(prefix))
+            .map(|e| (e.key().to_string(), e.value().1.clone()))
+            .collect()
+    }
+
+    fn sorted_from(&self, start: &str, n: usize) -> Vec<(String, Option<String>)> {
+        let entries = self
+            .entries
+            .range::<str, _>((Bound::Included(start), Bound::Unbounded));
+        entries
+            .take(n)
+            .map(|e| (e.key().to_string(), e.value().1.clone()))
+            .collect()
+    }
+
+    fn concurrent(&self) -> Option<&Arc<Concurrent>> {
+        Some(self)
+    }
+}
+
+// FxHash, as rustc uses for its own maps: a multiply for every eight bytes,
+// which makes it much quicker than the standard library's SipHash for short
+// keys. Unlike SipHash, though, whoever chooses the keys can choose them to
+// collide. The multiply leaves the low bits, which pick a key's slot in the
+// map, depending on little more than the last few bytes, so `finish` rotates
+// the high bits down into them, as the rustc-hash crate has since 2.0.
+#[derive(Debug, Default, Clone, Copy)]
+struct FxHasher(u64);
+
+impl FxHasher {
+    fn add(&mut self, word: u64) {
+        self.0 = self
+            .0
+            .wrapping_add(word)
+            .wrapping_mul(0xf1_35_7a_ea_2e_62_a9_c5);
+    }
+}
+
+impl Hasher for FxHasher {
+    fn write(&mut self, bytes: &[u8]) {
+        let mut chunks = bytes.chunks_exact(8);
+        for chunk in &mut chunks {
+            self.add(u64::from_le_bytes(chunk.try_into().unwrap()));
+        }
+        let mut last = [0; 8];
+        let rest = chunks.remainder();
+        if !rest.is_empty() {
+            last[..rest.len()].copy_from_slice(rest);
+            self.add(u64::from_le_bytes(last));
+        }
+    }
+
+    fn write_u8(&mut self, i: u8) {
+        self.add(i as u64);
+    }
+
+    fn finish(&self) -> u64 {
+        self.0.rotate_left(26)
+    }
+}
+
+impl Clone for Box<dyn Entries> {
+    fn clone(&self) -> Self {
+        self.clone_box()
+    }
+}
+
+// The latest value of every key written since the memtable was last flushed.
+// Before the first flush a deleted key can simply be forgotten; after it, the
+// deletion has to be remembered until the next one, since a table might still
+// have the key. The same goes for deleted ranges.
+#[derive(Debug, Clone)]
+pub struct Memtable {
+    entries: Box<dyn Entries>,
+    // Roughly how much memory the entries take up.
+    bytes: usize,
+    // The same for what a flush leaves behind: expiries, keyspaces, requests
+    // and snapshot history.
+    retained: usize,
+    tombstones: bool,
+    // Ranges deleted since the last flush, which hide whatever the tables have
+    // in them. Anything written to the memtable since is in `entries`, which
+    // take precedence.
+    deleted_ranges: Vec<KeyRange>,
+    // When keys set with a TTL expire, in milliseconds since the Unix epoch.
+    // These outlive a flush, since tables don't keep them.
+    expiries: HashMap<String, u64>,
+    // Named keyspaces (see `Db::cf`), which share the log but nothing else.
+    // They're never flushed, so they're plain maps, and one goes away when
+    // its last key does.
+    keyspaces: BTreeMap<String, HashMap<String, String>>,
+    // The last request applied for each client (see `IdempotencyKey`), which
+    // also outlives a flush.
+    requests: HashMap<u64, u64>,
+    // The seq each open snapshot (see `Db::snapshot`) reads at, and how many
+    // read there.
+    snapshots: BTreeMap<u64, usize>,
+    // What was overwritten while snapshots were open: for each key, the seq
+    // of each write and the value it replaced, oldest first. A flush doesn't
+    // touch these, and they're dropped once no snapshot needs them.
+    history: HashMap<String, Vec<(u64, Option<String>)>>,
+}
+
+// The same as deriving it, but with entries that are equal whatever they're
+// kept in.
+impl PartialEq for Memtable {
+    fn eq(&self, other: &Self) -> bool {
+        let Memtable {
+            entries,
+            bytes,
+            retained,
+            tombstones,
+            deleted_ranges,
+            expiries,
+            keyspaces,
+            requests,
+            snapshots,
+            history,
+        } = self;
+        entries.len() == other.entries.len()
+            && entries
+                .iter()
+                .all(|(k, v)| other.entries.get(&k) == Some(v))
+            && *bytes == other.bytes
+            && *retained == other.retained
+            && *tombstones == other.tombstones
+            && *deleted_ranges == other.deleted_ranges
+            && *expiries == other.expiries
+            && *keyspaces == other.keyspaces
+            && *requests == other.requests
+            && *snapshots == other.snapshots
+            && *history == other.history
+    }
+}
+
+impl Eq for Memtable {}
+
+impl Default for Memtable {
+    fn default() -> Self {
+        Memtable::new(MemtableKind::Hash, false)
+    }
+}
+
+impl Memtable {
+    pub fn new(kind: MemtableKind, tombstones: bool) -> Self {
+        let entries: Box<dyn Entries> = match kind {
+            MemtableKind::Hash => Box::<HashMap<_, _>>::default(),
+            MemtableKind::FxHash => Box::<HashMap<_, _, BuildHasherDefault<FxHasher>>>::default(),
+            MemtableKind::Ordered => Box::<BTreeMap<_, _>>::default(),
+            MemtableKind::Concurrent => Box::<Arc<Concurrent>>::default(),
+        };
+        Memtable {
+            entries,
+            bytes: 0,
+            retained: 0,
+            tombstones,
+            deleted_ranges: vec![],
+            expiries: HashMap::new(),
+            keyspaces: BTreeMap::new(),
+            requests: HashMap::new(),
+            snapshots: BTreeMap::new(),
+            history: HashMap::new(),
+        }
+    }
+
+    // `Some(None)` if the key has been deleted since the last flush, and None
+    // if it hasn't been touched.
+    pub fn get(&self, k: &str) -> Option<Option<Cow<'_, str>>> {
+        match self.entries.get(k) {
+            Some(v) => Some(v),
+            None if self.deleted_ranges.iter().any(|r| r.contains(k)) => Some(None),
+            None => None,
+        }
+    }
+
+    pub fn set(&mut self, k: Cow<str>, v: Cow<str>) {
+        self.forget_expiry(&k);
+        self.put(k, Some(v));
+    }
+
+    pub fn set_expiring(&mut self, k: Cow<str>, v: Cow<str>, expires_at: u64) {
+        self.expire(&k, expires_at);
+        self.put(k, Some(v));
+    }
+
+    // Adds `element` to the end of the list at `k` (see `append_element`),
+    // in place if the memtable has it. Otherwise `base` is called for what
+    // the tables have, and if that fails, nothing is appended. A list keeps
+    // its TTL as it grows.
+    pub fn append<F>(&mut self, k: Cow<str>, element: &str, base: F) -> Result<()>
+    where
+        F: FnOnce() -> Result<Option<String>>,
+    {
+        let bytes = &mut self.bytes;
+        let appended = self.entries.update(&k, &mut |list| {
+            let before = list.len();
+            append_element(list, element);
+            *bytes = *bytes - before + list.len();
+        });
+        if appended {
+            return Ok(());
+        }
+        let mut list = match self.get(&k) {
+            Some(_) => String::new(),
+            None => base()?.unwrap_or_default(),
+        };
+        append_element(&mut list, element);
+        self.put(k, Some(list.into()));
+        Ok(())
+    }
+
+    // Sets when `k` expires, without touching its value.
+    pub fn expire(&mut self, k: &str, expires_at: u64) {
+        match self.expiries.get_mut(k) {
+            Some(at) => *at = expires_at,
+            None => {
+                self.retained += ENTRY_OVERHEAD + k.len();
+                self.expiries.insert(k.to_owned(), expires_at);
+            }
+        }
+        self.note_expiries();
+    }
+
+    // Lets lock-free reads know whether there are TTLs to check, which they
+    // leave to reads that take the lock. Writes call this once they've
+    // changed the value as well, so that a read never sees the value a TTL
+    // was set on without it.
+    fn note_expiries(&self) {
+        if let Some(concurrent) = self.entries.concurrent() {
+            let expiring = !self.expiries.is_empty();
+            concurrent.expiring.store(expiring, Ordering::Release);
+        }
+    }
+
+    fn forget_expiry(&mut self, k: &str) {
+        if !self.expiries.is_empty() && self.expiries.remove(k).is_some() {
+            self.retained -= ENTRY_OVERHEAD + k.len();
+        }
+    }
+
+    // Whether `k` had a TTL that has run out by `now`. It's still here until
+    // it's deleted, by `Db::expire`, but reads shouldn't see it.
+    pub fn is_expired(&self, k: &str, now: u64) -> bool {
+        self.expiry(k).is_some_and(|at| at <= now)
+    }
+
+    pub fn expiry(&self, k: &str) -> Option<u64> {
+        self.expiries.get(k).copied()
+    }
+
+    // Every key whose TTL has run out by `now`.
+    pub fn expired(&self, now: u64) -> Vec<String> {
+        self.expiries
+            .iter()
+            .filter(|(_, &at)| at <= now)
+            .map(|(k, _)| k.clone())
+            .collect()
+    }
+
+    pub fn expiries(&self) -> impl Iterator<Item = (&String, u64)> {
+        self.expiries.iter().map(|(k, &at)| (k, at))
+    }
+
+    pub fn delete(&mut self, k: Cow<str>) {
+        self.forget_expiry(&k);
+        if self.tombstones {
+            self.put(k, None);
+        } else if let Some((k, v)) = self.entries.remove(&k) {
+            self.bytes -= ENTRY_OVERHEAD + k.len() + v.map_or(0, |v| v.len());
+        }
+        self.note_expiries();
+    }
+
+    // Drops whatever the memtable has in `range` straight away, but the
+    // tables are left alone: the range is remembered, and hides their keys
+    // until the next flush deletes them for good.
+    pub fn delete_range(&mut self, range: KeyRange) {
+        let retained = &mut self.retained;
+        self.expiries.retain(|k, _| {
+            let keep = !range.contains(k);
+            if !keep {
+                *retained -= ENTRY_OVERHEAD + k.len();
+            }
+            keep
+        });
+        let bytes = &mut self.bytes;
+        self.entries.retain(&mut |k, v| {
+            let keep = !range.contains(k);
+            if !keep {
+                *bytes -= ENTRY_OVERHEAD + k.len() + v.as_ref().map_or(0, |v| v.len());
+            }
+            keep
+        });
+        if self.tombstones {
+            self.bytes += ENTRY_OVERHEAD + range.len();
+            self.deleted_ranges.push(range);
+        }
+        self.note_expiries();
+    }
+
+    pub fn deleted_ranges(&self) -> &[KeyRange] {
+        &self.deleted_ranges
+    }
+
+    // Keeps deletions as tombstones from now on, for a flush that's about to
+    // start a table they may have to hide keys in.
+    pub fn keep_tombstones(&mut self) {
+        self.tombstones = true;
+    }
+
+    // Drops what a flush wrote out to a table: the entries in `flushed`,
+    // which is what `sorted` had when it started, that are still the same,
+    // and the first `ranges` deleted ranges, which it had then too. Whatever
+    // was written since is kept for the next flush.
+    pub fn forget_flushed(&mut self, flushed: &[(String, Option<String>)], ranges: usize) {
+        let bytes = &mut self.bytes;
+        self.entries.retain(&mut |k, v| {
+            let i = flushed.binary_search_by(|(f, _)| f.as_str().cmp(k));
+            let unchanged = i.is_ok_and(|i| flushed[i].1 == *v);
+            if unchanged {
+                *bytes -= ENTRY_OVERHEAD + k.len() + v.as_ref().map_or(0, |v| v.len());
+            }
+            !unchanged
+        });
+        for range in self.deleted_ranges.drain(..ranges) {
+            self.bytes -= ENTRY_OVERHEAD + range.len();
+        }
+    }
+
+    // Only allocates for the key if it's new.
+    fn put(&mut self, k: Cow<str>, v: Option<Cow<str>>) {
+        let len = v.as_ref().map_or(0, |v| v.len());
+        let key_len = k.len();
+        match self.entries.insert(k, v.map(Cow::into_owned)) {
+            Some(prior) => self.bytes = self.bytes - prior.map_or(0, |v| v.len()) + len,
+            None => self.bytes += ENTRY_OVERHEAD + key_len + len,
+        }
+        self.note_expiries();
+    }
+
+    // Roughly how much memory the entries that a flush writes out take up.
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    // Roughly how much memory the whole memtable takes up, which is `bytes`
+    // plus whatever a flush would leave behind.
+    pub fn memory(&self) -> usize {
+        self.bytes + self.retained
+    }
+
+    // The number of keys with a value, not counting deletions.
+    pub fn len(&self) -> usize {
+        if self.tombstones {
+            self.entries.iter().filter(|(_, v)| v.is_some()).count()
+        } else {
+            self.entries.len()
+        }
+    }
+
+    // How many keys in `range` have a value, and how many bytes the entries
+    // in it, deletions included, would take up in a table.
+    pub fn approximate(&self, range: &KeyRange) -> (u64, u64) {
+        let in_range = self.entries.iter().filter(|(k, _)| range.contains(k));
+        in_range.fold((0, 0), |(keys, bytes), (k, v)| {
+            let len = 8 + k.len() + v.as_ref().map_or(0, |v| v.len());
+            (keys + v.is_some() as u64, bytes + len as u64)
+        })
+    }
+
+    // Every key with a value.
+    pub fn iter(&self) -> impl Iterator<Item = (Cow<'_, str>, Cow<'_, str>)> {
+        self.entries.iter().filter_map(|(k, v)| v.map(|v| (k, v)))
+    }
+
+    // Every entry whose key starts with `prefix`, deletions included, in key
+    // order.
+    pub fn sorted(&self, prefix: &str) -> Vec<(String, Option<String>)> {
+        self.entries.sorted(prefix)
+    }
+
+    pub fn sorted_from(&self, start: &str, n: usize) -> Vec<(String, Option<String>)> {
+        self.entries.sorted_from(start, n)
+    }
+
+    pub fn keyspace(&self, name: &str) -> Option<&HashMap<String, String>> {
+        self.keyspaces.get(name)
+    }
+
+    pub fn keyspaces(&self) -> impl Iterator<Item = (&String, &HashMap<String, String>)> {
+        self.keyspaces.iter()
+    }
+
+    pub fn set_in(&mut self, name: Cow<str>, k: Cow<str>, v: Cow<str>) {
+        let keyspace = match self.keyspaces.get_mut(&*name) {
+            Some(keyspace) => keyspace,
+            None => {
+                self.retained += ENTRY_OVERHEAD + name.len();
+                self.keyspaces.entry(name.into_owned()).or_default()
+            }
+        };
+        match keyspace.get_mut(&*k) {
+            Some(slot) => {
+                self.retained = self.retained - slot.len() + v.len();
+                *slot = v.into_owned();
+            }
+            None => {
+                self.retained += ENTRY_OVERHEAD + k.len() + v.len();
+                keyspace.insert(k.into_owned(), v.into_owned());
+            }
+        }
+    }
+
+    pub fn delete_in(&mut self, name: &str, k: &str) {
+        if let Some(keyspace) = self.keyspaces.get_mut(name) {
+            if let Some(v) = keyspace.remove(k) {
+                self.retained -= ENTRY_OVERHEAD + k.len() + v.len();
+            }
+            if keyspace.is_empty() {
+                self.drop_keyspace(name);
+            }
+        }
+    }
+
+    pub fn drop_keyspace(&mut self, name: &str) {
+        if let Some(keyspace) = self.keyspaces.remove(name) {
+            self.retained -= ENTRY_OVERHEAD + name.len();
+            for (k, v) in keyspace {
+                self.retained -= ENTRY_OVERHEAD + k.len() + v.len();
+            }
+        }
+    }
+
+    pub fn note_request(&mut self, key: IdempotencyKey) {
+        if self
+            .requests
+            .insert(key.client_id, key.request_id)
+            .is_none()
+        {
+            self.retained += ENTRY_OVERHEAD;
+        }
+    }
+
+    pub fn last_request(&self, client_id: u64) -> Option<u64> {
+        self.requests.get(&client_id).copied()
+    }
+
+    pub fn requests(&self) -> impl Iterator<Item = IdempotencyKey> + '_ {
+        self.requests
+            .iter()
+            .map(|(&client_id, &request_id)| IdempotencyKey {
+                client_id,
+                request_id,
+            })
+    }
+
+    pub fn pin(&mut self, seq: u64) {
+        *self.snapshots.entry(seq).or_default() += 1;
+    }
+
+    pub fn unpin(&mut self, seq: u64) {
+        if let Some(n) = self.snapshots.get_mut(&seq) {
+            *n -= 1;
+            if *n == 0 {
+                self.snapshots.remove(&seq);
+            }
+        }
+        // A version is only read by snapshots from before it was replaced.
+        let oldest = self.oldest_snapshot();
+        let retained = &mut self.retained;
+        match oldest {
+            None => {
+                for (k, versions) in std::mem::take(&mut self.history) {
+                    *retained -= version_bytes(&k, &versions);
+                }
+            }
+            Some(oldest) => self.history.retain(|k, versions| {
+                *retained -= version_bytes(k, versions);
+                versions.retain(|&(seq, _)| seq >= oldest);
+                *retained += version_bytes(k, versions);
+                !versions.is_empty()
+            }),
+        }
+    }
+
+    pub fn oldest_snapshot(&self) -> Option<u64> {
+        self.snapshots.keys().next().copied()
+    }
+
+    pub fn has_snapshots(&self) -> bool {
+        !self.snapshots.is_empty()
+    }
+
+    // Notes that the write numbered `seq` replaced `prior` as the value of
+    // `k`. If no snapshot was taken since the last write to `k` that was
+    // noted, the snapshots that could read this version read that one.
+    pub fn remember(&mut self, k: String, seq: u64, prior: Option<String>) {
+        let newest = match self.snapshots.keys().next_back() {
+            Some(&newest) => newest,
+            None => return,
+        };
+        let key_len = k.len();
+        let versions = self.history.entry(k).or_default();
+        if versions.last().is_none_or(|&(last, _)| last < newest) {
+            let first = match versions.is_empty() {
+                true => key_len,
+                false => 0,
+            };
+            self.retained += first + ENTRY_OVERHEAD + prior.as_ref().map_or(0, |v| v.len());
+            versions.push((seq, prior));
+        }
+    }
+
+    // The value `k` had before the first write to it from `seq` on, or None
+    // if it hasn't been written since, as far as the history goes.
+    pub fn value_at(&self, k: &str, seq: u64) -> Option<Option<&String>> {
+        let versions = self.history.get(k)?;
+        let (_, prior) = versions.iter().find(|&&(replaced, _)| replaced >= seq)?;
+        Some(prior.as_ref())
+    }
+
+    // `value_at` for every key starting with `prefix` written since `seq`,
+    // in key order.
+    pub fn history_at(&self, prefix: &str, seq: u64) -> Vec<(String, Option<String>)> {
+        let mut entries = self
+            .history
+            .keys()
+            .filter(|k| k.starts_with(prefix))
+            .filter_map(|k| Some((k.clone(), self.value_at(k, seq)?.cloned())))
+            .collect::<Vec<_>>();
+        entries.sort_unstable();
+        entries
+    }
+
+    // A checksum of everything replay rebuilds, which leaves out the size and
+    // what snapshots need. Maps are summed over rather than hashed in order,
+    // so that it doesn't depend on how they happen to be laid out.
+    pub fn checksum(&self) -> u32 {
+        self.shared_checksum().wrapping_add(self.keyed_checksum())
+    }
+
+    // The part of `checksum` that each shard of a `ShardedMemtable` has a copy
+    // of: whether there are tombstones, and the deleted ranges.
+    fn shared_checksum(&self) -> u32 {
+        let mut sum = hash(&[&[self.tombstones as u8]]);
+        for (i, range) in self.deleted_ranges.iter().enumerate() {
+            let i = (i as u64).to_le_bytes();
+            sum = sum.wrapping_add(match range {
+                KeyRange::Between(start, end) => {
+                    hash(&[b"between", &i, start.as_bytes(), end.as_bytes()])
+                }
+                KeyRange::Prefix(prefix) => hash(&[b"prefix", &i, prefix.as_bytes()]),
+            });
+        }
+        sum
+    }
+
+    // The rest, which adds up across shards to what the memtable they were
+    // split from would have.
+    fn keyed_checksum(&self) -> u32 {
+        let mut sum = 0u32;
+        for (k, v) in self.entries.iter() {
+            sum = sum.wrapping_add(match v {
+                Some(v) => hash(&[b"set", k.as_bytes(), v.as_bytes()]),
+                None => hash(&[b"delete", k.as_bytes()]),
+            });
+        }
+        for (k, at) in &self.expiries {
+            sum = sum.wrapping_add(hash(&[b"expiry", k.as_bytes(), &at.to_le_bytes()]));
+        }
+        for (name, keyspace) in &self.keyspaces {
+            for (k, v) in keyspace {
+                let parts: [&[u8]; 4] = [b"keyspace", name.as_bytes(), k.as_bytes(), v.as_bytes()];
+                sum = sum.wrapping_add(hash(&parts));
+            }
+        }
+        for (client, request) in &self.requests {
+            let parts: [&[u8]; 3] = [b"request", &client.to_le_bytes(), &request.to_le_bytes()];
+            sum = sum.wrapping_add(hash(&parts));
+        }
+        sum
+    }
+
+    // Empties the memtable once everything in it has been flushed. Keyspaces,
+    // expiries, requests and snapshot history aren't flushed, so they stay.
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.deleted_ranges.clear();
+        self.bytes = 0;
+        self.tombstones = true;
+    }
+
+    // Splits the memtable into `n` shards the way `ShardedMemtable` keeps
+    // them: each key's entry, expiry and history go to the shard it hashes
+    // to, every shard gets the deleted ranges and open snapshots, and the
+    // first gets the keyspaces and requests.
+    fn split(mut self, n: usize) -> Vec<Memtable> {
+        if n == 1 {
+            return vec![self];
+        }
+        let mut shards = (0..n)
+            .map(|_| Memtable {
+                entries: self.entries.empty(),
+                bytes: 0,
+                retained: 0,
+                tombstones: self.tombstones,
+                deleted_ranges: vec![],
+                expiries: HashMap::new(),
+                keyspaces: BTreeMap::new(),
+                requests: HashMap::new(),
+                snapshots: self.snapshots.clone(),
+                history: HashMap::new(),
+            })
+            .collect::<Vec<_>>();
+        self.entries.retain(&mut |k, v| {
+            shards[shard_of(k, n)].put(k.into(), v.take().map(Cow::Owned));
+            false
+        });
+        for shard in &mut shards {
+            for range in &self.deleted_ranges {
+                shard.bytes += ENTRY_OVERHEAD + range.len();
+            }
+            shard.deleted_ranges = self.deleted_ranges.clone();
+        }
+        for (k, at) in self.expiries.drain() {
+            shards[shard_of(&k, n)].expire(&k, at);
+        }
+        for (k, versions) in self.history.drain() {
+            let shard = &mut shards[shard_of(&k, n)];
+            shard.retained += version_bytes(&k, &versions);
+            shard.history.insert(k, versions);
+        }
+        let home = &mut shards[0];
+        for (name, keyspace) in std::mem::take(&mut self.keyspaces) {
+            for (k, v) in keyspace {
+                home.set_in(name.as_str().into(), k.into(), v.into());
+            }
+        }
+        for (client_id, request_id) in self.requests.drain() {
+            home.note_request(IdempotencyKey {
+                client_id,
+                request_id,
+            });
+        }
+        shards
+    }
+}
+
+fn hash(parts: &[&[u8]]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    for part in parts {
+        hasher.update(&(part.len() as u64).to_le_bytes());
+        hasher.update(part);
+    }
+    hasher.finalize()
+}
+
+// Which of `n` shards `k` belongs in.
+fn shard_of(k: &str, n: usize) -> usize {
+    if n == 1 {
+        return 0;
+    }
+    let mut hasher = DefaultHasher::new();
+    k.hash(&mut hasher);
+    (hasher.finish() % n as u64) as usize
+}
+
+// The memtable split by key into `Options::memtable_shards` shards, each
+// behind a lock of its own, so that a read only waits on batches that write
+// to its key's shard. Each shard is a memtable in its own right, holding its
+// keys' entries, expiries and history. They all keep the deleted ranges,
+// counting them towards their bytes, and the open snapshots, and the first
+// keeps the keyspaces and requests. Shards are always locked in order, and a
+// batch locks each shard it writes to before applying any of it, so that
+// reads see all of a batch or none of it.
+#[derive(Debug)]
+pub struct ShardedMemtable {
+    shards: Box<[RwLock<Memtable>]>,
+    // Each shard's entries, if it's `MemtableKind::Concurrent`.
+    concurrent: Box<[Option<Arc<Concurrent>>]>,
+    // `Memtable::memory` of every shard between them, brought up to date as
+    // each lets go of its write lock.
+    memory: AtomicUsize,
+}
+
+impl ShardedMemtable {
+    pub fn new(memtable: Memtable, shards: usize) -> Self {
+        let shards = memtable.split(shards.max(1));
+        let concurrent = shards.iter().map(|s| s.entries.concurrent().cloned());
+        ShardedMemtable {
+            concurrent: concurrent.collect(),
+            memory: AtomicUsize::new(shards.iter().map(Memtable::memory).sum()),
+            shards: shards.into_iter().map(RwLock::new).collect(),
+        }
+    }
+
+    pub fn shard_count(&self) -> usize {
+        self.shards.len()
+    }
+
+    pub fn shard_of(&self, k: &str) -> usize {
+        shard_of(k, self.shards.len())
+    }
+
+    // The shard `k` is in. Reads carry on if a thread panicked while holding
+    // a shard, as they do for every other lock (see `error::lock`).
+    pub fn key(&self, k: &str) -> RwLockReadGuard<'_, Memtable> {
+        error::read(&self.shards[self.shard_of(k)])
+    }
+
+    // The first shard, which has the keyspaces and requests.
+    pub fn home(&self) -> RwLockReadGuard<'_, Memtable> {
+        error::read(&self.shards[0])
+    }
+
+    pub fn read(&self) -> Shards<RwLockReadGuard<'_, Memtable>> {
+        Shards(self.shards.iter().map(|s| Some(error::read(s))).collect())
+    }
+
+    // `Memtable::get` for a `MemtableKind::Concurrent` memtable, without
+    // locking the shard `k` is in, or None if it has to be locked after all:
+    // for any other kind of memtable, if the shard doesn't have `k`, if a
+    // write still holding the shard has changed it, or if any of the shard's
+    // keys have TTLs. A write that hasn't let go of the lock can't have
+    // finished with the rest of its batch, so this still sees all of a batch
+    // or none of it.
+    pub fn get_unlocked(&self, k: &str) -> Option<Option<String>> {
+        self.concurrent[self.shard_of(k)].as_ref()?.read(k)
+    }
+
+    pub fn write(&self) -> Result<Shards<Writing<'_>>> {
+        self.write_shards(&vec![true; self.shards.len()])
+    }
+
+    // Locks the shards marked in `which` for writing, leaving the rest alone.
+    pub fn write_shards(&self, which: &[bool]) -> Result<Shards<Writing<'_>>> {
+        let mut guards = vec![];
+        for ((shard, concurrent), &lock) in self.shards.iter().zip(&*self.concurrent).zip(which) {
+            guards.push(match lock {
+                true => Some(Writing::new(
+                    shard.write()?,
+                    concurrent.as_deref(),
+                    &self.memory,
+                )),
+                false => None,
+            });
+        }
+        Ok(Shards(guards))
+    }
+
+    // The memtable back out of a single shard.
+    pub fn into_unsharded(self) -> Result<Memtable> {
+        let [shard] = <[_; 1]>::try_from(self.shards.into_vec()).expect("the memtable is sharded");
+        Ok(shard.into_inner()?)
+    }
+
+    pub fn pin(&self, seq: u64) {
+        for shard in self.shards.iter() {
+            error::write(shard).pin(seq);
+        }
+    }
+
+    pub fn unpin(&self, seq: u64) {
+        for shard in self.shards.iter() {
+            let mut shard = error::write(shard);
+            let before = shard.memory();
+            shard.unpin(seq);
+            track_memory(&self.memory, before, shard.memory());
+        }
+    }
+
+    // How much memory the whole memtable takes up, as of the last write to
+    // it, without locking it.
+    pub fn memory(&self) -> usize {
+        self.memory.load(Ordering::Relaxed)
+    }
+}
+
+// Moves the running total `memory` from a shard's `before` to its `after`.
+fn track_memory(memory: &AtomicUsize, before: usize, after: usize) {
+    match after >= before {
+        true => memory.fetch_add(after - before, Ordering::Relaxed),
+        false => memory.fetch_sub(before - after, Ordering::Relaxed),
+    };
+}
+
+// A shard of a `ShardedMemtable` locked for writing. Lock-free reads of a
+// concurrent memtable leave whatever's written to it to reads that take the
+// lock until it's let go.
+pub struct Writing<'a> {
+    memtable: RwLockWriteGuard<'a, Memtable>,
+    concurrent: Option<&'a Concurrent>,
+    // The `ShardedMemtable`'s running total, and what the shard took up when
+    // it was locked.
+    memory: &'a AtomicUsize,
+    before: usize,
+}
+
+impl<'a> Writing<'a> {
+    fn new(
+        memtable: RwLockWriteGuard<'a, Memtable>,
+        concurrent: Option<&'a Concurrent>,
+        memory: &'a AtomicUsize,
+    ) -> Self {
+        if let Some(c) = concurrent {
+            let written = c.written.load(Ordering::Relaxed);
+            c.writing.store(written + 1, Ordering::Relaxed);
+        }
+        Writing {
+            before: memtable.memory(),
+            memtable,
+            concurrent,
+            memory,
+        }
+    }
+}
+
+impl Deref for Writing<'_> {
+    type Target = Memtable;
+
+    fn deref(&self) -> &Memtable {
+        &self.memtable
+    }
+}
+
+impl DerefMut for Writing<'_> {
+    fn deref_mut(&mut self) -> &mut Memtable {
+        &mut self.memtable
+    }
+}
+
+impl Drop for Writing<'_> {
+    fn drop(&mut self) {
+        if let Some(c) = self.concurrent {
+            c.written.store(c.tag(), Ordering::Release);
+        }
+        track_memory(self.memory, self.before, self.memtable.memory());
+    }
+}
+
+// Some or all of the shards of a `ShardedMemtable`, locked. Anything that
+// looks at more than one key needs them all.
+pub struct Shards<G>(Vec<Option<G>>);
+
+impl<G: Deref<Target = Memtable>> Shards<G> {
+    fn shard(&self, i: usize) -> &Memtable {
+        self.0[i].as_deref().expect("memtable shard isn't locked")
+    }
+
+    fn all(&self) -> impl Iterator<Item = &Memtable> {
+        (0..self.0.len()).map(|i| self.shard(i))
+    }
+
+    pub fn key(&self, k: &str) -> &Memtable {
+        self.shard(shard_of(k, self.0.len()))
+    }
+
+    pub fn home(&self) -> &Memtable {
+        self.shard(0)
+    }
+
+    // Every shard has the same snapshots, so any that's locked will do.
+    pub fn has_snapshots(&self) -> bool {
+        self.0
+            .iter()
+            .flatten()
+            .next()
+            .is_some_and(|m| m.has_snapshots())
+    }
+
+    pub fn bytes(&self) -> usize {
+        self.all().map(Memtable::bytes).sum()
+    }
+
+    pub fn memory(&self) -> usize {
+        self.all().map(Memtable::memory).sum()
+    }
+
+    pub fn len(&self) -> usize {
+        self.all().map(Memtable::len).sum()
+    }
+
+    pub fn approximate(&self, range: &KeyRange) -> (u64, u64) {
+        self.all()
+            .map(|m| m.approximate(range))
+            .fold((0, 0), |(keys, bytes), (k, b)| (keys + k, bytes + b))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (Cow<'_, str>, Cow<'_, str>)> {
+        self.all().flat_map(Memtable::iter)
+    }
+
+    pub fn expired(&self, now: u64) -> Vec<String> {
+        self.all().flat_map(|m| m.expired(now)).collect()
+    }
+
+    pub fn expiries(&self) -> impl Iterator<Item = (&String, u64)> {
+        self.all().flat_map(Memtable::expiries)
+    }
+
+    pub fn deleted_ranges(&self) -> &[KeyRange] {
+        self.home().deleted_ranges()
+    }
+
+    pub fn sorted(&self, prefix: &str) -> Vec<(String, Option<String>)> {
+        self.merge(|m| m.sorted(prefix))
+    }
+
+    // Each shard's first `n` from `start` on between them hold the first `n`
+    // of all of them.
+    pub fn sorted_from(&self, start: &str, n: usize) -> Vec<(String, Option<String>)> {
+        let mut entries = self.merge(|m| m.sorted_from(start, n));
+        entries.truncate(n);
+        entries
+    }
+
+    pub fn history_at(&self, prefix: &str, seq: u64) -> Vec<(String, Option<String>)> {
+        self.merge(|m| m.history_at(prefix, seq))
+    }
+
+    // What `f` gets from each shard, back in key order. No key is in more
+    // than one shard.
+    fn merge<F>(&self, f: F) -> Vec<(String, Option<String>)>
+    where
+        F: Fn(&Memtable) -> Vec<(String, Option<String>)>,
+    {
+        if self.0.len() == 1 {
+            return f(self.home());
+        }
+        let mut entries = self.all().flat_map(f).collect::<Vec<_>>();
+        entries.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+        entries
+    }
+
+    // What `Memtable::checksum` would be for the memtable the shards were
+    // split from.
+    pub fn checksum(&self) -> u32 {
+        let keyed = self.all().map(Memtable::keyed_checksum);
+        keyed.fold(self.home().shared_checksum(), u32::wrapping_add)
+    }
+}
+
+impl<G: DerefMut<Target = Memtable>> Shards<G> {
+    pub fn shard_mut(&mut self, i: usize) -> &mut Memtable {
+        self.0[i]
+            .as_deref_mut()
+            .expect("memtable shard isn't locked")
+    }
+
+    pub fn key_mut(&mut self, k: &str) -> &mut Memtable {
+        self.shard_mut(shard_of(k, self.0.len()))
+    }
+
+    pub fn home_mut(&mut self) -> &mut Memtable {
+        self.shard_mut(0)
+    }
+
+    pub fn each_mut(&mut self) -> impl Iterator<Item = &mut Memtable> {
+        self.0
+            .iter_mut()
+            .map(|g| g.as_deref_mut().expect("memtable shard isn't locked"))
+    }
+
+    // The whole memtable, as a read-only `Db` keeps it: in a single shard.
+    pub fn unsharded(&mut self) -> &mut Memtable {
+        assert_eq!(self.0.len(), 1, "the memtable is sharded");
+        self.home_mut()
+    }
+}
+
+// What `Memtable::retained` counts for `k`'s versions in the history: each
+// version is charged as an entry, and the key is charged along with the
+// first.
+fn version_bytes(k: &str, versions: &[(u64, Option<String>)]) -> usize {
+    let values = versions
+        .iter()
+        .map(|(_, v)| ENTRY_OVERHEAD + v.as_ref().map_or(0, |v| v.len()));
+    match versions.is_empty() {
+        true => 0,
+        false => k.len() + values.sum::<usize>(),
+    }
+}
+
+// Adds `element` to the end of `list`, which is a JSON array of strings. If
+// it isn't one, it's replaced by a list holding just `element`.
+pub fn append_element(list: &mut String, element: &str) {
+    if list == "[]" || (list.starts_with("[\"") && list.ends_with("\"]")) {
+        list.pop();
+        if list.len() > 1 {
+            list.push(',');
+        }
+    } else {
+        list.clear();
+        list.push('[');
+    }
+    list.push_str(&serde_json::to_string(element).unwrap());
+    list.push(']');
+}
+
+#[test]
+fn test_memory() {
+    let mut memtable = Memtable::default();
+    memtable.set("k".into(), "v".into());
+    let bytes = memtable.bytes();
+    assert_eq!(memtable.memory(), bytes);
+
+    // What a flush leaves behind counts too, and stops counting once it's
+    // gone.
+    memtable.expire("k", 1);
+    memtable.set_in("ks".into(), "a".into(), "1".into());
+    memtable.set_in("ks".into(), "a".into(), "longer".into());
+    memtable.set_in("ks".into(), "b".into(), "2".into());
+    memtable.note_request(IdempotencyKey {
+        client_id: 1,
+        request_id: 1,
+    });
+    memtable.pin(5);
+    memtable.remember("k".into(), 6, Some("old".into()));
+    memtable.clear();
+    assert_eq!(memtable.bytes(), 0);
+    assert!(memtable.memory() > 4 * ENTRY_OVERHEAD);
+
+    memtable.delete_in("ks", "a");
+    memtable.drop_keyspace("ks");
+    memtable.unpin(5);
+    memtable.delete("k".into());
+    let requests = memtable.memory();
+    assert_eq!(requests, ENTRY_OVERHEAD + memtable.bytes());
+}
+
+#[test]
+fn test_kinds() {
+    let kinds = [
+        MemtableKind::Hash,
+        MemtableKind::FxHash,
+        MemtableKind::Ordered,
+        MemtableKind::Concurrent,
+    ];
+    let mut memtables = kinds.map(|kind| Memtable::new(kind, true));
+    for memtable in &mut memtables {
+        for k in ["b/2", "a/1", "b/1", "b/3", "c", "b"] {
+            memtable.set(k.into(), "v".into());
+        }
+        memtable.delete("b/3".into());
+        memtable.delete_range(KeyRange::Prefix("a/".into()));
+        for element in ["x", "y"] {
+            memtable.append("l".into(), element, || Ok(None)).unwrap();
+        }
+    }
+    let keys = |memtable: &Memtable| {
+        let entries = memtable.sorted("b/");
+        entries
+            .into_iter()
+            .map(|(k, v)| (k, v.is_some()))
+            .collect::<Vec<_>>()
+    };
+    let expected = vec![
+        ("b/1".to_owned(), true),
+        ("b/2".to_owned(), true),
+        ("b/3".to_owned(), false),
+    ];
+    for memtable in &memtables {
+        assert_eq!(keys(memtable), expected);
+        assert_eq!(memtable.len(), 5);
+        assert_eq!(memtable.get("a/1"), Some(None));
+        assert_eq!(memtable.get("c"), Some(Some("v".into())));
+        assert_eq!(memtable.get("l"), memtables[0].get("l"));
+        assert_eq!(memtable.bytes(), memtables[0].bytes());
+        assert_eq!(memtable.checksum(), memtables[0].checksum());
+        assert_eq!(memtable, &memtables[0]);
+    }
+}
+
+#[test]
+fn test_unlocked_reads() -> Result<()> {
+    let mut memtable = Memtable::new(MemtableKind::Concurrent, true);
+    memtable.set("a".into(), "1".into());
+    memtable.delete("b".into());
+    let sharded = ShardedMemtable::new(memtable, 1);
+    assert_eq!(sharded.get_unlocked("a"), Some(Some("1".to_owned())));
+    assert_eq!(sharded.get_unlocked("b"), Some(None));
+    assert_eq!(sharded.get_unlocked("c"), None);
+
+    // What a write holding the lock has changed is left to locked reads,
+    // but the rest can still be read.
+    let mut shards = sharded.write()?;
+    shards.home_mut().set("a".into(), "2".into());
+    shards.home_mut().set("c".into(), "3".into());
+    assert_eq!(sharded.get_unlocked("a"), None);
+    assert_eq!(sharded.get_unlocked("b"), Some(None));
+    assert_eq!(sharded.get_unlocked("c"), None);
+    drop(shards);
+    assert_eq!(sharded.get_unlocked("a"), Some(Some("2".to_owned())));
+    assert_eq!(sharded.get_unlocked("c"), Some(Some("3".to_owned())));
+
+    // Nor are TTLs checked without the lock.
+    let mut shards = sharded.write()?;
+    shards.home_mut().set_expiring("d".into(), "4".into(), 0);
+    drop(shards);
+    assert_eq!(sharded.get_unlocked("a"), None);
+    sharded.write()?.home_mut().delete("d".into());
+    assert_eq!(sharded.get_unlocked("a"), Some(Some("2".to_owned())));
+
+    let hashed = ShardedMemtable::new(Memtable::new(MemtableKind::Hash, false), 1);
+    hashed.write()?.home_mut().set("a".into(), "1".into());
+    assert_eq!(hashed.get_unlocked("a"), None);
+    Ok(())
+}
+
+#[test]
+fn test_sharded_memory() -> Result<()> {
+    let mut memtable = Memtable::new(MemtableKind::Ordered, true);
+    memtable.set("a".into(), "1".into());
+    let sharded = ShardedMemtable::new(memtable, 4);
+    assert_eq!(sharded.memory(), sharded.read().memory());
+
+    let mut shards = sharded.write()?;
+    for i in 0..4 {
+        shards
+            .shard_mut(i)
+            .set(format!("k{}", i).into(), "v".into());
+    }
+    shards.home_mut().delete("a".into());
+    // Only once the shards are let go of.
+    assert_eq!(sharded.memory(), ENTRY_OVERHEAD + 2);
+    drop(shards);
+    assert_eq!(sharded.memory(), sharded.read().memory());
+    assert!(sharded.memory() > ENTRY_OVERHEAD * 4);
+    Ok(())
+}
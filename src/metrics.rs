@@ -0,0 +1,486 @@
+use crate::{NamespaceUsage, SegmentGarbage};
+use std::{
+    fmt::Write,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+// How finely `Histogram` splits values up: each range from one power of two
+// to the next is split into `2^SUB_BITS` buckets of the same width, so a
+// bucket's bounds are within 1/128 of each other, and values up to
+// `2^SUB_BITS` get a bucket each. Bucket `i` counts values no bigger than
+// `upper_bound(i)` that didn't fit in the bucket before it.
+const SUB_BITS: u32 = 7;
+const SUB_BUCKETS: usize = 1 << SUB_BITS;
+const BUCKETS: usize = SUB_BUCKETS * (u64::BITS - SUB_BITS + 1) as usize;
+
+// The bucket `value` goes in. Buckets are bounded above, so this goes by
+// `value - 1`, putting each power of two at the top of a bucket.
+fn bucket(value: u64) -> usize {
+    let v = value.saturating_sub(1);
+    if v < SUB_BUCKETS as u64 {
+        return v as usize;
+    }
+    // How far `v` has to be shifted to leave its top `SUB_BITS + 1` bits.
+    let shift = u64::BITS - 1 - SUB_BITS - v.leading_zeros();
+    SUB_BUCKETS * shift as usize + (v >> shift) as usize
+}
+
+// The biggest value that goes in bucket `i`.
+fn upper_bound(i: usize) -> u64 {
+    if i < SUB_BUCKETS {
+        return i as u64 + 1;
+    }
+    let shift = (i / SUB_BUCKETS - 1) as u32;
+    let top = (i % SUB_BUCKETS + SUB_BUCKETS) as u64;
+    // The last bucket ends at 2^64, which doesn't fit.
+    ((top + 1) << shift).wrapping_sub(1).saturating_add(1)
+}
+
+// The bounds the Prometheus histograms are given, as powers of two.
+const PROMETHEUS_BOUNDS: u32 = 40;
+
+// Counters updated by the group-commit leader each time it writes out a batch.
+#[derive(Debug, Default)]
+pub(crate) struct Counters {
+    batches: AtomicU64,
+    commands: AtomicU64,
+    bytes: AtomicU64,
+    largest_batch: AtomicU64,
+    commit_nanos: AtomicU64,
+    rewritten_bytes: AtomicU64,
+    replay_nanos: AtomicU64,
+    slowed_writes: AtomicU64,
+    slowdown_nanos: AtomicU64,
+    // What this database's background writes asked of the `RateLimiter`,
+    // which may be shared with others, and how long they waited for it.
+    background_bytes: AtomicU64,
+    background_nanos: AtomicU64,
+    batch_sizes: Histogram,
+    // In microseconds.
+    sync_latency: Histogram,
+    write_wait: Histogram,
+}
+
+impl Counters {
+    // `elapsed` covers writing and syncing the batch, and `sync` just the
+    // syncing, if it was synced at all.
+    pub(crate) fn record_batch(
+        &self,
+        commands: usize,
+        bytes: usize,
+        elapsed: Duration,
+        sync: Option<Duration>,
+    ) {
+        self.batches.fetch_add(1, Ordering::Relaxed);
+        self.commands.fetch_add(commands as u64, Ordering::Relaxed);
+        self.bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+        self.largest_batch
+            .fetch_max(commands as u64, Ordering::Relaxed);
+        self.commit_nanos
+            .fetch_add(elapsed.as_nanos() as u64, Ordering::Relaxed);
+        self.batch_sizes.record(commands as u64);
+        if let Some(sync) = sync {
+            self.sync_latency.record(sync.as_micros() as u64);
+        }
+    }
+
+    // How long a writer waited, from asking to write to its batch being done.
+    pub(crate) fn record_wait(&self, waited: Duration) {
+        self.write_wait.record(waited.as_micros() as u64);
+    }
+
+    // Bytes written out again by a compaction or a flush, tables included.
+    pub(crate) fn record_rewrite(&self, bytes: u64) {
+        self.rewritten_bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    // A write held back for `delay` by `Options::memtable_slowdown`.
+    pub(crate) fn record_slowdown(&self, delay: Duration) {
+        self.slowed_writes.fetch_add(1, Ordering::Relaxed);
+        self.slowdown_nanos
+            .fetch_add(delay.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_background(&self, bytes: u64, waited: Duration) {
+        self.background_bytes.fetch_add(bytes, Ordering::Relaxed);
+        self.background_nanos
+            .fetch_add(waited.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn background(&self) -> (u64, Duration) {
+        let nanos = self.background_nanos.load(Ordering::Relaxed);
+        (
+            self.background_bytes.load(Ordering::Relaxed),
+            Duration::from_nanos(nanos),
+        )
+    }
+
+    pub(crate) fn record_replay(&self, elapsed: Duration) {
+        self.replay_nanos
+            .store(elapsed.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn snapshot(&self) -> Metrics {
+        Metrics {
+            batches: self.batches.load(Ordering::Relaxed),
+            commands: self.commands.load(Ordering::Relaxed),
+            bytes: self.bytes.load(Ordering::Relaxed),
+            largest_batch: self.largest_batch.load(Ordering::Relaxed),
+            commit_time: Duration::from_nanos(self.commit_nanos.load(Ordering::Relaxed)),
+            rewritten_bytes: self.rewritten_bytes.load(Ordering::Relaxed),
+            replay_time: Duration::from_nanos(self.replay_nanos.load(Ordering::Relaxed)),
+            slowed_writes: self.slowed_writes.load(Ordering::Relaxed),
+            slowdown_time: Duration::from_nanos(self.slowdown_nanos.load(Ordering::Relaxed)),
+            batch_sizes: self.batch_sizes.snapshot(),
+            sync_latency: self.sync_latency.snapshot(),
+            write_wait: self.write_wait.snapshot(),
+            ..Metrics::default()
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Metrics {
+    // Number of batches written out by a leader.
+    pub batches: u64,
+    // Number of commands across all of those batches.
+    pub commands: u64,
+    // Bytes written to the log, including record headers.
+    pub bytes: u64,
+    pub largest_batch: u64,
+    // Total time leaders spent writing and syncing batches.
+    pub commit_time: Duration,
+    // Bytes written by compactions and flushes, to the log and to tables.
+    pub rewritten_bytes: u64,
+    // How long replaying the log took when the database was opened.
+    pub replay_time: Duration,
+    // Writes held back because the memtable was past
+    // `Options::memtable_slowdown`, and for how long altogether.
+    pub slowed_writes: u64,
+    pub slowdown_time: Duration,
+    // The number of commands in each batch.
+    pub batch_sizes: Buckets,
+    // How long each batch took to sync, in microseconds.
+    pub sync_latency: Buckets,
+    // How long each write waited for its batch to be done, in microseconds,
+    // counting any batches ahead of it.
+    pub write_wait: Buckets,
+    // Reads of table blocks that were and weren't in the block cache.
+    pub block_cache_hits: u64,
+    pub block_cache_misses: u64,
+}
+
+impl Metrics {
+    pub fn mean_batch_size(&self) -> f64 {
+        if self.batches == 0 {
+            return 0.0;
+        }
+        self.commands as f64 / self.batches as f64
+    }
+
+    // Commands committed per second of time spent committing.
+    pub fn commit_throughput(&self) -> f64 {
+        let secs = self.commit_time.as_secs_f64();
+        if secs == 0.0 {
+            return 0.0;
+        }
+        self.commands as f64 / secs
+    }
+
+    // Bytes written to disk for every byte committed to the log, counting
+    // what compactions and flushes wrote on top.
+    pub fn write_amplification(&self) -> f64 {
+        if self.bytes == 0 {
+            return 0.0;
+        }
+        (self.bytes + self.rewritten_bytes) as f64 / self.bytes as f64
+    }
+
+    pub fn block_cache_hit_rate(&self) -> f64 {
+        let reads = self.block_cache_hits + self.block_cache_misses;
+        if reads == 0 {
+            return 0.0;
+        }
+        self.block_cache_hits as f64 / reads as f64
+    }
+}
+
+impl Metrics {
+    // The metrics in Prometheus's text format, for a `/metrics` endpoint.
+    // Every name starts with `redo_log_`.
+    pub fn to_prometheus(&self) -> String {
+        let mut out = String::new();
+        let values = [
+            (
+                "batches_total",
+                "counter",
+                "Batches written to the log.",
+                self.batches as f64,
+            ),
+            (
+                "writes_total",
+                "counter",
+                "Commands written to the log.",
+                self.commands as f64,
+            ),
+            (
+                "log_bytes_total",
+                "counter",
+                "Bytes written to the log.",
+                self.bytes as f64,
+            ),
+            (
+                "rewritten_bytes_total",
+                "counter",
+                "Bytes written by compactions and flushes.",
+                self.rewritten_bytes as f64,
+            ),
+            (
+                "commit_seconds_total",
+                "counter",
+                "Time spent writing and syncing batches.",
+                self.commit_time.as_secs_f64(),
+            ),
+            (
+                "slowed_writes_total",
+                "counter",
+                "Writes held back by a full memtable.",
+                self.slowed_writes as f64,
+            ),
+            (
+                "slowdown_seconds_total",
+                "counter",
+                "Time writes were held back by a full memtable.",
+                self.slowdown_time.as_secs_f64(),
+            ),
+            (
+                "largest_batch",
+                "gauge",
+                "The most commands written in one batch.",
+                self.largest_batch as f64,
+            ),
+            (
+                "replay_seconds",
+                "gauge",
+                "Time spent replaying the log on open.",
+                self.replay_time.as_secs_f64(),
+            ),
+            (
+                "block_cache_hits_total",
+                "counter",
+                "Table block reads served by the block cache.",
+                self.block_cache_hits as f64,
+            ),
+            (
+                "block_cache_misses_total",
+                "counter",
+                "Table block reads that missed the block cache.",
+                self.block_cache_misses as f64,
+            ),
+        ];
+        for (name, kind, help, value) in values {
+            metric(&mut out, name, help, kind);
+            writeln!(out, "redo_log_{} {}", name, value).unwrap();
+        }
+        self.batch_sizes
+            .to_prometheus(&mut out, "batch_size", "Commands per batch.", 1.0);
+        self.sync_latency.to_prometheus(
+            &mut out,
+            "sync_seconds",
+            "Time spent syncing each batch.",
+            1e-6,
+        );
+        self.write_wait.to_prometheus(
+            &mut out,
+            "write_wait_seconds",
+            "Time each write waited for its batch.",
+            1e-6,
+        );
+        out
+    }
+}
+
+fn metric(out: &mut String, name: &str, help: &str, kind: &str) {
+    writeln!(out, "# HELP redo_log_{} {}", name, help).unwrap();
+    writeln!(out, "# TYPE redo_log_{} {}", name, kind).unwrap();
+}
+
+// The shape of a database on disk, from `Db::stats`, for deciding when it's
+// worth compacting.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Stats {
+    // Every segment, recycled ones included, and every table.
+    pub disk_bytes: u64,
+    // Records in the log, which compaction would replace with one per live
+    // key.
+    pub log_records: u64,
+    pub tables: usize,
+    // Roughly how much memory the memtable takes up, including what a flush
+    // leaves behind in it.
+    pub memtable_bytes: usize,
+    // Keys in the memtable plus entries in the tables. Keys that have been
+    // overwritten or deleted since they were flushed are counted more than
+    // once, so this is an overestimate until `compact` merges the tables.
+    pub estimated_live_keys: u64,
+    // See `Metrics::write_amplification`.
+    pub write_amplification: f64,
+    pub replay_time: Duration,
+    // How much of the log is dead, and of each segment of it, going back to
+    // the last compaction. Only counted with `Options::compact_dead_ratio`.
+    pub dead_log_bytes: u64,
+    pub segment_garbage: Vec<SegmentGarbage>,
+    // How much each of `Options::namespaces` holds, in the same order.
+    pub namespaces: Vec<NamespaceUsage>,
+    // `Options::background_bytes_per_sec`, and how many bytes flushes,
+    // compactions and value log rewrites have written under it, and how long
+    // they've been held back by it altogether. With a `DbManager`, the rate
+    // is shared by every database it opened, but the bytes and the time are
+    // this database's own.
+    pub background_bytes_per_sec: Option<u64>,
+    pub background_bytes: u64,
+    pub background_throttled: Duration,
+}
+
+// Counts of values in buckets a power of two apart, each split up further to
+// within 1% (see `SUB_BITS`), which anything can add to.
+#[derive(Debug)]
+pub struct Histogram {
+    // Boxed, since there are thousands of them.
+    counts: Box<[AtomicU64]>,
+    sum: AtomicU64,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Histogram {
+            counts: (0..BUCKETS).map(|_| AtomicU64::new(0)).collect(),
+            sum: AtomicU64::new(0),
+        }
+    }
+}
+
+impl Histogram {
+    pub fn record(&self, value: u64) {
+        self.counts[bucket(value)].fetch_add(1, Ordering::Relaxed);
+        self.sum.fetch_add(value, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> Buckets {
+        Buckets {
+            counts: self
+                .counts
+                .iter()
+                .map(|c| c.load(Ordering::Relaxed))
+                .collect(),
+            sum: self.sum.load(Ordering::Relaxed),
+        }
+    }
+}
+
+// What a `Histogram` had counted at some point.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Buckets {
+    // How many values landed in each bucket; see `BUCKETS`. Empty if nothing
+    // was ever counted.
+    pub counts: Vec<u64>,
+    pub sum: u64,
+}
+
+impl Buckets {
+    pub fn count(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    pub fn mean(&self) -> f64 {
+        match self.count() {
+            0 => 0.0,
+            n => self.sum as f64 / n as f64,
+        }
+    }
+
+    // The upper bound of the bucket that the `q`th quantile falls in, so an
+    // overestimate by less than 1%.
+    pub fn quantile(&self, q: f64) -> u64 {
+        let rank = (q * self.count() as f64).ceil().max(1.0) as u64;
+        let mut seen = 0;
+        for (i, &n) in self.counts.iter().enumerate() {
+            seen += n;
+            if seen >= rank {
+                return upper_bound(i);
+            }
+        }
+        0
+    }
+
+    // Writes the buckets out as a Prometheus histogram, with bounds and the
+    // sum multiplied by `scale`.
+    fn to_prometheus(&self, out: &mut String, name: &str, help: &str, scale: f64) {
+        metric(out, name, help, "histogram");
+        // Every power of two is the top of a bucket.
+        let (mut seen, mut next) = (0, 0);
+        for i in 0..PROMETHEUS_BOUNDS {
+            let last = bucket(1 << i);
+            seen += self.counts.get(next..=last).map_or(0, |c| c.iter().sum());
+            next = last + 1;
+            let bound = (1u64 << i) as f64 * scale;
+            writeln!(out, "redo_log_{}_bucket{{le=\"{}\"}} {}", name, bound, seen).unwrap();
+        }
+        let count = self.count();
+        writeln!(out, "redo_log_{}_bucket{{le=\"+Inf\"}} {}", name, count).unwrap();
+        writeln!(out, "redo_log_{}_sum {}", name, self.sum as f64 * scale).unwrap();
+        writeln!(out, "redo_log_{}_count {}", name, count).unwrap();
+    }
+}
+
+#[test]
+fn test_histogram() {
+    let histogram = Histogram::default();
+    assert_eq!(histogram.snapshot().quantile(0.5), 0);
+    for value in [0, 1, 2, 3, 4, 5, 100, u64::MAX / 2] {
+        histogram.record(value);
+    }
+    let buckets = histogram.snapshot();
+    assert_eq!(buckets.count(), 8);
+    assert_eq!(&buckets.counts[..5], &[2, 1, 1, 1, 1]);
+    assert_eq!(buckets.counts[99], 1);
+    assert_eq!(buckets.quantile(0.5), 3);
+    assert_eq!(buckets.quantile(0.75), 5);
+    assert_eq!(buckets.quantile(0.0), 1);
+    assert_eq!(buckets.quantile(1.0), u64::MAX / 2 + 1);
+
+    // Every bucket starts where the one before it ends, and its bounds are
+    // within 1% of each other.
+    for i in 1..BUCKETS {
+        let (lo, hi) = (upper_bound(i - 1) + 1, upper_bound(i));
+        assert_eq!((bucket(lo), bucket(hi)), (i, i));
+        assert!(
+            (hi - lo) as f64 <= hi as f64 / 100.0,
+            "{}: {} to {}",
+            i,
+            lo,
+            hi
+        );
+    }
+    assert_eq!(bucket(u64::MAX), BUCKETS - 1);
+    assert_eq!(upper_bound(BUCKETS - 1), u64::MAX);
+    let histogram = Histogram::default();
+    for value in 1..=10_000 {
+        histogram.record(value * 1000);
+    }
+    let p99 = histogram.snapshot().quantile(0.99);
+    assert!((9_900_000..9_990_000).contains(&p99), "{}", p99);
+
+    let metrics = Metrics {
+        batches: 3,
+        batch_sizes: buckets,
+        ..Metrics::default()
+    };
+    let text = metrics.to_prometheus();
+    assert!(text.contains("# TYPE redo_log_batches_total counter\nredo_log_batches_total 3\n"));
+    assert!(text.contains("redo_log_batch_size_bucket{le=\"4\"} 5\n"));
+    assert!(text.contains("redo_log_batch_size_bucket{le=\"128\"} 7\n"));
+    assert!(text.contains("redo_log_batch_size_bucket{le=\"+Inf\"} 8\n"));
+    assert!(text.contains("redo_log_batch_size_count 8\n"));
+    assert!(text.contains("redo_log_sync_seconds_count 0\n"));
+}
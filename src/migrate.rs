@@ -0,0 +1,182 @@
+use crate::log::Log;
+use crate::{
+    codec, durable_fs, Command, Durability, Error, LogReader, Options, RecordCodec, Result,
+};
+use std::{
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+#[cfg(test)]
+use tempfile::tempdir;
+
+// How many commands go to the log in each batch.
+const BATCH: usize = 1024;
+
+// Rewrites the log in the file `path`, written as one JSON command per line
+// the way the first versions of this crate wrote it, into a log directory of
+// the same name with its records in `codec`. The original is kept alongside
+// as `<path>.jsonl`. Returns the number of commands carried over.
+//
+// The new log is built in `<path>.migrate` and only swapped in once it's
+// complete and synced, so a crash partway through leaves the original where
+// it was. A crash between moving the original aside and moving the new log
+// into place leaves both finished, and running this again completes the swap.
+pub fn migrate(path: &Path, codec: Arc<dyn RecordCodec>) -> Result<u64> {
+    let original = sibling(path, "jsonl")?;
+    let scratch = sibling(path, "migrate")?;
+    if !path.exists() && original.is_file() && scratch.join("MIGRATE").is_file() {
+        durable_fs::rename(&scratch, path)?;
+        return Ok(LogReader::open(path)?.count() as u64);
+    }
+    if !path.is_file() {
+        return Err(Error::InvalidConfig(format!(
+            "{} is not a log of JSON lines",
+            path.display()
+        )));
+    }
+    if original.exists() {
+        return Err(Error::InvalidConfig(format!(
+            "{} already exists",
+            original.display()
+        )));
+    }
+
+    // Whatever is here is left over from a migration that didn't finish.
+    if scratch.exists() {
+        fs::remove_dir_all(&scratch)?;
+    }
+    let commands = read_lines(path)?;
+    let options = Options {
+        codec: codec.clone(),
+        ..Options::default()
+    };
+    let mut log = Log::open(&scratch, options, |_| Ok(()))?;
+    for batch in commands.chunks(BATCH) {
+        let payloads = batch
+            .iter()
+            .map(|command| codec::encode(&*codec, command))
+            .collect::<Result<Vec<_>>>()?;
+        log.append_batch(&payloads)?;
+    }
+    log.sync()?;
+    drop(log);
+
+    let mut file = durable_fs::create_new(&scratch.join("MIGRATE"))?;
+    write!(
+        file,
+        "migrated {} commands from {} into codec {}",
+        commands.len(),
+        original.display(),
+        codec.name()
+    )?;
+    durable_fs::sync_file(&file, Durability::Media)?;
+
+    durable_fs::rename(path, &original)?;
+    durable_fs::rename(&scratch, path)?;
+    Ok(commands.len() as u64)
+}
+
+// Parses every line of the log in `path` as a command. The old log wrote each
+// command and then its newline before syncing, so a last line that has no
+// newline and doesn't parse was torn by a crash, and never acknowledged.
+fn read_lines(path: &Path) -> Result<Vec<Command>> {
+    let contents = fs::read(path)?;
+    let mut commands = vec![];
+    let mut lines = contents.split(|&b| b == b'\n').enumerate().peekable();
+    while let Some((i, line)) = lines.next() {
+        let last = lines.peek().is_none();
+        if line.iter().all(u8::is_ascii_whitespace) {
+            continue;
+        }
+        match serde_json::from_slice(line) {
+            Ok(command) => commands.push(command),
+            Err(_) if last => {}
+            Err(e) => {
+                return Err(Error::InvalidConfig(format!(
+                    "line {} of {} isn't a command: {}",
+                    i + 1,
+                    path.display(),
+                    e
+                )))
+            }
+        }
+    }
+    Ok(commands)
+}
+
+// `<path>.<extension>`, next to `path`.
+fn sibling(path: &Path, extension: &str) -> Result<PathBuf> {
+    let mut name = path
+        .file_name()
+        .ok_or_else(|| Error::InvalidConfig(format!("{} has no name", path.display())))?
+        .to_os_string();
+    name.push(".");
+    name.push(extension);
+    Ok(path.with_file_name(name))
+}
+
+#[test]
+fn test_migrate() -> Result<()> {
+    use crate::{segment, Db};
+
+    let dir = tempdir()?;
+    let file = dir.path().join("logfile");
+    fs::write(
+        &file,
+        concat!(
+            "{\"Set\":[\"a\",\"1\"]}\n",
+            "{\"Set\":[\"b\",\"2\"]}\n",
+            "\n",
+            "{\"Delete\":\"a\"}\n",
+            "{\"Set\":[\"c\",\"3\"]}\n",
+            "{\"Set\":[\"d\",",
+        ),
+    )?;
+    let err = Db::new(&file).unwrap_err();
+    assert!(matches!(err, Error::InvalidConfig(_)), "{}", err);
+
+    // The torn last line is dropped, and the rest come through in order.
+    assert_eq!(migrate(&file, Arc::new(codec::Binary))?, 4);
+    assert!(dir.path().join("logfile.jsonl").is_file());
+    assert!(!dir.path().join("logfile.migrate").exists());
+    let (numbers, _) = segment::list(&file)?;
+    for number in numbers {
+        assert_eq!(segment::SegmentReader::open(&file, number)?.codec(), 2);
+    }
+    let mut db = Db::new(&file)?;
+    assert_eq!(db.get("a"), None);
+    assert_eq!(db.get("b").as_deref(), Some("2"));
+    assert_eq!(db.get("c").as_deref(), Some("3"));
+    assert_eq!(db.get("d"), None);
+    db.set("d", "4")?;
+    drop(db);
+
+    // It's a log directory now, so there's nothing left to migrate.
+    assert!(matches!(
+        migrate(&file, Arc::new(codec::Binary)),
+        Err(Error::InvalidConfig(_))
+    ));
+
+    // A crash between the two renames is finished off by running it again.
+    let other = dir.path().join("other");
+    fs::write(&other, "{\"Set\":[\"x\",\"1\"]}\n")?;
+    migrate(&other, Arc::new(codec::Json))?;
+    durable_fs::rename(&other, &dir.path().join("other.migrate"))?;
+    assert_eq!(migrate(&other, Arc::new(codec::Json))?, 1);
+    assert_eq!(Db::new(&other)?.get("x").as_deref(), Some("1"));
+
+    // A line in the middle that doesn't parse stops it before anything moves.
+    let bad = dir.path().join("bad");
+    fs::write(
+        &bad,
+        "{\"Set\":[\"x\",\"1\"]}\nnot json\n{\"Delete\":\"x\"}\n",
+    )?;
+    assert!(matches!(
+        migrate(&bad, Arc::new(codec::Binary)),
+        Err(Error::InvalidConfig(_))
+    ));
+    assert!(bad.is_file());
+    Ok(())
+}
@@ -0,0 +1,203 @@
+use crate::durable_fs;
+use crate::segment::{self, End, SegmentReader};
+#[cfg(test)]
+use crate::{Db, Error, Options};
+use crate::{Durability, Result};
+use std::{
+    fs::{self, OpenOptions},
+    io,
+    path::Path,
+};
+#[cfg(test)]
+use tempfile::tempdir;
+
+// A mirrored log (see `Options::log_mirror`) keeps a copy of each of its
+// segments in the mirror's directory, under the same number. The copies are
+// written in step, so they only differ where one fell behind the other: a
+// crash between writing one and the other, the mirror being dropped after a
+// failure, or a disk going bad under one of them. Before the log is replayed,
+// `heal` brings them back into line segment by segment, copying whichever copy
+// reads further over the other, so that replay only has to read the log's own
+// directory. That means reading both copies through every time the log is
+// opened for writing, even after a clean shutdown, since damage to either
+// one is what the mirror is there for.
+
+// Brings the copies of the log in `primary` and `mirror` back into line.
+pub fn heal(primary: &Path, mirror: &Path) -> Result<()> {
+    let (ours, _) = segment::list(primary)?;
+    let (theirs, _) = segment::list(mirror)?;
+    // The copy with the newest segment saw every compaction, so anything
+    // older than its oldest segment was retired, and only the other copy
+    // missed retiring it.
+    let newest = match ours.last() >= theirs.last() {
+        true => &ours,
+        false => &theirs,
+    };
+    let oldest = newest.first().copied().unwrap_or(0);
+    let mut numbers = ours.iter().chain(&theirs).copied().collect::<Vec<_>>();
+    numbers.sort_unstable();
+    numbers.dedup();
+    let mut changed = false;
+    for number in numbers {
+        if number < oldest {
+            changed |= remove(primary, number)?;
+            remove(mirror, number)?;
+            continue;
+        }
+        let (a, b) = (extent(primary, number), extent(mirror, number));
+        if a > b {
+            copy(primary, mirror, number)?;
+        } else if b > a {
+            copy(mirror, primary, number)?;
+            changed = true;
+        }
+    }
+    // What the primary's `CLEAN` file says about it may no longer be true.
+    if changed {
+        match fs::remove_file(primary.join("CLEAN")) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+// How far segment `number` in `dir` reads, and whether it ends cleanly there,
+// or None if there's no reading it at all. More of it is better, and so is a
+// clean end at the same place.
+fn extent(dir: &Path, number: u64) -> Option<(u64, bool)> {
+    let mut reader = SegmentReader::open(dir, number).ok()?;
+    reader.first_seq()?;
+    let mut buf = vec![];
+    while reader.next_record(&mut buf).ok()?.is_some() {}
+    Some((reader.offset(), reader.end().is_some_and(End::is_clean)))
+}
+
+// Replaces segment `number` in `to` with the one in `from`, so that a crash
+// leaves one or the other.
+fn copy(from: &Path, to: &Path, number: u64) -> Result<()> {
+    let tmp = to.join(format!("{:020}.tmp", number));
+    fs::copy(segment::segment_path(from, number), &tmp)?;
+    let file = OpenOptions::new().write(true).open(&tmp)?;
+    durable_fs::sync_file(&file, Durability::Media)?;
+    durable_fs::rename(&tmp, &segment::segment_path(to, number))
+}
+
+fn remove(dir: &Path, number: u64) -> Result<bool> {
+    let path = segment::segment_path(dir, number);
+    if !path.exists() {
+        return Ok(false);
+    }
+    durable_fs::remove_file(&path)?;
+    Ok(true)
+}
+
+#[cfg(test)]
+fn mirrored(mirror: &Path) -> Options {
+    Options {
+        segment_size: 4096,
+        log_mirror: Some(mirror.to_path_buf()),
+        ..Options::default()
+    }
+}
+
+#[cfg(test)]
+fn same_segments(a: &Path, b: &Path) -> Result<bool> {
+    let (numbers, _) = segment::list(a)?;
+    if numbers != segment::list(b)?.0 {
+        return Ok(false);
+    }
+    for number in numbers {
+        let a = fs::read(segment::segment_path(a, number))?;
+        if a != fs::read(segment::segment_path(b, number))? {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+#[test]
+fn test_mirror() -> Result<()> {
+    let dir = tempdir()?;
+    let (file, mirror) = (dir.path().join("db"), dir.path().join("mirror"));
+    let mut db = Db::with_options(&file, mirrored(&mirror))?;
+    for i in 0..500 {
+        db.set(&format!("k{}", i), "v")?;
+    }
+    drop(db);
+    assert!(same_segments(&file, &mirror)?);
+
+    // Losing every segment in one copy loses nothing.
+    let (numbers, _) = segment::list(&file)?;
+    assert!(numbers.len() > 2);
+    for &number in &numbers {
+        fs::remove_file(segment::segment_path(&file, number))?;
+    }
+    let mut db = Db::with_options(&file, mirrored(&mirror))?;
+    assert_eq!(db.len(), 500);
+    db.set("after", "v")?;
+    drop(db);
+    assert!(same_segments(&file, &mirror)?);
+
+    // Nor does damage in the middle of the other.
+    let path = segment::segment_path(&mirror, numbers[1]);
+    let mut bytes = fs::read(&path)?;
+    bytes[segment::SEGMENT_HEADER_LEN + segment::HEADER_LEN] ^= 1;
+    fs::write(&path, bytes)?;
+    let db = Db::with_options(&file, mirrored(&mirror))?;
+    assert_eq!(db.len(), 501);
+    drop(db);
+    assert!(same_segments(&file, &mirror)?);
+    Ok(())
+}
+
+#[test]
+fn test_mirror_compaction() -> Result<()> {
+    let dir = tempdir()?;
+    let (file, mirror) = (dir.path().join("db"), dir.path().join("mirror"));
+    let mut db = Db::with_options(&file, mirrored(&mirror))?;
+    for i in 0..100 {
+        db.set(&format!("k{}", i), "v")?;
+    }
+    let before = segment::list(&mirror)?.0;
+    db.compact()?;
+    db.set("after", "v")?;
+    assert!(same_segments(&file, &mirror)?);
+    assert!(segment::list(&mirror)?.0[0] > before[before.len() - 1]);
+    drop(db);
+
+    // A mirror that missed the compaction has what it retired dropped,
+    // rather than brought back.
+    for number in before {
+        let path = segment::segment_path(&file, number);
+        fs::write(segment::segment_path(&mirror, number), b"")?;
+        assert!(!path.exists());
+    }
+    let db = Db::with_options(&file, mirrored(&mirror))?;
+    assert_eq!(db.len(), 101);
+    drop(db);
+    assert!(same_segments(&file, &mirror)?);
+    Ok(())
+}
+
+#[test]
+fn test_mirror_rejects_shards() -> Result<()> {
+    let dir = tempdir()?;
+    let (file, mirror) = (dir.path().join("db"), dir.path().join("mirror"));
+    let options = Options {
+        log_shards: 2,
+        ..mirrored(&mirror)
+    };
+    assert!(matches!(
+        Db::with_options(&file, options),
+        Err(Error::InvalidConfig(_))
+    ));
+    // Nor can the mirror be locked twice.
+    let _db = Db::with_options(&file, mirrored(&mirror))?;
+    let other = dir.path().join("other");
+    assert!(matches!(
+        Db::with_options(other, mirrored(&mirror)),
+        Err(Error::Busy(_))
+    ));
+    Ok(())
+}
@@ -0,0 +1,213 @@
+// A `Db` for Python, built into an extension module by the `redo-log-python`
+// crate. It acts like a dict of strings, and as a context manager that closes
+// the database cleanly on the way out:
+//
+//     with redo_log.Db("path/to/db") as db:
+//         db["greeting"] = "hello"
+//         print(db.get("greeting"), len(db), list(db.items()))
+//
+// Errors from the database come up as `redo_log.Error`. Writes and syncs let
+// other Python threads run while they wait on the disk.
+use crate::Db;
+use pyo3::{
+    create_exception,
+    exceptions::{PyException, PyKeyError, PyValueError},
+    prelude::*,
+    types::{PyIterator, PyList, PyType},
+};
+use std::path::PathBuf;
+#[cfg(test)]
+use tempfile::tempdir;
+
+create_exception!(redo_log, Error, PyException, "An error from the database.");
+
+impl From<crate::Error> for PyErr {
+    fn from(e: crate::Error) -> Self {
+        Error::new_err(e.to_string())
+    }
+}
+
+#[pyclass(name = "Db", module = "redo_log")]
+pub struct PyDb {
+    // None once it's closed.
+    db: Option<Db>,
+}
+
+impl PyDb {
+    fn db(&self) -> PyResult<&Db> {
+        self.db
+            .as_ref()
+            .ok_or_else(|| PyValueError::new_err("the database is closed"))
+    }
+
+    fn db_mut(&mut self) -> PyResult<&mut Db> {
+        self.db
+            .as_mut()
+            .ok_or_else(|| PyValueError::new_err("the database is closed"))
+    }
+}
+
+#[pymethods]
+impl PyDb {
+    // Opens, or creates, the database in the directory `path`.
+    #[new]
+    fn new(py: Python<'_>, path: PathBuf) -> PyResult<Self> {
+        let db = py.detach(|| Db::new(path))?;
+        Ok(PyDb { db: Some(db) })
+    }
+
+    fn __getitem__(&self, key: &str) -> PyResult<String> {
+        self.db()?
+            .try_get(key)?
+            .ok_or_else(|| PyKeyError::new_err(key.to_owned()))
+    }
+
+    fn __setitem__(&mut self, py: Python<'_>, key: &str, value: &str) -> PyResult<()> {
+        let db = self.db_mut()?;
+        Ok(py.detach(|| db.set(key, value))?)
+    }
+
+    // Like a dict, deleting a key that isn't there raises `KeyError`.
+    fn __delitem__(&mut self, py: Python<'_>, key: &str) -> PyResult<()> {
+        let db = self.db_mut()?;
+        if db.try_get(key)?.is_none() {
+            return Err(PyKeyError::new_err(key.to_owned()));
+        }
+        Ok(py.detach(|| db.delete(key))?)
+    }
+
+    fn __contains__(&self, key: &str) -> PyResult<bool> {
+        Ok(self.db()?.try_get(key)?.is_some())
+    }
+
+    fn __len__(&self) -> PyResult<usize> {
+        Ok(self.db()?.try_len()?)
+    }
+
+    // Iterates over the keys, in order, as they were when it started.
+    fn __iter__<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyIterator>> {
+        PyList::new(py, self.keys()?)?.as_any().try_iter()
+    }
+
+    #[pyo3(signature = (key, default = None))]
+    fn get(&self, key: &str, default: Option<String>) -> PyResult<Option<String>> {
+        Ok(self.db()?.try_get(key)?.or(default))
+    }
+
+    fn keys(&self) -> PyResult<Vec<String>> {
+        Ok(self.items("")?.into_iter().map(|(k, _)| k).collect())
+    }
+
+    fn values(&self) -> PyResult<Vec<String>> {
+        Ok(self.items("")?.into_iter().map(|(_, v)| v).collect())
+    }
+
+    // Every key and its value, in key order, or only those whose keys start
+    // with `prefix`.
+    #[pyo3(signature = (prefix = ""))]
+    fn items(&self, prefix: &str) -> PyResult<Vec<(String, String)>> {
+        Ok(self.db()?.try_scan(prefix)?)
+    }
+
+    // Waits until every write so far is durable.
+    fn sync(&self, py: Python<'_>) -> PyResult<()> {
+        let db = self.db()?;
+        py.detach(|| db.sync())?;
+        Ok(())
+    }
+
+    // Closes the database cleanly. Closing it again does nothing.
+    fn close(&mut self, py: Python<'_>) -> PyResult<()> {
+        match self.db.take() {
+            Some(db) => Ok(py.detach(|| db.close())?),
+            None => Ok(()),
+        }
+    }
+
+    #[getter]
+    fn closed(&self) -> bool {
+        self.db.is_none()
+    }
+
+    fn __enter__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {
+        slf
+    }
+
+    // Closes the database, and lets any exception carry on.
+    fn __exit__(
+        &mut self,
+        py: Python<'_>,
+        _ty: Option<&Bound<'_, PyType>>,
+        _value: Option<&Bound<'_, PyAny>>,
+        _traceback: Option<&Bound<'_, PyAny>>,
+    ) -> PyResult<bool> {
+        self.close(py)?;
+        Ok(false)
+    }
+}
+
+// Adds `Db` and `Error` to `module`, for the extension module's init
+// function.
+pub fn register(module: &Bound<'_, PyModule>) -> PyResult<()> {
+    module.add_class::<PyDb>()?;
+    module.add("Error", module.py().get_type::<Error>())?;
+    Ok(())
+}
+
+#[test]
+fn test_python() -> PyResult<()> {
+    use pyo3::types::{PyDict, PyModule};
+    use std::ffi::CString;
+
+    let dir = tempdir()?;
+    Python::initialize();
+    Python::attach(|py| {
+        let module = PyModule::new(py, "redo_log")?;
+        register(&module)?;
+        let globals = PyDict::new(py);
+        globals.set_item("redo_log", module)?;
+        globals.set_item("path", dir.path().join("db"))?;
+        let script = CString::new(
+            r#"
+with redo_log.Db(path) as db:
+    db["b"] = "2"
+    db["a"] = "1"
+    db["c"] = "3"
+    del db["c"]
+    assert "a" in db and "c" not in db
+    assert len(db) == 2
+    assert list(db) == ["a", "b"]
+    assert db.items() == [("a", "1"), ("b", "2")]
+    assert db.get("c") is None and db.get("c", "x") == "x"
+    try:
+        db["c"]
+        assert False
+    except KeyError:
+        pass
+    try:
+        del db["c"]
+        assert False
+    except KeyError:
+        pass
+    db.sync()
+assert db.closed
+try:
+    db["a"]
+    assert False
+except ValueError:
+    pass
+
+db = redo_log.Db(path)
+assert db["a"] == "1"
+try:
+    redo_log.Db(path)
+    assert False
+except redo_log.Error:
+    pass
+db.close()
+db.close()
+"#,
+        )?;
+        py.run(&script, Some(&globals), None)
+    })
+}
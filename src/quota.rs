@@ -0,0 +1,362 @@
+// Keeps count of how much each namespace in `Options::namespaces` holds, and
+// turns away writes that would take one past its quota.
+//
+// Counts are kept up to date as each batch is applied, from the size each key
+// it writes had before and has after. A range deletion doesn't say which
+// keys it deleted, so it leaves the namespaces it overlaps to be counted
+// again from scratch, which happens before the next write is checked, or
+// `Db::stats` reports them, with the memtable locked.
+//
+// A write is checked before it joins a batch, and what it might add is held
+// against its namespace from then until it's been applied, so that writes
+// that are checked while others are still on their way to the memtable can't
+// take a namespace past its quota between them.
+use crate::{error, Command, Error, Result};
+#[cfg(test)]
+use crate::{Db, Options};
+use std::sync::{Arc, Mutex};
+#[cfg(test)]
+use tempfile::tempdir;
+
+// A tenant's share of the default keyspace: every key that starts with
+// `prefix`, or the first such prefix, if namespaces overlap. Sizes are of
+// keys and their values as they're stored, which for values in the value log
+// is the pointer to them. Expired keys count until they're deleted (see
+// `Db::expire`).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Namespace {
+    pub name: String,
+    pub prefix: String,
+    pub max_bytes: Option<u64>,
+    pub max_keys: Option<u64>,
+}
+
+// How much a namespace holds, in `Stats::namespaces`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct NamespaceUsage {
+    pub name: String,
+    pub bytes: u64,
+    pub keys: u64,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct Quotas {
+    namespaces: Vec<Namespace>,
+    usage: Mutex<Vec<Usage>>,
+}
+
+#[derive(Debug, Clone, Default)]
+struct Usage {
+    bytes: u64,
+    keys: u64,
+    // Whether it has to be counted again from scratch.
+    stale: bool,
+    // What writes that have been checked but not yet applied might add, in
+    // bytes and keys (see `Quotas::reserve`).
+    reserved: (u64, u64),
+}
+
+// What a write that's been let through might add to each namespace, which
+// counts against its quota until this is dropped, once the write is done.
+#[derive(Debug)]
+pub(crate) struct Reservation {
+    quotas: Arc<Quotas>,
+    growth: Vec<(u64, u64)>,
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        if self.growth.is_empty() {
+            return;
+        }
+        let mut usage = error::lock(&self.quotas.usage);
+        for (usage, (bytes, keys)) in usage.iter_mut().zip(&self.growth) {
+            usage.reserved.0 -= bytes;
+            usage.reserved.1 -= keys;
+        }
+    }
+}
+
+impl Quotas {
+    pub fn new(namespaces: Vec<Namespace>) -> Self {
+        let usage = vec![
+            Usage {
+                stale: true,
+                ..Usage::default()
+            };
+            namespaces.len()
+        ];
+        Quotas {
+            namespaces,
+            usage: Mutex::new(usage),
+        }
+    }
+
+    fn of(&self, k: &str) -> Option<usize> {
+        self.namespaces
+            .iter()
+            .position(|ns| k.starts_with(&ns.prefix))
+    }
+
+    // The keys `command` writes that are in a namespace, for `update`. Range
+    // deletions leave the namespaces they overlap stale instead.
+    pub fn keys(&self, command: &Command) -> Vec<String> {
+        if self.namespaces.is_empty() {
+            return vec![];
+        }
+        let overlaps: Box<dyn Fn(&str) -> bool + '_> = match command.unwrap_request() {
+            Command::Transaction(writes) => {
+                return writes
+                    .iter()
+                    .filter(|(k, _)| self.of(k).is_some())
+                    .map(|(k, _)| k.clone())
+                    .collect();
+            }
+            // Keys starting with `prefix` run from `prefix` up to, but not
+            // including, the first string after all of them.
+            Command::DeleteRange(start, end) => Box::new(move |prefix: &str| {
+                end.as_str() > prefix && (start.as_str() < prefix || start.starts_with(prefix))
+            }),
+            Command::DeletePrefix(deleted) => Box::new(move |prefix: &str| {
+                prefix.starts_with(deleted.as_str()) || deleted.starts_with(prefix)
+            }),
+            command => {
+                let k = command.key().filter(|k| self.of(k).is_some());
+                return k.into_iter().map(str::to_owned).collect();
+            }
+        };
+        let mut usage = error::lock(&self.usage);
+        for (ns, usage) in self.namespaces.iter().zip(usage.iter_mut()) {
+            if overlaps(&ns.prefix) {
+                usage.stale = true;
+            }
+        }
+        vec![]
+    }
+
+    // Counts a command applied to `keys`, which took up `before` and take
+    // up `after`, or None where a key isn't there.
+    pub fn update(&self, keys: &[String], before: &[Option<usize>], after: &[Option<usize>]) {
+        if keys.is_empty() {
+            return;
+        }
+        let mut usage = error::lock(&self.usage);
+        for ((k, before), after) in keys.iter().zip(before).zip(after) {
+            let Some(i) = self.of(k) else {
+                continue;
+            };
+            let usage = &mut usage[i];
+            let size = |size: &Option<usize>| size.map_or(0, |s| s as u64);
+            usage.bytes = (usage.bytes + size(after)).saturating_sub(size(before));
+            usage.keys =
+                (usage.keys + after.is_some() as u64).saturating_sub(before.is_some() as u64);
+        }
+    }
+
+    // Fails with `Error::QuotaExceeded` if `command` would take a namespace
+    // further past its quota, given how much each key takes up now by
+    // `stored`, and what writes already let through might add. Otherwise
+    // holds what it might add against its namespaces until the returned
+    // reservation is dropped. An `incr` or `append` is only checked for the
+    // key it might add, since what it does to the value isn't known until
+    // it's applied.
+    pub fn reserve<F>(self: &Arc<Self>, command: &Command, stored: F) -> Result<Reservation>
+    where
+        F: Fn(&str) -> Result<Option<usize>>,
+    {
+        let mut reservation = Reservation {
+            quotas: self.clone(),
+            growth: vec![],
+        };
+        if self.namespaces.is_empty() {
+            return Ok(reservation);
+        }
+        let writes = match command.unwrap_request() {
+            Command::Set(k, v) | Command::SetExpiring(k, v, _) => {
+                vec![(k, Some(k.len() + v.len()))]
+            }
+            Command::Incr(k, ..) | Command::Append(k, _) => {
+                vec![(k, Some(stored(k)?.unwrap_or(k.len())))]
+            }
+            Command::Transaction(writes) => writes
+                .iter()
+                .map(|(k, v)| (k, v.as_ref().map(|v| k.len() + v.len())))
+                .collect(),
+            _ => return Ok(reservation),
+        };
+        let mut growth = vec![(0i64, 0i64); self.namespaces.len()];
+        for (k, after) in writes {
+            let Some(i) = self.of(k) else {
+                continue;
+            };
+            let before = stored(k)?;
+            let size = |size: Option<usize>| size.map_or(0, |s| s as i64);
+            growth[i].0 += size(after) - size(before);
+            growth[i].1 += after.is_some() as i64 - before.is_some() as i64;
+        }
+        let mut usage = error::lock(&self.usage);
+        for ((ns, usage), &(bytes, keys)) in self.namespaces.iter().zip(usage.iter()).zip(&growth) {
+            let over = |now: u64, reserved: u64, growth: i64, max: Option<u64>| {
+                let now = now.saturating_add(reserved);
+                growth > 0 && max.is_some_and(|max| now.saturating_add_signed(growth) > max)
+            };
+            if over(usage.bytes, usage.reserved.0, bytes, ns.max_bytes)
+                || over(usage.keys, usage.reserved.1, keys, ns.max_keys)
+            {
+                return Err(Error::QuotaExceeded {
+                    namespace: ns.name.clone(),
+                    bytes: usage.bytes,
+                    keys: usage.keys,
+                });
+            }
+        }
+        // Only what a write adds is held. What it frees isn't counted until
+        // it's applied.
+        reservation.growth = growth
+            .into_iter()
+            .map(|(bytes, keys)| (bytes.max(0) as u64, keys.max(0) as u64))
+            .collect();
+        for (usage, (bytes, keys)) in usage.iter_mut().zip(&reservation.growth) {
+            usage.reserved.0 += bytes;
+            usage.reserved.1 += keys;
+        }
+        Ok(reservation)
+    }
+
+    // The prefix of each namespace that has to be counted from scratch, by
+    // its index, for `recounted`.
+    pub fn stale(&self) -> Vec<(usize, String)> {
+        let usage = error::lock(&self.usage);
+        let stale = usage.iter().enumerate().filter(|(_, u)| u.stale);
+        stale
+            .map(|(i, _)| (i, self.namespaces[i].prefix.clone()))
+            .collect()
+    }
+
+    // Takes a count from scratch of namespace `i`, which the caller made
+    // with the memtable locked, so that nothing changed while it ran.
+    pub fn recounted(&self, i: usize, (bytes, keys): (u64, u64)) {
+        let usage = &mut error::lock(&self.usage)[i];
+        (usage.bytes, usage.keys, usage.stale) = (bytes, keys, false);
+    }
+
+    // Leaves every namespace to be counted again from scratch, after the
+    // memtable changed in ways `update` didn't see.
+    pub fn invalidate(&self) {
+        for usage in error::lock(&self.usage).iter_mut() {
+            usage.stale = true;
+        }
+    }
+
+    pub fn usage(&self) -> Vec<NamespaceUsage> {
+        let usage = error::lock(&self.usage);
+        let namespaces = self.namespaces.iter().zip(usage.iter());
+        namespaces
+            .map(|(ns, usage)| NamespaceUsage {
+                name: ns.name.clone(),
+                bytes: usage.bytes,
+                keys: usage.keys,
+            })
+            .collect()
+    }
+}
+
+#[test]
+fn test_quotas() -> Result<()> {
+    let dir = tempdir()?;
+    let file = dir.path().join("logfile");
+    let namespaces = vec![
+        Namespace {
+            name: "acme".into(),
+            prefix: "acme/".into(),
+            max_keys: Some(3),
+            ..Namespace::default()
+        },
+        Namespace {
+            name: "globex".into(),
+            prefix: "globex/".into(),
+            max_bytes: Some(100),
+            ..Namespace::default()
+        },
+    ];
+    let options = Options {
+        segment_size: 4096,
+        memtable_bytes: Some(2048),
+        namespaces: namespaces.clone(),
+        ..Options::default()
+    };
+    let mut db = Db::with_options(&file, options.clone())?;
+    let exceeded = |result: Result<()>, name: &str| match result {
+        Err(Error::QuotaExceeded { namespace, .. }) => assert_eq!(namespace, name),
+        result => panic!("{:?}", result),
+    };
+
+    // A namespace can't be given more keys than it's allowed, though its keys
+    // can still be overwritten, and deleting some makes room.
+    for i in 0..3 {
+        db.set(&format!("acme/{}", i), "v")?;
+    }
+    exceeded(db.set("acme/3", "v"), "acme");
+    exceeded(db.incr("acme/3", 1).map(drop), "acme");
+    db.set("acme/0", &"v".repeat(100))?;
+    db.delete("acme/1")?;
+    db.set("acme/3", "v")?;
+    assert_eq!(db.get("acme/3"), Some("v".into()));
+
+    // Nor more bytes, counting keys and values alike.
+    db.set("globex/a", &"v".repeat(80))?;
+    exceeded(db.set("globex/b", &"v".repeat(20)), "globex");
+    db.set("globex/a", "v")?;
+    db.set("globex/b", &"v".repeat(20))?;
+
+    // A transaction is turned away whole.
+    let mut txn = db.transaction();
+    txn.set("acme/4", "v");
+    txn.set("other", "v");
+    exceeded(txn.commit(), "acme");
+    assert_eq!(db.get("other"), None);
+
+    // Keys outside every namespace, and keyspaces, aren't counted.
+    for i in 0..100 {
+        db.set(&format!("other/{}", i), &"x".repeat(50))?;
+    }
+    db.cf("acme/").set("k", "v")?;
+    let usage = db.stats()?.namespaces;
+    let expected = [("acme", 3, 106 + 7 + 7), ("globex", 2, 9 + 28)];
+    for (usage, (name, keys, bytes)) in usage.iter().zip(expected) {
+        assert_eq!(
+            (usage.name.as_str(), usage.keys, usage.bytes),
+            (name, keys, bytes)
+        );
+    }
+
+    // The counts are the same once reopened, with a flush in between, and
+    // range deletions are counted again from scratch.
+    db.flush()?;
+    drop(db);
+    let mut db = Db::with_options(&file, options.clone())?;
+    assert_eq!(db.stats()?.namespaces, usage);
+    db.delete_prefix("acme/")?;
+    db.delete_range("globex/", "globex/b")?;
+    let usage = db.stats()?.namespaces;
+    assert_eq!((usage[0].keys, usage[0].bytes), (0, 0));
+    assert_eq!((usage[1].keys, usage[1].bytes), (1, 28));
+    drop(db);
+
+    // Over a quota that's been lowered, writes that don't add to it still go
+    // through.
+    let options = Options {
+        namespaces: vec![Namespace {
+            max_bytes: Some(10),
+            ..namespaces[1].clone()
+        }],
+        ..options
+    };
+    let mut db = Db::with_options(&file, options)?;
+    exceeded(db.set("globex/b", &"v".repeat(21)), "globex");
+    db.set("globex/b", "v")?;
+    db.delete("globex/b")?;
+    db.set("globex/c", "v")?;
+    assert_eq!(db.stats()?.namespaces[0].bytes, 9);
+    Ok(())
+}
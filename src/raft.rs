@@ -0,0 +1,596 @@
+use crate::codec;
+use crate::error::{decode, encode};
+use crate::log::Log;
+use crate::{segment, Command, Db, Error, IdempotencyKey, Options, Result};
+use serde::{Deserialize, Serialize};
+use std::{collections::VecDeque, path::Path, sync::Arc};
+#[cfg(test)]
+use tempfile::tempdir;
+
+// Storage for running a `Db` as the state machine of a Raft group.
+//
+// This crate doesn't implement Raft itself. Instead, `LogStore` and
+// `StateMachine` describe what a Raft implementation needs from its storage,
+// and `RaftStore` provides both on top of a redo log, so hooking a Raft library
+// up to it is a matter of forwarding its storage calls. A replicated KV store
+// is then three of these, each with its own directory, and a Raft library
+// passing messages between them.
+//
+// A `RaftStore` directory holds two logs: `raft`, which holds the Raft log
+// entries and the node's hard state, and `data`, which is an ordinary `Db`
+// that committed entries are applied to.
+
+// A Raft log entry. `data` is a serialized `Command`, or empty for entries that
+// don't change the database, like the no-op a new leader commits.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    pub index: u64,
+    pub term: u64,
+    pub data: Vec<u8>,
+}
+
+impl Entry {
+    pub fn new(index: u64, term: u64, command: &Command) -> Result<Self> {
+        Ok(Entry {
+            index,
+            term,
+            data: encode(command)?,
+        })
+    }
+}
+
+// What a node has to remember across restarts to vote safely.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct HardState {
+    pub term: u64,
+    pub vote: Option<u64>,
+    pub commit: u64,
+}
+
+// Identifies an entry by its position and the term it was written in. The
+// zero value stands for "nothing yet".
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LogId {
+    pub index: u64,
+    pub term: u64,
+}
+
+// The full contents of the database as of `last`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Snapshot {
+    pub last: LogId,
+    pub data: Vec<(String, String)>,
+}
+
+pub trait LogStore {
+    fn hard_state(&self) -> HardState;
+
+    fn set_hard_state(&mut self, state: HardState) -> Result<()>;
+
+    // The index of the first entry still in the log. If the log is empty, this
+    // is one past `last_index`.
+    fn first_index(&self) -> u64;
+
+    // The index of the last entry in the log, or of the last entry purged from
+    // it if it's empty.
+    fn last_index(&self) -> u64;
+
+    // The term of the entry at `index`, which can also be the last entry
+    // purged.
+    fn term(&self, index: u64) -> Result<u64>;
+
+    // The entries from `lo` up to but not including `hi`.
+    fn entries(&self, lo: u64, hi: u64) -> Result<Vec<Entry>>;
+
+    // Durably appends `entries`, which must be contiguous. Any existing entries
+    // from the first one's index on are replaced.
+    fn append(&mut self, entries: &[Entry]) -> Result<()>;
+
+    // Discards every entry up to and including `index`, which must already
+    // have been applied.
+    fn purge(&mut self, index: u64) -> Result<()>;
+}
+
+pub trait StateMachine {
+    // The last entry applied.
+    fn applied(&self) -> LogId;
+
+    // Applies committed entries, which must carry on from `applied`. Entries
+    // that have already been applied are skipped.
+    fn apply(&mut self, entries: &[Entry]) -> Result<()>;
+
+    fn snapshot(&self) -> Result<Snapshot>;
+
+    // Replaces the state machine's contents with `snapshot`, and discards the
+    // log entries it covers.
+    fn install_snapshot(&mut self, snapshot: &Snapshot) -> Result<()>;
+}
+
+// What gets written to the `raft` log.
+#[derive(Serialize, Deserialize, Debug)]
+enum Record {
+    HardState(HardState),
+    Entry(Entry),
+    // Drops every entry from this index on.
+    Truncate(u64),
+    // Drops every entry up to and including this one.
+    Purge(LogId),
+    Applied(LogId),
+}
+
+#[derive(Debug)]
+pub struct RaftStore {
+    log: Log,
+    db: Db,
+    state: RaftState,
+}
+
+impl RaftStore {
+    pub fn open<P>(dir: P, options: Options) -> Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        let dir = dir.as_ref();
+        let db = Db::with_options(dir.join("data"), options.clone())?;
+        let raft_dir = dir.join("raft");
+        let mut state = RaftState::default();
+        // Raft's own records are always JSON.
+        let options = Options {
+            codec: Arc::new(codec::Json),
+            ..options
+        };
+        let log = Log::open(&raft_dir, options, |segments| {
+            let mut buf = vec![];
+            for &number in segments {
+                segment::read_records(&raft_dir, number, &mut buf, |offset, record| {
+                    state.replay(decode(number, offset, record)?);
+                    Ok(())
+                })?;
+            }
+            Ok(())
+        })?;
+        Ok(RaftStore { log, db, state })
+    }
+
+    // The database that committed entries are applied to. Only read from it;
+    // anything written to it directly isn't replicated.
+    pub fn db(&self) -> &Db {
+        &self.db
+    }
+
+    fn write(&mut self, records: &[Record], sync: bool) -> Result<()> {
+        let payloads = records.iter().map(encode).collect::<Result<Vec<_>>>()?;
+        self.log.append_batch(&payloads)?;
+        if sync {
+            self.log.sync()?;
+        }
+        Ok(())
+    }
+
+    // Rewrites the `raft` log as just what we're holding in memory, so that
+    // the segments holding purged entries can go.
+    fn compact(&mut self) -> Result<()> {
+        let records = [
+            Record::HardState(self.state.hard_state),
+            Record::Purge(self.state.purged),
+            Record::Applied(self.state.applied),
+        ]
+        .into_iter()
+        .chain(self.state.entries.iter().cloned().map(Record::Entry))
+        .map(|r| encode(&r))
+        .collect::<Result<Vec<_>>>()?;
+        self.log.compact(&records, None)
+    }
+}
+
+// The in-memory half of a `RaftStore`, as rebuilt from its log.
+#[derive(Debug, Default)]
+struct RaftState {
+    hard_state: HardState,
+    // The last entry dropped from the front of the log. We still need its term
+    // to check that whatever comes after it follows on.
+    purged: LogId,
+    entries: VecDeque<Entry>,
+    applied: LogId,
+}
+
+impl RaftState {
+    fn replay(&mut self, record: Record) {
+        match record {
+            Record::HardState(state) => self.hard_state = state,
+            Record::Entry(entry) => {
+                self.truncate(entry.index);
+                self.entries.push_back(entry);
+            }
+            Record::Truncate(index) => self.truncate(index),
+            Record::Purge(id) => {
+                while self.entries.front().is_some_and(|e| e.index <= id.index) {
+                    self.entries.pop_front();
+                }
+                // If what's left doesn't follow on, it was replaced by a
+                // snapshot.
+                if self
+                    .entries
+                    .front()
+                    .is_some_and(|e| e.index != id.index + 1)
+                {
+                    self.entries.clear();
+                }
+                self.purged = id;
+            }
+            Record::Applied(id) => self.applied = id,
+        }
+    }
+
+    fn truncate(&mut self, index: u64) {
+        let keep = index.saturating_sub(self.purged.index + 1) as usize;
+        self.entries.truncate(keep);
+    }
+}
+
+impl LogStore for RaftStore {
+    fn hard_state(&self) -> HardState {
+        self.state.hard_state
+    }
+
+    fn set_hard_state(&mut self, state: HardState) -> Result<()> {
+        self.write(&[Record::HardState(state)], true)?;
+        self.state.hard_state = state;
+        Ok(())
+    }
+
+    fn first_index(&self) -> u64 {
+        self.state.purged.index + 1
+    }
+
+    fn last_index(&self) -> u64 {
+        self.state
+            .entries
+            .back()
+            .map_or(self.state.purged.index, |e| e.index)
+    }
+
+    fn term(&self, index: u64) -> Result<u64> {
+        if index == self.state.purged.index {
+            return Ok(self.state.purged.term);
+        }
+        if index < self.first_index() || index > self.last_index() {
+            return Err(Error::Raft(format!(
+                "entry {} is not in the log ({} to {})",
+                index,
+                self.first_index(),
+                self.last_index()
+            )));
+        }
+        Ok(self.state.entries[(index - self.first_index()) as usize].term)
+    }
+
+    fn entries(&self, lo: u64, hi: u64) -> Result<Vec<Entry>> {
+        if lo < self.first_index() || hi > self.last_index() + 1 || lo > hi {
+            return Err(Error::Raft(format!(
+                "entries {} to {} are not in the log ({} to {})",
+                lo,
+                hi,
+                self.first_index(),
+                self.last_index()
+            )));
+        }
+        let first = self.first_index();
+        Ok(self
+            .state
+            .entries
+            .range((lo - first) as usize..(hi - first) as usize)
+            .cloned()
+            .collect())
+    }
+
+    fn append(&mut self, entries: &[Entry]) -> Result<()> {
+        let first = match entries.first() {
+            Some(entry) => entry.index,
+            None => return Ok(()),
+        };
+        if first < self.first_index() {
+            return Err(Error::Raft(format!(
+                "entry {} has already been purged",
+                first
+            )));
+        }
+        if first > self.last_index() + 1 {
+            return Err(Error::Raft(format!(
+                "entry {} doesn't follow on from the end of the log at {}",
+                first,
+                self.last_index()
+            )));
+        }
+        for (i, entry) in entries.iter().enumerate() {
+            if entry.index != first + i as u64 {
+                return Err(Error::Raft(format!(
+                    "gap in appended entries at {}",
+                    entry.index
+                )));
+            }
+        }
+        let mut records = Vec::with_capacity(entries.len() + 1);
+        if first <= self.last_index() {
+            records.push(Record::Truncate(first));
+        }
+        records.extend(entries.iter().cloned().map(Record::Entry));
+        self.write(&records, true)?;
+
+        for record in records {
+            self.state.replay(record);
+        }
+        Ok(())
+    }
+
+    fn purge(&mut self, index: u64) -> Result<()> {
+        if index <= self.state.purged.index {
+            return Ok(());
+        }
+        if index > self.state.applied.index {
+            return Err(Error::Raft(format!(
+                "can't purge up to {} when only {} has been applied",
+                index, self.state.applied.index
+            )));
+        }
+        let purged = LogId {
+            index,
+            term: self.term(index)?,
+        };
+        self.state.replay(Record::Purge(purged));
+        self.compact()
+    }
+}
+
+impl StateMachine for RaftStore {
+    fn applied(&self) -> LogId {
+        self.state.applied
+    }
+
+    // Commands are written to the database before `applied` is, and `applied`
+    // isn't synced, so after a crash some entries may be applied a second
+    // time. Each command is written as a request from `RAFT_CLIENT_ID`
+    // numbered by its entry, in the same record, so that the database skips
+    // the ones it has already applied as retries. Adding to a counter or a
+    // list twice, or setting a key back to what it was before one, would
+    // otherwise go unnoticed. Commands that already are requests are skipped by their own
+    // keys.
+    fn apply(&mut self, entries: &[Entry]) -> Result<()> {
+        let mut commands = vec![];
+        let mut last = self.state.applied;
+        for entry in entries {
+            if entry.index <= last.index {
+                continue;
+            }
+            if entry.index != last.index + 1 {
+                return Err(Error::Raft(format!(
+                    "entry {} doesn't follow on from {}",
+                    entry.index, last.index
+                )));
+            }
+            if !entry.data.is_empty() {
+                let command = serde_json::from_slice(&entry.data).map_err(|e| {
+                    Error::Raft(format!("entry {} isn't a command: {}", entry.index, e))
+                })?;
+                commands.push(match command {
+                    Command::Request(..) => command,
+                    command => {
+                        let key = IdempotencyKey {
+                            client_id: RAFT_CLIENT_ID,
+                            request_id: entry.index,
+                        };
+                        Command::Request(key, Box::new(command))
+                    }
+                });
+            }
+            last = LogId {
+                index: entry.index,
+                term: entry.term,
+            };
+        }
+        if last == self.state.applied {
+            return Ok(());
+        }
+        self.db.write_batch(commands)?;
+        self.write(&[Record::Applied(last)], false)?;
+        self.state.applied = last;
+        Ok(())
+    }
+
+    fn snapshot(&self) -> Result<Snapshot> {
+        Ok(Snapshot {
+            last: self.state.applied,
+            data: self.db.scan(""),
+        })
+    }
+
+    fn install_snapshot(&mut self, snapshot: &Snapshot) -> Result<()> {
+        let mut commands = self
+            .db
+            .scan("")
+            .into_iter()
+            .filter(|(k, _)| snapshot.data.binary_search_by(|(s, _)| s.cmp(k)).is_err())
+            .map(|(k, _)| Command::Delete(k))
+            .collect::<Vec<_>>();
+        commands.extend(
+            snapshot
+                .data
+                .iter()
+                .map(|(k, v)| Command::Set(k.clone(), v.clone())),
+        );
+        self.db.write_batch(commands)?;
+
+        // Keep whatever comes after the snapshot if it's consistent with it.
+        let last = snapshot.last;
+        if self.term(last.index).ok() != Some(last.term) {
+            self.state.entries.clear();
+        }
+        self.state.applied = last;
+        self.state.replay(Record::Purge(last));
+        self.compact()
+    }
+}
+
+// The client that entries are applied to the database as requests from (see
+// `RaftStore::apply`). Applications writing with their own `IdempotencyKey`s
+// can't use it.
+pub const RAFT_CLIENT_ID: u64 = u64::MAX;
+
+#[cfg(test)]
+fn set(index: u64, term: u64, k: &str, v: &str) -> Entry {
+    Entry::new(index, term, &Command::Set(k.into(), v.into())).unwrap()
+}
+
+#[test]
+fn test_log_store() -> Result<()> {
+    let dir = tempdir()?;
+    let mut store = RaftStore::open(dir.path(), Options::default())?;
+    assert_eq!((store.first_index(), store.last_index()), (1, 0));
+    assert_eq!(store.term(0)?, 0);
+
+    let state = HardState {
+        term: 2,
+        vote: Some(3),
+        commit: 1,
+    };
+    store.set_hard_state(state)?;
+    store.append(&[
+        set(1, 1, "a", "1"),
+        set(2, 1, "b", "1"),
+        set(3, 1, "c", "1"),
+    ])?;
+    // A new leader overwrites the entries that didn't get committed.
+    store.append(&[set(2, 2, "b", "2")])?;
+    assert_eq!(store.last_index(), 2);
+    assert_eq!(store.term(2)?, 2);
+    assert!(store.term(3).is_err());
+    assert!(store.append(&[set(5, 2, "e", "2")]).is_err());
+
+    drop(store);
+    let store = RaftStore::open(dir.path(), Options::default())?;
+    assert_eq!(store.hard_state(), state);
+    assert_eq!(
+        store.entries(1, 3)?,
+        vec![set(1, 1, "a", "1"), set(2, 2, "b", "2")]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_apply_and_purge() -> Result<()> {
+    let dir = tempdir()?;
+    let options = Options {
+        segment_size: 512,
+        ..Options::default()
+    };
+    let mut store = RaftStore::open(dir.path(), options.clone())?;
+    let entries = (1..=20)
+        .map(|i| set(i, 1, &format!("key{}", i % 3), &i.to_string()))
+        .collect::<Vec<_>>();
+    store.append(&entries)?;
+    assert!(store.purge(5).is_err());
+
+    store.apply(&entries[..15])?;
+    assert_eq!(store.applied(), LogId { index: 15, term: 1 });
+    assert_eq!(store.db().get("key0"), Some("15".into()));
+    // Entries that were already applied are skipped.
+    store.apply(&entries[10..12])?;
+    assert!(store.apply(&entries[16..]).is_err());
+
+    store.purge(12)?;
+    assert_eq!(store.first_index(), 13);
+    assert_eq!(store.term(12)?, 1);
+    assert!(store.entries(12, 14).is_err());
+
+    drop(store);
+    let store = RaftStore::open(dir.path(), options)?;
+    assert_eq!(store.first_index(), 13);
+    assert_eq!(store.last_index(), 20);
+    assert_eq!(store.applied(), LogId { index: 15, term: 1 });
+    assert_eq!(store.db().get("key2"), Some("14".into()));
+
+    Ok(())
+}
+
+#[test]
+fn test_reapply_after_crash() -> Result<()> {
+    let dir = tempdir()?;
+    let mut store = RaftStore::open(dir.path(), Options::default())?;
+    let entries = [
+        set(1, 1, "n", "1"),
+        Entry::new(2, 1, &Command::Incr("n".into(), 10, 0))?,
+        Entry::new(3, 1, &Command::Incr("n".into(), 100, 0))?,
+        Entry::new(4, 1, &Command::Append("list".into(), "a".into()))?,
+        Entry::new(5, 1, &Command::Append("list".into(), "b".into()))?,
+    ];
+    store.append(&entries)?;
+    store.apply(&entries[..4])?;
+
+    // A crash before `applied` got to disk has the entries applied again.
+    store.state.applied = LogId::default();
+    store.apply(&entries)?;
+    let list = Some(vec!["a".to_owned(), "b".to_owned()]);
+    assert_eq!(store.db().get("n"), Some("111".into()));
+    assert_eq!(store.db().get_list("list"), list);
+    drop(store);
+    let mut store = RaftStore::open(dir.path(), Options::default())?;
+    store.state.applied = LogId { index: 1, term: 1 };
+    store.apply(&entries)?;
+    assert_eq!(store.db().get("n"), Some("111".into()));
+    assert_eq!(store.db().get_list("list"), list);
+
+    Ok(())
+}
+
+// Plays the part of a Raft leader to check that three stores stay in step,
+// including a node that falls too far behind and has to be sent a snapshot.
+#[test]
+fn test_three_nodes() -> Result<()> {
+    let dir = tempdir()?;
+    let mut nodes = (0..3)
+        .map(|i| RaftStore::open(dir.path().join(i.to_string()), Options::default()))
+        .collect::<Result<Vec<_>>>()?;
+
+    // A leader in term 1 gets an entry out to node 1 but crashes before it
+    // commits.
+    let noop = |index, term| Entry {
+        index,
+        term,
+        data: vec![],
+    };
+    nodes[0].append(&[noop(1, 1), set(2, 1, "lost", "write")])?;
+    nodes[1].append(&[noop(1, 1), set(2, 1, "lost", "write")])?;
+
+    // Node 2 takes over in term 2 with node 1's vote, and brings node 1 into
+    // line. Node 0 is partitioned away.
+    let mut log = vec![noop(1, 1), noop(2, 2)];
+    for i in 0..10 {
+        log.push(set(log.len() as u64 + 1, 2, &format!("k{}", i), "v"));
+    }
+    log.push(Entry::new(13, 2, &Command::Delete("k0".into()))?);
+    for node in &mut nodes[1..] {
+        node.append(&log)?;
+        node.apply(&log)?;
+    }
+    assert_eq!(nodes[1].entries(1, 14)?, log);
+    nodes[2].purge(10)?;
+
+    // Node 0 comes back, but what it's missing has been purged from the
+    // leader, so it gets a snapshot instead.
+    let snapshot = nodes[2].snapshot()?;
+    nodes[0].install_snapshot(&snapshot)?;
+    assert_eq!(nodes[0].first_index(), 14);
+    nodes[0].append(&[set(14, 2, "k1", "after")])?;
+    nodes[0].apply(&[set(14, 2, "k1", "after")])?;
+
+    drop(nodes);
+    let node = RaftStore::open(dir.path().join("0"), Options::default())?;
+    assert_eq!(node.db().get("lost"), None);
+    assert_eq!(node.db().get("k0"), None);
+    assert_eq!(node.db().get("k1"), Some("after".into()));
+    assert_eq!(node.db().len(), 9);
+    assert_eq!(node.applied(), LogId { index: 14, term: 2 });
+
+    Ok(())
+}
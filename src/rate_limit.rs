@@ -0,0 +1,167 @@
+use crate::error;
+#[cfg(test)]
+use crate::{Db, Options, Result};
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+#[cfg(test)]
+use tempfile::tempdir;
+
+// Holds flushes, compactions and value log rewrites to
+// `Options::background_bytes_per_sec` between them, so that they leave the
+// disk to commits. It's a token bucket holding up to a second's worth of
+// bytes: a rewrite takes what it's about to write out of it, going into debt
+// if need be, and then waits until the debt would have been paid off. With
+// no rate, nothing waits, though the bytes are still counted. Databases
+// opened by the same `DbManager` share one.
+#[derive(Debug)]
+pub struct RateLimiter {
+    bytes_per_sec: Option<u64>,
+    bucket: Mutex<Bucket>,
+    // Bytes asked for, and how long was spent waiting for them altogether,
+    // in nanoseconds.
+    bytes: AtomicU64,
+    waited: AtomicU64,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(bytes_per_sec: Option<u64>) -> Self {
+        RateLimiter {
+            bytes_per_sec: bytes_per_sec.map(|rate| rate.max(1)),
+            bucket: Mutex::new(Bucket {
+                tokens: bytes_per_sec.unwrap_or(0) as f64,
+                refilled: Instant::now(),
+            }),
+            bytes: AtomicU64::new(0),
+            waited: AtomicU64::new(0),
+        }
+    }
+
+    pub fn bytes_per_sec(&self) -> Option<u64> {
+        self.bytes_per_sec
+    }
+
+    // Bytes written through the limiter, and how long they were held back.
+    pub fn bytes(&self) -> u64 {
+        self.bytes.load(Ordering::Relaxed)
+    }
+
+    pub fn waited(&self) -> Duration {
+        Duration::from_nanos(self.waited.load(Ordering::Relaxed))
+    }
+
+    // Waits until `bytes` more can be written, and returns how long that was.
+    pub(crate) fn request(&self, bytes: u64) -> Duration {
+        self.bytes.fetch_add(bytes, Ordering::Relaxed);
+        let Some(rate) = self.bytes_per_sec else {
+            return Duration::ZERO;
+        };
+        let rate = rate as f64;
+        let wait = {
+            let mut bucket = error::lock(&self.bucket);
+            let now = Instant::now();
+            let refill = (now - bucket.refilled).as_secs_f64() * rate;
+            bucket.tokens = (bucket.tokens + refill).min(rate) - bytes as f64;
+            bucket.refilled = now;
+            Duration::from_secs_f64((-bucket.tokens).max(0.0) / rate)
+        };
+        if wait.is_zero() {
+            return wait;
+        }
+        std::thread::sleep(wait);
+        self.waited
+            .fetch_add(wait.as_nanos() as u64, Ordering::Relaxed);
+        wait
+    }
+}
+
+#[test]
+fn test_rate_limiter() {
+    // A second's worth goes straight through, and the next has to wait for
+    // the bucket to fill up again.
+    let limiter = RateLimiter::new(Some(100_000));
+    let start = Instant::now();
+    limiter.request(100_000);
+    assert!(limiter.waited().is_zero());
+    limiter.request(20_000);
+    assert!(start.elapsed() >= Duration::from_millis(200));
+    assert!(limiter.waited() >= Duration::from_millis(150));
+    assert_eq!(limiter.bytes(), 120_000);
+
+    let unlimited = RateLimiter::new(None);
+    unlimited.request(u64::MAX / 2);
+    assert!(unlimited.waited().is_zero());
+}
+
+#[test]
+fn test_rate_limited_flush() -> Result<()> {
+    let dir = tempdir()?;
+    let options = Options {
+        background_bytes_per_sec: Some(20_000),
+        ..Options::default()
+    };
+    let mut db = Db::with_options(dir.path(), options)?;
+    for i in 0..100 {
+        db.set(&format!("k{:03}", i), &"v".repeat(400))?;
+    }
+    // Some 40KB of table takes at least a second once the first 20KB are
+    // used up.
+    let start = Instant::now();
+    db.flush()?;
+    assert!(start.elapsed() >= Duration::from_millis(900));
+    let stats = db.stats()?;
+    assert_eq!(stats.background_bytes_per_sec, Some(20_000));
+    assert!(stats.background_bytes >= 40_000);
+    assert!(stats.background_throttled >= Duration::from_millis(900));
+    Ok(())
+}
+
+#[test]
+fn test_writes_during_flush() -> Result<()> {
+    let dir = tempdir()?;
+    let options = Options {
+        background_bytes_per_sec: Some(20_000),
+        ..Options::default()
+    };
+    let mut db = Db::with_options(dir.path(), options.clone())?;
+    for i in 0..100 {
+        db.set(&format!("k{:03}", i), &"v".repeat(400))?;
+    }
+    // Writes carry on while the flush is held back writing its table, and
+    // what they do is kept, whether it's to keys being flushed or not.
+    let flush = std::thread::spawn({
+        let db = db.clone();
+        move || db.flush()
+    });
+    std::thread::sleep(Duration::from_millis(200));
+    let start = Instant::now();
+    db.set("k000", "changed")?;
+    db.delete("k001")?;
+    db.set("new", "v")?;
+    db.delete_range("k010", "k020")?;
+    assert!(start.elapsed() < Duration::from_millis(500));
+    assert!(!flush.is_finished());
+    flush.join().unwrap()?;
+    let check = |db: &Db| -> Result<()> {
+        assert_eq!(db.try_get("k000")?.as_deref(), Some("changed"));
+        assert_eq!(db.try_get("k001")?, None);
+        assert_eq!(db.try_get("k002")?, Some("v".repeat(400)));
+        assert_eq!(db.try_get("k015")?, None);
+        assert_eq!(db.try_get("new")?.as_deref(), Some("v"));
+        assert_eq!(db.try_scan("k")?.len(), 89);
+        Ok(())
+    };
+    check(&db)?;
+    drop(db);
+    check(&Db::with_options(dir.path(), options)?)
+}
@@ -0,0 +1,409 @@
+use crate::codec::{self, Decoder, RecordCodec};
+use crate::log::{self, Log};
+use crate::manifest::Manifest;
+use crate::segment::{self, SegmentReader};
+use crate::{Command, Error, Result};
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+#[cfg(test)]
+use tempfile::tempdir;
+
+// Where a record lives in the log: which segment, and how far into it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct LogOffset {
+    pub segment: u64,
+    pub offset: u64,
+}
+
+impl LogOffset {
+    // Before the first segment, which is numbered 1, for reading from the
+    // oldest record still in the log.
+    pub const START: LogOffset = LogOffset {
+        segment: 0,
+        offset: 0,
+    };
+}
+
+// Records read straight out of one segment by `Db::read_log_from`, framed as
+// they are on disk: each is a header and then its payload, which
+// `segment::decode_record` splits back apart. Empty once there's nothing
+// more to read yet.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogChunk {
+    pub records: Vec<u8>,
+    // The codec the payloads are in, which is the segment's.
+    pub codec: u32,
+    // Where to read from next.
+    pub next: LogOffset,
+}
+
+// Reads whole records from `from` on, until the next would take the chunk
+// past `max_bytes`, the end of the segment, or `last_seq`. At least one
+// record is read if there is one, however big. A segment that ends in a
+// torn record is finished if there are later ones, and otherwise is still
+// being written to. The log in `dir` can't be sharded.
+pub(crate) fn read_chunk(
+    dir: &Path,
+    from: LogOffset,
+    max_bytes: usize,
+    last_seq: u64,
+) -> Result<LogChunk> {
+    let (segments, _) = segment::list(dir)?;
+    let retired = |number| {
+        Error::InvalidConfig(format!(
+            "segment {} is no longer in the log in {}",
+            number,
+            dir.display()
+        ))
+    };
+    let mut at = match from {
+        LogOffset::START => match segments.first() {
+            Some(&segment) => LogOffset { segment, offset: 0 },
+            None => return Ok(LogChunk::empty(from)),
+        },
+        from if segments.contains(&from.segment) => from,
+        from => return Err(retired(from.segment)),
+    };
+    let mut payload = vec![];
+    loop {
+        let offset = at.offset.max(segment::SEGMENT_HEADER_LEN as u64);
+        // Records are only checked against each other, since nothing says
+        // what the sequence number at `offset` is.
+        let mut reader = match SegmentReader::resume(dir, at.segment, offset, 0) {
+            Ok(reader) => reader,
+            Err(e) if e.is_not_found() => return Err(retired(at.segment)),
+            Err(e) => return Err(e),
+        };
+        reader.set_sparse(true);
+        let mut chunk = LogChunk {
+            records: vec![],
+            codec: reader.codec(),
+            next: LogOffset { offset, ..at },
+        };
+        while let Some((offset, seq)) = reader.next_record(&mut payload)? {
+            let full = chunk.records.len() + segment::HEADER_LEN + payload.len() > max_bytes;
+            if seq > last_seq || (full && !chunk.records.is_empty()) {
+                chunk.next.offset = offset;
+                return Ok(chunk);
+            }
+            segment::encode_record(at.segment, seq, &payload, &mut chunk.records);
+            chunk.next.offset = reader.offset();
+        }
+        // Only move on to the next segment from the end of this one.
+        match segments.iter().find(|&&n| n > at.segment) {
+            Some(&segment) if chunk.records.is_empty() => at = LogOffset { segment, offset: 0 },
+            _ => return Ok(chunk),
+        }
+    }
+}
+
+impl LogChunk {
+    fn empty(next: LogOffset) -> Self {
+        LogChunk {
+            records: vec![],
+            codec: 0,
+            next,
+        }
+    }
+}
+
+// Reads every record in a log directory, in order, without building a `Db`.
+// The set of segments is fixed when the reader is opened; if a `Db` is writing
+// to the log at the same time, the reader sees whatever had made it to disk
+// when it got to each segment. The shards of a sharded log are merged back
+// into one sequence as they're read, and whatever a compaction left behind
+// from before it is skipped.
+#[derive(Debug)]
+pub struct LogReader {
+    shards: Vec<ShardReader>,
+    start: u64,
+    buf: Vec<u8>,
+    codec: Arc<dyn RecordCodec>,
+    failed: bool,
+}
+
+// How far a `LogReader` has got through one shard, along with the record it's
+// read ahead, if any.
+#[derive(Debug)]
+struct ShardReader {
+    dir: PathBuf,
+    segments: std::vec::IntoIter<u64>,
+    current: Option<SegmentReader>,
+    sparse: bool,
+    // The record's offset, sequence number and codec.
+    head: Option<(LogOffset, u64, u32)>,
+    buf: Vec<u8>,
+}
+
+impl ShardReader {
+    fn next(&mut self) -> Result<Option<(LogOffset, u64, u32)>> {
+        loop {
+            let reader = match &mut self.current {
+                Some(reader) => reader,
+                None => match self.segments.next() {
+                    Some(number) => {
+                        let mut reader = SegmentReader::open(&self.dir, number)?;
+                        reader.set_sparse(self.sparse);
+                        self.current.insert(reader)
+                    }
+                    None => return Ok(None),
+                },
+            };
+            match reader.next_record(&mut self.buf)? {
+                Some((offset, seq)) => {
+                    let offset = LogOffset {
+                        segment: reader.number(),
+                        offset,
+                    };
+                    return Ok(Some((offset, seq, reader.codec())));
+                }
+                None => self.current = None,
+            }
+        }
+    }
+}
+
+impl LogReader {
+    pub fn open<P>(dir: P) -> Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        let dir = dir.as_ref();
+        let mut shards = vec![];
+        for i in 0.. {
+            let dir = log::shard_dir(dir, i);
+            if i > 0 && !dir.is_dir() {
+                break;
+            }
+            let (segments, _) = segment::list(&dir)?;
+            shards.push(ShardReader {
+                dir,
+                segments: segments.into_iter(),
+                current: None,
+                sparse: false,
+                head: None,
+                buf: vec![],
+            });
+        }
+        let sparse = shards.len() > 1;
+        for shard in &mut shards {
+            shard.sparse = sparse;
+        }
+        Ok(LogReader {
+            shards,
+            start: Log::start(dir, Manifest::load(dir)?.as_ref())?,
+            buf: vec![],
+            codec: Arc::new(codec::Binary),
+            failed: false,
+        })
+    }
+
+    // Reads segments in `codec` as well as the built-in ones, and has
+    // `payloads` yield records in it.
+    pub fn with_codec(mut self, codec: Arc<dyn RecordCodec>) -> Self {
+        self.codec = codec;
+        self
+    }
+
+    // Turns this into an iterator over each record's sequence number and raw
+    // payload, for passing records along without decoding them. Records from
+    // segments in any other codec than the reader's are encoded again in it,
+    // so that they all come out the same.
+    pub fn payloads(self) -> Payloads {
+        Payloads(self)
+    }
+
+    // Reads the next record's payload into `buf`, returning its offset,
+    // sequence number and codec.
+    fn next_raw(&mut self) -> Result<Option<(LogOffset, u64, u32)>> {
+        loop {
+            for shard in &mut self.shards {
+                if shard.head.is_none() {
+                    shard.head = shard.next()?;
+                }
+            }
+            let Some(shard) = self
+                .shards
+                .iter_mut()
+                .filter(|shard| shard.head.is_some())
+                .min_by_key(|shard| shard.head.map(|(_, seq, _)| seq))
+            else {
+                return Ok(None);
+            };
+            let head = shard.head.take();
+            std::mem::swap(&mut self.buf, &mut shard.buf);
+            if let Some(head) = head.filter(|&(_, seq, _)| seq >= self.start) {
+                return Ok(Some(head));
+            }
+        }
+    }
+
+    fn next_record(&mut self) -> Result<Option<(LogOffset, u64, Command)>> {
+        match self.next_raw()? {
+            Some((offset, seq, codec)) => {
+                let decoder = Decoder::new(codec, &*self.codec)?;
+                let command = decoder.decode(offset.segment, offset.offset, &self.buf)?;
+                Ok(Some((offset, seq, command)))
+            }
+            None => Ok(None),
+        }
+    }
+
+    // Stops iteration for good after the first error.
+    fn fuse<T>(&mut self, result: Option<Result<T>>) -> Option<Result<T>> {
+        if self.failed {
+            return None;
+        }
+        if let Some(Err(_)) = result {
+            self.failed = true;
+        }
+        result
+    }
+}
+
+impl Iterator for LogReader {
+    type Item = Result<(LogOffset, u64, Command)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+        let result = self.next_record().transpose();
+        self.fuse(result)
+    }
+}
+
+#[derive(Debug)]
+pub struct Payloads(LogReader);
+
+impl Iterator for Payloads {
+    type Item = Result<(u64, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let r = &mut self.0;
+        if r.failed {
+            return None;
+        }
+        let result = (|| match r.next_raw()? {
+            Some((_, seq, codec)) if codec == r.codec.id() => Ok(Some((seq, r.buf.clone()))),
+            Some((offset, seq, codec)) => {
+                let decoder = Decoder::new(codec, &*r.codec)?;
+                let command = decoder.decode(offset.segment, offset.offset, &r.buf)?;
+                Ok(Some((seq, codec::encode(&*r.codec, &command)?)))
+            }
+            None => Ok(None),
+        })()
+        .transpose();
+        r.fuse(result)
+    }
+}
+
+#[test]
+fn test_log_reader() -> Result<()> {
+    let dir = tempdir()?;
+    let file = dir.path().to_path_buf().join("logfile");
+    let options = crate::Options {
+        segment_size: 256,
+        ..crate::Options::default()
+    };
+
+    let mut db = crate::Db::with_options(&file, options.clone())?;
+    for i in 0..20 {
+        db.set(&format!("key{}", i), "val")?;
+    }
+    db.delete("key3")?;
+    drop(db);
+    let mut db = crate::Db::with_options(&file, options)?;
+    db.set("after", "reopen")?;
+
+    let records = LogReader::open(&file)?.collect::<Result<Vec<_>>>()?;
+    assert_eq!(records.len(), 22);
+    let seqs = records.iter().map(|(_, seq, _)| *seq).collect::<Vec<_>>();
+    assert_eq!(seqs, (1..=22).collect::<Vec<_>>());
+    let offsets = records.iter().map(|(o, _, _)| *o).collect::<Vec<_>>();
+    assert!(offsets.windows(2).all(|w| w[0] < w[1]));
+    assert!(offsets.last().unwrap().segment > offsets[0].segment);
+    assert!(matches!(&records[20].2, Command::Delete(k) if k == "key3"));
+    assert!(matches!(&records[21].2, Command::Set(k, v) if k == "after" && v == "reopen"));
+
+    Ok(())
+}
+
+#[test]
+fn test_log_reader_empty() -> Result<()> {
+    let dir = tempdir()?;
+    let file = dir.path().to_path_buf().join("logfile");
+
+    crate::Db::new(&file)?;
+    assert_eq!(LogReader::open(&file)?.count(), 0);
+
+    Ok(())
+}
+
+#[test]
+fn test_read_log_from() -> Result<()> {
+    let dir = tempdir()?;
+    let file = dir.path().to_path_buf().join("logfile");
+    let options = crate::Options {
+        segment_size: 512,
+        ..crate::Options::default()
+    };
+
+    let mut db = crate::Db::with_options(&file, options.clone())?;
+    let empty = db.read_log_from(LogOffset::START, 1024)?;
+    assert!(empty.records.is_empty());
+    for i in 0..30 {
+        db.set(&format!("key{}", i), "val")?;
+    }
+
+    // Pull the log a few records at a time, decoding what comes back.
+    let mut at = LogOffset::START;
+    let mut seqs = vec![];
+    let mut segments = vec![];
+    loop {
+        let chunk = db.read_log_from(at, 100)?;
+        if chunk.records.is_empty() {
+            assert_eq!(db.read_log_from(chunk.next, 100)?.next, chunk.next);
+            break;
+        }
+        assert!(chunk.next > at);
+        segments.push(chunk.next.segment);
+        let mut bytes = &chunk.records[..];
+        while !bytes.is_empty() {
+            let (record, len) = segment::decode_record(bytes).unwrap();
+            assert_eq!(record.segment, chunk.next.segment);
+            let decoder = Decoder::new(chunk.codec, &codec::Binary)?;
+            let command: Command = decoder.decode(record.segment, 0, record.payload)?;
+            assert!(matches!(command, Command::Set(k, _) if k == format!("key{}", record.seq - 1)));
+            seqs.push(record.seq);
+            bytes = &bytes[len..];
+        }
+        at = chunk.next;
+    }
+    assert_eq!(seqs, (1..=30).collect::<Vec<_>>());
+    assert!(segments.last() > segments.first());
+
+    // Carrying on from the end picks up what's been written since.
+    db.set("more", "val")?;
+    let chunk = db.read_log_from(at, 100)?;
+    let (record, len) = segment::decode_record(&chunk.records).unwrap();
+    assert_eq!((record.seq, len), (31, chunk.records.len()));
+
+    // A record bigger than `max_bytes` still comes back on its own.
+    db.set("big", &"x".repeat(200))?;
+    let chunk = db.read_log_from(chunk.next, 100)?;
+    assert_eq!(segment::decode_record(&chunk.records).unwrap().0.seq, 32);
+
+    // Reopening doesn't lose track of what's been committed.
+    drop(db);
+    let db = crate::Db::with_options(&file, options)?;
+    let chunk = db.read_log_from(LogOffset::START, 1)?;
+    assert_eq!(segment::decode_record(&chunk.records).unwrap().0.seq, 1);
+
+    db.compact()?;
+    let err = db.read_log_from(at, 100).unwrap_err();
+    assert!(matches!(err, Error::InvalidConfig(_)), "{}", err);
+    Ok(())
+}
@@ -0,0 +1,352 @@
+use crate::codec;
+use crate::error::{decode, encode};
+use crate::log::Log;
+use crate::memtable;
+use crate::metrics::Counters;
+use crate::replay::read_segment;
+use crate::{Command, DbListener, Metrics, Options, RecoveryReport, Result, SlowSync};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    path::Path,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+#[cfg(test)]
+use tempfile::tempdir;
+
+// Anything that can be rebuilt by replaying a log of commands. `apply` has to
+// be deterministic, since it runs once when a command is written and again
+// every time the log is replayed.
+pub trait StateMachine {
+    type Command: Serialize + DeserializeOwned;
+    type Snapshot: Serialize + DeserializeOwned;
+
+    fn apply(&mut self, command: Self::Command);
+
+    // Captures everything needed to rebuild the current state, so that
+    // compaction can replace the commands that led up to it.
+    fn snapshot(&self) -> Self::Snapshot;
+
+    // Replaces the current state with `snapshot`.
+    fn restore(&mut self, snapshot: Self::Snapshot);
+}
+
+// Each record in the log is one of these, serialized as JSON.
+#[derive(Serialize, Deserialize, Debug)]
+enum Record<C, S> {
+    Command(C),
+    Snapshot(S),
+}
+
+// Journals commands for a `StateMachine`: each one is synced to the log before
+// it is applied, and opening the log again replays them all. Unlike `Db` there
+// is no group commit, so callers that want to write from several threads have
+// to share it behind a lock, and should batch up commands with `apply_batch`
+// where they can.
+#[derive(Debug)]
+pub struct RedoLog<S> {
+    log: Log,
+    state: S,
+    counters: Counters,
+    recovery: RecoveryReport,
+    listener: Option<Arc<dyn DbListener>>,
+    slow_sync: Option<Duration>,
+}
+
+impl<S: StateMachine> RedoLog<S> {
+    // Opens the log in `dir`, replaying it on top of `state`. `state` should be
+    // what the state machine looks like before any commands have been applied.
+    pub fn open<P>(dir: P, state: S) -> Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        Self::with_options(dir, Options::default(), state)
+    }
+
+    // `options.recovery.parallelism`, `options.write` and `options.codec`
+    // don't apply here: replay is always serial, nothing is replicated, and
+    // records are always JSON, since they can hold anything.
+    pub fn with_options<P>(dir: P, options: Options, mut state: S) -> Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        let dir = dir.as_ref();
+        let recovery_options = options.recovery.clone();
+        let listener = options.listener.clone();
+        let slow_sync = options.slow_sync;
+        let mut recovery = RecoveryReport::default();
+        let start = Instant::now();
+        let options = Options {
+            codec: Arc::new(codec::Json),
+            ..options
+        };
+        let log = Log::open(dir, options, |segments| {
+            let mut buf = vec![];
+            for (i, &number) in segments.iter().enumerate() {
+                read_segment(
+                    dir,
+                    number,
+                    segments.get(i + 1).copied(),
+                    &recovery_options,
+                    &mut buf,
+                    &mut recovery,
+                    |_, offset, _, record| {
+                        match decode(number, offset, record)? {
+                            Record::Command(command) => state.apply(command),
+                            Record::Snapshot(snapshot) => state.restore(snapshot),
+                        }
+                        Ok(())
+                    },
+                )?;
+            }
+            Ok(())
+        })?;
+        let counters = Counters::default();
+        counters.record_replay(start.elapsed());
+        if let Some(listener) = &listener {
+            listener.on_recovery_complete(&recovery);
+        }
+        Ok(RedoLog {
+            log,
+            state,
+            counters,
+            recovery,
+            listener,
+            slow_sync,
+        })
+    }
+
+    pub fn state(&self) -> &S {
+        &self.state
+    }
+
+    pub fn apply(&mut self, command: S::Command) -> Result<()> {
+        self.apply_batch(vec![command])
+    }
+
+    // Writes `commands` out with a single sync, then applies them in order.
+    pub fn apply_batch(&mut self, commands: Vec<S::Command>) -> Result<()> {
+        if commands.is_empty() {
+            return Ok(());
+        }
+        let start = Instant::now();
+        let payloads = commands
+            .iter()
+            .map(|c| encode(&Record::<_, ()>::Command(c)))
+            .collect::<Result<Vec<_>>>()?;
+        let first_seq = self.log.next_seq();
+        let bytes = self.log.append_batch(&payloads)?;
+        let synced = Instant::now();
+        self.log.sync()?;
+        let sync = synced.elapsed();
+        self.counters
+            .record_batch(commands.len(), bytes, start.elapsed(), Some(sync));
+        if let Some(listener) = &self.listener {
+            listener.on_batch_committed(first_seq..self.log.next_seq(), bytes);
+        }
+        if self.slow_sync.is_some_and(|max| sync > max) {
+            warn!(duration = ?sync, batch_size = commands.len(), bytes, "slow sync");
+            if let Some(listener) = &self.listener {
+                listener.on_slow_sync(&SlowSync {
+                    duration: sync,
+                    batch_size: commands.len(),
+                    bytes,
+                });
+            }
+        }
+        for command in commands {
+            self.state.apply(command);
+        }
+        Ok(())
+    }
+
+    // Writes a snapshot of the current state into a fresh segment so that
+    // every segment before it can be recycled.
+    pub fn compact(&mut self) -> Result<()> {
+        let snapshot = Record::<(), _>::Snapshot(self.state.snapshot());
+        self.log.compact(&[encode(&snapshot)?], None)
+    }
+
+    pub fn metrics(&self) -> Metrics {
+        self.counters.snapshot()
+    }
+
+    // What replaying the log turned up when it was opened.
+    pub fn recovery_report(&self) -> &RecoveryReport {
+        &self.recovery
+    }
+
+    pub fn into_state(self) -> S {
+        self.state
+    }
+}
+
+// The same key-value map that `Db` keeps, as a state machine.
+impl StateMachine for HashMap<String, String> {
+    type Command = Command;
+    type Snapshot = HashMap<String, String>;
+
+    fn apply(&mut self, command: Command) {
+        match command {
+            // Nothing expires here.
+            Command::Set(k, v) | Command::SetExpiring(k, v, _) => {
+                self.insert(k, v);
+            }
+            Command::Delete(k) => {
+                self.remove(&k);
+            }
+            Command::Incr(k, _, value) => {
+                self.insert(k, value.to_string());
+            }
+            Command::Append(k, element) => {
+                memtable::append_element(self.entry(k).or_default(), &element);
+            }
+            Command::Transaction(writes) => {
+                for (k, v) in writes {
+                    match v {
+                        Some(v) => self.insert(k, v),
+                        None => self.remove(&k),
+                    };
+                }
+            }
+            Command::DeleteRange(start, end) => {
+                self.retain(|k, _| *k < start || *k >= end);
+            }
+            Command::DeletePrefix(prefix) => {
+                self.retain(|k, _| !k.starts_with(&prefix));
+            }
+            Command::Request(_, command) => self.apply(*command),
+            // Only the default keyspace is kept.
+            Command::LastRequest(_)
+            | Command::Custom(_)
+            | Command::Expire(..)
+            | Command::KeyspaceSet(..)
+            | Command::KeyspaceDelete(..)
+            | Command::DropKeyspace(_) => {}
+        }
+    }
+
+    fn snapshot(&self) -> Self::Snapshot {
+        self.clone()
+    }
+
+    fn restore(&mut self, snapshot: Self::Snapshot) {
+        *self = snapshot;
+    }
+}
+
+#[cfg(test)]
+#[derive(Debug, Default, PartialEq)]
+struct Accounts {
+    balances: HashMap<String, i64>,
+    transfers: u64,
+}
+
+#[cfg(test)]
+#[derive(Serialize, Deserialize, Debug)]
+enum AccountCommand {
+    Deposit {
+        account: String,
+        amount: i64,
+    },
+    Transfer {
+        from: String,
+        to: String,
+        amount: i64,
+    },
+}
+
+#[cfg(test)]
+impl StateMachine for Accounts {
+    type Command = AccountCommand;
+    type Snapshot = (Vec<(String, i64)>, u64);
+
+    fn apply(&mut self, command: AccountCommand) {
+        match command {
+            AccountCommand::Deposit { account, amount } => {
+                *self.balances.entry(account).or_default() += amount;
+            }
+            AccountCommand::Transfer { from, to, amount } => {
+                *self.balances.entry(from).or_default() -= amount;
+                *self.balances.entry(to).or_default() += amount;
+                self.transfers += 1;
+            }
+        }
+    }
+
+    fn snapshot(&self) -> Self::Snapshot {
+        let balances = self.balances.iter().map(|(k, v)| (k.clone(), *v)).collect();
+        (balances, self.transfers)
+    }
+
+    fn restore(&mut self, (balances, transfers): Self::Snapshot) {
+        self.balances = balances.into_iter().collect();
+        self.transfers = transfers;
+    }
+}
+
+#[test]
+fn test_custom_state_machine() -> Result<()> {
+    let dir = tempdir()?;
+    let file = dir.path().join("logfile");
+    let options = Options {
+        segment_size: 256,
+        ..Options::default()
+    };
+
+    let mut log = RedoLog::with_options(&file, options.clone(), Accounts::default())?;
+    log.apply(AccountCommand::Deposit {
+        account: "alice".into(),
+        amount: 100,
+    })?;
+    log.apply_batch(
+        (0..10)
+            .map(|_| AccountCommand::Transfer {
+                from: "alice".into(),
+                to: "bob".into(),
+                amount: 7,
+            })
+            .collect(),
+    )?;
+    assert_eq!(log.state().balances["bob"], 70);
+    assert_eq!(log.metrics().commands, 11);
+    let expected = log.into_state();
+
+    let mut log = RedoLog::with_options(&file, options.clone(), Accounts::default())?;
+    assert_eq!(log.state(), &expected);
+
+    log.compact()?;
+    log.apply(AccountCommand::Deposit {
+        account: "carol".into(),
+        amount: 5,
+    })?;
+    drop(log);
+    let log = RedoLog::with_options(&file, options, Accounts::default())?;
+    assert_eq!(log.state().balances["alice"], 30);
+    assert_eq!(log.state().balances["carol"], 5);
+    assert_eq!(log.state().transfers, 10);
+
+    Ok(())
+}
+
+#[test]
+fn test_hash_map_state_machine() -> Result<()> {
+    let dir = tempdir()?;
+    let file = dir.path().join("logfile");
+
+    let mut log = RedoLog::open(&file, HashMap::new())?;
+    log.apply(Command::Set("foo".into(), "bar".into()))?;
+    log.apply(Command::Set("baz".into(), "goo".into()))?;
+    log.apply(Command::Delete("foo".into()))?;
+    log.compact()?;
+    log.apply(Command::Set("qux".into(), "quux".into()))?;
+
+    drop(log);
+    let log = RedoLog::open(&file, HashMap::new())?;
+    assert_eq!(log.state().len(), 2);
+    assert_eq!(log.state()["baz"], "goo");
+    assert_eq!(log.state()["qux"], "quux");
+
+    Ok(())
+}
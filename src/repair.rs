@@ -0,0 +1,179 @@
+use crate::cache::BlockCache;
+use crate::codec::{self, Decoder};
+use crate::log::Log;
+use crate::replay::read_segment;
+use crate::table::{self, Table};
+use crate::value_log;
+use crate::{
+    durable_fs, segment, Command, Durability, Error, LockPolicy, Options, RecoveryMode,
+    RecoveryOptions, RecoveryReport, Result,
+};
+use std::{
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+#[cfg(test)]
+use tempfile::tempdir;
+
+// Salvages what it can from the damaged log in `dir` into a fresh log that
+// takes its place, keeping the original alongside it as `<dir>.corrupt`.
+//
+// The fresh log is built in `<dir>.repair` and only swapped in once it's
+// complete and synced, so a crash partway through leaves the original where
+// it was. The original's lock is held throughout. Records come out in
+// `codec::Binary`, and segments in codecs that aren't built in can't be read.
+pub fn repair(dir: &Path) -> Result<RecoveryReport> {
+    if !dir.is_dir() {
+        return Err(Error::InvalidConfig(format!(
+            "{} is not a log directory",
+            dir.display()
+        )));
+    }
+    if Log::is_sharded(dir) {
+        return Err(Error::InvalidConfig(format!(
+            "{} is a sharded log, which can't be repaired",
+            dir.display()
+        )));
+    }
+    let corrupt = sibling(dir, "corrupt")?;
+    if corrupt.exists() {
+        return Err(Error::InvalidConfig(format!(
+            "{} already exists",
+            corrupt.display()
+        )));
+    }
+    let _lock = Log::lock(dir, LockPolicy::Fail)?;
+
+    // Whatever is here is left over from a repair that didn't finish.
+    let scratch = sibling(dir, "repair")?;
+    if scratch.exists() {
+        fs::remove_dir_all(&scratch)?;
+    }
+    let mut log = Log::open(&scratch, Options::default(), |_| Ok(()))?;
+    // Sequence numbers start over, but records the tables already have (see
+    // `Flushed`) have to stay before the point where the tables leave off, and
+    // the rest after it.
+    let cache = Arc::new(BlockCache::new(0));
+    let mut flushed = 0;
+    for number in table::list(dir)? {
+        flushed = flushed.max(Table::open(dir, number, &cache, true)?.seq());
+    }
+    let (segments, _) = segment::list(dir)?;
+    let mut report = RecoveryReport::default();
+    let mut buf = vec![];
+    let skip_corrupt = RecoveryOptions {
+        mode: RecoveryMode::SkipCorrupt,
+        ..RecoveryOptions::default()
+    };
+    for (i, &number) in segments.iter().enumerate() {
+        let mut payloads = vec![];
+        read_segment(
+            dir,
+            number,
+            segments.get(i + 1).copied(),
+            &skip_corrupt,
+            &mut buf,
+            &mut report,
+            |id, offset, seq, record| {
+                // Records are written out again in the fresh log's codec,
+                // whatever the segment they came from was in.
+                let command: Command =
+                    Decoder::new(id, &codec::Binary)?.decode(number, offset, record)?;
+                if seq >= flushed && log.next_seq() + (payloads.len() as u64) < flushed {
+                    log.append_batch(&std::mem::take(&mut payloads))?;
+                    log.skip_to(flushed)?;
+                }
+                payloads.push(codec::encode(&codec::Binary, &command)?);
+                Ok(())
+            },
+        )?;
+        log.append_batch(&payloads)?;
+    }
+    log.sync()?;
+    drop(log);
+    // Tables are checked as they're opened, so there's nothing to salvage
+    // from them, but the new log is no good without them, nor without the
+    // value log its records point into.
+    let tables = table::list(dir)?
+        .into_iter()
+        .map(|n| table::table_path(dir, n));
+    for from in tables.chain(value_log::paths(dir)?) {
+        let to = scratch.join(from.file_name().unwrap());
+        fs::copy(&from, &to)?;
+        durable_fs::sync_file(&fs::File::open(&to)?, Durability::Media)?;
+    }
+
+    let mut file = durable_fs::create_new(&scratch.join("REPAIR"))?;
+    write!(file, "repaired from {}\n{}", corrupt.display(), report)?;
+    durable_fs::sync_file(&file, Durability::Media)?;
+
+    durable_fs::rename(dir, &corrupt)?;
+    durable_fs::rename(&scratch, dir)?;
+    Ok(report)
+}
+
+// `<dir>.<extension>`, next to `dir`.
+fn sibling(dir: &Path, extension: &str) -> Result<PathBuf> {
+    let mut name = dir
+        .file_name()
+        .ok_or_else(|| Error::InvalidConfig(format!("{} has no name", dir.display())))?
+        .to_os_string();
+    name.push(".");
+    name.push(extension);
+    Ok(dir.with_file_name(name))
+}
+
+#[test]
+fn test_repair() -> Result<()> {
+    let dir = tempdir()?;
+    let file = dir.path().join("logfile");
+    let options = Options {
+        segment_size: 4096,
+        ..Options::default()
+    };
+    let mut db = crate::Db::with_options(&file, options)?;
+    for i in 0..10 {
+        db.set(&format!("key{}", i), "val")?;
+    }
+    let err = repair(&file).unwrap_err();
+    assert!(matches!(err, Error::Busy(_)), "{}", err);
+    drop(db);
+
+    // Flip a bit in the payload of the third record.
+    let number = segment::list(&file)?.0[0];
+    let path = segment::segment_path(&file, number);
+    let mut bytes = fs::read(&path)?;
+    let mut reader = segment::SegmentReader::open(&file, number)?;
+    reader.next_record(&mut vec![])?;
+    reader.next_record(&mut vec![])?;
+    bytes[(reader.offset() as usize) + segment::HEADER_LEN] ^= 1;
+    fs::write(&path, bytes)?;
+
+    let report = repair(&file)?;
+    assert_eq!(report.records, 9);
+    assert_eq!(report.skipped.len(), 1);
+    assert!(dir.path().join("logfile.corrupt").is_dir());
+    assert!(fs::read_to_string(file.join("REPAIR"))?.contains("skipped"));
+
+    // What's left is a log that replays cleanly.
+    let strict = Options {
+        recovery: crate::RecoveryOptions {
+            mode: RecoveryMode::Strict,
+            ..crate::RecoveryOptions::default()
+        },
+        ..Options::default()
+    };
+    let db = crate::Db::with_options(&file, strict)?;
+    assert_eq!(db.len(), 9);
+    assert_eq!(db.get("key2"), None);
+    assert_eq!(db.get("key9"), Some("val".into()));
+    assert_eq!(db.next_seq(), 10);
+    drop(db);
+
+    // The original isn't overwritten by a second repair.
+    assert!(repair(&file).is_err());
+
+    Ok(())
+}
@@ -0,0 +1,1028 @@
+use crate::codec::Decoder;
+use crate::garbage::Ledger;
+use crate::log::Shards;
+use crate::memtable::Memtable;
+use crate::segment::{self, End, SegmentReader};
+use crate::table::Table;
+use crate::{
+    try_table_get, Command, CommandRef, Db, Error, LogOffset, Options, RecoveryMode,
+    RecoveryOptions, Result,
+};
+use std::{
+    collections::BTreeMap,
+    fmt, fs,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        mpsc, Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+#[cfg(test)]
+use tempfile::tempdir;
+
+// Receives the payload of each `Command::Custom` during replay.
+pub type CustomHandler<'a> = dyn FnMut(serde_json::Value) -> Result<()> + 'a;
+
+// What replaying a log turned up, besides the records themselves.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RecoveryReport {
+    // How many records were replayed.
+    pub records: u64,
+    // Where each segment that ended in a torn record stops being valid.
+    pub torn: Vec<LogOffset>,
+    // Whatever `RecoveryMode::SkipCorrupt` skipped over, in log order.
+    pub skipped: Vec<Skipped>,
+    // Whether the database was last closed cleanly, in which case its tables
+    // were trusted rather than checked over.
+    pub clean_shutdown: bool,
+    // Whether the log was trusted too, and replayed without checking each
+    // record, because it rebuilt the memtable the database was closed with.
+    pub trusted: bool,
+}
+
+// A stretch of a segment that replay skipped over.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Skipped {
+    pub at: LogOffset,
+    // How many bytes were skipped, and how many records were lost going by
+    // the sequence numbers on either side.
+    pub len: u64,
+    pub records: u64,
+    pub reason: String,
+}
+
+// Told how replay is getting on, set with `RecoveryOptions::observer`. It's
+// called from the thread doing the replaying, which waits for it. A `Db` that
+// was closed cleanly may replay its log twice, if what the first pass comes
+// up with doesn't match the memtable it was closed with, and progress starts
+// over for the second.
+pub trait RecoveryObserver: fmt::Debug + Send + Sync {
+    fn on_progress(&self, progress: &RecoveryProgress);
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecoveryProgress {
+    // How far through the segments being replayed it is, in bytes, out of
+    // how many they take up on disk. Space preallocated for records that
+    // were never written counts towards the total, and is only made up for
+    // once the segment it's in is done with, so the estimate is on the long
+    // side until then.
+    pub bytes: u64,
+    pub total_bytes: u64,
+    pub records: u64,
+    pub elapsed: Duration,
+    // How much longer it should take, going by how long it's taken so far.
+    pub eta: Option<Duration>,
+}
+
+// Stops a recovery that's under way, set with `RecoveryOptions::cancel`.
+// Clones share the one flag, so an operator can keep one to cancel with while
+// the database is opened with another.
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Release);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Acquire)
+    }
+}
+
+// Keeps count of what replay has done, on the thread applying records, for
+// `RecoveryOptions::observer` and `cancel`.
+struct Tracker<'a> {
+    options: &'a RecoveryOptions,
+    total: u64,
+    bytes: u64,
+    records: u64,
+    started: Instant,
+    reported: Instant,
+}
+
+// How many records go by between looking at the clock.
+const CLOCK_EVERY: u64 = 256;
+
+impl<'a> Tracker<'a> {
+    // For replaying `segments` of each of `dirs`.
+    fn new(options: &'a RecoveryOptions, dirs: &[PathBuf], segments: &[Vec<u64>]) -> Result<Self> {
+        let mut total = 0;
+        if options.observer.is_some() {
+            for (dir, segments) in dirs.iter().zip(segments) {
+                for &number in segments {
+                    total += fs::metadata(segment::segment_path(dir, number))?.len();
+                }
+            }
+        }
+        let now = Instant::now();
+        Ok(Tracker {
+            options,
+            total,
+            bytes: 0,
+            records: 0,
+            started: now,
+            reported: now,
+        })
+    }
+
+    fn cancelled(&self) -> Result<()> {
+        match &self.options.cancel {
+            Some(cancel) if cancel.is_cancelled() => Err(Error::Cancelled),
+            _ => Ok(()),
+        }
+    }
+
+    // Counts a record that takes up `len` bytes of the log as replayed.
+    fn record(&mut self, len: usize) -> Result<()> {
+        self.bytes += len as u64;
+        self.records += 1;
+        self.cancelled()?;
+        if self.records.is_multiple_of(CLOCK_EVERY) {
+            self.report(false);
+        }
+        Ok(())
+    }
+
+    // Counts the whole of a segment that's `len` bytes long as replayed, of
+    // which `replayed` bytes have been counted already.
+    fn segment(&mut self, len: u64, replayed: u64) -> Result<()> {
+        self.bytes += len.saturating_sub(replayed);
+        self.cancelled()?;
+        self.report(false);
+        Ok(())
+    }
+
+    fn report(&mut self, done: bool) {
+        let Some(observer) = &self.options.observer else {
+            return;
+        };
+        let now = Instant::now();
+        if !done && now - self.reported < self.options.progress_interval {
+            return;
+        }
+        self.reported = now;
+        let elapsed = now - self.started;
+        let bytes = match done {
+            true => self.total,
+            false => self.bytes.min(self.total),
+        };
+        let eta = match bytes {
+            0 => None,
+            bytes => Some(elapsed.mul_f64((self.total - bytes) as f64 / bytes as f64)),
+        };
+        observer.on_progress(&RecoveryProgress {
+            bytes,
+            total_bytes: self.total,
+            records: self.records,
+            elapsed,
+            eta,
+        });
+    }
+}
+
+impl RecoveryReport {
+    fn extend(&mut self, other: RecoveryReport) {
+        self.records += other.records;
+        self.torn.extend(other.torn);
+        self.skipped.extend(other.skipped);
+    }
+}
+
+// One line for the record count, then one for each problem.
+impl fmt::Display for RecoveryReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{} records replayed", self.records)?;
+        for at in &self.torn {
+            writeln!(
+                f,
+                "torn record in segment {} at offset {}",
+                at.segment, at.offset
+            )?;
+        }
+        for skipped in &self.skipped {
+            writeln!(
+                f,
+                "skipped {} bytes ({} records) in segment {} at offset {}: {}",
+                skipped.len, skipped.records, skipped.at.segment, skipped.at.offset, skipped.reason
+            )?;
+        }
+        Ok(())
+    }
+}
+
+// Rebuilds the memtable from `segments`, in order, on top of `tables`,
+// passing custom commands to `custom` along the way, as `options.recovery`
+// says to. `ledger` counts the records as they go by.
+pub fn replay(
+    dir: &Path,
+    segments: &[u64],
+    options: &Options,
+    tables: &[Arc<Table>],
+    (memtable, ledger): (&mut Memtable, &mut Ledger),
+    custom: &mut CustomHandler,
+) -> Result<RecoveryReport> {
+    let _span = span!("replay", segments = segments.len());
+    let mut flushed = Flushed::new(tables);
+    let dirs = [dir.to_path_buf()];
+    let mut tracker = Tracker::new(&options.recovery, &dirs, &[segments.to_vec()])?;
+    let report = if options.recovery.parallelism <= 1 || segments.len() <= 1 {
+        replay_serial(
+            dir,
+            segments,
+            options,
+            tables,
+            &mut flushed,
+            (memtable, &mut tracker, ledger),
+            custom,
+        )
+    } else {
+        replay_parallel(
+            dir,
+            segments,
+            options,
+            tables,
+            &mut flushed,
+            (memtable, &mut tracker, ledger),
+            custom,
+        )
+    }?;
+    flushed.reach(u64::MAX, memtable);
+    tracker.report(true);
+    Ok(report)
+}
+
+// Where the tables leave off in the log. After a crash between a flush writing
+// its table and truncating the log, the log still has records from before
+// that point. They have to be replayed for what tables don't keep, like named
+// keyspaces and expiries, but what they did to the memtable's own keys is
+// thrown away once replay gets past them, the same as the flush did. Applying
+// them twice does no harm to most records, but it would to appends.
+pub(crate) struct Flushed(u64);
+
+impl Flushed {
+    pub fn new(tables: &[Arc<Table>]) -> Self {
+        Flushed(tables.iter().map(|t| t.seq()).max().unwrap_or(0))
+    }
+
+    // Call before applying the record numbered `seq`, and with `u64::MAX`
+    // once there are no more.
+    pub fn reach(&mut self, seq: u64, memtable: &mut Memtable) {
+        if self.0 > 0 && seq >= self.0 {
+            memtable.clear();
+            self.0 = 0;
+        }
+    }
+}
+
+// Passes each record of segment `number` to `f` along with the id of the codec
+// it's in, its offset and its sequence number, dealing with bad records as
+// `options` says. `f` failing with `Error::Corruption` means
+// it couldn't decode the record, which makes it a bad record too. `next` is the
+// segment that follows, if any.
+pub(crate) fn read_segment<F>(
+    dir: &Path,
+    number: u64,
+    next: Option<u64>,
+    options: &RecoveryOptions,
+    buf: &mut Vec<u8>,
+    report: &mut RecoveryReport,
+    f: F,
+) -> Result<()>
+where
+    F: FnMut(u32, u64, u64, &[u8]) -> Result<()>,
+{
+    let reader = SegmentReader::open(dir, number)?;
+    read_from(reader, dir, next, options, buf, report, f)
+}
+
+fn read_from<F>(
+    mut reader: SegmentReader,
+    dir: &Path,
+    next: Option<u64>,
+    options: &RecoveryOptions,
+    buf: &mut Vec<u8>,
+    report: &mut RecoveryReport,
+    mut f: F,
+) -> Result<()>
+where
+    F: FnMut(u32, u64, u64, &[u8]) -> Result<()>,
+{
+    let mode = options.mode;
+    let number = reader.number();
+    reader.set_verify(options.verify);
+    loop {
+        while let Some((offset, seq)) = reader.next_record(buf)? {
+            match f(reader.codec(), offset, seq, buf) {
+                Ok(()) => report.records += 1,
+                Err(Error::Corruption { reason, .. }) if mode == RecoveryMode::SkipCorrupt => {
+                    report.skipped.push(Skipped {
+                        at: LogOffset {
+                            segment: number,
+                            offset,
+                        },
+                        len: reader.offset() - offset,
+                        records: 1,
+                        reason,
+                    });
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        let end = match reader.end() {
+            Some(end) if !end.is_clean() => end,
+            _ => return Ok(()),
+        };
+        let at = LogOffset {
+            segment: number,
+            offset: reader.offset(),
+        };
+        let expected = reader.next_seq();
+        if mode == RecoveryMode::Strict && end != End::BadHeader {
+            return Err(Error::corruption(number, at.offset, end));
+        }
+        if !reader.resync()? {
+            // A segment whose header never made it to disk is just empty, as
+            // long as nothing was written to it either.
+            if end == End::BadHeader {
+                return Ok(());
+            }
+            // A crash can tear the last record written to a segment, and the
+            // log then carries on from that record in a new segment. If the
+            // next segment starts anywhere else, the record was written in
+            // full and has been damaged since, or the header was.
+            let resumed = match next {
+                Some(next) => SegmentReader::open(dir, next)?.first_seq(),
+                None => None,
+            };
+            match resumed {
+                Some(resumed) if resumed != expected => {
+                    if mode == RecoveryMode::TolerateTornTail {
+                        return Err(Error::corruption(
+                            number,
+                            at.offset,
+                            format!("{}, and the next segment starts at seq {}", end, resumed),
+                        ));
+                    }
+                    let len = fs::metadata(segment::segment_path(dir, number))?.len();
+                    report.skipped.push(Skipped {
+                        at,
+                        len: len - at.offset,
+                        records: resumed.saturating_sub(expected),
+                        reason: end.to_string(),
+                    });
+                }
+                _ => report.torn.push(at),
+            }
+            return Ok(());
+        }
+        if mode != RecoveryMode::SkipCorrupt {
+            return Err(Error::corruption(
+                number,
+                at.offset,
+                format!("{}, with valid records after it", end),
+            ));
+        }
+        report.skipped.push(Skipped {
+            at,
+            len: reader.offset() - at.offset,
+            // Only the header was lost, and the records carry their own
+            // sequence numbers.
+            records: if end == End::BadHeader {
+                0
+            } else {
+                reader.next_seq() - expected
+            },
+            reason: end.to_string(),
+        });
+    }
+}
+
+// Rebuilds the memtable from every shard of a log, and returns where the
+// sequence ends if the log is sharded (see `Log::open_shards`). A log that
+// isn't is replayed as `replay` would.
+pub fn replay_shards(
+    shards: &Shards,
+    options: &Options,
+    tables: &[Arc<Table>],
+    (memtable, ledger): (&mut Memtable, &mut Ledger),
+    custom: &mut CustomHandler,
+) -> Result<(RecoveryReport, Option<u64>)> {
+    if shards.dirs.len() == 1 {
+        let report = replay(
+            &shards.dirs[0],
+            &shards.segments[0],
+            options,
+            tables,
+            (memtable, ledger),
+            custom,
+        )?;
+        return Ok((report, None));
+    }
+    let _span = span!("replay", shards = shards.dirs.len());
+    let mut flushed = Flushed::new(tables);
+    let mut tracker = Tracker::new(&options.recovery, &shards.dirs, &shards.segments)?;
+    let (report, next_seq) = replay_merged(
+        shards,
+        options,
+        tables,
+        &mut flushed,
+        (memtable, &mut tracker, ledger),
+        custom,
+    )?;
+    flushed.reach(u64::MAX, memtable);
+    tracker.report(true);
+    Ok((report, Some(next_seq)))
+}
+
+// A record read from one shard of a sharded log.
+struct Merged {
+    seq: u64,
+    at: LogOffset,
+    // Whether it's the first record of a segment that says it starts there,
+    // because the sequence was moved forward to it on purpose (see
+    // `Log::skip_to`) rather than the records before it going missing.
+    resumes: bool,
+    // How many bytes of the log it takes up.
+    len: usize,
+    command: Command,
+}
+
+// Each shard is read on a thread of its own, while this thread takes the
+// records from all of them in order. A batch can make it to disk without the
+// one before it, in another shard, if the process dies while both are being
+// synced. Nobody was told the later one was committed, so replay stops at the
+// gap, and the log drops everything after it. Only `SkipCorrupt` carries on
+// past it, as it would past a damaged record.
+fn replay_merged(
+    shards: &Shards,
+    options: &Options,
+    tables: &[Arc<Table>],
+    flushed: &mut Flushed,
+    (memtable, tracker, ledger): Target,
+    custom: &mut CustomHandler,
+) -> Result<(RecoveryReport, u64)> {
+    // Where the log starts, if nothing has been compacted away: wherever the
+    // oldest segment of any shard says it does.
+    let mut expected = match shards.start {
+        0 => {
+            let mut firsts = vec![];
+            for (dir, segments) in shards.dirs.iter().zip(&shards.segments) {
+                if let Some(&number) = segments.first() {
+                    firsts.extend(SegmentReader::open(dir, number)?.first_seq());
+                }
+            }
+            firsts.into_iter().min()
+        }
+        start => Some(start),
+    };
+    thread::scope(|s| {
+        let mut receivers = vec![];
+        let mut readers = vec![];
+        for (dir, segments) in shards.dirs.iter().zip(&shards.segments) {
+            let (tx, rx) = mpsc::sync_channel::<Result<Merged>>(1024);
+            receivers.push(rx);
+            readers.push(s.spawn(move || {
+                let mut buf = vec![];
+                let mut report = RecoveryReport::default();
+                let mut read = || -> Result<()> {
+                    for &number in segments {
+                        let mut reader = SegmentReader::open(dir, number)?;
+                        reader.set_sparse(true);
+                        let starts = reader.first_seq();
+                        let mut first = true;
+                        read_from(
+                            reader,
+                            dir,
+                            None,
+                            &options.recovery,
+                            &mut buf,
+                            &mut report,
+                            |codec, offset, seq, record| {
+                                let merged = Merged {
+                                    seq,
+                                    at: LogOffset {
+                                        segment: number,
+                                        offset,
+                                    },
+                                    resumes: first && starts == Some(seq),
+                                    len: segment::HEADER_LEN + record.len(),
+                                    command: Decoder::new(codec, &*options.codec)?
+                                        .decode(number, offset, record)?,
+                                };
+                                first = false;
+                                // The merge has stopped, and so can we.
+                                tx.send(Ok(merged)).map_err(|_| Error::Closed)
+                            },
+                        )?;
+                    }
+                    Ok(())
+                };
+                if let Err(e) = read() {
+                    let _ = tx.send(Err(e));
+                }
+                report
+            }));
+        }
+        let next = |rx: &mpsc::Receiver<Result<Merged>>| match rx.recv() {
+            Ok(record) => record.map(Some),
+            Err(_) => Ok(None),
+        };
+        let mut heads = receivers.iter().map(next).collect::<Result<Vec<_>>>()?;
+        let mut report = RecoveryReport::default();
+        let mut records = 0;
+        while let Some(i) = (0..heads.len())
+            .filter(|&i| heads[i].is_some())
+            .min_by_key(|&i| heads[i].as_ref().map(|r| r.seq))
+        {
+            let record = heads[i].take().unwrap();
+            heads[i] = next(&receivers[i])?;
+            if record.seq < shards.start {
+                continue;
+            }
+            match expected {
+                Some(expected) if record.seq < expected => {
+                    return Err(Error::corruption(
+                        record.at.segment,
+                        record.at.offset,
+                        format!("seq {} turns up again", record.seq),
+                    ));
+                }
+                Some(expected) if record.seq > expected && !record.resumes => {
+                    if options.recovery.mode != RecoveryMode::SkipCorrupt {
+                        report.torn.push(record.at);
+                        break;
+                    }
+                    report.skipped.push(Skipped {
+                        at: record.at,
+                        len: 0,
+                        records: record.seq - expected,
+                        reason: format!("seq {} is missing from every shard", expected),
+                    });
+                }
+                _ => {}
+            }
+            expected = Some(record.seq + 1);
+            ledger.record(record.at.segment, record.len, record.command.effect());
+            match record.command {
+                Command::Custom(op) => custom(op)?,
+                command => {
+                    flushed.reach(record.seq, memtable);
+                    Db::apply_command_to_memtable(memtable, &|k| try_table_get(tables, k), command)?
+                }
+            }
+            tracker.record(record.len)?;
+            records += 1;
+        }
+        // Whatever readers are still going stop once they find nobody's
+        // listening.
+        drop(receivers);
+        for reader in readers {
+            let shard = reader.join().unwrap();
+            report.torn.extend(shard.torn);
+            report.skipped.extend(shard.skipped);
+        }
+        report.records = records;
+        Ok((report, expected.unwrap_or(shards.start.max(1))))
+    })
+}
+
+// The memtable being rebuilt, and what's keeping count of it.
+type Target<'a, 'b> = (&'a mut Memtable, &'a mut Tracker<'b>, &'a mut Ledger);
+
+fn replay_serial(
+    dir: &Path,
+    segments: &[u64],
+    options: &Options,
+    tables: &[Arc<Table>],
+    flushed: &mut Flushed,
+    (memtable, tracker, ledger): Target,
+    custom: &mut CustomHandler,
+) -> Result<RecoveryReport> {
+    let mut buf = vec![];
+    let mut report = RecoveryReport::default();
+    for (i, &number) in segments.iter().enumerate() {
+        let _span = span!("segment", number);
+        let mut replayed = 0;
+        read_segment(
+            dir,
+            number,
+            segments.get(i + 1).copied(),
+            &options.recovery,
+            &mut buf,
+            &mut report,
+            |codec, offset, seq, record| {
+                let command: CommandRef =
+                    Decoder::new(codec, &*options.codec)?.decode(number, offset, record)?;
+                ledger.record(number, segment::HEADER_LEN + record.len(), command.effect());
+                match command {
+                    CommandRef::Custom(op) => custom(op)?,
+                    command => {
+                        flushed.reach(seq, memtable);
+                        Db::replay_command(memtable, tables, command)?
+                    }
+                }
+                replayed += (segment::HEADER_LEN + record.len()) as u64;
+                tracker.record(segment::HEADER_LEN + record.len())
+            },
+        )?;
+        tracker.segment(segment_len(dir, number, tracker)?, replayed)?;
+    }
+    Ok(report)
+}
+
+// How long segment `number` is, if anyone's asking.
+fn segment_len(dir: &Path, number: u64, tracker: &Tracker) -> Result<u64> {
+    match tracker.options.observer {
+        Some(_) => Ok(fs::metadata(segment::segment_path(dir, number))?.len()),
+        None => Ok(0),
+    }
+}
+
+// Reading and deserializing is spread across `parallelism` threads, one segment
+// at a time, while this thread applies the results. Segments can finish out of
+// order, so finished ones are held until everything before them has been
+// applied.
+fn replay_parallel(
+    dir: &Path,
+    segments: &[u64],
+    options: &Options,
+    tables: &[Arc<Table>],
+    flushed: &mut Flushed,
+    (memtable, tracker, ledger): Target,
+    custom: &mut CustomHandler,
+) -> Result<RecoveryReport> {
+    let next = AtomicUsize::new(0);
+    type Read = (Vec<(u64, usize, Command)>, RecoveryReport);
+    let (tx, rx) = mpsc::sync_channel::<(usize, Result<Read>)>(options.recovery.parallelism);
+    thread::scope(|s| {
+        for _ in 0..options.recovery.parallelism {
+            let tx = tx.clone();
+            let next = &next;
+            s.spawn(move || {
+                let mut buf = vec![];
+                loop {
+                    let i = next.fetch_add(1, Ordering::Relaxed);
+                    if i >= segments.len() {
+                        break;
+                    }
+                    let mut commands = vec![];
+                    let mut report = RecoveryReport::default();
+                    let number = segments[i];
+                    let result = read_segment(
+                        dir,
+                        number,
+                        segments.get(i + 1).copied(),
+                        &options.recovery,
+                        &mut buf,
+                        &mut report,
+                        |codec, offset, seq, record| {
+                            let decoder = Decoder::new(codec, &*options.codec)?;
+                            let command = decoder.decode(number, offset, record)?;
+                            commands.push((seq, segment::HEADER_LEN + record.len(), command));
+                            Ok(())
+                        },
+                    )
+                    .map(|_| (commands, report));
+                    // If the applier has gone away it hit an error, and there's
+                    // no point reading any further.
+                    if tx.send((i, result)).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+        drop(tx);
+
+        let mut finished = BTreeMap::new();
+        let mut want = 0;
+        let mut report = RecoveryReport::default();
+        for (i, result) in rx {
+            finished.insert(i, result);
+            while let Some(result) = finished.remove(&want) {
+                let _span = span!("segment", number = segments[want]);
+                let (commands, segment_report) = result?;
+                let mut replayed = 0;
+                for (seq, len, command) in commands {
+                    ledger.record(segments[want], len, command.effect());
+                    match command {
+                        Command::Custom(op) => custom(op)?,
+                        command => {
+                            flushed.reach(seq, memtable);
+                            Db::apply_command_to_memtable(
+                                memtable,
+                                &|k| try_table_get(tables, k),
+                                command,
+                            )?
+                        }
+                    }
+                    replayed += len as u64;
+                    tracker.record(len)?;
+                }
+                tracker.segment(segment_len(dir, segments[want], tracker)?, replayed)?;
+                report.extend(segment_report);
+                want += 1;
+            }
+        }
+        Ok(report)
+    })
+}
+
+#[test]
+fn test_parallel_matches_serial() -> Result<()> {
+    let dir = tempdir()?;
+    let file = dir.path().to_path_buf().join("logfile");
+    let options = crate::Options {
+        segment_size: 512,
+        ..crate::Options::default()
+    };
+
+    let mut db = Db::with_options(&file, options)?;
+    for i in 0..500 {
+        let key = format!("key{}", i % 37);
+        if i % 7 == 0 {
+            db.delete(&key)?;
+        } else {
+            db.set(&key, &format!("val{}", i))?;
+        }
+    }
+    let expected = db.memtable.read().home().clone();
+    drop(db);
+
+    let (segments, _) = segment::list(&file)?;
+    assert!(segments.len() > 10);
+    for parallelism in [1, 2, 3, 8] {
+        let mut memtable = Memtable::default();
+        replay(
+            &file,
+            &segments,
+            &Options {
+                recovery: RecoveryOptions {
+                    parallelism,
+                    ..RecoveryOptions::default()
+                },
+                ..Options::default()
+            },
+            &[],
+            (&mut memtable, &mut Ledger::default()),
+            &mut |_| Ok(()),
+        )?;
+        assert_eq!(memtable, expected);
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_parallel_error() -> Result<()> {
+    let dir = tempdir()?;
+    let file = dir.path().to_path_buf().join("logfile");
+    crate::durable_fs::create_dir_all(&file)?;
+
+    for number in 1..=4 {
+        let mut w = segment::SegmentWriter::create(
+            &file,
+            number,
+            number,
+            1,
+            0,
+            None,
+            crate::Durability::None,
+        )?;
+        w.append(if number == 3 {
+            b"not json"
+        } else {
+            b"{\"Delete\":\"k\"}"
+        })?;
+    }
+    let (segments, _) = segment::list(&file)?;
+    let mut memtable = Memtable::default();
+    let mut options = Options {
+        recovery: RecoveryOptions {
+            parallelism: 2,
+            ..RecoveryOptions::default()
+        },
+        ..Options::default()
+    };
+    let err = replay(
+        &file,
+        &segments,
+        &options,
+        &[],
+        (&mut memtable, &mut Ledger::default()),
+        &mut |_| Ok(()),
+    )
+    .unwrap_err();
+    assert!(
+        matches!(err, Error::Corruption { segment: 3, .. }),
+        "{}",
+        err
+    );
+
+    // Skipping it loses just that record.
+    options.recovery.mode = RecoveryMode::SkipCorrupt;
+    let report = replay(
+        &file,
+        &segments,
+        &options,
+        &[],
+        (&mut memtable, &mut Ledger::default()),
+        &mut |_| Ok(()),
+    )?;
+    assert_eq!(report.records, 3);
+    assert_eq!(report.skipped.len(), 1);
+    assert_eq!(report.skipped[0].at.segment, 3);
+    assert_eq!(report.skipped[0].records, 1);
+
+    Ok(())
+}
+
+#[test]
+fn test_recovery_modes() -> Result<()> {
+    let dir = tempdir()?;
+    let file = dir.path().to_path_buf().join("logfile");
+    let options = crate::Options {
+        segment_size: 4096,
+        ..crate::Options::default()
+    };
+    let mut db = Db::with_options(&file, options.clone())?;
+    for i in 0..10 {
+        db.set(&format!("key{}", i), "val")?;
+    }
+    drop(db);
+
+    let number = segment::list(&file)?.0[0];
+    let mut offsets = vec![];
+    let mut reader = SegmentReader::open(&file, number)?;
+    while let Some((offset, _)) = reader.next_record(&mut vec![])? {
+        offsets.push(offset);
+    }
+    let corrupt = |offset: u64| -> Result<()> {
+        let path = segment::segment_path(&file, number);
+        let mut bytes = std::fs::read(&path)?;
+        bytes[offset as usize + segment::HEADER_LEN] ^= 1;
+        std::fs::write(&path, bytes)?;
+        Ok(())
+    };
+    let open = |mode| {
+        let recovery = RecoveryOptions {
+            mode,
+            ..RecoveryOptions::default()
+        };
+        Db::with_options(
+            &file,
+            crate::Options {
+                recovery,
+                ..options.clone()
+            },
+        )
+    };
+    let torn = LogOffset {
+        segment: number,
+        offset: offsets[9],
+    };
+
+    // A bad last record looks like a write that was cut short.
+    corrupt(offsets[9])?;
+    let err = open(RecoveryMode::Strict).unwrap_err();
+    assert!(
+        matches!(err, Error::Corruption { offset, .. } if offset == offsets[9]),
+        "{}",
+        err
+    );
+    let db = open(RecoveryMode::TolerateTornTail)?;
+    assert_eq!(db.len(), 9);
+    assert_eq!(db.recovery_report().records, 9);
+    assert_eq!(db.recovery_report().torn, vec![torn]);
+    drop(db);
+
+    // `redo-log verify` sees it the same way, after the log has moved on to
+    // another segment too.
+    let segments = segment::list(&file)?.0;
+    assert!(segments.len() > 1);
+    let report = segment::check(&file, number, Some(segments[1]))?;
+    assert_eq!(report.records, 9);
+    assert_eq!(report.torn, vec![torn]);
+    assert!(report.skipped.is_empty());
+
+    // One with valid records after it doesn't.
+    corrupt(offsets[2])?;
+    let err = open(RecoveryMode::TolerateTornTail).unwrap_err();
+    assert!(
+        matches!(err, Error::Corruption { offset, .. } if offset == offsets[2]),
+        "{}",
+        err
+    );
+    let report = segment::check(&file, number, Some(segments[1]))?;
+    assert_eq!(report.records, 8);
+    assert_eq!(report.skipped.len(), 1);
+    let db = open(RecoveryMode::SkipCorrupt)?;
+    assert_eq!(db.len(), 8);
+    assert_eq!(db.get("key2"), None);
+    assert_eq!(db.get("key3"), Some("val".into()));
+    let report = db.recovery_report();
+    assert_eq!(report.torn, vec![torn]);
+    assert_eq!(
+        report.skipped,
+        vec![Skipped {
+            at: LogOffset {
+                segment: number,
+                offset: offsets[2],
+            },
+            len: offsets[3] - offsets[2],
+            records: 1,
+            reason: "checksum mismatch".into(),
+        }]
+    );
+
+    Ok(())
+}
+
+// Keeps every report of progress, and cancels the recovery once it's had
+// `cancel_after` of them, if asked to.
+#[cfg(test)]
+#[derive(Debug, Default)]
+struct Watcher {
+    progress: std::sync::Mutex<Vec<RecoveryProgress>>,
+    cancel: Option<(CancelToken, usize)>,
+}
+
+#[cfg(test)]
+impl RecoveryObserver for Watcher {
+    fn on_progress(&self, progress: &RecoveryProgress) {
+        let mut all = self.progress.lock().unwrap();
+        all.push(progress.clone());
+        if let Some((token, after)) = &self.cancel {
+            if all.len() >= *after {
+                token.cancel();
+            }
+        }
+    }
+}
+
+#[test]
+fn test_recovery_progress() -> Result<()> {
+    for (parallelism, log_shards) in [(1, 1), (4, 1), (1, 3)] {
+        let dir = tempdir()?;
+        let file = dir.path().join("logfile");
+        let options = |recovery| Options {
+            segment_size: 4096,
+            log_shards,
+            recovery,
+            ..Options::default()
+        };
+        let mut db = Db::with_options(&file, options(RecoveryOptions::default()))?;
+        for i in 0..1000 {
+            db.set(&format!("key{}", i), "val")?;
+        }
+        drop(db);
+
+        let watcher = Arc::new(Watcher::default());
+        let db = Db::with_options(
+            &file,
+            options(RecoveryOptions {
+                parallelism,
+                observer: Some(watcher.clone()),
+                progress_interval: Duration::ZERO,
+                ..RecoveryOptions::default()
+            }),
+        )?;
+        assert_eq!(db.len(), 1000);
+        drop(db);
+        let progress = watcher.progress.lock().unwrap();
+        assert!(progress.len() > 2, "{:?}", progress);
+        assert!(progress.windows(2).all(|w| w[0].bytes <= w[1].bytes));
+        let last = progress.last().unwrap();
+        assert_eq!(last.bytes, last.total_bytes);
+        assert_eq!((last.records, last.eta), (1000, Some(Duration::ZERO)));
+
+        // Cancelling stops it, and leaves the log to be opened again.
+        let cancel = CancelToken::new();
+        let watcher = Arc::new(Watcher {
+            cancel: Some((cancel.clone(), 1)),
+            ..Watcher::default()
+        });
+        let cancelled = Db::with_options(
+            &file,
+            options(RecoveryOptions {
+                parallelism,
+                observer: Some(watcher.clone()),
+                progress_interval: Duration::ZERO,
+                cancel: Some(cancel),
+                ..RecoveryOptions::default()
+            }),
+        );
+        assert!(matches!(cancelled, Err(Error::Cancelled)));
+        assert!(watcher.progress.lock().unwrap().last().unwrap().records < 1000);
+        let db = Db::with_options(&file, options(RecoveryOptions::default()))?;
+        assert_eq!(db.len(), 1000);
+    }
+    Ok(())
+}
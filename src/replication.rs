@@ -0,0 +1,637 @@
+#[cfg(test)]
+use crate::Options;
+use crate::{error, Error, Result};
+use crate::{Db, LogReader};
+use std::{
+    collections::{HashMap, VecDeque},
+    io::{BufReader, BufWriter, Read, Write},
+    net::{Shutdown, TcpListener, TcpStream},
+    sync::{
+        mpsc::{self, Receiver, Sender},
+        Arc, Condvar, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+#[cfg(test)]
+use tempfile::tempdir;
+
+// Log shipping from a primary to any number of followers.
+//
+// A follower connects and sends the sequence number it wants to start from,
+// which is where its own log leaves off. A follower whose log has never had a
+// record sends 1, and is happy to start from wherever the primary's log
+// begins. The primary first sends what it has on disk from that point on, and
+// then every batch once it's committed and synced (see `Feed`). Followers
+// write what they receive into their own log, under the same sequence
+// numbers, and apply it to their own memtable, so they can serve reads, but
+// take no writes of their own (see `Db::open_follower`). After each batch has
+// been synced, a follower sends back the sequence number of its last record,
+// which is what writes using `Replication::Quorum` wait for.
+//
+// Everything on the wire is little-endian. The primary sends a stream of
+// frames, each starting with a tag byte:
+//
+//   FRAME_BATCH  count: u32, then count × (seq: u64, len: u32, crc: u32, payload)
+//   FRAME_ERROR  len: u32, message
+//
+// where each record's crc covers its seq and payload. The follower sends
+// nothing but u64s: first the seq it wants to start from, then its acks.
+const FRAME_BATCH: u8 = 0;
+const FRAME_ERROR: u8 = 1;
+
+// Records that have been committed, in a form that can be sent to a follower.
+#[derive(Debug)]
+pub(crate) struct Batch {
+    pub first_seq: u64,
+    pub payloads: Vec<Vec<u8>>,
+}
+
+// Lets followers hear about batches once they've been committed and synced.
+// A batch written without a sync waits here until the log is synced past it,
+// by a later batch or `Db::sync`, so that a follower never has a record that
+// the primary could lose in a crash, and would then give a different one the
+// same sequence number.
+#[derive(Debug)]
+pub(crate) struct Feed {
+    // The sequence number of the last record that has been synced and sent on
+    // to followers, or 0.
+    durable_seq: u64,
+    // How far the log has been synced, which the batches waiting on a sync
+    // go past.
+    synced: u64,
+    unsynced: VecDeque<Batch>,
+    subscribers: Vec<Sender<Arc<Batch>>>,
+}
+
+impl Feed {
+    // Whatever was replayed when the log was opened counts as durable.
+    pub fn new(durable_seq: u64) -> Self {
+        Feed {
+            durable_seq,
+            synced: durable_seq,
+            unsynced: VecDeque::new(),
+            subscribers: vec![],
+        }
+    }
+
+    // Batches have to be published in the order they were logged.
+    pub fn publish(&mut self, batch: Batch) {
+        if batch.payloads.is_empty() {
+            return;
+        }
+        self.unsynced.push_back(batch);
+        self.release();
+    }
+
+    // The log has been synced through `seq`.
+    pub fn synced(&mut self, seq: u64) {
+        self.synced = self.synced.max(seq);
+        self.release();
+    }
+
+    fn release(&mut self) {
+        while let Some(batch) = self.unsynced.front() {
+            let last = batch.first_seq + batch.payloads.len() as u64 - 1;
+            if last > self.synced {
+                return;
+            }
+            let batch = Arc::new(self.unsynced.pop_front().expect("there's a batch"));
+            self.durable_seq = last;
+            self.subscribers.retain(|s| s.send(batch.clone()).is_ok());
+        }
+    }
+
+    pub fn durable_seq(&self) -> u64 {
+        self.durable_seq
+    }
+
+    // Returns a receiver for every batch committed from here on, along with
+    // the sequence number of the last record committed before it.
+    fn subscribe(&mut self) -> (Receiver<Arc<Batch>>, u64) {
+        let (tx, rx) = mpsc::channel();
+        self.subscribers.push(tx);
+        (rx, self.durable_seq)
+    }
+}
+
+// The last seq each connected follower has acknowledged.
+#[derive(Debug, Default)]
+pub(crate) struct Acks {
+    followers: Mutex<AckState>,
+    changed: Condvar,
+}
+
+#[derive(Debug, Default)]
+struct AckState {
+    next_id: u64,
+    acked: HashMap<u64, u64>,
+}
+
+impl Acks {
+    fn register(&self) -> u64 {
+        let mut state = error::lock(&self.followers);
+        let id = state.next_id;
+        state.next_id += 1;
+        state.acked.insert(id, 0);
+        id
+    }
+
+    fn ack(&self, id: u64, seq: u64) {
+        let mut state = error::lock(&self.followers);
+        if let Some(acked) = state.acked.get_mut(&id) {
+            *acked = seq.max(*acked);
+        }
+        self.changed.notify_all();
+    }
+
+    fn remove(&self, id: u64) {
+        error::lock(&self.followers).acked.remove(&id);
+    }
+
+    // Waits until at least `n` followers have acknowledged `seq`.
+    pub fn wait_for(&self, seq: u64, n: usize, timeout: Duration) -> Result<()> {
+        let deadline = Instant::now() + timeout;
+        let mut state = self.followers.lock()?;
+        loop {
+            let count = state.acked.values().filter(|&&acked| acked >= seq).count();
+            if count >= n {
+                return Ok(());
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(Error::QuorumTimeout {
+                    seq,
+                    acked: count,
+                    needed: n,
+                });
+            }
+            state = self.changed.wait_timeout(state, deadline - now)?.0;
+        }
+    }
+}
+
+// Accepts followers on `listener` until it fails, serving each one on its own
+// thread.
+pub fn serve(db: Db, listener: TcpListener) -> Result<()> {
+    for stream in listener.incoming() {
+        let stream = stream?;
+        let db = db.clone();
+        thread::spawn(move || {
+            let mut w = BufWriter::new(stream.try_clone()?);
+            if let Err(e) = serve_follower(&db, &stream, &mut w) {
+                // Let the follower know why, if it's still listening.
+                let _ = write_error(&mut w, &e.to_string());
+            }
+            // The follower hangs up once it sees this, which in turn stops the
+            // thread reading its acks.
+            let _ = stream.shutdown(Shutdown::Write);
+            Ok::<_, Error>(())
+        });
+    }
+    Ok(())
+}
+
+fn serve_follower<W: Write>(db: &Db, stream: &TcpStream, w: &mut W) -> Result<()> {
+    let mut r = BufReader::new(stream.try_clone()?);
+    let from = read_u64(&mut r)?;
+    let acks = db.acks.clone();
+    let id = acks.register();
+    thread::spawn(move || {
+        while let Ok(seq) = read_u64(&mut r) {
+            acks.ack(id, seq);
+        }
+        acks.remove(id);
+    });
+
+    // Subscribe before reading the log, so that anything committed while we
+    // read it is waiting for us afterwards.
+    let (rx, durable_seq) = db.feed.lock()?.subscribe();
+    if from > durable_seq + 1 {
+        return Err(Error::Replication(format!(
+            "follower wants seq {} but the log only goes up to {}",
+            from, durable_seq
+        )));
+    }
+    // Whatever has been flushed to tables is gone from the log, so a follower
+    // starting from nothing can't be caught up from the log alone.
+    let fresh = from <= 1;
+    if fresh && !db.tables.lock()?.is_empty() {
+        return Err(Error::Replication(
+            "the primary has flushed to tables, so followers have to start from a copy of it"
+                .into(),
+        ));
+    }
+    let mut next = if fresh { 0 } else { from };
+    let mut batch = Vec::new();
+    let reader = LogReader::open(&*db.dir)?.with_codec(db.codec.clone());
+    for record in reader.payloads() {
+        let (seq, payload) = record?;
+        if seq > durable_seq {
+            break;
+        }
+        if next == 0 {
+            next = seq;
+        }
+        if seq < next {
+            continue;
+        }
+        if seq > next {
+            return Err(Error::Compacted { seq: next });
+        }
+        batch.push((seq, payload));
+        next += 1;
+        if batch.len() == 1024 {
+            write_batch(w, &batch)?;
+            batch.clear();
+        }
+    }
+    write_batch(w, &batch)?;
+    w.flush()?;
+
+    for committed in rx {
+        let last = committed.first_seq + committed.payloads.len() as u64 - 1;
+        if next == 0 {
+            next = committed.first_seq;
+        }
+        if last < next {
+            continue;
+        }
+        if committed.first_seq > next {
+            return Err(Error::Compacted { seq: next });
+        }
+        let skip = (next - committed.first_seq) as usize;
+        let records = committed.payloads[skip..]
+            .iter()
+            .enumerate()
+            .map(|(i, p)| (next + i as u64, p.clone()))
+            .collect::<Vec<_>>();
+        write_batch(w, &records)?;
+        w.flush()?;
+        next = last + 1;
+    }
+    Ok(())
+}
+
+// Applies everything the primary on the other end of `stream` sends to `db`,
+// until the connection closes or something goes wrong. Run it on its own
+// thread; `db` can serve reads in the meantime, and shutting down a clone of
+// `stream` stops it.
+pub fn follow(db: Db, stream: TcpStream) -> Result<()> {
+    if !db.following {
+        return Err(Error::InvalidConfig(
+            "followers have to be opened with Db::open_follower".into(),
+        ));
+    }
+    let from = db.next_seq();
+    let mut w = stream.try_clone()?;
+    w.write_all(&from.to_le_bytes())?;
+    let mut r = BufReader::new(stream);
+    loop {
+        let mut tag = [0];
+        match r.read_exact(&mut tag) {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(e) => return Err(e.into()),
+        }
+        match tag[0] {
+            FRAME_BATCH => {
+                let batch = read_batch(&mut r)?;
+                if let Some(&(last, _)) = batch.last() {
+                    db.apply_replicated(batch)?;
+                    w.write_all(&last.to_le_bytes())?;
+                }
+            }
+            FRAME_ERROR => {
+                let len = read_u32(&mut r)? as usize;
+                let mut msg = vec![0; len];
+                r.read_exact(&mut msg)?;
+                return Err(Error::Replication(format!(
+                    "primary: {}",
+                    String::from_utf8_lossy(&msg)
+                )));
+            }
+            t => return Err(Error::Replication(format!("unknown frame {}", t))),
+        }
+    }
+}
+
+fn record_crc(seq: u64, payload: &[u8]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(&seq.to_le_bytes());
+    hasher.update(payload);
+    hasher.finalize()
+}
+
+fn write_batch<W: Write>(w: &mut W, records: &[(u64, Vec<u8>)]) -> Result<()> {
+    if records.is_empty() {
+        return Ok(());
+    }
+    w.write_all(&[FRAME_BATCH])?;
+    w.write_all(&(records.len() as u32).to_le_bytes())?;
+    for (seq, payload) in records {
+        w.write_all(&seq.to_le_bytes())?;
+        w.write_all(&(payload.len() as u32).to_le_bytes())?;
+        w.write_all(&record_crc(*seq, payload).to_le_bytes())?;
+        w.write_all(payload)?;
+    }
+    Ok(())
+}
+
+fn write_error<W: Write>(w: &mut W, msg: &str) -> Result<()> {
+    w.write_all(&[FRAME_ERROR])?;
+    w.write_all(&(msg.len() as u32).to_le_bytes())?;
+    w.write_all(msg.as_bytes())?;
+    w.flush()?;
+    Ok(())
+}
+
+fn read_u32<R: Read>(r: &mut R) -> Result<u32> {
+    let mut buf = [0; 4];
+    r.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+fn read_u64<R: Read>(r: &mut R) -> Result<u64> {
+    let mut buf = [0; 8];
+    r.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+fn read_batch<R: Read>(r: &mut R) -> Result<Vec<(u64, Vec<u8>)>> {
+    let count = read_u32(r)?;
+    let mut records = Vec::with_capacity(count.min(1024) as usize);
+    for _ in 0..count {
+        let seq = read_u64(r)?;
+        let len = read_u32(r)? as usize;
+        let crc = read_u32(r)?;
+        let mut payload = vec![0; len];
+        r.read_exact(&mut payload)?;
+        if record_crc(seq, &payload) != crc {
+            return Err(Error::Replication(format!(
+                "checksum mismatch on seq {}",
+                seq
+            )));
+        }
+        records.push((seq, payload));
+    }
+    Ok(records)
+}
+
+#[cfg(test)]
+fn wait_for_value(db: &Db, k: &str, v: Option<&str>) {
+    let start = Instant::now();
+    while db.get(k).as_deref() != v {
+        assert!(
+            start.elapsed() < Duration::from_secs(10),
+            "timed out waiting for {} = {:?}",
+            k,
+            v
+        );
+        thread::sleep(Duration::from_millis(5));
+    }
+}
+
+#[cfg(test)]
+fn start_follower(
+    db: &Db,
+    addr: std::net::SocketAddr,
+) -> Result<(TcpStream, thread::JoinHandle<Result<()>>)> {
+    let stream = TcpStream::connect(addr)?;
+    let control = stream.try_clone()?;
+    let db = db.clone();
+    Ok((control, thread::spawn(move || follow(db, stream))))
+}
+
+#[test]
+fn test_replication() -> Result<()> {
+    let dir = tempdir()?;
+    let mut primary = Db::new(dir.path().join("primary"))?;
+    primary.set("before", "connect")?;
+    primary.set("deleted", "soon")?;
+    primary.delete("deleted")?;
+
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let db = primary.clone();
+    thread::spawn(move || serve(db, listener));
+
+    let follower = Db::open_follower(dir.path().join("follower"), Options::default())?;
+    start_follower(&follower, addr)?;
+    wait_for_value(&follower, "before", Some("connect"));
+    assert_eq!(follower.get("deleted"), None);
+
+    primary.set("after", "connect")?;
+    primary.delete("before")?;
+    wait_for_value(&follower, "after", Some("connect"));
+    wait_for_value(&follower, "before", None);
+    assert_eq!(follower.next_seq(), primary.next_seq());
+
+    // Only the primary writes to the follower's log.
+    let mut writer = follower.clone();
+    assert!(matches!(writer.set("mine", "1"), Err(Error::ReadOnly)));
+    let stray = Db::new(dir.path().join("stray"))?;
+    assert!(follow(stray, TcpStream::connect(addr)?).is_err());
+
+    // A write that isn't synced waits for one before it goes out, so that
+    // the follower can't end up with a record the primary loses.
+    let unsynced = crate::WriteOptions {
+        sync: false,
+        ..crate::WriteOptions::default()
+    };
+    primary.set_with_options("unsynced", "1", &unsynced)?;
+    thread::sleep(Duration::from_millis(50));
+    assert_eq!(follower.get("unsynced"), None);
+    assert_eq!(primary.feed.lock()?.durable_seq(), primary.next_seq() - 2);
+    primary.sync()?;
+    wait_for_value(&follower, "unsynced", Some("1"));
+
+    Ok(())
+}
+
+#[test]
+fn test_follower_catch_up() -> Result<()> {
+    let dir = tempdir()?;
+    let mut primary = Db::new(dir.path().join("primary"))?;
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let db = primary.clone();
+    thread::spawn(move || serve(db, listener));
+
+    let follower_dir = dir.path().join("follower");
+    let follower = Db::open_follower(&follower_dir, Options::default())?;
+    let (control, handle) = start_follower(&follower, addr)?;
+    primary.set("a", "1")?;
+    wait_for_value(&follower, "a", Some("1"));
+    control.shutdown(std::net::Shutdown::Both)?;
+    let _ = handle.join().unwrap();
+    drop(follower);
+
+    // The follower restarts after missing some writes, and picks up from
+    // where its own log left off.
+    primary.set("b", "2")?;
+    primary.set("c", "3")?;
+    let follower = Db::open_follower(&follower_dir, Options::default())?;
+    assert_eq!(follower.get("a"), Some("1".into()));
+    start_follower(&follower, addr)?;
+    wait_for_value(&follower, "c", Some("3"));
+    assert_eq!(follower.get("b"), Some("2".into()));
+    assert_eq!(follower.next_seq(), primary.next_seq());
+
+    Ok(())
+}
+
+#[test]
+fn test_follower_reconnect_without_keys() -> Result<()> {
+    let dir = tempdir()?;
+    let mut primary = Db::new(dir.path().join("primary"))?;
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let db = primary.clone();
+    thread::spawn(move || serve(db, listener));
+
+    // A follower whose data is all deleted, or all in keyspaces, still has a
+    // log to carry on from.
+    let follower_dir = dir.path().join("follower");
+    let follower = Db::open_follower(&follower_dir, Options::default())?;
+    let (control, handle) = start_follower(&follower, addr)?;
+    primary.set("a", "1")?;
+    primary.cf("ks").set("a", "1")?;
+    primary.delete("a")?;
+    let start = Instant::now();
+    while follower.next_seq() != primary.next_seq() {
+        assert!(start.elapsed() < Duration::from_secs(10));
+        thread::sleep(Duration::from_millis(5));
+    }
+    assert!(follower.is_empty());
+    control.shutdown(std::net::Shutdown::Both)?;
+    let _ = handle.join().unwrap();
+    drop(follower);
+
+    primary.set("b", "2")?;
+    let follower = Db::open_follower(&follower_dir, Options::default())?;
+    let (_control, handle) = start_follower(&follower, addr)?;
+    wait_for_value(&follower, "b", Some("2"));
+    assert_eq!(follower.cf("ks").get("a"), Some("1".into()));
+    assert_eq!(follower.next_seq(), primary.next_seq());
+    assert!(!handle.is_finished());
+
+    Ok(())
+}
+
+#[test]
+fn test_primary_restart() -> Result<()> {
+    let dir = tempdir()?;
+    let mut primary = Db::new(dir.path().join("primary"))?;
+    primary.set("a", "1")?;
+    drop(primary);
+
+    // What the primary replayed on opening is sent from its log.
+    let mut primary = Db::new(dir.path().join("primary"))?;
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let db = primary.clone();
+    thread::spawn(move || serve(db, listener));
+    let follower = Db::open_follower(dir.path().join("follower"), Options::default())?;
+    start_follower(&follower, addr)?;
+    wait_for_value(&follower, "a", Some("1"));
+    primary.set("b", "2")?;
+    wait_for_value(&follower, "b", Some("2"));
+
+    Ok(())
+}
+
+#[test]
+fn test_follower_too_far_behind() -> Result<()> {
+    let dir = tempdir()?;
+    let mut primary = Db::new(dir.path().join("primary"))?;
+    primary.set("a", "1")?;
+    primary.set("a", "2")?;
+    primary.compact()?;
+
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    thread::spawn(move || serve(primary, listener));
+
+    // A follower with data from before the compaction can't be caught up.
+    let mut follower = Db::new(dir.path().join("follower"))?;
+    follower.set("a", "1")?;
+    drop(follower);
+    let follower = Db::open_follower(dir.path().join("follower"), Options::default())?;
+    let err = follow(follower, TcpStream::connect(addr)?).unwrap_err();
+    assert!(err.to_string().contains("no longer in the log"), "{}", err);
+
+    Ok(())
+}
+
+#[test]
+fn test_quorum_writes() -> Result<()> {
+    let dir = tempdir()?;
+    let mut primary = Db::new(dir.path().join("primary"))?;
+    let quorum = |n| crate::WriteOptions {
+        replication: crate::Replication::Quorum(n),
+        timeout: Duration::from_millis(200),
+        ..crate::WriteOptions::default()
+    };
+
+    // With nobody following, a quorum write still commits locally, but
+    // reports that it wasn't replicated.
+    let err = primary.set_with_options("a", "1", &quorum(1)).unwrap_err();
+    assert!(
+        matches!(
+            err,
+            Error::QuorumTimeout {
+                acked: 0,
+                needed: 1,
+                ..
+            }
+        ),
+        "{}",
+        err
+    );
+    assert_eq!(primary.get("a"), Some("1".into()));
+
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let db = primary.clone();
+    thread::spawn(move || serve(db, listener));
+    let followers = (0..2)
+        .map(|i| {
+            Db::open_follower(
+                dir.path().join(format!("follower{}", i)),
+                Options::default(),
+            )
+        })
+        .collect::<Result<Vec<_>>>()?;
+    for follower in &followers {
+        start_follower(follower, addr)?;
+    }
+
+    // Once the write returns, it's on both followers without any waiting.
+    let long = crate::WriteOptions {
+        timeout: Duration::from_secs(10),
+        ..quorum(2)
+    };
+    primary.set_with_options("b", "2", &long)?;
+    primary.delete_with_options("a", &long)?;
+    for follower in &followers {
+        assert_eq!(follower.get("b"), Some("2".into()));
+        assert_eq!(follower.get("a"), None);
+    }
+
+    let err = primary.set_with_options("c", "3", &quorum(3)).unwrap_err();
+    assert!(
+        matches!(
+            err,
+            Error::QuorumTimeout {
+                acked: 2,
+                needed: 3,
+                ..
+            }
+        ),
+        "{}",
+        err
+    );
+
+    Ok(())
+}
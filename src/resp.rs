@@ -0,0 +1,491 @@
+use crate::Db;
+use crate::{Error, Result};
+use tokio::{
+    io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader},
+    net::TcpListener,
+};
+
+// A front-end speaking enough of the Redis protocol (RESP) for redis-cli and
+// ordinary Redis client libraries to get and set keys: PING, GET, SET, DEL,
+// MGET, and SCAN.
+
+// Accepts connections on `listener` until it fails, serving each one on its
+// own task.
+pub async fn serve(db: Db, listener: TcpListener) -> Result<()> {
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let db = db.clone();
+        tokio::spawn(async move {
+            let (r, w) = stream.into_split();
+            // The connection is dropped on any I/O or protocol error; there's
+            // nobody else to tell.
+            let _ = serve_connection(db, BufReader::new(r), w).await;
+        });
+    }
+}
+
+pub async fn serve_connection<R, W>(db: Db, mut r: R, mut w: W) -> Result<()>
+where
+    R: AsyncBufRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut out = vec![];
+    while let Some(args) = read_command(&mut r).await? {
+        out.clear();
+        let quit = execute(&db, args, &mut out).await;
+        w.write_all(&out).await?;
+        if quit {
+            break;
+        }
+    }
+    w.flush().await?;
+    Ok(())
+}
+
+// Reads one command, either as an array of bulk strings (what clients send) or
+// as a plain line of space-separated words (what you type into telnet).
+// Returns None at the end of the stream.
+pub async fn read_command<R>(r: &mut R) -> Result<Option<Vec<Vec<u8>>>>
+where
+    R: AsyncBufRead + Unpin,
+{
+    let line = match read_line(r).await? {
+        Some(line) => line,
+        None => return Ok(None),
+    };
+    if line.first() != Some(&b'*') {
+        return Ok(Some(
+            line.split(|&b| b == b' ')
+                .filter(|w| !w.is_empty())
+                .map(|w| w.to_vec())
+                .collect(),
+        ));
+    }
+    let n = parse_len(&line[1..])?;
+    if n > MAX_ARGS {
+        return Err(Error::Protocol("too many arguments".into()));
+    }
+    let mut args = Vec::with_capacity(n.min(1024));
+    let mut total = 0;
+    for _ in 0..n {
+        let line = read_line(r)
+            .await?
+            .ok_or_else(|| Error::Protocol("unexpected end of stream".into()))?;
+        if line.first() != Some(&b'$') {
+            return Err(Error::Protocol("expected a bulk string".into()));
+        }
+        let len = parse_len(&line[1..])?;
+        // Same limit as Redis, so a bad length can't make us allocate
+        // arbitrarily much.
+        if len > 512 << 20 {
+            return Err(Error::Protocol("bulk string too long".into()));
+        }
+        total += len;
+        if total > MAX_REQUEST {
+            return Err(Error::Protocol("request too large".into()));
+        }
+        let mut arg = vec![0; len + 2];
+        r.read_exact(&mut arg).await?;
+        if !arg.ends_with(b"\r\n") {
+            return Err(Error::Protocol(
+                "bulk string is missing its terminator".into(),
+            ));
+        }
+        arg.truncate(len);
+        args.push(arg);
+    }
+    Ok(Some(args))
+}
+
+// The longest line we'll read looking for its end, so that a client that
+// never sends one can't make us buffer arbitrarily much. Bulk strings aren't
+// lines, and have their own limit.
+const MAX_LINE: u64 = 64 << 10;
+
+// The most arguments, and bytes of them between them, that one command can
+// have, so that a client can't make us buffer arbitrarily much by sending
+// lots of bulk strings that are each under the limit. The first is Redis's
+// own limit.
+const MAX_ARGS: usize = 1 << 20;
+const MAX_REQUEST: usize = 512 << 20;
+
+async fn read_line<R>(r: &mut R) -> Result<Option<Vec<u8>>>
+where
+    R: AsyncBufRead + Unpin,
+{
+    let mut line = vec![];
+    let read = r.take(MAX_LINE).read_until(b'\n', &mut line).await?;
+    if read == 0 {
+        return Ok(None);
+    }
+    if read as u64 == MAX_LINE && line.last() != Some(&b'\n') {
+        return Err(Error::Protocol("line too long".into()));
+    }
+    while line.last() == Some(&b'\n') || line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    Ok(Some(line))
+}
+
+fn parse_len(s: &[u8]) -> Result<usize> {
+    std::str::from_utf8(s)
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .ok_or_else(|| Error::Protocol("invalid length".into()))
+}
+
+// Runs `args` against `db`, encoding the reply into `out`. Returns whether the
+// client asked to close the connection.
+async fn execute(db: &Db, args: Vec<Vec<u8>>, out: &mut Vec<u8>) -> bool {
+    let mut args = args.into_iter();
+    let name = match args.next() {
+        Some(name) => String::from_utf8_lossy(&name).to_ascii_uppercase(),
+        None => return false,
+    };
+    let args = match args.map(String::from_utf8).collect::<Result<Vec<_>, _>>() {
+        Ok(args) => args,
+        Err(_) => {
+            write_error(out, "keys and values must be UTF-8");
+            return false;
+        }
+    };
+    let result = match (name.as_str(), args.as_slice()) {
+        ("PING", []) => {
+            write_simple(out, "PONG");
+            Ok(())
+        }
+        ("PING", [msg]) => {
+            write_bulk(out, Some(msg));
+            Ok(())
+        }
+        ("QUIT", _) => {
+            write_simple(out, "OK");
+            return true;
+        }
+        // redis-cli asks about the server's commands when it starts up;
+        // telling it there are none is enough to keep it happy.
+        ("COMMAND", _) => {
+            write_array_len(out, 0);
+            Ok(())
+        }
+        // Reads can go to the tables or the value log on disk, which would
+        // hold up everything else on this task's thread.
+        ("GET", [k]) => {
+            let (db, k) = (db.clone(), k.clone());
+            blocking(move || db.try_get(&k))
+                .await
+                .map(|v| write_bulk(out, v.as_deref()))
+        }
+        ("SET", [k, v]) => {
+            let (mut db, k, v) = (db.clone(), k.clone(), v.clone());
+            blocking(move || db.set(&k, &v)).await.map(|_| {
+                write_simple(out, "OK");
+            })
+        }
+        ("DEL", keys) if !keys.is_empty() => {
+            let mut db = db.clone();
+            let keys = keys.to_vec();
+            blocking(move || {
+                let mut deleted = 0;
+                for k in keys {
+                    if db.try_get(&k)?.is_some() {
+                        db.delete(&k)?;
+                        deleted += 1;
+                    }
+                }
+                Ok(deleted)
+            })
+            .await
+            .map(|deleted| write_integer(out, deleted))
+        }
+        ("MGET", keys) if !keys.is_empty() => {
+            let (db, keys) = (db.clone(), keys.to_vec());
+            let values = blocking(move || keys.iter().map(|k| db.try_get(k)).collect()).await;
+            values.map(|values: Vec<_>| {
+                write_array_len(out, values.len());
+                for v in values {
+                    write_bulk(out, v.as_deref());
+                }
+            })
+        }
+        ("SCAN", [cursor, options @ ..]) => scan(db, cursor, options, out).await,
+        _ => {
+            write_error(
+                out,
+                &format!(
+                    "unknown command or wrong number of arguments for '{}'",
+                    name
+                ),
+            );
+            Ok(())
+        }
+    };
+    if let Err(e) = result {
+        write_error(out, &e.to_string());
+    }
+    false
+}
+
+// The cursor is where the next page starts: the key just after the last one
+// returned, or 0 at either end. Clients hand it back as they got it, though
+// some, like redis-py, parse it as a number first, so it's the key's bytes
+// in decimal, three digits each, after a 1 that keeps any leading zeros.
+// COUNT is how many keys to go through, as in Redis, including those MATCH
+// then leaves out, and is capped at `MAX_SCAN_COUNT`.
+async fn scan(db: &Db, cursor: &str, options: &[String], out: &mut Vec<u8>) -> Result<()> {
+    let start = decode_cursor(cursor)?;
+    let mut pattern = "*";
+    let mut count = 10;
+    let mut options = options.iter();
+    while let Some(option) = options.next() {
+        let value = options
+            .next()
+            .ok_or_else(|| Error::Protocol("syntax error".into()))?;
+        match option.to_ascii_uppercase().as_str() {
+            "MATCH" => pattern = value,
+            "COUNT" => {
+                count = value
+                    .parse()
+                    .map_err(|_| Error::Protocol("invalid count".into()))?
+            }
+            _ => return Err(Error::Protocol("syntax error".into())),
+        }
+    }
+    let count = count.clamp(1, MAX_SCAN_COUNT);
+    let db = db.clone();
+    let keys = blocking(move || db.keys_from(&start, count)).await?;
+    let next = match keys.len() == count {
+        true => keys.last().map(|k| encode_cursor(&format!("{}\0", k))),
+        false => None,
+    };
+    let keys = keys
+        .iter()
+        .filter(|k| glob_match(pattern.as_bytes(), k.as_bytes()))
+        .collect::<Vec<_>>();
+    write_array_len(out, 2);
+    write_bulk(out, Some(next.as_deref().unwrap_or("0")));
+    write_array_len(out, keys.len());
+    for k in keys {
+        write_bulk(out, Some(k));
+    }
+    Ok(())
+}
+
+// The most keys one SCAN goes through, whatever COUNT it asks for.
+const MAX_SCAN_COUNT: usize = 10_000;
+
+fn encode_cursor(k: &str) -> String {
+    let mut cursor = String::with_capacity(1 + 3 * k.len());
+    cursor.push('1');
+    for b in k.bytes() {
+        cursor.push_str(&format!("{:03}", b));
+    }
+    cursor
+}
+
+fn decode_cursor(cursor: &str) -> Result<String> {
+    let invalid = || Error::Protocol("invalid cursor".into());
+    if cursor == "0" {
+        return Ok(String::new());
+    }
+    let digits = cursor.strip_prefix('1').ok_or_else(invalid)?.as_bytes();
+    if digits.len() % 3 != 0 || !digits.iter().all(u8::is_ascii_digit) {
+        return Err(invalid());
+    }
+    let bytes = digits
+        .chunks(3)
+        .map(|b| std::str::from_utf8(b).ok()?.parse::<u8>().ok())
+        .collect::<Option<Vec<_>>>()
+        .ok_or_else(invalid)?;
+    String::from_utf8(bytes).map_err(|_| invalid())
+}
+
+// Redis-style globs, minus character classes: `*` matches any run of
+// characters and `?` matches any single one. Patterns come from clients, so
+// rather than trying every way of splitting `s` between the stars, this only
+// goes back to the last star seen, which is enough and takes O(len^2) at
+// worst.
+fn glob_match(pattern: &[u8], s: &[u8]) -> bool {
+    let (mut p, mut i) = (0, 0);
+    // Where the pattern carries on after the last star, and where in `s` that
+    // star's match ends so far.
+    let mut star = None;
+    while i < s.len() {
+        match pattern.get(p) {
+            Some(b'*') => {
+                star = Some((p + 1, i));
+                p += 1;
+            }
+            Some(&c) if c == b'?' || c == s[i] => {
+                p += 1;
+                i += 1;
+            }
+            _ => match star {
+                Some((after, matched)) => {
+                    star = Some((after, matched + 1));
+                    p = after;
+                    i = matched + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+async fn blocking<F, T>(f: F) -> Result<T>
+where
+    F: FnOnce() -> Result<T> + Send + 'static,
+    T: Send + 'static,
+{
+    tokio::task::spawn_blocking(f)
+        .await
+        .map_err(std::io::Error::from)?
+}
+
+fn write_simple(out: &mut Vec<u8>, s: &str) {
+    out.extend_from_slice(format!("+{}\r\n", s).as_bytes());
+}
+
+fn write_error(out: &mut Vec<u8>, s: &str) {
+    out.extend_from_slice(format!("-ERR {}\r\n", s.replace(['\r', '\n'], " ")).as_bytes());
+}
+
+fn write_integer(out: &mut Vec<u8>, n: i64) {
+    out.extend_from_slice(format!(":{}\r\n", n).as_bytes());
+}
+
+fn write_array_len(out: &mut Vec<u8>, n: usize) {
+    out.extend_from_slice(format!("*{}\r\n", n).as_bytes());
+}
+
+fn write_bulk(out: &mut Vec<u8>, s: Option<&str>) {
+    match s {
+        Some(s) => {
+            out.extend_from_slice(format!("${}\r\n", s.len()).as_bytes());
+            out.extend_from_slice(s.as_bytes());
+            out.extend_from_slice(b"\r\n");
+        }
+        None => out.extend_from_slice(b"$-1\r\n"),
+    }
+}
+
+#[cfg(test)]
+async fn run(db: &Db, input: &[u8]) -> Result<String> {
+    let mut out = vec![];
+    serve_connection(db.clone(), input, &mut out).await?;
+    Ok(String::from_utf8_lossy(&out).into_owned())
+}
+
+#[tokio::test]
+async fn test_read_command() -> Result<()> {
+    let mut input: &[u8] = b"*3\r\n$3\r\nSET\r\n$5\r\nk\r\ney\r\n$0\r\n\r\nGET  key\r\n";
+    assert_eq!(
+        read_command(&mut input).await?,
+        Some(vec![b"SET".to_vec(), b"k\r\ney".to_vec(), b"".to_vec()])
+    );
+    assert_eq!(
+        read_command(&mut input).await?,
+        Some(vec![b"GET".to_vec(), b"key".to_vec()])
+    );
+    assert_eq!(read_command(&mut input).await?, None);
+
+    let mut input: &[u8] = b"*1\r\n$10\r\nshort\r\n";
+    assert!(read_command(&mut input).await.is_err());
+
+    // A line that never ends is cut off rather than read into memory.
+    let long = vec![b'a'; MAX_LINE as usize + 1];
+    let err = read_command(&mut long.as_slice()).await.unwrap_err();
+    assert!(matches!(err, Error::Protocol(_)), "{}", err);
+    let mut line = vec![b'a'; MAX_LINE as usize - 2];
+    line.extend_from_slice(b"\r\n");
+    assert_eq!(read_command(&mut line.as_slice()).await?.unwrap().len(), 1);
+
+    // As is a request with more arguments than it's worth waiting for.
+    let err = read_command(&mut &b"*2000000\r\n"[..]).await.unwrap_err();
+    assert!(matches!(err, Error::Protocol(_)), "{}", err);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_commands() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let db = Db::new(dir.path().join("logfile"))?;
+
+    assert_eq!(
+        run(
+            &db,
+            b"*3\r\n$3\r\nSET\r\n$3\r\nfoo\r\n$3\r\nbar\r\nSET baz goo\r\n"
+        )
+        .await?,
+        "+OK\r\n+OK\r\n"
+    );
+    assert_eq!(
+        run(&db, b"GET foo\r\nget nope\r\nMGET foo nope baz\r\n").await?,
+        "$3\r\nbar\r\n$-1\r\n*3\r\n$3\r\nbar\r\n$-1\r\n$3\r\ngoo\r\n"
+    );
+    assert_eq!(
+        run(&db, b"DEL foo nope\r\nGET foo\r\n").await?,
+        ":1\r\n$-1\r\n"
+    );
+    assert_eq!(
+        run(&db, b"PING\r\nFROB\r\nGET\r\n").await?,
+        "+PONG\r\n\
+         -ERR unknown command or wrong number of arguments for 'FROB'\r\n\
+         -ERR unknown command or wrong number of arguments for 'GET'\r\n"
+    );
+    assert_eq!(run(&db, b"QUIT\r\nGET baz\r\n").await?, "+OK\r\n");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_scan() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let mut db = Db::new(dir.path().join("logfile"))?;
+    for k in ["a1", "a2", "b1", "b2", "b3"] {
+        db.set(k, "v")?;
+    }
+
+    assert_eq!(
+        run(&db, b"SCAN 0 COUNT 3\r\nSCAN 1098049000 COUNT 3\r\n").await?,
+        "*2\r\n$10\r\n1098049000\r\n*3\r\n$2\r\na1\r\n$2\r\na2\r\n$2\r\nb1\r\n\
+         *2\r\n$1\r\n0\r\n*2\r\n$2\r\nb2\r\n$2\r\nb3\r\n"
+    );
+    // The cursor is a key, so it holds its place even with keys before it
+    // gone.
+    db.delete("a1")?;
+    assert_eq!(
+        run(&db, b"SCAN 1098049000 COUNT 1\r\n").await?,
+        "*2\r\n$10\r\n1098050000\r\n*1\r\n$2\r\nb2\r\n"
+    );
+    assert_eq!(
+        run(&db, b"SCAN 12 COUNT 1\r\n").await?,
+        "-ERR invalid cursor\r\n"
+    );
+    assert_eq!(
+        run(&db, b"SCAN 0 MATCH b?\r\n").await?,
+        "*2\r\n$1\r\n0\r\n*3\r\n$2\r\nb1\r\n$2\r\nb2\r\n$2\r\nb3\r\n"
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_glob_match() {
+    assert!(glob_match(b"*", b""));
+    assert!(glob_match(b"a*c", b"abbbc"));
+    assert!(glob_match(b"a?c", b"abc"));
+    assert!(!glob_match(b"a?c", b"ac"));
+    assert!(!glob_match(b"a*", b"ba"));
+    assert!(glob_match(b"a*b*c", b"aXbYbZc"));
+    assert!(glob_match(b"*a?", b"aaab"));
+    assert!(!glob_match(b"a*c?", b"abc"));
+    assert!(glob_match(b"**", b"x"));
+
+    // Patterns that backtracking into every star would take forever over.
+    let s = vec![b'a'; 1000];
+    assert!(!glob_match(b"a*a*a*a*a*a*a*a*a*a*b", &s));
+    assert!(glob_match(b"a*a*a*a*a*a*a*a*a*a*", &s));
+}
@@ -0,0 +1,52 @@
+// xorshift64, which is plenty random for sampling keys, benchmarks and
+// randomized tests, and always comes up with the same numbers from the same
+// seed.
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+};
+
+#[derive(Debug, Clone)]
+pub struct Rng(u64);
+
+impl Rng {
+    // A state of 0 never changes, so the seed is made odd.
+    pub fn new(seed: u64) -> Self {
+        Rng(seed | 1)
+    }
+
+    // Seeded differently every time.
+    pub fn random() -> Self {
+        Rng::new(RandomState::new().build_hasher().finish())
+    }
+
+    // A randomized test's generator, seeded from `REDO_LOG_SEED` if it's set
+    // so that a failure can be run again, and differently every time if not.
+    // The seed is printed, for the test's output to show if it fails.
+    #[cfg(test)]
+    pub(crate) fn for_test() -> (Self, u64) {
+        let seed = match std::env::var("REDO_LOG_SEED") {
+            Ok(seed) => seed.parse().expect("REDO_LOG_SEED is a number"),
+            Err(_) => Rng::random().next_u64(),
+        };
+        eprintln!("REDO_LOG_SEED={}", seed);
+        (Rng::new(seed), seed)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    // Below `n`, or 0 if `n` is 0.
+    pub fn below(&mut self, n: u64) -> u64 {
+        self.next_u64() % n.max(1)
+    }
+
+    // Between 0 and 1.
+    pub fn fraction(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
@@ -0,0 +1,765 @@
+#[cfg(feature = "failpoints")]
+use crate::failpoint::{self, FailAction, Failpoint, Failpoints};
+use crate::Result;
+use crate::{durable_fs, Durability, Error};
+use redo_log_core::{write_header, SegmentCursor, Storage, WriteStorage};
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, BufReader, Read, Seek, SeekFrom, Write},
+    os::unix::fs::FileExt,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+// The segment header and record formats, and reading a segment a record at a
+// time, live in `redo_log_core`, which works on any `Storage`; this is the
+// part that keeps segments in files.
+pub use redo_log_core::{
+    decode_record, encode_footer, encode_record, End, Record, FOOTER_LEN, FORMAT_VERSION,
+    HEADER_LEN, MAGIC, SEGMENT_HEADER_LEN,
+};
+
+pub fn segment_path(dir: &Path, number: u64) -> PathBuf {
+    dir.join(format!("{:020}.log", number))
+}
+
+pub fn recycled_path(dir: &Path, number: u64) -> PathBuf {
+    dir.join(format!("{:020}.recycle", number))
+}
+
+// Returns the numbers of the live segments and the recycled segments in `dir`,
+// each in ascending order.
+pub fn list(dir: &Path) -> Result<(Vec<u64>, Vec<u64>)> {
+    let mut segments = vec![];
+    let mut recycled = vec![];
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let number = match path.file_stem().and_then(|s| s.to_str()) {
+            Some(stem) => match stem.parse::<u64>() {
+                Ok(n) => n,
+                Err(_) => continue,
+            },
+            None => continue,
+        };
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("log") => segments.push(number),
+            Some("recycle") => recycled.push(number),
+            _ => {}
+        }
+    }
+    segments.sort_unstable();
+    recycled.sort_unstable();
+    Ok((segments, recycled))
+}
+
+// Wipes segment `number` from `offset` on, so that it reads as if nothing
+// had been written there. The file keeps its length, though not necessarily
+// the space allocated to it.
+pub fn zero_from(dir: &Path, number: u64, offset: u64) -> Result<()> {
+    let file = OpenOptions::new()
+        .write(true)
+        .open(segment_path(dir, number))?;
+    let len = file.metadata()?.len();
+    file.set_len(offset)?;
+    file.set_len(len)?;
+    durable_fs::sync_file(&file, Durability::Media)
+}
+
+// Reads the records of a single segment file in order, stopping at the first
+// one that is zeroed (preallocated space), stale (left over from a recycled
+// file), or torn.
+#[derive(Debug)]
+pub struct SegmentReader {
+    cursor: SegmentCursor<FileStorage>,
+}
+
+impl SegmentReader {
+    pub fn open(dir: &Path, number: u64) -> Result<Self> {
+        let storage = FileStorage::open(&segment_path(dir, number))?;
+        Ok(SegmentReader {
+            cursor: SegmentCursor::open(storage, number)?,
+        })
+    }
+
+    // Stops checking records against their checksums, for a segment that's
+    // known to be intact some other way. A damaged record is then only
+    // noticed if it stops making sense as a record.
+    pub fn set_verify(&mut self, verify: bool) {
+        self.cursor.set_verify(verify);
+    }
+
+    // Takes records to be in sequence as long as each one comes after the
+    // last, as they do in a shard of a sharded log, where the records in
+    // between went to the other shards. `next_seq` is then one past the last
+    // record read.
+    pub fn set_sparse(&mut self, sparse: bool) {
+        self.cursor.set_sparse(sparse);
+    }
+
+    // Opens segment `number` to carry on from `offset`, where an earlier
+    // reader left off after reading up to `next_seq`. Records appended since
+    // then show up as if the earlier reader had kept going.
+    pub fn resume(dir: &Path, number: u64, offset: u64, next_seq: u64) -> Result<Self> {
+        let storage = FileStorage::open(&segment_path(dir, number))?;
+        Ok(SegmentReader {
+            cursor: SegmentCursor::resume(storage, number, offset, next_seq)?,
+        })
+    }
+
+    pub fn number(&self) -> u64 {
+        self.cursor.number()
+    }
+
+    pub fn first_seq(&self) -> Option<u64> {
+        self.cursor.first_seq()
+    }
+
+    // The id of the codec the segment's records are in, or 0 if its header
+    // is missing or stale.
+    pub fn codec(&self) -> u32 {
+        self.cursor.codec()
+    }
+
+    // The offset just past the last valid record read so far.
+    pub fn offset(&self) -> u64 {
+        self.cursor.offset()
+    }
+
+    // The sequence number the next record in this segment would have.
+    pub fn next_seq(&self) -> u64 {
+        self.cursor.next_seq()
+    }
+
+    // Why the reader stopped, once it has.
+    pub fn end(&self) -> Option<End> {
+        self.cursor.end()
+    }
+
+    // Reads the next record into `payload`, which can be reused from one
+    // record to the next. Returns the record's offset and sequence number, or
+    // None once the end of the valid records has been reached.
+    pub fn next_record(&mut self, payload: &mut Vec<u8>) -> Result<Option<(u64, u64)>> {
+        self.cursor.next_record(payload)
+    }
+
+    // Once the reader has stopped at a record that is torn, fails its
+    // checksum, or is out of sequence, looks further into the segment for a
+    // valid record that carries on the sequence. If there is one, the reader
+    // picks up from there and this returns true; if not, whatever went wrong
+    // was the last thing written to the segment.
+    pub fn resync(&mut self) -> Result<bool> {
+        self.cursor.resync()
+    }
+}
+
+// A segment file as `SegmentCursor` reads it: as long as it was when opened,
+// and read through a buffer that's only thrown away when the cursor jumps.
+#[derive(Debug)]
+struct FileStorage {
+    file: BufReader<File>,
+    len: u64,
+    pos: u64,
+}
+
+impl FileStorage {
+    fn open(path: &Path) -> Result<Self> {
+        let file = File::open(path)?;
+        let len = file.metadata()?.len();
+        Ok(FileStorage {
+            file: BufReader::new(file),
+            len,
+            pos: 0,
+        })
+    }
+}
+
+impl Storage for FileStorage {
+    type Error = Error;
+
+    fn size(&self) -> u64 {
+        self.len
+    }
+
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<()> {
+        if offset != self.pos {
+            self.file.seek(SeekFrom::Start(offset))?;
+        }
+        self.file.read_exact(buf)?;
+        self.pos = offset + buf.len() as u64;
+        Ok(())
+    }
+}
+
+// Passes each record of segment `number` to `f` along with its offset. Each
+// payload is read into `payload`, which is reused from one record to the next.
+// Returns the offset just past the last valid record.
+pub fn read_records<F>(dir: &Path, number: u64, payload: &mut Vec<u8>, mut f: F) -> Result<u64>
+where
+    F: FnMut(u64, &[u8]) -> Result<()>,
+{
+    let mut reader = SegmentReader::open(dir, number)?;
+    while let Some((offset, _)) = reader.next_record(payload)? {
+        f(offset, payload)?;
+    }
+    Ok(reader.offset())
+}
+
+// Reads segment `number` as replay would with `RecoveryMode::SkipCorrupt`,
+// without applying anything, for `redo-log verify`. Whatever ends up in the
+// report's `skipped` is what `RecoveryMode::TolerateTornTail` refuses to open.
+// A bad record with nothing valid after it is a torn tail, wherever the
+// segment falls in the log, as long as `next`, the segment after it if any,
+// carries on the sequence from there.
+pub fn check(dir: &Path, number: u64, next: Option<u64>) -> Result<crate::RecoveryReport> {
+    let options = crate::RecoveryOptions {
+        mode: crate::RecoveryMode::SkipCorrupt,
+        ..crate::RecoveryOptions::default()
+    };
+    let mut report = crate::RecoveryReport::default();
+    crate::replay::read_segment(
+        dir,
+        number,
+        next,
+        &options,
+        &mut vec![],
+        &mut report,
+        |_, _, _, _| Ok(()),
+    )?;
+    Ok(report)
+}
+
+// Creates segment `number` the way `SegmentWriter::create` does, returning
+// its file with the header written and synced, ready for records to be
+// written after it.
+pub(crate) fn create_file(
+    dir: &Path,
+    number: u64,
+    first_seq: u64,
+    codec: u32,
+    size: u64,
+    reuse: Option<u64>,
+    durability: Durability,
+) -> Result<File> {
+    let path = segment_path(dir, number);
+    let mut file = match reuse {
+        Some(old) => {
+            durable_fs::rename(&recycled_path(dir, old), &path)?;
+            OpenOptions::new().write(true).open(&path)?
+        }
+        None => durable_fs::create_new(&path)?,
+    };
+    preallocate(&file, size)?;
+    file.write_all(&write_header(number, first_seq, codec))?;
+    durable_fs::sync_file(&file, durability)?;
+    Ok(file)
+}
+
+#[derive(Debug)]
+pub struct SegmentWriter {
+    file: SegmentSync,
+    offset: u64,
+    next_seq: u64,
+    buf: Vec<u8>,
+}
+
+// A segment's file, as far as syncing it goes. A sharded log takes one of
+// these away from the segment a batch went to, so that it can sync that batch
+// while the next one is written to another shard.
+#[derive(Debug, Clone)]
+pub struct SegmentSync {
+    file: Arc<File>,
+    durability: Durability,
+    number: u64,
+    // Set once a write or sync has failed. There's no telling what made it
+    // into the file after that, so nothing more is written to it.
+    failed: Arc<AtomicBool>,
+    #[cfg(feature = "failpoints")]
+    failpoints: Option<Arc<Failpoints>>,
+    // Whether this is a mirror's copy of the segment, which has failpoints
+    // of its own.
+    #[cfg(feature = "failpoints")]
+    mirror: bool,
+}
+
+impl SegmentWriter {
+    // Creates segment `number`, whose first record will be `first_seq` and
+    // whose records will be in codec `codec`, either from scratch or by taking
+    // over the recycled segment `reuse`. Either way the file is allocated up
+    // to `size` bytes up front so that appending to it does not change its
+    // length, and syncing it only has to flush data rather than file metadata.
+    pub fn create(
+        dir: &Path,
+        number: u64,
+        first_seq: u64,
+        codec: u32,
+        size: u64,
+        reuse: Option<u64>,
+        durability: Durability,
+    ) -> Result<Self> {
+        let file = create_file(dir, number, first_seq, codec, size, reuse, durability)?;
+        Ok(SegmentWriter {
+            file: SegmentSync {
+                file: Arc::new(file),
+                durability,
+                number,
+                failed: Arc::new(AtomicBool::new(false)),
+                #[cfg(feature = "failpoints")]
+                failpoints: None,
+                #[cfg(feature = "failpoints")]
+                mirror: false,
+            },
+            offset: SEGMENT_HEADER_LEN as u64,
+            next_seq: first_seq,
+            buf: vec![],
+        })
+    }
+
+    #[cfg(feature = "failpoints")]
+    pub(crate) fn set_failpoints(&mut self, failpoints: Option<Arc<Failpoints>>, mirror: bool) {
+        self.file.failpoints = failpoints;
+        self.file.mirror = mirror;
+    }
+
+    // Fails this segment whenever `failed` is set, and sets it when this
+    // segment fails, so that a log can stop writing to every segment at once.
+    pub(crate) fn share_failure(&mut self, failed: Arc<AtomicBool>) {
+        self.file.failed = failed;
+    }
+
+    pub fn number(&self) -> u64 {
+        self.file.number
+    }
+
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    pub fn next_seq(&self) -> u64 {
+        self.next_seq
+    }
+
+    // Numbers the records appended from here on from `seq`, which can't be
+    // behind where they'd otherwise start.
+    pub fn skip_to(&mut self, seq: u64) {
+        assert!(seq >= self.next_seq);
+        self.next_seq = seq;
+    }
+
+    pub fn append(&mut self, payload: &[u8]) -> Result<()> {
+        self.append_batch(std::slice::from_ref(&payload))?;
+        Ok(())
+    }
+
+    // Encodes every payload into a single buffer so the whole batch goes out
+    // in one write, numbering them from `next_seq`. Returns the number of
+    // bytes written.
+    pub fn append_batch<T>(&mut self, payloads: &[T]) -> Result<usize>
+    where
+        T: AsRef<[u8]>,
+    {
+        self.file.check()?;
+        self.buf.clear();
+        for payload in payloads {
+            encode_record(
+                self.file.number,
+                self.next_seq,
+                payload.as_ref(),
+                &mut self.buf,
+            );
+            self.next_seq += 1;
+        }
+        let written = self.write_buf();
+        self.file.fail_if(written.map_err(Error::from))?;
+        self.offset += self.buf.len() as u64;
+        Ok(self.buf.len())
+    }
+
+    // Ends the segment with a footer saying where the sequence carries on,
+    // and syncs it, so that replay can tell it was finished with rather than
+    // cut short. Nothing is appended after it.
+    pub fn seal(&mut self) -> Result<()> {
+        self.file.check()?;
+        self.buf.clear();
+        encode_footer(self.file.number, self.next_seq, &mut self.buf);
+        let written = self.write_footer();
+        self.file.fail_if(written.map_err(Error::from))?;
+        self.offset += self.buf.len() as u64;
+        self.sync()
+    }
+
+    fn write_footer(&mut self) -> io::Result<()> {
+        #[cfg(feature = "failpoints")]
+        let written = self
+            .file
+            .write_failpoint(Failpoint::BeforeSeal, self.offset, &self.buf)?;
+        #[cfg(not(feature = "failpoints"))]
+        let written = false;
+        if !written {
+            self.file.write_at(self.offset, &self.buf)?;
+        }
+        #[cfg(feature = "failpoints")]
+        self.file.failpoint(Failpoint::AfterSeal)?;
+        Ok(())
+    }
+
+    fn write_buf(&mut self) -> io::Result<()> {
+        #[cfg(feature = "failpoints")]
+        let written = self
+            .file
+            .write_failpoint(Failpoint::BeforeWrite, self.offset, &self.buf)?;
+        #[cfg(not(feature = "failpoints"))]
+        let written = false;
+        if !written {
+            self.file.write_at(self.offset, &self.buf)?;
+        }
+        #[cfg(feature = "failpoints")]
+        self.file.failpoint(Failpoint::AfterWrite)?;
+        Ok(())
+    }
+
+    pub fn sync(&self) -> Result<()> {
+        self.file.sync()
+    }
+
+    pub fn sync_with(&self, durability: Durability) -> Result<()> {
+        self.file.sync_with(durability)
+    }
+
+    // For syncing what's been written so far without the writer.
+    pub fn syncer(&self) -> SegmentSync {
+        self.file.clone()
+    }
+}
+
+impl SegmentSync {
+    pub fn number(&self) -> u64 {
+        self.number
+    }
+
+    pub fn sync(&self) -> Result<()> {
+        self.sync_with(self.durability)
+    }
+
+    // Syncs as hard as `durability` says, whatever the segment was created
+    // with.
+    pub fn sync_with(&self, durability: Durability) -> Result<()> {
+        self.check()?;
+        #[cfg(feature = "failpoints")]
+        self.fail_if(self.failpoint(Failpoint::BeforeSync).map_err(Error::from))?;
+        self.fail_if(durable_fs::sync_file(&self.file, durability))?;
+        #[cfg(feature = "failpoints")]
+        self.fail_if(self.failpoint(Failpoint::AfterSync).map_err(Error::from))?;
+        Ok(())
+    }
+
+    fn check(&self) -> Result<()> {
+        if self.failed.load(Ordering::Acquire) {
+            return Err(Error::WriteFailed(format!(
+                "an earlier write to the log failed, as of segment {}; reopen the log",
+                self.number
+            )));
+        }
+        Ok(())
+    }
+
+    fn fail_if<T>(&self, result: Result<T>) -> Result<T> {
+        if result.is_err() {
+            self.failed.store(true, Ordering::Release);
+        }
+        result
+    }
+
+    // Fails if `point` is armed.
+    #[cfg(feature = "failpoints")]
+    fn failpoint(&self, point: Failpoint) -> io::Result<()> {
+        match self.armed(point) {
+            Some((point, _)) => Err(failpoint::error(point)),
+            None => Ok(()),
+        }
+    }
+
+    // Fails if `point`, which comes before a write of `batch` to `offset`, is
+    // armed, after writing whatever part of the batch a partial write asks
+    // for. A flipped bit doesn't fail: the batch is written with it flipped,
+    // and this returns true to say it's been written.
+    #[cfg(feature = "failpoints")]
+    fn write_failpoint(&mut self, point: Failpoint, offset: u64, batch: &[u8]) -> io::Result<bool> {
+        let Some((point, action)) = self.armed(point) else {
+            return Ok(false);
+        };
+        match action {
+            FailAction::Error => {}
+            FailAction::PartialWrite(n) => self.write_at(offset, &batch[..n.min(batch.len())])?,
+            FailAction::FlipBit(bit) => {
+                let mut batch = batch.to_vec();
+                let bit = bit % (batch.len() * 8);
+                batch[bit / 8] ^= 1 << (bit % 8);
+                self.write_at(offset, &batch)?;
+                return Ok(true);
+            }
+        }
+        Err(failpoint::error(point))
+    }
+
+    // Whether `point` is armed, and how, as the failpoint it is for this copy
+    // of the segment. The mirror's segments only have failpoints for writing
+    // and syncing.
+    #[cfg(feature = "failpoints")]
+    fn armed(&self, point: Failpoint) -> Option<(Failpoint, FailAction)> {
+        let point = match (self.mirror, point) {
+            (false, point) => point,
+            (true, Failpoint::BeforeWrite) => Failpoint::MirrorWrite,
+            (true, Failpoint::BeforeSync) => Failpoint::MirrorSync,
+            (true, _) => return None,
+        };
+        let action = self.failpoints.as_ref()?.hit(point)?;
+        Some((point, action))
+    }
+}
+
+// A segment's file as `Storage`, which is what records are written through:
+// each batch at the offset its writer has got to.
+impl Storage for SegmentSync {
+    type Error = io::Error;
+
+    fn size(&self) -> u64 {
+        self.file.metadata().map_or(0, |m| m.len())
+    }
+
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        self.file.read_exact_at(buf, offset)
+    }
+}
+
+impl WriteStorage for SegmentSync {
+    fn write_at(&mut self, offset: u64, buf: &[u8]) -> io::Result<()> {
+        self.file.write_all_at(buf, offset)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.sync().map_err(io::Error::other)
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn preallocate(file: &File, size: u64) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+    if size == 0 {
+        return Ok(());
+    }
+    let ret = unsafe { libc::fallocate(file.as_raw_fd(), 0, 0, size as libc::off_t) };
+    if ret == 0 {
+        return Ok(());
+    }
+    let err = io::Error::last_os_error();
+    if err.raw_os_error() == Some(libc::EOPNOTSUPP) {
+        // Not every filesystem can allocate ahead of time; a sparse file at
+        // least keeps the length fixed.
+        return extend(file, size);
+    }
+    Err(err)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn preallocate(file: &File, size: u64) -> io::Result<()> {
+    extend(file, size)
+}
+
+fn extend(file: &File, size: u64) -> io::Result<()> {
+    if file.metadata()?.len() < size {
+        file.set_len(size)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+fn read_all(dir: &Path, number: u64) -> Result<Vec<Vec<u8>>> {
+    let mut records = vec![];
+    read_records(dir, number, &mut vec![], |_, r| {
+        records.push(r.to_vec());
+        Ok(())
+    })?;
+    Ok(records)
+}
+
+#[test]
+fn test_preallocated_segment() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+
+    let mut w = SegmentWriter::create(dir.path(), 1, 1, 2, 4096, None, Durability::Media)?;
+    w.append(b"foo")?;
+    w.append(b"bar")?;
+    w.sync()?;
+    assert_eq!(fs::metadata(segment_path(dir.path(), 1))?.len(), 4096);
+    assert_eq!(
+        read_all(dir.path(), 1)?,
+        vec![b"foo".to_vec(), b"bar".to_vec()]
+    );
+    let mut reader = SegmentReader::open(dir.path(), 1)?;
+    while reader.next_record(&mut vec![])?.is_some() {}
+    assert_eq!(reader.end(), Some(End::Zeroed));
+
+    Ok(())
+}
+
+#[test]
+fn test_append_batch() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+
+    let mut w = SegmentWriter::create(dir.path(), 1, 1, 2, 4096, None, Durability::Media)?;
+    let written = w.append_batch(&[b"foo".to_vec(), b"barbaz".to_vec()])?;
+    w.append(b"qux")?;
+    w.sync()?;
+    assert_eq!(written, 2 * HEADER_LEN + 9);
+    assert_eq!(
+        w.offset(),
+        (SEGMENT_HEADER_LEN + 3 * HEADER_LEN + 12) as u64
+    );
+    assert_eq!(w.next_seq(), 4);
+    assert_eq!(
+        read_all(dir.path(), 1)?,
+        vec![b"foo".to_vec(), b"barbaz".to_vec(), b"qux".to_vec()]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_recycled_segment_ignores_stale_records() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+
+    let mut w = SegmentWriter::create(dir.path(), 1, 1, 2, 4096, None, Durability::Media)?;
+    w.append(b"old record one")?;
+    w.append(b"old record two")?;
+    w.sync()?;
+    drop(w);
+    fs::rename(segment_path(dir.path(), 1), recycled_path(dir.path(), 1))?;
+
+    let mut w = SegmentWriter::create(dir.path(), 2, 3, 2, 4096, Some(1), Durability::Media)?;
+    w.append(b"new record one")?;
+    w.sync()?;
+    assert_eq!(read_all(dir.path(), 2)?, vec![b"new record one".to_vec()]);
+    assert_eq!(list(dir.path())?, (vec![2], vec![]));
+
+    Ok(())
+}
+
+#[test]
+fn test_segment_header() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let path = segment_path(dir.path(), 1);
+    let mut w = SegmentWriter::create(dir.path(), 1, 7, 2, 4096, None, Durability::Media)?;
+    w.append(b"record")?;
+    w.sync()?;
+    drop(w);
+    let reader = SegmentReader::open(dir.path(), 1)?;
+    assert_eq!((reader.first_seq(), reader.codec()), (Some(7), 2));
+    let good = fs::read(&path)?;
+
+    // Anything this build can't be sure of reading correctly is refused.
+    let damage = |at: usize, byte: u8| -> Result<Error> {
+        let mut bytes = good.clone();
+        bytes[at] = byte;
+        fs::write(&path, bytes)?;
+        Ok(SegmentReader::open(dir.path(), 1).unwrap_err())
+    };
+    for (at, byte, reason) in [
+        (0, b'R', "magic"),
+        (8, 2, "version 2"),
+        (11, 1, "features"),
+        (12, 1, "compressed"),
+        (13, 2, "checksums"),
+    ] {
+        let err = damage(at, byte)?;
+        assert!(
+            matches!(&err, Error::IncompatibleFormat { segment: 1, reason: r } if r.contains(reason)),
+            "{}",
+            err
+        );
+    }
+    fs::write(
+        &path,
+        "a text file that happens to be named like a segment\n",
+    )?;
+    let err = SegmentReader::open(dir.path(), 1).unwrap_err();
+    assert!(matches!(err, Error::IncompatibleFormat { .. }), "{}", err);
+    let err = crate::Db::new(dir.path()).unwrap_err();
+    assert!(matches!(err, Error::IncompatibleFormat { .. }), "{}", err);
+
+    // A header that was never written, or only partly, just leaves the
+    // segment empty, as does one for another segment.
+    for bytes in [vec![0; 4096], MAGIC[..5].to_vec(), {
+        let mut bytes = good.clone();
+        bytes[24] = 9;
+        bytes
+    }] {
+        fs::write(&path, bytes)?;
+        let reader = SegmentReader::open(dir.path(), 1)?;
+        assert_eq!(reader.end(), Some(End::BadHeader));
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_torn_record() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+
+    let mut w = SegmentWriter::create(dir.path(), 1, 1, 2, 0, None, Durability::Media)?;
+    w.append(b"whole")?;
+    w.append(b"torn")?;
+    w.sync()?;
+    let path = segment_path(dir.path(), 1);
+    let len = fs::metadata(&path)?.len();
+    OpenOptions::new()
+        .write(true)
+        .open(&path)?
+        .set_len(len - 1)?;
+    assert_eq!(read_all(dir.path(), 1)?, vec![b"whole".to_vec()]);
+    let mut reader = SegmentReader::open(dir.path(), 1)?;
+    while reader.next_record(&mut vec![])?.is_some() {}
+    assert_eq!(reader.end(), Some(End::Torn));
+
+    Ok(())
+}
+
+#[test]
+fn test_decode_record() {
+    let (mut rng, _) = crate::Rng::for_test();
+    let mut next = |n: u64| rng.below(n);
+    for _ in 0..10_000 {
+        let payload = (0..next(64)).map(|_| next(256) as u8).collect::<Vec<_>>();
+        let (segment, seq) = (next(1 << 40), next(1 << 40));
+        let mut buf = vec![];
+        encode_record(segment, seq, &payload, &mut buf);
+        let len = buf.len();
+        assert_eq!(
+            decode_record(&buf),
+            Ok((
+                Record {
+                    segment,
+                    seq,
+                    payload: &payload
+                },
+                len
+            ))
+        );
+        // Whatever's damaged or cut short must be caught, and whatever gets
+        // through must be a record that encodes back to the same bytes.
+        match next(3) {
+            0 => buf.truncate(next(len as u64) as usize),
+            1 => buf[next(len as u64) as usize] ^= 1 << next(8),
+            _ => buf = (0..next(64)).map(|_| next(256) as u8).collect(),
+        }
+        if let Ok((record, len)) = decode_record(&buf) {
+            let mut again = vec![];
+            encode_record(record.segment, record.seq, record.payload, &mut again);
+            assert_eq!(again, buf[..len]);
+            let _ = crate::error::decode::<crate::Command>(0, 0, record.payload);
+        }
+    }
+    assert_eq!(decode_record(&[0; HEADER_LEN + 8]), Err(End::Zeroed));
+}
@@ -0,0 +1,595 @@
+// Deterministic simulation of the write path. Each simulated thread is a real
+// thread, but only one of them runs at a time: the others are parked at a
+// `step`, and every step hands control to a thread picked by a seeded random
+// number generator. Syncs take a random amount of virtual time, which is what
+// `Db` sees as the time while it's being simulated. The same seed therefore
+// always gives the same interleaving, so a failure can be replayed from the
+// seed it prints rather than hoped for again under a stress run.
+//
+// Steps are only taken where the thread isn't holding a lock that another
+// simulated thread could block on for real, since that thread would never
+// give control back. The one exception is the log lock around a sync: the
+// only way to it is through the previous batch being done, which simulated
+// threads wait for by stepping.
+use crate::{Backpressure, Db, Durability, Error, Options, Result, Rng, WriteOptions};
+use std::{
+    cell::RefCell,
+    collections::BTreeSet,
+    sync::{Arc, Condvar, Mutex},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+use tempfile::tempdir;
+
+// Past this many steps, the simulated threads are taken to be stuck waiting
+// on each other.
+const MAX_STEPS: usize = 1_000_000;
+// And if this long goes by without a step, the running thread is taken to be
+// blocked for real.
+const STUCK: Duration = Duration::from_secs(10);
+
+#[derive(Debug)]
+pub(crate) struct Sim {
+    seed: u64,
+    start: Instant,
+    state: Mutex<State>,
+    turn: Condvar,
+}
+
+#[derive(Debug)]
+struct State {
+    rng: Rng,
+    // Threads that haven't finished yet, and whose turn it is.
+    live: BTreeSet<usize>,
+    running: Option<usize>,
+    next_id: usize,
+    clock: Duration,
+    // Which thread took which step, in order.
+    trace: Vec<(usize, &'static str)>,
+}
+
+thread_local! {
+    static CURRENT: RefCell<Option<(Arc<Sim>, usize)>> = const { RefCell::new(None) };
+}
+
+impl Sim {
+    pub fn new(seed: u64) -> Arc<Self> {
+        Arc::new(Sim {
+            seed,
+            start: Instant::now(),
+            state: Mutex::new(State {
+                rng: Rng::new(seed),
+                live: BTreeSet::new(),
+                running: None,
+                next_id: 0,
+                clock: Duration::ZERO,
+                trace: vec![],
+            }),
+            turn: Condvar::new(),
+        })
+    }
+
+    // Starts `f` as a simulated thread. It doesn't get to run until `run`.
+    pub fn spawn<F, T>(self: &Arc<Self>, f: F) -> JoinHandle<T>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let id = {
+            let mut state = self.state.lock().unwrap();
+            state.next_id += 1;
+            let id = state.next_id;
+            state.live.insert(id);
+            id
+        };
+        let sim = self.clone();
+        thread::spawn(move || {
+            sim.wait_turn(id);
+            CURRENT.with(|c| *c.borrow_mut() = Some((sim.clone(), id)));
+            let _exit = Exit(sim, id);
+            f()
+        })
+    }
+
+    // Runs every thread spawned so far to completion, returning the trace of
+    // who took which step.
+    pub fn run(&self) -> Vec<(usize, &'static str)> {
+        let mut state = self.state.lock().unwrap();
+        self.pass(&mut state);
+        while !state.live.is_empty() {
+            let steps = state.trace.len();
+            let (next, timeout) = self.turn.wait_timeout(state, STUCK).unwrap();
+            state = next;
+            // Whoever's turn it is has blocked on something other than a
+            // step, and nobody else can run to unblock it.
+            assert!(
+                !timeout.timed_out() || state.trace.len() != steps,
+                "simulation with seed {} stuck with thread {:?} blocked after {:?}",
+                self.seed,
+                state.running,
+                &state.trace[state.trace.len().saturating_sub(10)..]
+            );
+        }
+        state.trace.clone()
+    }
+
+    // Hands the turn to one of the live threads, maybe the current one.
+    fn pass(&self, state: &mut State) {
+        let live = state.live.iter().copied().collect::<Vec<_>>();
+        state.running = live
+            .get(state.rng.below(live.len() as u64) as usize)
+            .copied();
+        assert!(
+            state.trace.len() < MAX_STEPS,
+            "simulation with seed {} made no progress in {} steps",
+            self.seed,
+            MAX_STEPS
+        );
+        self.turn.notify_all();
+    }
+
+    fn wait_turn(&self, id: usize) {
+        let mut state = self.state.lock().unwrap();
+        while state.running != Some(id) {
+            state = self.turn.wait(state).unwrap();
+        }
+    }
+
+    fn step(&self, id: usize, point: &'static str, max_time: Duration) {
+        let mut state = self.state.lock().unwrap();
+        state.trace.push((id, point));
+        let nanos = max_time.as_nanos() as u64;
+        if nanos > 0 {
+            let nanos = state.rng.below(nanos);
+            state.clock += Duration::from_nanos(nanos);
+        }
+        self.pass(&mut state);
+        drop(state);
+        self.wait_turn(id);
+    }
+}
+
+// Takes a thread out of the simulation once it's done, even if it panicked,
+// so that `run` doesn't wait for it forever.
+struct Exit(Arc<Sim>, usize);
+
+impl Drop for Exit {
+    fn drop(&mut self) {
+        let Exit(sim, id) = self;
+        CURRENT.with(|c| *c.borrow_mut() = None);
+        let mut state = sim.state.lock().unwrap_or_else(|e| e.into_inner());
+        state.live.remove(id);
+        state.trace.push((*id, "exit"));
+        sim.pass(&mut state);
+    }
+}
+
+fn current() -> Option<(Arc<Sim>, usize)> {
+    CURRENT.with(|c| c.borrow().clone())
+}
+
+// Whether this thread is being simulated.
+pub(crate) fn active() -> bool {
+    CURRENT.with(|c| c.borrow().is_some())
+}
+
+// Lets another simulated thread run, if this is one.
+pub(crate) fn step(point: &'static str) {
+    if let Some((sim, id)) = current() {
+        sim.step(id, point, Duration::from_micros(10));
+    }
+}
+
+// A step that takes as long as a sync might.
+pub(crate) fn sync() {
+    if let Some((sim, id)) = current() {
+        sim.step(id, "sync", Duration::from_millis(10));
+    }
+}
+
+// The virtual time, if this thread is being simulated.
+pub(crate) fn now() -> Option<Instant> {
+    let (sim, _) = current()?;
+    let clock = sim.state.lock().unwrap().clock;
+    Some(sim.start + clock)
+}
+
+fn options() -> Options {
+    Options {
+        durability: Durability::None,
+        ..Options::default()
+    }
+}
+
+// Has `threads` writers set `writes` keys each, returning the trace along with
+// the database. Every other writer doesn't wait for its writes to be synced.
+fn simulate(seed: u64, threads: usize, writes: usize) -> Result<(Vec<(usize, &'static str)>, Db)> {
+    simulate_with(seed, threads, writes, options())
+}
+
+fn simulate_with(
+    seed: u64,
+    threads: usize,
+    writes: usize,
+    options: Options,
+) -> Result<(Vec<(usize, &'static str)>, Db)> {
+    let dir = tempdir()?;
+    let db = Db::with_options(dir.path(), options)?;
+    let sim = Sim::new(seed);
+    let writers = (0..threads)
+        .map(|t| {
+            let mut db = db.clone();
+            let options = WriteOptions {
+                sync: t % 2 == 0,
+                ..WriteOptions::default()
+            };
+            sim.spawn(move || -> Result<()> {
+                for i in 0..writes {
+                    db.set_with_options(&format!("{}_{}", t, i), &format!("v{}", i), &options)?;
+                }
+                Ok(())
+            })
+        })
+        .collect::<Vec<_>>();
+    let trace = sim.run();
+    for writer in writers {
+        writer.join().unwrap()?;
+    }
+    for t in 0..threads {
+        for i in 0..writes {
+            assert_eq!(
+                db.get(&format!("{}_{}", t, i)),
+                Some(format!("v{}", i)),
+                "seed {}",
+                seed
+            );
+        }
+    }
+    Ok((trace, db))
+}
+
+#[test]
+fn test_sim_replays_from_seed() -> Result<()> {
+    for seed in 1..20 {
+        let (first, a) = simulate(seed, 4, 5)?;
+        let (second, b) = simulate(seed, 4, 5)?;
+        assert_eq!(first, second, "seed {}", seed);
+        let (a, b) = (a.metrics(), b.metrics());
+        assert_eq!(a.batch_sizes, b.batch_sizes);
+        assert_eq!(a.sync_latency, b.sync_latency);
+    }
+    let traces = (1..20)
+        .map(|seed| Ok(simulate(seed, 4, 5)?.0))
+        .collect::<Result<BTreeSet<_>>>()?;
+    assert!(traces.len() > 1);
+    Ok(())
+}
+
+#[test]
+fn test_sim_group_commit() -> Result<()> {
+    let mut largest = 0;
+    for seed in 1..200 {
+        let (_, db) = simulate(seed, 1 + seed as usize % 8, 10)?;
+        let metrics = db.metrics();
+        assert_eq!(metrics.commands, 10 * (1 + seed % 8), "seed {}", seed);
+        largest = largest.max(metrics.largest_batch);
+    }
+    // Some writers had to have joined someone else's batch.
+    assert!(largest > 1);
+    Ok(())
+}
+
+// The 8-writer workload, where half the writers ride on the others' syncs.
+// Writers join batches in the order they show up, so each one waits for at
+// most the batch ahead of its own and then its own, though the simulation
+// can take another sync's worth of time to get back to it after that.
+#[test]
+fn test_sim_write_wait() -> Result<()> {
+    for seed in 1..50 {
+        let (_, db) = simulate(seed, 8, 20)?;
+        let wait = db.metrics().write_wait;
+        assert_eq!(wait.count(), 8 * 20, "seed {}", seed);
+        // Three syncs of up to 10ms, rounded up to a bucket.
+        assert!(wait.quantile(0.99) <= 32_768, "seed {}: {:?}", seed, wait);
+    }
+    Ok(())
+}
+
+#[test]
+fn test_sim_backpressure_block() -> Result<()> {
+    for seed in 1..50 {
+        let options = Options {
+            max_pending_writes: Some(3),
+            ..options()
+        };
+        // Everything still gets written, just never more than three writes'
+        // worth at once.
+        let (trace, db) = simulate_with(seed, 8, 10, options)?;
+        assert!(db.metrics().largest_batch <= 3, "seed {}", seed);
+        assert!(trace.iter().any(|&(_, step)| step == "backoff"));
+    }
+    Ok(())
+}
+
+#[test]
+fn test_sim_backpressure_fail() -> Result<()> {
+    let mut turned_away = 0;
+    for seed in 1..50 {
+        let dir = tempdir()?;
+        let db = Db::with_options(
+            dir.path(),
+            Options {
+                max_pending_writes: Some(3),
+                backpressure: Backpressure::Fail,
+                ..options()
+            },
+        )?;
+        let sim = Sim::new(seed);
+        let writers = (0..8)
+            .map(|t| {
+                let mut db = db.clone();
+                sim.spawn(move || -> Result<Vec<bool>> {
+                    (0..10)
+                        .map(|i| match db.set(&format!("{}_{}", t, i), "v") {
+                            Ok(()) => Ok(true),
+                            Err(Error::Overloaded { pending }) => {
+                                assert_eq!(pending, 3);
+                                Ok(false)
+                            }
+                            Err(e) => Err(e),
+                        })
+                        .collect()
+                })
+            })
+            .collect::<Vec<_>>();
+        sim.run();
+        for (t, writer) in writers.into_iter().enumerate() {
+            for (i, written) in writer.join().unwrap()?.into_iter().enumerate() {
+                // Writes that were turned away left nothing behind.
+                let expected = written.then(|| "v".to_string());
+                assert_eq!(db.get(&format!("{}_{}", t, i)), expected, "seed {}", seed);
+                turned_away += usize::from(!written);
+            }
+        }
+        assert!(db.metrics().largest_batch <= 3, "seed {}", seed);
+    }
+    assert!(turned_away > 0);
+    Ok(())
+}
+
+// Batches to a sharded log are synced outside the log lock, so the next one
+// can be written meanwhile, but they still have to finish in order.
+#[test]
+fn test_sim_log_shards() -> Result<()> {
+    for seed in 1..50 {
+        let options = Options {
+            log_shards: 2,
+            ..options()
+        };
+        let (first, db) = simulate_with(seed, 8, 10, options.clone())?;
+        assert_eq!(db.metrics().commands, 80, "seed {}", seed);
+        let (second, _) = simulate_with(seed, 8, 10, options)?;
+        assert_eq!(first, second, "seed {}", seed);
+    }
+    Ok(())
+}
+
+// With pipelined commits, a batch is written while the one before it syncs,
+// but they're still synced, applied and acknowledged in order.
+#[test]
+fn test_sim_pipeline_commits() -> Result<()> {
+    for seed in 1..50 {
+        let options = Options {
+            pipeline_commits: true,
+            ..options()
+        };
+        let (first, db) = simulate_with(seed, 8, 10, options.clone())?;
+        assert_eq!(db.metrics().commands, 80, "seed {}", seed);
+        let (second, _) = simulate_with(seed, 8, 10, options)?;
+        assert_eq!(first, second, "seed {}", seed);
+    }
+    Ok(())
+}
+
+// Batches staged while they sync can't be seen until they're synced: a reader
+// running while the leader is in the middle of a sync never sees more keys
+// than have been committed.
+#[test]
+fn test_sim_apply_during_sync() -> Result<()> {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    for seed in 1..50 {
+        let dir = tempdir()?;
+        let options = Options {
+            apply_during_sync: Some(1),
+            memtable_shards: 4,
+            ..options()
+        };
+        let db = Db::with_options(dir.path(), options)?;
+        let sim = Sim::new(seed);
+        let finished = Arc::new(AtomicUsize::new(0));
+        let writers = (0..4)
+            .map(|t| {
+                let mut db = db.clone();
+                let finished = finished.clone();
+                sim.spawn(move || -> Result<()> {
+                    for i in 0..10 {
+                        db.set(&format!("{}_{}", t, i), "v")?;
+                    }
+                    finished.fetch_add(1, Ordering::SeqCst);
+                    Ok(())
+                })
+            })
+            .collect::<Vec<_>>();
+        let reader = {
+            let db = db.clone();
+            sim.spawn(move || -> Result<()> {
+                while finished.load(Ordering::SeqCst) < 4 {
+                    let visible = db.len() as u64;
+                    let committed = db.feed.lock()?.durable_seq();
+                    assert!(visible <= committed, "seed {}", seed);
+                    step("read");
+                }
+                Ok(())
+            })
+        };
+        sim.run();
+        for writer in writers {
+            writer.join().unwrap()?;
+        }
+        reader.join().unwrap()?;
+        assert_eq!(db.len(), 40, "seed {}", seed);
+    }
+    Ok(())
+}
+
+// A transaction or snapshot that starts while a pipelined batch is syncing,
+// after it's been logged but before it's applied, waits for it to be applied
+// rather than starting after it without seeing what it wrote. Each writer
+// makes a single write, and transactions are committed once the simulation is
+// over, since either would block for real on a log lock held across a step.
+#[test]
+fn test_sim_transaction_start() -> Result<()> {
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    for seed in 1..50 {
+        for log_shards in [1, 2] {
+            let dir = tempdir()?;
+            let options = Options {
+                log_shards,
+                pipeline_commits: true,
+                ..options()
+            };
+            let db = Db::with_options(dir.path(), options)?;
+            let sim = Sim::new(seed);
+            let done = Arc::new(AtomicBool::new(false));
+            let writer = {
+                let mut db = db.clone();
+                let done = done.clone();
+                sim.spawn(move || -> Result<()> {
+                    db.incr("k", 1)?;
+                    done.store(true, Ordering::SeqCst);
+                    Ok(())
+                })
+            };
+            let reader = {
+                let db = db.clone();
+                sim.spawn(move || -> Result<Vec<_>> {
+                    let mut txns = vec![];
+                    while !done.load(Ordering::SeqCst) {
+                        let txn = db.transaction();
+                        match txn.get("k") {
+                            Ok(v) => txns.push((v, txn)),
+                            Err(Error::Conflict { .. }) => {}
+                            Err(e) => return Err(e),
+                        }
+                        step("read");
+                    }
+                    Ok(txns)
+                })
+            };
+            sim.run();
+            writer.join().unwrap()?;
+            // Those that didn't see the increment have to conflict.
+            for (read, mut txn) in reader.join().unwrap()? {
+                if read.is_some() {
+                    continue;
+                }
+                txn.set("k", "lost");
+                let err = txn.commit().unwrap_err();
+                assert!(matches!(err, Error::Conflict { .. }), "seed {}", seed);
+            }
+            assert_eq!(db.get("k").as_deref(), Some("1"), "seed {}", seed);
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn test_sim_snapshot_start() -> Result<()> {
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    for seed in 1..50 {
+        for log_shards in [1, 2] {
+            let dir = tempdir()?;
+            let options = Options {
+                log_shards,
+                pipeline_commits: true,
+                ..options()
+            };
+            let mut db = Db::with_options(dir.path(), options)?;
+            db.set("k", "old")?;
+            let sim = Sim::new(seed);
+            let done = Arc::new(AtomicBool::new(false));
+            let writer = {
+                let mut db = db.clone();
+                let done = done.clone();
+                sim.spawn(move || -> Result<()> {
+                    db.set("k", "new")?;
+                    done.store(true, Ordering::SeqCst);
+                    Ok(())
+                })
+            };
+            let reader = {
+                let db = db.clone();
+                sim.spawn(move || -> Result<Vec<_>> {
+                    let mut snapshots = vec![];
+                    while !done.load(Ordering::SeqCst) {
+                        let snapshot = db.snapshot();
+                        snapshots.push((snapshot.try_get("k")?, snapshot));
+                        step("read");
+                    }
+                    Ok(snapshots)
+                })
+            };
+            sim.run();
+            writer.join().unwrap()?;
+            // Each one still reads what it did when it was taken.
+            for (read, snapshot) in reader.join().unwrap()? {
+                assert_eq!(snapshot.try_get("k")?, read, "seed {}", seed);
+            }
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn test_sim_quota() -> Result<()> {
+    use crate::Namespace;
+
+    // Writers racing each other into a namespace are held to its quota
+    // between them, not each to what was there before any of them.
+    for seed in 1..30 {
+        let dir = tempdir()?;
+        let options = Options {
+            pipeline_commits: true,
+            namespaces: vec![Namespace {
+                name: "acme".into(),
+                prefix: "acme/".into(),
+                max_keys: Some(2),
+                ..Namespace::default()
+            }],
+            ..options()
+        };
+        let db = Db::with_options(dir.path(), options)?;
+        let sim = Sim::new(seed);
+        let writers = (0..4)
+            .map(|i| {
+                let mut db = db.clone();
+                sim.spawn(move || db.set(&format!("acme/{}", i), "v"))
+            })
+            .collect::<Vec<_>>();
+        sim.run();
+        let mut written = 0;
+        for writer in writers {
+            match writer.join().unwrap() {
+                Ok(()) => written += 1,
+                Err(Error::QuotaExceeded { .. }) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        assert_eq!(written, 2, "seed {}", seed);
+        assert_eq!(db.stats()?.namespaces[0].keys, 2, "seed {}", seed);
+    }
+    Ok(())
+}
@@ -0,0 +1,139 @@
+#[cfg(test)]
+use crate::{table, Error, Options};
+use crate::{Db, Result};
+#[cfg(test)]
+use tempfile::tempdir;
+
+// A read-only view of a `Db` as of the moment `Db::snapshot` was called,
+// which stays put while writes carry on, so that a long scan sees a single
+// state of the database.
+//
+// While any snapshot is open, every write keeps a copy of the value it
+// replaces, so snapshots are cheap to take but shouldn't be kept open for
+// longer than they're needed. Named keyspaces aren't covered.
+#[derive(Debug)]
+pub struct Snapshot {
+    db: Db,
+    seq: u64,
+}
+
+impl Snapshot {
+    pub(crate) fn new(db: Db, seq: u64) -> Self {
+        Snapshot { db, seq }
+    }
+
+    // The sequence number of the first record the snapshot doesn't see,
+    // which `Db::get_at` accepts while the snapshot is open.
+    pub fn seq(&self) -> u64 {
+        self.seq
+    }
+
+    // Like `Db::get`, panics if a table or the value log can't be read.
+    pub fn get(&self, k: &str) -> Option<String> {
+        self.try_get(k)
+            .unwrap_or_else(|e| panic!("reading the value of {:?}: {}", k, e))
+    }
+
+    pub fn try_get(&self, k: &str) -> Result<Option<String>> {
+        self.db.read(k, Some(self.seq))
+    }
+
+    // Like `Db::scan`.
+    pub fn scan(&self, prefix: &str) -> Vec<(String, String)> {
+        self.try_scan(prefix)
+            .unwrap_or_else(|e| panic!("scanning {:?}: {}", prefix, e))
+    }
+
+    pub fn try_scan(&self, prefix: &str) -> Result<Vec<(String, String)>> {
+        let mut result = vec![];
+        self.db
+            .merged(prefix, Some(self.seq), |k, v| result.push((k, v)))?;
+        Ok(result)
+    }
+}
+
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        self.db.memtable.unpin(self.seq);
+    }
+}
+
+#[test]
+fn test_snapshot() -> Result<()> {
+    let dir = tempdir()?;
+    let file = dir.path().join("logfile");
+    let options = Options {
+        memtable_bytes: Some(1024),
+        ..Options::default()
+    };
+    let mut db = Db::with_options(&file, options)?;
+    for i in 0..20 {
+        db.set(&format!("key{:02}", i), "old")?;
+    }
+    let before = db.scan("");
+    let snapshot = db.snapshot();
+    assert!(db.get_at("key00", snapshot.seq() - 1).is_err());
+
+    // Overwrites, deletes and new keys, enough of them to flush.
+    for i in 0..20 {
+        db.set(&format!("key{:02}", i), "new")?;
+        db.set(&format!("key{:02}", i), "newer")?;
+    }
+    db.delete("key03")?;
+    db.delete_range("key10", "key15")?;
+    db.set("key99", "added")?;
+    db.incr("key98", 1)?;
+    assert!(!table::list(&file)?.is_empty());
+
+    assert_eq!(snapshot.scan(""), before);
+    assert_eq!(snapshot.get("key03"), Some("old".into()));
+    assert_eq!(snapshot.get("key12"), Some("old".into()));
+    assert_eq!(snapshot.get("key99"), None);
+    assert_eq!(db.get_at("key12", snapshot.seq())?, Some("old".into()));
+    assert_eq!(db.get("key12"), None);
+    assert_eq!(db.get("key00"), Some("newer".into()));
+
+    // A later snapshot sees what was written before it.
+    let later = db.snapshot();
+    db.set("key00", "newest")?;
+    assert_eq!(later.get("key00"), Some("newer".into()));
+    assert_eq!(snapshot.get("key00"), Some("old".into()));
+    drop(snapshot);
+    assert!(matches!(
+        db.get_at("key00", 1),
+        Err(Error::Compacted { seq: 1 })
+    ));
+    assert_eq!(
+        later.scan("key9"),
+        vec![
+            ("key98".into(), "1".into()),
+            ("key99".into(), "added".into())
+        ]
+    );
+    drop(later);
+    assert!(db.memtable.read().history_at("", 0).is_empty());
+
+    // Scans stay put while another thread writes.
+    let snapshot = db.snapshot();
+    let expected = snapshot.scan("");
+    let writer = {
+        let mut db = db.clone();
+        std::thread::spawn(move || -> Result<()> {
+            for i in 0..200 {
+                db.set(&format!("key{:02}", i % 30), &i.to_string())?;
+                if i % 10 == 0 {
+                    db.delete_prefix("key2")?;
+                }
+            }
+            Ok(())
+        })
+    };
+    while !writer.is_finished() {
+        assert_eq!(snapshot.scan(""), expected);
+    }
+    writer.join().unwrap()?;
+    assert_eq!(snapshot.scan(""), expected);
+    assert_ne!(db.scan(""), expected);
+
+    Ok(())
+}
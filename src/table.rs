@@ -0,0 +1,611 @@
+use crate::bloom::{self, Bloom};
+use crate::cache::BlockCache;
+use crate::memtable::KeyRange;
+use crate::{durable_fs, Durability, Error, Result};
+use std::{
+    fs::{self, File},
+    io::{BufWriter, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+// A key and its value, or None if it was deleted.
+pub type Entry = (String, Option<String>);
+
+// Somewhere entries come from, for `Merge`.
+pub type Source<'a> = Box<dyn Iterator<Item = Result<Entry>> + 'a>;
+
+// Every this many entries, the key and offset go in the table's index. Finding
+// a key means reading at most this many entries past the index.
+const INDEX_INTERVAL: u64 = 16;
+
+// Tables are read, and cached, a block of this many bytes at a time. The last
+// block stops short where the entries end.
+const BLOCK_LEN: u64 = 16 << 10;
+
+// The index and filter offsets, the number of entries, the sequence number
+// the table goes up to, whether the table is full (see `Table::write`), and a
+// checksum of everything before it.
+const FOOTER_LEN: u64 = 37;
+
+// A value length that means the key was deleted.
+const DELETED: u32 = u32::MAX;
+
+pub fn table_path(dir: &Path, number: u64) -> PathBuf {
+    dir.join(format!("{:020}.sst", number))
+}
+
+// Returns the numbers of the tables in `dir`, in ascending order.
+pub fn list(dir: &Path) -> Result<Vec<u64>> {
+    let mut tables = vec![];
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().and_then(|e| e.to_str()) != Some("sst") {
+            continue;
+        }
+        if let Some(Ok(n)) = path.file_stem().and_then(|s| s.to_str()).map(str::parse) {
+            tables.push(n);
+        }
+    }
+    tables.sort_unstable();
+    Ok(tables)
+}
+
+// Opens the tables in `dir` that are still in use, oldest first. Tables older
+// than the newest full one are obsolete, left behind by a crash partway through
+// a compaction.
+pub fn open_all(dir: &Path, cache: &Arc<BlockCache>, verify: bool) -> Result<Vec<Arc<Table>>> {
+    let mut tables = vec![];
+    for number in list(dir)?.into_iter().rev() {
+        let table = Table::open(dir, number, cache, verify)?;
+        let full = table.full;
+        tables.push(Arc::new(table));
+        if full {
+            break;
+        }
+    }
+    tables.reverse();
+    Ok(tables)
+}
+
+// Deletes every table in `dir` older than `first`.
+pub fn remove_before(dir: &Path, first: u64) -> Result<()> {
+    for number in list(dir)?.into_iter().filter(|&n| n < first) {
+        durable_fs::remove_file(&table_path(dir, number))?;
+    }
+    Ok(())
+}
+
+// A sorted, immutable file of entries, written when the memtable is flushed.
+// Only its index is kept in memory; entries are read from disk as they're
+// needed.
+//
+// The entries come first, each being the key length and value length as u32s
+// followed by the key and the value. Then comes the index, which is the key
+// length, key and offset of every `INDEX_INTERVAL`th entry, then the bloom
+// filter over every key, and then the footer.
+#[derive(Debug)]
+pub struct Table {
+    number: u64,
+    file: Mutex<File>,
+    cache: Arc<BlockCache>,
+    // What the table's blocks are cached under.
+    id: u64,
+    index: Vec<(String, u64)>,
+    // Where the entries end.
+    index_offset: u64,
+    filter: Bloom,
+    seq: u64,
+    full: bool,
+    // Entries, deletions included.
+    count: u64,
+    // The size of the file.
+    bytes: u64,
+}
+
+impl Table {
+    // Writes `entries`, which must be sorted by key, as table `number`. A full
+    // table holds everything in the database, so deletions are left out of it
+    // and every older table is obsolete once it's written. The table only
+    // shows up under its real name once it's complete and synced.
+    //
+    // Its bloom filter gets `bits_per_key` bits for each key, deleted ones
+    // included, or is left out with zero. `seq` is the sequence number of the
+    // first record that isn't in it.
+    pub fn write<I>(
+        dir: &Path,
+        number: u64,
+        entries: I,
+        seq: u64,
+        full: bool,
+        bits_per_key: usize,
+        cache: &Arc<BlockCache>,
+    ) -> Result<Table>
+    where
+        I: IntoIterator<Item = Result<Entry>>,
+    {
+        let tmp = dir.join(format!("{:020}.sst.tmp", number));
+        if tmp.exists() {
+            fs::remove_file(&tmp)?;
+        }
+        let mut w = ChecksumWriter {
+            w: BufWriter::new(durable_fs::create_new(&tmp)?),
+            hasher: crc32fast::Hasher::new(),
+            offset: 0,
+        };
+        let mut index = vec![];
+        let mut hashes = vec![];
+        let mut count = 0;
+        for entry in entries {
+            let (k, v) = entry?;
+            if full && v.is_none() {
+                continue;
+            }
+            if count % INDEX_INTERVAL == 0 {
+                index.push((k.clone(), w.offset));
+            }
+            if bits_per_key > 0 {
+                hashes.push(bloom::hash(&k));
+            }
+            w.write(&(k.len() as u32).to_le_bytes())?;
+            w.write(&v.as_ref().map_or(DELETED, |v| v.len() as u32).to_le_bytes())?;
+            w.write(k.as_bytes())?;
+            w.write(v.as_deref().unwrap_or("").as_bytes())?;
+            count += 1;
+        }
+        let index_offset = w.offset;
+        for (k, offset) in &index {
+            w.write(&(k.len() as u32).to_le_bytes())?;
+            w.write(k.as_bytes())?;
+            w.write(&offset.to_le_bytes())?;
+        }
+        let filter_offset = w.offset;
+        let filter = Bloom::new(&hashes, bits_per_key);
+        w.write(&filter.encode())?;
+        w.write(&index_offset.to_le_bytes())?;
+        w.write(&filter_offset.to_le_bytes())?;
+        w.write(&count.to_le_bytes())?;
+        w.write(&seq.to_le_bytes())?;
+        w.write(&[full as u8])?;
+        let crc = w.hasher.finalize();
+        let bytes = w.offset + 4;
+        let mut file = w.w.into_inner().map_err(|e| e.into_error())?;
+        file.write_all(&crc.to_le_bytes())?;
+        durable_fs::sync_file(&file, Durability::Media)?;
+        let path = table_path(dir, number);
+        durable_fs::rename(&tmp, &path)?;
+        Ok(Table {
+            number,
+            // The file was created write-only.
+            file: Mutex::new(File::open(path)?),
+            cache: cache.clone(),
+            id: cache.new_id(),
+            index,
+            index_offset,
+            filter,
+            seq,
+            full,
+            count,
+            bytes,
+        })
+    }
+
+    // Opens table `number` and reads its index. With `verify`, the whole file
+    // is read first to check it against its checksum, which is most of the
+    // cost of opening a big table.
+    pub fn open(dir: &Path, number: u64, cache: &Arc<BlockCache>, verify: bool) -> Result<Table> {
+        let corrupt = |reason: &str| Error::CorruptTable {
+            table: number,
+            reason: reason.into(),
+        };
+        let mut file = File::open(table_path(dir, number))?;
+        let len = file.metadata()?.len();
+        if len < FOOTER_LEN {
+            return Err(corrupt("too short"));
+        }
+        if verify {
+            let mut hasher = crc32fast::Hasher::new();
+            let mut buf = vec![0; BLOCK_LEN as usize];
+            let mut remaining = len - 4;
+            while remaining > 0 {
+                let n = remaining.min(BLOCK_LEN) as usize;
+                file.read_exact(&mut buf[..n])?;
+                hasher.update(&buf[..n]);
+                remaining -= n as u64;
+            }
+            let mut crc = [0; 4];
+            file.read_exact(&mut crc)?;
+            if hasher.finalize() != u32::from_le_bytes(crc) {
+                return Err(corrupt("checksum mismatch"));
+            }
+        }
+
+        file.seek(SeekFrom::Start(len - FOOTER_LEN))?;
+        let mut footer = [0; FOOTER_LEN as usize];
+        file.read_exact(&mut footer)?;
+        let index_offset = u64::from_le_bytes(footer[0..8].try_into().unwrap());
+        let filter_offset = u64::from_le_bytes(footer[8..16].try_into().unwrap());
+        let count = u64::from_le_bytes(footer[16..24].try_into().unwrap());
+        let seq = u64::from_le_bytes(footer[24..32].try_into().unwrap());
+        let full = footer[32] != 0;
+        if index_offset > filter_offset || filter_offset > len - FOOTER_LEN {
+            return Err(corrupt("index or filter offset out of range"));
+        }
+        file.seek(SeekFrom::Start(index_offset))?;
+        let mut raw = vec![0; (len - FOOTER_LEN - index_offset) as usize];
+        file.read_exact(&mut raw)?;
+        let (raw_index, raw_filter) = raw.split_at((filter_offset - index_offset) as usize);
+        let index = parse_index(raw_index).ok_or_else(|| corrupt("bad index"))?;
+        let filter = Bloom::decode(raw_filter).ok_or_else(|| corrupt("bad filter"))?;
+        Ok(Table {
+            number,
+            file: Mutex::new(file),
+            cache: cache.clone(),
+            id: cache.new_id(),
+            index,
+            index_offset,
+            filter,
+            seq,
+            full,
+            count,
+            bytes: len,
+        })
+    }
+
+    pub fn number(&self) -> u64 {
+        self.number
+    }
+
+    // The sequence number of the first record that isn't in the table.
+    pub fn seq(&self) -> u64 {
+        self.seq
+    }
+
+    // How many entries it has, deletions included.
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn bytes(&self) -> u64 {
+        self.bytes
+    }
+
+    // Roughly how many entries are in `range`, deletions included, and how
+    // many bytes they take up, going by the index alone. Each end of the
+    // range can be out by up to `INDEX_INTERVAL` entries.
+    pub fn approximate(&self, range: &KeyRange) -> (u64, u64) {
+        let first = self
+            .index
+            .partition_point(|(k, _)| k.as_str() < range.start());
+        let end = first + self.index[first..].partition_point(|(k, _)| range.contains(k));
+        let at = |i: usize| match self.index.get(i) {
+            Some((_, offset)) => (i as u64 * INDEX_INTERVAL, *offset),
+            None => (self.count, self.index_offset),
+        };
+        let ((first_entry, first_offset), (end_entry, end_offset)) = (at(first), at(end));
+        (end_entry - first_entry, end_offset - first_offset)
+    }
+
+    // `Some(None)` if the table has the key as deleted.
+    pub fn get(&self, k: &str) -> Result<Option<Option<String>>> {
+        if !self.filter.may_contain(bloom::hash(k)) {
+            return Ok(None);
+        }
+        for entry in self.iter_from(k) {
+            let (key, value) = entry?;
+            if key == k {
+                return Ok(Some(value));
+            }
+            if key.as_str() > k {
+                break;
+            }
+        }
+        Ok(None)
+    }
+
+    // Iterates over the entries in key order, starting from the first one at
+    // or after `start`.
+    pub fn iter_from(&self, start: &str) -> TableIter<'_> {
+        // The last indexed entry before `start`, from which we read forward.
+        let i = self.index.partition_point(|(k, _)| k.as_str() < start);
+        let pos = match i {
+            0 => 0,
+            i => self.index[i - 1].1,
+        };
+        TableIter {
+            table: self,
+            start: start.to_string(),
+            pos,
+            block: None,
+            scratch: vec![],
+        }
+    }
+
+    // Block `n`, from the cache if it's there.
+    fn block(&self, n: u64) -> Result<Arc<[u8]>> {
+        self.cache.get(self.id, n, || {
+            let start = n * BLOCK_LEN;
+            let mut buf = vec![];
+            self.read_at(
+                start,
+                BLOCK_LEN.min(self.index_offset - start) as usize,
+                &mut buf,
+            )?;
+            Ok(buf)
+        })
+    }
+
+    fn read_at(&self, offset: u64, len: usize, buf: &mut Vec<u8>) -> Result<()> {
+        let mut file = self.file.lock()?;
+        file.seek(SeekFrom::Start(offset))?;
+        buf.resize(len, 0);
+        file.read_exact(buf)?;
+        Ok(())
+    }
+}
+
+fn parse_index(mut raw: &[u8]) -> Option<Vec<(String, u64)>> {
+    let mut index = vec![];
+    while !raw.is_empty() {
+        let key_len = u32::from_le_bytes(raw.get(..4)?.try_into().ok()?) as usize;
+        let key = String::from_utf8(raw.get(4..4 + key_len)?.to_vec()).ok()?;
+        let offset = u64::from_le_bytes(raw.get(4 + key_len..12 + key_len)?.try_into().ok()?);
+        index.push((key, offset));
+        raw = &raw[12 + key_len..];
+    }
+    Some(index)
+}
+
+pub struct TableIter<'a> {
+    table: &'a Table,
+    // Entries before this are skipped.
+    start: String,
+    pos: u64,
+    // The block `pos` was last in, and its number.
+    block: Option<(u64, Arc<[u8]>)>,
+    // Where an entry that spans blocks is put back together.
+    scratch: Vec<u8>,
+}
+
+impl TableIter<'_> {
+    // Makes sure `len` bytes from `pos` are in the buffer, and returns them.
+    fn bytes(&mut self, len: u64) -> Result<&[u8]> {
+        let end = self.table.index_offset;
+        if self.pos + len > end {
+            return Err(Error::CorruptTable {
+                table: self.table.number,
+                reason: format!("entry at offset {} runs past the index", self.pos),
+            });
+        }
+        let first = self.pos / BLOCK_LEN;
+        let last = (self.pos + len - 1) / BLOCK_LEN;
+        if first == last {
+            if self.block.as_ref().map(|(n, _)| *n) != Some(first) {
+                self.block = Some((first, self.table.block(first)?));
+            }
+            let (_, block) = self.block.as_ref().unwrap();
+            let start = (self.pos - first * BLOCK_LEN) as usize;
+            return Ok(&block[start..start + len as usize]);
+        }
+        self.scratch.clear();
+        for n in first..=last {
+            let block = self.table.block(n)?;
+            let block_start = n * BLOCK_LEN;
+            let from = self.pos.max(block_start) - block_start;
+            let to = (self.pos + len).min(block_start + block.len() as u64) - block_start;
+            self.scratch
+                .extend_from_slice(&block[from as usize..to as usize]);
+        }
+        Ok(&self.scratch)
+    }
+
+    fn read_entry(&mut self) -> Result<Entry> {
+        let lens = self.bytes(8)?;
+        let key_len = u32::from_le_bytes(lens[0..4].try_into().unwrap()) as u64;
+        let value_len = u32::from_le_bytes(lens[4..8].try_into().unwrap());
+        let deleted = value_len == DELETED;
+        let value_len = if deleted { 0 } else { value_len as u64 };
+        let raw = self.bytes(8 + key_len + value_len)?;
+        let (key, value) = raw[8..].split_at(key_len as usize);
+        let entry = match (std::str::from_utf8(key), std::str::from_utf8(value)) {
+            (Ok(key), Ok(value)) => (key.to_string(), (!deleted).then(|| value.to_string())),
+            _ => {
+                return Err(Error::CorruptTable {
+                    table: self.table.number,
+                    reason: format!("entry at offset {} isn't UTF-8", self.pos),
+                })
+            }
+        };
+        self.pos += 8 + key_len + value_len;
+        Ok(entry)
+    }
+}
+
+impl Iterator for TableIter<'_> {
+    type Item = Result<Entry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.pos < self.table.index_offset {
+            match self.read_entry() {
+                Ok((k, _)) if k < self.start => continue,
+                Ok(entry) => return Some(Ok(entry)),
+                Err(e) => {
+                    // Don't keep going after a bad entry.
+                    self.pos = self.table.index_offset;
+                    return Some(Err(e));
+                }
+            }
+        }
+        None
+    }
+}
+
+struct ChecksumWriter {
+    w: BufWriter<File>,
+    hasher: crc32fast::Hasher,
+    offset: u64,
+}
+
+impl ChecksumWriter {
+    fn write(&mut self, buf: &[u8]) -> Result<()> {
+        self.w.write_all(buf)?;
+        self.hasher.update(buf);
+        self.offset += buf.len() as u64;
+        Ok(())
+    }
+}
+
+// Merges sorted sources of entries into a single sorted stream in which each
+// key appears once. Where several sources have the same key, the entry from
+// the first of them wins, so sources should be ordered newest first.
+pub struct Merge<'a> {
+    sources: Vec<Source<'a>>,
+    heads: Vec<Option<Entry>>,
+    started: bool,
+}
+
+impl<'a> Merge<'a> {
+    pub fn new(sources: Vec<Source<'a>>) -> Self {
+        let heads = sources.iter().map(|_| None).collect();
+        Merge {
+            sources,
+            heads,
+            started: false,
+        }
+    }
+
+    fn advance(&mut self, i: usize) -> Result<()> {
+        self.heads[i] = self.sources[i].next().transpose()?;
+        Ok(())
+    }
+
+    fn next_entry(&mut self) -> Result<Option<Entry>> {
+        if !self.started {
+            self.started = true;
+            for i in 0..self.sources.len() {
+                self.advance(i)?;
+            }
+        }
+        let first = (0..self.heads.len())
+            .filter_map(|i| self.heads[i].as_ref().map(|(k, _)| (k, i)))
+            .min()
+            .map(|(_, i)| i);
+        let i = match first {
+            Some(i) => i,
+            None => return Ok(None),
+        };
+        let entry = self.heads[i].take().unwrap();
+        self.advance(i)?;
+        // Older versions of the same key lose.
+        for j in i + 1..self.heads.len() {
+            if self.heads[j].as_ref().is_some_and(|(k, _)| *k == entry.0) {
+                self.advance(j)?;
+            }
+        }
+        Ok(Some(entry))
+    }
+}
+
+impl Iterator for Merge<'_> {
+    type Item = Result<Entry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_entry().transpose()
+    }
+}
+
+#[test]
+fn test_table() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let cache = Arc::new(BlockCache::new(1 << 20));
+    let entries = (0..100)
+        .map(|i| {
+            let value = (i % 10 != 0).then(|| "v".repeat(i));
+            Ok((format!("key{:03}", i), value))
+        })
+        .collect::<Vec<_>>();
+    let written = Table::write(dir.path(), 1, entries, 12, false, 10, &cache)?;
+    assert_eq!(written.index.len(), 7);
+
+    let table = Table::open(dir.path(), 1, &cache, true)?;
+    assert_eq!(table.index, written.index);
+    assert_eq!(table.filter, written.filter);
+    assert_eq!(table.seq(), 12);
+    assert!(table.filter.may_contain(bloom::hash("key040")));
+    assert_eq!(table.get("key042")?, Some(Some("v".repeat(42))));
+    assert_eq!(table.get("key040")?, Some(None));
+    assert_eq!(table.get("key0421")?, None);
+    assert_eq!(table.get("zzz")?, None);
+    let keys = table
+        .iter_from("key095")
+        .map(|e| e.map(|(k, _)| k))
+        .collect::<Result<Vec<_>>>()?;
+    assert_eq!(keys, ["key095", "key096", "key097", "key098", "key099"]);
+
+    // A full table leaves deletions out.
+    let entries = vec![Ok(("a".into(), None)), Ok(("b".into(), Some("1".into())))];
+    let full = Table::write(dir.path(), 2, entries, 13, true, 0, &cache)?;
+    assert_eq!(
+        Table::open(dir.path(), 2, &cache, true)?.filter,
+        Bloom::default()
+    );
+    assert_eq!(full.get("b")?, Some(Some("1".into())));
+    assert_eq!(full.iter_from("").count(), 1);
+    assert_eq!(open_all(dir.path(), &cache, true)?.len(), 1);
+    remove_before(dir.path(), 2)?;
+    assert_eq!(list(dir.path())?, vec![2]);
+
+    // Entries can span blocks.
+    let big = "x".repeat(40_000);
+    let entries = vec![
+        Ok(("a".into(), Some(big.clone()))),
+        Ok(("b".into(), Some(big.clone()))),
+        Ok(("c".into(), None)),
+    ];
+    let spanning = Table::write(dir.path(), 3, entries, 14, false, 10, &cache)?;
+    assert_eq!(spanning.get("b")?, Some(Some(big)));
+    assert_eq!(spanning.get("c")?, Some(None));
+    assert_eq!(spanning.iter_from("").count(), 3);
+
+    let path = table_path(dir.path(), 2);
+    let mut bytes = fs::read(&path)?;
+    bytes[3] ^= 1;
+    fs::write(&path, bytes)?;
+    let err = Table::open(dir.path(), 2, &cache, true).unwrap_err();
+    assert!(
+        matches!(err, Error::CorruptTable { table: 2, .. }),
+        "{}",
+        err
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_merge() -> Result<()> {
+    let source = |entries: &[(&str, Option<&str>)]| -> Source {
+        let entries = entries
+            .iter()
+            .map(|(k, v)| Ok((k.to_string(), v.map(str::to_string))))
+            .collect::<Vec<_>>();
+        Box::new(entries.into_iter())
+    };
+    let merged = Merge::new(vec![
+        source(&[("b", None), ("d", Some("new"))]),
+        source(&[]),
+        source(&[("a", Some("1")), ("b", Some("2")), ("d", Some("old"))]),
+        source(&[("c", Some("3")), ("d", Some("older"))]),
+    ])
+    .collect::<Result<Vec<_>>>()?;
+    assert_eq!(
+        merged,
+        vec![
+            ("a".into(), Some("1".into())),
+            ("b".into(), None),
+            ("c".into(), Some("3".into())),
+            ("d".into(), Some("new".into())),
+        ]
+    );
+
+    Ok(())
+}
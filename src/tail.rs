@@ -0,0 +1,120 @@
+use crate::cache::BlockCache;
+use crate::codec::{self, Decoder};
+use crate::memtable::{Memtable, ShardedMemtable};
+use crate::replay::Flushed;
+use crate::segment::{self, SegmentReader};
+use crate::table::{self, Table};
+use crate::{Db, MemtableKind, Result};
+use std::{
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+// How far a read-only `Db` has got through a log that another process may
+// still be writing to.
+#[derive(Debug, Default)]
+pub(crate) struct Tail {
+    // The first segment in the log when we last read it from the start. Once
+    // that segment is gone, a compaction or a flush has retired it, and the
+    // log has to be read from the start again, along with the tables, to pick
+    // up whatever it deleted.
+    first: Option<u64>,
+    segment: u64,
+    offset: u64,
+    next_seq: u64,
+}
+
+impl Tail {
+    // Applies whatever has been written to the log in `dir` since the last
+    // call to `memtable`, and returns the number of records read.
+    pub fn refresh(
+        &mut self,
+        dir: &Path,
+        memtable: &ShardedMemtable,
+        tables: &Mutex<Vec<Arc<Table>>>,
+        cache: &Arc<BlockCache>,
+    ) -> Result<usize> {
+        // A segment can be retired between listing it and opening it, in which
+        // case we start over. Compactions don't happen often enough to lose
+        // that race many times in a row.
+        let mut attempts = 0;
+        loop {
+            attempts += 1;
+            match self.try_refresh(dir, memtable, tables, cache) {
+                Err(e) if attempts < 3 && e.is_not_found() => self.first = None,
+                result => return result,
+            }
+        }
+    }
+
+    fn try_refresh(
+        &mut self,
+        dir: &Path,
+        memtable: &ShardedMemtable,
+        tables: &Mutex<Vec<Arc<Table>>>,
+        cache: &Arc<BlockCache>,
+    ) -> Result<usize> {
+        let (segments, _) = segment::list(dir)?;
+        if segments.first() == self.first.as_ref() {
+            let mut memtable = memtable.write()?;
+            let tables = tables.lock()?;
+            let mut flushed = Flushed::new(&[]);
+            return self.read(dir, &segments, &tables, &mut flushed, memtable.unsharded());
+        }
+        // Build the new memtable off to the side, so that readers see the old
+        // one until it's ready.
+        let mut tail = Tail {
+            first: segments.first().copied(),
+            ..Tail::default()
+        };
+        // A flush writes its table before it retires any segments, so listing
+        // the tables after the segments can't miss any. It might pick up a
+        // table that holds some of what's in the segments too, which
+        // `Flushed` takes care of, as it does for replay.
+        let fresh_tables = table::open_all(dir, cache, true)?;
+        let mut fresh = Memtable::new(MemtableKind::Hash, !fresh_tables.is_empty());
+        let mut flushed = Flushed::new(&fresh_tables);
+        let read = tail.read(dir, &segments, &fresh_tables, &mut flushed, &mut fresh)?;
+        flushed.reach(u64::MAX, &mut fresh);
+        let mut memtable = memtable.write()?;
+        *tables.lock()? = fresh_tables;
+        *memtable.unsharded() = fresh;
+        *self = tail;
+        Ok(read)
+    }
+
+    fn read(
+        &mut self,
+        dir: &Path,
+        segments: &[u64],
+        tables: &[Arc<Table>],
+        flushed: &mut Flushed,
+        memtable: &mut Memtable,
+    ) -> Result<usize> {
+        let mut buf = vec![];
+        let mut read = 0;
+        let start = self.segment;
+        for &number in segments.iter().filter(|&&n| n >= start) {
+            let mut reader = if number == self.segment {
+                SegmentReader::resume(dir, number, self.offset, self.next_seq)?
+            } else {
+                SegmentReader::open(dir, number)?
+            };
+            // The writer may not have written the header of a brand new
+            // segment yet. Skip it for now, and look again next time.
+            if reader.first_seq().is_none() {
+                continue;
+            }
+            let decoder = Decoder::new(reader.codec(), &codec::Binary)?;
+            while let Some((offset, seq)) = reader.next_record(&mut buf)? {
+                flushed.reach(seq, memtable);
+                Db::replay_command(memtable, tables, decoder.decode(number, offset, &buf)?)?;
+                read += 1;
+            }
+            self.segment = number;
+            self.offset = reader.offset();
+            self.next_seq = reader.next_seq();
+        }
+        Ok(read)
+    }
+}
@@ -0,0 +1,371 @@
+use crate::codec::{self, Decoder, RecordCodec};
+use crate::log::Log;
+use crate::segment::{self, SegmentReader};
+use crate::{Command, Error, LogOffset, Result};
+use std::{
+    io,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+#[cfg(test)]
+use tempfile::tempdir;
+
+// Follows a log directory that another process is writing to, yielding each
+// record once it has been written. Iterating blocks until the next record
+// shows up; `try_next` doesn't.
+//
+// Records are yielded exactly as they appear in the log, so a compaction shows
+// up as a run of sets restating every live key. If the tailer falls so far
+// behind that a compaction retires segments it hasn't read yet, the records in
+// them are gone, and it stops with an error.
+#[derive(Debug)]
+pub struct LogTailer {
+    dir: PathBuf,
+    // The segment being read, and how far into it we've got.
+    segment: Option<u64>,
+    offset: u64,
+    // The sequence number we expect next, or None if anything will do.
+    next_seq: Option<u64>,
+    // Records before this are read but not yielded.
+    skip_before: u64,
+    buf: Vec<u8>,
+    codec: Arc<dyn RecordCodec>,
+    watcher: Watcher,
+    poll_interval: Duration,
+    failed: bool,
+}
+
+impl LogTailer {
+    // Starts from the oldest record still in the log.
+    pub fn open<P>(dir: P) -> Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        Self::open_from(dir, 0)
+    }
+
+    // Starts from record `seq`, for picking up where an earlier tailer left
+    // off. Fails once it gets going if `seq` has already been compacted away.
+    pub fn open_from<P>(dir: P, seq: u64) -> Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        let dir = dir.as_ref().to_path_buf();
+        if !dir.is_dir() {
+            return Err(Error::InvalidConfig(format!(
+                "{} is not a log directory",
+                dir.display()
+            )));
+        }
+        if Log::is_sharded(&dir) {
+            return Err(Error::InvalidConfig(format!(
+                "{} is a sharded log, which can't be tailed",
+                dir.display()
+            )));
+        }
+        let watcher = Watcher::new(&dir)?;
+        Ok(LogTailer {
+            dir,
+            segment: None,
+            offset: 0,
+            next_seq: None,
+            skip_before: seq,
+            buf: vec![],
+            codec: Arc::new(codec::Binary),
+            watcher,
+            poll_interval: Duration::from_millis(100),
+            failed: false,
+        })
+    }
+
+    // How long to wait between looking for new records, when there's no way
+    // to be told about them. Defaults to 100ms.
+    pub fn poll_interval(mut self, interval: Duration) -> Self {
+        self.poll_interval = interval;
+        self
+    }
+
+    // Reads segments in `codec` as well as the built-in ones.
+    pub fn with_codec(mut self, codec: Arc<dyn RecordCodec>) -> Self {
+        self.codec = codec;
+        self
+    }
+
+    // Returns the next record if it has been written yet.
+    pub fn try_next(&mut self) -> Result<Option<(LogOffset, u64, Command)>> {
+        if self.failed {
+            return Ok(None);
+        }
+        let result = self.next_record();
+        if result.is_err() {
+            self.failed = true;
+        }
+        result
+    }
+
+    fn next_record(&mut self) -> Result<Option<(LogOffset, u64, Command)>> {
+        loop {
+            if let Some(number) = self.segment {
+                match self.read_from(number) {
+                    Ok(Some(record)) => return Ok(Some(record)),
+                    Ok(None) => {}
+                    // Compaction retired it. If we'd finished with it there's
+                    // no harm done; if not, the sequence numbers will tell.
+                    Err(e) if e.is_not_found() => {}
+                    Err(e) => return Err(e),
+                }
+            }
+            // We've read everything in this segment so far. If the writer has
+            // moved on to a later one, this one is finished, but it may have
+            // been written to since we last looked, so check it once more.
+            let (segments, _) = segment::list(&self.dir)?;
+            let next = match segments.into_iter().find(|&n| Some(n) > self.segment) {
+                Some(next) => next,
+                None => return Ok(None),
+            };
+            if let Some(number) = self.segment {
+                match self.read_from(number) {
+                    Ok(Some(record)) => return Ok(Some(record)),
+                    Ok(None) => {}
+                    Err(e) if e.is_not_found() => {}
+                    Err(e) => return Err(e),
+                }
+            }
+            let reader = match SegmentReader::open(&self.dir, next) {
+                Ok(reader) => reader,
+                Err(e) if e.is_not_found() => continue,
+                Err(e) => return Err(e),
+            };
+            let first_seq = match reader.first_seq() {
+                Some(first_seq) => first_seq,
+                // Either it's empty for good, or the writer hasn't written its
+                // header yet. Only the last segment can be the latter.
+                None => {
+                    let (segments, _) = segment::list(&self.dir)?;
+                    if segments.last() == Some(&next) {
+                        return Ok(None);
+                    }
+                    self.segment = Some(next);
+                    self.offset = reader.offset();
+                    continue;
+                }
+            };
+            let expected = self.next_seq.unwrap_or(first_seq).max(self.skip_before);
+            if first_seq > expected {
+                return Err(Error::Compacted { seq: expected });
+            }
+            self.segment = Some(next);
+            self.offset = reader.offset();
+            self.next_seq = Some(first_seq);
+        }
+    }
+
+    // Reads the next record from segment `number`, reopening it so that we see
+    // whatever has been written since we last looked.
+    fn read_from(&mut self, number: u64) -> Result<Option<(LogOffset, u64, Command)>> {
+        let next_seq = match self.next_seq {
+            Some(next_seq) => next_seq,
+            None => return Ok(None),
+        };
+        let mut reader = SegmentReader::resume(&self.dir, number, self.offset, next_seq)?;
+        while let Some((offset, seq)) = reader.next_record(&mut self.buf)? {
+            self.offset = reader.offset();
+            self.next_seq = Some(reader.next_seq());
+            if seq < self.skip_before {
+                continue;
+            }
+            let offset = LogOffset {
+                segment: number,
+                offset,
+            };
+            let decoder = Decoder::new(reader.codec(), &*self.codec)?;
+            let command = decoder.decode(number, offset.offset, &self.buf)?;
+            return Ok(Some((offset, seq, command)));
+        }
+        Ok(None)
+    }
+}
+
+impl Iterator for LogTailer {
+    type Item = Result<(LogOffset, u64, Command)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            match self.try_next() {
+                Ok(Some(record)) => return Some(Ok(record)),
+                Ok(None) if self.failed => return None,
+                Ok(None) => {}
+                Err(e) => return Some(Err(e)),
+            }
+            if let Err(e) = self.watcher.wait(self.poll_interval) {
+                self.failed = true;
+                return Some(Err(e));
+            }
+        }
+    }
+}
+
+// Wakes the tailer up when something in the log directory changes. On Linux
+// this is inotify; elsewhere it just sleeps for the poll interval. Even with
+// inotify we give up waiting after the poll interval, in case a change slips
+// between looking at the log and starting to wait.
+#[cfg(target_os = "linux")]
+#[derive(Debug)]
+struct Watcher {
+    fd: std::os::fd::OwnedFd,
+}
+
+#[cfg(target_os = "linux")]
+impl Watcher {
+    fn new(dir: &Path) -> Result<Self> {
+        use std::os::{fd::FromRawFd, unix::ffi::OsStrExt};
+        let fd = unsafe { libc::inotify_init1(libc::IN_NONBLOCK | libc::IN_CLOEXEC) };
+        if fd == -1 {
+            return Err(io::Error::last_os_error().into());
+        }
+        let fd = unsafe { std::os::fd::OwnedFd::from_raw_fd(fd) };
+        let path = std::ffi::CString::new(dir.as_os_str().as_bytes())
+            .map_err(|e| Error::InvalidConfig(e.to_string()))?;
+        let mask = libc::IN_MODIFY | libc::IN_CREATE | libc::IN_MOVED_TO | libc::IN_DELETE;
+        if unsafe { libc::inotify_add_watch(fd_of(&fd), path.as_ptr(), mask) } == -1 {
+            return Err(io::Error::last_os_error().into());
+        }
+        Ok(Watcher { fd })
+    }
+
+    fn wait(&mut self, timeout: Duration) -> Result<()> {
+        let mut pollfd = libc::pollfd {
+            fd: fd_of(&self.fd),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        let millis = timeout.as_millis().min(i32::MAX as u128) as i32;
+        if unsafe { libc::poll(&mut pollfd, 1, millis) } == -1 {
+            let e = io::Error::last_os_error();
+            if e.kind() != io::ErrorKind::Interrupted {
+                return Err(e.into());
+            }
+        }
+        // We only care that something happened, not what, so throw the events
+        // away.
+        let mut buf = [0u8; 4096];
+        loop {
+            let n = unsafe { libc::read(fd_of(&self.fd), buf.as_mut_ptr().cast(), buf.len()) };
+            if n <= 0 {
+                return Ok(());
+            }
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn fd_of(fd: &std::os::fd::OwnedFd) -> i32 {
+    use std::os::fd::AsRawFd;
+    fd.as_raw_fd()
+}
+
+#[cfg(not(target_os = "linux"))]
+#[derive(Debug)]
+struct Watcher;
+
+#[cfg(not(target_os = "linux"))]
+impl Watcher {
+    fn new(_dir: &Path) -> Result<Self> {
+        Ok(Watcher)
+    }
+
+    fn wait(&mut self, timeout: Duration) -> Result<()> {
+        std::thread::sleep(timeout);
+        Ok(())
+    }
+}
+
+#[test]
+fn test_tailer() -> Result<()> {
+    let dir = tempdir()?;
+    let file = dir.path().to_path_buf().join("logfile");
+    let options = crate::Options {
+        segment_size: 256,
+        ..crate::Options::default()
+    };
+
+    let mut db = crate::Db::with_options(&file, options)?;
+    db.set("before", "open")?;
+    let mut tailer = LogTailer::open(&file)?;
+    let (tx, rx) = std::sync::mpsc::channel();
+    std::thread::spawn(move || {
+        for record in LogTailer::open_from(&file, 3)? {
+            if tx.send(record?).is_err() {
+                break;
+            }
+        }
+        Ok::<_, Error>(())
+    });
+
+    for i in 0..20 {
+        db.set(&format!("key{}", i), "after")?;
+    }
+    db.delete("key0")?;
+    let (_, seq, command) = tailer.try_next()?.unwrap();
+    assert_eq!(seq, 1);
+    assert!(matches!(command, Command::Set(k, _) if k == "before"));
+    let mut seqs = vec![];
+    while let Some((_, seq, _)) = tailer.try_next()? {
+        seqs.push(seq);
+    }
+    assert_eq!(seqs, (2..=22).collect::<Vec<_>>());
+
+    // The blocking tailer on the other thread sees the same records, as they
+    // arrive, from where it was asked to start.
+    let followed = (3..=22)
+        .map(|_| rx.recv_timeout(Duration::from_secs(10)).unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(followed.first().unwrap().1, 3);
+    assert!(matches!(&followed.last().unwrap().2, Command::Delete(k) if k == "key0"));
+    db.set("late", "write")?;
+    let (_, seq, _) = rx.recv_timeout(Duration::from_secs(10)).unwrap();
+    assert_eq!(seq, 23);
+
+    // A compaction is just more records to a tailer that's kept up.
+    assert_eq!(tailer.try_next()?.unwrap().1, 23);
+    db.compact()?;
+    let mut compacted = 0;
+    while let Some((_, _, command)) = tailer.try_next()? {
+        assert!(matches!(command, Command::Set(..)));
+        compacted += 1;
+    }
+    assert_eq!(compacted, db.len());
+
+    Ok(())
+}
+
+#[test]
+fn test_tailer_falls_behind() -> Result<()> {
+    let dir = tempdir()?;
+    let file = dir.path().to_path_buf().join("logfile");
+    let options = crate::Options {
+        segment_size: 256,
+        ..crate::Options::default()
+    };
+
+    let mut db = crate::Db::with_options(&file, options)?;
+    db.set("a", "1")?;
+    let mut tailer = LogTailer::open(&file)?;
+    assert!(tailer.try_next()?.is_some());
+    for i in 0..20 {
+        db.set("a", &i.to_string())?;
+    }
+    db.compact()?;
+    let err = loop {
+        match tailer.try_next() {
+            Ok(Some(_)) => {}
+            Ok(None) => panic!("expected the tailer to notice the gap"),
+            Err(e) => break e,
+        }
+    };
+    assert!(matches!(err, Error::Compacted { seq: 2 }), "{}", err);
+    assert!(tailer.next().is_none());
+
+    Ok(())
+}
@@ -0,0 +1,137 @@
+// Spans for the `tracing` crate, when the `tracing` feature is on, so that
+// commits and recovery show up under whatever subscriber the application
+// has set up. Without the feature, `span!` doesn't evaluate its arguments and
+// costs nothing.
+//
+// The spans, all at debug level, are:
+//
+//   recover          opening a database, including replay
+//     replay         replaying the log
+//       segment      one segment (serial replay) or one segment's commands
+//                    (parallel replay)
+//   commit           a batch, once its leader has the log
+//     write          appending the batch to the log
+//     sync
+//     apply          applying the batch to the memtable
+//     flush          writing the memtable out to a table, if it's full
+//   flush            the same, from `Db::compact`
+//
+// Slow syncs and stalled writes (see `Options::slow_sync`) are logged as
+// warnings.
+#[cfg(test)]
+use crate::Result;
+#[cfg(all(test, feature = "tracing"))]
+use crate::{Db, Options};
+#[cfg(all(test, feature = "tracing"))]
+use std::sync::{Arc, Mutex};
+#[cfg(all(test, feature = "tracing"))]
+use tempfile::tempdir;
+
+// Enters a span until the value it returns is dropped. Takes the same
+// arguments as `tracing::debug_span!`.
+macro_rules! span {
+    ($($arg:tt)*) => {{
+        #[cfg(feature = "tracing")]
+        let span = tracing::debug_span!($($arg)*).entered();
+        #[cfg(not(feature = "tracing"))]
+        let span = $crate::trace::NoSpan;
+        span
+    }};
+}
+
+#[cfg(not(feature = "tracing"))]
+pub(crate) struct NoSpan;
+
+// `tracing::warn!`, or nothing without the feature.
+macro_rules! warn {
+    ($($arg:tt)*) => {
+        #[cfg(feature = "tracing")]
+        tracing::warn!($($arg)*);
+    };
+}
+
+// Notes the name of every span entered, and the name of the span it was
+// entered in.
+#[cfg(all(test, feature = "tracing"))]
+#[derive(Default)]
+struct Recorder {
+    spans: Mutex<Vec<&'static str>>,
+    stack: Mutex<Vec<u64>>,
+    entered: Mutex<Vec<(&'static str, Option<&'static str>)>>,
+}
+
+#[cfg(all(test, feature = "tracing"))]
+impl tracing::Subscriber for Recorder {
+    fn enabled(&self, _: &tracing::Metadata<'_>) -> bool {
+        true
+    }
+
+    fn new_span(&self, attrs: &tracing::span::Attributes<'_>) -> tracing::span::Id {
+        let mut spans = self.spans.lock().unwrap();
+        spans.push(attrs.metadata().name());
+        tracing::span::Id::from_u64(spans.len() as u64)
+    }
+
+    fn record(&self, _: &tracing::span::Id, _: &tracing::span::Record<'_>) {}
+
+    fn record_follows_from(&self, _: &tracing::span::Id, _: &tracing::span::Id) {}
+
+    fn event(&self, _: &tracing::Event<'_>) {}
+
+    fn enter(&self, id: &tracing::span::Id) {
+        let spans = self.spans.lock().unwrap();
+        let mut stack = self.stack.lock().unwrap();
+        let name = |id: u64| spans[id as usize - 1];
+        let parent = stack.last().map(|&id| name(id));
+        self.entered
+            .lock()
+            .unwrap()
+            .push((name(id.into_u64()), parent));
+        stack.push(id.into_u64());
+    }
+
+    fn exit(&self, _: &tracing::span::Id) {
+        self.stack.lock().unwrap().pop();
+    }
+}
+
+#[cfg(feature = "tracing")]
+#[test]
+fn test_spans() -> Result<()> {
+    let dir = tempdir()?;
+    let file = dir.path().join("logfile");
+    let options = Options {
+        memtable_bytes: Some(64),
+        ..Options::default()
+    };
+    let recorder = Arc::new(Recorder::default());
+    tracing::subscriber::with_default(recorder.clone(), || -> Result<()> {
+        let mut db = Db::with_options(&file, options.clone())?;
+        db.set("a", &"x".repeat(100))?;
+        drop(db);
+        Db::with_options(&file, options)?;
+        Ok(())
+    })?;
+    let entered = recorder.entered.lock().unwrap();
+    for span in [
+        ("recover", None),
+        ("replay", Some("recover")),
+        ("segment", Some("replay")),
+        ("commit", None),
+        ("write", Some("commit")),
+        ("sync", Some("commit")),
+        ("apply", Some("commit")),
+        ("flush", Some("commit")),
+    ] {
+        assert!(entered.contains(&span), "{:?} not in {:?}", span, entered);
+    }
+
+    Ok(())
+}
+
+#[cfg(not(feature = "tracing"))]
+#[test]
+fn test_spans() -> Result<()> {
+    let _span = span!("unused", n = unreachable!());
+    Ok(())
+}
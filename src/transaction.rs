@@ -0,0 +1,269 @@
+use crate::memtable::KeyRange;
+#[cfg(test)]
+use crate::Options;
+use crate::{error, Command, Db, Error, Result};
+use std::collections::{BTreeMap, HashMap};
+#[cfg(test)]
+use tempfile::tempdir;
+
+// A set of reads and writes against a `Db`, from `Db::transaction`, that
+// commits all at once or not at all.
+//
+// Reads see the database as it was when the transaction started, along with
+// the transaction's own writes. There's only ever one version of each key, so
+// reading a key that has been written since fails with `Error::Conflict`
+// rather than returning something newer. Writes are held until `commit`,
+// which fails the same way if another write got to one of the same keys
+// first. Dropping a transaction without committing it throws its writes away.
+#[derive(Debug)]
+pub struct Transaction {
+    db: Db,
+    // The sequence number of the first record it can't see.
+    start: u64,
+    writes: BTreeMap<String, Option<String>>,
+}
+
+impl Transaction {
+    pub(crate) fn new(db: Db, start: u64) -> Self {
+        error::lock(&db.versions).begin(start);
+        Transaction {
+            db,
+            start,
+            writes: BTreeMap::new(),
+        }
+    }
+
+    pub fn get(&self, k: &str) -> Result<Option<String>> {
+        if let Some(v) = self.writes.get(k) {
+            return Ok(v.clone());
+        }
+        // Writes are noted before they reach the memtable, so if this read
+        // saw one, checking afterwards catches it. A table or the value log
+        // that can't be read fails the read rather than panicking.
+        let v = self.db.try_get(k)?;
+        if self.db.versions.lock()?.changed_since(k, self.start) {
+            return Err(Error::Conflict { key: k.to_owned() });
+        }
+        Ok(v)
+    }
+
+    pub fn set(&mut self, k: &str, v: &str) {
+        self.writes.insert(k.to_owned(), Some(v.to_owned()));
+    }
+
+    pub fn delete(&mut self, k: &str) {
+        self.writes.insert(k.to_owned(), None);
+    }
+
+    // Logs every write as a single record.
+    pub fn commit(mut self) -> Result<()> {
+        let writes = std::mem::take(&mut self.writes).into_iter().collect();
+        self.db.commit_transaction(self.start, writes)
+    }
+
+    // The same as dropping it.
+    pub fn rollback(self) {}
+}
+
+impl Drop for Transaction {
+    fn drop(&mut self) {
+        if let Ok(mut versions) = self.db.versions.lock() {
+            versions.end(self.start);
+        }
+    }
+}
+
+// When each key was last written, going back as far as the oldest open
+// transaction, which is as far as anyone needs to know. With no transactions
+// open, nothing is kept.
+#[derive(Debug, Default)]
+pub(crate) struct Versions {
+    // Where each open transaction started, and how many started there.
+    open: BTreeMap<u64, usize>,
+    written: HashMap<String, u64>,
+    deleted_ranges: Vec<(KeyRange, u64)>,
+}
+
+impl Versions {
+    fn begin(&mut self, start: u64) {
+        *self.open.entry(start).or_default() += 1;
+    }
+
+    fn end(&mut self, start: u64) {
+        if let Some(n) = self.open.get_mut(&start) {
+            *n -= 1;
+            if *n == 0 {
+                self.open.remove(&start);
+            }
+        }
+        match self.open.keys().next() {
+            None => {
+                self.written = HashMap::new();
+                self.deleted_ranges = vec![];
+            }
+            Some(&oldest) => {
+                self.written.retain(|_, seq| *seq >= oldest);
+                self.deleted_ranges.retain(|(_, seq)| *seq >= oldest);
+            }
+        }
+    }
+
+    // Notes the keys that `command`, which was logged as `seq`, writes.
+    pub fn record(&mut self, seq: u64, command: &Command) {
+        if self.open.is_empty() {
+            return;
+        }
+        match command.unwrap_request() {
+            Command::Set(k, _)
+            | Command::Delete(k)
+            | Command::SetExpiring(k, _, _)
+            | Command::Expire(k, _)
+            | Command::Incr(k, _, _)
+            | Command::Append(k, _) => {
+                self.written.insert(k.clone(), seq);
+            }
+            Command::Transaction(writes) => {
+                for (k, _) in writes {
+                    self.written.insert(k.clone(), seq);
+                }
+            }
+            Command::DeleteRange(start, end) => {
+                let range = KeyRange::Between(start.clone(), end.clone());
+                self.deleted_ranges.push((range, seq));
+            }
+            Command::DeletePrefix(prefix) => {
+                let range = KeyRange::Prefix(prefix.clone());
+                self.deleted_ranges.push((range, seq));
+            }
+            // Transactions only see the default keyspace.
+            Command::Custom(_)
+            | Command::KeyspaceSet(..)
+            | Command::KeyspaceDelete(..)
+            | Command::DropKeyspace(_)
+            | Command::Request(..)
+            | Command::LastRequest(_) => {}
+        }
+    }
+
+    // Whether `k` has been written since the transaction that started at
+    // `start` did.
+    pub fn changed_since(&self, k: &str, start: u64) -> bool {
+        self.written.get(k).is_some_and(|&seq| seq >= start)
+            || self
+                .deleted_ranges
+                .iter()
+                .any(|(range, seq)| *seq >= start && range.contains(k))
+    }
+}
+
+#[test]
+fn test_transaction() -> Result<()> {
+    let dir = tempdir()?;
+    let file = dir.path().join("logfile");
+    let mut db = Db::new(&file)?;
+    db.set("a", "1")?;
+    db.set("b", "2")?;
+
+    let mut txn = db.transaction();
+    txn.set("a", "10");
+    txn.delete("b");
+    assert_eq!(txn.get("a")?, Some("10".into()));
+    assert_eq!(txn.get("b")?, None);
+    assert_eq!(db.get("a"), Some("1".into()));
+    let seq = db.next_seq();
+    txn.commit()?;
+    assert_eq!(db.next_seq(), seq + 1);
+    assert_eq!(db.get("a"), Some("10".into()));
+    assert_eq!(db.get("b"), None);
+
+    // Rolled back, nothing is written.
+    let mut txn = db.transaction();
+    txn.set("c", "3");
+    txn.rollback();
+    assert_eq!(db.get("c"), None);
+
+    // The first of two transactions to write a key wins.
+    let mut first = db.transaction();
+    let mut second = db.transaction();
+    let mut other = db.transaction();
+    first.set("a", "first");
+    second.set("a", "second");
+    other.set("c", "other");
+    first.commit()?;
+    let err = second.commit().unwrap_err();
+    assert!(
+        matches!(&err, Error::Conflict { key } if key == "a"),
+        "{}",
+        err
+    );
+    other.commit()?;
+    assert_eq!(db.get("a"), Some("first".into()));
+    assert_eq!(db.get("c"), Some("other".into()));
+
+    // Reads can't see past the snapshot.
+    let txn = db.transaction();
+    assert_eq!(txn.get("a")?, Some("first".into()));
+    db.set("a", "outside")?;
+    db.delete_prefix("c")?;
+    assert!(matches!(txn.get("a"), Err(Error::Conflict { .. })));
+    assert!(matches!(txn.get("c"), Err(Error::Conflict { .. })));
+    assert_eq!(txn.get("b")?, None);
+    drop(txn);
+    assert!(db.versions.lock()?.written.is_empty());
+
+    drop(db);
+    let db = Db::new(&file)?;
+    assert_eq!(db.get("a"), Some("outside".into()));
+    assert_eq!(db.get("b"), None);
+
+    Ok(())
+}
+
+#[test]
+fn test_concurrent_transactions() -> Result<()> {
+    let dir = tempdir()?;
+    let file = dir.path().join("logfile");
+    let options = Options {
+        memtable_bytes: Some(512),
+        ..Options::default()
+    };
+    let db = Db::with_options(&file, options.clone())?;
+    let threads = (0..4)
+        .map(|_| {
+            let db = db.clone();
+            std::thread::spawn(move || -> Result<()> {
+                let mut done = 0;
+                while done < 50 {
+                    // Moves one from `from` to `to`, so the total stays at
+                    // zero however the transactions interleave.
+                    let mut txn = db.transaction();
+                    let result = (|| {
+                        let get = |k| -> Result<i64> {
+                            Ok(txn.get(k)?.map_or(0, |v: String| v.parse().unwrap()))
+                        };
+                        let (from, to) = (get("from")?, get("to")?);
+                        txn.set("from", &(from - 1).to_string());
+                        txn.set("to", &(to + 1).to_string());
+                        txn.commit()
+                    })();
+                    match result {
+                        Ok(()) => done += 1,
+                        Err(Error::Conflict { .. }) => {}
+                        Err(e) => return Err(e),
+                    }
+                }
+                Ok(())
+            })
+        })
+        .collect::<Vec<_>>();
+    for thread in threads {
+        thread.join().unwrap()?;
+    }
+    assert_eq!(db.get("from"), Some("-200".into()));
+    assert_eq!(db.get("to"), Some("200".into()));
+    drop(db);
+    let db = Db::with_options(&file, options)?;
+    assert_eq!(db.get("to"), Some("200".into()));
+
+    Ok(())
+}
@@ -0,0 +1,82 @@
+use crate::error;
+use crate::memtable::KeyRange;
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Mutex,
+};
+
+// What a batch does to the keys it writes and the requests it makes, as far
+// as the commands after it need to know to work out what they log.
+#[derive(Debug, Default)]
+pub(crate) struct BatchWrites {
+    values: HashMap<String, Option<String>>,
+    deleted: Vec<KeyRange>,
+    requests: HashMap<u64, u64>,
+}
+
+impl BatchWrites {
+    // The value the batch leaves `k` with, or None for a deletion, if it
+    // writes or deletes it at all.
+    pub fn value(&self, k: &str) -> Option<Option<&String>> {
+        match self.values.get(k) {
+            Some(v) => Some(v.as_ref()),
+            None if self.deleted.iter().any(|r| r.contains(k)) => Some(None),
+            None => None,
+        }
+    }
+
+    pub fn write(&mut self, k: &str, v: Option<String>) {
+        self.values.insert(k.to_owned(), v);
+    }
+
+    // A range deletion hides whatever the batch wrote before it.
+    pub fn delete(&mut self, range: KeyRange) {
+        self.values.retain(|k, _| !range.contains(k));
+        self.deleted.push(range);
+    }
+
+    pub fn request(&mut self, client_id: u64, request_id: u64) {
+        self.requests.insert(client_id, request_id);
+    }
+}
+
+// Batches written to a sharded log that haven't been applied yet, oldest
+// first. Each one is only applied once it's synced and the one before it is
+// committed, so that nothing can be read that a crash might still lose, but
+// the next batch is written alongside, and works out its increments and
+// retries from these as well as from the memtable.
+#[derive(Debug, Default)]
+pub(crate) struct Unapplied {
+    batches: Mutex<VecDeque<(u64, BatchWrites)>>,
+}
+
+impl Unapplied {
+    // The value the newest batch to write `k` leaves it with, as in
+    // `BatchWrites::value`.
+    pub fn value(&self, k: &str) -> Option<Option<String>> {
+        let batches = error::lock(&self.batches);
+        let mut values = batches.iter().rev().map(|(_, batch)| batch.value(k));
+        values.find_map(|v| v.map(|v| v.cloned()))
+    }
+
+    // The last request a batch made for `client_id`.
+    pub fn last_request(&self, client_id: u64) -> Option<u64> {
+        let batches = error::lock(&self.batches);
+        let mut requests = batches.iter().rev();
+        requests.find_map(|(_, batch)| batch.requests.get(&client_id).copied())
+    }
+
+    // Adds the batch ending at `last_seq`, which has been written but not
+    // yet applied.
+    pub fn push(&self, last_seq: u64, batch: BatchWrites) {
+        error::lock(&self.batches).push_back((last_seq, batch));
+    }
+
+    // Drops every batch up to `last_seq`, now that they've been applied.
+    pub fn applied(&self, last_seq: u64) {
+        let mut batches = error::lock(&self.batches);
+        while batches.front().is_some_and(|&(seq, _)| seq <= last_seq) {
+            batches.pop_front();
+        }
+    }
+}
@@ -0,0 +1,289 @@
+// Appends to a log through io_uring, for async code that would otherwise hand
+// every write and fsync to `spawn_blocking` and tie up a thread of the
+// blocking pool while the disk gets round to it. Both go to the kernel as
+// io_uring operations instead, and the task waits on them like any other
+// future, so it has to run on a `tokio_uring` runtime:
+//
+//     tokio_uring::start(async {
+//         let mut log = UringLog::open("path/to/db", Options::default()).await?;
+//         log.append(&[Command::Set("greeting".into(), "hello".into())]).await?;
+//         log.sync().await
+//     })
+//
+// The log it writes is an ordinary one, so a `Db` opened on the directory
+// afterwards replays the commands like any others. It only writes unsharded,
+// unmirrored logs, and it never compacts. Opening the log and moving on to a
+// new segment still read and create files with plain blocking calls, which
+// only happens once per segment.
+use crate::log::Log;
+use crate::manifest::{self, Edit, Manifest, State};
+use crate::segment::{self, FOOTER_LEN, FORMAT_VERSION, HEADER_LEN, SEGMENT_HEADER_LEN};
+use crate::{codec, durable_fs, Command, Durability, Error, LockPolicy, Options, Result};
+use std::{
+    fs,
+    io::{self, ErrorKind},
+    path::{Path, PathBuf},
+};
+#[cfg(test)]
+use tempfile::tempdir;
+use tokio_uring::{buf::IoBuf, fs::File};
+
+#[derive(Debug)]
+pub struct UringLog {
+    dir: PathBuf,
+    options: Options,
+    file: File,
+    number: u64,
+    // Where the next record goes in the active segment.
+    offset: u64,
+    next_seq: u64,
+    // Recycled segments that new segments can take over.
+    recycled: Vec<u64>,
+    manifest: Manifest,
+    // Set once a write or sync has failed, after which nothing more is
+    // written, as with `Log`.
+    failed: bool,
+    buf: Vec<u8>,
+    // Held for as long as the log is open; dropping it releases the lock.
+    _lock: Option<fs::File>,
+}
+
+impl UringLog {
+    // Opens the log in `dir`, creating it if there isn't one, and starts a
+    // fresh segment after whatever is already there. `options.codec`,
+    // `options.durability` and `options.segment_size` apply as they do for a
+    // `Db`; anything to do with reading the log back doesn't.
+    pub async fn open<P>(dir: P, options: Options) -> Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        let dir = dir.as_ref();
+        if Log::is_sharded(dir) {
+            return Err(Error::InvalidConfig(format!(
+                "the log in {} is sharded, which only a Db can write",
+                dir.display()
+            )));
+        }
+        durable_fs::create_dir_all(dir)?;
+        let lock = Log::lock(dir, LockPolicy::Fail)?;
+        let state = Manifest::load(dir)?;
+        let (sealed, mut recycled) = manifest::list(dir, 0, state.as_ref(), true)?;
+        let mut manifest = Manifest::create(
+            dir,
+            &State {
+                format: Some(manifest::Format {
+                    version: FORMAT_VERSION,
+                    codec: options.codec.id(),
+                    segment_size: options.segment_size,
+                    shards: 1,
+                }),
+                segments: vec![sealed.clone()],
+                snapshot: state.and_then(|s| s.snapshot),
+                last_number: 0,
+            },
+        )?;
+        let clean = Log::closed_cleanly(dir);
+        let next_seq = Log::recover_next_seq(dir, &sealed, clean)?;
+        if clean.is_some() {
+            durable_fs::remove_file(&dir.join("CLEAN"))?;
+        }
+        let number = sealed.iter().chain(&recycled).max().map_or(1, |n| n + 1);
+        let file = segment::create_file(
+            dir,
+            number,
+            next_seq,
+            options.codec.id(),
+            options.segment_size,
+            recycled.pop(),
+            options.durability,
+        )?;
+        manifest.record(&[Edit::Add { shard: 0, number }])?;
+        Ok(UringLog {
+            dir: dir.to_path_buf(),
+            options,
+            file: File::from_std(file),
+            number,
+            offset: SEGMENT_HEADER_LEN as u64,
+            next_seq,
+            recycled,
+            manifest,
+            failed: false,
+            buf: vec![],
+            _lock: lock,
+        })
+    }
+
+    // The sequence number the next command appended will get.
+    pub fn next_seq(&self) -> u64 {
+        self.next_seq
+    }
+
+    // Writes `commands` to the log in a single write, numbered from
+    // `next_seq`, and returns the sequence number of the first. They aren't
+    // durable until `sync` says so.
+    pub async fn append(&mut self, commands: &[Command]) -> Result<u64> {
+        self.check()?;
+        let payloads = commands
+            .iter()
+            .map(|command| codec::encode(&*self.options.codec, command))
+            .collect::<Result<Vec<_>>>()?;
+        let len = payloads.iter().map(|p| HEADER_LEN + p.len()).sum::<usize>() as u64;
+        if self.offset > SEGMENT_HEADER_LEN as u64
+            && self.offset + len + FOOTER_LEN as u64 > self.options.segment_size
+        {
+            let rotated = self.rotate().await;
+            self.fail_if(rotated)?;
+        }
+        let first = self.next_seq;
+        let mut buf = std::mem::take(&mut self.buf);
+        buf.clear();
+        for payload in &payloads {
+            segment::encode_record(self.number, self.next_seq, payload, &mut buf);
+            self.next_seq += 1;
+        }
+        let (written, buf) = self.write_all(buf).await;
+        self.buf = buf;
+        if let Err(e) = written {
+            self.next_seq = first;
+            return self.fail_if(Err(e.into()));
+        }
+        self.offset += len;
+        Ok(first)
+    }
+
+    // Waits until everything appended so far is as durable as
+    // `options.durability` says.
+    pub async fn sync(&mut self) -> Result<()> {
+        self.check()?;
+        let synced = match self.options.durability {
+            Durability::Media | Durability::Device => self.file.sync_data().await,
+            Durability::None => Ok(()),
+        };
+        self.fail_if(synced.map_err(Error::from))
+    }
+
+    // Syncs the log and releases it.
+    pub async fn close(mut self) -> Result<()> {
+        self.sync().await?;
+        self.file.close().await?;
+        Ok(())
+    }
+
+    // Writes all of `buf` at the end of the active segment, handing the
+    // buffer back so it can be reused.
+    async fn write_all(&self, mut buf: Vec<u8>) -> (io::Result<()>, Vec<u8>) {
+        let mut written = 0;
+        while written < buf.len() {
+            let at = self.offset + written as u64;
+            let (result, slice) = self.file.write_at(buf.slice(written..), at).await;
+            buf = slice.into_inner();
+            match result {
+                Ok(0) => return (Err(ErrorKind::WriteZero.into()), buf),
+                Ok(n) => written += n,
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return (Err(e), buf),
+            }
+        }
+        (Ok(()), buf)
+    }
+
+    // Seals the active segment with a footer, as `SegmentWriter::seal` does,
+    // and moves on to a new one, which starts at `next_seq`.
+    async fn rotate(&mut self) -> Result<()> {
+        let mut buf = std::mem::take(&mut self.buf);
+        buf.clear();
+        segment::encode_footer(self.number, self.next_seq, &mut buf);
+        let (written, buf) = self.write_all(buf).await;
+        self.buf = buf;
+        written?;
+        self.sync().await?;
+        let file = segment::create_file(
+            &self.dir,
+            self.number + 1,
+            self.next_seq,
+            self.options.codec.id(),
+            self.options.segment_size,
+            self.recycled.pop(),
+            self.options.durability,
+        )?;
+        self.manifest.record(&[Edit::Add {
+            shard: 0,
+            number: self.number + 1,
+        }])?;
+        let sealed = std::mem::replace(&mut self.file, File::from_std(file));
+        self.number += 1;
+        self.offset = SEGMENT_HEADER_LEN as u64;
+        sealed.close().await?;
+        Ok(())
+    }
+
+    fn check(&self) -> Result<()> {
+        if self.failed {
+            return Err(Error::WriteFailed(format!(
+                "an earlier write to the log failed, as of segment {}; reopen the log",
+                self.number
+            )));
+        }
+        Ok(())
+    }
+
+    fn fail_if<T>(&mut self, result: Result<T>) -> Result<T> {
+        if result.is_err() {
+            self.failed = true;
+        }
+        result
+    }
+}
+
+#[test]
+fn test_uring_log() -> Result<()> {
+    use crate::Db;
+
+    let dir = tempdir()?;
+    let options = || Options {
+        segment_size: 256,
+        ..Options::default()
+    };
+    let mut db = Db::with_options(dir.path(), options())?;
+    db.set("before", "1")?;
+    db.close()?;
+
+    tokio_uring::start(async {
+        let mut log = UringLog::open(dir.path(), options()).await?;
+        let first = log.next_seq();
+        let set = |i: usize| Command::Set(format!("key{}", i), "x".repeat(i));
+        for i in 0..20 {
+            assert_eq!(log.append(&[set(i)]).await?, first + i as u64);
+        }
+        log.append(&[Command::Delete("key3".into()), set(20)])
+            .await?;
+        log.sync().await?;
+        // It's locked while it's open, as a `Db`'s log is.
+        assert!(matches!(Db::new(dir.path()), Err(Error::Busy(_))));
+        log.close().await
+    })?;
+
+    // Small segments meant it moved on to new ones along the way, and a `Db`
+    // replays them all.
+    let (segments, _) = segment::list(dir.path())?;
+    assert!(segments.len() > 3, "{:?}", segments);
+    let mut db = Db::with_options(dir.path(), options())?;
+    assert_eq!(db.get("before").as_deref(), Some("1"));
+    assert_eq!(db.get("key3"), None);
+    assert_eq!(db.get("key19"), Some("x".repeat(19)));
+    assert_eq!(db.len(), 21);
+    db.set("after", "2")?;
+    db.close()?;
+
+    // Opening it again carries on the sequence from the `Db`'s writes.
+    tokio_uring::start(async {
+        let mut log = UringLog::open(dir.path(), options()).await?;
+        log.append(&[Command::Set("last".into(), "3".into())])
+            .await?;
+        log.close().await
+    })?;
+    let db = Db::new(dir.path())?;
+    assert_eq!(db.get("after").as_deref(), Some("2"));
+    assert_eq!(db.get("last").as_deref(), Some("3"));
+    Ok(())
+}
@@ -0,0 +1,769 @@
+// Keeps big values out of the log, as WiscKey does. With `Options::value_log`,
+// a value of `Options::value_separation_threshold` bytes or more is written
+// to a value log file before the command that sets it is logged, and the
+// command, the memtable and tables only ever hold a pointer to it. Compactions and flushes then copy
+// the pointer rather than the value, which is where most of the writing they
+// do would otherwise go. Reads follow the pointer back to the value.
+//
+// Value log files are `<number>.vlog` in the database's directory, each a run
+// of records framed the way the log's are, whose payload is the key's length
+// as a u32, the key and then the value. A value that has since been
+// overwritten or deleted stays in its file until `Db::gc_value_log` copies
+// whatever is still in use out of it and removes the file.
+//
+// A blob written with `Db::put_blob` gets a file of its own, `<number>.blob`,
+// in records of the same kind, each holding the next `CHUNK_LEN` bytes of it,
+// so that it can be written and read back without ever being in memory all at
+// once. It's removed once its key no longer points to it.
+//
+// A pointer is a value of its own, starting with a NUL like the name of the
+// keyspace cursors are kept in, so nothing else that handles values has to
+// know about them. While the value log is on, a value that starts the same
+// way is always written to it, so that it can't be taken for a pointer.
+// Followers only get the pointers, not the value log, so it isn't for a
+// database with followers.
+use crate::error;
+use crate::segment::{self, HEADER_LEN};
+use crate::{durable_fs, Durability, Error, Options, Result};
+use std::{
+    fmt,
+    fs::{self, File},
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::{Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard},
+};
+#[cfg(test)]
+use tempfile::tempdir;
+
+// What a value that was moved to the value log leaves behind in its place,
+// followed by where it went.
+const POINTER: &str = "\0vlog:";
+
+// The same for a blob, which also starts like a pointer, so that separating
+// values keeps them from being taken for one too.
+const BLOB: &str = "\0vlog:blob:";
+
+// How much of a blob each record holds.
+const CHUNK_LEN: usize = 64 << 10;
+
+pub fn file_path(dir: &Path, number: u64) -> PathBuf {
+    dir.join(format!("{:020}.vlog", number))
+}
+
+pub fn blob_path(dir: &Path, number: u64) -> PathBuf {
+    dir.join(format!("{:020}.blob", number))
+}
+
+// Returns the numbers of the value log files in `dir`, in ascending order.
+pub fn list(dir: &Path) -> Result<Vec<u64>> {
+    numbers(dir, "vlog")
+}
+
+// The same for blobs.
+pub fn list_blobs(dir: &Path) -> Result<Vec<u64>> {
+    numbers(dir, "blob")
+}
+
+// Every value log file and blob in `dir`.
+pub fn paths(dir: &Path) -> Result<Vec<PathBuf>> {
+    let files = list(dir)?.into_iter().map(|n| file_path(dir, n));
+    let blobs = list_blobs(dir)?.into_iter().map(|n| blob_path(dir, n));
+    Ok(files.chain(blobs).collect())
+}
+
+fn numbers(dir: &Path, extension: &str) -> Result<Vec<u64>> {
+    let mut files = vec![];
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().and_then(|e| e.to_str()) != Some(extension) {
+            continue;
+        }
+        if let Some(Ok(n)) = path.file_stem().and_then(|s| s.to_str()).map(str::parse) {
+            files.push(n);
+        }
+    }
+    files.sort_unstable();
+    Ok(files)
+}
+
+// Where a value is in the value log: its record's file, offset and length.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Pointer {
+    pub file: u64,
+    pub offset: u64,
+    pub len: u64,
+}
+
+impl Pointer {
+    // The pointer `v` is, if it's one.
+    pub fn decode(v: &str) -> Option<Pointer> {
+        let mut fields = v.strip_prefix(POINTER)?.split(':').map(str::parse);
+        let pointer = Pointer {
+            file: fields.next()?.ok()?,
+            offset: fields.next()?.ok()?,
+            len: fields.next()?.ok()?,
+        };
+        fields.next().is_none().then_some(pointer)
+    }
+}
+
+impl fmt::Display for Pointer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{}:{}:{}", POINTER, self.file, self.offset, self.len)
+    }
+}
+
+// Where a blob is: the file it has to itself, and how long it is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlobRef {
+    pub file: u64,
+    pub len: u64,
+}
+
+impl BlobRef {
+    // The blob `v` points to, if it's a pointer to one.
+    pub fn decode(v: &str) -> Option<BlobRef> {
+        let (file, len) = v.strip_prefix(BLOB)?.split_once(':')?;
+        Some(BlobRef {
+            file: file.parse().ok()?,
+            len: len.parse().ok()?,
+        })
+    }
+}
+
+impl fmt::Display for BlobRef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{}:{}", BLOB, self.file, self.len)
+    }
+}
+
+// A value read back out of a value log file by `ValueLog::read_file`.
+#[derive(Debug)]
+pub struct Stored {
+    pub pointer: Pointer,
+    pub key: String,
+    pub value: Vec<u8>,
+}
+
+#[derive(Debug)]
+pub struct ValueLog {
+    dir: PathBuf,
+    // Whether new values are moved out to it at all. Pointers are followed
+    // either way, so that turning it off doesn't lose what's already there.
+    enabled: bool,
+    // Values shorter than this stay where they are.
+    threshold: usize,
+    durability: Durability,
+    file_size: u64,
+    // Only read from the directory the first time it's needed, since most
+    // databases never have a value log.
+    files: Mutex<Option<Files>>,
+    // Held for reading by writes between moving their values out and
+    // logging the pointers to them, and for writing by `Db::gc_value_log`,
+    // which would otherwise take those values for dead ones.
+    collecting: RwLock<()>,
+}
+
+#[derive(Debug)]
+struct Files {
+    numbers: Vec<u64>,
+    blobs: Vec<u64>,
+    // Value log files and blobs are numbered from the same sequence.
+    next_number: u64,
+    // The file values are being written to, which is the last of `numbers`.
+    // Every time the database is opened, it starts a new one.
+    active: Option<Active>,
+}
+
+#[derive(Debug)]
+struct Active {
+    file: File,
+    number: u64,
+    offset: u64,
+    next_seq: u64,
+}
+
+impl ValueLog {
+    // Files are started afresh once they reach `options.segment_size`, and
+    // synced as `options.durability` says.
+    pub fn new(dir: &Path, options: &Options) -> Self {
+        ValueLog {
+            dir: dir.to_path_buf(),
+            enabled: options.value_log,
+            threshold: options.value_separation_threshold,
+            durability: options.durability,
+            file_size: options.segment_size,
+            files: Mutex::new(None),
+            collecting: RwLock::new(()),
+        }
+    }
+
+    fn files(&self) -> Result<MutexGuard<'_, Option<Files>>> {
+        let mut files = self.files.lock()?;
+        if files.is_none() {
+            let numbers = list(&self.dir)?;
+            let blobs = list_blobs(&self.dir)?;
+            let last = numbers.iter().chain(&blobs).max();
+            *files = Some(Files {
+                next_number: last.map_or(1, |n| n + 1),
+                numbers,
+                blobs,
+                active: None,
+            });
+        }
+        Ok(files)
+    }
+
+    // Keeps values from being collected until the guard is dropped.
+    pub fn pin(&self) -> RwLockReadGuard<'_, ()> {
+        error::read(&self.collecting)
+    }
+
+    // Waits for every write holding a `pin` to finish, and keeps any more
+    // from starting, until the guard is dropped.
+    pub fn collecting(&self) -> RwLockWriteGuard<'_, ()> {
+        error::write(&self.collecting)
+    }
+
+    // Moves `v` out to the value log, leaving a pointer to it behind, if it's
+    // long enough to be worth it or could be taken for a pointer.
+    pub fn separate(&self, k: &str, v: &mut String) -> Result<()> {
+        if self.enabled && (v.len() >= self.threshold || v.starts_with(POINTER)) {
+            *v = self.put(k, v.as_bytes())?.to_string();
+        }
+        Ok(())
+    }
+
+    // Appends `v` as the value of `k`, and syncs it, so that it's durable
+    // before anything that points to it is logged.
+    pub fn put(&self, k: &str, v: &[u8]) -> Result<Pointer> {
+        let mut payload = Vec::with_capacity(4 + k.len() + v.len());
+        payload.extend_from_slice(&(k.len() as u32).to_le_bytes());
+        payload.extend_from_slice(k.as_bytes());
+        payload.extend_from_slice(v);
+        let mut guard = self.files()?;
+        let files = guard.as_mut().unwrap();
+        let len = (HEADER_LEN + payload.len()) as u64;
+        if files
+            .active
+            .as_ref()
+            .is_some_and(|a| a.offset > 0 && a.offset + len > self.file_size)
+        {
+            files.active = None;
+        }
+        if files.active.is_none() {
+            let number = files.next_number;
+            let file = durable_fs::create_new(&file_path(&self.dir, number))?;
+            files.next_number += 1;
+            files.numbers.push(number);
+            files.active = Some(Active {
+                file,
+                number,
+                offset: 0,
+                next_seq: 1,
+            });
+        }
+        let active = files.active.as_mut().unwrap();
+        let mut buf = Vec::with_capacity(len as usize);
+        segment::encode_record(active.number, active.next_seq, &payload, &mut buf);
+        let written = active
+            .file
+            .write_all(&buf)
+            .map_err(Error::from)
+            .and_then(|()| durable_fs::sync_file(&active.file, self.durability));
+        if let Err(e) = written {
+            // Whatever made it into the file is never pointed to, and the
+            // next value goes to a new one.
+            files.active = None;
+            return Err(e);
+        }
+        let pointer = Pointer {
+            file: active.number,
+            offset: active.offset,
+            len,
+        };
+        active.offset += len;
+        active.next_seq += 1;
+        Ok(pointer)
+    }
+
+    // Writes everything `reader` has as a blob that `k` is about to be set
+    // to, and syncs it.
+    pub fn put_blob<R: Read>(&self, k: &str, reader: R) -> Result<BlobRef> {
+        let number = {
+            let mut files = self.files()?;
+            let files = files.as_mut().unwrap();
+            files.next_number += 1;
+            files.next_number - 1
+        };
+        let path = blob_path(&self.dir, number);
+        let written = self.write_blob(&path, number, k, reader);
+        match written {
+            Ok(len) => {
+                let mut files = self.files()?;
+                files.as_mut().unwrap().blobs.push(number);
+                Ok(BlobRef { file: number, len })
+            }
+            Err(e) => {
+                // Nothing points to it, so it'd only be collected anyway.
+                let _ = fs::remove_file(&path);
+                Err(e)
+            }
+        }
+    }
+
+    fn write_blob<R: Read>(&self, path: &Path, number: u64, k: &str, reader: R) -> Result<u64> {
+        let mut file = durable_fs::create_new(path)?;
+        let mut reader = reader.take(u64::MAX);
+        let mut payload = vec![];
+        let mut buf = vec![];
+        let mut len = 0;
+        // There's always a record, even for an empty blob, to say whose it is.
+        for seq in 1.. {
+            payload.clear();
+            payload.extend_from_slice(&(k.len() as u32).to_le_bytes());
+            payload.extend_from_slice(k.as_bytes());
+            reader.set_limit(CHUNK_LEN as u64);
+            let n = reader.read_to_end(&mut payload)?;
+            buf.clear();
+            segment::encode_record(number, seq, &payload, &mut buf);
+            file.write_all(&buf)?;
+            len += n as u64;
+            if n < CHUNK_LEN {
+                break;
+            }
+        }
+        durable_fs::sync_file(&file, self.durability)?;
+        Ok(len)
+    }
+
+    // `v`, to be read a chunk at a time.
+    pub fn blob(&self, k: &str, v: String) -> Result<Blob> {
+        if let Some(blob) = BlobRef::decode(&v) {
+            if let Some(chunks) = self.chunks(blob.file)?.filter(|c| c.key == k) {
+                return Ok(Blob {
+                    len: blob.len,
+                    source: Source::Chunks(chunks, blob.len),
+                });
+            }
+        }
+        let bytes = match Pointer::decode(&v) {
+            Some(pointer) => self.get(k, pointer)?,
+            None => None,
+        };
+        let bytes = bytes.unwrap_or_else(|| v.into_bytes());
+        Ok(Blob {
+            len: bytes.len() as u64,
+            source: Source::Memory(io::Cursor::new(bytes)),
+        })
+    }
+
+    // Opens blob `number` to be read from the start, or returns None if
+    // there's no such blob.
+    fn chunks(&self, number: u64) -> Result<Option<Chunks>> {
+        if !self.files()?.as_ref().unwrap().blobs.contains(&number) {
+            return Ok(None);
+        }
+        let file = File::open(blob_path(&self.dir, number))?;
+        let mut chunks = Chunks {
+            file: io::BufReader::with_capacity(CHUNK_LEN, file),
+            number,
+            key: String::new(),
+            next_seq: 1,
+            offset: 0,
+            chunk: vec![],
+            pos: 0,
+        };
+        chunks.next_chunk()?;
+        Ok(Some(chunks))
+    }
+
+    // The key blob `number` was written for, if it's still there.
+    pub fn blob_key(&self, number: u64) -> Result<Option<String>> {
+        Ok(self.chunks(number)?.map(|c| c.key))
+    }
+
+    // `v` itself, unless it points into the value log, in which case it's
+    // the value it points to.
+    pub fn resolve(&self, k: &str, v: String) -> Result<String> {
+        if BlobRef::decode(&v).is_some() {
+            let mut bytes = vec![];
+            self.blob(k, v)?.read_to_end(&mut bytes)?;
+            return Ok(match String::from_utf8(bytes) {
+                Ok(v) => v,
+                Err(e) => String::from_utf8_lossy(e.as_bytes()).into_owned(),
+            });
+        }
+        let Some(pointer) = Pointer::decode(&v) else {
+            return Ok(v);
+        };
+        match self.get(k, pointer)? {
+            Some(value) => String::from_utf8(value).map_err(|e| corrupt(pointer, e)),
+            None => Ok(v),
+        }
+    }
+
+    // The value of `k` that `pointer` points to, or None if it doesn't point
+    // to one, being a value that only looks like a pointer.
+    pub fn get(&self, k: &str, pointer: Pointer) -> Result<Option<Vec<u8>>> {
+        if !self
+            .files()?
+            .as_ref()
+            .unwrap()
+            .numbers
+            .contains(&pointer.file)
+        {
+            return Ok(None);
+        }
+        let mut file = File::open(file_path(&self.dir, pointer.file))?;
+        file.seek(SeekFrom::Start(pointer.offset))?;
+        let mut buf = vec![0; pointer.len as usize];
+        match file.read_exact(&mut buf) {
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                return Err(corrupt(pointer, "the file is cut short"));
+            }
+            result => result?,
+        }
+        let (record, len) = segment::decode_record(&buf).map_err(|end| corrupt(pointer, end))?;
+        if len as u64 != pointer.len || record.segment != pointer.file {
+            return Err(corrupt(pointer, "not the record that was written there"));
+        }
+        let (key, value) = split(record.payload).ok_or_else(|| corrupt(pointer, "bad payload"))?;
+        Ok((key == k.as_bytes()).then(|| value.to_vec()))
+    }
+
+    // Every file but the one being written to, oldest first.
+    pub fn sealed(&self) -> Result<Vec<u64>> {
+        let files = self.files()?;
+        let files = files.as_ref().unwrap();
+        let active = files.active.as_ref().map(|a| a.number);
+        Ok(files
+            .numbers
+            .iter()
+            .copied()
+            .filter(|&n| Some(n) != active)
+            .collect())
+    }
+
+    // Every value in file `number`, and the size of the file. A crash can
+    // leave a record cut short at the end, which nothing points to.
+    pub fn read_file(&self, number: u64) -> Result<(Vec<Stored>, u64)> {
+        let bytes = fs::read(file_path(&self.dir, number))?;
+        let mut stored = vec![];
+        let mut offset = 0;
+        while let Ok((record, len)) = segment::decode_record(&bytes[offset..]) {
+            let pointer = Pointer {
+                file: number,
+                offset: offset as u64,
+                len: len as u64,
+            };
+            let (key, value) =
+                split(record.payload).ok_or_else(|| corrupt(pointer, "bad payload"))?;
+            let key = String::from_utf8(key.to_vec()).map_err(|e| corrupt(pointer, e))?;
+            stored.push(Stored {
+                pointer,
+                key,
+                value: value.to_vec(),
+            });
+            offset += len;
+        }
+        Ok((stored, bytes.len() as u64))
+    }
+
+    pub fn remove(&self, number: u64) -> Result<()> {
+        let mut files = self.files()?;
+        durable_fs::remove_file(&file_path(&self.dir, number))?;
+        files.as_mut().unwrap().numbers.retain(|&n| n != number);
+        Ok(())
+    }
+
+    // Every blob that's been written in full, and how long its file is.
+    pub fn blobs(&self) -> Result<Vec<(u64, u64)>> {
+        let blobs = self.files()?.as_ref().unwrap().blobs.clone();
+        blobs
+            .into_iter()
+            .map(|n| Ok((n, fs::metadata(blob_path(&self.dir, n))?.len())))
+            .collect()
+    }
+
+    pub fn remove_blob(&self, number: u64) -> Result<()> {
+        let mut files = self.files()?;
+        durable_fs::remove_file(&blob_path(&self.dir, number))?;
+        files.as_mut().unwrap().blobs.retain(|&n| n != number);
+        Ok(())
+    }
+}
+
+// A value being read a chunk at a time, from `Db::get_blob`.
+#[derive(Debug)]
+pub struct Blob {
+    len: u64,
+    source: Source,
+}
+
+#[derive(Debug)]
+enum Source {
+    // A value that was small enough to be read all at once.
+    Memory(io::Cursor<Vec<u8>>),
+    // A blob written with `Db::put_blob`, and how much of it is left.
+    Chunks(Chunks, u64),
+}
+
+impl Blob {
+    // How many bytes there are in all.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+impl Read for Blob {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match &mut self.source {
+            Source::Memory(cursor) => cursor.read(buf),
+            Source::Chunks(chunks, left) => {
+                while chunks.pos == chunks.chunk.len() && *left > 0 {
+                    chunks.next_chunk().map_err(io::Error::other)?;
+                }
+                let n = buf.len().min(chunks.chunk.len() - chunks.pos);
+                let n = n.min(*left as usize);
+                buf[..n].copy_from_slice(&chunks.chunk[chunks.pos..chunks.pos + n]);
+                chunks.pos += n;
+                *left -= n as u64;
+                Ok(n)
+            }
+        }
+    }
+}
+
+// The records of a blob, read one at a time.
+#[derive(Debug)]
+struct Chunks {
+    file: io::BufReader<File>,
+    number: u64,
+    key: String,
+    next_seq: u64,
+    offset: u64,
+    // The chunk of the blob read last, and how far into it we've got.
+    chunk: Vec<u8>,
+    pos: usize,
+}
+
+impl Chunks {
+    // Reads the next record into `chunk`, checking it's the one that was
+    // written there.
+    fn next_chunk(&mut self) -> Result<()> {
+        let pointer = Pointer {
+            file: self.number,
+            offset: self.offset,
+            len: 0,
+        };
+        let cut_short = |e: io::Error| match e.kind() {
+            io::ErrorKind::UnexpectedEof => corrupt(pointer, "the blob is cut short"),
+            _ => e.into(),
+        };
+        let mut buf = vec![0; HEADER_LEN];
+        self.file.read_exact(&mut buf).map_err(cut_short)?;
+        let len = u32::from_le_bytes(buf[4..8].try_into().unwrap()) as usize;
+        buf.resize(HEADER_LEN + len, 0);
+        self.file
+            .read_exact(&mut buf[HEADER_LEN..])
+            .map_err(cut_short)?;
+        let (record, len) = segment::decode_record(&buf).map_err(|end| corrupt(pointer, end))?;
+        if record.segment != self.number || record.seq != self.next_seq {
+            return Err(corrupt(pointer, "not the record that was written there"));
+        }
+        let (key, chunk) = split(record.payload).ok_or_else(|| corrupt(pointer, "bad payload"))?;
+        if self.next_seq == 1 {
+            self.key = String::from_utf8(key.to_vec()).map_err(|e| corrupt(pointer, e))?;
+        } else if key != self.key.as_bytes() {
+            return Err(corrupt(pointer, "a chunk of another blob"));
+        }
+        self.chunk = chunk.to_vec();
+        self.pos = 0;
+        self.next_seq += 1;
+        self.offset += len as u64;
+        Ok(())
+    }
+}
+
+// Splits a record's payload into the key and the value.
+fn split(payload: &[u8]) -> Option<(&[u8], &[u8])> {
+    let len = u32::from_le_bytes(payload.get(..4)?.try_into().unwrap()) as usize;
+    let rest = &payload[4..];
+    (len <= rest.len()).then(|| rest.split_at(len))
+}
+
+fn corrupt(pointer: Pointer, reason: impl fmt::Display) -> Error {
+    Error::CorruptValue {
+        file: pointer.file,
+        offset: pointer.offset,
+        reason: reason.to_string(),
+    }
+}
+
+#[test]
+fn test_value_log() -> Result<()> {
+    use crate::Db;
+
+    let dir = tempdir()?;
+    let file = dir.path().join("db");
+    let options = Options {
+        segment_size: 64 << 10,
+        value_log: true,
+        ..Options::default()
+    };
+    let big = |i: usize, round: usize| format!("{}/{}", i, round).repeat(2000);
+    let mut db = Db::with_options(&file, options.clone())?;
+    for i in 0..20 {
+        db.set(&format!("big{:02}", i), &big(i, 0))?;
+    }
+    db.set("small", "v")?;
+    let lookalike = format!("{}1:0:10", POINTER);
+    db.set("lookalike", &lookalike)?;
+    let mut txn = db.transaction();
+    txn.set("txn", &big(99, 0));
+    txn.commit()?;
+
+    // Only the pointers are in the memtable, and so the log.
+    let raw = db.read_raw("big00", None)?.unwrap();
+    assert_eq!(Pointer::decode(&raw).map(|p| p.file), Some(1));
+    assert_eq!(db.read_raw("small", None)?.as_deref(), Some("v"));
+    assert!(Pointer::decode(&db.read_raw("lookalike", None)?.unwrap()).is_some());
+    assert_eq!(db.get("big03"), Some(big(3, 0)));
+    assert_eq!(db.get("lookalike"), Some(lookalike.clone()));
+    assert_eq!(db.get("txn"), Some(big(99, 0)));
+    assert_eq!(db.scan("big").len(), 20);
+    assert_eq!(db.scan("big")[7].1, big(7, 0));
+    assert!(list(&file)?.len() > 1);
+
+    // Compacting and flushing copy the pointers, not the values.
+    db.compact()?;
+    db.flush()?;
+    assert!(db.metrics().rewritten_bytes < 10 << 10);
+    drop(db);
+    let mut db = Db::with_options(&file, options.clone())?;
+    assert_eq!(db.get("big19"), Some(big(19, 0)));
+    assert_eq!(db.get("lookalike"), Some(lookalike.clone()));
+
+    // Once most of its values have been overwritten or deleted, a file is
+    // collected, and the values still in use move to a new one.
+    let files = list(&file)?;
+    for i in 0..18 {
+        db.set(&format!("big{:02}", i), &big(i, 1))?;
+    }
+    db.delete("big18")?;
+    let snapshot = db.snapshot();
+    assert_eq!(db.gc_value_log(0.5)?, 0);
+    drop(snapshot);
+    let freed = db.gc_value_log(0.5)?;
+    assert!(freed > 100 << 10, "{}", freed);
+    let after = list(&file)?;
+    assert!(!after.contains(&files[0]), "{:?} {:?}", files, after);
+    assert_eq!(db.get("big19"), Some(big(19, 0)));
+    assert_eq!(db.get("big02"), Some(big(2, 1)));
+    assert_eq!(db.get("big18"), None);
+    assert_eq!(db.get("txn"), Some(big(99, 0)));
+    assert_eq!(db.get("lookalike"), Some(lookalike.clone()));
+    drop(db);
+
+    let db = Db::with_options(&file, options)?;
+    assert_eq!(db.get("big19"), Some(big(19, 0)));
+    assert_eq!(db.get("txn"), Some(big(99, 0)));
+    assert_eq!(db.len(), 22);
+    drop(db);
+    Db::destroy(&file)?;
+    assert!(!file.exists());
+    Ok(())
+}
+
+#[test]
+fn test_value_separation_threshold() -> Result<()> {
+    use crate::Db;
+
+    let dir = tempdir()?;
+    let file = dir.path().join("db");
+    let options = |threshold| Options {
+        value_log: true,
+        value_separation_threshold: threshold,
+        ..Options::default()
+    };
+    let is_pointer =
+        |db: &Db, k: &str| Pointer::decode(&db.read_raw(k, None).unwrap().unwrap()).is_some();
+    let mut db = Db::with_options(&file, options(100))?;
+    db.set("short", &"s".repeat(99))?;
+    assert!(!is_pointer(&db, "short"));
+    // Nothing has gone to the value log yet, so there isn't one.
+    assert!(list(&file)?.is_empty());
+    db.set("long", &"l".repeat(100))?;
+    assert!(is_pointer(&db, "long"));
+    assert_eq!(list(&file)?.len(), 1);
+    drop(db);
+
+    // Raising it leaves what's already in the value log there, but keeps
+    // new values out of it.
+    let mut db = Db::with_options(&file, options(1000))?;
+    db.set("longer", &"l".repeat(500))?;
+    assert!(!is_pointer(&db, "longer"));
+    assert!(is_pointer(&db, "long"));
+    assert_eq!(db.get("long"), Some("l".repeat(100)));
+    assert_eq!(db.get("short"), Some("s".repeat(99)));
+    Ok(())
+}
+
+#[test]
+fn test_blob() -> Result<()> {
+    use crate::Db;
+
+    let dir = tempdir()?;
+    let file = dir.path().join("db");
+    // Not UTF-8, and a few chunks long, with a short one at the end.
+    let data = (0..3 * CHUNK_LEN + 100)
+        .map(|i| (i % 251) as u8 | 0x80)
+        .collect::<Vec<_>>();
+    let read_all = |mut blob: Blob| -> io::Result<Vec<u8>> {
+        let mut buf = vec![];
+        blob.read_to_end(&mut buf)?;
+        Ok(buf)
+    };
+    let mut db = Db::new(&file)?;
+    assert_eq!(db.put_blob("blob", &data[..])?, data.len() as u64);
+    assert_eq!(db.put_blob("empty", io::empty())?, 0);
+    db.set("small", "v")?;
+    let blob = db.get_blob("blob")?.unwrap();
+    assert_eq!(blob.len(), data.len() as u64);
+    assert_eq!(read_all(blob)?, data);
+    assert!(db.get_blob("empty")?.unwrap().is_empty());
+    assert_eq!(read_all(db.get_blob("small")?.unwrap())?, b"v");
+    assert!(db.get_blob("missing")?.is_none());
+    assert_eq!(
+        db.get("blob"),
+        Some(String::from_utf8_lossy(&data).into_owned())
+    );
+    assert_eq!(list_blobs(&file)?.len(), 2);
+    drop(db);
+
+    let mut db = Db::new(&file)?;
+    assert_eq!(read_all(db.get_blob("blob")?.unwrap())?, data);
+
+    // Once nothing points to a blob, collecting removes it.
+    db.set("empty", "v")?;
+    assert!(db.gc_value_log(0.5)? > 0);
+    assert_eq!(list_blobs(&file)?.len(), 1);
+    assert_eq!(read_all(db.get_blob("blob")?.unwrap())?, data);
+
+    // Damage to any chunk is caught when it's read.
+    let number = list_blobs(&file)?[0];
+    let mut f = fs::OpenOptions::new()
+        .write(true)
+        .open(blob_path(&file, number))?;
+    f.seek(SeekFrom::Start(2 * CHUNK_LEN as u64))?;
+    f.write_all(b"junk")?;
+    drop(f);
+    let err = read_all(db.get_blob("blob")?.unwrap()).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::Other);
+    Ok(())
+}
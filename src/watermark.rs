@@ -0,0 +1,63 @@
+use crate::{error, Result};
+use std::sync::{Condvar, Mutex};
+
+// How far the log is known to be durable, and how far the memtable has
+// applied it, each as the sequence number of the last record (see
+// `Db::last_durable_seq` and `Db::last_applied_seq`). Either can be ahead of
+// the other: a write that doesn't ask to be synced is applied before it's
+// durable, and a pipelined batch is durable before it's applied.
+#[derive(Debug)]
+pub(crate) struct Watermarks {
+    marks: Mutex<Marks>,
+    changed: Condvar,
+}
+
+#[derive(Debug)]
+struct Marks {
+    durable: u64,
+    applied: u64,
+}
+
+impl Watermarks {
+    pub fn new(durable: u64, applied: u64) -> Self {
+        Watermarks {
+            marks: Mutex::new(Marks { durable, applied }),
+            changed: Condvar::new(),
+        }
+    }
+
+    pub fn durable(&self) -> u64 {
+        error::lock(&self.marks).durable
+    }
+
+    pub fn applied(&self) -> u64 {
+        error::lock(&self.marks).applied
+    }
+
+    // Each only ever goes forward, so these can be called in any order.
+    pub fn set_durable(&self, seq: u64) {
+        let mut marks = error::lock(&self.marks);
+        if seq > marks.durable {
+            marks.durable = seq;
+            self.changed.notify_all();
+        }
+    }
+
+    pub fn set_applied(&self, seq: u64) {
+        let mut marks = error::lock(&self.marks);
+        if seq > marks.applied {
+            marks.applied = seq;
+            self.changed.notify_all();
+        }
+    }
+
+    // Waits until `seq` is durable or has at least been applied, and returns
+    // whether it's durable.
+    pub fn wait_for(&self, seq: u64) -> Result<bool> {
+        let mut marks = self.marks.lock()?;
+        while marks.durable < seq && marks.applied < seq {
+            marks = self.changed.wait(marks)?;
+        }
+        Ok(marks.durable >= seq)
+    }
+}
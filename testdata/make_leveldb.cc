@@ -0,0 +1,48 @@
+// Writes testdata/leveldb with LevelDB itself, for `test_leveldb_fixture` in
+// src/interop.rs to import. Most of it ends up in Snappy-compressed tables,
+// and the last few writes are left in the write-ahead log, as they would be
+// after a clean close. Built against LevelDB 1.22 with Snappy:
+//
+//     g++ -std=c++11 make_leveldb.cc -lleveldb -lsnappy -lpthread
+//     ./a.out leveldb
+#include <cstdio>
+#include <cstdlib>
+#include <string>
+
+#include "leveldb/db.h"
+
+static void check(const leveldb::Status& s) {
+  if (!s.ok()) {
+    std::fprintf(stderr, "%s\n", s.ToString().c_str());
+    std::exit(1);
+  }
+}
+
+int main(int argc, char** argv) {
+  leveldb::Options options;
+  options.create_if_missing = true;
+  options.error_if_exists = true;
+  leveldb::DB* db;
+  check(leveldb::DB::Open(options, argv[1], &db));
+  leveldb::WriteOptions w;
+  char key[16];
+  for (int i = 0; i < 500; i++) {
+    std::snprintf(key, sizeof key, "key%03d", i);
+    check(db->Put(w, key, "value " + std::to_string(i) + std::string(40, 'x')));
+  }
+  for (int i = 0; i < 500; i += 5) {
+    std::snprintf(key, sizeof key, "key%03d", i);
+    check(db->Put(w, key, "rewritten " + std::to_string(i)));
+  }
+  for (int i = 0; i < 500; i += 7) {
+    std::snprintf(key, sizeof key, "key%03d", i);
+    check(db->Delete(w, key));
+  }
+  db->CompactRange(nullptr, nullptr);
+  check(db->Put(w, "key001", "from the log"));
+  check(db->Delete(w, "key002"));
+  check(db->Put(w, "key007", "back again"));
+  check(db->Put(w, "log only", "yes"));
+  delete db;
+  return 0;
+}
@@ -0,0 +1,282 @@
+// The log in a browser, for offline-first apps: a journal of records kept in
+// the origin private file system (OPFS), replayed into whatever state the app
+// builds from it each time it starts. It's `redo_log_core` with the file
+// behind an OPFS sync access handle, built for `wasm32-unknown-unknown`:
+//
+//     cargo build -p redo-log-web --target wasm32-unknown-unknown --release
+//     wasm-bindgen --target web target/wasm32-unknown-unknown/release/redo_log_web.wasm --out-dir pkg
+//
+// Sync access handles are only handed out in dedicated workers, so the log
+// lives in one, with the page talking to it through messages. IndexedDB isn't
+// an option underneath, since everything it does is asynchronous and `Storage`
+// reads and writes as it's asked to.
+//
+// This isn't `redo_log::Db` in the browser, which needs a file system and
+// threads (see `redo_log_core`): there's no memtable, tables or commands,
+// just records for the app to replay into a state of its own.
+//
+// The journal is a single segment, number 0, in the same format as a segment
+// of `redo-log`'s, so `redo-log`'s tools can read a copy of it. There's no
+// rotating to a new segment: an app that wants to start over writes out its
+// state as the first records of a new file.
+#[cfg(test)]
+use redo_log_core::{encode_record, End};
+use redo_log_core::{Incompatible, SegmentCursor, SegmentWriter, Storage, WriteStorage};
+use wasm_bindgen::prelude::*;
+use web_sys::{FileSystemReadWriteOptions, FileSystemSyncAccessHandle};
+
+// What reading or writing the file failed with, as the exception it came
+// from, or else as an `Error` saying what was wrong with the segment.
+#[derive(Debug)]
+pub struct WebError(JsValue);
+
+impl From<JsValue> for WebError {
+    fn from(e: JsValue) -> Self {
+        WebError(e)
+    }
+}
+
+impl From<Incompatible> for WebError {
+    fn from(e: Incompatible) -> Self {
+        error(format!("segment {}: {}", e.segment, e.reason))
+    }
+}
+
+fn error(message: String) -> WebError {
+    WebError(js_sys::Error::new(&message).into())
+}
+
+impl From<WebError> for JsValue {
+    fn from(e: WebError) -> Self {
+        e.0
+    }
+}
+
+// A file in the origin private file system as `Storage`. Its size is read
+// once, when it's opened, and kept up to date from the writes made through
+// it, since nothing else can write to the file while the handle is open.
+#[derive(Debug)]
+pub struct OpfsStorage {
+    handle: FileSystemSyncAccessHandle,
+    size: u64,
+}
+
+impl OpfsStorage {
+    pub fn new(handle: FileSystemSyncAccessHandle) -> Result<Self, WebError> {
+        let size = handle.get_size()? as u64;
+        Ok(OpfsStorage { handle, size })
+    }
+
+    pub fn close(self) {
+        self.handle.close();
+    }
+}
+
+fn at(offset: u64) -> FileSystemReadWriteOptions {
+    let options = FileSystemReadWriteOptions::new();
+    options.set_at(offset as f64);
+    options
+}
+
+impl Storage for OpfsStorage {
+    type Error = WebError;
+
+    fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<(), WebError> {
+        let read = self
+            .handle
+            .read_with_u8_array_and_options(buf, &at(offset))?;
+        if read as usize != buf.len() {
+            return Err(error(format!(
+                "read {} bytes at {}, not {}",
+                read,
+                offset,
+                buf.len()
+            )));
+        }
+        Ok(())
+    }
+}
+
+impl WriteStorage for OpfsStorage {
+    fn write_at(&mut self, offset: u64, buf: &[u8]) -> Result<(), WebError> {
+        let written = self
+            .handle
+            .write_with_u8_array_and_options(buf, &at(offset))?;
+        if written as usize != buf.len() {
+            return Err(error(format!(
+                "wrote {} bytes at {}, not {}",
+                written,
+                offset,
+                buf.len()
+            )));
+        }
+        self.size = self.size.max(offset + buf.len() as u64);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), WebError> {
+        Ok(self.handle.flush()?)
+    }
+}
+
+// Replays the journal in `storage` through `apply`, and returns a writer to
+// append to it from there on, starting the journal if it's empty. A journal
+// that was written in a codec other than `codec` isn't replayed at all.
+fn recover<S, F>(storage: S, codec: u32, mut apply: F) -> Result<SegmentWriter<S>, S::Error>
+where
+    S: WriteStorage,
+    S::Error: From<Incompatible>,
+    F: FnMut(u64, &[u8]) -> Result<(), S::Error>,
+{
+    let mut cursor = SegmentCursor::open(storage, 0)?;
+    if cursor.first_seq().is_some() && cursor.codec() != codec {
+        return Err(Incompatible {
+            segment: 0,
+            reason: format!("written in codec {}, not {}", cursor.codec(), codec),
+        }
+        .into());
+    }
+    redo_log_core::replay(&mut cursor, &mut apply)?;
+    match SegmentWriter::resume(cursor) {
+        Ok(writer) => Ok(writer),
+        Err(storage) => SegmentWriter::create(storage, 0, 1, codec),
+    }
+}
+
+// The journal, for JavaScript. Records are byte arrays, in whatever codec the
+// app chooses; if they're `redo-log` commands as JSON, passing 1 as the codec
+// lets `redo-log`'s tools decode them too.
+#[wasm_bindgen]
+pub struct BrowserLog {
+    writer: SegmentWriter<OpfsStorage>,
+}
+
+#[wasm_bindgen]
+impl BrowserLog {
+    // Opens the journal in the file that `handle` was created for, calling
+    // `apply` with the sequence number (a BigInt) and payload (a Uint8Array)
+    // of each record in it. If `apply` throws, so does this.
+    pub fn open(
+        handle: FileSystemSyncAccessHandle,
+        codec: u32,
+        apply: &js_sys::Function,
+    ) -> Result<BrowserLog, JsValue> {
+        let writer = recover(OpfsStorage::new(handle)?, codec, |seq, payload| {
+            let payload = js_sys::Uint8Array::from(payload);
+            apply.call2(&JsValue::NULL, &JsValue::from(seq), &payload)?;
+            Ok(())
+        })?;
+        Ok(BrowserLog { writer })
+    }
+
+    // Appends `payload` and returns its sequence number. It isn't durable
+    // until `sync`.
+    pub fn append(&mut self, payload: &[u8]) -> Result<u64, JsValue> {
+        Ok(self.writer.append(payload)?)
+    }
+
+    pub fn sync(&mut self) -> Result<(), JsValue> {
+        Ok(self.writer.flush()?)
+    }
+
+    // The sequence number the next record will get.
+    #[wasm_bindgen(js_name = nextSeq)]
+    pub fn next_seq(&self) -> u64 {
+        self.writer.next_seq()
+    }
+
+    // Syncs the journal and closes its handle, so that the file can be
+    // opened again.
+    pub fn close(mut self) -> Result<(), JsValue> {
+        self.writer.flush()?;
+        self.writer.into_storage().close();
+        Ok(())
+    }
+}
+
+// A file in memory, standing in for OPFS, which only exists in a browser.
+#[cfg(test)]
+#[derive(Debug, Default)]
+struct Memory(Vec<u8>);
+
+#[cfg(test)]
+#[derive(Debug, PartialEq)]
+struct MemoryError(String);
+
+#[cfg(test)]
+impl From<Incompatible> for MemoryError {
+    fn from(e: Incompatible) -> Self {
+        MemoryError(e.reason)
+    }
+}
+
+#[cfg(test)]
+impl Storage for Memory {
+    type Error = MemoryError;
+
+    fn size(&self) -> u64 {
+        self.0.len() as u64
+    }
+
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<(), MemoryError> {
+        let offset = offset as usize;
+        buf.copy_from_slice(&self.0[offset..offset + buf.len()]);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+impl WriteStorage for Memory {
+    fn write_at(&mut self, offset: u64, buf: &[u8]) -> Result<(), MemoryError> {
+        let offset = offset as usize;
+        if self.0.len() < offset + buf.len() {
+            self.0.resize(offset + buf.len(), 0);
+        }
+        self.0[offset..offset + buf.len()].copy_from_slice(buf);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), MemoryError> {
+        Ok(())
+    }
+}
+
+#[test]
+fn test_recover() {
+    let no_records = |_: u64, _: &[u8]| -> Result<(), MemoryError> { panic!("nothing to replay") };
+    let mut writer = recover(Memory::default(), 1, no_records).unwrap();
+    assert_eq!(writer.append(b"one").unwrap(), 1);
+    assert_eq!(writer.append(b"two").unwrap(), 2);
+
+    // A record torn by closing the tab mid-write is dropped, and written over
+    // by the next one.
+    let mut storage = writer.into_storage();
+    let mut torn = Vec::new();
+    encode_record(0, 3, b"three", &mut torn);
+    storage.0.extend_from_slice(&torn[..10]);
+    let mut replayed = Vec::new();
+    let mut writer = recover(storage, 1, |seq, payload| {
+        replayed.push((seq, payload.to_vec()));
+        Ok(())
+    })
+    .unwrap();
+    assert_eq!(replayed, [(1, b"one".to_vec()), (2, b"two".to_vec())]);
+    assert_eq!(writer.append(b"four").unwrap(), 3);
+
+    let mut cursor = SegmentCursor::open(writer.into_storage(), 0).unwrap();
+    let mut seqs = Vec::new();
+    let end = redo_log_core::replay(&mut cursor, |seq, _| {
+        seqs.push(seq);
+        Ok::<_, MemoryError>(())
+    })
+    .unwrap();
+    assert_eq!((end, seqs), (End::Eof, vec![1, 2, 3]));
+
+    // Opening with some other codec than the journal was written in fails.
+    let err = recover(cursor.into_storage(), 2, no_records).unwrap_err();
+    assert_eq!(err, MemoryError("written in codec 1, not 2".to_string()));
+}